@@ -0,0 +1,69 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Benchmarks of the issuance hot path: checking the certification request,
+//! appraising each platform's evidence and signing the certificate.
+
+use attestation::crypto::{CertReqExt, PrivateKeyInfoExt, TbsCertificateExt};
+use attestation::sgx::Sgx;
+use attestation::snp::Snp;
+
+use const_oid::db::rfc5912::{SECP_256_R_1, SECP_384_R_1};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use der::Decode;
+use sec1::pkcs8::PrivateKeyInfo;
+use x509::ext::Extension;
+use x509::request::{CertReq, ExtensionReq};
+use x509::PkiPath;
+
+const ICELAKE_CSR: &[u8] = include_bytes!("../src/sgx/icelake.signed.crl.csr");
+const MILAN_CSR: &[u8] = include_bytes!("../src/snp/milan.signed.crl.csr");
+const MILAN_PATH: &[u8] = include_bytes!("../src/snp/milan.pkipath");
+
+/// The first extension requested by `cr`.
+fn evidence<'a>(cr: &CertReq<'a>) -> Extension<'a> {
+    let attr = cr.info.attributes.iter().next().unwrap();
+    let ereq: ExtensionReq<'a> = attr.values.iter().next().unwrap().decode_into().unwrap();
+    Vec::from(ereq).remove(0)
+}
+
+fn csr(c: &mut Criterion) {
+    let cr = CertReq::from_der(ICELAKE_CSR).unwrap();
+    c.bench_function("csr/decode", |b| {
+        b.iter(|| CertReq::from_der(black_box(ICELAKE_CSR)))
+    });
+    c.bench_function("csr/verify", |b| b.iter(|| black_box(cr.clone()).verify()));
+}
+
+fn appraisal(c: &mut Criterion) {
+    let sgx = CertReq::from_der(ICELAKE_CSR).unwrap();
+    let ext = evidence(&sgx);
+    let now = Sgx::issued(&ext).unwrap().unwrap();
+    c.bench_function("sgx/appraise", |b| {
+        b.iter(|| Sgx::default().verify(&sgx.info, black_box(&ext), None, false, now))
+    });
+
+    let snp = CertReq::from_der(MILAN_CSR).unwrap();
+    let ext = evidence(&snp);
+    c.bench_function("snp/appraise", |b| {
+        b.iter(|| Snp::default().verify(&snp.info, black_box(&ext), None, false))
+    });
+}
+
+fn signing(c: &mut Criterion) {
+    let path = PkiPath::from_der(MILAN_PATH).unwrap();
+
+    for (name, curve) in [("sign/p256", SECP_256_R_1), ("sign/p384", SECP_384_R_1)] {
+        let key = PrivateKeyInfo::generate(curve).unwrap();
+        let pki = PrivateKeyInfo::from_der(key.as_ref()).unwrap();
+
+        let mut tbs = path[1].tbs_certificate.clone();
+        tbs.signature = pki.signs_with().unwrap();
+        tbs.subject_public_key_info = pki.public_key().unwrap();
+
+        c.bench_function(name, |b| b.iter(|| black_box(tbs.clone()).sign(&pki)));
+    }
+}
+
+criterion_group!(benches, csr, appraisal, signing);
+criterion_main!(benches);
@@ -0,0 +1,30 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use super::super::Digest;
+
+use std::collections::HashSet;
+
+use serde::Deserialize;
+
+#[derive(Clone, Deserialize, Debug, Default, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// DER encoded root certificates, read from the listed files, of the
+    /// platforms whose evidence is accepted. Unlike other platforms, no
+    /// roots are built in, since each vendor has its own.
+    #[serde(deserialize_with = "crate::from_certificate_files")]
+    pub roots: Vec<Vec<u8>>,
+
+    /// Allowed SHA-384 digests of the TVM, from the TCB of the evidence.
+    #[serde(default)]
+    pub hash: HashSet<Digest<48>>,
+
+    /// Denied SHA-384 digests of the TVM.
+    #[serde(default)]
+    pub hash_blacklist: HashSet<Digest<48>>,
+
+    /// Minimum security version of the TVM.
+    #[serde(default)]
+    pub svn: Option<u64>,
+}
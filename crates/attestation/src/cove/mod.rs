@@ -0,0 +1,445 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! RISC-V CoVE (AP-TEE) attestation.
+//!
+//! A TEE VM (TVM) asks the TEE security manager (TSM) for evidence about a
+//! key of its own. The TSM answers with a certificate for the key, carrying
+//! the measurements of the TVM as a DICE `TcbInfo` extension, signed by a key
+//! derived through the DICE layers of the platform, each certified by the
+//! one below it down to the root of trust of the hardware vendor.
+//!
+//! The evidence is that chain, as a `PkiPath` ordered from the first
+//! certificate below a root of trust to the TVM. Platforms differ in their
+//! roots, so none are built in; the policy lists the roots to accept.
+
+pub mod config;
+
+use self::config::Config;
+use super::crypto::TbsCertificateExt;
+use super::ct;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use const_oid::db::rfc5912::ID_SHA_384;
+use const_oid::ObjectIdentifier;
+use der::asn1::{BitStringRef, OctetStringRef, UIntRef, Utf8StringRef};
+use der::{Decode, Sequence};
+use x509::ext::Extension;
+use x509::{request::CertReqInfo, Certificate, TbsCertificate};
+
+/// The TCG DICE `TcbInfo` extension.
+const TCB_INFO: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.23.133.5.4.1");
+
+#[derive(Clone, Debug, Sequence)]
+pub struct Fwid<'a> {
+    pub hash_alg: ObjectIdentifier,
+    pub digest: OctetStringRef<'a>,
+}
+
+/// The measurements of a DICE layer. See the TCG DICE Attestation
+/// Architecture, section 6.1.1.
+#[derive(Clone, Debug, Sequence)]
+pub struct TcbInfo<'a> {
+    #[asn1(context_specific = "0", optional = "true", tag_mode = "IMPLICIT")]
+    pub vendor: Option<Utf8StringRef<'a>>,
+
+    #[asn1(context_specific = "1", optional = "true", tag_mode = "IMPLICIT")]
+    pub model: Option<Utf8StringRef<'a>>,
+
+    #[asn1(context_specific = "2", optional = "true", tag_mode = "IMPLICIT")]
+    pub version: Option<Utf8StringRef<'a>>,
+
+    #[asn1(context_specific = "3", optional = "true", tag_mode = "IMPLICIT")]
+    pub svn: Option<UIntRef<'a>>,
+
+    #[asn1(context_specific = "4", optional = "true", tag_mode = "IMPLICIT")]
+    pub layer: Option<UIntRef<'a>>,
+
+    #[asn1(context_specific = "5", optional = "true", tag_mode = "IMPLICIT")]
+    pub index: Option<UIntRef<'a>>,
+
+    #[asn1(context_specific = "6", optional = "true", tag_mode = "IMPLICIT")]
+    pub fwids: Option<Vec<Fwid<'a>>>,
+
+    #[asn1(context_specific = "7", optional = "true", tag_mode = "IMPLICIT")]
+    pub flags: Option<BitStringRef<'a>>,
+
+    #[asn1(context_specific = "8", optional = "true", tag_mode = "IMPLICIT")]
+    pub vendor_info: Option<OctetStringRef<'a>>,
+
+    #[asn1(context_specific = "9", optional = "true", tag_mode = "IMPLICIT")]
+    pub tcb_type: Option<OctetStringRef<'a>>,
+}
+
+impl<'a> TcbInfo<'a> {
+    /// The `TcbInfo` of the certificate `tbs`.
+    pub fn of(tbs: &TbsCertificate<'a>) -> Result<Self> {
+        let mut tcbs = tbs.extensions::<TcbInfo<'a>>(TCB_INFO)?.into_iter();
+        let (_, tcb) = tcbs
+            .next()
+            .ok_or_else(|| anyhow!("cove evidence has no tcb info"))?;
+        ensure!(tcbs.next().is_none(), "cove evidence has several tcb infos");
+        Ok(tcb)
+    }
+
+    /// The SHA-384 digest of the layer.
+    pub fn digest(&self) -> Result<[u8; 48]> {
+        let fwid = self
+            .fwids
+            .iter()
+            .flatten()
+            .find(|fwid| fwid.hash_alg == ID_SHA_384)
+            .ok_or_else(|| anyhow!("cove tcb info has no sha384 digest"))?;
+        fwid.digest
+            .as_bytes()
+            .try_into()
+            .context("cove tcb info digest is incorrect size")
+    }
+
+    /// The security version of the layer, if it has one.
+    pub fn svn(&self) -> Result<Option<u64>> {
+        let svn = match &self.svn {
+            Some(svn) => svn,
+            None => return Ok(None),
+        };
+
+        let bytes = svn.as_bytes();
+        ensure!(bytes.len() <= 8, "cove tcb info svn is too large");
+        Ok(Some(bytes.iter().fold(0, |n, b| n << 8 | u64::from(*b))))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Cove(());
+
+impl Cove {
+    pub const OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.58270.1.6");
+    pub const ATT: bool = true;
+
+    /// The certificate for the key of the TVM in `ext`, which is only to be
+    /// trusted once verified.
+    pub fn leaf<'a>(ext: &Extension<'a>) -> Result<Certificate<'a>> {
+        let mut path = crate::parse::cove_evidence(ext.extn_value)?;
+        path.pop().ok_or_else(|| anyhow!("cove evidence is empty"))
+    }
+
+    // This ensures that `path` is rooted in one of the configured roots,
+    // returning the certificate at its end.
+    fn is_trusted<'r, 'c>(
+        &self,
+        config: &Config,
+        path: &'r [Certificate<'c>],
+    ) -> Result<&'r TbsCertificate<'c>> {
+        let (first, rest) = path
+            .split_first()
+            .ok_or_else(|| anyhow!("cove evidence is empty"))?;
+
+        for root in &config.roots {
+            let root = Certificate::from_der(root)?;
+            let mut signer = match root.tbs_certificate.verify_crt(first) {
+                Ok(signer) => signer,
+                Err(..) => continue,
+            };
+
+            for crt in rest {
+                signer = signer
+                    .verify_crt(crt)
+                    .context("cove evidence has invalid certificate chain")?;
+            }
+            return Ok(signer);
+        }
+
+        bail!("cove evidence is untrusted")
+    }
+
+    pub fn verify(
+        &self,
+        cri: &CertReqInfo<'_>,
+        ext: &Extension<'_>,
+        config: Option<&Config>,
+        dbg: bool,
+    ) -> Result<bool> {
+        ensure!(!ext.critical, "cove extension cannot be critical");
+
+        let config = config.ok_or_else(|| anyhow!("cove requires configured roots of trust"))?;
+        let path = crate::parse::cove_evidence(ext.extn_value)?;
+        let leaf = self.is_trusted(config, &path)?;
+
+        if !dbg {
+            // Validate that the evidence certifies the key of the request.
+            ensure!(
+                leaf.subject_public_key_info == cri.public_key,
+                "cove evidence certifies another key"
+            );
+        }
+
+        let tcb = TcbInfo::of(leaf)?;
+        let digest = tcb.digest()?;
+
+        if !config.hash.is_empty() {
+            ensure!(ct::contains(&config.hash, &digest), "cove untrusted tvm");
+        }
+
+        if !config.hash_blacklist.is_empty() {
+            let denied = ct::contains(&config.hash_blacklist, &digest);
+            ensure!(!denied, "cove tvm is denied");
+        }
+
+        if let Some(min) = config.svn {
+            let svn = tcb.svn()?.unwrap_or(0);
+            ensure!(svn >= min, "cove tvm svn {svn} is below {min}");
+        }
+
+        Ok(Self::ATT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKeyInfoExt;
+
+    use std::collections::HashSet;
+    use std::time::{Duration, SystemTime};
+
+    use const_oid::db::rfc5912::{
+        ID_CE_BASIC_CONSTRAINTS, ID_CE_KEY_USAGE, ID_SHA_256, SECP_384_R_1,
+    };
+    use der::asn1::GeneralizedTime;
+    use der::Encode;
+    use sec1::pkcs8::PrivateKeyInfo;
+    use spki::SubjectPublicKeyInfo;
+    use x509::ext::pkix::{BasicConstraints, KeyUsage, KeyUsages};
+    use x509::name::RdnSequence;
+    use x509::time::{Time, Validity};
+    use x509::PkiPath;
+
+    const TVM: [u8; 48] = [3; 48];
+
+    /// Issues a certificate for `key`, as `subject`, signed by `issuer`.
+    fn issue(
+        issuer: (&str, &PrivateKeyInfo<'_>),
+        subject: &str,
+        key: SubjectPublicKeyInfo<'_>,
+        tcb: Option<&[u8]>,
+    ) -> Vec<u8> {
+        let ca = tcb.is_none();
+        let ku = match ca {
+            true => KeyUsage(KeyUsages::KeyCertSign.into()),
+            false => KeyUsage(KeyUsages::DigitalSignature.into()),
+        }
+        .to_vec()
+        .unwrap();
+        let bc = BasicConstraints {
+            ca,
+            path_len_constraint: None,
+        }
+        .to_vec()
+        .unwrap();
+
+        let issuer_name = RdnSequence::encode_from_string(issuer.0).unwrap();
+        let subject_name = RdnSequence::encode_from_string(subject).unwrap();
+
+        let now = SystemTime::now();
+        let validity = Validity {
+            not_before: Time::GeneralTime(GeneralizedTime::from_system_time(now).unwrap()),
+            not_after: Time::GeneralTime(
+                GeneralizedTime::from_system_time(now + Duration::from_secs(3600)).unwrap(),
+            ),
+        };
+
+        let mut extensions = vec![
+            Extension {
+                extn_id: ID_CE_KEY_USAGE,
+                critical: true,
+                extn_value: &ku,
+            },
+            Extension {
+                extn_id: ID_CE_BASIC_CONSTRAINTS,
+                critical: true,
+                extn_value: &bc,
+            },
+        ];
+        if let Some(tcb) = tcb {
+            extensions.push(Extension {
+                extn_id: TCB_INFO,
+                critical: false,
+                extn_value: tcb,
+            });
+        }
+
+        TbsCertificate {
+            version: x509::Version::V3,
+            serial_number: UIntRef::new(&[1]).unwrap(),
+            signature: issuer.1.signs_with().unwrap(),
+            issuer: RdnSequence::from_der(&issuer_name).unwrap(),
+            validity,
+            subject: RdnSequence::from_der(&subject_name).unwrap(),
+            subject_public_key_info: key,
+            issuer_unique_id: None,
+            subject_unique_id: None,
+            extensions: Some(extensions),
+        }
+        .sign(issuer.1)
+        .unwrap()
+    }
+
+    /// A root of trust, and evidence from a TSM below it certifying `tvm`.
+    fn chain(tvm: SubjectPublicKeyInfo<'_>, tcb: &TcbInfo<'_>) -> (Vec<u8>, Vec<u8>) {
+        let keys: Vec<_> = (0..2)
+            .map(|_| PrivateKeyInfo::generate(SECP_384_R_1).unwrap())
+            .collect();
+        let root = PrivateKeyInfo::from_der(&keys[0]).unwrap();
+        let tsm = PrivateKeyInfo::from_der(&keys[1]).unwrap();
+
+        let root_crt = issue(
+            ("CN=root", &root),
+            "CN=root",
+            root.public_key().unwrap(),
+            None,
+        );
+        let tsm_crt = issue(
+            ("CN=root", &root),
+            "CN=tsm",
+            tsm.public_key().unwrap(),
+            None,
+        );
+        let tcb = tcb.to_vec().unwrap();
+        let tvm_crt = issue(("CN=tsm", &tsm), "CN=tvm", tvm, Some(&tcb));
+
+        let path: PkiPath<'_> = vec![
+            Certificate::from_der(&tsm_crt).unwrap(),
+            Certificate::from_der(&tvm_crt).unwrap(),
+        ];
+        (root_crt, path.to_vec().unwrap())
+    }
+
+    fn tcb<'a>(fwids: &'a [Fwid<'a>], svn: &'a [u8]) -> TcbInfo<'a> {
+        TcbInfo {
+            vendor: None,
+            model: None,
+            version: None,
+            svn: Some(UIntRef::new(svn).unwrap()),
+            layer: None,
+            index: None,
+            fwids: Some(Vec::from(fwids)),
+            flags: None,
+            vendor_info: None,
+            tcb_type: None,
+        }
+    }
+
+    /// Verifies evidence certifying the key of the request, or another.
+    fn verify(config: impl FnOnce(Vec<u8>) -> Config, bound: bool, dbg: bool) -> Result<bool> {
+        let keys: Vec<_> = (0..2)
+            .map(|_| PrivateKeyInfo::generate(SECP_384_R_1).unwrap())
+            .collect();
+        let pki = PrivateKeyInfo::from_der(&keys[0]).unwrap();
+        let other = PrivateKeyInfo::from_der(&keys[1]).unwrap();
+
+        let rdns = RdnSequence::encode_from_string("CN=tvm").unwrap();
+        let cri = CertReqInfo {
+            version: x509::request::Version::V1,
+            subject: RdnSequence::from_der(&rdns).unwrap(),
+            public_key: pki.public_key().unwrap(),
+            attributes: Default::default(),
+        };
+
+        let fwids = [
+            Fwid {
+                hash_alg: ID_SHA_256,
+                digest: OctetStringRef::new(&[1; 32]).unwrap(),
+            },
+            Fwid {
+                hash_alg: ID_SHA_384,
+                digest: OctetStringRef::new(&TVM).unwrap(),
+            },
+        ];
+        let tcb = tcb(&fwids, &[2]);
+        let key = match bound {
+            true => pki.public_key().unwrap(),
+            false => other.public_key().unwrap(),
+        };
+        let (root, der) = chain(key, &tcb);
+
+        let ext = Extension {
+            extn_id: Cove::OID,
+            critical: false,
+            extn_value: &der,
+        };
+        Cove::default().verify(&cri, &ext, Some(&config(root)), dbg)
+    }
+
+    fn roots(root: Vec<u8>) -> Config {
+        Config {
+            roots: vec![root],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rooted() {
+        assert!(verify(roots, true, false).unwrap());
+
+        // Evidence from another platform.
+        let other = |_| {
+            let key = PrivateKeyInfo::generate(SECP_384_R_1).unwrap();
+            let key = PrivateKeyInfo::from_der(&key).unwrap();
+            roots(issue(
+                ("CN=root", &key),
+                "CN=root",
+                key.public_key().unwrap(),
+                None,
+            ))
+        };
+        assert!(verify(other, true, false).is_err());
+        assert!(verify(|_| Config::default(), true, false).is_err());
+    }
+
+    #[test]
+    fn bound() {
+        assert!(verify(roots, false, false).is_err());
+        assert!(verify(roots, false, true).unwrap());
+    }
+
+    #[test]
+    fn policy() {
+        let hash = |hash: [u8; 48]| {
+            move |root| Config {
+                hash: HashSet::from([crate::Digest(hash)]),
+                ..roots(root)
+            }
+        };
+        assert!(verify(hash(TVM), true, false).is_ok());
+        assert!(verify(hash([4; 48]), true, false).is_err());
+
+        let denied = |root| Config {
+            hash_blacklist: HashSet::from([crate::Digest(TVM)]),
+            ..roots(root)
+        };
+        assert!(verify(denied, true, false).is_err());
+
+        let svn = |svn| {
+            move |root| Config {
+                svn: Some(svn),
+                ..roots(root)
+            }
+        };
+        assert!(verify(svn(2), true, false).is_ok());
+        assert!(verify(svn(3), true, false).is_err());
+    }
+
+    #[test]
+    fn digest() {
+        let fwids = [Fwid {
+            hash_alg: ID_SHA_256,
+            digest: OctetStringRef::new(&[1; 32]).unwrap(),
+        }];
+        assert!(tcb(&fwids, &[1]).digest().is_err());
+        assert_eq!(tcb(&fwids, &[1, 0]).svn().unwrap(), Some(256));
+
+        let der = tcb(&fwids, &[1]).to_vec().unwrap();
+        let decoded = TcbInfo::from_der(&der).unwrap();
+        assert_eq!(decoded.fwids.unwrap()[0].digest.as_bytes(), [1; 32]);
+    }
+}
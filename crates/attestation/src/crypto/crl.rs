@@ -1,11 +1,15 @@
 // SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
 // SPDX-License-Identifier: AGPL-3.0-only
 
-use crate::crypto::{SubjectPublicKeyInfoExt, TbsCertificateExt};
+use crate::crypto::{PrivateKeyInfoExt, SubjectPublicKeyInfoExt, TbsCertificateExt};
+
+use std::time::SystemTime;
 
 use anyhow::{bail, ensure, Context, Result};
+use der::asn1::BitStringRef;
 use der::{Encode, Sequence};
-use x509::crl::CertificateList;
+use sec1::pkcs8::PrivateKeyInfo;
+use x509::crl::{CertificateList, TbsCertList};
 use x509::name::Name;
 use x509::PkiPath;
 
@@ -30,22 +34,55 @@ impl<'a> CrlList<'a> {
         None
     }
 
-    fn get_crl_by_issuer(&self, issuer: &Name) -> Option<&CertificateList> {
-        for pair in &self.crls {
-            if &pair.crl.tbs_cert_list.issuer == issuer {
-                return Some(&pair.crl);
-            }
-        }
-        None
+    /// The time the most recently issued CRL was issued, if there are any.
+    ///
+    /// Since clients fetch CRLs alongside their evidence, this bounds how
+    /// stale the evidence may be for platforms without a report timestamp.
+    pub fn latest_update(&self) -> Option<SystemTime> {
+        self.crls
+            .iter()
+            .map(|pair| pair.crl.tbs_cert_list.this_update.to_system_time())
+            .max()
+    }
+
+    fn get_crls_by_issuer(&self, issuer: &Name) -> Vec<&CertificateList> {
+        self.crls
+            .iter()
+            .map(|pair| &pair.crl)
+            .filter(|crl| &crl.tbs_cert_list.issuer == issuer)
+            .collect()
+    }
+}
+
+pub trait TbsCertListExt {
+    /// Signs the `TbsCertList` with the specified `PrivateKeyInfo`
+    fn sign(self, pki: &PrivateKeyInfo<'_>) -> Result<Vec<u8>>;
+}
+
+impl TbsCertListExt for TbsCertList<'_> {
+    fn sign(self, pki: &PrivateKeyInfo<'_>) -> Result<Vec<u8>> {
+        let algo = self.signature;
+        let body = self.to_vec()?;
+        let sign = pki.sign(&body, algo)?;
+
+        let rval = CertificateList {
+            tbs_cert_list: self,
+            signature_algorithm: algo,
+            signature: BitStringRef::from_bytes(&sign)?,
+        };
+
+        Ok(rval.to_vec()?)
     }
 }
 
 pub trait PkiPathCRLCheck<'a> {
-    fn check_crl(&self, pairs: &CrlList<'a>) -> Result<()>;
+    /// Checks that no certificate of the path is revoked by the CRLs of its
+    /// issuer in `pairs`, all of which must be current at `now`.
+    fn check_crl(&self, pairs: &CrlList<'a>, now: SystemTime) -> Result<()>;
 }
 
 impl<'a> PkiPathCRLCheck<'a> for PkiPath<'a> {
-    fn check_crl(&self, pairs: &CrlList<'a>) -> Result<()> {
+    fn check_crl(&self, pairs: &CrlList<'a>, now: SystemTime) -> Result<()> {
         // We want to ensure that a valid CRL was passed, so we make sure at least one of the CRLs
         // is valid for this `PkiPath`, otherwise, no valid CRLs were received, and that's not okay.
         let mut found = false;
@@ -59,11 +96,11 @@ impl<'a> PkiPathCRLCheck<'a> for PkiPath<'a> {
             let cert = cert.unwrap();
             let next = next.unwrap();
 
+            // Every CRL from the issuer is checked, since they may come from
+            // different sources (e.g. sent with the evidence and configured).
             let crls = {
-                let mut crls = vec![];
-                if let Some(crl) = pairs.get_crl_by_issuer(&cert.tbs_certificate.subject) {
-                    crls.push(crl);
-                } else {
+                let mut crls = pairs.get_crls_by_issuer(&cert.tbs_certificate.subject);
+                if crls.is_empty() {
                     let urls = cert.tbs_certificate.get_crl_urls()?;
                     for url in urls {
                         if let Some(crl) = pairs.get_crl_by_url(&url) {
@@ -79,13 +116,11 @@ impl<'a> PkiPathCRLCheck<'a> for PkiPath<'a> {
 
             for crl in crls {
                 if let Some(next_update) = crl.tbs_cert_list.next_update {
-                    if next_update.to_system_time() <= std::time::SystemTime::now() {
-                        // Don't error on CRL expiration in unit tests
-                        #[cfg(not(debug_assertions))]
-                        bail!("CRL expired");
-                        #[cfg(debug_assertions)]
-                        eprintln!("CRL {} expired.", crl.tbs_cert_list.issuer);
-                    }
+                    ensure!(
+                        next_update.to_system_time() > now,
+                        "CRL {} expired",
+                        crl.tbs_cert_list.issuer
+                    );
                 }
 
                 let raw_bytes = crl.tbs_cert_list.to_vec().unwrap();
@@ -119,7 +154,7 @@ impl<'a> PkiPathCRLCheck<'a> for PkiPath<'a> {
 
 #[cfg(test)]
 mod tests {
-    use super::super::{PkiPathCRLCheck, PrivateKeyInfoExt, TbsCertificateExt};
+    use super::super::{PkiPathCRLCheck, PrivateKeyInfoExt, TbsCertListExt, TbsCertificateExt};
     use super::*;
 
     use const_oid::db::rfc5280::{ID_CE_BASIC_CONSTRAINTS, ID_CE_KEY_USAGE};
@@ -291,6 +326,7 @@ mod tests {
         let now = SystemTime::now();
         let dur = Duration::from_secs(60 * 60 * 24);
         let yesterday = Time::GeneralTime(GeneralizedTime::from_system_time(now - dur).unwrap());
+        let tomorrow = Time::GeneralTime(GeneralizedTime::from_system_time(now + dur).unwrap());
 
         // Create a relative distinguished name for the "Issuer" field.
         let rdns = RdnSequence::encode_from_string(TEST_ISSUER).unwrap();
@@ -311,7 +347,7 @@ mod tests {
             signature: ca_cert.signature_algorithm.clone(),
             issuer: rdns,
             this_update: yesterday,
-            next_update: None,
+            next_update: Some(tomorrow),
             revoked_certificates: revoked,
             crl_extensions: None,
         };
@@ -360,7 +396,13 @@ mod tests {
         };
 
         // Should be okay
-        assert!(path.check_crl(&crl_list).is_ok());
+        let now = SystemTime::now();
+        assert!(path.check_crl(&crl_list, now).is_ok());
+
+        // But not once the CRL has expired.
+        let later = now + Duration::from_secs(60 * 60 * 24 * 2);
+        let err = path.check_crl(&crl_list, later).err().unwrap();
+        assert!(err.to_string().contains("expired"));
     }
 
     #[test]
@@ -391,7 +433,46 @@ mod tests {
         };
 
         // Should be revoked!
-        let err = path.check_crl(&crl_list).err().unwrap();
+        let err = path.check_crl(&crl_list, SystemTime::now()).err().unwrap();
+        assert_eq!(err.to_string(), "revoked!");
+    }
+
+    #[test]
+    fn signed() {
+        let revoked_serial = REVOKED_SERIAL.to_be_bytes();
+        let revoked_serial = UIntRef::new(&revoked_serial).unwrap();
+
+        let (ca_key, ca_cert) = create_ca();
+        let ca_cert = Certificate::from_der(&ca_cert).unwrap();
+        let (_end_pki, end_cert) = create_cert(&ca_key, &revoked_serial);
+        let end_cert = Certificate::from_der(&end_cert).unwrap();
+
+        let ca_pki = PrivateKeyInfo::from_der(ca_key.as_ref()).unwrap();
+        let now = Time::GeneralTime(GeneralizedTime::from_system_time(SystemTime::now()).unwrap());
+        let crl = TbsCertList {
+            version: x509::Version::V2,
+            signature: ca_pki.signs_with().unwrap(),
+            issuer: ca_cert.tbs_certificate.subject.clone(),
+            this_update: now,
+            next_update: None,
+            revoked_certificates: Some(vec![RevokedCert {
+                serial_number: revoked_serial,
+                revocation_date: now,
+                crl_entry_extensions: None,
+            }]),
+            crl_extensions: None,
+        }
+        .sign(&ca_pki)
+        .unwrap();
+
+        let crl_list = CrlList {
+            crls: vec![CrlListEntry {
+                url: TEST_URL.into(),
+                crl: CertificateList::from_der(&crl).unwrap(),
+            }],
+        };
+        let path = PkiPath::from([ca_cert, end_cert]);
+        let err = path.check_crl(&crl_list, SystemTime::now()).err().unwrap();
         assert_eq!(err.to_string(), "revoked!");
     }
 
@@ -427,7 +508,7 @@ mod tests {
         };
 
         // Should fail as the provided CRL isn't for this Certificate chain
-        let err = path.check_crl(&crl_list).err().unwrap();
+        let err = path.check_crl(&crl_list, SystemTime::now()).err().unwrap();
         assert_eq!(err.to_string(), "CRL validation error");
     }
 }
@@ -0,0 +1,154 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! FIPS 140-3 crypto backend selection.
+//!
+//! When built with the `fips` feature, `enable()` switches all signing,
+//! verification and key generation in this module's parent over to the
+//! validated AWS-LC module. Algorithms outside of the FIPS boundary are
+//! refused while FIPS mode is active.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Result;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the FIPS backend was compiled in.
+pub const AVAILABLE: bool = cfg!(all(feature = "fips", not(target_os = "wasi")));
+
+/// Returns true if FIPS mode is active.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Activates FIPS mode, failing if the validated module is unavailable.
+#[cfg(all(feature = "fips", not(target_os = "wasi")))]
+pub fn enable() -> Result<()> {
+    aws_lc_rs::try_fips_mode().map_err(|e| anyhow::anyhow!("fips self test failed: {e}"))?;
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Activates FIPS mode, failing if the validated module is unavailable.
+#[cfg(not(all(feature = "fips", not(target_os = "wasi"))))]
+pub fn enable() -> Result<()> {
+    anyhow::bail!("built without fips support")
+}
+
+#[cfg(all(feature = "fips", not(target_os = "wasi")))]
+pub(crate) use backend::{generate, sign, verify};
+
+#[cfg(all(feature = "fips", not(target_os = "wasi")))]
+mod backend {
+    use super::super::spki::pss_digest;
+
+    use anyhow::{anyhow, bail, Result};
+    use aws_lc_rs::rand::SystemRandom;
+    use aws_lc_rs::signature::{self, EcdsaKeyPair, RsaKeyPair, UnparsedPublicKey};
+    use const_oid::db::rfc5912::{
+        ECDSA_WITH_SHA_256, ECDSA_WITH_SHA_384, ECDSA_WITH_SHA_512, ID_EC_PUBLIC_KEY as ECPK,
+        ID_RSASSA_PSS, ID_SHA_256 as SHA256, ID_SHA_384 as SHA384, ID_SHA_512 as SHA512,
+        RSA_ENCRYPTION as RSA, SECP_256_R_1 as P256, SECP_384_R_1 as P384, SECP_521_R_1 as P521,
+    };
+    use sec1::pkcs8::{ObjectIdentifier, PrivateKeyInfo, SubjectPublicKeyInfo};
+    use spki::AlgorithmIdentifier;
+    use zeroize::Zeroizing;
+
+    fn ecdsa(curve: ObjectIdentifier) -> Result<&'static signature::EcdsaSigningAlgorithm> {
+        match curve {
+            P256 => Ok(&signature::ECDSA_P256_SHA256_ASN1_SIGNING),
+            P384 => Ok(&signature::ECDSA_P384_SHA384_ASN1_SIGNING),
+            P521 => Ok(&signature::ECDSA_P521_SHA512_ASN1_SIGNING),
+            _ => bail!("unsupported in fips mode"),
+        }
+    }
+
+    pub(crate) fn generate(oid: ObjectIdentifier) -> Result<Zeroizing<Vec<u8>>> {
+        let rng = SystemRandom::new();
+        let doc = EcdsaKeyPair::generate_pkcs8(ecdsa(oid)?, &rng)
+            .map_err(|_| anyhow!("fips key generation failed"))?;
+        Ok(Zeroizing::new(doc.as_ref().to_vec()))
+    }
+
+    pub(crate) fn sign(
+        pki: &PrivateKeyInfo<'_>,
+        body: &[u8],
+        algo: AlgorithmIdentifier<'_>,
+    ) -> Result<Vec<u8>> {
+        let rng = SystemRandom::new();
+
+        match (pki.algorithm.oids()?, (algo.oid, algo.parameters)) {
+            ((ECPK, Some(curve)), (oid, None))
+                if matches!(
+                    (curve, oid),
+                    (P256, ECDSA_WITH_SHA_256)
+                        | (P384, ECDSA_WITH_SHA_384)
+                        | (P521, ECDSA_WITH_SHA_512)
+                ) =>
+            {
+                // Re-encode as PKCS#8 v1, which is all AWS-LC accepts.
+                let v1 = PrivateKeyInfo {
+                    public_key: None,
+                    ..*pki
+                };
+                let der = Zeroizing::new(der::Encode::to_vec(&v1)?);
+                let key = EcdsaKeyPair::from_pkcs8(ecdsa(curve)?, &der)
+                    .map_err(|e| anyhow!("invalid key: {e}"))?;
+                let sig = key
+                    .sign(&rng, body)
+                    .map_err(|_| anyhow!("fips signing failed"))?;
+                Ok(sig.as_ref().to_vec())
+            }
+
+            ((RSA, None), (ID_RSASSA_PSS, Some(p))) => {
+                let encoding: &'static dyn signature::RsaEncoding = match pss_digest(p)? {
+                    SHA256 => &signature::RSA_PSS_SHA256,
+                    SHA384 => &signature::RSA_PSS_SHA384,
+                    SHA512 => &signature::RSA_PSS_SHA512,
+                    _ => bail!("unsupported in fips mode"),
+                };
+
+                let key = RsaKeyPair::from_der(pki.private_key)
+                    .map_err(|e| anyhow!("invalid key: {e}"))?;
+                let mut sig = vec![0u8; key.public_modulus_len()];
+                key.sign(encoding, &rng, body, &mut sig)
+                    .map_err(|_| anyhow!("fips signing failed"))?;
+                Ok(sig)
+            }
+
+            _ => bail!("unsupported in fips mode"),
+        }
+    }
+
+    pub(crate) fn verify(
+        spki: &SubjectPublicKeyInfo<'_>,
+        body: &[u8],
+        algo: AlgorithmIdentifier<'_>,
+        sign: &[u8],
+    ) -> Result<()> {
+        let alg: &'static dyn signature::VerificationAlgorithm =
+            match (spki.algorithm.oids()?, (algo.oid, algo.parameters)) {
+                ((ECPK, Some(P256)), (ECDSA_WITH_SHA_256, None)) => {
+                    &signature::ECDSA_P256_SHA256_ASN1
+                }
+                ((ECPK, Some(P384)), (ECDSA_WITH_SHA_384, None)) => {
+                    &signature::ECDSA_P384_SHA384_ASN1
+                }
+                ((ECPK, Some(P521)), (ECDSA_WITH_SHA_512, None)) => {
+                    &signature::ECDSA_P521_SHA512_ASN1
+                }
+                ((RSA, None), (ID_RSASSA_PSS, Some(p))) => match pss_digest(p)? {
+                    SHA256 => &signature::RSA_PSS_2048_8192_SHA256,
+                    SHA384 => &signature::RSA_PSS_2048_8192_SHA384,
+                    SHA512 => &signature::RSA_PSS_2048_8192_SHA512,
+                    _ => bail!("unsupported in fips mode"),
+                },
+                _ => bail!("unsupported in fips mode"),
+            };
+
+        UnparsedPublicKey::new(alg, spki.subject_public_key)
+            .verify(body, sign)
+            .map_err(|_| anyhow!("invalid signature"))
+    }
+}
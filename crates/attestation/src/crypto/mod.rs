@@ -4,11 +4,14 @@
 mod cert;
 mod certreq;
 mod crl;
+pub mod fips;
 mod pki;
+#[cfg(feature = "pqc")]
+pub mod pqc;
 mod spki;
 
 pub use self::cert::TbsCertificateExt;
 pub use self::certreq::{CertReqExt, CertReqInfoExt};
-pub use self::crl::{CrlList, CrlListEntry, PkiPathCRLCheck};
+pub use self::crl::{CrlList, CrlListEntry, PkiPathCRLCheck, TbsCertListExt};
 pub use self::pki::PrivateKeyInfoExt;
 pub use self::spki::SubjectPublicKeyInfoExt;
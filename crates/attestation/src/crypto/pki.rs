@@ -5,15 +5,21 @@ use anyhow::{anyhow, bail, Result};
 use sec1::pkcs8::{EncodePrivateKey, ObjectIdentifier, PrivateKeyInfo, SubjectPublicKeyInfo};
 use zeroize::Zeroizing;
 
-use der::Decode;
+use der::asn1::AnyRef;
+use der::{Decode, Encode};
 use sec1::EcPrivateKey;
 use spki::AlgorithmIdentifier;
 
 use const_oid::db::rfc5912::{
-    ECDSA_WITH_SHA_256, ECDSA_WITH_SHA_384, ID_EC_PUBLIC_KEY as ECPK, SECP_256_R_1 as P256,
-    SECP_384_R_1 as P384,
+    ECDSA_WITH_SHA_256, ECDSA_WITH_SHA_384, ECDSA_WITH_SHA_512, ID_EC_PUBLIC_KEY as ECPK,
+    ID_RSASSA_PSS, ID_SHA_256 as SHA256, ID_SHA_384 as SHA384, ID_SHA_512 as SHA512,
+    RSA_ENCRYPTION as RSA, SECP_256_R_1 as P256, SECP_384_R_1 as P384, SECP_521_R_1 as P521,
+    SHA_256_WITH_RSA_ENCRYPTION as RS256, SHA_384_WITH_RSA_ENCRYPTION as RS384,
+    SHA_512_WITH_RSA_ENCRYPTION as RS512,
 };
 
+use super::spki::{digest_info, pss_digest};
+
 const ES256: AlgorithmIdentifier<'static> = AlgorithmIdentifier {
     oid: ECDSA_WITH_SHA_256,
     parameters: None,
@@ -24,6 +30,45 @@ const ES384: AlgorithmIdentifier<'static> = AlgorithmIdentifier {
     parameters: None,
 };
 
+const ES512: AlgorithmIdentifier<'static> = AlgorithmIdentifier {
+    oid: ECDSA_WITH_SHA_512,
+    parameters: None,
+};
+
+/// The size of generated RSA keys.
+const RSA_BITS: usize = 3072;
+
+/// DER encoded `RSASSA-PSS-params` for SHA-256, MGF1 with SHA-256 and a 32 byte salt.
+const PS256: &[u8] = &[
+    0x30, 0x35, 0xa0, 0x0d, 0x30, 0x0b, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+    0x01, 0xa1, 0x1a, 0x30, 0x18, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x08,
+    0x30, 0x0b, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0xa2, 0x03, 0x02,
+    0x01, 0x20, 0xa3, 0x03, 0x02, 0x01, 0x01,
+];
+
+/// DER encoded `RSASSA-PSS-params` for SHA-384, MGF1 with SHA-384 and a 48 byte salt.
+const PS384: &[u8] = &[
+    0x30, 0x35, 0xa0, 0x0d, 0x30, 0x0b, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+    0x02, 0xa1, 0x1a, 0x30, 0x18, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x08,
+    0x30, 0x0b, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02, 0xa2, 0x03, 0x02,
+    0x01, 0x30, 0xa3, 0x03, 0x02, 0x01, 0x01,
+];
+
+/// DER encoded `RSASSA-PSS-params` for SHA-512, MGF1 with SHA-512 and a 64 byte salt.
+const PS512: &[u8] = &[
+    0x30, 0x35, 0xa0, 0x0d, 0x30, 0x0b, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+    0x03, 0xa1, 0x1a, 0x30, 0x18, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x08,
+    0x30, 0x0b, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03, 0xa2, 0x03, 0x02,
+    0x01, 0x40, 0xa3, 0x03, 0x02, 0x01, 0x01,
+];
+
+fn pss(params: &'static [u8]) -> Result<AlgorithmIdentifier<'static>> {
+    Ok(AlgorithmIdentifier {
+        oid: ID_RSASSA_PSS,
+        parameters: Some(AnyRef::from_der(params)?),
+    })
+}
+
 pub trait PrivateKeyInfoExt {
     /// Generates a keypair
     ///
@@ -41,6 +86,12 @@ pub trait PrivateKeyInfoExt {
     /// Get the default signing algorithm for this `SubjectPublicKeyInfo`
     fn signs_with(&self) -> Result<AlgorithmIdentifier<'_>>;
 
+    /// Get the signing algorithm for this key using the specified digest
+    ///
+    /// RSA keys sign with RSASSA-PSS using any of SHA-256, SHA-384 or SHA-512.
+    /// Elliptic curve keys only accept the digest matching their curve size.
+    fn signs_with_digest(&self, digest: ObjectIdentifier) -> Result<AlgorithmIdentifier<'_>>;
+
     /// Signs the body with the specified algorithm
     ///
     /// Note that the signature is returned in its encoded form as it will
@@ -50,21 +101,61 @@ pub trait PrivateKeyInfoExt {
 
 impl<'a> PrivateKeyInfoExt for PrivateKeyInfo<'a> {
     fn generate(oid: ObjectIdentifier) -> Result<Zeroizing<Vec<u8>>> {
-        let rand = rand::thread_rng();
+        #[cfg(all(feature = "fips", not(target_os = "wasi")))]
+        if super::fips::enabled() {
+            return super::fips::generate(oid);
+        }
+
+        let mut rand = rand::thread_rng();
 
-        let doc = match oid {
-            P256 => p256::SecretKey::random(rand)
+        match oid {
+            P256 => Ok(p256::SecretKey::random(rand)
                 .to_pkcs8_der()
-                .map_err(|e| anyhow!("{:?}", e))?,
+                .map_err(|e| anyhow!("{:?}", e))?
+                .to_bytes()),
 
-            P384 => p384::SecretKey::random(rand)
+            P384 => Ok(p384::SecretKey::random(rand)
                 .to_pkcs8_der()
-                .map_err(|e| anyhow!("{:?}", e))?,
+                .map_err(|e| anyhow!("{:?}", e))?
+                .to_bytes()),
 
-            _ => bail!("unsupported"),
-        };
+            P521 => {
+                use p521::pkcs8::EncodePrivateKey;
+                Ok(p521::SecretKey::random(&mut rand)
+                    .to_pkcs8_der()
+                    .map_err(|e| anyhow!("{:?}", e))?
+                    .to_bytes())
+            }
+
+            // RSA keys are encoded as version 2 so that the public key is
+            // available without recomputing it from the private key.
+            RSA => {
+                use rsa::pkcs1::{EncodeRsaPrivateKey, EncodeRsaPublicKey};
+
+                let key = rsa::RsaPrivateKey::new(&mut rand, RSA_BITS)?;
+                let sk = key.to_pkcs1_der().map_err(|e| anyhow!("{:?}", e))?;
+                let pk = key
+                    .to_public_key()
+                    .to_pkcs1_der()
+                    .map_err(|e| anyhow!("{:?}", e))?;
+
+                let pki = PrivateKeyInfo {
+                    algorithm: AlgorithmIdentifier {
+                        oid: RSA,
+                        parameters: Some(AnyRef::NULL),
+                    },
+                    private_key: sk.as_bytes(),
+                    public_key: Some(pk.as_bytes()),
+                };
+
+                Ok(Zeroizing::new(pki.to_vec()?))
+            }
+
+            #[cfg(feature = "pqc")]
+            oid if super::pqc::is_pqc(oid) => super::pqc::generate(oid),
 
-        Ok(doc.to_bytes())
+            _ => bail!("unsupported"),
+        }
     }
 
     fn public_key(&self) -> Result<SubjectPublicKeyInfo<'_>> {
@@ -77,6 +168,28 @@ impl<'a> PrivateKeyInfoExt for PrivateKeyInfo<'a> {
                     subject_public_key: pk,
                 })
             }
+
+            (RSA, None) => {
+                let pk = self
+                    .public_key
+                    .ok_or_else(|| anyhow!("missing public key"))?;
+                Ok(SubjectPublicKeyInfo {
+                    algorithm: self.algorithm,
+                    subject_public_key: pk,
+                })
+            }
+
+            #[cfg(feature = "pqc")]
+            (oid, None) if super::pqc::is_pqc(oid) => {
+                let pk = self
+                    .public_key
+                    .ok_or_else(|| anyhow!("missing public key"))?;
+                Ok(SubjectPublicKeyInfo {
+                    algorithm: self.algorithm,
+                    subject_public_key: pk,
+                })
+            }
+
             _ => bail!("unsupported"),
         }
     }
@@ -85,15 +198,44 @@ impl<'a> PrivateKeyInfoExt for PrivateKeyInfo<'a> {
         match self.algorithm.oids()? {
             (ECPK, Some(P256)) => Ok(ES256),
             (ECPK, Some(P384)) => Ok(ES384),
+            (ECPK, Some(P521)) => Ok(ES512),
+            (RSA, None) => pss(PS256),
+            #[cfg(feature = "pqc")]
+            (oid, None) if super::pqc::is_pqc(oid) => super::pqc::algorithm(oid),
+            _ => bail!("unsupported"),
+        }
+    }
+
+    fn signs_with_digest(&self, digest: ObjectIdentifier) -> Result<AlgorithmIdentifier<'_>> {
+        match (self.algorithm.oids()?, digest) {
+            ((ECPK, Some(P256)), SHA256) => Ok(ES256),
+            ((ECPK, Some(P384)), SHA384) => Ok(ES384),
+            ((ECPK, Some(P521)), SHA512) => Ok(ES512),
+            ((RSA, None), SHA256) => pss(PS256),
+            ((RSA, None), SHA384) => pss(PS384),
+            ((RSA, None), SHA512) => pss(PS512),
             _ => bail!("unsupported"),
         }
     }
 
     fn sign(&self, body: &[u8], algo: AlgorithmIdentifier<'_>) -> Result<Vec<u8>> {
-        let ec = EcPrivateKey::from_der(self.private_key)?;
+        #[cfg(all(feature = "fips", not(target_os = "wasi")))]
+        if super::fips::enabled() {
+            return super::fips::sign(self, body, algo);
+        }
+
+        #[cfg(feature = "pqc")]
+        if super::pqc::is_pqc(self.algorithm.oid) {
+            if algo != super::pqc::algorithm(self.algorithm.oid)? {
+                bail!("unsupported");
+            }
+            return super::pqc::sign(algo.oid, self.private_key, body);
+        }
+
         match (self.algorithm.oids()?, algo) {
             ((ECPK, Some(P256)), ES256) => {
                 use p256::ecdsa::signature::Signer;
+                let ec = EcPrivateKey::from_der(self.private_key)?;
                 let private_key = p256::SecretKey::from_be_bytes(ec.private_key)?;
                 let sign_key = p256::ecdsa::SigningKey::from(private_key);
                 Ok(sign_key.sign(body).to_der().as_bytes().to_vec())
@@ -101,12 +243,173 @@ impl<'a> PrivateKeyInfoExt for PrivateKeyInfo<'a> {
 
             ((ECPK, Some(P384)), ES384) => {
                 use p384::ecdsa::signature::Signer;
+                let ec = EcPrivateKey::from_der(self.private_key)?;
                 let private_key = p384::SecretKey::from_be_bytes(ec.private_key)?;
                 let sign_key = p384::ecdsa::SigningKey::from(private_key);
                 Ok(sign_key.sign(body).to_der().as_bytes().to_vec())
             }
 
+            ((ECPK, Some(P521)), ES512) => {
+                use p521::ecdsa::signature::Signer;
+                let ec = EcPrivateKey::from_der(self.private_key)?;
+                let sign_key = p521::ecdsa::SigningKey::from_slice(ec.private_key)?;
+                let sig: p521::ecdsa::Signature = sign_key.sign(body);
+                Ok(sig.to_der().as_bytes().to_vec())
+            }
+
+            (
+                (RSA, None),
+                AlgorithmIdentifier {
+                    oid: ID_RSASSA_PSS,
+                    parameters: Some(p),
+                },
+            ) => {
+                use rsa::pkcs1::DecodeRsaPrivateKey;
+                use rsa::pss::SigningKey;
+                use signature::{RandomizedSigner, Signature};
+
+                let key = rsa::RsaPrivateKey::from_pkcs1_der(self.private_key)?;
+                let rand = rand::thread_rng();
+                let sign = match pss_digest(p)? {
+                    SHA256 => SigningKey::<sha2::Sha256>::new_with_salt_len(key, 32)
+                        .sign_with_rng(rand, body),
+                    SHA384 => SigningKey::<sha2::Sha384>::new_with_salt_len(key, 48)
+                        .sign_with_rng(rand, body),
+                    SHA512 => SigningKey::<sha2::Sha512>::new_with_salt_len(key, 64)
+                        .sign_with_rng(rand, body),
+                    _ => bail!("unsupported"),
+                };
+
+                Ok(sign.as_bytes().to_vec())
+            }
+
+            (
+                (RSA, None),
+                AlgorithmIdentifier {
+                    oid: oid @ (RS256 | RS384 | RS512),
+                    ..
+                },
+            ) => {
+                use rsa::pkcs1::DecodeRsaPrivateKey;
+
+                let key = rsa::RsaPrivateKey::from_pkcs1_der(self.private_key)?;
+                let padding = rsa::PaddingScheme::new_pkcs1v15_sign_raw();
+                Ok(key.sign(padding, &digest_info(oid, body)?)?)
+            }
+
             _ => bail!("unsupported"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::SubjectPublicKeyInfoExt;
+    use super::*;
+
+    fn roundtrip(oid: ObjectIdentifier) {
+        let key = PrivateKeyInfo::generate(oid).unwrap();
+        let pki = PrivateKeyInfo::from_der(key.as_ref()).unwrap();
+        let spki = pki.public_key().unwrap();
+
+        let algo = pki.signs_with().unwrap();
+        let sign = pki.sign(b"steward", algo).unwrap();
+        spki.verify(b"steward", algo, &sign).unwrap();
+        assert!(spki.verify(b"stewarD", algo, &sign).is_err());
+    }
+
+    #[test]
+    fn p256() {
+        roundtrip(P256);
+    }
+
+    #[test]
+    fn p384() {
+        roundtrip(P384);
+    }
+
+    #[test]
+    fn p521() {
+        roundtrip(P521);
+    }
+
+    #[test]
+    fn rsa_pss() {
+        let key = PrivateKeyInfo::generate(RSA).unwrap();
+        let pki = PrivateKeyInfo::from_der(key.as_ref()).unwrap();
+        let spki = pki.public_key().unwrap();
+
+        for digest in [SHA256, SHA384, SHA512] {
+            let algo = pki.signs_with_digest(digest).unwrap();
+            let sign = pki.sign(b"steward", algo).unwrap();
+            spki.verify(b"steward", algo, &sign).unwrap();
+        }
+
+        // A signature made with one digest must not verify with another.
+        let sign = pki.sign(b"steward", pss(PS384).unwrap()).unwrap();
+        assert!(spki.verify(b"steward", pss(PS512).unwrap(), &sign).is_err());
+    }
+
+    #[test]
+    fn rsa_pkcs1v15() {
+        let key = PrivateKeyInfo::generate(RSA).unwrap();
+        let pki = PrivateKeyInfo::from_der(key.as_ref()).unwrap();
+        let spki = pki.public_key().unwrap();
+
+        for oid in [RS256, RS384, RS512] {
+            let algo = AlgorithmIdentifier {
+                oid,
+                parameters: Some(AnyRef::NULL),
+            };
+            let sign = pki.sign(b"steward", algo).unwrap();
+            spki.verify(b"steward", algo, &sign).unwrap();
+            assert!(spki.verify(b"stewarD", algo, &sign).is_err());
+        }
+
+        // Nor is it mistaken for RSASSA-PSS.
+        let algo = AlgorithmIdentifier {
+            oid: RS256,
+            parameters: None,
+        };
+        let sign = pki.sign(b"steward", algo).unwrap();
+        assert!(spki.verify(b"steward", pss(PS256).unwrap(), &sign).is_err());
+    }
+
+    #[test]
+    fn mismatched_algorithms() {
+        let key = PrivateKeyInfo::generate(P256).unwrap();
+        let pki = PrivateKeyInfo::from_der(key.as_ref()).unwrap();
+        assert!(pki.sign(b"steward", ES384).is_err());
+        assert!(pki.sign(b"steward", pss(PS256).unwrap()).is_err());
+        assert!(pki.signs_with_digest(SHA384).is_err());
+
+        let key = PrivateKeyInfo::generate(P521).unwrap();
+        let pki = PrivateKeyInfo::from_der(key.as_ref()).unwrap();
+        assert!(pki.sign(b"steward", ES256).is_err());
+
+        // An ES256 signature is rejected when presented as ES512.
+        let key = PrivateKeyInfo::generate(P256).unwrap();
+        let pki = PrivateKeyInfo::from_der(key.as_ref()).unwrap();
+        let sign = pki.sign(b"steward", ES256).unwrap();
+        let spki = pki.public_key().unwrap();
+        assert!(spki.verify(b"steward", ES512, &sign).is_err());
+    }
+
+    #[test]
+    fn inconsistent_pss_params() {
+        // Use SHA-384 in MGF1 while the message digest is SHA-256.
+        let mut params = PS256.to_vec();
+        params[44] = 0x02;
+        assert!(pss_digest(AnyRef::from_der(&params).unwrap()).is_err());
+
+        // Use a 16 byte salt with SHA-256.
+        let mut params = PS256.to_vec();
+        params[49] = 0x10;
+        assert!(pss_digest(AnyRef::from_der(&params).unwrap()).is_err());
+
+        assert_eq!(
+            pss_digest(AnyRef::from_der(PS256).unwrap()).unwrap(),
+            SHA256
+        );
+    }
+}
@@ -0,0 +1,269 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Post-quantum ML-DSA (FIPS 204) signature support.
+//!
+//! Keys are carried in the usual PKCS#8 and SPKI containers using the NIST
+//! algorithm identifiers (with absent parameters). The PKCS#8 encoding holds
+//! the expanded private key plus the public key in the `publicKey` field so
+//! that `public_key()` needs no cryptographic computation.
+//!
+//! Hybrid keys pair ML-DSA with a classical algorithm, as composites in the
+//! encoding of the IETF LAMPS composite signatures draft: the public key and
+//! the signature are each a `SEQUENCE` of two `BIT STRING`s, ML-DSA first,
+//! and the private key is a `SEQUENCE` of the two components' PKCS#8. Both
+//! components sign the message as is, and both signatures must verify. The
+//! draft's identifiers are provisional, so may change before publication.
+
+use super::PrivateKeyInfoExt;
+
+use anyhow::{anyhow, bail, ensure, Result};
+use const_oid::db::rfc5912::{ECDSA_WITH_SHA_256, SECP_256_R_1};
+use const_oid::ObjectIdentifier;
+use der::asn1::BitStringRef;
+use der::{Decode, Encode};
+use fips204::traits::{SerDes, Signer, Verifier};
+use sec1::pkcs8::{AlgorithmIdentifier, PrivateKeyInfo};
+use zeroize::Zeroizing;
+
+pub const ML_DSA_44: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.3.17");
+pub const ML_DSA_65: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.3.18");
+pub const ML_DSA_87: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.3.19");
+
+/// ML-DSA-44 composed with ECDSA on P-256 using SHA-256.
+pub const ML_DSA_44_ECDSA_P256: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("2.16.840.1.114027.80.8.1.4");
+
+const ES256: AlgorithmIdentifier<'static> = AlgorithmIdentifier {
+    oid: ECDSA_WITH_SHA_256,
+    parameters: None,
+};
+
+/// Returns true if the oid names an ML-DSA parameter set.
+pub fn is_ml_dsa(oid: ObjectIdentifier) -> bool {
+    matches!(oid, ML_DSA_44 | ML_DSA_65 | ML_DSA_87)
+}
+
+/// Returns true if the oid names a composite of ML-DSA and a classical algorithm.
+pub fn is_composite(oid: ObjectIdentifier) -> bool {
+    oid == ML_DSA_44_ECDSA_P256
+}
+
+/// Returns true if the oid names either an ML-DSA or a composite algorithm.
+pub fn is_pqc(oid: ObjectIdentifier) -> bool {
+    is_ml_dsa(oid) || is_composite(oid)
+}
+
+/// Returns the signing algorithm for an ML-DSA or composite key.
+pub fn algorithm(oid: ObjectIdentifier) -> Result<AlgorithmIdentifier<'static>> {
+    if !is_pqc(oid) {
+        bail!("unsupported");
+    }
+
+    Ok(AlgorithmIdentifier {
+        oid,
+        parameters: None,
+    })
+}
+
+macro_rules! ml_dsa {
+    ($oid:expr, $op:ident) => {
+        match $oid {
+            ML_DSA_44 => $op!(ml_dsa_44),
+            ML_DSA_65 => $op!(ml_dsa_65),
+            ML_DSA_87 => $op!(ml_dsa_87),
+            _ => bail!("unsupported"),
+        }
+    };
+}
+
+/// Generates a keypair, returning the DER encoding of the `PrivateKeyInfo`.
+pub fn generate(oid: ObjectIdentifier) -> Result<Zeroizing<Vec<u8>>> {
+    if is_composite(oid) {
+        return generate_composite(oid);
+    }
+
+    macro_rules! keygen {
+        ($level:ident) => {{
+            let (pk, sk) = fips204::$level::try_keygen().map_err(|e| anyhow!("{e}"))?;
+            let pk = pk.into_bytes();
+            let sk = Zeroizing::new(sk.into_bytes());
+            let pki = PrivateKeyInfo {
+                algorithm: algorithm(oid)?,
+                private_key: sk.as_ref(),
+                public_key: Some(pk.as_ref()),
+            };
+            Ok(Zeroizing::new(pki.to_vec()?))
+        }};
+    }
+
+    ml_dsa!(oid, keygen)
+}
+
+/// Signs the body, returning the raw signature bytes.
+pub fn sign(oid: ObjectIdentifier, private_key: &[u8], body: &[u8]) -> Result<Vec<u8>> {
+    if is_composite(oid) {
+        return sign_composite(private_key, body);
+    }
+
+    macro_rules! sign {
+        ($level:ident) => {{
+            let bytes: Zeroizing<[u8; fips204::$level::SK_LEN]> = Zeroizing::new(
+                private_key
+                    .try_into()
+                    .map_err(|_| anyhow!("invalid private key length"))?,
+            );
+            let sk =
+                fips204::$level::PrivateKey::try_from_bytes(*bytes).map_err(|e| anyhow!("{e}"))?;
+            let sig = sk.try_sign(body, &[]).map_err(|e| anyhow!("{e}"))?;
+            Ok(sig.to_vec())
+        }};
+    }
+
+    ml_dsa!(oid, sign)
+}
+
+/// Verifies a raw signature over the body.
+pub fn verify(oid: ObjectIdentifier, public_key: &[u8], body: &[u8], sign: &[u8]) -> Result<()> {
+    if is_composite(oid) {
+        return verify_composite(public_key, body, sign);
+    }
+
+    macro_rules! verify {
+        ($level:ident) => {{
+            let pk: [u8; fips204::$level::PK_LEN] = public_key
+                .try_into()
+                .map_err(|_| anyhow!("invalid public key length"))?;
+            let sig: [u8; fips204::$level::SIG_LEN] = sign
+                .try_into()
+                .map_err(|_| anyhow!("invalid signature length"))?;
+            let pk = fips204::$level::PublicKey::try_from_bytes(pk).map_err(|e| anyhow!("{e}"))?;
+            if !pk.verify(body, &sig, &[]) {
+                bail!("invalid signature");
+            }
+            Ok(())
+        }};
+    }
+
+    ml_dsa!(oid, verify)
+}
+
+/// Generates a composite keypair of ML-DSA-44 and ECDSA P-256.
+fn generate_composite(oid: ObjectIdentifier) -> Result<Zeroizing<Vec<u8>>> {
+    let ml = generate(ML_DSA_44)?;
+    let ec = PrivateKeyInfo::generate(SECP_256_R_1)?;
+    let ml = PrivateKeyInfo::from_der(&ml)?;
+    let ec = PrivateKeyInfo::from_der(&ec)?;
+
+    let public = vec![
+        BitStringRef::from_bytes(ml.public_key()?.subject_public_key)?,
+        BitStringRef::from_bytes(ec.public_key()?.subject_public_key)?,
+    ]
+    .to_vec()?;
+    let private = Zeroizing::new(vec![ml, ec].to_vec()?);
+
+    let pki = PrivateKeyInfo {
+        algorithm: algorithm(oid)?,
+        private_key: &private,
+        public_key: Some(&public),
+    };
+    Ok(Zeroizing::new(pki.to_vec()?))
+}
+
+/// Signs the body with both components of a composite private key.
+fn sign_composite(private_key: &[u8], body: &[u8]) -> Result<Vec<u8>> {
+    let keys: Vec<PrivateKeyInfo<'_>> = Vec::from_der(private_key)?;
+    let (ml, ec) = match keys.as_slice() {
+        [ml, ec] if ml.algorithm.oid == ML_DSA_44 => (ml, ec),
+        _ => bail!("invalid composite private key"),
+    };
+
+    let ml = ml.sign(body, algorithm(ML_DSA_44)?)?;
+    let ec = ec.sign(body, ES256)?;
+    Ok(vec![
+        BitStringRef::from_bytes(&ml)?,
+        BitStringRef::from_bytes(&ec)?,
+    ]
+    .to_vec()?)
+}
+
+/// Verifies both components of a composite signature over the body.
+fn verify_composite(public_key: &[u8], body: &[u8], sign: &[u8]) -> Result<()> {
+    let keys: Vec<BitStringRef<'_>> = Vec::from_der(public_key)?;
+    let signs: Vec<BitStringRef<'_>> = Vec::from_der(sign)?;
+    ensure!(keys.len() == 2, "invalid composite public key");
+    ensure!(signs.len() == 2, "invalid composite signature");
+
+    fn bytes<'a>(bits: &BitStringRef<'a>) -> Result<&'a [u8]> {
+        bits.as_bytes()
+            .ok_or_else(|| anyhow!("invalid composite encoding"))
+    }
+
+    verify(ML_DSA_44, bytes(&keys[0])?, body, bytes(&signs[0])?)?;
+
+    use p256::ecdsa::signature::Verifier;
+    let vkey = p256::ecdsa::VerifyingKey::from_sec1_bytes(bytes(&keys[1])?)?;
+    let sig = p256::ecdsa::Signature::from_der(bytes(&signs[1])?)?;
+    Ok(vkey.verify(body, &sig)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::SubjectPublicKeyInfoExt;
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        for oid in [ML_DSA_44, ML_DSA_65, ML_DSA_87] {
+            let key = PrivateKeyInfo::generate(oid).unwrap();
+            let pki = PrivateKeyInfo::from_der(key.as_ref()).unwrap();
+
+            let algo = pki.signs_with().unwrap();
+            assert_eq!(algo.oid, oid);
+
+            let sign = pki.sign(b"steward", algo).unwrap();
+            let spki = pki.public_key().unwrap();
+            spki.verify(b"steward", algo, &sign).unwrap();
+            assert!(spki.verify(b"stewarD", algo, &sign).is_err());
+        }
+    }
+
+    #[test]
+    fn composite() {
+        let key = PrivateKeyInfo::generate(ML_DSA_44_ECDSA_P256).unwrap();
+        let pki = PrivateKeyInfo::from_der(key.as_ref()).unwrap();
+
+        let algo = pki.signs_with().unwrap();
+        assert_eq!(algo.oid, ML_DSA_44_ECDSA_P256);
+
+        let sign = pki.sign(b"steward", algo).unwrap();
+        let spki = pki.public_key().unwrap();
+        spki.verify(b"steward", algo, &sign).unwrap();
+        assert!(spki.verify(b"stewarD", algo, &sign).is_err());
+
+        // Both signatures must verify, so neither can be stripped or swapped.
+        let signs: Vec<BitStringRef<'_>> = Vec::from_der(&sign).unwrap();
+        let other = pki.sign(b"other", algo).unwrap();
+        let others: Vec<BitStringRef<'_>> = Vec::from_der(&other).unwrap();
+        for mixed in [
+            vec![signs[0], others[1]],
+            vec![others[0], signs[1]],
+            vec![signs[0]],
+        ] {
+            let mixed = mixed.to_vec().unwrap();
+            assert!(spki.verify(b"steward", algo, &mixed).is_err());
+        }
+    }
+
+    #[test]
+    fn mismatched_level() {
+        let key = PrivateKeyInfo::generate(ML_DSA_65).unwrap();
+        let pki = PrivateKeyInfo::from_der(key.as_ref()).unwrap();
+        let sign = pki.sign(b"steward", pki.signs_with().unwrap()).unwrap();
+
+        let spki = pki.public_key().unwrap();
+        assert!(spki
+            .verify(b"steward", algorithm(ML_DSA_87).unwrap(), &sign)
+            .is_err());
+    }
+}
@@ -1,20 +1,23 @@
 // SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
 // SPDX-License-Identifier: AGPL-3.0-only
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use const_oid::ObjectIdentifier;
 use der::{asn1::AnyRef, Sequence};
 use rsa::pkcs1::DecodeRsaPublicKey;
 use spki::{AlgorithmIdentifier, SubjectPublicKeyInfo};
 
 use const_oid::db::rfc5912::{
-    ECDSA_WITH_SHA_256, ECDSA_WITH_SHA_384, ID_EC_PUBLIC_KEY as ECPK, ID_MGF_1, ID_RSASSA_PSS,
-    ID_SHA_256 as SHA256, ID_SHA_384 as SHA384, ID_SHA_512 as SHA512, RSA_ENCRYPTION as RSA,
-    SECP_256_R_1 as P256, SECP_384_R_1 as P384,
+    ECDSA_WITH_SHA_256, ECDSA_WITH_SHA_384, ECDSA_WITH_SHA_512, ID_EC_PUBLIC_KEY as ECPK, ID_MGF_1,
+    ID_RSASSA_PSS, ID_SHA_256 as SHA256, ID_SHA_384 as SHA384, ID_SHA_512 as SHA512,
+    RSA_ENCRYPTION as RSA, SECP_256_R_1 as P256, SECP_384_R_1 as P384, SECP_521_R_1 as P521,
+    SHA_256_WITH_RSA_ENCRYPTION as RS256, SHA_384_WITH_RSA_ENCRYPTION as RS384,
+    SHA_512_WITH_RSA_ENCRYPTION as RS512,
 };
 
 const ES256: (ObjectIdentifier, Option<AnyRef<'static>>) = (ECDSA_WITH_SHA_256, None);
 const ES384: (ObjectIdentifier, Option<AnyRef<'static>>) = (ECDSA_WITH_SHA_384, None);
+const ES512: (ObjectIdentifier, Option<AnyRef<'static>>) = (ECDSA_WITH_SHA_512, None);
 
 #[derive(Clone, Debug, PartialEq, Eq, Sequence)]
 pub struct RsaSsaPssParams<'a> {
@@ -31,6 +34,76 @@ pub struct RsaSsaPssParams<'a> {
     trailer_field: u32,
 }
 
+/// Validates RSASSA-PSS parameters and returns the digest they use.
+///
+/// We only accept the parameter combinations recommended by RFC 4055: the
+/// same digest for the message and MGF1, a salt as long as the digest and
+/// the standard trailer field.
+pub(crate) fn pss_digest(params: AnyRef<'_>) -> Result<ObjectIdentifier> {
+    let RsaSsaPssParams {
+        hash_algorithm: hash,
+        mask_algorithm: mask,
+        salt_length: salt,
+        trailer_field: tfld,
+    } = params.decode_into()?;
+
+    // Validate the sanity of the mask algorithm.
+    let algo = match (mask.oid, mask.parameters) {
+        (ID_MGF_1, Some(p)) => {
+            let p = p.decode_into::<AlgorithmIdentifier<'_>>()?;
+            match (p.oids()?, salt, tfld) {
+                ((SHA256, None), 32, 1) => Ok(SHA256),
+                ((SHA384, None), 48, 1) => Ok(SHA384),
+                ((SHA512, None), 64, 1) => Ok(SHA512),
+                _ => Err(anyhow!("unsupported")),
+            }
+        }
+        _ => Err(anyhow!("unsupported")),
+    }?;
+
+    match hash.oids()? {
+        (hash, None) if hash == algo => Ok(algo),
+        _ => Err(anyhow!("unsupported")),
+    }
+}
+
+/// Returns the DER encoded `DigestInfo` of `body` which a PKCS #1 v1.5
+/// signature with `algo` signs (RFC 8017, section 9.2).
+///
+/// Steward itself signs with RSASSA-PSS, but legacy devices enrolling over
+/// SCEP only know PKCS #1 v1.5.
+pub(crate) fn digest_info(algo: ObjectIdentifier, body: &[u8]) -> Result<Vec<u8>> {
+    use sha2::Digest;
+
+    // The encoding of each `DigestInfo` up to the digest itself.
+    let (prefix, digest): (&[u8], Vec<u8>) = match algo {
+        RS256 => (
+            &[
+                0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+                0x01, 0x05, 0x00, 0x04, 0x20,
+            ],
+            sha2::Sha256::digest(body).to_vec(),
+        ),
+        RS384 => (
+            &[
+                0x30, 0x41, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+                0x02, 0x05, 0x00, 0x04, 0x30,
+            ],
+            sha2::Sha384::digest(body).to_vec(),
+        ),
+        RS512 => (
+            &[
+                0x30, 0x51, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+                0x03, 0x05, 0x00, 0x04, 0x40,
+            ],
+            sha2::Sha512::digest(body).to_vec(),
+        ),
+        _ => bail!("unsupported"),
+    };
+
+    Ok([prefix, &digest].concat())
+}
+
 pub trait SubjectPublicKeyInfoExt {
     /// Verifies a signature
     ///
@@ -44,6 +117,11 @@ pub trait SubjectPublicKeyInfoExt {
 
 impl<'a> SubjectPublicKeyInfoExt for SubjectPublicKeyInfo<'a> {
     fn verify(&self, body: &[u8], algo: AlgorithmIdentifier<'_>, sign: &[u8]) -> Result<()> {
+        #[cfg(all(feature = "fips", not(target_os = "wasi")))]
+        if super::fips::enabled() {
+            return super::fips::verify(self, body, algo, sign);
+        }
+
         match (self.algorithm.oids()?, (algo.oid, algo.parameters)) {
             ((ECPK, Some(P256)), ES256) => {
                 use p256::ecdsa::signature::Verifier;
@@ -59,43 +137,29 @@ impl<'a> SubjectPublicKeyInfoExt for SubjectPublicKeyInfo<'a> {
                 Ok(vkey.verify(body, &sig)?)
             }
 
+            ((ECPK, Some(P521)), ES512) => {
+                use p521::ecdsa::signature::Verifier;
+                let vkey = p521::ecdsa::VerifyingKey::from_sec1_bytes(self.subject_public_key)?;
+                let sig = p521::ecdsa::Signature::from_der(sign)?;
+                Ok(vkey.verify(body, &sig)?)
+            }
+
             ((RSA, None), (ID_RSASSA_PSS, Some(p))) => {
                 use signature::{Signature, Verifier};
-                // Decompose the RSA PSS parameters.
-                let RsaSsaPssParams {
-                    hash_algorithm: hash,
-                    mask_algorithm: mask,
-                    salt_length: salt,
-                    trailer_field: tfld,
-                } = p.decode_into()?;
 
                 let pkey = rsa::RsaPublicKey::from_pkcs1_der(self.subject_public_key)?;
                 let s = rsa::pss::Signature::from_bytes(sign)?;
 
-                // Validate the sanity of the mask algorithm.
-                let algo = match (mask.oid, mask.parameters) {
-                    (ID_MGF_1, Some(p)) => {
-                        let p = p.decode_into::<AlgorithmIdentifier<'_>>()?;
-                        match (p.oids()?, salt, tfld) {
-                            ((SHA256, None), 32, 1) => Ok(SHA256),
-                            ((SHA384, None), 48, 1) => Ok(SHA384),
-                            ((SHA512, None), 64, 1) => Ok(SHA512),
-                            _ => Err(anyhow!("unsupported")),
-                        }
-                    }
-                    _ => Err(anyhow!("unsupported")),
-                }?;
-
-                match (hash.oids()?, algo) {
-                    ((SHA256, None), SHA256) => {
+                match pss_digest(p)? {
+                    SHA256 => {
                         let vkey = rsa::pss::VerifyingKey::<sha2::Sha256>::new(pkey);
                         Ok(vkey.verify(body, &s)?)
                     }
-                    ((SHA384, None), SHA384) => {
+                    SHA384 => {
                         let vkey = rsa::pss::VerifyingKey::<sha2::Sha384>::new(pkey);
                         Ok(vkey.verify(body, &s)?)
                     }
-                    ((SHA512, None), SHA512) => {
+                    SHA512 => {
                         let vkey = rsa::pss::VerifyingKey::<sha2::Sha512>::new(pkey);
                         Ok(vkey.verify(body, &s)?)
                     }
@@ -103,6 +167,19 @@ impl<'a> SubjectPublicKeyInfoExt for SubjectPublicKeyInfo<'a> {
                 }
             }
 
+            ((RSA, None), (RS256 | RS384 | RS512, _)) => {
+                use rsa::PublicKey;
+
+                let pkey = rsa::RsaPublicKey::from_pkcs1_der(self.subject_public_key)?;
+                let padding = rsa::PaddingScheme::new_pkcs1v15_sign_raw();
+                Ok(pkey.verify(padding, &digest_info(algo.oid, body)?, sign)?)
+            }
+
+            #[cfg(feature = "pqc")]
+            ((oid, None), (sig, None)) if oid == sig && super::pqc::is_pqc(oid) => {
+                super::pqc::verify(oid, self.subject_public_key, body, sign)
+            }
+
             _ => Err(anyhow!("unsupported")),
         }
     }
@@ -0,0 +1,70 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Constant-time comparisons for the verifiers.
+//!
+//! Report data and measurements are compared byte for byte against values
+//! a client may be probing for. These helpers take the same time whatever
+//! the contents, so that neither the length of a matching prefix nor which
+//! allowlist entry matched shows in the response time. Lengths are not
+//! considered secret.
+
+use super::Digest;
+
+use std::collections::HashSet;
+
+use subtle::{Choice, ConstantTimeEq};
+
+/// Whether `a` and `b` are equal.
+pub fn eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+/// Whether `data` begins with `prefix`.
+pub fn starts_with(data: &[u8], prefix: &[u8]) -> bool {
+    match data.get(..prefix.len()) {
+        Some(head) => eq(head, prefix),
+        None => false,
+    }
+}
+
+/// Whether `set` contains `value`.
+///
+/// Every entry is compared, rather than hashing `value` and probing.
+pub fn contains<const N: usize>(set: &HashSet<Digest<N>>, value: &[u8; N]) -> bool {
+    set.iter()
+        .fold(Choice::from(0), |found, digest| {
+            found | digest.0[..].ct_eq(&value[..])
+        })
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal() {
+        assert!(eq(b"", b""));
+        assert!(eq(b"abc", b"abc"));
+        assert!(!eq(b"abc", b"abd"));
+        assert!(!eq(b"abc", b"ab"));
+    }
+
+    #[test]
+    fn prefix() {
+        assert!(starts_with(b"abcdef", b"abc"));
+        assert!(starts_with(b"abc", b""));
+        assert!(!starts_with(b"abcdef", b"abd"));
+        assert!(!starts_with(b"ab", b"abc"));
+    }
+
+    #[test]
+    fn allowlist() {
+        let set = HashSet::from([Digest([1, 2]), Digest([3, 4])]);
+        assert!(contains(&set, &[1, 2]));
+        assert!(contains(&set, &[3, 4]));
+        assert!(!contains(&set, &[1, 4]));
+        assert!(!contains(&HashSet::new(), &[1, 2]));
+    }
+}
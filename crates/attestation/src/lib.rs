@@ -1,7 +1,13 @@
 // SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
 // SPDX-License-Identifier: AGPL-3.0-only
 
+#[cfg(feature = "cove")]
+pub mod cove;
 pub mod crypto;
+pub mod ct;
+pub mod parse;
+#[cfg(feature = "se")]
+pub mod se;
 #[cfg(feature = "sgx")]
 pub mod sgx;
 #[cfg(feature = "snp")]
@@ -63,6 +69,32 @@ impl<const N: usize> Display for Digest<N> {
     }
 }
 
+/// Reads the DER certificates from the listed files, such as the roots of
+/// trust of a platform.
+#[cfg(any(feature = "cove", feature = "se"))]
+fn from_certificate_files<'de, D>(deserializer: D) -> Result<Vec<Vec<u8>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    use der::Decode;
+    use serde::de::Error;
+    use std::path::PathBuf;
+
+    let paths: Vec<PathBuf> = Deserialize::deserialize(deserializer)?;
+
+    let mut crts = Vec::with_capacity(paths.len());
+    for path in paths {
+        let der = std::fs::read(&path)
+            .map_err(|e| D::Error::custom(format!("failed to read {}: {e}", path.display())))?;
+        x509::Certificate::from_der(&der).map_err(|e| {
+            D::Error::custom(format!("invalid certificate {}: {e}", path.display()))
+        })?;
+        crts.push(der);
+    }
+
+    Ok(crts)
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize)]
 #[serde(deny_unknown_fields)]
 struct UnvalidatedMeasurements<const N: usize> {
@@ -0,0 +1,557 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Parsers for untrusted input, as pure functions over byte slices.
+//!
+//! These decode exactly what the verifiers decode before doing any
+//! cryptography, and are what the fuzz targets exercise.
+//!
+//! Certification requests come from anyone, so they are held to [`Limits`]
+//! which no legitimate request comes near, rejecting adversarial DER before
+//! it costs much time or memory.
+
+use anyhow::{bail, ensure, Result};
+use const_oid::db::rfc5280::ID_CE_SUBJECT_ALT_NAME;
+use const_oid::db::rfc5912::ID_EXTENSION_REQ;
+use der::{Decode, Encode};
+use serde::Deserialize;
+use x509::ext::pkix::SubjectAltName;
+use x509::request::{CertReq, CertReqInfo, ExtensionReq};
+
+/// Bounds on the shape of a certification request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// How deeply constructed values may nest.
+    pub depth: usize,
+
+    /// The most attributes in a request.
+    pub attributes: usize,
+
+    /// The most extensions requested, over all extension requests.
+    pub extensions: usize,
+
+    /// The most subject alternative names, over all requested extensions.
+    pub sans: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            depth: 32,
+            attributes: 16,
+            extensions: 64,
+            sans: 64,
+        }
+    }
+}
+
+impl Limits {
+    /// Checks how deeply the DER values in `bytes` nest, without decoding them.
+    pub fn check_depth(&self, bytes: &[u8]) -> Result<()> {
+        // The ends of the constructed values being walked, outermost first.
+        let mut ends = vec![bytes.len()];
+        let mut pos = 0;
+        while pos < bytes.len() {
+            while ends.last() == Some(&pos) {
+                ends.pop();
+            }
+
+            let (constructed, header, len) = header(&bytes[pos..])?;
+            let outer = ends.last().copied().unwrap_or(bytes.len());
+            let end = match pos.checked_add(header).and_then(|n| n.checked_add(len)) {
+                Some(end) if end <= outer => end,
+                _ => bail!("der value overruns its container"),
+            };
+
+            if constructed {
+                ensure!(ends.len() <= self.depth, "der nests too deeply");
+                ends.push(end);
+                pos += header;
+            } else {
+                pos = end;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the counts of attributes, extensions and names in `cr`.
+    pub fn check(&self, cr: &CertReq<'_>) -> Result<()> {
+        self.check_info(&cr.info)
+    }
+
+    /// Checks the counts of attributes, extensions and names in `info`, as
+    /// for requests synthesized from other protocols.
+    pub fn check_info(&self, info: &CertReqInfo<'_>) -> Result<()> {
+        let attributes = info.attributes.len();
+        ensure!(
+            attributes <= self.attributes,
+            "{attributes} attributes exceed the limit of {}",
+            self.attributes
+        );
+
+        let (mut extensions, mut sans) = (0, 0);
+        for attr in info.attributes.iter() {
+            if attr.oid != ID_EXTENSION_REQ {
+                continue;
+            }
+            for any in attr.values.iter() {
+                let ereq = Vec::from(any.decode_into::<ExtensionReq<'_>>()?);
+                extensions += ereq.len();
+                ensure!(
+                    extensions <= self.extensions,
+                    "requested extensions exceed the limit of {}",
+                    self.extensions
+                );
+
+                for ext in ereq.iter() {
+                    if ext.extn_id == ID_CE_SUBJECT_ALT_NAME {
+                        sans += SubjectAltName::from_der(ext.extn_value)?.0.len();
+                        ensure!(
+                            sans <= self.sans,
+                            "subject alternative names exceed the limit of {}",
+                            self.sans
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Decodes the identifier and length octets of a DER value, returning
+/// whether it is constructed, the length of the header and of the contents.
+fn header(bytes: &[u8]) -> Result<(bool, usize, usize)> {
+    match ber_header(bytes)? {
+        (_, header, Some(len)) => Ok((bytes[0] & 0x20 != 0, header, len)),
+        (.., None) => bail!("indefinite length is not der"),
+    }
+}
+
+/// Decodes the identifier and length octets of a BER value, returning the
+/// length of the identifier, of the header and of the contents, if definite.
+fn ber_header(bytes: &[u8]) -> Result<(usize, usize, Option<usize>)> {
+    let mut octets = bytes.iter().copied();
+    let tag = match octets.next() {
+        Some(tag) => tag,
+        None => bail!("truncated der header"),
+    };
+    let mut header = 1;
+
+    // High tag numbers continue while the top bit is set.
+    if tag & 0x1f == 0x1f {
+        loop {
+            header += 1;
+            match octets.next() {
+                Some(octet) if octet & 0x80 != 0 => ensure!(header < 6, "der tag is too long"),
+                Some(..) => break,
+                None => bail!("truncated der tag"),
+            }
+        }
+    }
+
+    let identifier = header;
+    header += 1;
+    let len = match octets.next() {
+        Some(len) if len < 0x80 => len.into(),
+        Some(0x80) => return Ok((identifier, header, None)),
+        Some(n @ 0x81..=0x84) => {
+            let mut len = 0usize;
+            for _ in 0..n & 0x7f {
+                header += 1;
+                match octets.next() {
+                    Some(octet) => len = (len << 8) | usize::from(octet),
+                    None => bail!("truncated der length"),
+                }
+            }
+            len
+        }
+        Some(..) => bail!("der length is too long"),
+        None => bail!("truncated der length"),
+    };
+
+    Ok((identifier, header, Some(len)))
+}
+
+/// Re-encodes the BER value at the start of `bytes` as DER onto `out`,
+/// returning how many bytes the value took up.
+fn reencode(bytes: &[u8], depth: usize, out: &mut Vec<u8>) -> Result<usize> {
+    ensure!(depth > 0, "ber nests too deeply");
+    let (identifier, header, len) = ber_header(bytes)?;
+    let constructed = bytes[0] & 0x20 != 0;
+
+    let mut contents = Vec::new();
+    let end = match len {
+        Some(len) => {
+            let end = match header.checked_add(len) {
+                Some(end) if end <= bytes.len() => end,
+                _ => bail!("ber value overruns its container"),
+            };
+            if constructed {
+                let mut pos = header;
+                while pos < end {
+                    pos += reencode(&bytes[pos..end], depth - 1, &mut contents)?;
+                }
+            } else if bytes[0] == 0x01 && len == 1 {
+                // Any octet but zero is true in BER; only 0xff is in DER.
+                contents.push(if bytes[header] == 0 { 0x00 } else { 0xff });
+            } else {
+                contents.extend_from_slice(&bytes[header..end]);
+            }
+            end
+        }
+
+        // Indefinite lengths run until an end-of-contents marker.
+        None => {
+            ensure!(constructed, "primitive ber value of indefinite length");
+            let mut pos = header;
+            loop {
+                match bytes.get(pos..pos + 2) {
+                    Some([0x00, 0x00]) => break pos + 2,
+                    Some(..) => pos += reencode(&bytes[pos..], depth - 1, &mut contents)?,
+                    None => bail!("unterminated indefinite length"),
+                }
+            }
+        }
+    };
+
+    // Lengths are in as few octets as possible.
+    out.extend_from_slice(&bytes[..identifier]);
+    match contents.len() {
+        len @ 0..=0x7f => out.push(len as u8),
+        len => {
+            let octets = len.to_be_bytes();
+            let zeros = octets.iter().take_while(|octet| **octet == 0).count();
+            out.push(0x80 | (octets.len() - zeros) as u8);
+            out.extend_from_slice(&octets[zeros..]);
+        }
+    }
+    out.extend_from_slice(&contents);
+    Ok(end)
+}
+
+/// How strictly certification requests are held to DER.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Strictness {
+    /// Whatever decodes as DER.
+    #[default]
+    Der,
+
+    /// Only canonical DER, which re-encodes to exactly the bytes received.
+    Strict,
+
+    /// BER with the quirks of common client stacks, normalized to DER
+    /// before decoding. See [`normalize`].
+    Compat,
+}
+
+/// Normalizes the BER value `bytes` to DER, within the depth of `limits`.
+///
+/// Tolerates the quirks of common client stacks: indefinite lengths,
+/// lengths in more octets than needed and booleans true other than 0xff.
+/// Anything else which is not DER is left for the decoder to reject.
+pub fn normalize(bytes: &[u8], limits: &Limits) -> Result<Vec<u8>> {
+    let mut der = Vec::with_capacity(bytes.len());
+    let used = reencode(bytes, limits.depth + 1, &mut der)?;
+    ensure!(used == bytes.len(), "trailing bytes after ber value");
+    Ok(der)
+}
+
+/// Checks that `bytes`, decoded as `value`, are its canonical encoding.
+pub fn canonical(value: &impl Encode, bytes: &[u8]) -> Result<()> {
+    ensure!(value.to_vec()? == bytes, "not canonical der");
+    Ok(())
+}
+
+/// Decodes a certification request, including any extension requests.
+pub fn cert_req(bytes: &[u8]) -> Result<CertReq<'_>> {
+    cert_req_with(bytes, &Limits::default())
+}
+
+/// Decodes a certification request within `limits`.
+pub fn cert_req_with<'a>(bytes: &'a [u8], limits: &Limits) -> Result<CertReq<'a>> {
+    limits.check_depth(bytes)?;
+    let cr = CertReq::from_der(bytes)?;
+    limits.check(&cr)?;
+    Ok(cr)
+}
+
+/// Decodes a bundle of certification requests.
+pub fn cert_reqs(bytes: &[u8]) -> Result<Vec<CertReq<'_>>> {
+    cert_reqs_with(bytes, &Limits::default())
+}
+
+/// Decodes a bundle of certification requests, each within `limits`.
+pub fn cert_reqs_with<'a>(bytes: &'a [u8], limits: &Limits) -> Result<Vec<CertReq<'a>>> {
+    // The bundle adds a level of nesting to each request.
+    let bundle = Limits {
+        depth: limits.depth + 1,
+        ..*limits
+    };
+    bundle.check_depth(bytes)?;
+    let crs: Vec<CertReq<'_>> = Vec::from_der(bytes)?;
+    for cr in &crs {
+        limits.check(cr)?;
+    }
+    Ok(crs)
+}
+
+/// Decodes an SGX quote and its certificate chain.
+#[cfg(feature = "sgx")]
+pub fn sgx_quote(bytes: &[u8]) -> Result<crate::sgx::quote::Quote<'_>> {
+    use crate::sgx::quote::traits::ParseBytes;
+    use anyhow::ensure;
+    use x509::Certificate;
+
+    let (quote, rest): (crate::sgx::quote::Quote<'_>, _) = bytes.parse()?;
+    ensure!(rest.is_empty(), "unknown trailing bytes in sgx quote");
+    for crt in quote.chain()? {
+        Certificate::from_der(crt)?;
+    }
+    Ok(quote)
+}
+
+/// Decodes SNP evidence, in either of the formats clients send it.
+#[cfg(feature = "snp")]
+pub fn snp_evidence(bytes: &[u8]) -> Result<crate::snp::Parts<'_>> {
+    use crate::snp::Report;
+    use anyhow::ensure;
+
+    let parts = crate::snp::Parts::decode(bytes)?;
+    ensure!(
+        parts.report.len() == std::mem::size_of::<Report>(),
+        "snp report is incorrect size"
+    );
+    Ok(parts)
+}
+
+/// Decodes RISC-V CoVE evidence, a certificate chain ending in the TVM.
+#[cfg(feature = "cove")]
+pub fn cove_evidence(bytes: &[u8]) -> Result<x509::PkiPath<'_>> {
+    use anyhow::ensure;
+
+    let path = x509::PkiPath::from_der(bytes)?;
+    ensure!(!path.is_empty(), "cove evidence is empty");
+    Ok(path)
+}
+
+/// Decodes IBM Secure Execution evidence.
+#[cfg(feature = "se")]
+pub fn se_evidence(bytes: &[u8]) -> Result<crate::se::Evidence<'_>> {
+    crate::se::Evidence::decode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn garbage() {
+        for bytes in [&[][..], &[0x30], &[0x30, 0x80], &[0xff; 64]] {
+            assert!(cert_req(bytes).is_err());
+            assert!(cert_reqs(bytes).is_err());
+            #[cfg(feature = "sgx")]
+            assert!(sgx_quote(bytes).is_err());
+            #[cfg(feature = "snp")]
+            assert!(snp_evidence(bytes).is_err());
+        }
+    }
+
+    /// A signed request for `exts`.
+    fn request(exts: Vec<x509::ext::Extension<'_>>) -> Vec<u8> {
+        use crate::crypto::{CertReqInfoExt, PrivateKeyInfoExt};
+        use const_oid::db::rfc5912::SECP_256_R_1;
+        use der::{asn1::AnyRef, Encode};
+        use sec1::pkcs8::PrivateKeyInfo;
+        use x509::attr::Attribute;
+        use x509::name::RdnSequence;
+        use x509::request::{CertReqInfo, Version};
+
+        let pki = PrivateKeyInfo::generate(SECP_256_R_1).unwrap();
+        let pki = PrivateKeyInfo::from_der(pki.as_ref()).unwrap();
+        let req = ExtensionReq::from(exts).to_vec().unwrap();
+        let attr = Attribute {
+            oid: ID_EXTENSION_REQ,
+            values: vec![AnyRef::from_der(&req).unwrap()].try_into().unwrap(),
+        };
+        let cri = CertReqInfo {
+            version: Version::V1,
+            subject: RdnSequence::default(),
+            public_key: pki.public_key().unwrap(),
+            attributes: vec![attr].try_into().unwrap(),
+        };
+        cri.sign(&pki).unwrap()
+    }
+
+    #[test]
+    fn limits() {
+        // Sequences nested a thousand deep, as fuzzing turns up.
+        let mut nested = vec![0x30, 0x00];
+        for _ in 0..1000 {
+            let len = (nested.len() as u32).to_be_bytes();
+            nested = [&[0x30, 0x84][..], &len, &nested].concat();
+        }
+        assert!(Limits::default().check_depth(&nested).is_err());
+        assert!(cert_req(&nested).is_err());
+        assert!(cert_reqs(&nested).is_err());
+
+        // Indefinite, overrunning and oversized lengths.
+        for bytes in [
+            &[0x30, 0x80, 0x00, 0x00][..],
+            &[0x30, 0x05, 0x02, 0x01, 0x00],
+            &[0x30, 0x03, 0x30, 0x04, 0x00],
+            &[0x30, 0x85, 0x01, 0x00, 0x00, 0x00, 0x00],
+            &[0x1f, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01, 0x00],
+        ] {
+            assert!(Limits::default().check_depth(bytes).is_err());
+        }
+
+        // A hundred subject alternative names.
+        use der::{asn1::Ia5StringRef, Encode};
+        use x509::ext::pkix::name::GeneralName;
+
+        let names: Vec<_> = (0..100).map(|i| format!("{i}.example.com")).collect();
+        let names = names
+            .iter()
+            .map(|name| GeneralName::DnsName(Ia5StringRef::new(name).unwrap()))
+            .collect();
+        let sans = SubjectAltName(names).to_vec().unwrap();
+        let der = request(vec![x509::ext::Extension {
+            extn_id: ID_CE_SUBJECT_ALT_NAME,
+            critical: false,
+            extn_value: &sans,
+        }]);
+        assert!(cert_req(&der).is_err());
+        assert!(cert_reqs(&vec![CertReq::from_der(&der).unwrap()].to_vec().unwrap()).is_err());
+
+        let roomy = Limits {
+            sans: 100,
+            ..Default::default()
+        };
+        assert!(cert_req_with(&der, &roomy).is_ok());
+        for limits in [
+            Limits { depth: 4, ..roomy },
+            Limits {
+                attributes: 0,
+                ..roomy
+            },
+            Limits {
+                extensions: 0,
+                ..roomy
+            },
+        ] {
+            assert!(cert_req_with(&der, &limits).is_err());
+        }
+    }
+
+    #[test]
+    fn normalized() {
+        // A request is its own normal form, and canonical.
+        let der = request(vec![]);
+        assert_eq!(normalize(&der, &Limits::default()).unwrap(), der);
+        canonical(&cert_req(&der).unwrap(), &der).unwrap();
+
+        // Overlong and indefinite lengths, and a boolean true of 0x01.
+        let cases: [(&[u8], &[u8]); 4] = [
+            (
+                &[0x30, 0x81, 0x03, 0x02, 0x01, 0x05],
+                &[0x30, 0x03, 0x02, 0x01, 0x05],
+            ),
+            (
+                &[0x30, 0x80, 0x05, 0x00, 0x00, 0x00],
+                &[0x30, 0x02, 0x05, 0x00],
+            ),
+            (
+                &[0x30, 0x03, 0x01, 0x01, 0x01],
+                &[0x30, 0x03, 0x01, 0x01, 0xff],
+            ),
+            (
+                &[0x30, 0x80, 0x30, 0x80, 0x00, 0x00, 0x00, 0x00],
+                &[0x30, 0x02, 0x30, 0x00],
+            ),
+        ];
+        for (ber, expected) in cases {
+            assert_eq!(normalize(ber, &Limits::default()).unwrap(), expected);
+        }
+
+        // A request with an overlong length decodes only once normalized,
+        // and is never canonical.
+        let (_, head, len) = header(&der).unwrap();
+        let mut ber = vec![der[0], 0x84];
+        ber.extend_from_slice(&(len as u32).to_be_bytes());
+        ber.extend_from_slice(&der[head..]);
+        let normal = normalize(&ber, &Limits::default()).unwrap();
+        assert_eq!(normal, der);
+        if let Ok(cr) = CertReq::from_der(&ber) {
+            assert!(canonical(&cr, &ber).is_err());
+        }
+
+        for bytes in [
+            &[][..],
+            &[0x30, 0x80, 0x05, 0x00],
+            &[0x04, 0x80, 0x00, 0x00],
+            &[0x30, 0x00, 0x00],
+            &[0x30, 0x05, 0x02, 0x01, 0x00],
+        ] {
+            assert!(normalize(bytes, &Limits::default()).is_err());
+        }
+
+        let mut nested = vec![0x30, 0x00];
+        for _ in 0..100 {
+            nested = [&[0x30, 0x80][..], &nested, &[0x00, 0x00]].concat();
+        }
+        assert!(normalize(&nested, &Limits::default()).is_err());
+    }
+
+    #[cfg(all(feature = "sgx", feature = "snp"))]
+    #[test]
+    fn canned() {
+        use crate::sgx::Sgx;
+        use crate::snp::Snp;
+
+        for csr in [
+            &include_bytes!("sgx/icelake.signed.crl.csr")[..],
+            &include_bytes!("snp/milan.signed.crl.csr")[..],
+        ] {
+            let cr = cert_req(csr).unwrap();
+            let attr = cr.info.attributes.iter().next().unwrap();
+            let ereq: ExtensionReq<'_> = attr.values.iter().next().unwrap().decode_into().unwrap();
+            for ext in Vec::from(ereq) {
+                match ext.extn_id {
+                    Sgx::OID => assert!(sgx_quote(ext.extn_value).is_ok()),
+                    Snp::OID => assert!(snp_evidence(ext.extn_value).is_ok()),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    #[cfg(all(feature = "sgx", feature = "snp"))]
+    #[test]
+    fn truncated() {
+        use crate::sgx::Sgx;
+        use crate::snp::Snp;
+
+        for csr in [
+            &include_bytes!("sgx/icelake.signed.crl.csr")[..],
+            &include_bytes!("snp/milan.signed.crl.csr")[..],
+        ] {
+            let cr = cert_req(csr).unwrap();
+            let attr = cr.info.attributes.iter().next().unwrap();
+            let ereq: ExtensionReq<'_> = attr.values.iter().next().unwrap().decode_into().unwrap();
+            for ext in Vec::from(ereq) {
+                let value = ext.extn_value;
+                for len in (0..value.len()).step_by(7) {
+                    match ext.extn_id {
+                        Sgx::OID => assert!(sgx_quote(&value[..len]).is_err()),
+                        Snp::OID => assert!(snp_evidence(&value[..len]).is_err()),
+                        _ => (),
+                    }
+                }
+            }
+        }
+    }
+}
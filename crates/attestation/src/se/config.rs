@@ -0,0 +1,83 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use super::super::Digest;
+
+use std::collections::HashSet;
+use std::fmt::{Debug, Formatter};
+use std::path::PathBuf;
+
+use serde::{de::Error, Deserialize, Deserializer};
+use zeroize::Zeroizing;
+
+#[derive(Clone, Deserialize, Debug, Default, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// DER encoded CA certificates, read from the listed files, which issue
+    /// IBM's host key signing keys, such as DigiCert's intermediate.
+    #[serde(deserialize_with = "crate::from_certificate_files")]
+    pub ca: Vec<Vec<u8>>,
+
+    /// DER encoded IBM Z host key signing key certificates, read from the
+    /// listed files, which must sign the host key documents.
+    #[serde(deserialize_with = "crate::from_certificate_files")]
+    pub signing_keys: Vec<Vec<u8>>,
+
+    /// The attestation requests which guests are measured with.
+    pub requests: Vec<Request>,
+
+    /// Approved images, by the tag of their Secure Execution header. Any
+    /// image may be attested if empty.
+    #[serde(default)]
+    pub images: HashSet<Digest<16>>,
+
+    /// Denied images, by the tag of their Secure Execution header.
+    #[serde(default)]
+    pub images_blacklist: HashSet<Digest<16>>,
+}
+
+/// An attestation request, which hosts answer with measurements keyed by
+/// its measurement key.
+#[derive(Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Request {
+    /// The 64 byte measurement key of the request, read from a file.
+    #[serde(deserialize_with = "from_key_file")]
+    pub measurement_key: MeasurementKey,
+
+    /// DER encoded host key documents, read from the listed files, of the
+    /// hosts the request was encrypted for.
+    #[serde(deserialize_with = "crate::from_certificate_files")]
+    pub host_key_documents: Vec<Vec<u8>>,
+}
+
+/// The key of the measurements answering a request, kept out of debug output.
+#[derive(Clone, Eq, PartialEq)]
+pub struct MeasurementKey(pub Zeroizing<Vec<u8>>);
+
+impl Debug for MeasurementKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MeasurementKey(..)")
+    }
+}
+
+fn from_key_file<'de, D>(deserializer: D) -> Result<MeasurementKey, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let path: PathBuf = Deserialize::deserialize(deserializer)?;
+
+    let key = std::fs::read(&path)
+        .map_err(|e| D::Error::custom(format!("failed to read {}: {e}", path.display())))?;
+    let key = Zeroizing::new(key);
+    if key.len() != super::KEY_SIZE {
+        return Err(D::Error::custom(format!(
+            "expected measurement key {} to have length of {}, got {}",
+            path.display(),
+            super::KEY_SIZE,
+            key.len()
+        )));
+    }
+
+    Ok(MeasurementKey(key))
+}
@@ -0,0 +1,518 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! IBM Secure Execution (SE) attestation, for confidential guests on s390x.
+//!
+//! The guest answers an attestation request, created by the verifier and
+//! encrypted for the host key documents of the hosts it may run on, by asking
+//! the Ultravisor for a measurement: an HMAC-SHA512, under the measurement
+//! key of the request, of the Secure Execution header of the image, the
+//! configuration unique ID of the guest, and data of its choosing. Only the
+//! Ultravisor of a host holding a private host key can decrypt the request,
+//! so a measurement which verifies under the key was taken by one of them.
+//!
+//! Host key documents are certified by IBM's host key signing keys, which
+//! are in turn issued by a CA. Steward holds the measurement keys of its
+//! requests, checks that the host key documents they were encrypted for are
+//! signed by IBM, and appraises the image by the tag of its header.
+//!
+//! ```text
+//! SeEvidence ::= SEQUENCE {
+//!     measurement OCTET STRING,       -- HMAC-SHA512
+//!     pageListDigest OCTET STRING,    -- from the SE header
+//!     addressListDigest OCTET STRING,
+//!     tweakListDigest OCTET STRING,
+//!     tag OCTET STRING,
+//!     configUid OCTET STRING,         -- from the Ultravisor
+//!     userData OCTET STRING,          -- starts with SHA-512 of the CSR SPKI
+//!     additionalData OCTET STRING,
+//! }
+//! ```
+
+pub mod config;
+
+use self::config::Config;
+use super::crypto::TbsCertificateExt;
+use super::ct;
+
+use std::time::SystemTime;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use const_oid::ObjectIdentifier;
+use der::asn1::OctetStringRef;
+use der::{Decode, Encode, Sequence};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha512};
+use x509::ext::Extension;
+use x509::{request::CertReqInfo, Certificate};
+
+/// The size of measurement keys and measurements.
+pub const KEY_SIZE: usize = 64;
+
+/// The most user data the Ultravisor measures.
+const USER_DATA_MAX: usize = 256;
+
+#[derive(Clone, Debug, Sequence)]
+pub struct Evidence<'a> {
+    pub measurement: OctetStringRef<'a>,
+    pub page_list_digest: OctetStringRef<'a>,
+    pub address_list_digest: OctetStringRef<'a>,
+    pub tweak_list_digest: OctetStringRef<'a>,
+    pub tag: OctetStringRef<'a>,
+    pub config_uid: OctetStringRef<'a>,
+    pub user_data: OctetStringRef<'a>,
+    pub additional_data: OctetStringRef<'a>,
+}
+
+impl<'a> Evidence<'a> {
+    /// Decodes the evidence, checking the size of each field.
+    pub fn decode(bytes: &'a [u8]) -> Result<Self> {
+        let evidence = Self::from_der(bytes).context("se evidence is malformed")?;
+
+        let sizes = [
+            (evidence.measurement.as_bytes(), KEY_SIZE, "measurement"),
+            (evidence.page_list_digest.as_bytes(), 64, "page list digest"),
+            (
+                evidence.address_list_digest.as_bytes(),
+                64,
+                "address list digest",
+            ),
+            (
+                evidence.tweak_list_digest.as_bytes(),
+                64,
+                "tweak list digest",
+            ),
+            (evidence.tag.as_bytes(), 16, "tag"),
+            (evidence.config_uid.as_bytes(), 16, "config uid"),
+        ];
+        for (field, size, name) in sizes {
+            ensure!(field.len() == size, "se {name} is incorrect size");
+        }
+        ensure!(
+            evidence.user_data.as_bytes().len() <= USER_DATA_MAX,
+            "se user data is too long"
+        );
+
+        Ok(evidence)
+    }
+
+    /// The measurement of this evidence under `key`.
+    ///
+    /// The Ultravisor measures the fields in order, with the length of the
+    /// user data as a big endian `u16` followed by six reserved bytes.
+    pub fn measure(&self, key: &[u8]) -> Result<[u8; KEY_SIZE]> {
+        let mut mac = Hmac::<Sha512>::new_from_slice(key).map_err(|e| anyhow!("{e}"))?;
+        mac.update(self.page_list_digest.as_bytes());
+        mac.update(self.address_list_digest.as_bytes());
+        mac.update(self.tweak_list_digest.as_bytes());
+        mac.update(self.tag.as_bytes());
+        mac.update(self.config_uid.as_bytes());
+        mac.update(&u16::try_from(self.user_data.as_bytes().len())?.to_be_bytes());
+        mac.update(&[0; 6]);
+        mac.update(self.user_data.as_bytes());
+        mac.update(self.additional_data.as_bytes());
+        Ok(mac.finalize().into_bytes()[..].try_into()?)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Se(());
+
+impl Se {
+    pub const OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.58270.1.5");
+    pub const ATT: bool = true;
+
+    /// The evidence in `ext`, which is only to be trusted once verified.
+    pub fn evidence<'a>(ext: &Extension<'a>) -> Result<Evidence<'a>> {
+        crate::parse::se_evidence(ext.extn_value)
+    }
+
+    /// Verifies that the host key document `hkd` is signed by one of the
+    /// host key signing keys, which are in turn issued by one of the CAs.
+    ///
+    /// IBM's signing keys may only sign documents, so host key documents are
+    /// verified as signed data rather than as certificates. Both must be
+    /// valid at `now`.
+    fn is_trusted(config: &Config, hkd: &[u8], now: SystemTime) -> Result<()> {
+        let hkd = Certificate::from_der(hkd)?;
+        Self::is_current(&hkd, "host key document", now)?;
+
+        let body = hkd.tbs_certificate.to_vec()?;
+        let signature = hkd
+            .signature
+            .as_bytes()
+            .ok_or_else(|| anyhow!("se host key document has invalid signature"))?;
+
+        for key in &config.signing_keys {
+            let key = Certificate::from_der(key)?;
+            if key.tbs_certificate.subject != hkd.tbs_certificate.issuer {
+                continue;
+            }
+            if Self::is_current(&key, "host key signing key", now).is_err() {
+                continue;
+            }
+
+            let issued = config.ca.iter().any(|ca| {
+                Certificate::from_der(ca)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|ca| ca.tbs_certificate.verify_crt(&key).map(|_| ()))
+                    .is_ok()
+            });
+            if !issued {
+                continue;
+            }
+
+            if key
+                .tbs_certificate
+                .verify_raw(&body, hkd.signature_algorithm, signature)
+                .is_ok()
+            {
+                return Ok(());
+            }
+        }
+
+        bail!("se host key document is not signed by a trusted signing key")
+    }
+
+    /// Checks that `crt`, described as `what`, is valid at `now`.
+    fn is_current(crt: &Certificate<'_>, what: &str, now: SystemTime) -> Result<()> {
+        let validity = &crt.tbs_certificate.validity;
+        ensure!(
+            validity.not_before.to_system_time() <= now,
+            "se {what} is not yet valid"
+        );
+        ensure!(
+            validity.not_after.to_system_time() >= now,
+            "se {what} has expired"
+        );
+        Ok(())
+    }
+
+    pub fn verify(
+        &self,
+        cri: &CertReqInfo<'_>,
+        ext: &Extension<'_>,
+        config: Option<&Config>,
+        dbg: bool,
+        now: SystemTime,
+    ) -> Result<bool> {
+        ensure!(!ext.critical, "se extension cannot be critical");
+
+        let config =
+            config.ok_or_else(|| anyhow!("se requires configured attestation requests"))?;
+        let evidence = Self::evidence(ext)?;
+
+        // Find the request the measurement answers. Every key is tried, so
+        // that the time taken does not say which one matched.
+        let mut answered = None;
+        for request in &config.requests {
+            let measurement = evidence.measure(&request.measurement_key.0)?;
+            if ct::eq(&measurement, evidence.measurement.as_bytes()) {
+                answered = Some(request);
+            }
+        }
+        let request = answered.ok_or_else(|| anyhow!("se measurement is invalid"))?;
+
+        // Only hosts certified by IBM may hold the keys of the request.
+        ensure!(
+            !request.host_key_documents.is_empty(),
+            "se request has no host key documents"
+        );
+        for hkd in &request.host_key_documents {
+            Self::is_trusted(config, hkd, now)?;
+        }
+
+        if !dbg {
+            // Validate that the certification request came from the guest.
+            let hash = Sha512::digest(cri.public_key.to_vec()?);
+            ensure!(
+                ct::starts_with(evidence.user_data.as_bytes(), &hash),
+                "se user data is invalid"
+            );
+        }
+
+        let tag: [u8; 16] = evidence.tag.as_bytes().try_into()?;
+        if !config.images.is_empty() {
+            ensure!(ct::contains(&config.images, &tag), "se untrusted image");
+        }
+        if !config.images_blacklist.is_empty() {
+            ensure!(
+                !ct::contains(&config.images_blacklist, &tag),
+                "se image is denied"
+            );
+        }
+
+        Ok(Self::ATT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::config::{MeasurementKey, Request};
+    use super::*;
+    use crate::crypto::PrivateKeyInfoExt;
+
+    use std::collections::HashSet;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use const_oid::db::rfc5912::{ID_CE_BASIC_CONSTRAINTS, ID_CE_KEY_USAGE, SECP_256_R_1};
+    use der::asn1::{GeneralizedTime, UIntRef};
+    use sec1::pkcs8::PrivateKeyInfo;
+    use spki::SubjectPublicKeyInfo;
+    use x509::ext::pkix::{BasicConstraints, KeyUsage, KeyUsages};
+    use x509::name::RdnSequence;
+    use x509::time::{Time, Validity};
+    use x509::TbsCertificate;
+    use zeroize::Zeroizing;
+
+    const KEY: [u8; KEY_SIZE] = [7; KEY_SIZE];
+
+    fn now() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+    }
+
+    /// Issues a certificate for `subject`, with `usage`, signed by `issuer`,
+    /// valid for an hour from `from`.
+    fn issue(
+        issuer: (&str, &PrivateKeyInfo<'_>),
+        subject: (&str, &PrivateKeyInfo<'_>),
+        usage: KeyUsage,
+        ca: bool,
+        from: SystemTime,
+    ) -> Vec<u8> {
+        let ku = usage.to_vec().unwrap();
+        let bc = BasicConstraints {
+            ca,
+            path_len_constraint: None,
+        }
+        .to_vec()
+        .unwrap();
+
+        let name = |dn: &str| {
+            let der = RdnSequence::encode_from_string(dn).unwrap();
+            RdnSequence::from_der(&der).unwrap().to_vec().unwrap()
+        };
+        let (issuer_name, subject_name) = (name(issuer.0), name(subject.0));
+
+        let validity = Validity {
+            not_before: Time::GeneralTime(GeneralizedTime::from_system_time(from).unwrap()),
+            not_after: Time::GeneralTime(
+                GeneralizedTime::from_system_time(from + Duration::from_secs(3600)).unwrap(),
+            ),
+        };
+
+        TbsCertificate {
+            version: x509::Version::V3,
+            serial_number: UIntRef::new(&[1]).unwrap(),
+            signature: issuer.1.signs_with().unwrap(),
+            issuer: RdnSequence::from_der(&issuer_name).unwrap(),
+            validity,
+            subject: RdnSequence::from_der(&subject_name).unwrap(),
+            subject_public_key_info: subject.1.public_key().unwrap(),
+            issuer_unique_id: None,
+            subject_unique_id: None,
+            extensions: Some(vec![
+                Extension {
+                    extn_id: ID_CE_KEY_USAGE,
+                    critical: true,
+                    extn_value: &ku,
+                },
+                Extension {
+                    extn_id: ID_CE_BASIC_CONSTRAINTS,
+                    critical: true,
+                    extn_value: &bc,
+                },
+            ]),
+        }
+        .sign(issuer.1)
+        .unwrap()
+    }
+
+    /// A CA, an IBM signing key it issued, and a host key document.
+    fn config() -> Config {
+        config_at(now())
+    }
+
+    /// A configuration as `config()`, but whose signing key is valid for an
+    /// hour from `signing`.
+    fn config_at(signing: SystemTime) -> Config {
+        let keys: Vec<_> = (0..3)
+            .map(|_| PrivateKeyInfo::generate(SECP_256_R_1).unwrap())
+            .collect();
+        let ca = ("CN=CA", &PrivateKeyInfo::from_der(&keys[0]).unwrap());
+        let ibm = (
+            "CN=IBM Z Host Key Signing Service",
+            &PrivateKeyInfo::from_der(&keys[1]).unwrap(),
+        );
+        let host = ("CN=host", &PrivateKeyInfo::from_der(&keys[2]).unwrap());
+
+        let sign = KeyUsage(KeyUsages::KeyCertSign | KeyUsages::CRLSign);
+        Config {
+            ca: vec![issue(ca, ca, sign, true, now())],
+            signing_keys: vec![issue(
+                ca,
+                ibm,
+                KeyUsage(KeyUsages::DigitalSignature.into()),
+                false,
+                signing,
+            )],
+            requests: vec![Request {
+                measurement_key: MeasurementKey(Zeroizing::new(KEY.to_vec())),
+                host_key_documents: vec![issue(
+                    ibm,
+                    host,
+                    KeyUsage(KeyUsages::KeyAgreement.into()),
+                    false,
+                    now(),
+                )],
+            }],
+            images: HashSet::new(),
+            images_blacklist: HashSet::new(),
+        }
+    }
+
+    /// Evidence bound to `spki`, measured under `key`.
+    fn evidence(spki: &SubjectPublicKeyInfo<'_>, key: &[u8], tag: &[u8; 16]) -> Vec<u8> {
+        let user_data = Sha512::digest(spki.to_vec().unwrap());
+        let mut evidence = Evidence {
+            measurement: OctetStringRef::new(&[0; KEY_SIZE]).unwrap(),
+            page_list_digest: OctetStringRef::new(&[1; 64]).unwrap(),
+            address_list_digest: OctetStringRef::new(&[2; 64]).unwrap(),
+            tweak_list_digest: OctetStringRef::new(&[3; 64]).unwrap(),
+            tag: OctetStringRef::new(tag).unwrap(),
+            config_uid: OctetStringRef::new(&[4; 16]).unwrap(),
+            user_data: OctetStringRef::new(&user_data).unwrap(),
+            additional_data: OctetStringRef::new(&[]).unwrap(),
+        };
+        let measurement = evidence.measure(key).unwrap();
+        evidence.measurement = OctetStringRef::new(&measurement).unwrap();
+        evidence.to_vec().unwrap()
+    }
+
+    /// Verifies evidence measured under `key`, bound to the key of the
+    /// request or, unless `bound`, to another.
+    fn verify(
+        config: Option<&Config>,
+        key: &[u8],
+        tag: &[u8; 16],
+        bound: bool,
+        dbg: bool,
+    ) -> Result<bool> {
+        verify_at(config, key, tag, bound, dbg, now())
+    }
+
+    /// Verifies evidence as `verify()` does, but at `now`.
+    fn verify_at(
+        config: Option<&Config>,
+        key: &[u8],
+        tag: &[u8; 16],
+        bound: bool,
+        dbg: bool,
+        now: SystemTime,
+    ) -> Result<bool> {
+        let keys: Vec<_> = (0..2)
+            .map(|_| PrivateKeyInfo::generate(SECP_256_R_1).unwrap())
+            .collect();
+        let pki = PrivateKeyInfo::from_der(&keys[0]).unwrap();
+        let other = PrivateKeyInfo::from_der(&keys[1]).unwrap();
+
+        let rdns = RdnSequence::encode_from_string("CN=guest").unwrap();
+        let cri = CertReqInfo {
+            version: x509::request::Version::V1,
+            subject: RdnSequence::from_der(&rdns).unwrap(),
+            public_key: pki.public_key().unwrap(),
+            attributes: Default::default(),
+        };
+
+        let spki = match bound {
+            true => pki.public_key().unwrap(),
+            false => other.public_key().unwrap(),
+        };
+        let der = evidence(&spki, key, tag);
+        let ext = Extension {
+            extn_id: Se::OID,
+            critical: false,
+            extn_value: &der,
+        };
+        Se::default().verify(&cri, &ext, config, dbg, now)
+    }
+
+    #[test]
+    fn measured() {
+        let config = config();
+        assert!(verify(Some(&config), &KEY, &[5; 16], true, false).unwrap());
+
+        // Another key measured it.
+        assert!(verify(Some(&config), &[8; KEY_SIZE], &[5; 16], true, false).is_err());
+
+        // Without a policy there is no key to check it with.
+        assert!(verify(None, &KEY, &[5; 16], true, true).is_err());
+    }
+
+    #[test]
+    fn unbound() {
+        let config = config();
+        assert!(verify(Some(&config), &KEY, &[5; 16], false, false).is_err());
+        assert!(verify(Some(&config), &KEY, &[5; 16], false, true).unwrap());
+    }
+
+    #[test]
+    fn images() {
+        let mut config = config();
+        config.images = HashSet::from([crate::Digest([5; 16])]);
+        assert!(verify(Some(&config), &KEY, &[5; 16], true, false).is_ok());
+        assert!(verify(Some(&config), &KEY, &[6; 16], true, false).is_err());
+
+        config.images.clear();
+        config.images_blacklist = HashSet::from([crate::Digest([6; 16])]);
+        assert!(verify(Some(&config), &KEY, &[5; 16], true, false).is_ok());
+        assert!(verify(Some(&config), &KEY, &[6; 16], true, false).is_err());
+    }
+
+    #[test]
+    fn untrusted() {
+        // The host key document is not signed by a configured signing key.
+        let mut config = config();
+        let signing_keys = std::mem::take(&mut config.signing_keys);
+        assert!(verify(Some(&config), &KEY, &[5; 16], true, false).is_err());
+
+        // Nor is the signing key issued by a configured CA.
+        config.signing_keys = signing_keys;
+        config.ca.clear();
+        assert!(verify(Some(&config), &KEY, &[5; 16], true, false).is_err());
+    }
+
+    #[test]
+    fn validity() {
+        let config = config();
+        let hour = Duration::from_secs(3600);
+        assert!(verify_at(Some(&config), &KEY, &[5; 16], true, false, now() + hour).is_ok());
+
+        // The host key document has expired, or is not yet valid.
+        let later = now() + 2 * hour;
+        assert!(verify_at(Some(&config), &KEY, &[5; 16], true, false, later).is_err());
+        let earlier = now() - hour;
+        assert!(verify_at(Some(&config), &KEY, &[5; 16], true, false, earlier).is_err());
+
+        // The signing key has expired, though the document has not.
+        let config = config_at(now() - 2 * hour);
+        assert!(verify(Some(&config), &KEY, &[5; 16], true, false).is_err());
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(Evidence::decode(&[]).is_err());
+
+        let short = Evidence {
+            measurement: OctetStringRef::new(&[0; 32]).unwrap(),
+            page_list_digest: OctetStringRef::new(&[1; 64]).unwrap(),
+            address_list_digest: OctetStringRef::new(&[2; 64]).unwrap(),
+            tweak_list_digest: OctetStringRef::new(&[3; 64]).unwrap(),
+            tag: OctetStringRef::new(&[5; 16]).unwrap(),
+            config_uid: OctetStringRef::new(&[4; 16]).unwrap(),
+            user_data: OctetStringRef::new(&[]).unwrap(),
+            additional_data: OctetStringRef::new(&[]).unwrap(),
+        };
+        assert!(Evidence::decode(&short.to_vec().unwrap()).is_err());
+    }
+}
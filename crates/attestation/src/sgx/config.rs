@@ -1,9 +1,13 @@
 // SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
 // SPDX-License-Identifier: AGPL-3.0-only
 
-use super::super::Measurements;
+use super::super::{Digest, Measurements};
 
-use serde::{Deserialize, Deserializer};
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use der::Decode;
+use serde::{de::Error, Deserialize, Deserializer};
 use sgx::parameters::{Features, MiscSelect};
 
 #[derive(Clone, Deserialize, Debug, Eq, PartialEq)]
@@ -20,6 +24,18 @@ pub enum SgxMiscSelect {
     EXINFO,
 }
 
+/// What verifies quotes.
+#[derive(Clone, Copy, Deserialize, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// The verification in this crate.
+    #[default]
+    Rust,
+
+    /// Intel's Quote Verification Library, see `super::qvl`.
+    Qvl,
+}
+
 #[derive(Clone, Deserialize, Debug, Default, Eq, PartialEq)]
 pub struct Config {
     /// Values for `mrsigner` in the report body, as `Measurements::signer()`
@@ -39,6 +55,24 @@ pub struct Config {
     #[serde(deserialize_with = "from_features")]
     pub features: Features,
 
+    /// Features which must be enabled, such as `KSS`.
+    /// Checked against `sgx::parameters::attributes::Attributes::features()`
+    #[serde(default)]
+    #[serde(deserialize_with = "from_feature_list")]
+    pub required_features: Features,
+
+    /// Features which must not be enabled, such as `ProvisioningKey`, which
+    /// lets an enclave derive the keys of the platform's provisioning.
+    /// Checked against `sgx::parameters::attributes::Attributes::features()`
+    #[serde(default)]
+    #[serde(deserialize_with = "from_feature_list")]
+    pub forbidden_features: Features,
+
+    /// The value the XFRM bits selected by the mask must have, constraining
+    /// the CPU extended state (e.g. AVX-512) the enclave may use.
+    /// Checked against `sgx::parameters::attributes::Attributes::xfrm()`
+    pub xfrm: Option<Masked>,
+
     /// Minimum value for `isv_svn`.
     /// Checked against `sgx::report::ReportBody::enclave_security_version()`
     /// This is the security version of the enclave.
@@ -53,15 +87,81 @@ pub struct Config {
     #[serde(default)]
     #[serde(deserialize_with = "from_misc_select")]
     pub misc_select: MiscSelect,
+
+    /// Values for `CONFIGID`, the configuration the enclave was launched with.
+    /// Requires the KSS feature.
+    #[serde(default)]
+    pub config_id: HashSet<Digest<64>>,
+
+    /// Minimum value for `CONFIGSVN`, the security version of the configuration.
+    /// Requires the KSS feature.
+    pub config_security_version: Option<u16>,
+
+    /// Values for `ISVEXTPRODID`, the extended product id of the enclave.
+    /// Requires the KSS feature.
+    #[serde(default)]
+    pub extended_product_id: HashSet<Digest<16>>,
+
+    /// Values for `ISVFAMILYID`, the product family of the enclave.
+    /// Requires the KSS feature.
+    #[serde(default)]
+    pub family_id: HashSet<Digest<16>>,
+
+    /// Values for the FMSPC of the PCK certificate, restricting issuance to
+    /// these classes of platform, e.g. Ice Lake server parts.
+    /// Checked against `super::pck::fmspc()`
+    #[serde(default)]
+    pub fmspc: HashSet<Digest<6>>,
+
+    /// What verifies quotes: `rust` (the default) or `qvl`, which requires
+    /// the `qvl` feature and fetches collateral itself. Either way the PCK
+    /// chain is checked against `roots` and `crls`.
+    #[serde(default)]
+    pub backend: Backend,
+
+    /// DER encoded CRLs, read from the listed files, checked against the PCK
+    /// chain in addition to those sent with the quote. This allows revocation
+    /// to be enforced with lists fetched out of band, e.g. on offline hosts.
+    #[serde(default)]
+    #[serde(deserialize_with = "from_crl_files")]
+    pub crls: Vec<Vec<u8>>,
+
+    /// DER root CA certificates which replace the pinned Intel SGX Root CA,
+    /// unless empty. Uploaded at runtime rather than configured.
+    #[serde(skip)]
+    pub roots: Vec<Vec<u8>>,
+}
+
+/// Bits of which those selected by `mask` must equal `value`.
+#[derive(Clone, Copy, Deserialize, Debug, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Masked {
+    pub value: u64,
+    pub mask: u64,
+}
+
+impl Masked {
+    /// Whether `bits` match under the mask.
+    pub fn matches(&self, bits: u64) -> bool {
+        bits & self.mask == self.value & self.mask
+    }
+}
+
+impl Config {
+    /// Whether any Key Separation and Sharing field is constrained.
+    pub fn requires_kss(&self) -> bool {
+        !self.config_id.is_empty()
+            || self.config_security_version.is_some()
+            || !self.extended_product_id.is_empty()
+            || !self.family_id.is_empty()
+    }
 }
 
 fn from_features<'de, D>(deserializer: D) -> Result<Features, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let s: Vec<SgxFeatures> = Deserialize::deserialize(deserializer)?;
-
-    let mut flags = Features::empty();
+    let mut flags = from_feature_list(deserializer)?;
 
     // Must be set according to Intel SGX documentation, this indicates permission
     // to create SGX enclaves.
@@ -70,6 +170,16 @@ where
     // Required by Enarx, as Wasmtime requires 64-bit, and modern systems are all 64-bit anyway
     flags |= Features::MODE64BIT;
 
+    Ok(flags)
+}
+
+fn from_feature_list<'de, D>(deserializer: D) -> Result<Features, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: Vec<SgxFeatures> = Deserialize::deserialize(deserializer)?;
+
+    let mut flags = Features::empty();
     for flag in s {
         match flag {
             SgxFeatures::CET => {
@@ -93,6 +203,24 @@ where
     Ok(flags)
 }
 
+fn from_crl_files<'de, D>(deserializer: D) -> Result<Vec<Vec<u8>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let paths: Vec<PathBuf> = Deserialize::deserialize(deserializer)?;
+
+    let mut crls = Vec::with_capacity(paths.len());
+    for path in paths {
+        let der = std::fs::read(&path)
+            .map_err(|e| D::Error::custom(format!("failed to read {}: {e}", path.display())))?;
+        x509::crl::CertificateList::from_der(&der)
+            .map_err(|e| D::Error::custom(format!("invalid crl {}: {e}", path.display())))?;
+        crls.push(der);
+    }
+
+    Ok(crls)
+}
+
 fn from_misc_select<'de, D>(deserializer: D) -> Result<MiscSelect, D::Error>
 where
     D: Deserializer<'de>,
@@ -115,8 +243,6 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::Digest;
-    use std::collections::HashSet;
 
     #[test]
     fn empty_config() {
@@ -139,6 +265,8 @@ mod tests {
 {SIGNER}
 features = ["Debug"]
 misc_select = ["EXINFO"]
+fmspc = ["00706e470000"]
+backend = "qvl"
 "#,
         ))
         .expect("Couldn't deserialize");
@@ -149,6 +277,64 @@ misc_select = ["EXINFO"]
             (Features::DEBUG | Features::INIT | Features::MODE64BIT).bits()
         );
         assert!(config.misc_select.contains(MiscSelect::EXINFO));
+        assert!(config.fmspc.contains(&[0x00, 0x70, 0x6e, 0x47, 0x00, 0x00]));
+        assert_eq!(config.backend, Backend::Qvl);
+    }
+
+    #[test]
+    fn kss() {
+        let config: Config = toml::from_str(
+            r#"
+signer = ["2eba0f494f428e799c22d6f12778aebea4dc8d991f9e63fd3cddd57ac6eb5dd9"]
+config_security_version = 2
+family_id = ["000102030405060708090a0b0c0d0e0f"]
+"#,
+        )
+        .expect("Couldn't deserialize");
+
+        assert!(config.requires_kss());
+        assert_eq!(config.config_security_version, Some(2));
+        assert!(config.family_id.contains(&[
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+            0x0e, 0x0f
+        ]));
+        assert!(config.config_id.is_empty());
+        assert!(config.fmspc.is_empty());
+
+        let config: Result<Config, _> = toml::from_str(
+            r#"
+signer = ["2eba0f494f428e799c22d6f12778aebea4dc8d991f9e63fd3cddd57ac6eb5dd9"]
+extended_product_id = ["0001"]
+"#,
+        );
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn attributes() {
+        let config: Config = toml::from_str(
+            r#"
+signer = ["2eba0f494f428e799c22d6f12778aebea4dc8d991f9e63fd3cddd57ac6eb5dd9"]
+required_features = ["KSS"]
+forbidden_features = ["ProvisioningKey", "EInitKey"]
+xfrm = { value = 0x3, mask = 0xe7 }
+"#,
+        )
+        .expect("Couldn't deserialize");
+
+        // Unlike `features`, these list exactly the named features.
+        assert_eq!(config.required_features, Features::KSS);
+        assert_eq!(
+            config.forbidden_features,
+            Features::PROVISIONING_KEY | Features::EINIT_KEY
+        );
+        assert!(config.features.is_empty());
+
+        let xfrm = config.xfrm.unwrap();
+        assert!(xfrm.matches(0x3));
+        assert!(xfrm.matches(0x1b));
+        assert!(!xfrm.matches(0x7));
+        assert!(!xfrm.matches(0x1));
     }
 
     #[test]
@@ -0,0 +1,83 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Key Separation and Sharing (KSS) fields of the SGX report body.
+//!
+//! These occupy space the `sgx` crate treats as reserved, so we read them
+//! from the raw report. They are only meaningful when the enclave was
+//! launched with the KSS attribute. See the Intel SDM, section 38.15.
+
+use std::mem::size_of;
+use std::slice::from_raw_parts;
+
+use sgx::ReportBody;
+
+const ISV_EXT_PROD_ID: usize = 32;
+const CONFIG_ID: usize = 192;
+const CONFIG_SVN: usize = 260;
+const ISV_FAMILY_ID: usize = 304;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Kss {
+    /// The extended product id of the enclave (`ISVEXTPRODID`).
+    pub extended_product_id: [u8; 16],
+
+    /// The configuration the enclave was launched with (`CONFIGID`).
+    pub config_id: [u8; 64],
+
+    /// The security version of the configuration (`CONFIGSVN`).
+    pub config_security_version: u16,
+
+    /// The product family of the enclave (`ISVFAMILYID`).
+    pub family_id: [u8; 16],
+}
+
+impl From<&ReportBody> for Kss {
+    fn from(report: &ReportBody) -> Self {
+        const _: () = assert!(size_of::<ReportBody>() == 384);
+
+        let raw = unsafe {
+            from_raw_parts(
+                report as *const ReportBody as *const u8,
+                size_of::<ReportBody>(),
+            )
+        };
+
+        let mut kss = Self {
+            extended_product_id: [0; 16],
+            config_id: [0; 64],
+            config_security_version: u16::from_le_bytes([raw[CONFIG_SVN], raw[CONFIG_SVN + 1]]),
+            family_id: [0; 16],
+        };
+        kss.extended_product_id
+            .copy_from_slice(&raw[ISV_EXT_PROD_ID..][..16]);
+        kss.config_id.copy_from_slice(&raw[CONFIG_ID..][..64]);
+        kss.family_id.copy_from_slice(&raw[ISV_FAMILY_ID..][..16]);
+        kss
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets() {
+        let mut raw = [0u8; 384];
+        raw[ISV_EXT_PROD_ID] = 1;
+        raw[CONFIG_ID + 63] = 2;
+        raw[CONFIG_SVN..][..2].copy_from_slice(&0x0304u16.to_le_bytes());
+        raw[ISV_FAMILY_ID + 15] = 5;
+
+        let report: ReportBody = unsafe { std::mem::transmute(raw) };
+        let kss = Kss::from(&report);
+        assert_eq!(kss.extended_product_id[0], 1);
+        assert_eq!(kss.config_id[63], 2);
+        assert_eq!(kss.config_security_version, 0x0304);
+        assert_eq!(kss.family_id[15], 5);
+
+        // Neighbouring fields are untouched.
+        assert_eq!(report.mrenclave, [0; 32]);
+        assert_eq!(report.reportdata, [0; 64]);
+    }
+}
@@ -1,22 +1,28 @@
 // SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
 // SPDX-License-Identifier: AGPL-3.0-only
 
-#![allow(unused_variables, unused_imports)] // temporary until CRL validation enabled
-
 pub mod config;
+pub mod kss;
+pub mod pck;
 pub mod quote;
+pub mod qvl;
 
 use crate::crypto::*;
+use crate::ct;
 use quote::traits::ParseBytes;
 
-use std::fmt::Debug;
+use crate::sgx::config::{Backend, Config};
+
+use std::time::SystemTime;
 
-use crate::sgx::config::Config;
-use anyhow::{bail, ensure, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use const_oid::ObjectIdentifier;
 use der::{Decode, Encode};
+use kss::Kss;
+use sgx::parameters::Features;
 use sha2::{Digest, Sha256};
-use x509::{ext::Extension, request::CertReqInfo, Certificate, PkiPath, TbsCertificate};
+use x509::crl::CertificateList;
+use x509::{ext::Extension, request::CertReqInfo, Certificate, TbsCertificate};
 
 #[derive(Clone, Debug)]
 pub struct Sgx([Certificate<'static>; 1]);
@@ -28,21 +34,87 @@ impl Default for Sgx {
 }
 
 impl Sgx {
-    const ROOT: &'static [u8] = include_bytes!("root.der");
+    /// The DER encoded Intel SGX Root CA certificate.
+    pub const ROOT: &'static [u8] = include_bytes!("root.der");
+
+    /// The SHA-256 hash of the DER encoded Intel SGX Root CA certificate.
+    const ROOT_HASH: [u8; 32] = [
+        0x44, 0xa0, 0x19, 0x6b, 0x2b, 0x99, 0xf8, 0x89, 0xb8, 0xe1, 0x49, 0x5e, 0x95, 0xb8, 0x0a,
+        0x35, 0x0e, 0x74, 0x24, 0x96, 0x43, 0x99, 0xe8, 0x85, 0xa7, 0xcb, 0xb8, 0xcc, 0xfa, 0xb6,
+        0x74, 0xd3,
+    ];
+
     pub const OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.58270.1.2");
     pub const ATT: bool = true;
 
+    /// When the collateral accompanying the quote in `ext` was issued.
+    pub fn issued(ext: &Extension<'_>) -> Result<Option<SystemTime>> {
+        let (quote, _): (quote::Quote<'_>, _) = ext.extn_value.parse()?;
+        Ok(quote.crls.latest_update())
+    }
+
+    /// The enclave report in `ext`, which is only to be trusted once verified.
+    pub fn report<'a>(ext: &Extension<'a>) -> Result<&'a sgx::ReportBody> {
+        let (quote, _): (quote::Quote<'a>, _) = ext.extn_value.parse()?;
+        Ok(quote.report())
+    }
+
+    /// Validates a root-first PCK certificate chain, returning the PCK.
+    ///
+    /// The chain must begin with the pinned Intel SGX Root CA and no
+    /// certificate in it may be revoked by the supplied CRLs, which must be
+    /// current at `now`.
     pub fn trusted<'c>(
         &'c self,
         chain: &'c [Certificate<'c>],
         crls: &'c CrlList<'c>,
+        now: SystemTime,
     ) -> Result<&'c TbsCertificate<'c>> {
-        let mut signer = &self.0[0].tbs_certificate;
-        for cert in self.0.iter().chain(chain.iter()) {
-            signer = signer.verify_crt(cert)?;
-        }
+        self.trusted_by(chain, crls, &[], now)
+    }
+
+    /// Validates a root-first PCK certificate chain as `trusted()` does, but
+    /// rooted in one of the DER `roots` in place of the pinned root, unless
+    /// there are none.
+    pub fn trusted_by<'c>(
+        &'c self,
+        chain: &'c [Certificate<'c>],
+        crls: &'c CrlList<'c>,
+        roots: &[Vec<u8>],
+        now: SystemTime,
+    ) -> Result<&'c TbsCertificate<'c>> {
+        let root = chain
+            .first()
+            .ok_or_else(|| anyhow!("sgx pck chain is empty"))?;
+        let der = root.to_vec()?;
+
+        let signer = if roots.is_empty() {
+            let hash = Sha256::digest(&der);
+            ensure!(
+                hash[..] == Self::ROOT_HASH,
+                "sgx pck chain has an untrusted root"
+            );
 
-        //PkiPath::from(chain).check_crl(crls)?;
+            let mut signer = &self.0[0].tbs_certificate;
+            for cert in self.0.iter().chain(chain.iter()) {
+                signer = signer.verify_crt(cert)?;
+            }
+            signer
+        } else {
+            let known = roots.iter().any(|r| *r == der);
+            ensure!(known, "sgx pck chain has an untrusted root");
+
+            let mut signer = root.tbs_certificate.verify_crt(root)?;
+            for cert in &chain[1..] {
+                signer = signer.verify_crt(cert)?;
+            }
+            signer
+        };
+
+        chain
+            .to_vec()
+            .check_crl(crls, now)
+            .context("sgx pck chain failed revocation checks")?;
 
         Ok(signer)
     }
@@ -53,12 +125,12 @@ impl Sgx {
         ext: &Extension<'_>,
         config: Option<&Config>,
         dbg: bool,
+        now: SystemTime,
     ) -> Result<bool> {
         ensure!(!ext.critical, "sgx extension cannot be critical");
 
         // Decode the quote.
-        let (quote, bytes): (quote::Quote<'_>, _) = ext.extn_value.parse()?;
-        ensure!(bytes.is_empty(), "unknown trailing bytes in sgx quote");
+        let quote = crate::parse::sgx_quote(ext.extn_value)?;
 
         // Parse the certificate chain.
         let chain = quote.chain()?;
@@ -67,9 +139,41 @@ impl Sgx {
             .map(|c| Certificate::from_der(c))
             .collect::<Result<Vec<_>, _>>()?;
 
-        // Validate the report.
-        let pck = self.trusted(&chain, &quote.crls)?;
-        let rpt = quote.verify(pck)?;
+        // Check the chain against the CRLs sent with the quote and any
+        // which the configuration supplies offline.
+        let offline = match config {
+            Some(config) => config
+                .crls
+                .iter()
+                .map(|der| CertificateList::from_der(der))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => Vec::new(),
+        };
+        let mut crls = quote.crls.clone();
+        crls.crls
+            .extend(offline.into_iter().map(|crl| CrlListEntry {
+                url: String::new(),
+                crl,
+            }));
+
+        // The chain is held to the configured roots and CRLs whichever
+        // backend validates the report, as the QVL knows of neither.
+        let roots = config.map_or(&[][..], |config| &config.roots);
+        let pck = self.trusted_by(&chain, &crls, roots, now)?;
+
+        // Validate the report, here or by the QVL.
+        let backend = config.map(|config| config.backend).unwrap_or_default();
+        let rpt = match backend {
+            Backend::Rust => quote.verify(pck)?,
+            Backend::Qvl => {
+                let verdict = qvl::verify(quote.raw(), now)?;
+                ensure!(
+                    verdict == qvl::Verdict::Ok,
+                    "sgx quote rejected by qvl: {verdict:?}"
+                );
+                quote.report()
+            }
+        };
 
         // Force certs to have the same key type as the PCK.
         //
@@ -94,24 +198,33 @@ impl Sgx {
             // Validate that the certification request came from an SGX enclave.
             let hash = Sha256::digest(cri.public_key.to_vec()?);
             ensure!(
-                hash.as_slice() == &rpt.reportdata[..hash.as_slice().len()],
+                ct::starts_with(&rpt.reportdata, &hash),
                 "sgx report data is invalid"
             );
         }
 
         if let Some(config) = config {
+            if !config.fmspc.is_empty() {
+                let fmspc = pck::fmspc(pck)?;
+                ensure!(
+                    config.fmspc.contains(&fmspc),
+                    "sgx untrusted platform class, fmspc {}",
+                    hex::encode(fmspc)
+                );
+            }
+
             if !config.measurements.signer.is_empty() {
-                let signed = config.measurements.signer.contains(&rpt.mrsigner);
+                let signed = ct::contains(&config.measurements.signer, &rpt.mrsigner);
                 ensure!(signed, "sgx untrusted enarx signer");
             }
 
             if !config.measurements.hash.is_empty() {
-                let approved = config.measurements.hash.contains(&rpt.mrenclave);
+                let approved = ct::contains(&config.measurements.hash, &rpt.mrenclave);
                 ensure!(approved, "sgx untrusted enarx hash");
             }
 
             if !config.measurements.hash_blacklist.is_empty() {
-                let denied = config.measurements.hash_blacklist.contains(&rpt.mrenclave);
+                let denied = ct::contains(&config.measurements.hash_blacklist, &rpt.mrenclave);
                 ensure!(!denied, "sgx untrusted enarx hash");
             }
 
@@ -139,14 +252,195 @@ impl Sgx {
                 bail!("sgx untrusted features");
             }
 
+            let features = rpt.attributes().features();
+            let missing = config.required_features.difference(features);
+            ensure!(
+                missing.is_empty(),
+                "sgx required features {missing:?} not enabled"
+            );
+
+            let forbidden = config.forbidden_features.intersection(features);
+            ensure!(
+                forbidden.is_empty(),
+                "sgx forbidden features {forbidden:?} enabled"
+            );
+
+            if let Some(xfrm) = config.xfrm {
+                let bits = rpt.attributes().xfrm().bits();
+                ensure!(
+                    xfrm.matches(bits),
+                    "sgx xfrm {bits:#x} does not match {:#x} under mask {:#x}",
+                    xfrm.value,
+                    xfrm.mask
+                );
+            }
+
             if !config.misc_select.is_empty() {
                 ensure!(
                     rpt.misc_select().difference(config.misc_select).is_empty(),
                     "sgx untrusted misc select"
                 );
             }
+
+            if config.requires_kss() {
+                ensure!(
+                    rpt.attributes().features().contains(Features::KSS),
+                    "sgx kss policy configured but kss not enabled"
+                );
+                let kss = Kss::from(rpt);
+
+                if !config.config_id.is_empty() {
+                    let approved = ct::contains(&config.config_id, &kss.config_id);
+                    ensure!(approved, "sgx untrusted config id");
+                }
+
+                if let Some(version) = config.config_security_version {
+                    ensure!(
+                        kss.config_security_version >= version,
+                        "sgx untrusted config security version"
+                    );
+                }
+
+                if !config.extended_product_id.is_empty() {
+                    let approved =
+                        ct::contains(&config.extended_product_id, &kss.extended_product_id);
+                    ensure!(approved, "sgx untrusted extended product id");
+                }
+
+                if !config.family_id.is_empty() {
+                    let approved = ct::contains(&config.family_id, &kss.family_id);
+                    ensure!(approved, "sgx untrusted family id");
+                }
+            }
         }
 
         Ok(false)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use x509::request::{CertReq, ExtensionReq};
+
+    const ICELAKE_CSR: &[u8] = include_bytes!("icelake.signed.crl.csr");
+
+    #[test]
+    fn root_pinned() {
+        assert_eq!(Sha256::digest(Sgx::ROOT)[..], Sgx::ROOT_HASH);
+    }
+
+    #[test]
+    fn chain() {
+        let csr = CertReq::from_der(ICELAKE_CSR).unwrap();
+        let attr = csr.info.attributes.iter().next().unwrap();
+        let any = attr.values.iter().next().unwrap();
+        let ereq: ExtensionReq<'_> = any.decode_into().unwrap();
+        let ext = Vec::from(ereq).remove(0);
+        let (quote, _): (quote::Quote<'_>, _) = ext.extn_value.parse().unwrap();
+        let chain = quote
+            .chain()
+            .unwrap()
+            .iter()
+            .map(|c| Certificate::from_der(c))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        let sgx = Sgx::default();
+        let now = quote.crls.latest_update().unwrap();
+        sgx.trusted(&chain, &quote.crls, now).unwrap();
+
+        // The chain must start at the pinned root.
+        assert!(sgx.trusted(&chain[1..], &quote.crls, now).is_err());
+
+        // Revocation checks require CRLs, which must be current.
+        let empty = CrlList { crls: Vec::new() };
+        assert!(sgx.trusted(&chain, &empty, now).is_err());
+        let expired = SystemTime::now();
+        assert!(sgx.trusted(&chain, &quote.crls, expired).is_err());
+    }
+
+    #[test]
+    fn qvl_checks_chain() {
+        let csr = CertReq::from_der(ICELAKE_CSR).unwrap();
+        let attr = csr.info.attributes.iter().next().unwrap();
+        let ereq: ExtensionReq<'_> = attr.values.iter().next().unwrap().decode_into().unwrap();
+        let ext = Vec::from(ereq).remove(0);
+        let now = crate::parse::sgx_quote(ext.extn_value)
+            .unwrap()
+            .crls
+            .latest_update()
+            .unwrap();
+
+        // The chain is refused before the quote reaches the QVL.
+        let config = Config {
+            backend: Backend::Qvl,
+            roots: vec![b"another root".to_vec()],
+            ..Default::default()
+        };
+        let e = Sgx::default()
+            .verify(&csr.info, &ext, Some(&config), false, now)
+            .unwrap_err();
+        assert!(e.to_string().contains("untrusted root"), "{e:#}");
+
+        // As are expired CRLs.
+        let config = Config {
+            backend: Backend::Qvl,
+            ..Default::default()
+        };
+        let e = Sgx::default()
+            .verify(&csr.info, &ext, Some(&config), false, SystemTime::now())
+            .unwrap_err();
+        assert!(format!("{e:#}").contains("expired"), "{e:#}");
+    }
+
+    /// Compares the verdicts of both backends on the canned quote, and on it
+    /// with its enclave measurement altered.
+    #[cfg(all(feature = "qvl", target_os = "linux"))]
+    #[test]
+    #[ignore = "needs the QVL library and a PCCS on the host"]
+    fn backends() {
+        let mut tampered = ICELAKE_CSR.to_vec();
+        let csr = CertReq::from_der(ICELAKE_CSR).unwrap();
+        let attr = csr.info.attributes.iter().next().unwrap();
+        let ereq: ExtensionReq<'_> = attr.values.iter().next().unwrap().decode_into().unwrap();
+        let ext = Vec::from(ereq).remove(0);
+        let raw = crate::parse::sgx_quote(ext.extn_value).unwrap().raw();
+        let at = ICELAKE_CSR
+            .windows(raw.len())
+            .position(|w| w == raw)
+            .unwrap();
+        tampered[at + 48 + 64] ^= 1; // The first byte of MRENCLAVE.
+
+        for (csr, genuine) in [(ICELAKE_CSR, true), (&tampered[..], false)] {
+            let csr = CertReq::from_der(csr).unwrap();
+            let attr = csr.info.attributes.iter().next().unwrap();
+            let ereq: ExtensionReq<'_> = attr.values.iter().next().unwrap().decode_into().unwrap();
+            let ext = Vec::from(ereq).remove(0);
+            let quote = crate::parse::sgx_quote(ext.extn_value).unwrap();
+            let chain = quote
+                .chain()
+                .unwrap()
+                .iter()
+                .map(|c| Certificate::from_der(c))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+
+            let sgx = Sgx::default();
+            let now = quote.crls.latest_update().unwrap();
+            let rust = sgx
+                .trusted(&chain, &quote.crls, now)
+                .and_then(|pck| quote.verify(pck))
+                .is_ok();
+            assert_eq!(rust, genuine);
+
+            let authentic = match qvl::verify(quote.raw(), now) {
+                Ok(verdict) => verdict.authentic(),
+                Err(e) if genuine => panic!("qvl failed: {e:#}"),
+                Err(..) => false,
+            };
+            assert_eq!(authentic, rust);
+        }
+    }
+}
@@ -0,0 +1,79 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! The SGX extension of PCK certificates.
+//!
+//! Intel names the platform a PCK certificate was issued to in an extension
+//! holding a sequence of identified values. Of these we read the FMSPC, the
+//! Family-Model-Stepping-Platform-CustomSKU which identifies the class of
+//! platform, such as an Ice Lake server part. See the Intel SGX PCK
+//! Certificate and CRL Profile, section 1.5.
+
+use anyhow::{anyhow, Context, Result};
+use const_oid::ObjectIdentifier;
+use der::asn1::{AnyRef, OctetStringRef};
+use der::{Decode, Sequence};
+use x509::TbsCertificate;
+
+/// The extension of PCK certificates holding the SGX values.
+const SGX_EXTENSIONS: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113741.1.13.1");
+
+/// The FMSPC among the SGX values.
+const FMSPC: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113741.1.13.1.4");
+
+#[derive(Clone, Debug, Sequence)]
+struct SgxValue<'a> {
+    id: ObjectIdentifier,
+    value: AnyRef<'a>,
+}
+
+/// The FMSPC of the platform the PCK certificate `pck` was issued to.
+pub fn fmspc(pck: &TbsCertificate<'_>) -> Result<[u8; 6]> {
+    let ext = pck
+        .extensions
+        .iter()
+        .flatten()
+        .find(|ext| ext.extn_id == SGX_EXTENSIONS)
+        .ok_or_else(|| anyhow!("sgx pck has no sgx extension"))?;
+    let values = Vec::<SgxValue<'_>>::from_der(ext.extn_value).context("sgx pck extension")?;
+    let fmspc = values
+        .iter()
+        .find(|value| value.id == FMSPC)
+        .ok_or_else(|| anyhow!("sgx pck has no fmspc"))?;
+    let fmspc: OctetStringRef<'_> = fmspc.value.decode_into()?;
+    fmspc
+        .as_bytes()
+        .try_into()
+        .context("sgx pck fmspc is incorrect size")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::quote::{traits::ParseBytes, Quote};
+    use super::*;
+
+    use x509::request::{CertReq, ExtensionReq};
+    use x509::Certificate;
+
+    const ICELAKE_CSR: &[u8] = include_bytes!("icelake.signed.crl.csr");
+
+    #[test]
+    fn icelake() {
+        let csr = CertReq::from_der(ICELAKE_CSR).unwrap();
+        let attr = csr.info.attributes.iter().next().unwrap();
+        let any = attr.values.iter().next().unwrap();
+        let ereq: ExtensionReq<'_> = any.decode_into().unwrap();
+        let ext = Vec::from(ereq).remove(0);
+        let (quote, _): (Quote<'_>, _) = ext.extn_value.parse().unwrap();
+        let chain = quote.chain().unwrap();
+        let pck = Certificate::from_der(chain.last().unwrap()).unwrap();
+        assert_eq!(
+            fmspc(&pck.tbs_certificate).unwrap(),
+            [0, 0x70, 0x6e, 0x47, 0, 0]
+        );
+
+        // Only PCK certificates carry the extension.
+        let root = Certificate::from_der(&chain[0]).unwrap();
+        assert!(fmspc(&root.tbs_certificate).is_err());
+    }
+}
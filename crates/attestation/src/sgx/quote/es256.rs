@@ -55,8 +55,8 @@ impl<'a> FromBytes<'a> for Signature<'a> {
         let (r, bytes): (&[u8; 32], _) = bytes.parse()?;
         let (s, bytes): (&[u8; 32], _) = bytes.parse()?;
         let sv = Self {
-            r: UIntRef::new(r).unwrap(),
-            s: UIntRef::new(s).unwrap(),
+            r: UIntRef::new(r)?,
+            s: UIntRef::new(s)?,
         };
 
         Ok((sv, bytes))
@@ -77,7 +77,7 @@ impl<'a> FromBytes<'a> for SignatureData<'a> {
 
     fn from_bytes(bytes: &'a [u8]) -> Result<(Self, &'a [u8]), Self::Error> {
         let (len, bytes) = bytes.parse()?;
-        let len = u32::from_le_bytes(len).try_into().unwrap();
+        let len = usize::try_from(u32::from_le_bytes(len))?;
         if len > bytes.len() {
             return Err(anyhow!("invalid signature data length"));
         }
@@ -15,15 +15,18 @@ pub mod es256;
 pub mod qe;
 pub mod traits;
 
-use super::super::crypto::{CrlList, TbsCertificateExt};
+use super::super::crypto::{CrlList, SubjectPublicKeyInfoExt, TbsCertificateExt};
+use super::super::ct;
 use body::Body;
 use traits::{FromBytes, ParseBytes, Steal};
 
 use anyhow::anyhow;
+use const_oid::db::rfc5912::{ECDSA_WITH_SHA_256, ID_EC_PUBLIC_KEY, SECP_256_R_1};
+use der::asn1::AnyRef;
 use der::{Decode, Encode, Sequence};
-use p256::ecdsa::signature::Verifier;
 use sgx::ReportBody;
 use sha2::{digest::DynDigest, Sha256};
+use spki::{AlgorithmIdentifier, SubjectPublicKeyInfo};
 use x509::TbsCertificate;
 
 #[derive(Sequence)]
@@ -34,6 +37,7 @@ struct SgxEvidence<'a> {
 }
 
 pub struct Quote<'a> {
+    raw: &'a [u8],
     body: &'a Body,
     sign: es256::SignatureData<'a>,
     pub crls: CrlList<'a>,
@@ -60,9 +64,18 @@ impl<'a> FromBytes<'a> for Quote<'a> {
         }
 
         let (sign, bytes) = bytes.parse()?;
+        let raw = evidence.quote;
         let crls = evidence.crl;
 
-        Ok((Quote { body, sign, crls }, bytes))
+        Ok((
+            Quote {
+                raw,
+                body,
+                sign,
+                crls,
+            },
+            bytes,
+        ))
     }
 }
 
@@ -73,6 +86,16 @@ impl<'a> Quote<'a> {
         }
     }
 
+    /// The quote as the platform produced it, without the CRLs sent with it.
+    pub fn raw(&self) -> &'a [u8] {
+        self.raw
+    }
+
+    /// The enclave report, unverified.
+    pub fn report(&self) -> &'a ReportBody {
+        &self.body.report
+    }
+
     pub fn verify(&self, pck: &TbsCertificate<'_>) -> anyhow::Result<&'a ReportBody> {
         // Validate the QE report.
         pck.verify_raw(
@@ -87,14 +110,23 @@ impl<'a> Quote<'a> {
         hash.update(self.sign.key.as_ref());
         hash.update(self.sign.iqe.auth.as_ref());
         hash.finalize_into(&mut data[..32])?;
-        if data != self.sign.iqe.rprt.reportdata {
+        if !ct::eq(&data, &self.sign.iqe.rprt.reportdata) {
             return Err(anyhow!("untrusted ecdsa attestation key"));
         }
 
         // Verify the signature on the enclave report.
-        let vkey = p256::ecdsa::VerifyingKey::from_sec1_bytes(self.sign.key.sec1())?;
-        let sig = p256::ecdsa::Signature::from_der(&self.sign.sig.to_vec()?)?;
-        vkey.verify(self.body.as_ref(), &sig)?;
+        let akey = SubjectPublicKeyInfo {
+            algorithm: AlgorithmIdentifier {
+                oid: ID_EC_PUBLIC_KEY,
+                parameters: Some(AnyRef::from(&SECP_256_R_1)),
+            },
+            subject_public_key: self.sign.key.sec1(),
+        };
+        let algo = AlgorithmIdentifier {
+            oid: ECDSA_WITH_SHA_256,
+            parameters: None,
+        };
+        akey.verify(self.body.as_ref(), algo, &self.sign.sig.to_vec()?)?;
 
         // Verify the PCE security version.
         if self.body.pce_svn() < Body::PCE_SVN {
@@ -17,7 +17,7 @@ impl<'a> FromBytes<'a> for Data {
     fn from_bytes(bytes: &'a [u8]) -> Result<(Self, &'a [u8]), Self::Error> {
         let (typ, bytes) = bytes.parse()?;
         let (len, bytes) = bytes.parse()?;
-        let (buf, bytes) = bytes.steal(u32::from_le_bytes(len).try_into().unwrap())?;
+        let (buf, bytes) = bytes.steal(usize::try_from(u32::from_le_bytes(len))?)?;
 
         match u16::from_le_bytes(typ) {
             5 => {
@@ -0,0 +1,179 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Quote verification by Intel's DCAP Quote Verification Library (QVL).
+//!
+//! When built with the `qvl` feature, `verify()` hands quotes to the QVL, as
+//! an alternative to verifying them here. The library is loaded at runtime
+//! from `libsgx_dcap_quoteverify.so.1`, so that builds need not have it, and
+//! fetches its own collateral through the provider (QPL) configured on the
+//! host, usually a PCCS. Unlike the verification here, it appraises the TCB
+//! level of the platform, so a platform which is out of date is refused.
+//!
+//! The QVL also verifies TDX quotes, which steward does not yet accept.
+
+use anyhow::Result;
+
+/// Whether the QVL backend was compiled in.
+pub const AVAILABLE: bool = cfg!(all(feature = "qvl", target_os = "linux"));
+
+/// The verdict of the QVL on a quote (`sgx_ql_qv_result_t`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Ok,
+    ConfigNeeded,
+    OutOfDate,
+    OutOfDateConfigNeeded,
+    InvalidSignature,
+    Revoked,
+    Unspecified,
+    SwHardeningNeeded,
+    ConfigAndSwHardeningNeeded,
+    Other(u32),
+}
+
+impl From<u32> for Verdict {
+    fn from(result: u32) -> Self {
+        match result {
+            0x0000 => Self::Ok,
+            0xa001 => Self::ConfigNeeded,
+            0xa002 => Self::OutOfDate,
+            0xa003 => Self::OutOfDateConfigNeeded,
+            0xa004 => Self::InvalidSignature,
+            0xa005 => Self::Revoked,
+            0xa006 => Self::Unspecified,
+            0xa007 => Self::SwHardeningNeeded,
+            0xa008 => Self::ConfigAndSwHardeningNeeded,
+            other => Self::Other(other),
+        }
+    }
+}
+
+impl Verdict {
+    /// Whether the quote was signed by a genuine, unrevoked platform, even if
+    /// its TCB level is not up to date.
+    pub fn authentic(self) -> bool {
+        !matches!(
+            self,
+            Self::InvalidSignature | Self::Revoked | Self::Unspecified | Self::Other(..)
+        )
+    }
+}
+
+/// Verifies the raw `quote` as of `now`, returning the verdict of the QVL.
+#[cfg(all(feature = "qvl", target_os = "linux"))]
+pub fn verify(quote: &[u8], now: std::time::SystemTime) -> Result<Verdict> {
+    backend::verify(quote, now)
+}
+
+/// Verifies the raw `quote` as of `now`, returning the verdict of the QVL.
+#[cfg(not(all(feature = "qvl", target_os = "linux")))]
+pub fn verify(_quote: &[u8], _now: std::time::SystemTime) -> Result<Verdict> {
+    anyhow::bail!("built without qvl support")
+}
+
+#[cfg(all(feature = "qvl", target_os = "linux"))]
+mod backend {
+    use super::Verdict;
+
+    use std::ffi::{c_void, CStr};
+    use std::ptr::{null, null_mut};
+    use std::sync::OnceLock;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use anyhow::{anyhow, ensure, Result};
+
+    const LIBRARY: &CStr = c"libsgx_dcap_quoteverify.so.1";
+    const SYMBOL: &CStr = c"sgx_qv_verify_quote";
+
+    /// `SGX_QL_SUCCESS`
+    const SUCCESS: u32 = 0;
+
+    /// `sgx_qv_verify_quote`, verifying without the QvE enclave and with
+    /// collateral fetched by the QPL.
+    type VerifyQuote = unsafe extern "C" fn(
+        p_quote: *const u8,
+        quote_size: u32,
+        p_quote_collateral: *const c_void,
+        expiration_check_date: libc::time_t,
+        p_collateral_expiration_status: *mut u32,
+        p_quote_verification_result: *mut u32,
+        p_qve_report_info: *mut c_void,
+        supplemental_data_size: u32,
+        p_supplemental_data: *mut u8,
+    ) -> u32;
+
+    /// The entry point of the library, loaded once and never unloaded.
+    fn entry() -> Result<VerifyQuote> {
+        static ENTRY: OnceLock<Option<usize>> = OnceLock::new();
+
+        let address = ENTRY.get_or_init(|| {
+            // SAFETY: both names are NUL terminated, and the handle is leaked
+            // so that the symbol stays valid.
+            unsafe {
+                let handle = libc::dlopen(LIBRARY.as_ptr(), libc::RTLD_NOW);
+                if handle.is_null() {
+                    return None;
+                }
+                let symbol = libc::dlsym(handle, SYMBOL.as_ptr());
+                (!symbol.is_null()).then_some(symbol as usize)
+            }
+        });
+
+        let address = address.ok_or_else(|| anyhow!("qvl library is not installed"))?;
+        // SAFETY: the symbol has the signature declared in
+        // `sgx_dcap_quoteverify.h`.
+        Ok(unsafe { std::mem::transmute::<usize, VerifyQuote>(address) })
+    }
+
+    pub(super) fn verify(quote: &[u8], now: SystemTime) -> Result<Verdict> {
+        let verify = entry()?;
+        let size = u32::try_from(quote.len())?;
+        let date = now.duration_since(UNIX_EPOCH)?.as_secs();
+        let date = libc::time_t::try_from(date)?;
+
+        let mut expired = 0u32;
+        let mut result = 0u32;
+        // SAFETY: the quote outlives the call, and the outputs are valid.
+        let status = unsafe {
+            verify(
+                quote.as_ptr(),
+                size,
+                null(),
+                date,
+                &mut expired,
+                &mut result,
+                null_mut(),
+                0,
+                null_mut(),
+            )
+        };
+        ensure!(status == SUCCESS, "qvl failed with status {status:#06x}");
+        ensure!(expired == 0, "qvl collateral has expired");
+        Ok(Verdict::from(result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verdicts() {
+        assert_eq!(Verdict::from(0), Verdict::Ok);
+        assert_eq!(Verdict::from(0xa002), Verdict::OutOfDate);
+        assert_eq!(Verdict::from(0xe001), Verdict::Other(0xe001));
+
+        assert!(Verdict::OutOfDate.authentic());
+        assert!(Verdict::SwHardeningNeeded.authentic());
+        assert!(!Verdict::InvalidSignature.authentic());
+        assert!(!Verdict::Revoked.authentic());
+    }
+
+    #[test]
+    fn unavailable() {
+        if !AVAILABLE {
+            assert!(verify(&[], std::time::SystemTime::now()).is_err());
+        }
+    }
+}
@@ -48,6 +48,18 @@ pub struct Config {
 
     #[serde(default)]
     pub platform_info_flags: Option<PlatformInfoFlags>,
+
+    /// Require the ID block the guest was launched with, so that its
+    /// signatures and the reported ID and author key digests are verified.
+    /// Combine with `signer` or `id_key_digest` to pin the launch identity.
+    #[serde(default)]
+    pub require_id_block: bool,
+
+    /// DER `PkiPath`s, each an ARK followed by its ASKs, which replace the
+    /// built-in AMD roots, unless empty. Uploaded at runtime rather than
+    /// configured.
+    #[serde(skip)]
+    pub roots: Vec<Vec<u8>>,
 }
 
 fn from_policy_string<'de, D>(deserializer: D) -> Result<Option<u8>, D::Error>
@@ -0,0 +1,266 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Verification of the ID block a guest was launched with.
+//!
+//! The report only carries digests of the ID and author keys. Given the ID
+//! block and its authentication information (SNP ABI specification, tables
+//! 74 and 75), we can check the signatures ourselves and that the block
+//! describes the reported guest. See also section 8.17 of the specification.
+
+use super::super::crypto::SubjectPublicKeyInfoExt;
+use super::super::ct;
+use super::{Body, Es384};
+
+use anyhow::{ensure, Context, Result};
+use const_oid::db::rfc5912::{ECDSA_WITH_SHA_384, ID_EC_PUBLIC_KEY, SECP_384_R_1};
+use der::asn1::AnyRef;
+use sha2::{Digest, Sha384};
+use spki::{AlgorithmIdentifier, SubjectPublicKeyInfo};
+
+const BLOCK: usize = 0x60;
+const AUTH: usize = 0x1000;
+
+const ID_BLOCK_SIG: usize = 0x040;
+const ID_KEY: usize = 0x240;
+const ID_KEY_SIG: usize = 0x680;
+const AUTHOR_KEY: usize = 0x880;
+
+const SIG: usize = 0x200;
+const KEY: usize = 0x404;
+
+/// ECDSA P-384 with SHA-384, the only algorithm defined for either key.
+const ECDSA_P384_SHA384: u32 = 1;
+const CURVE_P384: u32 = 2;
+
+/// An ID block and its authentication information.
+#[derive(Clone, Copy, Debug)]
+pub struct IdBlock<'a> {
+    block: &'a [u8],
+    auth: &'a [u8],
+}
+
+fn field(bytes: &[u8], offset: usize, len: usize) -> Result<&[u8]> {
+    offset
+        .checked_add(len)
+        .and_then(|end| bytes.get(offset..end))
+        .context("snp id block field is out of bounds")
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32> {
+    Ok(u32::from_le_bytes(field(bytes, offset, 4)?.try_into()?))
+}
+
+/// Converts a little-endian signature structure to DER.
+fn signature(bytes: &[u8]) -> Result<Vec<u8>> {
+    Es384 {
+        r: field(bytes, 0, 0x48)?.try_into()?,
+        s: field(bytes, 0x48, 0x48)?.try_into()?,
+    }
+    .to_der()
+}
+
+/// Verifies `signature` over `body` with a little-endian public key structure.
+fn verify(key: &[u8], body: &[u8], signature: &[u8]) -> Result<()> {
+    ensure!(
+        u32_at(key, 0)? == CURVE_P384,
+        "snp id key curve is not p384"
+    );
+
+    // Coordinates are 72 byte little-endian integers.
+    let (x, y) = (field(key, 0x04, 0x48)?, field(key, 0x4c, 0x48)?);
+    ensure!(
+        x[48..].iter().chain(&y[48..]).all(|b| *b == 0),
+        "snp id key coordinate out of range"
+    );
+
+    let mut sec1 = vec![4];
+    sec1.extend(x[..48].iter().rev());
+    sec1.extend(y[..48].iter().rev());
+
+    let spki = SubjectPublicKeyInfo {
+        algorithm: AlgorithmIdentifier {
+            oid: ID_EC_PUBLIC_KEY,
+            parameters: Some(AnyRef::from(&SECP_384_R_1)),
+        },
+        subject_public_key: &sec1,
+    };
+    let algo = AlgorithmIdentifier {
+        oid: ECDSA_WITH_SHA_384,
+        parameters: None,
+    };
+    spki.verify(body, algo, signature)
+}
+
+impl<'a> IdBlock<'a> {
+    pub fn new(block: &'a [u8], auth: &'a [u8]) -> Result<Self> {
+        ensure!(block.len() == BLOCK, "snp id block has the wrong size");
+        ensure!(auth.len() == AUTH, "snp id auth info has the wrong size");
+        Ok(Self { block, auth })
+    }
+
+    /// Checks the block's signatures, and that it describes the guest in `body`.
+    pub fn verify(&self, body: &Body) -> Result<()> {
+        let report = body.as_ref();
+
+        // The block must describe the reported guest.
+        ensure!(u32_at(self.block, 0x50)? == 1, "snp id block version not 1");
+        ensure!(
+            self.block[0x00..0x30] == body.measurement,
+            "snp id block launch digest mismatch"
+        );
+        ensure!(
+            self.block[0x30..0x40] == body.family_id,
+            "snp id block family id mismatch"
+        );
+        ensure!(
+            self.block[0x40..0x50] == body.image_id,
+            "snp id block image id mismatch"
+        );
+        ensure!(
+            self.block[0x54..0x58] == report[0x04..0x08],
+            "snp id block guest svn mismatch"
+        );
+        ensure!(
+            self.block[0x58..0x60] == report[0x08..0x10],
+            "snp id block policy mismatch"
+        );
+
+        // The ID key signed the block, and is the one reported.
+        ensure!(
+            u32_at(self.auth, 0)? == ECDSA_P384_SHA384,
+            "snp id key algorithm unsupported"
+        );
+        let id_key = field(self.auth, ID_KEY, KEY)?;
+        let sig = signature(field(self.auth, ID_BLOCK_SIG, SIG)?)?;
+        verify(id_key, self.block, &sig).context("snp id block signature is invalid")?;
+        ensure!(
+            ct::eq(&Sha384::digest(id_key), &body.id_key_digest),
+            "snp id key digest mismatch"
+        );
+
+        // If present, the author key signed the ID key, and is the one reported.
+        if body.author_key_en & 1 == 1 {
+            ensure!(
+                u32_at(self.auth, 4)? == ECDSA_P384_SHA384,
+                "snp author key algorithm unsupported"
+            );
+            let author_key = field(self.auth, AUTHOR_KEY, KEY)?;
+            let sig = signature(field(self.auth, ID_KEY_SIG, SIG)?)?;
+            verify(author_key, id_key, &sig).context("snp id key signature is invalid")?;
+            ensure!(
+                ct::eq(&Sha384::digest(author_key), &body.author_key_digest),
+                "snp author key digest mismatch"
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Report;
+    use super::*;
+
+    use crate::crypto::PrivateKeyInfoExt;
+
+    use der::Decode;
+    use sec1::pkcs8::PrivateKeyInfo;
+    use std::mem::size_of;
+
+    /// Generates a P-384 key, returning it and its public key structure.
+    fn key() -> (Vec<u8>, Vec<u8>) {
+        let der = PrivateKeyInfo::generate(SECP_384_R_1).unwrap();
+        let pki = PrivateKeyInfo::from_der(&der).unwrap();
+        let sec1 = pki.public_key().unwrap().subject_public_key;
+
+        let mut key = vec![0; KEY];
+        key[..4].copy_from_slice(&CURVE_P384.to_le_bytes());
+        key[0x04..0x34].copy_from_slice(&sec1[1..49]);
+        key[0x04..0x34].reverse();
+        key[0x4c..0x7c].copy_from_slice(&sec1[49..97]);
+        key[0x4c..0x7c].reverse();
+        (der.to_vec(), key)
+    }
+
+    /// Signs `body`, returning the little-endian signature structure.
+    fn sign(der: &[u8], body: &[u8]) -> Vec<u8> {
+        let pki = PrivateKeyInfo::from_der(der).unwrap();
+        let algo = AlgorithmIdentifier {
+            oid: ECDSA_WITH_SHA_384,
+            parameters: None,
+        };
+        let sig = pki.sign(body, algo).unwrap();
+        let sig = p384::ecdsa::Signature::from_der(&sig).unwrap();
+        let (r, s) = sig.as_ref().split_at(48);
+
+        let mut out = vec![0; SIG];
+        out[..48].copy_from_slice(r);
+        out[..48].reverse();
+        out[0x48..0x78].copy_from_slice(s);
+        out[0x48..0x78].reverse();
+        out
+    }
+
+    fn fixture(author: bool) -> (Body, Vec<u8>, Vec<u8>) {
+        let mut body = Report::cast(&[0; size_of::<Report>()]).body;
+        body.measurement = [7; 48];
+        body.image_id = [9; 16];
+        body.guest_svn = 3;
+
+        let mut block = vec![0; BLOCK];
+        block[..0x30].copy_from_slice(&body.measurement);
+        block[0x40..0x50].copy_from_slice(&body.image_id);
+        block[0x50..0x54].copy_from_slice(&1u32.to_le_bytes());
+        block[0x54..0x58].copy_from_slice(&3u32.to_le_bytes());
+
+        let (id, id_key) = key();
+        let mut auth = vec![0; AUTH];
+        auth[..4].copy_from_slice(&ECDSA_P384_SHA384.to_le_bytes());
+        auth[ID_BLOCK_SIG..][..SIG].copy_from_slice(&sign(&id, &block));
+        auth[ID_KEY..][..KEY].copy_from_slice(&id_key);
+        body.id_key_digest.copy_from_slice(&Sha384::digest(&id_key));
+
+        if author {
+            let (author, author_key) = key();
+            auth[4..8].copy_from_slice(&ECDSA_P384_SHA384.to_le_bytes());
+            auth[ID_KEY_SIG..][..SIG].copy_from_slice(&sign(&author, &id_key));
+            auth[AUTHOR_KEY..][..KEY].copy_from_slice(&author_key);
+            body.author_key_en = 1;
+            body.author_key_digest
+                .copy_from_slice(&Sha384::digest(&author_key));
+        }
+
+        (body, block, auth)
+    }
+
+    #[test]
+    fn valid() {
+        for author in [false, true] {
+            let (body, block, auth) = fixture(author);
+            IdBlock::new(&block, &auth).unwrap().verify(&body).unwrap();
+        }
+    }
+
+    #[test]
+    fn mismatch() {
+        let (mut body, block, auth) = fixture(true);
+        body.image_id = [0; 16];
+        assert!(IdBlock::new(&block, &auth).unwrap().verify(&body).is_err());
+
+        let (mut body, block, auth) = fixture(true);
+        body.author_key_digest = [0; 48];
+        assert!(IdBlock::new(&block, &auth).unwrap().verify(&body).is_err());
+
+        let (body, mut block, auth) = fixture(false);
+        block[0x58] = 1;
+        assert!(IdBlock::new(&block, &auth).unwrap().verify(&body).is_err());
+
+        let (body, block, mut auth) = fixture(false);
+        auth[ID_BLOCK_SIG] ^= 1;
+        assert!(IdBlock::new(&block, &auth).unwrap().verify(&body).is_err());
+
+        assert!(IdBlock::new(&block[1..], &auth).is_err());
+    }
+}
@@ -4,16 +4,21 @@
 #![allow(unused_variables, unused_imports)] // temporary until CRL validation enabled
 
 pub mod config;
+pub mod id;
+pub mod table;
 
 use self::config::Config;
+use self::id::IdBlock;
+use self::table::CertTable;
 use super::crypto::{CrlList, PkiPathCRLCheck, TbsCertificateExt};
+use super::ct;
 
 use std::{fmt::Debug, mem::size_of};
 
 use anyhow::{bail, ensure, Context, Result};
 use const_oid::db::rfc5912::ECDSA_WITH_SHA_384;
 use const_oid::ObjectIdentifier;
-use der::asn1::UIntRef;
+use der::asn1::{OctetStringRef, UIntRef};
 use der::{Decode, Encode, Sequence};
 use flagset::{flags, FlagSet};
 use sec1::pkcs8::AlgorithmIdentifier;
@@ -36,6 +41,14 @@ pub struct Evidence<'a> {
 
     #[asn1(type = "OCTET STRING")]
     pub report: &'a [u8],
+
+    /// The ID block the guest was launched with, if any.
+    #[asn1(context_specific = "0", optional = "true", tag_mode = "IMPLICIT")]
+    pub id_block: Option<OctetStringRef<'a>>,
+
+    /// The authentication information for the ID block.
+    #[asn1(context_specific = "1", optional = "true", tag_mode = "IMPLICIT")]
+    pub id_auth: Option<OctetStringRef<'a>>,
 }
 
 flags! {
@@ -229,11 +242,85 @@ impl Report {
     }
 }
 
+/// The parts of SNP evidence, in whichever format it arrived.
+pub struct Parts<'a> {
+    pub endorsement: Certificate<'a>,
+    pub intermediates: Vec<Certificate<'a>>,
+    pub crl: Option<CrlList<'a>>,
+    pub report: &'a [u8],
+    pub id: Option<IdBlock<'a>>,
+}
+
+impl<'a> Parts<'a> {
+    /// Decodes either a DER `Evidence`, or an extended guest request: the raw
+    /// report followed by its certificate table.
+    pub fn decode(bytes: &'a [u8]) -> Result<Self> {
+        // A DER SEQUENCE, whereas reports start with a small version number.
+        if bytes.first() == Some(&0x30) {
+            let evidence = Evidence::from_der(bytes)?;
+            let id = match (evidence.id_block, evidence.id_auth) {
+                (Some(block), Some(auth)) => Some(IdBlock::new(block.as_bytes(), auth.as_bytes())?),
+                (None, None) => None,
+                _ => bail!("snp id block and auth info must be sent together"),
+            };
+            return Ok(Self {
+                endorsement: evidence.crts.vcek,
+                intermediates: Vec::new(),
+                crl: Some(evidence.crts.crl),
+                report: evidence.report,
+                id,
+            });
+        }
+
+        ensure!(
+            bytes.len() > size_of::<Report>(),
+            "snp extended report is truncated"
+        );
+        let (report, table) = bytes.split_at(size_of::<Report>());
+        let table = CertTable::parse(table)?;
+
+        let endorsement = table
+            .get(&CertTable::VCEK)
+            .or_else(|| table.get(&CertTable::VLEK))
+            .context("snp certificate table has no vcek or vlek")?;
+        let intermediates = table
+            .get(&CertTable::ASK)
+            .into_iter()
+            .map(Certificate::from_der)
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            endorsement: Certificate::from_der(endorsement)?,
+            intermediates,
+            crl: None,
+            report,
+            id: None,
+        })
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct Snp(());
 
 impl Snp {
-    const ROOTS: &'static [&'static [u8]] = &[
+    /// When the collateral accompanying the report in `ext` was issued.
+    pub fn issued(ext: &Extension<'_>) -> Result<Option<std::time::SystemTime>> {
+        let parts = Parts::decode(ext.extn_value)?;
+        Ok(parts.crl.and_then(|crl| crl.latest_update()))
+    }
+
+    /// The report in `ext`, which is only to be trusted once verified.
+    pub fn report(ext: &Extension<'_>) -> Result<Report> {
+        let parts = crate::parse::snp_evidence(ext.extn_value)?;
+        let array = parts
+            .report
+            .try_into()
+            .context("snp report is incorrect size")?;
+        Ok(*Report::cast(array))
+    }
+
+    /// The DER encoded `PkiPath`s of AMD's ARKs and their ASKs, per product.
+    pub const ROOTS: &'static [&'static [u8]] = &[
         include_bytes!("milan.pkipath"),
         include_bytes!("genoa.pkipath"),
     ];
@@ -242,22 +329,35 @@ impl Snp {
     pub const ATT: bool = true;
 
     // This ensures that the supplied vcek is rooted in one of our trusted chains.
-    fn is_trusted<'c>(&self, certs: &'c Certificates<'c>) -> Result<&'c TbsCertificate<'c>> {
-        for root in Self::ROOTS {
-            let path = PkiPath::from_der(root)?;
-            let vcek = &certs.vcek;
-
-            let mut signer = Some(&path[0].tbs_certificate);
-            for cert in path.iter().chain([vcek].into_iter()) {
-                signer = signer.and_then(|s| s.verify_crt(cert).ok());
-            }
+    //
+    // The intermediate is either our pinned ASK or, for evidence carrying a
+    // certificate table, one it supplies (such as the ASVK of a VLEK) which
+    // must itself be signed by our pinned ARK. Uploaded `roots`, if any,
+    // replace those built in.
+    fn is_trusted<'c>(
+        &self,
+        vcek: &'c Certificate<'c>,
+        intermediates: &[Certificate<'_>],
+        roots: &[Vec<u8>],
+    ) -> Result<&'c TbsCertificate<'c>> {
+        let uploaded: Vec<&[u8]> = roots.iter().map(Vec::as_slice).collect();
+        let roots = match uploaded.is_empty() {
+            true => Self::ROOTS,
+            false => &uploaded[..],
+        };
 
-            if let Some(signer) = signer {
-                if signer == &vcek.tbs_certificate {
-                    //let mut path = path;
-                    //path.push(vcek.clone());
+        for root in roots {
+            let path = PkiPath::from_der(root)?;
+            let ark = match path[0].tbs_certificate.verify_crt(&path[0]) {
+                Ok(ark) => ark,
+                Err(..) => continue,
+            };
+
+            for ask in path[1..].iter().chain(intermediates) {
+                let signer = ark.verify_crt(ask).and_then(|ask| ask.verify_crt(vcek));
+                if let Ok(signer) = signer {
                     //path.check_crl(&certs.crl)?;
-                    return Ok(&vcek.tbs_certificate);
+                    return Ok(signer);
                 }
             }
         }
@@ -275,10 +375,11 @@ impl Snp {
         ensure!(!ext.critical, "snp extension cannot be critical");
 
         // Decode the evidence.
-        let evidence = Evidence::from_der(ext.extn_value)?;
+        let evidence = crate::parse::snp_evidence(ext.extn_value)?;
 
         // Validate the VCEK.
-        let vcek = self.is_trusted(&evidence.crts)?;
+        let roots = config.map_or(&[][..], |config| &config.roots);
+        let vcek = self.is_trusted(&evidence.endorsement, &evidence.intermediates, roots)?;
 
         // Force certs to have the same key type as the VCEK.
         //
@@ -394,11 +495,16 @@ impl Snp {
 
         ensure!(report.body.vmpl == 0, "snp report vmpl field invalid value");
 
+        // Check the launch identity, if we were given it.
+        if let Some(id) = &evidence.id {
+            id.verify(&report.body)?;
+        }
+
         if !dbg {
             // Validate that the certification request came from an SNP VM.
             let hash = Sha384::digest(cri.public_key.to_vec()?);
             ensure!(
-                hash.as_slice() == &report.body.report_data[..hash.as_slice().len()],
+                ct::starts_with(&report.body.report_data, &hash),
                 "snp report.report_data is invalid"
             );
 
@@ -409,6 +515,11 @@ impl Snp {
         }
 
         if let Some(config) = config {
+            ensure!(
+                evidence.id.is_some() || !config.require_id_block,
+                "snp id block required"
+            );
+
             ensure!(
                 config.abi.matches(&report.body.policy.into()),
                 "snp minimum abi not met"
@@ -417,15 +528,13 @@ impl Snp {
             if !config.measurements.signer.is_empty() {
                 ensure!(report.body.author_key_en == 1, "snp author key unset");
 
-                let approved = config
-                    .measurements
-                    .signer
-                    .contains(&report.body.author_key_digest);
+                let approved =
+                    ct::contains(&config.measurements.signer, &report.body.author_key_digest);
                 ensure!(approved, "snp untrusted enarx author_key_digest");
             }
 
             if !config.id_key_digest.is_empty() {
-                let approved = config.id_key_digest.contains(&report.body.id_key_digest);
+                let approved = ct::contains(&config.id_key_digest, &report.body.id_key_digest);
                 ensure!(
                     approved,
                     "snp untrusted enarx id_key_digest not in list of allowed key digests"
@@ -433,22 +542,21 @@ impl Snp {
             }
 
             if !config.id_key_digest_blacklist.is_empty() {
-                let denied = config
-                    .id_key_digest_blacklist
-                    .contains(&report.body.id_key_digest);
+                let denied =
+                    ct::contains(&config.id_key_digest_blacklist, &report.body.id_key_digest);
                 ensure!(!denied, "snp untrusted enarx id_key_digest in blacklist");
             }
 
             if !config.measurements.hash.is_empty() {
-                let allowed = config.measurements.hash.contains(&report.body.measurement);
+                let allowed = ct::contains(&config.measurements.hash, &report.body.measurement);
                 ensure!(allowed, "snp untrusted enarx measurement");
             }
 
             if !config.measurements.hash_blacklist.is_empty() {
-                let denied = config
-                    .measurements
-                    .hash_blacklist
-                    .contains(&report.body.measurement);
+                let denied = ct::contains(
+                    &config.measurements.hash_blacklist,
+                    &report.body.measurement,
+                );
                 ensure!(!denied, "snp untrusted enarx hash");
             }
 
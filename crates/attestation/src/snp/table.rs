@@ -0,0 +1,206 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! The certificate table returned by an SNP extended guest request.
+//!
+//! The table is a sequence of `(guid, offset, length)` entries terminated by
+//! an all-zero entry, followed by the DER certificates the entries point to.
+//! Offsets are relative to the start of the table. See the GHCB specification,
+//! section 4.1.8.1.
+
+use anyhow::{bail, ensure, Context, Result};
+
+type Guid = [u8; 16];
+
+const ENTRY: usize = 24;
+
+/// A parsed certificate table.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CertTable<'a> {
+    entries: Vec<(Guid, &'a [u8])>,
+}
+
+impl<'a> CertTable<'a> {
+    /// The Versioned Chip Endorsement Key: 63da758d-e664-4564-adc5-f4b93be8accd.
+    pub const VCEK: Guid = [
+        0x63, 0xda, 0x75, 0x8d, 0xe6, 0x64, 0x45, 0x64, 0xad, 0xc5, 0xf4, 0xb9, 0x3b, 0xe8, 0xac,
+        0xcd,
+    ];
+
+    /// The Versioned Loaded Endorsement Key: a8074bc2-a25a-483e-aae6-39c045a0b8a1.
+    pub const VLEK: Guid = [
+        0xa8, 0x07, 0x4b, 0xc2, 0xa2, 0x5a, 0x48, 0x3e, 0xaa, 0xe6, 0x39, 0xc0, 0x45, 0xa0, 0xb8,
+        0xa1,
+    ];
+
+    /// The AMD SEV Key (or AMD SEV VLEK Key): 4ab7b379-bbac-4fe4-a02f-05aef327c782.
+    pub const ASK: Guid = [
+        0x4a, 0xb7, 0xb3, 0x79, 0xbb, 0xac, 0x4f, 0xe4, 0xa0, 0x2f, 0x05, 0xae, 0xf3, 0x27, 0xc7,
+        0x82,
+    ];
+
+    /// The AMD Root Key: c0b406a4-a803-4952-9743-3fb6014cd0ae.
+    pub const ARK: Guid = [
+        0xc0, 0xb4, 0x06, 0xa4, 0xa8, 0x03, 0x49, 0x52, 0x97, 0x43, 0x3f, 0xb6, 0x01, 0x4c, 0xd0,
+        0xae,
+    ];
+
+    /// Parses a table.
+    ///
+    /// GUIDs are accepted both in RFC 4122 byte order and in the mixed-endian
+    /// order of Microsoft/Linux `guid_t`, since hypervisors differ.
+    pub fn parse(table: &'a [u8]) -> Result<Self> {
+        let mut entries = Vec::new();
+
+        for entry in table.chunks(ENTRY) {
+            ensure!(
+                entry.len() == ENTRY,
+                "snp certificate table is not terminated"
+            );
+
+            let guid: Guid = entry[..16].try_into()?;
+            let offset = u32::from_le_bytes(entry[16..20].try_into()?) as usize;
+            let length = u32::from_le_bytes(entry[20..24].try_into()?) as usize;
+
+            if guid == [0; 16] {
+                return Ok(Self { entries });
+            }
+
+            let guid = Self::canonical(guid);
+            ensure!(
+                !entries.iter().any(|(g, _)| *g == guid),
+                "snp certificate table has duplicate entries"
+            );
+
+            let end = offset
+                .checked_add(length)
+                .filter(|end| *end <= table.len())
+                .context("snp certificate table entry is out of bounds")?;
+            entries.push((guid, &table[offset..end]));
+        }
+
+        bail!("snp certificate table is not terminated")
+    }
+
+    /// Returns the DER certificate with the given GUID.
+    pub fn get(&self, guid: &Guid) -> Option<&'a [u8]> {
+        self.entries
+            .iter()
+            .find(|(g, _)| g == guid)
+            .map(|(_, der)| *der)
+    }
+
+    /// Converts known mixed-endian GUIDs to RFC 4122 byte order.
+    fn canonical(guid: Guid) -> Guid {
+        let mut swapped = guid;
+        swapped[..4].reverse();
+        swapped[4..6].reverse();
+        swapped[6..8].reverse();
+
+        let known = [Self::VCEK, Self::VLEK, Self::ASK, Self::ARK];
+        if known.contains(&swapped) {
+            swapped
+        } else {
+            guid
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a table from entries, appending the terminator and the data.
+    fn build(entries: &[(Guid, &[u8])]) -> Vec<u8> {
+        let mut offset = (entries.len() + 1) * ENTRY;
+        let mut table = Vec::new();
+        for (guid, der) in entries {
+            table.extend_from_slice(guid);
+            table.extend_from_slice(&(offset as u32).to_le_bytes());
+            table.extend_from_slice(&(der.len() as u32).to_le_bytes());
+            offset += der.len();
+        }
+        table.extend_from_slice(&[0; ENTRY]);
+        for (_, der) in entries {
+            table.extend_from_slice(der);
+        }
+        table
+    }
+
+    #[test]
+    fn parse() {
+        let table = build(&[(CertTable::VCEK, b"vcek"), (CertTable::ASK, b"ask")]);
+        let table = CertTable::parse(&table).unwrap();
+        assert_eq!(table.get(&CertTable::VCEK), Some(&b"vcek"[..]));
+        assert_eq!(table.get(&CertTable::ASK), Some(&b"ask"[..]));
+        assert_eq!(table.get(&CertTable::ARK), None);
+    }
+
+    #[test]
+    fn mixed_endian() {
+        let mut guid = CertTable::VLEK;
+        guid[..4].reverse();
+        guid[4..6].reverse();
+        guid[6..8].reverse();
+
+        let table = build(&[(guid, b"vlek")]);
+        let table = CertTable::parse(&table).unwrap();
+        assert_eq!(table.get(&CertTable::VLEK), Some(&b"vlek"[..]));
+    }
+
+    #[test]
+    fn extended_report() {
+        use crate::snp::{Evidence, Report, Snp};
+
+        use der::{Decode, Encode};
+        use std::mem::size_of;
+        use x509::attr::Attribute;
+        use x509::ext::Extension;
+        use x509::request::{CertReq, ExtensionReq};
+
+        // Repackage the canned evidence as an extended report.
+        let csr = include_bytes!("milan.signed.crl.csr");
+        let csr = CertReq::from_der(csr).unwrap();
+        let Attribute { values, .. } = csr.info.attributes.iter().next().unwrap();
+        let ereq: ExtensionReq<'_> = values.iter().next().unwrap().decode_into().unwrap();
+        let ext = &Vec::from(ereq)[0];
+        let evidence = Evidence::from_der(ext.extn_value).unwrap();
+        let vcek = evidence.crts.vcek.to_vec().unwrap();
+
+        let mut extended = evidence.report.to_vec();
+        assert_eq!(extended.len(), size_of::<Report>());
+        extended.extend(build(&[(CertTable::VCEK, &vcek)]));
+
+        let ext = Extension {
+            extn_value: &extended,
+            ..ext.clone()
+        };
+        Snp::default().verify(&csr.info, &ext, None, false).unwrap();
+
+        // A table without an endorsement key is useless.
+        let mut extended = evidence.report.to_vec();
+        extended.extend(build(&[(CertTable::ARK, &vcek)]));
+        let ext = Extension {
+            extn_value: &extended,
+            ..ext
+        };
+        assert!(Snp::default().verify(&csr.info, &ext, None, false).is_err());
+    }
+
+    #[test]
+    fn malformed() {
+        // Unterminated.
+        let table = build(&[(CertTable::VCEK, b"vcek")]);
+        assert!(CertTable::parse(&table[..ENTRY]).is_err());
+        assert!(CertTable::parse(&table[..ENTRY + 3]).is_err());
+
+        // Out of bounds.
+        let mut table = build(&[(CertTable::VCEK, b"vcek")]);
+        table[20] = 0xff;
+        assert!(CertTable::parse(&table).is_err());
+
+        // Duplicated.
+        let table = build(&[(CertTable::VCEK, b"a"), (CertTable::VCEK, b"b")]);
+        assert!(CertTable::parse(&table).is_err());
+    }
+}
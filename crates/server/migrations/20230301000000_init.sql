@@ -0,0 +1,33 @@
+-- SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+-- SPDX-License-Identifier: AGPL-3.0-only
+
+-- Times are seconds since the Unix epoch.
+
+CREATE TABLE issued (
+    serial BYTEA PRIMARY KEY,
+    not_before BIGINT NOT NULL,
+    not_after BIGINT NOT NULL,
+    der BYTEA NOT NULL
+);
+
+CREATE INDEX issued_not_after ON issued (not_after);
+
+CREATE TABLE revocations (
+    serial BYTEA PRIMARY KEY REFERENCES issued (serial),
+    revoked_at BIGINT NOT NULL,
+    reason SMALLINT
+);
+
+CREATE TABLE audit (
+    id BIGSERIAL PRIMARY KEY,
+    at BIGINT NOT NULL,
+    event TEXT NOT NULL,
+    detail TEXT NOT NULL
+);
+
+CREATE TABLE reference_values (
+    platform TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    value BYTEA NOT NULL,
+    PRIMARY KEY (platform, kind, value)
+);
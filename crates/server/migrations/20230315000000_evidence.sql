@@ -0,0 +1,10 @@
+-- SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+-- SPDX-License-Identifier: AGPL-3.0-only
+
+CREATE TABLE evidence (
+    serial BYTEA PRIMARY KEY REFERENCES issued (serial),
+    archived_at BIGINT NOT NULL,
+    data BYTEA NOT NULL
+);
+
+CREATE INDEX evidence_archived_at ON evidence (archived_at);
@@ -0,0 +1,9 @@
+-- SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+-- SPDX-License-Identifier: AGPL-3.0-only
+
+-- The append-only issuance log; `idx` is the dense Merkle tree leaf index.
+CREATE TABLE log (
+    idx BIGINT PRIMARY KEY,
+    serial BYTEA NOT NULL UNIQUE REFERENCES issued (serial),
+    leaf BYTEA NOT NULL
+);
@@ -0,0 +1,6 @@
+-- SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+-- SPDX-License-Identifier: AGPL-3.0-only
+
+ALTER TABLE issued ADD COLUMN rekor_index BIGINT;
+
+CREATE INDEX issued_unpublished ON issued (serial) WHERE rekor_index IS NULL;
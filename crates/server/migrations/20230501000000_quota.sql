@@ -0,0 +1,10 @@
+-- SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+-- SPDX-License-Identifier: AGPL-3.0-only
+
+-- Issuances counted against quotas, kept until they leave the window.
+CREATE TABLE quota (
+    subject TEXT NOT NULL,
+    expires BIGINT NOT NULL
+);
+
+CREATE INDEX quota_subject ON quota (subject);
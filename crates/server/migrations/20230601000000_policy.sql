@@ -0,0 +1,12 @@
+-- SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+-- SPDX-License-Identifier: AGPL-3.0-only
+
+-- Every change of the policy in force, in order.
+CREATE TABLE policies (
+    version BIGSERIAL PRIMARY KEY,
+    sha256 BYTEA NOT NULL,
+    activated_at BIGINT NOT NULL
+);
+
+-- Certificates issued before versioning are marked 0.
+ALTER TABLE issued ADD COLUMN policy_version BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,5 @@
+-- SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+-- SPDX-License-Identifier: AGPL-3.0-only
+
+-- Inventory exports page through certificates in order of issuance.
+CREATE INDEX issued_not_before ON issued (not_before, serial);
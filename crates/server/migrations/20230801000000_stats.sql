@@ -0,0 +1,10 @@
+-- SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+-- SPDX-License-Identifier: AGPL-3.0-only
+
+-- Issuance statistics are grouped by platform and tenant, which are unknown
+-- for certificates issued before they were recorded.
+ALTER TABLE issued ADD COLUMN platform TEXT;
+ALTER TABLE issued ADD COLUMN tenant TEXT;
+
+-- Active certificates and upcoming expirations are counted by expiry.
+CREATE INDEX issued_not_after ON issued (not_after);
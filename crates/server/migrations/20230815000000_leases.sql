@@ -0,0 +1,11 @@
+-- SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+-- SPDX-License-Identifier: AGPL-3.0-only
+
+-- The lease held on each certified key, until it is renewed or lapses.
+CREATE TABLE leases (
+    key BYTEA PRIMARY KEY,
+    serial BYTEA NOT NULL,
+    renew_by BIGINT NOT NULL
+);
+
+CREATE INDEX leases_renew_by ON leases (renew_by);
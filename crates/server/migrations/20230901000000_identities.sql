@@ -0,0 +1,15 @@
+-- SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+-- SPDX-License-Identifier: AGPL-3.0-only
+
+-- The history of every workload identity, that is of every certified key,
+-- with the measurements attested for each issuance as a JSON object.
+CREATE TABLE identities (
+    serial BYTEA PRIMARY KEY,
+    key BYTEA NOT NULL,
+    not_before BIGINT NOT NULL,
+    not_after BIGINT NOT NULL,
+    platform TEXT,
+    measurements TEXT NOT NULL
+);
+
+CREATE INDEX identities_key ON identities (key, not_before, serial);
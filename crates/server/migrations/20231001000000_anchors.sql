@@ -0,0 +1,10 @@
+-- SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+-- SPDX-License-Identifier: AGPL-3.0-only
+
+-- The trust anchors uploaded for each platform, replacing those built in.
+CREATE TABLE anchors (
+    platform TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    der BYTEA NOT NULL,
+    PRIMARY KEY (platform, position)
+);
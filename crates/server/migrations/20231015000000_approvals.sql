@@ -0,0 +1,22 @@
+-- SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+-- SPDX-License-Identifier: AGPL-3.0-only
+
+-- Certification requests parked for an operator's decision, with the
+-- measurements needing approval as a JSON object, and the certificate chain
+-- issued for those approved.
+CREATE TABLE approvals (
+    id TEXT PRIMARY KEY,
+    received_at BIGINT NOT NULL,
+    expires_at BIGINT NOT NULL,
+    request BYTEA NOT NULL,
+    measurements TEXT NOT NULL,
+    status TEXT NOT NULL,
+    response BYTEA
+);
+
+CREATE INDEX approvals_pending ON approvals (status, received_at, id);
+
+-- The SHA-256 fingerprints of the measurements operators have approved.
+CREATE TABLE approved_measurements (
+    fingerprint BYTEA PRIMARY KEY
+);
@@ -0,0 +1,10 @@
+-- SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+-- SPDX-License-Identifier: AGPL-3.0-only
+
+-- The measurements first seen for each workload identity, as a JSON object,
+-- to which it is held thereafter.
+CREATE TABLE pins (
+    identity TEXT PRIMARY KEY,
+    measurements TEXT NOT NULL,
+    pinned_at BIGINT NOT NULL
+);
@@ -0,0 +1,6 @@
+-- SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+-- SPDX-License-Identifier: AGPL-3.0-only
+
+-- The issuance profile each parked request asked for by name, under which
+-- it is issued once approved.
+ALTER TABLE approvals ADD COLUMN profile TEXT;
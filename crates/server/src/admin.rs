@@ -0,0 +1,598 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Operator access to the admin API, gated by attestation.
+//!
+//! Rather than holding a static secret, an operator's management enclave
+//! attests to steward just as a workload would: it posts a certification
+//! request carrying its evidence to `/admin/credential`, which appraises it
+//! against a separate policy of approved management measurements, e.g.
+//!
+//! ```toml
+//! [admin]
+//! ttl = 600
+//!
+//! [admin.snp]
+//! hash = ["ff717ae7..."]
+//! ```
+//!
+//! and returns a short-lived bearer credential for the admin endpoints.
+//!
+//! So that captured requests cannot be replayed, the enclave first obtains a
+//! single-use nonce from `/admin/nonce` and places it in its report data,
+//! directly after the digest of its key.
+//!
+//! Auditors hold a separate, static bearer token (`--auditor-token`), with
+//! which they may read, but not change, what the admin API exposes.
+
+use super::{State, PKCS10};
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+#[cfg(any(feature = "sgx", feature = "snp"))]
+use anyhow::anyhow;
+use anyhow::{bail, ensure, Result};
+use attestation::crypto::CertReqExt;
+use attestation::parse;
+#[cfg(feature = "sgx")]
+use attestation::sgx::Sgx;
+#[cfg(feature = "snp")]
+use attestation::snp::Snp;
+use axum::body::Bytes;
+use axum::extract::{Extension, TypedHeader};
+use axum::headers::authorization::{Authorization, Bearer};
+use axum::headers::ContentType;
+use axum::http::HeaderMap;
+use axum::Json;
+use const_oid::db::rfc5912::ID_EXTENSION_REQ;
+use der::Decode;
+use hyper::StatusCode;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info};
+use x509::attr::Attribute;
+use x509::ext::Extension as X509Extension;
+use x509::request::{CertReqInfo, ExtensionReq};
+use x509::Certificate;
+use zeroize::Zeroizing;
+
+/// The default lifetime of admin credentials.
+const TTL: Duration = Duration::from_secs(15 * 60);
+
+/// The longest lifetime which may be configured for admin credentials.
+const MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The length of the nonces placed in management evidence.
+const NONCE: usize = 16;
+
+/// How long a nonce may be redeemed after it is issued.
+const NONCE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// The management enclaves whose operators may use the admin API.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[cfg(feature = "sgx")]
+    pub sgx: Option<attestation::sgx::config::Config>,
+    #[cfg(not(feature = "sgx"))]
+    pub sgx: Option<super::Unsupported>,
+
+    #[cfg(feature = "snp")]
+    pub snp: Option<attestation::snp::config::Config>,
+    #[cfg(not(feature = "snp"))]
+    pub snp: Option<super::Unsupported>,
+
+    /// The lifetime, in seconds, of admin credentials.
+    pub ttl: Option<u64>,
+}
+
+impl Policy {
+    /// Whether any management enclave is approved.
+    pub fn enabled(&self) -> bool {
+        self.sgx.is_some() || self.snp.is_some()
+    }
+
+    /// The lifetime of admin credentials.
+    pub fn ttl(&self) -> Duration {
+        self.ttl.map_or(TTL, Duration::from_secs)
+    }
+
+    /// Refuses policies which would approve any enclave, or credentials
+    /// which outlive their purpose.
+    pub fn validate(&self) -> Result<()> {
+        #[cfg(feature = "sgx")]
+        if let Some(sgx) = &self.sgx {
+            ensure!(
+                !sgx.measurements.hash.is_empty(),
+                "admin sgx policy must list approved measurements"
+            );
+        }
+
+        #[cfg(feature = "snp")]
+        if let Some(snp) = &self.snp {
+            ensure!(
+                !snp.measurements.hash.is_empty(),
+                "admin snp policy must list approved measurements"
+            );
+        }
+
+        let ttl = self.ttl();
+        ensure!(
+            !ttl.is_zero() && ttl <= MAX_TTL,
+            "admin credential lifetime must be between 1 and {} seconds",
+            MAX_TTL.as_secs()
+        );
+
+        Ok(())
+    }
+}
+
+/// A short-lived admin credential.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Credential {
+    /// The bearer token.
+    pub token: String,
+
+    /// Seconds since the Unix epoch.
+    pub expires_at: u64,
+}
+
+/// The shared key under which a credential's expiry is kept.
+pub(crate) fn key(token: &str) -> String {
+    format!("admin/credential/{}", hex::encode(Sha256::digest(token)))
+}
+
+/// A single-use nonce for the report data of a management enclave.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Challenge {
+    /// The hex nonce.
+    pub nonce: String,
+
+    /// Seconds since the Unix epoch.
+    pub expires_at: u64,
+}
+
+/// The shared key under which an outstanding nonce is kept.
+fn issued(nonce: &[u8]) -> String {
+    format!("admin/nonce/{}", hex::encode(nonce))
+}
+
+/// The shared key marking a nonce as spent.
+fn redeemed(nonce: &[u8]) -> String {
+    format!("admin/nonce/{}/redeemed", hex::encode(nonce))
+}
+
+/// Makes `nonce` redeemable, once, until it expires.
+async fn challenge(state: &State, nonce: &[u8]) -> Result<()> {
+    state.shared.put(&issued(nonce), &[], NONCE_TTL).await
+}
+
+/// Redeems `nonce`, which must have been issued and not yet redeemed.
+async fn redeem(state: &State, nonce: &[u8]) -> Result<()> {
+    let outstanding = state.shared.get(&issued(nonce)).await?;
+    ensure!(outstanding.is_some(), "unknown or expired admin nonce");
+
+    // Replicas sharing the backend agree on the one request redeeming it.
+    let fresh = state
+        .shared
+        .put_new(&redeemed(nonce), &[], NONCE_TTL)
+        .await?;
+    ensure!(fresh, "admin nonce already redeemed");
+    Ok(())
+}
+
+/// The nonce which follows the digest of the key in the report data of the
+/// management evidence `ext`.
+#[cfg_attr(not(any(feature = "sgx", feature = "snp")), allow(unreachable_code))]
+fn reported(ext: &X509Extension<'_>) -> Result<[u8; NONCE]> {
+    // SGX binds the key by its SHA-256 digest, SNP by its SHA-384.
+    let (data, offset): ([u8; 64], usize) = match ext.extn_id {
+        #[cfg(feature = "sgx")]
+        Sgx::OID => (Sgx::report(ext)?.reportdata, 32),
+        #[cfg(feature = "snp")]
+        Snp::OID => (Snp::report(ext)?.body.report_data, 48),
+        oid => bail!("no report data in {oid}"),
+    };
+    Ok(<[u8; NONCE]>::try_from(&data[offset..offset + NONCE])?)
+}
+
+/// The bearer token of auditors, kept as its digest.
+#[derive(Clone)]
+pub struct Auditor(Zeroizing<[u8; 32]>);
+
+impl fmt::Debug for Auditor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Auditor").finish_non_exhaustive()
+    }
+}
+
+impl Auditor {
+    pub fn new(token: &str) -> Result<Self> {
+        ensure!(!token.is_empty(), "empty auditor token");
+        Ok(Self(Zeroizing::new(Sha256::digest(token).into())))
+    }
+
+    fn authorized(&self, token: &str) -> bool {
+        let digest: [u8; 32] = Sha256::digest(token).into();
+        digest == *self.0
+    }
+}
+
+/// Whether `token` is an unexpired admin credential.
+pub async fn authorized(state: &State, token: &str) -> Result<bool> {
+    let expires_at = match state.shared.get(&key(token)).await? {
+        Some(value) => match <[u8; 8]>::try_from(value.as_slice()) {
+            Ok(bytes) => u64::from_be_bytes(bytes),
+            Err(_) => return Ok(false),
+        },
+        None => return Ok(false),
+    };
+
+    let now = state.clock.now().duration_since(UNIX_EPOCH)?.as_secs();
+    Ok(now < expires_at)
+}
+
+/// Checks that a request bears an unexpired admin credential or, where
+/// `auditors` may also read, the auditor token.
+pub(crate) async fn authorize(
+    state: &State,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    auditors: bool,
+) -> Result<(), StatusCode> {
+    let token = match &auth {
+        Some(TypedHeader(auth)) => auth.token(),
+        None => return Err(StatusCode::UNAUTHORIZED),
+    };
+
+    let auditor = state
+        .auditor
+        .as_ref()
+        .map_or(false, |a| a.authorized(token));
+    if auditors && auditor {
+        return Ok(());
+    }
+
+    match authorized(state, token).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(StatusCode::UNAUTHORIZED),
+        Err(e) => {
+            debug!("failed to check admin credential: {e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Appraises the evidence of a management enclave against the admin policy.
+///
+/// Unlike issuance, every piece of evidence must verify, whatever the
+/// platform policy says, and carry an outstanding nonce, which is then
+/// redeemed. Returns the platforms which attested.
+#[cfg_attr(not(any(feature = "sgx", feature = "snp")), allow(unused_variables))]
+async fn appraise(
+    info: &CertReqInfo<'_>,
+    dbg: bool,
+    state: &State,
+) -> Result<Vec<&'static str>, StatusCode> {
+    let policy = state.policy();
+    let mut platforms = Vec::new();
+    let mut nonces = BTreeSet::new();
+    for Attribute { oid, values } in info.attributes.iter() {
+        if *oid != ID_EXTENSION_REQ {
+            continue;
+        }
+        for any in values.iter() {
+            let ereq: ExtensionReq<'_> = any.decode_into().map_err(|e| {
+                debug!("failed to decode extension request: {e}");
+                StatusCode::BAD_REQUEST
+            })?;
+            for ext in Vec::from(ereq) {
+                let now = state.clock.now();
+                let verified = match ext.extn_id {
+                    #[cfg(feature = "sgx")]
+                    Sgx::OID => match &policy.config.admin.sgx {
+                        Some(config) => {
+                            let config = super::sgx_config(state, Some(config)).await?;
+                            Sgx::default()
+                                .verify(info, &ext, config.as_deref(), dbg, now)
+                                .map(|_| "sgx")
+                        }
+                        None => Err(anyhow!("sgx is not an approved management platform")),
+                    },
+                    #[cfg(feature = "snp")]
+                    Snp::OID => match &policy.config.admin.snp {
+                        Some(config) => {
+                            let config = super::snp_config(state, Some(config)).await?;
+                            Snp::default()
+                                .verify(info, &ext, config.as_deref(), dbg)
+                                .map(|_| "snp")
+                        }
+                        None => Err(anyhow!("snp is not an approved management platform")),
+                    },
+                    // Only hardware evidence can vouch for a management enclave.
+                    _ => continue,
+                };
+
+                let max_age = policy.config.max_evidence_age;
+                let fresh = |platform| match state.verifiers.get(&ext.extn_id) {
+                    Some(verifier) => super::fresh(max_age, verifier, &ext, now).map(|_| platform),
+                    None => Ok(platform),
+                };
+                let nonced = |platform| reported(&ext).map(|nonce| (platform, nonce));
+                match verified.and_then(fresh).and_then(nonced) {
+                    Ok((platform, nonce)) => {
+                        platforms.push(platform);
+                        nonces.insert(nonce);
+                    }
+                    Err(e) => {
+                        debug!("admin attestation failed: {e}");
+                        return Err(StatusCode::UNAUTHORIZED);
+                    }
+                }
+            }
+        }
+    }
+
+    if platforms.is_empty() {
+        debug!("admin attestation failed: no management evidence");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    for nonce in nonces {
+        if let Err(e) = redeem(state, &nonce).await {
+            debug!("admin attestation failed: {e}");
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    Ok(platforms)
+}
+
+/// Issues a nonce for a management enclave to attest with.
+pub async fn nonce(Extension(state): Extension<Arc<State>>) -> Result<Json<Challenge>, StatusCode> {
+    if !state.policy().config.admin.enabled() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let nonce = rand::thread_rng().gen::<[u8; NONCE]>();
+    let expires_at = (state.clock.now() + NONCE_TTL)
+        .duration_since(UNIX_EPOCH)
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?
+        .as_secs();
+    challenge(&state, &nonce).await.map_err(|e| {
+        debug!("failed to store admin nonce: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(Challenge {
+        nonce: hex::encode(nonce),
+        expires_at,
+    }))
+}
+
+/// Exchanges a management enclave's attested certification request for an
+/// admin credential.
+pub async fn credential(
+    TypedHeader(ct): TypedHeader<ContentType>,
+    headers: HeaderMap,
+    body: Bytes,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Json<Credential>, StatusCode> {
+    let policy = state.policy();
+    if !policy.config.admin.enabled() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    if super::media_type(&ct) != PKCS10 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let (body, _) = super::decode_body(&headers, body)?;
+    let der = super::normalized(policy.config.parsing, &body).or(Err(StatusCode::BAD_REQUEST))?;
+    let cr = parse::cert_req(&der).or(Err(StatusCode::BAD_REQUEST))?;
+    let info = cr.verify().map_err(|e| {
+        debug!("failed to verify certificate info: {e}");
+        StatusCode::BAD_REQUEST
+    })?;
+
+    let issuer = Certificate::from_der(&state.crt).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    let platforms = appraise(&info, super::debug_mode(&issuer), &state).await?;
+
+    // Only the digest of the token is kept, alongside its expiry.
+    let token = hex::encode(rand::thread_rng().gen::<[u8; 32]>());
+    let ttl = policy.config.admin.ttl();
+    let expires_at = (state.clock.now() + ttl)
+        .duration_since(UNIX_EPOCH)
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?
+        .as_secs();
+    state
+        .shared
+        .put(&key(&token), &expires_at.to_be_bytes(), ttl)
+        .await
+        .map_err(|e| {
+            debug!("failed to store admin credential: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    info!(
+        "issued admin credential to {} management enclave",
+        platforms.join("+")
+    );
+    Ok(Json(Credential { token, expires_at }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate() {
+        let policy: Policy = toml::from_str("").unwrap();
+        assert!(!policy.enabled());
+        assert_eq!(policy.ttl(), TTL);
+        policy.validate().unwrap();
+
+        let policy: Policy = toml::from_str("ttl = 0").unwrap();
+        assert!(policy.validate().is_err());
+
+        let policy: Policy = toml::from_str("ttl = 86401").unwrap();
+        assert!(policy.validate().is_err());
+
+        assert!(toml::from_str::<Policy>("token = \"secret\"").is_err());
+    }
+
+    #[test]
+    fn auditor() {
+        let auditor = Auditor::new("token").unwrap();
+        assert!(auditor.authorized("token"));
+        assert!(!auditor.authorized("tokeN"));
+        assert!(Auditor::new("").is_err());
+    }
+
+    #[cfg(feature = "snp")]
+    mod snp {
+        use super::super::super::clock::Manual;
+        use super::super::super::{app, Archive, State, PKCS10};
+        use super::super::{challenge, Auditor, Challenge, Credential, NONCE};
+
+        use std::sync::Arc;
+        use std::time::{Duration, SystemTime};
+
+        use http::header::{AUTHORIZATION, CONTENT_TYPE};
+        use http::{Request, StatusCode};
+        use hyper::Body;
+        use tower::ServiceExt; // for `app.oneshot()`
+
+        const MILAN_CSR: &[u8] =
+            include_bytes!("../../../crates/attestation/src/snp/milan.signed.crl.csr");
+        const MILAN_HASH: &str = "ff717ae719840c93c1fca3b7db96488454c3c21b43531488eecff51cfed3febcd91da8be87a4cbcbc52a3bae770987c3";
+
+        fn state(hash: &str, clock: Arc<Manual>) -> State {
+            let mut state = State::generate(None, "localhost")
+                .unwrap()
+                .with_clock(clock)
+                .with_archive(Archive::new(Duration::from_secs(60)))
+                .with_auditor(Auditor::new("auditor").unwrap());
+            let admin = &mut state.config_mut().admin;
+            *admin = toml::from_str(&format!("snp.hash = [\"{hash}\"]")).unwrap();
+            admin.validate().unwrap();
+            state
+        }
+
+        fn credential() -> Request<Body> {
+            Request::builder()
+                .method("POST")
+                .uri("/admin/credential")
+                .header(CONTENT_TYPE, PKCS10)
+                .body(Body::from(MILAN_CSR))
+                .unwrap()
+        }
+
+        /// Issues the nonce carried by the fixture, which is all zeros.
+        async fn nonced(state: &State) {
+            challenge(state, &[0; NONCE]).await.unwrap();
+        }
+
+        fn evidence(token: &str) -> Request<Body> {
+            Request::builder()
+                .uri("/certs/00/evidence")
+                .header(AUTHORIZATION, format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap()
+        }
+
+        #[tokio::test]
+        async fn approved() {
+            let clock = Arc::new(Manual::new(SystemTime::now()));
+            let state = state(MILAN_HASH, clock.clone());
+            nonced(&state).await;
+
+            let response = app(state.clone()).oneshot(credential()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let credential: Credential = serde_json::from_slice(&body).unwrap();
+
+            // The credential opens the admin endpoints; the record is absent.
+            let response = app(state.clone()).oneshot(evidence(&credential.token));
+            assert_eq!(response.await.unwrap().status(), StatusCode::NOT_FOUND);
+
+            let response = app(state.clone()).oneshot(evidence("forged"));
+            assert_eq!(response.await.unwrap().status(), StatusCode::UNAUTHORIZED);
+
+            // Until it expires.
+            clock.advance(state.policy().config.admin.ttl());
+            let response = app(state).oneshot(evidence(&credential.token));
+            assert_eq!(response.await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        }
+
+        #[tokio::test]
+        async fn unapproved() {
+            let clock = Arc::new(Manual::new(SystemTime::now()));
+            let state = state(&"00".repeat(48), clock);
+            nonced(&state).await;
+
+            let response = app(state).oneshot(credential()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        #[tokio::test]
+        async fn replayed() {
+            let clock = Arc::new(Manual::new(SystemTime::now()));
+            let state = state(MILAN_HASH, clock);
+
+            // Evidence without an outstanding nonce is refused.
+            let response = app(state.clone()).oneshot(credential()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+            nonced(&state).await;
+            let response = app(state.clone()).oneshot(credential()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+
+            // As is the same request, once its nonce is spent.
+            let response = app(state.clone()).oneshot(credential()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+            nonced(&state).await;
+            let response = app(state).oneshot(credential()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        #[tokio::test]
+        async fn nonce() {
+            let clock = Arc::new(Manual::new(SystemTime::now()));
+            let state = state(MILAN_HASH, clock);
+
+            let request = || {
+                Request::builder()
+                    .method("POST")
+                    .uri("/admin/nonce")
+                    .body(Body::empty())
+                    .unwrap()
+            };
+            let response = app(state.clone()).oneshot(request()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let first: Challenge = serde_json::from_slice(&body).unwrap();
+            assert_eq!(hex::decode(&first.nonce).unwrap().len(), NONCE);
+
+            let response = app(state).oneshot(request()).await.unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let second: Challenge = serde_json::from_slice(&body).unwrap();
+            assert_ne!(first.nonce, second.nonce);
+
+            let state = State::generate(None, "localhost").unwrap();
+            let response = app(state).oneshot(request()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+
+        #[tokio::test]
+        async fn disabled() {
+            let state = State::generate(None, "localhost").unwrap();
+            let response = app(state).oneshot(credential()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+    }
+}
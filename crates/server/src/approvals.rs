@@ -0,0 +1,879 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Requests parked for an operator's approval.
+//!
+//! Given an `[approvals]` section in the policy, a request attesting
+//! measurements which no operator has approved is not issued a certificate
+//! at once, however well its evidence verifies. It is parked in the store
+//! instead and answered with `202 Accepted`, naming `GET /requests/{id}`,
+//! which the client polls: it answers `202` while the request awaits a
+//! decision, the certificate chain once an operator approves it, and `403`
+//! if they deny it. Approving a request approves its measurements, so that
+//! later requests attesting them are issued at once:
+//!
+//! ```toml
+//! [approvals]
+//! measurements = ["snp.measurement", "sgx.mrenclave"]
+//! ttl = 86400
+//! ```
+//!
+//! `measurements` names those which identify a workload, by default those
+//! in [`MEASUREMENTS`]; requests attesting none of them are not parked.
+//! Parked requests are forgotten after `ttl` seconds, a day by default.
+//!
+//! Operators list the pending requests at `GET /admin/requests`, as auditors
+//! may, and decide on one at `POST /admin/requests/{id}` with
+//! `{"decision": "approve"}` or `"deny"`, bearing an admin credential. Given
+//! approver CAs (`--approver-ca`), deciding also takes a client certificate
+//! issued by one of them, valid at the time, which a trusted proxy
+//! terminating TLS forwards; its subject is recorded as the approver. The
+//! certificate is issued on approval, so the evidence must still verify, and
+//! be fresh, then. Requests in a bundle are parked alike, but nothing is
+//! issued for the bundle while any of it is parked: it is answered with the
+//! IDs of those parked, and must be sent again once they are approved.
+
+use super::identities;
+use super::proxy::Peer;
+use super::store::{AuditRecord, Decision, Parked};
+use super::verifier::Appraisal;
+use super::{admin, State};
+
+use std::collections::BTreeMap;
+use std::io::BufRead;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{ensure, Result};
+use attestation::crypto::TbsCertificateExt;
+use axum::body::Bytes;
+use axum::extract::{ConnectInfo, Extension, Path, TypedHeader};
+use axum::headers::authorization::{Authorization, Bearer};
+use axum::http::header::{LOCATION, RETRY_AFTER};
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use der::Decode;
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tracing::{debug, info};
+use x509::Certificate;
+
+/// The measurements which identify a workload, unless the policy names
+/// others.
+pub const MEASUREMENTS: &[&str] = &["sgx.mrenclave", "snp.measurement", "se.tag", "cove.tvm"];
+
+/// The default lifetime of parked requests.
+const TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The longest lifetime which may be configured for parked requests.
+const MAX_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// How long clients are asked to wait before polling again, in seconds.
+const POLL: &str = "30";
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    /// The measurements which identify a workload, such as `sgx.mrenclave`.
+    pub measurements: Option<Vec<String>>,
+
+    /// The lifetime, in seconds, of parked requests.
+    pub ttl: Option<u64>,
+}
+
+impl Policy {
+    /// The lifetime of parked requests.
+    pub fn ttl(&self) -> Duration {
+        self.ttl.map_or(TTL, Duration::from_secs)
+    }
+
+    /// Refuses policies which would park nothing, or forever.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(measurements) = &self.measurements {
+            ensure!(
+                !measurements.is_empty(),
+                "approvals must name at least one measurement"
+            );
+        }
+
+        let ttl = self.ttl();
+        ensure!(
+            !ttl.is_zero() && ttl <= MAX_TTL,
+            "parked request lifetime must be between 1 and {} seconds",
+            MAX_TTL.as_secs()
+        );
+        Ok(())
+    }
+
+    /// The measurements of `appraisals` which must be approved.
+    fn measurements(&self, appraisals: &[Appraisal]) -> BTreeMap<String, String> {
+        let named = |name: &String| match &self.measurements {
+            Some(names) => names.contains(name),
+            None => MEASUREMENTS.contains(&name.as_str()),
+        };
+        identities::measurements(appraisals)
+            .into_iter()
+            .filter(|(name, _)| named(name))
+            .collect()
+    }
+}
+
+/// The CAs whose client certificates identify the operators who may decide
+/// on parked requests.
+#[derive(Clone, Debug)]
+pub struct Approvers(Vec<Vec<u8>>);
+
+impl Approvers {
+    /// Reads one or more PEM CA certificates.
+    pub fn read(mut pem: impl BufRead) -> Result<Self> {
+        Self::new(rustls_pemfile::certs(&mut pem)?)
+    }
+
+    /// Trusts the DER certificates `cas`.
+    pub fn new(cas: Vec<Vec<u8>>) -> Result<Self> {
+        ensure!(!cas.is_empty(), "no approver ca certificate found");
+        for ca in &cas {
+            Certificate::from_der(ca)?;
+        }
+        Ok(Self(cas))
+    }
+
+    /// The subject of the DER `crt`, if one of the CAs issued it and it is
+    /// valid at `now`.
+    fn approver(&self, crt: &[u8], now: SystemTime) -> Option<String> {
+        let crt = Certificate::from_der(crt).ok()?;
+        let validity = &crt.tbs_certificate.validity;
+        if now < validity.not_before.to_system_time() || now >= validity.not_after.to_system_time()
+        {
+            return None;
+        }
+
+        self.0
+            .iter()
+            .filter_map(|ca| Certificate::from_der(ca).ok())
+            .find(|ca| ca.tbs_certificate.verify_crt(&crt).is_ok())?;
+        Some(crt.tbs_certificate.subject.to_string())
+    }
+}
+
+/// The approver named by the client certificate of a request, if approver
+/// CAs are configured.
+fn approver(
+    state: &State,
+    peer: Option<ConnectInfo<Peer>>,
+    headers: &HeaderMap,
+) -> Result<Option<String>, StatusCode> {
+    let approvers = match &state.approvers {
+        Some(approvers) => approvers,
+        None => return Ok(None),
+    };
+
+    let crt = peer.and_then(|ConnectInfo(Peer(peer))| state.proxies.client_crt(peer.ip(), headers));
+    let crt = crt.ok_or_else(|| {
+        debug!("no client certificate forwarded for decision");
+        StatusCode::UNAUTHORIZED
+    })?;
+    match approvers.approver(&crt, state.clock.now()) {
+        Some(subject) => Ok(Some(subject)),
+        None => {
+            debug!("client certificate not issued by an approver ca, or not current");
+            Err(StatusCode::FORBIDDEN)
+        }
+    }
+}
+
+/// The digest under which `measurements` are approved.
+fn fingerprint(measurements: &BTreeMap<String, String>) -> Result<[u8; 32]> {
+    Ok(Sha256::digest(serde_json::to_vec(measurements)?).into())
+}
+
+/// Parks the DER `request`, asking for the issuance `profile`, unless its
+/// measurements are approved, or are those an operator is `approving`,
+/// returning its ID if it was.
+pub(crate) async fn park(
+    state: &State,
+    policy: &Policy,
+    request: &[u8],
+    appraisals: &[Appraisal],
+    profile: Option<&str>,
+    approving: Option<&BTreeMap<String, String>>,
+) -> Result<Option<String>> {
+    let measurements = policy.measurements(appraisals);
+    if measurements.is_empty() || approving == Some(&measurements) {
+        return Ok(None);
+    }
+    if state.store.approved(&fingerprint(&measurements)?).await? {
+        return Ok(None);
+    }
+
+    // The same request, sent again, awaits the same decision.
+    let now = state.clock.now();
+    let parked = Parked {
+        id: hex::encode(Sha256::digest(request)),
+        received_at: now,
+        expires_at: now + policy.ttl(),
+        request: request.to_vec(),
+        measurements,
+        profile: profile.map(Into::into),
+        decision: Decision::Pending,
+    };
+    if state.store.park(&parked).await? {
+        let record = AuditRecord {
+            at: now,
+            event: "approval".into(),
+            detail: format!("parked request {} for approval", parked.id),
+        };
+        state.store.audit(&record).await?;
+        state.notify(
+            &record.event,
+            json!({
+                "id": parked.id,
+                "decision": "pending",
+                "measurements": parked.measurements,
+            }),
+        );
+        info!("{}", record.detail);
+    }
+    Ok(Some(parked.id))
+}
+
+/// The answer to requests which were parked.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Accepted {
+    /// The IDs of the parked requests.
+    pub requests: Vec<String>,
+}
+
+impl Accepted {
+    /// Answers with the IDs of the parked requests, pointing to the one
+    /// request to poll if there is only one.
+    pub(crate) fn respond(self) -> Response {
+        let mut response =
+            (StatusCode::ACCEPTED, [(RETRY_AFTER, POLL)], Json(&self)).into_response();
+        if let [id] = &self.requests[..] {
+            if let Ok(location) = format!("/requests/{id}").parse() {
+                response.headers_mut().insert(LOCATION, location);
+            }
+        }
+        response
+    }
+}
+
+/// Answers a client polling for the decision on its request.
+pub async fn request(
+    Extension(state): Extension<Arc<State>>,
+    Path(id): Path<String>,
+) -> Result<Response, StatusCode> {
+    let parked = state.store.parked(&id, state.clock.now()).await;
+    let parked = parked.map_err(|e| {
+        debug!("failed to look up parked request: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    match parked.map(|p| p.decision) {
+        None => Err(StatusCode::NOT_FOUND),
+        Some(Decision::Pending | Decision::Deciding) => {
+            Ok((StatusCode::ACCEPTED, [(RETRY_AFTER, POLL)]).into_response())
+        }
+        Some(Decision::Approved(chain)) => Ok(chain.into_response()),
+        Some(Decision::Denied) => Err(StatusCode::FORBIDDEN),
+    }
+}
+
+/// A request awaiting an operator's decision.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Pending {
+    pub id: String,
+
+    /// Seconds since the Unix epoch.
+    pub received_at: u64,
+
+    /// Seconds since the Unix epoch.
+    pub expires_at: u64,
+
+    /// The hex measurements which need approval, by platform and name.
+    pub measurements: BTreeMap<String, String>,
+}
+
+/// Lists the requests awaiting a decision, oldest first.
+pub async fn requests(
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Json<Vec<Pending>>, StatusCode> {
+    admin::authorize(&state, auth, true).await?;
+
+    let pending = state.store.pending(state.clock.now()).await.map_err(|e| {
+        debug!("failed to list parked requests: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let secs = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    };
+    let pending = pending
+        .into_iter()
+        .map(|parked| Pending {
+            id: parked.id,
+            received_at: secs(parked.received_at),
+            expires_at: secs(parked.expires_at),
+            measurements: parked.measurements,
+        })
+        .collect();
+    Ok(Json(pending))
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Verdict {
+    Approve,
+    Deny,
+}
+
+/// The body of `POST /admin/requests/{id}`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Decide {
+    decision: Verdict,
+}
+
+/// Approves or denies a parked request.
+pub async fn decide(
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    peer: Option<ConnectInfo<Peer>>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<State>>,
+    Path(id): Path<String>,
+    body: Bytes,
+) -> Result<StatusCode, StatusCode> {
+    admin::authorize(&state, auth, false).await?;
+    let approver = approver(&state, peer, &headers)?;
+    let Decide { decision } = serde_json::from_slice(&body).map_err(|e| {
+        debug!("invalid decision: {e}");
+        StatusCode::BAD_REQUEST
+    })?;
+
+    let failed = |e: anyhow::Error| {
+        debug!("failed to decide on request {id}: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    let parked = state.store.parked(&id, state.clock.now()).await;
+    let parked = parked.map_err(failed)?.ok_or(StatusCode::NOT_FOUND)?;
+
+    // Only the operator claiming the request decides on it.
+    if !state.store.claim(&id).await.map_err(failed)? {
+        return Err(StatusCode::CONFLICT);
+    }
+    let decision = match decision {
+        Verdict::Approve => match super::reissue(&state, &parked).await {
+            Ok(chain) => Decision::Approved(chain),
+            Err(status) => {
+                state.store.release(&id).await.map_err(failed)?;
+                return Err(status);
+            }
+        },
+        Verdict::Deny => Decision::Denied,
+    };
+
+    // Approved measurements are never parked again.
+    let fingerprint = fingerprint(&parked.measurements).map_err(failed)?;
+    let decided = state.store.decide(&id, &decision, &fingerprint).await;
+    if !decided.map_err(failed)? {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let verdict = match decision {
+        Decision::Approved(..) => "approved",
+        _ => "denied",
+    };
+    let detail = match &approver {
+        Some(approver) => format!("{verdict} request {id} as `{approver}`"),
+        None => format!("{verdict} request {id}"),
+    };
+    let record = AuditRecord {
+        at: state.clock.now(),
+        event: "approval".into(),
+        detail,
+    };
+    state.store.audit(&record).await.map_err(failed)?;
+    state.notify(
+        &record.event,
+        json!({
+            "id": id,
+            "decision": verdict,
+            "approver": approver,
+            "measurements": parked.measurements,
+        }),
+    );
+    info!("{}", record.detail);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(all(test, feature = "snp"))]
+mod tests {
+    use super::super::admin::Auditor;
+    use super::super::audit::{Event, Sink};
+    use super::super::proxy::Trusted;
+    use super::super::quota::{self, Per, Quota};
+    use super::super::{app, BUNDLE, PKCS10, PROFILE_HEADER};
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use attestation::crypto::{PrivateKeyInfoExt, SubjectPublicKeyInfoExt};
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine;
+    use const_oid::db::rfc5912::SECP_256_R_1;
+    use der::asn1::UIntRef;
+    use der::Encode;
+    use http::header::{AUTHORIZATION, CONTENT_TYPE};
+    use http::{Method, Request};
+    use hyper::Body;
+    use sec1::pkcs8::PrivateKeyInfo;
+    use tower::ServiceExt; // for `app.oneshot()`
+    use x509::name::RdnSequence;
+    use x509::request::CertReq;
+    use x509::time::{Time, Validity};
+    use x509::{PkiPath, TbsCertificate};
+
+    #[derive(Debug, Default)]
+    struct Recorder(Mutex<Vec<Event>>);
+
+    impl Sink for Recorder {
+        fn emit(&self, event: &Event) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    const MILAN_CSR: &[u8] = include_bytes!("../../attestation/src/snp/milan.signed.crl.csr");
+
+    /// A steward parking requests, on which `operator` is an admin credential.
+    async fn state() -> State {
+        let mut state = State::generate(None, "localhost")
+            .unwrap()
+            .with_auditor(Auditor::new("auditor").unwrap());
+        state.config_mut().approvals = Some(Policy::default());
+
+        let ttl = Duration::from_secs(60);
+        let expires_at = (state.clock.now() + ttl).duration_since(UNIX_EPOCH);
+        let expires_at = expires_at.unwrap().as_secs().to_be_bytes();
+        state
+            .shared
+            .put(&admin::key("operator"), &expires_at, ttl)
+            .await
+            .unwrap();
+        state
+    }
+
+    async fn call(
+        state: &State,
+        method: Method,
+        uri: &str,
+        token: &str,
+        body: impl Into<Body>,
+    ) -> Response {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(CONTENT_TYPE, PKCS10)
+            .header(AUTHORIZATION, format!("Bearer {token}"))
+            .body(body.into())
+            .unwrap();
+        app(state.clone()).oneshot(request).await.unwrap()
+    }
+
+    /// Sends the canned Milan request, asking for `profile` if given,
+    /// returning the ID it was parked under.
+    async fn attest(state: &State, profile: Option<&str>) -> String {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri("/")
+            .header(CONTENT_TYPE, PKCS10);
+        if let Some(profile) = profile {
+            request = request.header(PROFILE_HEADER, profile);
+        }
+        let request = request.body(Body::from(MILAN_CSR)).unwrap();
+        let response = app(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let location = response.headers()[LOCATION].to_str().unwrap().to_owned();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let accepted: Accepted = serde_json::from_slice(&body).unwrap();
+        assert_eq!(location, format!("/requests/{}", accepted.requests[0]));
+        accepted.requests[0].clone()
+    }
+
+    /// Issues a client certificate for `CN=alice` from the CA of `ca`, valid
+    /// from `from` for an hour, as a proxy would forward it.
+    fn certify(ca: &State, from: SystemTime) -> String {
+        let ca_crt = Certificate::from_der(&ca.crt).unwrap();
+        let ca_pki = PrivateKeyInfo::from_der(&ca.key).unwrap();
+        let key = PrivateKeyInfo::generate(SECP_256_R_1).unwrap();
+        let pki = PrivateKeyInfo::from_der(key.as_ref()).unwrap();
+        let subject = RdnSequence::encode_from_string("CN=alice").unwrap();
+
+        let crt = TbsCertificate {
+            version: x509::Version::V3,
+            serial_number: UIntRef::new(&[1]).unwrap(),
+            signature: ca_pki.signs_with().unwrap(),
+            issuer: ca_crt.tbs_certificate.subject.clone(),
+            validity: Validity {
+                not_before: Time::try_from(from).unwrap(),
+                not_after: Time::try_from(from + Duration::from_secs(3600)).unwrap(),
+            },
+            subject: RdnSequence::from_der(&subject).unwrap(),
+            subject_public_key_info: pki.public_key().unwrap(),
+            issuer_unique_id: None,
+            subject_unique_id: None,
+            extensions: None,
+        }
+        .sign(&ca_pki)
+        .unwrap();
+
+        let pem = format!(
+            "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+            BASE64.encode(crt)
+        );
+        pem.replace('+', "%2B")
+            .replace('/', "%2F")
+            .replace('=', "%3D")
+            .replace('\n', "%0A")
+    }
+
+    /// Decides on request `id` through a proxy at `proxy`, forwarding `crt`.
+    async fn decide_via(state: &State, id: &str, proxy: &str, crt: Option<&str>) -> StatusCode {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("/admin/requests/{id}"))
+            .header(AUTHORIZATION, "Bearer operator")
+            .extension(ConnectInfo(Peer(proxy.parse().unwrap())));
+        if let Some(crt) = crt {
+            request = request.header("x-client-cert", crt);
+        }
+        let request = request.body(Body::from(decision("approve"))).unwrap();
+        app(state.clone()).oneshot(request).await.unwrap().status()
+    }
+
+    fn decision(decision: &str) -> String {
+        json!({ "decision": decision }).to_string()
+    }
+
+    #[test]
+    fn validate() {
+        let policy: Policy = toml::from_str("").unwrap();
+        assert_eq!(policy.ttl(), TTL);
+        policy.validate().unwrap();
+
+        let policy: Policy = toml::from_str("measurements = []").unwrap();
+        assert!(policy.validate().is_err());
+        for ttl in [0, MAX_TTL.as_secs() + 1] {
+            let policy = Policy {
+                ttl: Some(ttl),
+                ..Default::default()
+            };
+            assert!(policy.validate().is_err(), "{ttl}");
+        }
+    }
+
+    #[tokio::test]
+    async fn approved() {
+        let state = state().await;
+
+        // A request is parked, and sending it again changes nothing.
+        let id = attest(&state, None).await;
+        assert_eq!(attest(&state, None).await, id);
+        let uri = format!("/requests/{id}");
+        let response = call(&state, Method::GET, &uri, "", Body::empty()).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        // Auditors see what awaits a decision, but may not decide.
+        let response = call(&state, Method::GET, "/admin/requests", "auditor", "").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let pending: Vec<Pending> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, id);
+        assert!(pending[0].measurements.contains_key("snp.measurement"));
+
+        let admin = format!("/admin/requests/{id}");
+        let approve = decision("approve");
+        let response = call(&state, Method::POST, &admin, "auditor", approve.clone()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // Once approved, the client collects its certificate.
+        let response = call(&state, Method::POST, &admin, "operator", approve.clone()).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = call(&state, Method::GET, &uri, "", Body::empty()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(PkiPath::from_der(&body).unwrap().len(), 2);
+
+        // Each request is decided once, and its measurements stay approved.
+        let response = call(&state, Method::POST, &admin, "operator", approve).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = call(&state, Method::POST, "/", "", MILAN_CSR).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn profiled() {
+        let mut state = state().await;
+        state.config_mut().validity = toml::from_str(
+            r#"
+            [profiles.builder]
+            lifetime = 3600
+            platforms = ["snp"]
+            "#,
+        )
+        .unwrap();
+
+        let id = attest(&state, Some("builder")).await;
+
+        // Once approved, the certificate is issued under the profile asked for.
+        let admin = format!("/admin/requests/{id}");
+        let response = call(
+            &state,
+            Method::POST,
+            &admin,
+            "operator",
+            decision("approve"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let uri = format!("/requests/{id}");
+        let response = call(&state, Method::GET, &uri, "", Body::empty()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let validity = PkiPath::from_der(&body).unwrap()[1]
+            .tbs_certificate
+            .validity;
+        let start = validity.not_before.to_system_time();
+        let lifetime = Duration::from_secs(3600);
+        assert_eq!(validity.not_after.to_system_time(), start + lifetime);
+    }
+
+    #[tokio::test]
+    async fn quota() {
+        let mut state = state().await;
+        state.config_mut().quotas = quota::Policy(vec![Quota {
+            platform: "snp".into(),
+            per: Per::ChipId,
+            limit: 1,
+            window: 3600,
+        }]);
+
+        // Parked requests are not counted against quotas, however often sent.
+        let id = attest(&state, None).await;
+        assert_eq!(attest(&state, None).await, id);
+
+        // The certificate issued on approval is.
+        let admin = format!("/admin/requests/{id}");
+        let response = call(
+            &state,
+            Method::POST,
+            &admin,
+            "operator",
+            decision("approve"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = call(&state, Method::POST, "/", "", MILAN_CSR).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[cfg(feature = "kvm")]
+    fn kvm() -> Vec<u8> {
+        use super::super::Kvm;
+        use attestation::crypto::CertReqInfoExt;
+        use const_oid::db::rfc5912::ID_EXTENSION_REQ;
+        use der::asn1::AnyRef;
+        use x509::attr::Attribute;
+        use x509::ext::Extension;
+        use x509::request::{CertReqInfo, ExtensionReq, Version};
+
+        let key = PrivateKeyInfo::generate(SECP_256_R_1).unwrap();
+        let pki = PrivateKeyInfo::from_der(key.as_ref()).unwrap();
+        let ext = Extension {
+            extn_id: Kvm::OID,
+            critical: false,
+            extn_value: &[],
+        };
+        let req = ExtensionReq::from(vec![ext]).to_vec().unwrap();
+        let attribute = Attribute {
+            oid: ID_EXTENSION_REQ,
+            values: vec![AnyRef::from_der(&req).unwrap()].try_into().unwrap(),
+        };
+        CertReqInfo {
+            version: Version::V1,
+            subject: RdnSequence::default(),
+            public_key: pki.public_key().unwrap(),
+            attributes: vec![attribute].try_into().unwrap(),
+        }
+        .sign(&pki)
+        .unwrap()
+    }
+
+    #[cfg(feature = "kvm")]
+    #[tokio::test]
+    async fn bundle() {
+        let state = state().await;
+        let kvm = kvm();
+        let requests = vec![
+            CertReq::from_der(&kvm).unwrap(),
+            CertReq::from_der(MILAN_CSR).unwrap(),
+        ];
+        let bundle = requests.to_vec().unwrap();
+        let attest = || {
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri("/")
+                .header(CONTENT_TYPE, BUNDLE)
+                .body(Body::from(bundle.clone()))
+                .unwrap();
+            app(state.clone()).oneshot(request)
+        };
+
+        // Nothing is issued for a bundle while any of it awaits approval.
+        let response = attest().await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let accepted: Accepted = serde_json::from_slice(&body).unwrap();
+        assert_eq!(accepted.requests.len(), 1);
+        assert!(state.store.log_leaves(0).await.unwrap().is_empty());
+
+        // Once approved, the whole bundle is issued for when sent again.
+        let admin = format!("/admin/requests/{}", accepted.requests[0]);
+        let response = call(
+            &state,
+            Method::POST,
+            &admin,
+            "operator",
+            decision("approve"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(state.store.log_leaves(0).await.unwrap().len(), 1);
+        let response = attest().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(state.store.log_leaves(0).await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn contended() {
+        let state = state().await;
+        let id = attest(&state, None).await;
+
+        // Of operators approving the request at once, only one issues for it.
+        let admin = format!("/admin/requests/{id}");
+        let approve = || {
+            call(
+                &state,
+                Method::POST,
+                &admin,
+                "operator",
+                decision("approve"),
+            )
+        };
+        let (a, b) = tokio::join!(approve(), approve());
+        let mut statuses = [a.status(), b.status()];
+        statuses.sort();
+        assert_eq!(statuses, [StatusCode::NO_CONTENT, StatusCode::CONFLICT]);
+        assert_eq!(state.store.log_leaves(0).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn unissued() {
+        let state = state().await;
+        let id = attest(&state, Some("missing")).await;
+
+        // Where no certificate can be issued, nothing is approved.
+        let admin = format!("/admin/requests/{id}");
+        let response = call(
+            &state,
+            Method::POST,
+            &admin,
+            "operator",
+            decision("approve"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let uri = format!("/requests/{id}");
+        let response = call(&state, Method::GET, &uri, "", Body::empty()).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(attest(&state, None).await, id);
+
+        // And the request may still be decided on.
+        let response = call(&state, Method::POST, &admin, "operator", decision("deny")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn denied() {
+        let state = state().await;
+        let id = attest(&state, None).await;
+
+        let admin = format!("/admin/requests/{id}");
+        let response = call(&state, Method::POST, &admin, "operator", decision("deny")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        // The denial stands for the request, however often it is sent.
+        let uri = format!("/requests/{id}");
+        let response = call(&state, Method::GET, &uri, "", Body::empty()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(attest(&state, None).await, id);
+        let response = call(&state, Method::GET, &uri, "", Body::empty()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = call(&state, Method::GET, "/requests/00", "", Body::empty()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn approvers() {
+        let issuer = State::generate(None, "approvers").unwrap();
+        let stranger = State::generate(None, "approvers").unwrap();
+        let approvers = Approvers::new(vec![issuer.crt.clone()]).unwrap();
+        let recorder = Arc::new(Recorder::default());
+        let state = state()
+            .await
+            .with_proxies(Trusted::new(vec!["10.0.0.0/8".parse().unwrap()]))
+            .with_approvers(approvers)
+            .with_audit_sink(recorder.clone());
+        let id = attest(&state, None).await;
+        let now = state.clock.now();
+
+        // The admin token alone no longer suffices.
+        let response = call(
+            &state,
+            Method::POST,
+            &format!("/admin/requests/{id}"),
+            "operator",
+            decision("approve"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // Nor does a certificate which is forged, stale, or not forwarded by
+        // a trusted proxy.
+        let alice = certify(&issuer, now - Duration::from_secs(60));
+        let forged = certify(&stranger, now - Duration::from_secs(60));
+        let stale = certify(&issuer, now - Duration::from_secs(7200));
+        let status = decide_via(&state, &id, "192.0.2.1:443", Some(&alice)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        for crt in [&forged, &stale] {
+            let status = decide_via(&state, &id, "10.0.0.1:443", Some(crt)).await;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+        }
+
+        // The approver is recorded in the audit trail.
+        let status = decide_via(&state, &id, "10.0.0.1:443", Some(&alice)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let events = recorder.0.lock().unwrap();
+        let decided = events.last().unwrap();
+        assert_eq!(decided.detail["decision"], "approved");
+        assert_eq!(decided.detail["approver"], "CN=alice");
+    }
+}
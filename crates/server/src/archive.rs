@@ -0,0 +1,230 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Archival of attestation evidence for later audit.
+
+use super::verifier::Appraisal;
+use super::{admin, State};
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{anyhow, bail, ensure, Result};
+use axum::extract::{Extension, Path, TypedHeader};
+use axum::headers::authorization::{Authorization, Bearer};
+use axum::Json;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hyper::StatusCode;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+use zeroize::Zeroizing;
+
+const PLAIN: u8 = 0;
+const AES_256_GCM: u8 = 1;
+
+/// The evidence behind an issued certificate.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Evidence {
+    /// The hex serial number of the issued certificate.
+    pub serial: String,
+
+    /// Seconds since the Unix epoch.
+    pub archived_at: u64,
+
+    /// The platforms whose evidence was appraised.
+    pub platforms: Vec<String>,
+
+    /// What the appraisal of each piece of evidence found.
+    #[serde(default)]
+    pub appraisals: Vec<Appraisal>,
+
+    /// The version of the policy it was appraised against.
+    #[serde(default)]
+    pub policy_version: u64,
+
+    /// The base64 DER certification request which carried the evidence.
+    pub request: String,
+}
+
+impl Evidence {
+    pub fn new(
+        serial: &[u8],
+        appraisals: Vec<Appraisal>,
+        policy_version: u64,
+        request: &[u8],
+        now: SystemTime,
+    ) -> Self {
+        let archived_at = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+        Self {
+            serial: hex::encode(serial),
+            archived_at,
+            platforms: appraisals.iter().map(|a| a.platform.clone()).collect(),
+            appraisals,
+            policy_version,
+            request: BASE64.encode(request),
+        }
+    }
+}
+
+/// Archival settings.
+///
+/// Records are optionally encrypted at rest with AES-256-GCM, bound to the
+/// certificate serial number so that they cannot be swapped between rows.
+#[derive(Clone)]
+pub struct Archive {
+    retention: Duration,
+    key: Option<Zeroizing<[u8; 32]>>,
+}
+
+impl std::fmt::Debug for Archive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Archive")
+            .field("retention", &self.retention)
+            .field("encrypted", &self.key.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Archive {
+    /// Creates an archive whose records are kept for `retention`.
+    pub fn new(retention: Duration) -> Self {
+        Self {
+            retention,
+            key: None,
+        }
+    }
+
+    /// Encrypts records at rest with the 256-bit `key`.
+    pub fn with_key(mut self, key: &[u8]) -> Result<Self> {
+        ensure!(key.len() == 32, "invalid archive key length");
+
+        // Copy straight into the zeroizing buffer, not via the stack.
+        let mut buf = Zeroizing::new([0u8; 32]);
+        buf.copy_from_slice(key);
+        self.key = Some(buf);
+        Ok(self)
+    }
+
+    /// How long records are kept.
+    pub fn retention(&self) -> Duration {
+        self.retention
+    }
+
+    pub(crate) fn seal(&self, serial: &[u8], evidence: &Evidence) -> Result<Vec<u8>> {
+        let json = Zeroizing::new(serde_json::to_vec(evidence)?);
+
+        let key = match &self.key {
+            None => return Ok([&[PLAIN][..], &json].concat()),
+            Some(key) => key,
+        };
+
+        let nonce: [u8; 12] = rand::thread_rng().gen();
+        let payload = Payload {
+            msg: &json,
+            aad: serial,
+        };
+        let sealed = cipher(key)?
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| anyhow!("failed to encrypt evidence"))?;
+        Ok([&[AES_256_GCM][..], &nonce, &sealed].concat())
+    }
+
+    pub(crate) fn open(&self, serial: &[u8], data: &[u8]) -> Result<Evidence> {
+        let json = match (data.split_first(), &self.key) {
+            (Some((&PLAIN, json)), _) => Zeroizing::new(json.to_vec()),
+            (Some((&AES_256_GCM, sealed)), Some(key)) => {
+                ensure!(sealed.len() >= 12, "truncated evidence");
+                let (nonce, msg) = sealed.split_at(12);
+                let payload = Payload { msg, aad: serial };
+                let json = cipher(key)?
+                    .decrypt(Nonce::from_slice(nonce), payload)
+                    .map_err(|_| anyhow!("failed to decrypt evidence"))?;
+                Zeroizing::new(json)
+            }
+            (Some((&AES_256_GCM, _)), None) => bail!("evidence is encrypted"),
+            _ => bail!("unknown evidence format"),
+        };
+
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
+
+fn cipher(key: &[u8; 32]) -> Result<Aes256Gcm> {
+    Aes256Gcm::new_from_slice(key).map_err(|_| anyhow!("invalid archive key"))
+}
+
+/// Returns the evidence archived for the certificate with the hex `serial`.
+pub async fn evidence(
+    Path(serial): Path<String>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Json<Evidence>, StatusCode> {
+    let archive = state.archive.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+
+    // Auditors and attested operators alike may read the archive.
+    admin::authorize(&state, auth, true).await?;
+
+    let serial = hex::decode(serial).or(Err(StatusCode::BAD_REQUEST))?;
+    let data = state
+        .store
+        .archived(&serial)
+        .await
+        .map_err(|e| {
+            debug!("failed to read archived evidence: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    archive.open(&serial, &data).map(Json).map_err(|e| {
+        debug!("failed to open archived evidence: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(60 * 60 * 24);
+
+    #[test]
+    fn plain() {
+        let archive = Archive::new(DAY);
+        let evidence = Evidence::new(
+            &[1, 2],
+            vec![Appraisal::new("sgx", true).with_measurement("mrenclave", &[1; 32])],
+            1,
+            b"request",
+            SystemTime::now(),
+        );
+        let sealed = archive.seal(&[1, 2], &evidence).unwrap();
+        assert_eq!(archive.open(&[1, 2], &sealed).unwrap(), evidence);
+    }
+
+    #[test]
+    fn encrypted() {
+        let archive = Archive::new(DAY).with_key(&[7; 32]).unwrap();
+        let evidence = Evidence::new(
+            &[1, 2],
+            vec![Appraisal::new("snp", true)],
+            1,
+            b"request",
+            SystemTime::now(),
+        );
+        let sealed = archive.seal(&[1, 2], &evidence).unwrap();
+        assert_eq!(sealed[0], AES_256_GCM);
+        assert_eq!(archive.open(&[1, 2], &sealed).unwrap(), evidence);
+
+        // Bound to the serial number.
+        assert!(archive.open(&[1, 3], &sealed).is_err());
+
+        // Unreadable without the key.
+        let plain = Archive::new(DAY);
+        assert!(plain.open(&[1, 2], &sealed).is_err());
+    }
+}
@@ -0,0 +1,151 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Hand encoding of the DER structures of the enrollment and timestamping
+//! protocols.
+//!
+//! SCEP, CMP and RFC 3161 messages nest CMS and CRMF structures which the
+//! `der` and `x509` crates do not model, so they are taken apart as generic
+//! values and put together from the encodings of their parts.
+
+use anyhow::{bail, ensure, Result};
+use const_oid::ObjectIdentifier;
+use der::asn1::AnyRef;
+use der::{Decode, Encode, Reader, SliceReader, Tagged};
+use x509::Certificate;
+
+pub const BOOLEAN: u8 = 0x01;
+pub const INTEGER: u8 = 0x02;
+pub const BIT_STRING: u8 = 0x03;
+pub const OCTET_STRING: u8 = 0x04;
+pub const OBJECT_IDENTIFIER: u8 = 0x06;
+pub const PRINTABLE_STRING: u8 = 0x13;
+pub const GENERALIZED_TIME: u8 = 0x18;
+pub const SEQUENCE: u8 = 0x30;
+pub const SET: u8 = 0x31;
+
+pub const ID_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.1");
+pub const ID_SIGNED_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.2");
+pub const ID_CONTENT_TYPE: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.3");
+pub const ID_MESSAGE_DIGEST: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.4");
+
+/// The tag of a context-specific, constructed `[n]`, as every explicit tag is.
+pub const fn constructed(n: u8) -> u8 {
+    0xa0 | n
+}
+
+/// The tag of a context-specific, primitive `[n]`.
+pub const fn primitive(n: u8) -> u8 {
+    0x80 | n
+}
+
+/// Encodes `value` with `tag`.
+pub fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut der = vec![tag];
+    match value.len() {
+        len @ 0..=0x7f => der.push(len as u8),
+        len => {
+            let bytes = len.to_be_bytes();
+            let skip = bytes.iter().take_while(|b| **b == 0).count();
+            der.push(0x80 | (bytes.len() - skip) as u8);
+            der.extend_from_slice(&bytes[skip..]);
+        }
+    }
+    der.extend_from_slice(value);
+    der
+}
+
+pub fn sequence(fields: &[&[u8]]) -> Vec<u8> {
+    tlv(SEQUENCE, &fields.concat())
+}
+
+/// Encodes a SET OF, whose elements DER orders by their encodings.
+pub fn set(mut elements: Vec<Vec<u8>>) -> Vec<u8> {
+    elements.sort();
+    tlv(SET, &elements.concat())
+}
+
+pub fn oid(oid: ObjectIdentifier) -> Result<Vec<u8>> {
+    Ok(oid.to_vec()?)
+}
+
+/// Encodes a BIT STRING with only the named bit `bit` set.
+pub fn named_bit(bit: usize) -> Vec<u8> {
+    let mut bits = vec![0; bit / 8 + 1];
+    bits[bit / 8] = 0x80 >> (bit % 8);
+    let unused = 7 - bit % 8;
+    tlv(BIT_STRING, &[&[unused as u8][..], &bits].concat())
+}
+
+pub fn tag(any: &AnyRef<'_>) -> u8 {
+    any.tag().into()
+}
+
+/// Decodes the elements of a constructed value.
+pub fn elements(value: &[u8]) -> Result<Vec<AnyRef<'_>>> {
+    let mut reader = SliceReader::new(value)?;
+    let mut elements = Vec::new();
+    while !reader.is_finished() {
+        elements.push(reader.decode()?);
+    }
+    Ok(elements)
+}
+
+/// Encodes a CMS `Attribute` with a single value.
+pub fn attribute(id: ObjectIdentifier, value: &[u8]) -> Result<Vec<u8>> {
+    Ok(sequence(&[&oid(id)?, &tlv(SET, value)]))
+}
+
+pub fn content_info(content_type: ObjectIdentifier, content: &[u8]) -> Result<Vec<u8>> {
+    Ok(sequence(&[
+        &oid(content_type)?,
+        &tlv(constructed(0), content),
+    ]))
+}
+
+/// Identifies a certificate as CMS does.
+pub fn issuer_and_serial(crt: &Certificate<'_>) -> Result<Vec<u8>> {
+    let tbs = &crt.tbs_certificate;
+    Ok(sequence(&[
+        &tbs.issuer.to_vec()?,
+        &tbs.serial_number.to_vec()?,
+    ]))
+}
+
+/// Decodes a `ContentInfo` of `content_type`, returning its content.
+pub fn content_of<'a>(der: &'a [u8], content_type: ObjectIdentifier) -> Result<AnyRef<'a>> {
+    match &elements(AnyRef::from_der(der)?.value())?[..] {
+        [id, content] if tag(content) == constructed(0) => {
+            ensure!(
+                id.decode_into::<ObjectIdentifier>()? == content_type,
+                "expected {content_type} content"
+            );
+            Ok(AnyRef::from_der(content.value())?)
+        }
+        _ => bail!("invalid content info"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use der::Decode;
+
+    #[test]
+    fn lengths() {
+        for len in [0, 0x7f, 0x80, 0x100, 0x10000] {
+            let der = tlv(OCTET_STRING, &vec![0; len]);
+            let any = AnyRef::from_der(&der).unwrap();
+            assert_eq!(any.value().len(), len);
+        }
+    }
+
+    #[test]
+    fn bits() {
+        // badRequest and badPOP (RFC 4210, section 5.2.3).
+        assert_eq!(named_bit(2), [0x03, 0x02, 0x05, 0x20]);
+        assert_eq!(named_bit(9), [0x03, 0x03, 0x06, 0x00, 0x40]);
+    }
+}
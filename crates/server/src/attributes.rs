@@ -0,0 +1,116 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Policy for certification request attributes other than `extensionRequest`.
+//!
+//! Off-the-shelf CSR generators commonly add PKCS #9 attributes which carry
+//! nothing steward needs. Each well-known attribute may be ignored, required
+//! or rejected; any other attribute is always rejected.
+
+use anyhow::{bail, Result};
+use const_oid::ObjectIdentifier;
+use serde::Deserialize;
+
+pub(crate) const CHALLENGE_PASSWORD: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.7");
+const UNSTRUCTURED_NAME: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.2");
+const FRIENDLY_NAME: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.20");
+
+/// What to do with an attribute.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Handling {
+    /// Accept the attribute, but do not use it.
+    #[default]
+    Ignore,
+
+    /// Reject requests without the attribute.
+    Require,
+
+    /// Reject requests with the attribute.
+    Reject,
+}
+
+/// The handling of each well-known attribute.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    pub challenge_password: Handling,
+    pub unstructured_name: Handling,
+    pub friendly_name: Handling,
+}
+
+impl Policy {
+    fn rules(&self) -> [(ObjectIdentifier, &'static str, Handling); 3] {
+        [
+            (
+                CHALLENGE_PASSWORD,
+                "challengePassword",
+                self.challenge_password,
+            ),
+            (
+                UNSTRUCTURED_NAME,
+                "unstructuredName",
+                self.unstructured_name,
+            ),
+            (FRIENDLY_NAME, "friendlyName", self.friendly_name),
+        ]
+    }
+
+    /// Checks that an attribute other than `extensionRequest` is acceptable.
+    pub fn check(&self, oid: &ObjectIdentifier) -> Result<()> {
+        match self.rules().into_iter().find(|(o, ..)| o == oid) {
+            Some((_, name, Handling::Reject)) => bail!("attribute `{name}` is rejected by policy"),
+            Some(..) => Ok(()),
+            None => bail!("attribute `{oid}` is unsupported"),
+        }
+    }
+
+    /// Checks that every required attribute is among those `present`.
+    pub fn check_required(&self, present: &[ObjectIdentifier]) -> Result<()> {
+        for (oid, name, handling) in self.rules() {
+            if handling == Handling::Require && !present.contains(&oid) {
+                bail!("attribute `{name}` is required by policy");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default() {
+        let policy: Policy = toml::from_str("").unwrap();
+        assert_eq!(policy, Policy::default());
+
+        assert!(policy.check(&CHALLENGE_PASSWORD).is_ok());
+        assert!(policy.check(&FRIENDLY_NAME).is_ok());
+        assert!(policy
+            .check(&ObjectIdentifier::new_unwrap("1.2.3.4"))
+            .is_err());
+        assert!(policy.check_required(&[]).is_ok());
+    }
+
+    #[test]
+    fn configured() {
+        let policy: Policy = toml::from_str(
+            r#"
+            challenge_password = "require"
+            friendly_name = "reject"
+            "#,
+        )
+        .unwrap();
+
+        assert!(policy.check(&CHALLENGE_PASSWORD).is_ok());
+        assert!(policy.check(&UNSTRUCTURED_NAME).is_ok());
+        assert!(policy.check(&FRIENDLY_NAME).is_err());
+        assert!(policy.check_required(&[]).is_err());
+        assert!(policy.check_required(&[CHALLENGE_PASSWORD]).is_ok());
+
+        assert!(toml::from_str::<Policy>("challenge_password = \"allow\"").is_err());
+    }
+}
@@ -0,0 +1,354 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Audit events and the sinks which receive them.
+//!
+//! Every issuance, rejection and policy change is passed to each configured
+//! sink. Besides the webhook (behind the `webhook` feature), events may go to
+//! the host's log collection: syslog, as RFC 5424 messages with the details
+//! as structured data, or journald, with the details as `STEWARD_*` fields.
+
+use std::fmt::Debug;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use der::DateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// An audit event.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    /// A unique ID, stable across redeliveries.
+    pub id: String,
+
+    /// Seconds since the Unix epoch at which the event happened.
+    pub at: u64,
+
+    /// The kind of event: `issuance`, `rejection` or `policy`.
+    pub event: String,
+
+    /// What happened.
+    pub detail: Value,
+
+    /// The ID of the request which caused the event, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl Event {
+    pub fn new(at: SystemTime, event: &str, detail: Value) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            at: at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            event: event.into(),
+            detail,
+            request_id: None,
+        }
+    }
+
+    /// The request ID and details as named strings, for sinks without
+    /// nesting.
+    pub fn fields(&self) -> Vec<(String, String)> {
+        let text = |value: &Value| match value {
+            Value::String(s) => s.clone(),
+            value => value.to_string(),
+        };
+
+        let request = self
+            .request_id
+            .iter()
+            .map(|id| ("request_id".into(), id.clone()));
+        let detail = match &self.detail {
+            Value::Object(map) => map.iter().map(|(k, v)| (k.clone(), text(v))).collect(),
+            Value::Null => vec![],
+            value => vec![("detail".into(), text(value))],
+        };
+        request.chain(detail).collect()
+    }
+
+    /// Whether the event is a refusal rather than routine.
+    fn warns(&self) -> bool {
+        self.event == "rejection"
+    }
+}
+
+/// A destination of audit events.
+pub trait Sink: Debug + Send + Sync {
+    /// Hands over an event.
+    ///
+    /// Failures are logged rather than returned, since auditing must not
+    /// stand in the way of the event itself.
+    fn emit(&self, event: &Event);
+}
+
+#[cfg(unix)]
+pub use host::{Journald, Syslog};
+
+#[cfg(unix)]
+mod host {
+    use super::{Event, Sink};
+
+    use std::net::UdpSocket;
+    use std::os::unix::net::UnixDatagram;
+    use std::path::Path;
+
+    use anyhow::{anyhow, Context, Result};
+    use tracing::warn;
+
+    /// The syslog facility of security messages (`authpriv`).
+    const FACILITY: u8 = 10;
+
+    /// The structured data ID of the details, under the documentation PEN.
+    const SD_ID: &str = "steward@32473";
+
+    /// The socket of the journal's native protocol.
+    const JOURNAL: &str = "/run/systemd/journal/socket";
+
+    fn severity(event: &Event) -> u8 {
+        // Warning or notice.
+        if event.warns() {
+            4
+        } else {
+            5
+        }
+    }
+
+    #[derive(Debug)]
+    enum Socket {
+        Unix(UnixDatagram),
+        Udp(UdpSocket),
+    }
+
+    /// A sink sending RFC 5424 messages to a syslog daemon.
+    #[derive(Debug)]
+    pub struct Syslog {
+        socket: Socket,
+        hostname: String,
+    }
+
+    impl Syslog {
+        /// Connects to the daemon at `target`: `unix:PATH` or `udp:HOST:PORT`.
+        pub fn connect(target: &str) -> Result<Self> {
+            let socket = match target.split_once(':') {
+                Some(("unix", path)) => {
+                    let socket = UnixDatagram::unbound()?;
+                    socket
+                        .connect(path)
+                        .with_context(|| format!("failed to connect to syslog at {path}"))?;
+                    Socket::Unix(socket)
+                }
+                Some(("udp", addr)) => {
+                    let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+                    socket
+                        .connect(addr)
+                        .with_context(|| format!("failed to connect to syslog at {addr}"))?;
+                    Socket::Udp(socket)
+                }
+                _ => return Err(anyhow!("syslog target must be unix:PATH or udp:HOST:PORT")),
+            };
+
+            // The kernel's idea of the hostname, or the nil value.
+            let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+                .map(|name| name.trim().to_string())
+                .ok()
+                .filter(|name| !name.is_empty() && name.is_ascii() && !name.contains(' '))
+                .unwrap_or_else(|| "-".into());
+
+            Ok(Self { socket, hostname })
+        }
+
+        /// Formats `event` as an RFC 5424 message.
+        pub fn format(&self, event: &Event) -> String {
+            let pri = FACILITY * 8 + severity(event);
+            let timestamp = super::timestamp(event.at);
+            let pid = std::process::id();
+
+            let mut sd = format!("[{SD_ID} id=\"{}\"", escape(&event.id));
+            for (name, value) in event.fields() {
+                let name: String = name
+                    .chars()
+                    .filter(|c| c.is_ascii_graphic() && !matches!(c, '=' | ']' | '"'))
+                    .take(32)
+                    .collect();
+                sd += &format!(" {name}=\"{}\"", escape(&value));
+            }
+            sd.push(']');
+
+            let msg = serde_json::to_string(&event.detail).unwrap_or_default();
+            format!(
+                "<{pri}>1 {timestamp} {} steward {pid} {} {sd} {msg}",
+                self.hostname, event.event
+            )
+        }
+    }
+
+    /// Escapes a structured data parameter value.
+    fn escape(value: &str) -> String {
+        let mut escaped = String::with_capacity(value.len());
+        for c in value.chars() {
+            if matches!(c, '"' | '\\' | ']') {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    }
+
+    impl Sink for Syslog {
+        fn emit(&self, event: &Event) {
+            let msg = self.format(event);
+            let sent = match &self.socket {
+                Socket::Unix(socket) => socket.send(msg.as_bytes()),
+                Socket::Udp(socket) => socket.send(msg.as_bytes()),
+            };
+            if let Err(e) = sent {
+                warn!("failed to send audit event {} to syslog: {e}", event.id);
+            }
+        }
+    }
+
+    /// A sink sending events to journald as structured entries.
+    #[derive(Debug)]
+    pub struct Journald {
+        socket: UnixDatagram,
+    }
+
+    impl Journald {
+        /// Connects to the journal of this host.
+        pub fn connect() -> Result<Self> {
+            Self::connect_to(JOURNAL)
+        }
+
+        /// Connects to the journal listening on `path`.
+        pub fn connect_to(path: impl AsRef<Path>) -> Result<Self> {
+            let path = path.as_ref();
+            let socket = UnixDatagram::unbound()?;
+            socket
+                .connect(path)
+                .with_context(|| format!("failed to connect to journald at {}", path.display()))?;
+            Ok(Self { socket })
+        }
+
+        /// Encodes `event` in the journal's native protocol.
+        pub fn encode(event: &Event) -> Vec<u8> {
+            let detail = serde_json::to_string(&event.detail).unwrap_or_default();
+            let mut fields = vec![
+                ("MESSAGE".into(), format!("{} {detail}", event.event)),
+                ("PRIORITY".into(), severity(event).to_string()),
+                ("SYSLOG_IDENTIFIER".into(), "steward".into()),
+                ("STEWARD_EVENT".into(), event.event.clone()),
+                ("STEWARD_EVENT_ID".into(), event.id.clone()),
+            ];
+            for (name, value) in event.fields() {
+                let name: String = name
+                    .chars()
+                    .map(|c| match c {
+                        'a'..='z' => c.to_ascii_uppercase(),
+                        'A'..='Z' | '0'..='9' => c,
+                        _ => '_',
+                    })
+                    .collect();
+                fields.push((format!("STEWARD_{name}"), value));
+            }
+
+            let mut buf = Vec::new();
+            for (name, value) in fields {
+                buf.extend_from_slice(name.as_bytes());
+                if value.contains('\n') {
+                    // Values spanning lines are length prefixed.
+                    buf.push(b'\n');
+                    buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+                } else {
+                    buf.push(b'=');
+                }
+                buf.extend_from_slice(value.as_bytes());
+                buf.push(b'\n');
+            }
+            buf
+        }
+    }
+
+    impl Sink for Journald {
+        fn emit(&self, event: &Event) {
+            if let Err(e) = self.socket.send(&Self::encode(event)) {
+                warn!("failed to send audit event {} to journald: {e}", event.id);
+            }
+        }
+    }
+}
+
+/// Formats seconds since the Unix epoch as an RFC 3339 timestamp.
+fn timestamp(at: u64) -> String {
+    DateTime::from_unix_duration(Duration::from_secs(at))
+        .map(|t| t.to_string())
+        .unwrap_or_else(|_| "-".into())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixDatagram;
+
+    use serde_json::json;
+
+    fn event() -> Event {
+        Event {
+            id: "42".into(),
+            at: 1_700_000_000,
+            event: "rejection".into(),
+            detail: json!({ "status": 403, "reason": "bad \"quote\"]\nline" }),
+            request_id: Some("abc".into()),
+        }
+    }
+
+    fn listen() -> (std::path::PathBuf, UnixDatagram) {
+        let path = std::env::temp_dir().join(format!("steward-{}.sock", uuid::Uuid::new_v4()));
+        let socket = UnixDatagram::bind(&path).unwrap();
+        (path, socket)
+    }
+
+    fn receive(socket: &UnixDatagram) -> Vec<u8> {
+        let mut buf = vec![0; 4096];
+        let n = socket.recv(&mut buf).unwrap();
+        buf.truncate(n);
+        buf
+    }
+
+    #[test]
+    fn syslog() {
+        let (path, socket) = listen();
+        let syslog = Syslog::connect(&format!("unix:{}", path.display())).unwrap();
+        syslog.emit(&event());
+
+        let msg = String::from_utf8(receive(&socket)).unwrap();
+        assert!(msg.starts_with("<84>1 2023-11-14T22:13:20Z "), "{msg}");
+        let header = format!(" steward {} rejection [", std::process::id());
+        assert!(msg.contains(&header), "{msg}");
+        assert!(msg.contains(
+            r#"[steward@32473 id="42" request_id="abc" reason="bad \"quote\"\]
+line" status="403"]"#
+        ));
+
+        assert!(Syslog::connect("tcp:localhost:514").is_err());
+    }
+
+    #[test]
+    fn journald() {
+        let (path, socket) = listen();
+        Journald::connect_to(&path).unwrap().emit(&event());
+
+        let entry = receive(&socket);
+        let text = String::from_utf8_lossy(&entry);
+        assert!(text.contains("PRIORITY=4\n"));
+        assert!(text.contains("STEWARD_EVENT=rejection\n"));
+        assert!(text.contains("STEWARD_STATUS=403\n"));
+        assert!(text.contains("STEWARD_REQUEST_ID=abc\n"));
+
+        // Values spanning lines are length prefixed.
+        let mut multiline = b"STEWARD_REASON\n".to_vec();
+        multiline.extend_from_slice(&17u64.to_le_bytes());
+        multiline.extend_from_slice(b"bad \"quote\"]\nline\n");
+        assert!(entry.windows(multiline.len()).any(|w| w == multiline));
+    }
+}
@@ -0,0 +1,230 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Load generation against a running steward, for capacity planning.
+//!
+//! Requests are sent at a fixed rate, whether or not earlier ones have been
+//! answered, so that a slow steward shows up as growing latency rather than
+//! a lower request rate. For `kvm`, each request is for a fresh key, which
+//! only a steward in debug mode certifies. For `sgx` and `snp` the canned
+//! requests of the test suite are replayed, which exercises the appraisal
+//! cache and needs a policy accepting their evidence.
+
+use super::PKCS10;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, ensure, Result};
+use attestation::crypto::{CertReqInfoExt, PrivateKeyInfoExt};
+use const_oid::db::rfc5912::{ID_EXTENSION_REQ, SECP_256_R_1};
+use const_oid::ObjectIdentifier;
+use der::asn1::AnyRef;
+use der::{Decode, Encode};
+use reqwest::header::CONTENT_TYPE;
+use sec1::pkcs8::PrivateKeyInfo;
+use x509::attr::Attribute;
+use x509::ext::Extension;
+use x509::name::RdnSequence;
+use x509::request::{CertReqInfo, ExtensionReq, Version};
+
+const KVM: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.58270.1.1");
+
+const ICELAKE: &[u8] = include_bytes!("../../attestation/src/sgx/icelake.signed.crl.csr");
+const MILAN: &[u8] = include_bytes!("../../attestation/src/snp/milan.signed.crl.csr");
+
+/// What load to generate.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// The URL which requests are posted to.
+    pub target: String,
+
+    /// The platform whose evidence requests carry.
+    pub platform: String,
+
+    /// Requests sent per second.
+    pub rps: u32,
+
+    /// How long to keep sending.
+    pub duration: Duration,
+
+    /// How long to wait for each response.
+    pub timeout: Duration,
+}
+
+/// Returns a certification request carrying evidence of `platform`.
+pub fn request(platform: &str) -> Result<Vec<u8>> {
+    match platform {
+        "kvm" => (),
+        "sgx" => return Ok(ICELAKE.to_vec()),
+        "snp" => return Ok(MILAN.to_vec()),
+        _ => bail!("no evidence for platform `{platform}`"),
+    }
+
+    let key = PrivateKeyInfo::generate(SECP_256_R_1)?;
+    let pki = PrivateKeyInfo::from_der(key.as_ref())?;
+    let kvm = Extension {
+        extn_id: KVM,
+        critical: false,
+        extn_value: &[],
+    };
+    let req = ExtensionReq::from(vec![kvm]).to_vec()?;
+    let attribute = Attribute {
+        oid: ID_EXTENSION_REQ,
+        values: vec![AnyRef::from_der(&req)?].try_into()?,
+    };
+
+    CertReqInfo {
+        version: Version::V1,
+        subject: RdnSequence::default(),
+        public_key: pki.public_key()?,
+        attributes: vec![attribute].try_into()?,
+    }
+    .sign(&pki)
+}
+
+/// What came of the requests sent.
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    /// The latency of each successful request.
+    pub latencies: Vec<Duration>,
+
+    /// The number of failed requests, by status or error.
+    pub failures: BTreeMap<String, u64>,
+
+    /// How long it took to send every request and hear back.
+    pub elapsed: Duration,
+}
+
+impl Report {
+    fn record(&mut self, latency: Duration, outcome: reqwest::Result<reqwest::StatusCode>) {
+        let failure = match outcome {
+            Ok(status) if status.is_success() => return self.latencies.push(latency),
+            Ok(status) => status.to_string(),
+            Err(e) if e.is_timeout() => "timeout".into(),
+            Err(e) if e.is_connect() => "connection failed".into(),
+            Err(..) => "other error".into(),
+        };
+        *self.failures.entry(failure).or_default() += 1;
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.latencies.len() as u64 + self.failures.values().sum::<u64>()
+    }
+
+    /// Returns the latency which `percent` of successful requests were
+    /// answered within.
+    pub fn percentile(&self, percent: u32) -> Option<Duration> {
+        let mut sorted = self.latencies.clone();
+        sorted.sort();
+        let rank = (sorted.len() * percent as usize + 99) / 100;
+        sorted.get(rank.max(1) - 1).copied()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.elapsed.as_secs_f64();
+        let rate = self.sent() as f64 / secs.max(f64::EPSILON);
+        writeln!(
+            f,
+            "sent {} requests in {secs:.1}s ({rate:.1}/s), {} succeeded",
+            self.sent(),
+            self.latencies.len()
+        )?;
+        for (failure, count) in &self.failures {
+            writeln!(f, "  {failure}: {count}")?;
+        }
+
+        let ms = |percent| self.percentile(percent).map(|d| d.as_secs_f64() * 1000.0);
+        if let (Some(p50), Some(p90), Some(p99), Some(max)) = (ms(50), ms(90), ms(99), ms(100)) {
+            writeln!(
+                f,
+                "latency p50 {p50:.1}ms, p90 {p90:.1}ms, p99 {p99:.1}ms, max {max:.1}ms"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Drives load against a steward as `settings` say.
+pub async fn run(settings: &Settings) -> Result<Report> {
+    ensure!(
+        settings.rps > 0,
+        "at least one request per second is needed"
+    );
+    request(&settings.platform)?;
+
+    let client = reqwest::Client::builder()
+        .timeout(settings.timeout)
+        .build()?;
+    let mut ticks = tokio::time::interval(Duration::from_secs(1) / settings.rps);
+    let started = Instant::now();
+    let mut sent = Vec::new();
+    while started.elapsed() < settings.duration {
+        ticks.tick().await;
+        let body = request(&settings.platform)?;
+        let post = client
+            .post(&settings.target)
+            .header(CONTENT_TYPE, PKCS10)
+            .body(body);
+        sent.push(tokio::spawn(async move {
+            let start = Instant::now();
+            let outcome = post.send().await.map(|rsp| rsp.status());
+            (start.elapsed(), outcome)
+        }));
+    }
+
+    let mut report = Report::default();
+    for answer in sent {
+        let (latency, outcome) = answer.await?;
+        report.record(latency, outcome);
+    }
+    report.elapsed = started.elapsed();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use attestation::crypto::CertReqExt;
+    use x509::request::CertReq;
+
+    #[test]
+    fn requests() {
+        let kvm = request("kvm").unwrap();
+        let info = CertReq::from_der(&kvm).unwrap().verify().unwrap();
+        assert_ne!(request("kvm").unwrap(), kvm);
+        assert_eq!(info.attributes.len(), 1);
+
+        for platform in ["sgx", "snp"] {
+            let cr = request(platform).unwrap();
+            CertReq::from_der(&cr).unwrap().verify().unwrap();
+        }
+        assert!(request("tdx").is_err());
+    }
+
+    #[test]
+    fn report() {
+        let mut report = Report::default();
+        for ms in (1..=100).rev() {
+            report.record(Duration::from_millis(ms), Ok(reqwest::StatusCode::OK));
+        }
+        let status = reqwest::StatusCode::SERVICE_UNAVAILABLE;
+        report.record(Duration::from_millis(1), Ok(status));
+        report.elapsed = Duration::from_secs(10);
+
+        assert_eq!(report.sent(), 101);
+        assert_eq!(report.percentile(50), Some(Duration::from_millis(50)));
+        assert_eq!(report.percentile(99), Some(Duration::from_millis(99)));
+        assert_eq!(report.percentile(100), Some(Duration::from_millis(100)));
+        assert_eq!(Report::default().percentile(50), None);
+
+        let text = report.to_string();
+        assert!(text.starts_with("sent 101 requests in 10.0s (10.1/s), 100 succeeded\n"));
+        assert!(text.contains("  503 Service Unavailable: 1\n"), "{text}");
+        assert!(text.contains("latency p50 50.0ms, p90 90.0ms, p99 99.0ms, max 100.0ms"));
+    }
+}
@@ -0,0 +1,211 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Trust bundles for provisioning relying parties.
+//!
+//! What relying parties need to trust issued certificates is published under
+//! `/bundles/`, so that provisioning systems can fetch it without piecing it
+//! together from several endpoints:
+//!
+//! - `ca.pem` holds the CA certificate, the chains of its cross-certificates
+//!   and the current full CRL, as PEM.
+//! - `trust-anchors.json` lists the platform roots verifiers trust, as at
+//!   `/v1/trust-anchors`.
+//!
+//! Bundles are rebuilt every [`INTERVAL`] by a background task, and served
+//! with ETags and the time they last changed, so that pollers can tell when
+//! to fetch them again. Each replica builds its own.
+
+use super::endorsements::{self, TrustAnchors};
+use super::scheduler::Scheduler;
+use super::State;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use axum::extract::{Extension, Path};
+use axum::http::HeaderMap;
+use axum::response::Response;
+use der::pem::{self, LineEnding};
+use hyper::StatusCode;
+use tracing::debug;
+
+/// How often bundles are rebuilt.
+pub const INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+const PEM: &str = "application/x-pem-file";
+const JSON: &str = "application/json";
+
+/// A built bundle, and when it last changed.
+#[derive(Clone, Debug)]
+struct Bundle {
+    content_type: &'static str,
+    body: Vec<u8>,
+    modified: SystemTime,
+}
+
+/// The bundles last built, by name.
+#[derive(Debug, Default)]
+pub struct Bundles(RwLock<BTreeMap<&'static str, Bundle>>);
+
+impl Bundles {
+    fn get(&self, name: &str) -> Option<Bundle> {
+        self.0.read().unwrap().get(name).cloned()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.read().unwrap().is_empty()
+    }
+
+    /// Replaces the bundles with those `built` at `now`, keeping the time
+    /// each last changed if it has not.
+    fn replace(&self, built: Vec<(&'static str, &'static str, Vec<u8>)>, now: SystemTime) {
+        let mut bundles = self.0.write().unwrap();
+        for (name, content_type, body) in built {
+            let modified = match bundles.get(name) {
+                Some(old) if old.body == body => old.modified,
+                _ => now,
+            };
+            let bundle = Bundle {
+                content_type,
+                body,
+                modified,
+            };
+            bundles.insert(name, bundle);
+        }
+    }
+}
+
+/// The CA certificate and the chains of its cross-certificates, each from
+/// the certificate towards its root, followed by the current full CRL.
+async fn ca(state: &State) -> Result<Vec<u8>> {
+    let mut crts = vec![state.crt.as_slice()];
+    for chain in &state.cross {
+        crts.extend(chain.iter().rev().map(Vec::as_slice));
+    }
+
+    let mut bundle = String::new();
+    for crt in crts {
+        bundle += &pem::encode_string("CERTIFICATE", LineEnding::LF, crt)?;
+    }
+    let (crl, _) = super::crl::current(state).await?;
+    bundle += &pem::encode_string("X509 CRL", LineEnding::LF, &crl)?;
+    Ok(bundle.into_bytes())
+}
+
+/// Rebuilds every bundle from the current state.
+pub(crate) async fn refresh(state: &State) -> Result<()> {
+    let anchors = TrustAnchors {
+        anchors: endorsements::active(state).await?,
+    };
+    let built = vec![
+        ("ca.pem", PEM, ca(state).await?),
+        ("trust-anchors.json", JSON, serde_json::to_vec(&anchors)?),
+    ];
+    state.bundles.replace(built, state.clock.now());
+    Ok(())
+}
+
+/// Adds a task which rebuilds the bundles.
+pub(crate) fn schedule(state: &State, scheduler: Scheduler) -> Scheduler {
+    let state = state.clone();
+    scheduler.every("bundles", INTERVAL, INTERVAL / 10, move || {
+        let state = state.clone();
+        async move { refresh(&state).await }
+    })
+}
+
+/// Returns a bundle, unless the client's copy is current.
+///
+/// Bundles are built on first use if the background task has yet to run.
+pub async fn bundle(
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Response, StatusCode> {
+    if state.bundles.is_empty() {
+        refresh(&state).await.map_err(|e| {
+            debug!("failed to build bundles: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    }
+
+    let bundle = state.bundles.get(&name).ok_or(StatusCode::NOT_FOUND)?;
+    super::conditional(&headers, bundle.content_type, bundle.body, bundle.modified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::clock::Manual;
+    use super::super::public;
+    use super::super::store::Revocation;
+    use super::*;
+
+    use http::header::{CONTENT_TYPE, ETAG, IF_NONE_MATCH, LAST_MODIFIED};
+    use http::Request;
+    use hyper::Body;
+    use tower::ServiceExt; // for `app.oneshot()`
+
+    async fn get(state: &State, uri: &str, etag: Option<&str>) -> Response {
+        let mut request = Request::builder().uri(uri);
+        if let Some(etag) = etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        let request = request.body(Body::empty()).unwrap();
+        public(state.clone()).oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn bundles() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = Arc::new(Manual::new(start));
+        let state = State::generate(None, "localhost")
+            .unwrap()
+            .with_clock(clock.clone());
+
+        // Built on first use: the CA certificate, then the CRL.
+        let response = get(&state, "/bundles/ca.pem", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], PEM);
+        let etag = response.headers()[ETAG].to_str().unwrap().to_owned();
+        let modified = response.headers()[LAST_MODIFIED].clone();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let crts = rustls_pemfile::certs(&mut &body[..]).unwrap();
+        assert_eq!(crts, [state.crt.clone()]);
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("-----BEGIN X509 CRL-----"));
+
+        let response = get(&state, "/bundles/trust-anchors.json", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], JSON);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let anchors: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(anchors["anchors"].is_array());
+
+        let response = get(&state, "/bundles/other.pem", None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Rebuilding leaves an unchanged bundle as it was.
+        clock.advance(Duration::from_secs(60));
+        refresh(&state).await.unwrap();
+        let response = get(&state, "/bundles/ca.pem", Some(&etag)).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[LAST_MODIFIED], modified);
+
+        // But a revocation changes the CRL, and so the bundle.
+        let revocation = Revocation {
+            serial: vec![1],
+            revoked_at: start,
+            reason: None,
+        };
+        state.store.revoke(&revocation).await.unwrap();
+        refresh(&state).await.unwrap();
+        let response = get(&state, "/bundles/ca.pem", Some(&etag)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.headers()[ETAG], etag.as_str());
+        assert_ne!(response.headers()[LAST_MODIFIED], modified);
+    }
+}
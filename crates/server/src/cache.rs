@@ -0,0 +1,229 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use super::shared::Shared;
+use super::verifier::Appraisal;
+
+use std::time::Duration;
+
+use anyhow::anyhow;
+use der::Encode;
+use sha2::{Digest, Sha256};
+use tracing::debug;
+use x509::ext::Extension;
+use x509::request::CertReqInfo;
+
+/// A short-lived cache of extension appraisals.
+///
+/// Workloads which restart quickly (or retry aggressively) present the same
+/// evidence over and over. Caching the outcome avoids repeating expensive
+/// chain validation. Failures are cached too, but for a shorter time, so that
+/// a retry storm of bad evidence is cheap to reject.
+///
+/// Entries are keyed by the evidence, the requested public key (since the
+/// evidence binds to it) and digests of the policy and of the trust anchors
+/// and revocation lists in use. A changed policy, an uploaded anchor or a
+/// refreshed revocation list thus never sees results appraised under the old
+/// ones, and replicas trusting the same can share results through the
+/// `Shared` backend.
+#[derive(Clone, Copy, Debug)]
+pub struct AppraisalCache {
+    positive: Duration,
+    negative: Duration,
+}
+
+impl Default for AppraisalCache {
+    fn default() -> Self {
+        Self::new(Self::POSITIVE_TTL, Self::NEGATIVE_TTL)
+    }
+}
+
+impl AppraisalCache {
+    pub const POSITIVE_TTL: Duration = Duration::from_secs(30);
+    pub const NEGATIVE_TTL: Duration = Duration::from_secs(5);
+
+    /// Creates a cache with the given lifetimes; a zero lifetime disables caching.
+    pub fn new(positive: Duration, negative: Duration) -> Self {
+        Self { positive, negative }
+    }
+
+    /// Returns the cached appraisal, or runs `verify` and caches its result.
+    ///
+    /// The `basis` of the appraisal is the policy, followed by the trust
+    /// anchors and revocation lists which the verifier depends on.
+    ///
+    /// Backend failures are logged and otherwise ignored.
+    pub async fn appraise(
+        &self,
+        shared: &dyn Shared,
+        basis: &[&[u8]],
+        cri: &CertReqInfo<'_>,
+        ext: &Extension<'_>,
+        dbg: bool,
+        verify: impl FnOnce() -> anyhow::Result<Appraisal>,
+    ) -> anyhow::Result<Appraisal> {
+        if self.positive.is_zero() && self.negative.is_zero() {
+            return verify();
+        }
+
+        let key = match Self::key(basis, cri, ext, dbg) {
+            Ok(key) => key,
+            Err(..) => return verify(),
+        };
+
+        match shared.get(&key).await {
+            Ok(Some(value)) => match value.split_first() {
+                Some((1, json)) => match serde_json::from_slice(json) {
+                    Ok(appraisal) => return Ok(appraisal),
+                    Err(..) => debug!("ignoring malformed cached appraisal"),
+                },
+                Some((2, msg)) => return Err(anyhow!("{}", String::from_utf8_lossy(msg))),
+                _ => debug!("ignoring malformed cached appraisal"),
+            },
+            Ok(None) => (),
+            Err(e) => debug!("failed to read appraisal cache: {e}"),
+        }
+
+        let result = verify();
+        let (ttl, value) = match &result {
+            Ok(appraisal) => match serde_json::to_vec(appraisal) {
+                Ok(json) => (self.positive, [&[1][..], &json].concat()),
+                Err(..) => (Duration::ZERO, Vec::new()),
+            },
+            Err(e) => (self.negative, [&[2][..], e.to_string().as_bytes()].concat()),
+        };
+        if !ttl.is_zero() {
+            if let Err(e) = shared.put(&key, &value, ttl).await {
+                debug!("failed to write appraisal cache: {e}");
+            }
+        }
+
+        result
+    }
+
+    fn key(
+        basis: &[&[u8]],
+        cri: &CertReqInfo<'_>,
+        ext: &Extension<'_>,
+        dbg: bool,
+    ) -> der::Result<String> {
+        let mut hash = Sha256::new();
+        for input in basis {
+            hash.update(Sha256::digest(input));
+        }
+        hash.update([dbg as u8]);
+        hash.update(cri.public_key.to_vec()?);
+        hash.update(ext.to_vec()?);
+        Ok(format!("appraisal/{}", hex::encode(hash.finalize())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::shared::Memory;
+    use super::*;
+
+    use attestation::crypto::PrivateKeyInfoExt;
+    use const_oid::db::rfc5912::SECP_256_R_1;
+    use const_oid::ObjectIdentifier;
+    use der::Decode;
+    use sec1::pkcs8::PrivateKeyInfo;
+    use x509::name::RdnSequence;
+
+    const OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.58270.1.1");
+
+    fn cri<'a>(pki: &'a PrivateKeyInfo<'_>) -> CertReqInfo<'a> {
+        CertReqInfo {
+            version: x509::request::Version::V1,
+            subject: RdnSequence::default(),
+            public_key: pki.public_key().unwrap(),
+            attributes: Default::default(),
+        }
+    }
+
+    fn ext(value: &[u8]) -> Extension<'_> {
+        Extension {
+            extn_id: OID,
+            critical: false,
+            extn_value: value,
+        }
+    }
+
+    #[tokio::test]
+    async fn caches_positive_and_negative() {
+        let cache = AppraisalCache::default();
+        let shared = Memory::default();
+        let key = PrivateKeyInfo::generate(SECP_256_R_1).unwrap();
+        let pki = PrivateKeyInfo::from_der(key.as_ref()).unwrap();
+        let cri = cri(&pki);
+
+        let good = ext(b"good");
+        let bad = ext(b"bad");
+        let mut calls = 0;
+        for _ in 0..2 {
+            let appraisal = cache
+                .appraise(&shared, &[b""], &cri, &good, false, || {
+                    calls += 1;
+                    Ok(Appraisal::new("kvm", true).with_measurement("hash", b"good"))
+                })
+                .await
+                .unwrap();
+            assert_eq!(appraisal.measurements["hash"], hex::encode(b"good"));
+            assert!(cache
+                .appraise(&shared, &[b""], &cri, &bad, false, || {
+                    calls += 1;
+                    Err(anyhow!("bad"))
+                })
+                .await
+                .is_err());
+        }
+        assert_eq!(calls, 2);
+    }
+
+    #[tokio::test]
+    async fn keyed_by_basis_and_key() {
+        let cache = AppraisalCache::default();
+        let shared = Memory::default();
+        let one = PrivateKeyInfo::generate(SECP_256_R_1).unwrap();
+        let one = PrivateKeyInfo::from_der(one.as_ref()).unwrap();
+        let two = PrivateKeyInfo::generate(SECP_256_R_1).unwrap();
+        let two = PrivateKeyInfo::from_der(two.as_ref()).unwrap();
+        let ext = ext(b"evidence");
+
+        // Another policy, another key, or a new trust anchor each miss.
+        let mut calls = 0;
+        let bases: [&[&[u8]]; 4] = [&[b"a"], &[b"b"], &[b"a"], &[b"a", b"root"]];
+        for (basis, pki) in bases.into_iter().zip([&one, &one, &two, &one]) {
+            cache
+                .appraise(&shared, basis, &cri(pki), &ext, false, || {
+                    calls += 1;
+                    Ok(Appraisal::default())
+                })
+                .await
+                .unwrap();
+        }
+        assert_eq!(calls, 4);
+    }
+
+    #[tokio::test]
+    async fn disabled() {
+        let cache = AppraisalCache::new(Duration::ZERO, Duration::ZERO);
+        let shared = Memory::default();
+        let key = PrivateKeyInfo::generate(SECP_256_R_1).unwrap();
+        let pki = PrivateKeyInfo::from_der(key.as_ref()).unwrap();
+        let cri = cri(&pki);
+        let ext = ext(&[]);
+
+        let mut calls = 0;
+        for _ in 0..2 {
+            cache
+                .appraise(&shared, &[b""], &cri, &ext, false, || {
+                    calls += 1;
+                    Ok(Appraisal::default())
+                })
+                .await
+                .unwrap();
+        }
+        assert_eq!(calls, 2);
+    }
+}
@@ -0,0 +1,231 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! A machine-readable description of what this steward accepts.
+//!
+//! Client libraries read this to construct certification requests without
+//! hard-coding the details of any particular steward version.
+
+use super::{platforms, tokens, Config, State, BUNDLE, PKCS10};
+
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use axum::extract::Extension;
+use axum::Json;
+#[cfg(any(feature = "sgx", feature = "snp"))]
+use const_oid::db::rfc5912::ID_EC_PUBLIC_KEY;
+#[cfg(feature = "sgx")]
+use const_oid::db::rfc5912::SECP_256_R_1;
+#[cfg(feature = "snp")]
+use const_oid::db::rfc5912::SECP_384_R_1;
+use serde::Serialize;
+
+/// How evidence is bound to the key in the certification request.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Binding {
+    /// The hash of the DER `SubjectPublicKeyInfo` of the request.
+    pub hash: &'static str,
+
+    /// Where the hash is placed: the leading bytes of this report field.
+    pub field: &'static str,
+}
+
+/// A platform whose evidence this steward appraises.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Platform {
+    pub name: &'static str,
+
+    /// The OID of the certification request extension carrying the evidence.
+    pub oid: String,
+
+    /// The accepted encodings of the extension value.
+    pub evidence: Vec<&'static str>,
+
+    /// The key algorithm and curve the request must use, if constrained.
+    pub key: Option<[String; 2]>,
+
+    /// The report data binding, absent for platforms without a report.
+    pub binding: Option<Binding>,
+}
+
+/// Nonce requirements.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Nonce {
+    /// Whether a server-issued nonce must be included in the evidence.
+    ///
+    /// Freshness is instead established by binding the evidence to a key
+    /// generated for the request.
+    pub required: bool,
+}
+
+/// The full capabilities document.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Capabilities {
+    pub version: &'static str,
+
+    /// Accepted request content types.
+    pub content_types: Vec<&'static str>,
+
+    pub platforms: Vec<Platform>,
+
+    /// Whether all presented evidence must verify, or any.
+    pub require: platforms::Require,
+
+    /// Sets of platforms, one of which must be among those verified.
+    pub combinations: Vec<BTreeSet<String>>,
+
+    /// Additional extensions which are copied into issued certificates.
+    pub extensions: Vec<String>,
+
+    pub nonce: Nonce,
+}
+
+impl Capabilities {
+    pub fn new(state: &State) -> Self {
+        let policy = state.policy();
+        let config = &policy.config;
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            content_types: vec![PKCS10, BUNDLE],
+            platforms: platforms(config),
+            require: config.platforms.require,
+            combinations: config.platforms.combinations.clone(),
+            extensions: config
+                .extensions
+                .copy
+                .iter()
+                .map(|rule| rule.oid.to_string())
+                .collect(),
+            nonce: Nonce { required: false },
+        }
+    }
+}
+
+fn platforms(config: &Config) -> Vec<Platform> {
+    let mut platforms = Vec::new();
+
+    #[cfg(feature = "kvm")]
+    platforms.push(Platform {
+        name: "kvm",
+        oid: super::Kvm::OID.to_string(),
+        evidence: vec!["empty"],
+        key: None,
+        binding: None,
+    });
+
+    #[cfg(feature = "sgx")]
+    platforms.push(Platform {
+        name: "sgx",
+        oid: super::Sgx::OID.to_string(),
+        evidence: vec!["sgx-quote-v3-with-crls"],
+        key: Some([ID_EC_PUBLIC_KEY.to_string(), SECP_256_R_1.to_string()]),
+        binding: Some(Binding {
+            hash: "sha256",
+            field: "reportdata",
+        }),
+    });
+
+    #[cfg(feature = "snp")]
+    platforms.push(Platform {
+        name: "snp",
+        oid: super::Snp::OID.to_string(),
+        evidence: vec!["snp-evidence", "snp-extended-report"],
+        key: Some([ID_EC_PUBLIC_KEY.to_string(), SECP_384_R_1.to_string()]),
+        binding: Some(Binding {
+            hash: "sha384",
+            field: "report_data",
+        }),
+    });
+
+    #[cfg(feature = "cove")]
+    platforms.push(Platform {
+        name: "cove",
+        oid: super::Cove::OID.to_string(),
+        evidence: vec!["dice-certificate-chain"],
+        key: None,
+        binding: None,
+    });
+
+    #[cfg(feature = "se")]
+    platforms.push(Platform {
+        name: "se",
+        oid: super::Se::OID.to_string(),
+        evidence: vec!["se-measurement"],
+        key: None,
+        binding: Some(Binding {
+            hash: "sha512",
+            field: "user_data",
+        }),
+    });
+
+    // Registration tokens are only accepted when the policy says so.
+    if config.tokens.is_some() {
+        platforms.push(Platform {
+            name: tokens::PLATFORM,
+            oid: tokens::OID.to_string(),
+            evidence: vec!["registration-token"],
+            key: None,
+            binding: None,
+        });
+    }
+
+    platforms
+}
+
+/// Returns the capabilities of this steward.
+pub async fn capabilities(Extension(state): Extension<Arc<State>>) -> Json<Capabilities> {
+    Json(Capabilities::new(&state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated() {
+        let state = State::generate(None, "localhost").unwrap();
+        let caps = Capabilities::new(&state);
+        assert_eq!(caps.content_types, [PKCS10, BUNDLE]);
+        assert_eq!(caps.require, platforms::Require::All);
+        assert!(!caps.nonce.required);
+
+        let json = serde_json::to_value(&caps).unwrap();
+        assert_eq!(json["require"], "all");
+
+        #[cfg(feature = "sgx")]
+        {
+            let sgx = caps.platforms.iter().find(|p| p.name == "sgx").unwrap();
+            assert_eq!(sgx.oid, "1.3.6.1.4.1.58270.1.2");
+            assert_eq!(sgx.binding.as_ref().unwrap().hash, "sha256");
+        }
+
+        #[cfg(feature = "cove")]
+        {
+            let cove = caps.platforms.iter().find(|p| p.name == "cove").unwrap();
+            assert_eq!(cove.oid, "1.3.6.1.4.1.58270.1.6");
+            assert_eq!(cove.binding, None);
+        }
+
+        #[cfg(feature = "se")]
+        {
+            let se = caps.platforms.iter().find(|p| p.name == "se").unwrap();
+            assert_eq!(se.oid, "1.3.6.1.4.1.58270.1.5");
+            assert_eq!(se.binding.as_ref().unwrap().field, "user_data");
+        }
+
+        #[cfg(feature = "kvm")]
+        assert_eq!(caps.extensions, ["1.3.6.1.4.1.58270.1.1"]);
+        assert!(caps.platforms.iter().all(|p| p.name != tokens::PLATFORM));
+    }
+
+    #[test]
+    fn registration() {
+        let mut state = State::generate(None, "localhost").unwrap();
+        state.config_mut().tokens = Some(Default::default());
+        let caps = Capabilities::new(&state);
+        let token = caps.platforms.iter().find(|p| p.name == "token").unwrap();
+        assert_eq!(token.oid, "1.3.6.1.4.1.58270.1.4");
+        assert_eq!(token.binding, None);
+    }
+}
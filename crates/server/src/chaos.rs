@@ -0,0 +1,282 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Fault injection, for soak and chaos testing.
+//!
+//! Builds with the `chaos` feature can be made to fail on purpose, to check
+//! that steward degrades gracefully under load rather than corrupting its
+//! state. Each fault is toggled by an environment variable, set to the
+//! probability of it striking, from `0` to `1`:
+//!
+//! * `STEWARD_CHAOS_UPSTREAM_TIMEOUT`: fetches of collateral time out.
+//! * `STEWARD_CHAOS_POLICY_RELOAD`: the policy is reloaded between
+//!   appraising a request and issuing a certificate for it.
+//! * `STEWARD_CHAOS_SIGNER_ERROR`: signing a certificate fails.
+//!
+//! The feature is for test builds only and must never be shipped.
+
+use super::policy;
+use super::State;
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, ensure, Result};
+use tracing::{debug, warn};
+
+/// Marks the policy file as reloaded by fault injection.
+const RELOADED: &[u8] = b"\n# reloaded by fault injection\n";
+
+/// A failure which may be injected.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Fault {
+    UpstreamTimeout,
+    PolicyReload,
+    SignerError,
+}
+
+impl Fault {
+    const ALL: [Self; 3] = [Self::UpstreamTimeout, Self::PolicyReload, Self::SignerError];
+
+    /// The environment variable toggling the fault.
+    pub fn var(self) -> &'static str {
+        match self {
+            Self::UpstreamTimeout => "STEWARD_CHAOS_UPSTREAM_TIMEOUT",
+            Self::PolicyReload => "STEWARD_CHAOS_POLICY_RELOAD",
+            Self::SignerError => "STEWARD_CHAOS_SIGNER_ERROR",
+        }
+    }
+}
+
+/// The faults to inject, with the probability of each striking.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Faults(BTreeMap<Fault, f64>);
+
+impl Faults {
+    /// Reads the faults to inject from the environment.
+    pub fn from_env() -> Result<Self> {
+        Self::from_vars(|var| std::env::var(var).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let mut faults = Self::default();
+        for fault in Fault::ALL {
+            if let Some(value) = var(fault.var()) {
+                let probability: f64 = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| anyhow!("{} must be a probability", fault.var()))?;
+                ensure!(
+                    (0.0..=1.0).contains(&probability),
+                    "{} must be between 0 and 1",
+                    fault.var()
+                );
+                faults = faults.with(fault, probability);
+            }
+        }
+        Ok(faults)
+    }
+
+    /// Injects `fault` with the given probability.
+    pub fn with(mut self, fault: Fault, probability: f64) -> Self {
+        self.0.insert(fault, probability);
+        self
+    }
+
+    /// Whether no fault is ever injected.
+    pub fn is_empty(&self) -> bool {
+        self.0.values().all(|probability| *probability <= 0.0)
+    }
+
+    /// Whether `fault` strikes this time.
+    pub fn strikes(&self, fault: Fault) -> bool {
+        let strikes = match self.0.get(&fault) {
+            Some(probability) => rand::random::<f64>() < *probability,
+            None => false,
+        };
+        if strikes {
+            debug!("injecting {fault:?}");
+        }
+        strikes
+    }
+}
+
+/// Reloads the policy, as if its file had just been changed.
+///
+/// The file is alternately marked and unmarked, so that every reload puts a
+/// new version of the policy in force.
+pub(crate) async fn reload(state: &State) {
+    let mut raw = state.policy().raw.clone();
+    match raw.ends_with(RELOADED) {
+        true => raw.truncate(raw.len() - RELOADED.len()),
+        false => raw.extend_from_slice(RELOADED),
+    }
+
+    if let Err(e) = policy::put_in_force(state, raw).await {
+        warn!("injected policy reload failed: {e:#}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{app, store, PKCS10, SERIAL_HEADER};
+    use super::*;
+
+    use std::time::UNIX_EPOCH;
+
+    use http::{Request, StatusCode};
+    use hyper::Body;
+    use tower::ServiceExt; // for `app.oneshot()`
+
+    #[test]
+    fn faults() {
+        let faults = Faults::from_vars(|_| None).unwrap();
+        assert!(faults.is_empty());
+        assert!(!faults.strikes(Fault::SignerError));
+
+        let faults = Faults::from_vars(|var| match var {
+            "STEWARD_CHAOS_SIGNER_ERROR" => Some("1".into()),
+            "STEWARD_CHAOS_POLICY_RELOAD" => Some(" 0 ".into()),
+            _ => None,
+        })
+        .unwrap();
+        assert!(!faults.is_empty());
+        assert!(faults.strikes(Fault::SignerError));
+        assert!(!faults.strikes(Fault::PolicyReload));
+        assert!(!faults.strikes(Fault::UpstreamTimeout));
+
+        for bad in ["often", "1.5", "-1"] {
+            assert!(Faults::from_vars(|_| Some(bad.into())).is_err(), "{bad}");
+        }
+    }
+
+    async fn issued(state: &State) -> Vec<store::Issued> {
+        state.store.inventory((UNIX_EPOCH, &[]), 10).await.unwrap()
+    }
+
+    #[cfg(feature = "kvm")]
+    async fn attest_kvm(state: &State) -> axum::response::Response {
+        use super::super::Kvm;
+        use attestation::crypto::{CertReqInfoExt, PrivateKeyInfoExt};
+        use const_oid::db::rfc5912::{ID_EXTENSION_REQ, SECP_256_R_1};
+        use der::asn1::AnyRef;
+        use der::{Decode, Encode};
+        use sec1::pkcs8::PrivateKeyInfo;
+        use x509::attr::Attribute;
+        use x509::ext::Extension;
+        use x509::name::RdnSequence;
+        use x509::request::{CertReqInfo, ExtensionReq, Version};
+
+        let key = PrivateKeyInfo::generate(SECP_256_R_1).unwrap();
+        let pki = PrivateKeyInfo::from_der(key.as_ref()).unwrap();
+        let ext = Extension {
+            extn_id: Kvm::OID,
+            critical: false,
+            extn_value: &[],
+        };
+        let req = ExtensionReq::from(vec![ext]).to_vec().unwrap();
+        let attribute = Attribute {
+            oid: ID_EXTENSION_REQ,
+            values: vec![AnyRef::from_der(&req).unwrap()].try_into().unwrap(),
+        };
+        let cr = CertReqInfo {
+            version: Version::V1,
+            subject: RdnSequence::default(),
+            public_key: pki.public_key().unwrap(),
+            attributes: vec![attribute].try_into().unwrap(),
+        }
+        .sign(&pki)
+        .unwrap();
+
+        let request = Request::post("/")
+            .header(http::header::CONTENT_TYPE, PKCS10)
+            .body(Body::from(cr))
+            .unwrap();
+        app(state.clone()).oneshot(request).await.unwrap()
+    }
+
+    #[cfg(feature = "kvm")]
+    #[tokio::test]
+    async fn signer_error() {
+        let faults = Faults::default().with(Fault::SignerError, 1.0);
+        let state = State::generate(None, "localhost")
+            .unwrap()
+            .with_faults(faults);
+
+        // Nothing is recorded for a certificate which was never signed.
+        let rsp = attest_kvm(&state).await;
+        assert_eq!(rsp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(issued(&state).await.is_empty());
+        assert!(state.store.log_leaves(0).await.unwrap().is_empty());
+
+        // Issuance carries on once the signer recovers.
+        let state = state.with_faults(Faults::default());
+        let rsp = attest_kvm(&state).await;
+        assert_eq!(rsp.status(), StatusCode::OK);
+        assert_eq!(issued(&state).await.len(), 1);
+    }
+
+    #[cfg(feature = "kvm")]
+    #[tokio::test]
+    async fn policy_reload() {
+        let faults = Faults::default().with(Fault::PolicyReload, 1.0);
+        let state = State::generate(None, "localhost")
+            .unwrap()
+            .with_faults(faults)
+            .activate_policy()
+            .await
+            .unwrap();
+        assert_eq!(state.policy().version, 1);
+
+        // The certificate is recorded under the policy it was appraised under.
+        let rsp = attest_kvm(&state).await;
+        assert_eq!(rsp.status(), StatusCode::OK);
+        assert_eq!(state.policy().version, 2);
+        let serial = hex::decode(rsp.headers()[SERIAL_HEADER].to_str().unwrap()).unwrap();
+        let issued = issued(&state).await;
+        assert_eq!(issued.len(), 1);
+        assert_eq!(issued[0].serial, serial);
+        assert_eq!(issued[0].policy_version, 1);
+
+        // Reloads alternate between two versions of the file.
+        let rsp = attest_kvm(&state).await;
+        assert_eq!(rsp.status(), StatusCode::OK);
+        assert_eq!(state.policy().version, 3);
+        assert!(!state.policy().raw.ends_with(RELOADED));
+    }
+
+    #[cfg(all(feature = "collateral", feature = "sgx", not(target_os = "wasi")))]
+    #[tokio::test]
+    async fn upstream_timeout() {
+        use super::super::collateral::{Collateral, Settings};
+        use axum::http::header::RETRY_AFTER;
+        use std::time::Duration;
+
+        const ICELAKE: &[u8] = include_bytes!("../../attestation/src/sgx/icelake.signed.crl.csr");
+
+        let faults = Faults::default().with(Fault::UpstreamTimeout, 1.0);
+        let settings = Settings {
+            timeout: Duration::from_millis(10),
+            retries: 0,
+            threshold: 1,
+            ..Default::default()
+        };
+        let collateral = Collateral::new("http://127.0.0.1:9", settings)
+            .unwrap()
+            .with_faults(faults);
+        let state = State::generate(None, "localhost")
+            .unwrap()
+            .with_collateral(collateral);
+
+        // Without collateral to fall back on, clients are told to come back.
+        for _ in 0..2 {
+            let request = Request::post("/")
+                .header(http::header::CONTENT_TYPE, PKCS10)
+                .body(Body::from(ICELAKE))
+                .unwrap();
+            let rsp = app(state.clone()).oneshot(request).await.unwrap();
+            assert_eq!(rsp.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert!(rsp.headers().contains_key(RETRY_AFTER));
+        }
+        assert!(issued(&state).await.is_empty());
+    }
+}
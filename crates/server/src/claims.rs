@@ -0,0 +1,125 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! The claims extension of issued certificates.
+//!
+//! Besides their measurements, some evidence carries fields which the
+//! deployment rather than the workload chooses, such as the SNP `host_data`
+//! set by the host at launch, or the `family_id` and `image_id` of the ID
+//! block. Deployments encode workload metadata in them, so they are appraised
+//! as named measurements, which validity rules may match and profiles may
+//! name certificates after, and are stated in issued certificates for
+//! relying parties. So is how a host or device without evidence was
+//! enrolled.
+//!
+//! ```text
+//! Claims ::= SEQUENCE OF Claim
+//! Claim ::= SEQUENCE {
+//!     name UTF8String,
+//!     value UTF8String,
+//! }
+//! ```
+//!
+//! Claims from evidence are named by platform, such as `snp.host_data`, with
+//! hex values.
+
+use super::verifier::Appraisal;
+use super::{scep, tokens};
+
+use anyhow::Result;
+use const_oid::ObjectIdentifier;
+use der::asn1::Utf8StringRef;
+use der::{Encode, Sequence};
+
+/// The certificate extension stating claims about its subject.
+pub const OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.58270.2.1");
+
+/// The measurements stated as claims, by platform.
+const CLAIMED: &[(&str, &[&str])] = &[
+    (
+        "snp",
+        &["host_data", "report_data", "family_id", "image_id"],
+    ),
+    ("se", &["config_uid"]),
+];
+
+#[derive(Clone, Debug, Eq, PartialEq, Sequence)]
+pub(crate) struct Claim<'a> {
+    pub name: Utf8StringRef<'a>,
+    pub value: Utf8StringRef<'a>,
+}
+
+/// The claims about the subject of a certificate issued on `appraisals`.
+pub fn claims(appraisals: &[Appraisal]) -> Result<Vec<(String, String)>> {
+    let mut claims = Vec::new();
+    for appraisal in appraisals {
+        if appraisal.platform == tokens::PLATFORM {
+            claims.extend(tokens::claims(appraisal)?);
+        }
+        if appraisal.platform == scep::PLATFORM {
+            claims.extend(scep::claims(appraisal)?);
+        }
+
+        let names = CLAIMED
+            .iter()
+            .filter(|(platform, _)| *platform == appraisal.platform)
+            .flat_map(|(_, names)| names.iter());
+        for name in names {
+            if let Some(value) = appraisal.measurements.get(*name) {
+                claims.push((format!("{}.{name}", appraisal.platform), value.clone()));
+            }
+        }
+    }
+    Ok(claims)
+}
+
+/// The DER claims extension for a certificate issued on `appraisals`, unless
+/// there is nothing to claim.
+pub fn extension(appraisals: &[Appraisal]) -> Result<Option<Vec<u8>>> {
+    let claims = claims(appraisals)?;
+    if claims.is_empty() {
+        return Ok(None);
+    }
+
+    let claims = claims
+        .iter()
+        .map(|(name, value)| {
+            Ok(Claim {
+                name: Utf8StringRef::new(name)?,
+                value: Utf8StringRef::new(value)?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Some(claims.to_vec()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use der::Decode;
+
+    #[test]
+    fn snp() {
+        let snp = Appraisal::new("snp", true)
+            .with_measurement("measurement", &[1; 48])
+            .with_measurement("host_data", &[2; 32])
+            .with_measurement("family_id", &[3; 16]);
+        let kvm = Appraisal::new("kvm", true);
+        assert_eq!(extension(&[kvm.clone()]).unwrap(), None);
+
+        let der = extension(&[kvm, snp]).unwrap().unwrap();
+        let claims = Vec::<Claim<'_>>::from_der(&der).unwrap();
+        let claims: Vec<_> = claims
+            .iter()
+            .map(|c| (c.name.as_str(), c.value.as_str()))
+            .collect();
+        assert_eq!(
+            claims,
+            [
+                ("snp.host_data", hex::encode([2; 32]).as_str()),
+                ("snp.family_id", hex::encode([3; 16]).as_str()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,73 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! The source of the current time.
+//!
+//! Everything time dependent asks the state's clock rather than the system,
+//! so that tests can pin the time and probe expiry edge cases without
+//! sleeping.
+
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Tells the time.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system's real time clock.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct System;
+
+impl Clock for System {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock which only moves when told to.
+#[derive(Debug)]
+pub struct Manual(Mutex<SystemTime>);
+
+impl Manual {
+    pub fn new(now: SystemTime) -> Self {
+        Self(Mutex::new(now))
+    }
+
+    /// Sets the time.
+    pub fn set(&self, now: SystemTime) {
+        *self.0.lock().unwrap() = now;
+    }
+
+    /// Moves the time forward.
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+impl Clock for Manual {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn manual() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = Manual::new(start);
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(clock.now(), start + Duration::from_secs(60));
+
+        clock.set(start);
+        assert_eq!(clock.now(), start);
+    }
+}
@@ -0,0 +1,755 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Enrollment over CMP (RFC 4210), transported over HTTP (RFC 6712) at
+//! `/cmp`.
+//!
+//! Initialization (`ir`) and certification (`cr`) requests are answered with
+//! `ip` and `cp`. Each `CertReqMsg` is treated as a certification request:
+//! its template's subject, public key and extensions (carrying the evidence)
+//! are appraised exactly as if they had arrived in a PKCS #10 request, and
+//! its signature proof-of-possession stands in for the request's own
+//! signature. Other kinds of proof are refused with `badPOP`.
+//!
+//! Trust comes from the evidence, so requests need not be protected. If they
+//! are, the signature must verify with the first of their `extraCerts`;
+//! password-based MACs are refused with `badAlg`. Responses are signed by the
+//! CA, whose certificate is sent in their `extraCerts`. As steward keeps no
+//! state between messages, `certConf` is simply acknowledged, and clients
+//! asking for `implicitConfirm` are granted it.
+//!
+//! Messages are held to the parsing policy, and the requests synthesized
+//! from them to the [`Limits`] of those posted directly.
+
+use super::asn1::{
+    constructed, elements, named_bit, oid, sequence, tag, tlv, BIT_STRING, INTEGER, OCTET_STRING,
+    SEQUENCE,
+};
+use super::policy::Policy;
+use super::{appraise, debug_mode, issue, sans, State};
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, ensure, Result};
+use attestation::crypto::{PrivateKeyInfoExt, SubjectPublicKeyInfoExt};
+use attestation::parse::{self, Limits, Strictness};
+use axum::body::Bytes;
+use axum::extract::Extension;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use const_oid::db::rfc5912::ID_EXTENSION_REQ;
+use const_oid::ObjectIdentifier;
+use der::asn1::{AnyRef, BitStringRef, GeneralizedTime, OctetStringRef};
+use der::{Decode, Encode};
+use hyper::StatusCode;
+use rand::RngCore;
+use sec1::pkcs8::PrivateKeyInfo;
+use serde_json::json;
+use spki::{AlgorithmIdentifier, SubjectPublicKeyInfo};
+use tracing::debug;
+use x509::attr::Attribute;
+use x509::name::RdnSequence;
+use x509::request::{CertReqInfo, Version};
+use x509::Certificate;
+
+/// The content type of CMP messages.
+pub const PKIXCMP: &str = "application/pkixcmp";
+
+/// How much deeper a `PKIMessage` nests the subject of each template than a
+/// PKCS #10 request nests its own.
+const NESTING: usize = 5;
+
+const ID_IT_IMPLICIT_CONFIRM: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.5.5.7.4.13");
+const PASSWORD_BASED_MAC: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113533.7.66.13");
+
+// The tags of the `PKIBody` choices which are handled.
+const IR: u8 = constructed(0);
+const IP: u8 = constructed(1);
+const CR: u8 = constructed(2);
+const CP: u8 = constructed(3);
+const PKI_CONF: u8 = constructed(19);
+const ERROR: u8 = constructed(23);
+const CERT_CONF: u8 = constructed(24);
+
+// The `PKIStatus` values which are sent.
+const ACCEPTED: u8 = 0;
+const GRANTED_WITH_MODS: u8 = 1;
+const REJECTION: u8 = 2;
+
+/// The `PKIFailureInfo` bits which are sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Failure {
+    BadAlg = 0,
+    BadMessageCheck = 1,
+    BadRequest = 2,
+    BadDataFormat = 5,
+    BadPop = 9,
+    BadCertTemplate = 19,
+    UnsupportedVersion = 22,
+    NotAuthorized = 23,
+    SystemUnavail = 24,
+    SystemFailure = 25,
+}
+
+impl Failure {
+    fn name(self) -> &'static str {
+        match self {
+            Self::BadAlg => "badAlg",
+            Self::BadMessageCheck => "badMessageCheck",
+            Self::BadRequest => "badRequest",
+            Self::BadDataFormat => "badDataFormat",
+            Self::BadPop => "badPOP",
+            Self::BadCertTemplate => "badCertTemplate",
+            Self::UnsupportedVersion => "unsupportedVersion",
+            Self::NotAuthorized => "notAuthorized",
+            Self::SystemUnavail => "systemUnavail",
+            Self::SystemFailure => "systemFailure",
+        }
+    }
+}
+
+impl From<StatusCode> for Failure {
+    /// Maps a refusal of the issuance pipeline onto CMP.
+    fn from(status: StatusCode) -> Self {
+        match status {
+            StatusCode::BAD_REQUEST => Self::BadRequest,
+            StatusCode::UNAUTHORIZED => Self::NotAuthorized,
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => Self::SystemUnavail,
+            _ => Self::SystemFailure,
+        }
+    }
+}
+
+/// The fields of a `PKIHeader` which steward acts on.
+struct Header<'a> {
+    der: AnyRef<'a>,
+    pvno: u8,
+    sender: AnyRef<'a>,
+    protection: Option<AlgorithmIdentifier<'a>>,
+    transaction: Option<&'a [u8]>,
+    nonce: Option<&'a [u8]>,
+    implicit_confirm: bool,
+}
+
+impl<'a> Header<'a> {
+    fn decode(der: AnyRef<'a>) -> Result<Self> {
+        let fields = elements(der.value())?;
+        let (pvno, sender, rest) = match &fields[..] {
+            [pvno, sender, _recipient, rest @ ..] => (*pvno, *sender, rest),
+            _ => bail!("invalid pki header"),
+        };
+
+        let mut header = Self {
+            der,
+            pvno: pvno.decode_into()?,
+            sender,
+            protection: None,
+            transaction: None,
+            nonce: None,
+            implicit_confirm: false,
+        };
+        let octets = |field: &AnyRef<'a>| -> Result<&'a [u8]> {
+            Ok(OctetStringRef::from_der(field.value())?.as_bytes())
+        };
+        for field in rest {
+            match tag(field) {
+                t if t == constructed(1) => {
+                    header.protection = Some(AlgorithmIdentifier::from_der(field.value())?)
+                }
+                t if t == constructed(4) => header.transaction = Some(octets(field)?),
+                t if t == constructed(5) => header.nonce = Some(octets(field)?),
+                t if t == constructed(8) => {
+                    let info = AnyRef::from_der(field.value())?;
+                    for pair in elements(info.value())? {
+                        let kind = elements(pair.value())?
+                            .first()
+                            .ok_or_else(|| anyhow!("invalid general info"))?
+                            .decode_into::<ObjectIdentifier>()?;
+                        header.implicit_confirm |= kind == ID_IT_IMPLICIT_CONFIRM;
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(header)
+    }
+}
+
+/// A decoded `PKIMessage`.
+struct Message<'a> {
+    header: Header<'a>,
+    body: AnyRef<'a>,
+    protection: Option<AnyRef<'a>>,
+    extra: Vec<Certificate<'a>>,
+}
+
+impl<'a> Message<'a> {
+    fn decode(der: &'a [u8]) -> Result<Self> {
+        let message = AnyRef::from_der(der)?;
+        if tag(&message) != SEQUENCE {
+            bail!("invalid pki message");
+        }
+        let (header, body, rest) = match &elements(message.value())?[..] {
+            [header, body, rest @ ..] => (*header, *body, rest.to_vec()),
+            _ => bail!("invalid pki message"),
+        };
+
+        let mut protection = None;
+        let mut extra = Vec::new();
+        for field in rest {
+            match tag(&field) {
+                t if t == constructed(0) => protection = Some(field),
+                t if t == constructed(1) => {
+                    let crts = AnyRef::from_der(field.value())?;
+                    for crt in elements(crts.value())? {
+                        extra.push(crt.decode_into()?);
+                    }
+                }
+                _ => bail!("invalid pki message"),
+            }
+        }
+
+        Ok(Self {
+            header: Header::decode(header)?,
+            body,
+            protection,
+            extra,
+        })
+    }
+
+    /// Verifies the protection of the message, if it has any.
+    fn verify(&self) -> Result<(), Failure> {
+        let protection = match self.protection {
+            Some(protection) => protection,
+            None => return Ok(()),
+        };
+        let algorithm = self.header.protection.ok_or(Failure::BadMessageCheck)?;
+        if algorithm.oid == PASSWORD_BASED_MAC {
+            debug!("cmp message protected by a password-based mac");
+            return Err(Failure::BadAlg);
+        }
+        let signer = self.extra.first().ok_or(Failure::BadMessageCheck)?;
+
+        let verified = (|| {
+            let protected = sequence(&[&self.header.der.to_vec()?, &self.body.to_vec()?]);
+            let signature = BitStringRef::from_der(protection.value())?;
+            signer.tbs_certificate.subject_public_key_info.verify(
+                &protected,
+                algorithm,
+                signature.raw_bytes(),
+            )
+        })();
+        verified.map_err(|e| {
+            debug!("failed to verify cmp message protection: {e}");
+            Failure::BadMessageCheck
+        })
+    }
+}
+
+/// A `CertReqMsg`, from which a certification request is synthesized.
+struct Template<'a> {
+    /// The DER `CertReqMsg`, archived as the request.
+    der: Vec<u8>,
+
+    /// The DER `certReqId`, echoed in the response.
+    id: Vec<u8>,
+
+    /// The DER `CertRequest`, which a signature proof-of-possession signs.
+    request: Vec<u8>,
+
+    popo: Option<AnyRef<'a>>,
+    subject: Option<&'a [u8]>,
+    public_key: Option<Vec<u8>>,
+    extensions: Option<Vec<u8>>,
+
+    /// Whether the template asks for anything steward decides itself.
+    modified: bool,
+}
+
+impl<'a> Template<'a> {
+    fn decode(msg: AnyRef<'a>) -> Result<Self> {
+        let (request, popo) = match &elements(msg.value())?[..] {
+            [request, rest @ ..] => (*request, rest.first().copied()),
+            _ => bail!("invalid cert req msg"),
+        };
+        let (id, template) = match &elements(request.value())?[..] {
+            [id, template, ..] if tag(id) == INTEGER => (*id, *template),
+            _ => bail!("invalid cert request"),
+        };
+
+        let mut decoded = Self {
+            der: msg.to_vec()?,
+            id: id.to_vec()?,
+            request: request.to_vec()?,
+            popo,
+            subject: None,
+            public_key: None,
+            extensions: None,
+            modified: false,
+        };
+
+        // The template is implicitly tagged, except for the `Name` choice.
+        for field in elements(template.value())? {
+            match tag(&field) & 0x1f {
+                1..=4 => decoded.modified = true,
+                5 => decoded.subject = Some(field.value()),
+                6 => decoded.public_key = Some(tlv(SEQUENCE, field.value())),
+                9 => decoded.extensions = Some(tlv(SEQUENCE, field.value())),
+                _ => (),
+            }
+        }
+        Ok(decoded)
+    }
+
+    /// Verifies a signature proof-of-possession of `public_key`.
+    fn prove(&self, public_key: &SubjectPublicKeyInfo<'_>) -> Result<(), Failure> {
+        let popo = match self.popo {
+            Some(popo) if tag(&popo) == constructed(1) => popo,
+            _ => {
+                debug!("cmp request without a signature proof-of-possession");
+                return Err(Failure::BadPop);
+            }
+        };
+
+        let verified = (|| match &elements(popo.value())?[..] {
+            [algorithm, signature] => public_key.verify(
+                &self.request,
+                algorithm.decode_into()?,
+                signature.decode_into::<BitStringRef<'_>>()?.raw_bytes(),
+            ),
+            _ => bail!("proof-of-possession over an input other than the request"),
+        })();
+        verified.map_err(|e| {
+            debug!("failed to verify cmp proof-of-possession: {e}");
+            Failure::BadPop
+        })
+    }
+}
+
+/// Appraises the request of `template` and issues for it, returning the
+/// certificate and whether the template was not followed to the letter.
+async fn certify(
+    state: &State,
+    issuer: &Certificate<'_>,
+    pki: &PrivateKeyInfo<'_>,
+    policy: &Policy,
+    template: &Template<'_>,
+) -> Result<(Vec<u8>, bool), Failure> {
+    let public_key = template
+        .public_key
+        .as_ref()
+        .ok_or(Failure::BadCertTemplate)?;
+    let public_key =
+        SubjectPublicKeyInfo::from_der(public_key).or(Err(Failure::BadCertTemplate))?;
+    template.prove(&public_key)?;
+
+    let subject = match template.subject {
+        Some(subject) => RdnSequence::from_der(subject).or(Err(Failure::BadCertTemplate))?,
+        None => RdnSequence::default(),
+    };
+    let mut attributes = Vec::new();
+    if let Some(extensions) = &template.extensions {
+        let any = AnyRef::from_der(extensions).or(Err(Failure::BadCertTemplate))?;
+        attributes.push(Attribute {
+            oid: ID_EXTENSION_REQ,
+            values: vec![any].try_into().or(Err(Failure::SystemFailure))?,
+        });
+    }
+    let info = CertReqInfo {
+        version: Version::V1,
+        subject,
+        public_key,
+        attributes: attributes.try_into().or(Err(Failure::SystemFailure))?,
+    };
+    Limits::default().check_info(&info).map_err(|e| {
+        debug!("cmp certification request refused: {e}");
+        Failure::BadRequest
+    })?;
+
+    let (extensions, appraisals) = appraise(&info, debug_mode(issuer), state, policy).await?;
+    let request = state.archive.as_ref().map(|_| template.der.clone());
+    let (crt, _) = issue(
+        issuer,
+        pki,
+        sans(state)?,
+        info,
+        extensions,
+        &appraisals,
+        None,
+        policy,
+        state,
+        request,
+    )
+    .await?;
+    Ok((crt, template.modified))
+}
+
+/// Encodes a `PKIStatusInfo`.
+fn status(status: u8, failure: Option<Failure>) -> Vec<u8> {
+    let status = tlv(INTEGER, &[status]);
+    match failure {
+        Some(failure) => sequence(&[&status, &named_bit(failure as usize)]),
+        None => sequence(&[&status]),
+    }
+}
+
+/// Encodes the `CertResponse` to the request `id`.
+fn response(id: &[u8], outcome: Result<(Vec<u8>, bool), Failure>) -> Vec<u8> {
+    match outcome {
+        Ok((crt, modified)) => {
+            let granted = if modified {
+                GRANTED_WITH_MODS
+            } else {
+                ACCEPTED
+            };
+            let pair = sequence(&[&tlv(constructed(0), &crt)]);
+            sequence(&[id, &status(granted, None), &pair])
+        }
+        Err(failure) => sequence(&[id, &status(REJECTION, Some(failure))]),
+    }
+}
+
+/// Answers the body of `message`.
+async fn answer(
+    state: &State,
+    issuer: &Certificate<'_>,
+    pki: &PrivateKeyInfo<'_>,
+    message: &Message<'_>,
+) -> Result<Vec<u8>, Failure> {
+    if !matches!(message.header.pvno, 2 | 3) {
+        debug!("unsupported cmp version {}", message.header.pvno);
+        return Err(Failure::UnsupportedVersion);
+    }
+    message.verify()?;
+
+    let kind = tag(&message.body);
+    match kind {
+        IR | CR => {
+            let templates = (|| {
+                let msgs = AnyRef::from_der(message.body.value())?;
+                elements(msgs.value())?
+                    .into_iter()
+                    .map(Template::decode)
+                    .collect::<Result<Vec<_>>>()
+            })()
+            .map_err(|e| {
+                debug!("invalid cmp certification request: {e}");
+                Failure::BadDataFormat
+            })?;
+
+            // Issue under the same policy throughout, whatever reloads meanwhile.
+            let policy = state.policy();
+            let mut responses = Vec::with_capacity(templates.len());
+            for template in &templates {
+                let outcome = certify(state, issuer, pki, &policy, template).await;
+                if let Err(failure) = &outcome {
+                    state.notify(
+                        "rejection",
+                        json!({ "protocol": "cmp", "reason": failure.name() }),
+                    );
+                }
+                responses.push(response(&template.id, outcome));
+            }
+
+            let rep = sequence(&[&tlv(SEQUENCE, &responses.concat())]);
+            Ok(tlv(if kind == IR { IP } else { CP }, &rep))
+        }
+        CERT_CONF => Ok(tlv(PKI_CONF, &[0x05, 0x00])),
+        _ => {
+            debug!("unsupported cmp message type {kind:#x}");
+            Err(Failure::BadRequest)
+        }
+    }
+}
+
+/// Wraps `body` in a `PKIMessage` answering `request`, signed by the CA.
+fn reply(
+    state: &State,
+    issuer: &Certificate<'_>,
+    pki: &PrivateKeyInfo<'_>,
+    request: &Header<'_>,
+    body: &[u8],
+) -> Result<Vec<u8>> {
+    let algorithm = pki.signs_with()?;
+    let now = GeneralizedTime::from_system_time(state.clock.now())?;
+    let mut nonce = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let mut fields = vec![
+        tlv(INTEGER, &[2]),
+        tlv(constructed(4), &issuer.tbs_certificate.subject.to_vec()?),
+        request.sender.to_vec()?,
+        tlv(constructed(0), &now.to_vec()?),
+        tlv(constructed(1), &algorithm.to_vec()?),
+    ];
+    if let Some(transaction) = request.transaction {
+        fields.push(tlv(constructed(4), &tlv(OCTET_STRING, transaction)));
+    }
+    fields.push(tlv(constructed(5), &tlv(OCTET_STRING, &nonce)));
+    if let Some(nonce) = request.nonce {
+        fields.push(tlv(constructed(6), &tlv(OCTET_STRING, nonce)));
+    }
+    if request.implicit_confirm && matches!(body.first(), Some(&IP | &CP)) {
+        let confirm = sequence(&[&oid(ID_IT_IMPLICIT_CONFIRM)?, &[0x05, 0x00]]);
+        fields.push(tlv(constructed(8), &sequence(&[&confirm])));
+    }
+    let header = tlv(SEQUENCE, &fields.concat());
+
+    let signature = pki.sign(&sequence(&[&header, body]), algorithm)?;
+    let protection = tlv(BIT_STRING, &[&[0][..], &signature].concat());
+    Ok(sequence(&[
+        &header,
+        body,
+        &tlv(constructed(0), &protection),
+        &tlv(constructed(1), &sequence(&[&state.crt])),
+    ]))
+}
+
+fn internal(e: anyhow::Error) -> StatusCode {
+    debug!("cmp failure: {e}");
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+/// Holds the DER or BER `message` to the `parsing` policy, returning its DER:
+/// normalized from BER in compat mode, or checked to be canonical in strict
+/// mode.
+fn normalized(parsing: Strictness, message: &[u8]) -> Result<Vec<u8>> {
+    let limits = Limits {
+        depth: Limits::default().depth + NESTING,
+        ..Limits::default()
+    };
+    match parsing {
+        Strictness::Der => limits.check_depth(message)?,
+        Strictness::Strict => {
+            limits.check_depth(message)?;
+            ensure!(
+                parse::normalize(message, &limits)? == message,
+                "not canonical der"
+            );
+        }
+        Strictness::Compat => return parse::normalize(message, &limits),
+    }
+    Ok(message.to_vec())
+}
+
+/// Answers a CMP message, with an `error` message if it is refused as a
+/// whole.
+async fn respond(state: &State, der: &[u8]) -> Result<Vec<u8>, StatusCode> {
+    let der = normalized(state.policy().config.parsing, der).map_err(|e| {
+        debug!("invalid cmp message: {e}");
+        StatusCode::BAD_REQUEST
+    })?;
+    let message = Message::decode(&der).map_err(|e| {
+        debug!("invalid cmp message: {e}");
+        StatusCode::BAD_REQUEST
+    })?;
+    let issuer = Certificate::from_der(&state.crt).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    let pki = PrivateKeyInfo::from_der(&state.key).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    let body = match answer(state, &issuer, &pki, &message).await {
+        Ok(body) => body,
+        Err(failure) => {
+            state.notify(
+                "rejection",
+                json!({ "protocol": "cmp", "reason": failure.name() }),
+            );
+            tlv(ERROR, &sequence(&[&status(REJECTION, Some(failure))]))
+        }
+    };
+    reply(state, &issuer, &pki, &message.header, &body).map_err(internal)
+}
+
+/// Answers a CMP message POSTed to `/cmp`.
+pub async fn cmp(
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<State>>,
+    body: Bytes,
+) -> Result<Response, StatusCode> {
+    match headers.get(CONTENT_TYPE).and_then(|ct| ct.to_str().ok()) {
+        Some(ct) if ct.eq_ignore_ascii_case(PKIXCMP) => (),
+        _ => return Err(StatusCode::BAD_REQUEST),
+    }
+
+    let rep = respond(&state, &body).await?;
+    Ok(([(CONTENT_TYPE, PKIXCMP)], rep).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use const_oid::db::rfc5912::SECP_256_R_1;
+
+    const NONCE: &[u8] = &[0x5a; 16];
+
+    /// Encodes a `CertReqMsg` for the key of `pki`, whose proof-of-possession
+    /// is signed by `signer`.
+    fn cert_req_msg(pki: &PrivateKeyInfo<'_>, signer: &PrivateKeyInfo<'_>, exts: &[u8]) -> Vec<u8> {
+        let spki = pki.public_key().unwrap().to_vec().unwrap();
+        let spki = AnyRef::from_der(&spki).unwrap();
+        let subject = RdnSequence::default().to_vec().unwrap();
+        let template = sequence(&[
+            &tlv(constructed(5), &subject),
+            &tlv(constructed(6), spki.value()),
+            &tlv(constructed(9), exts),
+        ]);
+        let request = sequence(&[&tlv(INTEGER, &[7]), &template]);
+
+        let algorithm = signer.signs_with().unwrap();
+        let signature = signer.sign(&request, algorithm).unwrap();
+        let popo = [
+            algorithm.to_vec().unwrap(),
+            tlv(BIT_STRING, &[&[0][..], &signature].concat()),
+        ];
+        sequence(&[&request, &tlv(constructed(1), &popo.concat())])
+    }
+
+    /// Encodes an unprotected `PKIMessage` of version `pvno` with `body`.
+    fn message(pvno: u8, body: &[u8]) -> Vec<u8> {
+        let name = tlv(constructed(4), &RdnSequence::default().to_vec().unwrap());
+        let confirm = sequence(&[&oid(ID_IT_IMPLICIT_CONFIRM).unwrap(), &[0x05, 0x00]]);
+        let header = sequence(&[
+            &tlv(INTEGER, &[pvno]),
+            &name,
+            &name,
+            &tlv(constructed(4), &tlv(OCTET_STRING, b"42")),
+            &tlv(constructed(5), &tlv(OCTET_STRING, NONCE)),
+            &tlv(constructed(8), &sequence(&[&confirm])),
+        ]);
+        sequence(&[&header, body])
+    }
+
+    /// Answers `msg`, checking the reply is protected by the CA and echoes
+    /// the transaction and nonce.
+    async fn exchange(state: &State, msg: &[u8]) -> Vec<u8> {
+        let der = respond(state, msg).await.unwrap();
+        let rep = Message::decode(&der).unwrap();
+        rep.verify().unwrap();
+        assert_eq!(rep.extra[0].to_vec().unwrap(), state.crt);
+        assert_eq!(rep.header.transaction, Some(&b"42"[..]));
+
+        let fields = elements(rep.header.der.value()).unwrap();
+        let recipient_nonce = fields.iter().find(|f| tag(f) == constructed(6)).unwrap();
+        assert_eq!(recipient_nonce.value(), tlv(OCTET_STRING, NONCE));
+        der
+    }
+
+    #[tokio::test]
+    async fn confirm() {
+        let mut state = State::generate(None, "localhost").unwrap();
+
+        let rep = exchange(&state, &message(2, &tlv(CERT_CONF, &sequence(&[])))).await;
+        let confirmed = Message::decode(&rep).unwrap();
+        assert_eq!(confirmed.body.to_vec().unwrap(), [PKI_CONF, 2, 0x05, 0x00]);
+        assert!(!confirmed.header.implicit_confirm);
+
+        let rep = exchange(&state, &message(1, &tlv(CERT_CONF, &sequence(&[])))).await;
+        let error = Message::decode(&rep).unwrap();
+        assert_eq!(tag(&error.body), ERROR);
+        let expected = sequence(&[&status(REJECTION, Some(Failure::UnsupportedVersion))]);
+        assert_eq!(error.body.value(), expected);
+
+        assert_eq!(respond(&state, b"junk").await, Err(StatusCode::BAD_REQUEST));
+
+        // Messages in BER are only answered in compat mode.
+        let msg = message(2, &tlv(CERT_CONF, &sequence(&[])));
+        let any = AnyRef::from_der(&msg).unwrap();
+        let ber = [&[SEQUENCE, 0x80][..], any.value(), &[0, 0]].concat();
+        assert_eq!(respond(&state, &ber).await, Err(StatusCode::BAD_REQUEST));
+        state.config_mut().parsing = Strictness::Compat;
+        exchange(&state, &ber).await;
+    }
+
+    #[tokio::test]
+    async fn protection() {
+        let state = State::generate(None, "localhost").unwrap();
+
+        // Once its body is changed, a reply no longer verifies.
+        let mut rep = exchange(&state, &message(2, &tlv(CERT_CONF, &sequence(&[])))).await;
+        let body = rep.windows(4).position(|w| w == [PKI_CONF, 2, 0x05, 0x00]);
+        rep[body.unwrap() + 2] = OCTET_STRING;
+        let mut changed = Message::decode(&rep).unwrap();
+        assert_eq!(changed.verify(), Err(Failure::BadMessageCheck));
+
+        // Nor is a MAC accepted.
+        changed.header.protection = Some(AlgorithmIdentifier {
+            oid: PASSWORD_BASED_MAC,
+            parameters: None,
+        });
+        assert_eq!(changed.verify(), Err(Failure::BadAlg));
+    }
+
+    #[cfg(feature = "kvm")]
+    #[tokio::test]
+    async fn enroll() {
+        use super::super::kvm::Kvm;
+
+        use attestation::crypto::TbsCertificateExt;
+        use x509::ext::Extension as X509Extension;
+
+        let state = State::generate(None, "localhost").unwrap();
+        let kvm = X509Extension {
+            extn_id: Kvm::OID,
+            critical: false,
+            extn_value: &[],
+        };
+        let exts = kvm.to_vec().unwrap();
+
+        let key = PrivateKeyInfo::generate(SECP_256_R_1).unwrap();
+        let pki = PrivateKeyInfo::from_der(key.as_ref()).unwrap();
+        let other = PrivateKeyInfo::generate(SECP_256_R_1).unwrap();
+        let other = PrivateKeyInfo::from_der(other.as_ref()).unwrap();
+
+        // The second request's proof-of-possession is signed by another key.
+        let msgs = [
+            cert_req_msg(&pki, &pki, &exts),
+            cert_req_msg(&pki, &other, &exts),
+        ];
+        let msg = message(2, &tlv(IR, &tlv(SEQUENCE, &msgs.concat())));
+
+        let rep = exchange(&state, &msg).await;
+        let ip = Message::decode(&rep).unwrap();
+        assert_eq!(tag(&ip.body), IP);
+        assert!(ip.header.implicit_confirm);
+
+        let rep = AnyRef::from_der(ip.body.value()).unwrap();
+        let responses = elements(elements(rep.value()).unwrap()[0].value()).unwrap();
+        let (accepted, refused) = match &responses[..] {
+            [accepted, refused] => (
+                elements(accepted.value()).unwrap(),
+                elements(refused.value()).unwrap(),
+            ),
+            _ => panic!("expected two responses"),
+        };
+
+        assert_eq!(accepted[0].to_vec().unwrap(), tlv(INTEGER, &[7]));
+        assert_eq!(accepted[1].to_vec().unwrap(), status(ACCEPTED, None));
+        let pair = elements(accepted[2].value()).unwrap()[0];
+        let crt = Certificate::from_der(pair.value()).unwrap();
+        let ca = Certificate::from_der(&state.crt).unwrap();
+        ca.tbs_certificate.verify_crt(&crt).unwrap();
+        assert_eq!(
+            crt.tbs_certificate.subject_public_key_info,
+            pki.public_key().unwrap()
+        );
+
+        let expected = status(REJECTION, Some(Failure::BadPop));
+        assert_eq!(refused[1].to_vec().unwrap(), expected);
+        assert_eq!(refused.len(), 2);
+
+        // Requests are held to the same limits as those posted directly.
+        let exts = vec![exts; 65].concat();
+        let msg = message(
+            2,
+            &tlv(IR, &tlv(SEQUENCE, &cert_req_msg(&pki, &pki, &exts))),
+        );
+        let rep = exchange(&state, &msg).await;
+        let ip = Message::decode(&rep).unwrap();
+        let rep = AnyRef::from_der(ip.body.value()).unwrap();
+        let responses = elements(elements(rep.value()).unwrap()[0].value()).unwrap();
+        let refused = elements(responses[0].value()).unwrap();
+        let expected = status(REJECTION, Some(Failure::BadRequest));
+        assert_eq!(refused[1].to_vec().unwrap(), expected);
+    }
+}
@@ -0,0 +1,693 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Resilient fetching of platform collateral from vendor services.
+//!
+//! Clients send revocation lists alongside their evidence, but steward can
+//! also fetch current ones itself. Those calls sit on the issuance path, so
+//! every upstream has bounded timeouts, retries with exponential backoff
+//! and a circuit breaker: once a service keeps failing, requests are served
+//! from the last good response, or fail fast with `503 Service Unavailable`,
+//! until it has had time to recover.
+//!
+//! Collateral is prefetched at startup and refreshed in the background
+//! before it expires, so that issuance rarely waits on a vendor.
+
+#[cfg(feature = "chaos")]
+use super::chaos::{Fault, Faults};
+use super::metrics::FETCH_DURATION;
+use super::scheduler::Scheduler;
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, ensure, Context, Result};
+use axum::http::header::RETRY_AFTER;
+use axum::http::{HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use der::Decode;
+use tracing::{debug, warn};
+use x509::crl::CertificateList;
+
+/// Intel's Provisioning Certification Service.
+pub const PCS: &str = "https://api.trustedservices.intel.com";
+
+const SGX_ROOT_CRL: &str = "https://certificates.trustedservices.intel.com/IntelSGXRootCA.der";
+
+/// Timeouts, retries and circuit breaker settings for an upstream service.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Settings {
+    /// How long to wait for a connection.
+    pub connect_timeout: Duration,
+
+    /// How long to wait for a whole response.
+    pub timeout: Duration,
+
+    /// How many times a failed request is retried.
+    pub retries: u32,
+
+    /// The delay before the first retry, doubled for each one after.
+    pub backoff: Duration,
+
+    /// Consecutive failures after which the circuit opens.
+    pub threshold: u32,
+
+    /// How long an open circuit fails fast before trying again.
+    pub cooldown: Duration,
+
+    /// How long a response is used before it is refreshed.
+    pub ttl: Duration,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(2),
+            timeout: Duration::from_secs(5),
+            retries: 2,
+            backoff: Duration::from_millis(200),
+            threshold: 5,
+            cooldown: Duration::from_secs(30),
+            ttl: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// The service is down and no earlier response is available.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Unavailable {
+    pub service: &'static str,
+    pub retry_after: Duration,
+}
+
+impl std::fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is unavailable", self.service)
+    }
+}
+
+impl std::error::Error for Unavailable {}
+
+#[derive(Debug, Default)]
+struct Circuit {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// A circuit breaker, which opens after too many consecutive failures.
+#[derive(Debug)]
+struct Breaker {
+    threshold: u32,
+    cooldown: Duration,
+    circuit: Mutex<Circuit>,
+}
+
+impl Breaker {
+    fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            cooldown,
+            circuit: Default::default(),
+        }
+    }
+
+    /// Checks whether a call may be made, or else how long until one may.
+    fn check(&self, now: Instant) -> Result<(), Duration> {
+        let mut circuit = self.circuit.lock().unwrap();
+        match circuit.open_until {
+            Some(until) if until > now => Err(until - now),
+
+            // Half open: let this call through as a trial, and hold off all
+            // others until it has had the chance to complete.
+            Some(..) => {
+                circuit.open_until = Some(now + self.cooldown);
+                Ok(())
+            }
+
+            None => Ok(()),
+        }
+    }
+
+    /// Whether calls are currently failing fast.
+    fn is_open(&self, now: Instant) -> bool {
+        let circuit = self.circuit.lock().unwrap();
+        circuit.open_until.map_or(false, |until| until > now)
+    }
+
+    fn success(&self) {
+        *self.circuit.lock().unwrap() = Circuit::default();
+    }
+
+    fn failure(&self, now: Instant) {
+        let mut circuit = self.circuit.lock().unwrap();
+        circuit.failures += 1;
+        if circuit.failures >= self.threshold {
+            circuit.open_until = Some(now + self.cooldown);
+        }
+    }
+
+    /// How long clients should wait before trying again.
+    fn retry_after(&self, now: Instant) -> Duration {
+        let circuit = self.circuit.lock().unwrap();
+        circuit
+            .open_until
+            .map_or(Duration::ZERO, |until| until.saturating_duration_since(now))
+            .max(Duration::from_secs(1))
+    }
+}
+
+/// A client of one upstream service.
+#[derive(Debug)]
+pub struct Upstream {
+    name: &'static str,
+    client: reqwest::Client,
+    settings: Settings,
+    breaker: Breaker,
+    cache: Mutex<HashMap<String, (Instant, Vec<u8>)>>,
+    #[cfg(feature = "chaos")]
+    faults: Faults,
+}
+
+impl Upstream {
+    pub fn new(name: &'static str, settings: Settings) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(settings.connect_timeout)
+            .timeout(settings.timeout)
+            .build()?;
+
+        Ok(Self {
+            name,
+            client,
+            breaker: Breaker::new(settings.threshold, settings.cooldown),
+            settings,
+            cache: Default::default(),
+            #[cfg(feature = "chaos")]
+            faults: Faults::default(),
+        })
+    }
+
+    /// Injects `faults` into fetches, for soak and chaos testing.
+    #[cfg(feature = "chaos")]
+    pub fn with_faults(mut self, faults: Faults) -> Self {
+        self.faults = faults;
+        self
+    }
+
+    /// Fetches `url`, whose body must pass `check` to count as a success.
+    pub async fn get(
+        &self,
+        url: &str,
+        check: impl Fn(&[u8]) -> Result<()>,
+    ) -> Result<Vec<u8>, Unavailable> {
+        let now = Instant::now();
+        let cached = self.cache.lock().unwrap().get(url).cloned();
+        let stale = match cached {
+            Some((fetched, body)) if now.duration_since(fetched) < self.settings.ttl => {
+                return Ok(body)
+            }
+            cached => cached.map(|(_, body)| body),
+        };
+
+        self.load(url, &check, stale).await
+    }
+
+    /// Fetches `url` ahead of time, regardless of any cached response.
+    pub async fn refresh(
+        &self,
+        url: &str,
+        check: impl Fn(&[u8]) -> Result<()>,
+    ) -> Result<(), Unavailable> {
+        self.load(url, &check, None).await.map(drop)
+    }
+
+    async fn load(
+        &self,
+        url: &str,
+        check: &impl Fn(&[u8]) -> Result<()>,
+        stale: Option<Vec<u8>>,
+    ) -> Result<Vec<u8>, Unavailable> {
+        let now = Instant::now();
+        if let Err(retry_after) = self.breaker.check(now) {
+            debug!("{} circuit is open", self.name);
+            return stale.ok_or(Unavailable {
+                service: self.name,
+                retry_after,
+            });
+        }
+
+        let fetching = Instant::now();
+        let fetched = self.fetch(url, check).await;
+        FETCH_DURATION.observe(self.name, fetching.elapsed());
+        match fetched {
+            Ok(body) => {
+                self.breaker.success();
+                let entry = (Instant::now(), body.clone());
+                self.cache.lock().unwrap().insert(url.into(), entry);
+                Ok(body)
+            }
+            Err(e) => {
+                warn!("failed to fetch {url}: {e:#}");
+                let now = Instant::now();
+                self.breaker.failure(now);
+                stale.ok_or(Unavailable {
+                    service: self.name,
+                    retry_after: self.breaker.retry_after(now),
+                })
+            }
+        }
+    }
+
+    async fn fetch(&self, url: &str, check: &impl Fn(&[u8]) -> Result<()>) -> Result<Vec<u8>> {
+        let mut delay = self.settings.backoff;
+        let mut attempt = 0;
+        loop {
+            match self.attempt(url, check).await {
+                Err(e) if attempt < self.settings.retries => {
+                    debug!("attempt {attempt} to fetch {url} failed: {e:#}");
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn attempt(&self, url: &str, check: &impl Fn(&[u8]) -> Result<()>) -> Result<Vec<u8>> {
+        #[cfg(feature = "chaos")]
+        if self.faults.strikes(Fault::UpstreamTimeout) {
+            tokio::time::sleep(self.settings.timeout).await;
+            bail!("timed out reaching {}", self.name);
+        }
+
+        let rsp = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .with_context(|| format!("failed to reach {}", self.name))?;
+        ensure!(
+            rsp.status().is_success(),
+            "{} returned {}",
+            self.name,
+            rsp.status()
+        );
+
+        let body = rsp.bytes().await?.to_vec();
+        check(&body)?;
+        Ok(body)
+    }
+
+    /// How long clients should wait before trying again.
+    pub fn retry_after(&self) -> Duration {
+        self.breaker.retry_after(Instant::now())
+    }
+
+    /// Checks that `url` could be served, if only from a stale response.
+    fn available(&self, url: &str) -> Result<(), Unavailable> {
+        let now = Instant::now();
+        if self.cache.lock().unwrap().contains_key(url) || !self.breaker.is_open(now) {
+            return Ok(());
+        }
+
+        Err(Unavailable {
+            service: self.name,
+            retry_after: self.breaker.retry_after(now),
+        })
+    }
+}
+
+fn crl(der: &[u8]) -> Result<()> {
+    CertificateList::from_der(der).context("invalid crl")?;
+    Ok(())
+}
+
+/// The Intel CA which issues the PCK certificates of a product line.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PckCa {
+    /// Single-package parts, such as Xeon E.
+    Processor,
+
+    /// Multi-package parts, such as Xeon Scalable.
+    Platform,
+}
+
+impl PckCa {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Processor => "processor",
+            Self::Platform => "platform",
+        }
+    }
+}
+
+impl FromStr for PckCa {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "processor" => Ok(Self::Processor),
+            "platform" => Ok(Self::Platform),
+            _ => bail!("unknown pck ca `{s}`"),
+        }
+    }
+}
+
+/// Collateral fetched from the platform vendors.
+#[derive(Debug)]
+pub struct Collateral {
+    pcs: String,
+    root_crl: String,
+    cas: Vec<PckCa>,
+    intel: Upstream,
+}
+
+impl Collateral {
+    /// Fetches SGX collateral from the PCS instance at `pcs`.
+    pub fn new(pcs: &str, settings: Settings) -> Result<Self> {
+        Ok(Self {
+            pcs: pcs.trim_end_matches('/').into(),
+            root_crl: SGX_ROOT_CRL.into(),
+            cas: vec![PckCa::Processor, PckCa::Platform],
+            intel: Upstream::new("intel pcs", settings)?,
+        })
+    }
+
+    /// Injects `faults` into fetches of collateral, for soak and chaos testing.
+    #[cfg(feature = "chaos")]
+    pub fn with_faults(mut self, faults: Faults) -> Self {
+        self.intel = self.intel.with_faults(faults);
+        self
+    }
+
+    /// Fetches the revocation list of the SGX root CA from `url`, such as a
+    /// mirror, rather than from Intel.
+    pub fn with_root_crl(mut self, url: &str) -> Self {
+        self.root_crl = url.into();
+        self
+    }
+
+    /// Only fetches revocation lists for the PCK CAs of these product lines.
+    pub fn with_cas(mut self, cas: Vec<PckCa>) -> Self {
+        self.cas = cas;
+        self
+    }
+
+    fn sgx_urls(&self) -> Vec<String> {
+        let pck = self.cas.iter().map(|ca| {
+            format!(
+                "{}/sgx/certification/v4/pckcrl?ca={}&encoding=der",
+                self.pcs,
+                ca.as_str()
+            )
+        });
+        std::iter::once(self.root_crl.clone()).chain(pck).collect()
+    }
+
+    /// Returns the current SGX root CA and PCK CA revocation lists.
+    pub async fn sgx_crls(&self) -> Result<Vec<Vec<u8>>, Unavailable> {
+        let urls = self.sgx_urls();
+        let mut crls = Vec::with_capacity(urls.len());
+        for url in urls {
+            crls.push(self.intel.get(&url, crl).await?);
+        }
+        Ok(crls)
+    }
+
+    /// Fetches all collateral, so that the first attestations need not wait.
+    pub async fn prefetch(&self) -> Result<()> {
+        for url in self.sgx_urls() {
+            self.intel
+                .refresh(&url, crl)
+                .await
+                .with_context(|| format!("failed to prefetch {url}"))?;
+        }
+        Ok(())
+    }
+
+    /// Checks that every revocation list is cached or can be fetched.
+    pub fn available(&self) -> Result<(), Unavailable> {
+        self.sgx_urls()
+            .iter()
+            .try_for_each(|url| self.intel.available(url))
+    }
+
+    /// Adds a task refreshing all collateral well before it expires.
+    pub(crate) fn schedule(self: &Arc<Self>, scheduler: Scheduler) -> Scheduler {
+        const MINUTE: Duration = Duration::from_secs(60);
+
+        let interval = (self.intel.settings.ttl / 2).max(MINUTE);
+        let collateral = self.clone();
+        scheduler.every("collateral", interval, MINUTE, move || {
+            let collateral = collateral.clone();
+            async move { collateral.prefetch().await }
+        })
+    }
+
+    /// How long clients should wait before trying again.
+    pub fn retry_after(&self) -> Duration {
+        self.intel.retry_after()
+    }
+}
+
+/// Tells clients when to retry requests which failed for want of collateral.
+pub(crate) async fn retry_after<B>(
+    collateral: Arc<Collateral>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let mut rsp = next.run(req).await;
+    if rsp.status() == StatusCode::SERVICE_UNAVAILABLE {
+        let secs = collateral.retry_after().as_secs();
+        rsp.headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(secs));
+    }
+    rsp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn breaker() {
+        let breaker = Breaker::new(2, COOLDOWN);
+        let now = Instant::now();
+        assert_eq!(breaker.check(now), Ok(()));
+
+        breaker.failure(now);
+        assert_eq!(breaker.check(now), Ok(()));
+        breaker.failure(now);
+        assert_eq!(breaker.check(now), Err(COOLDOWN));
+        assert_eq!(breaker.retry_after(now), COOLDOWN);
+
+        // A single trial is let through once the cooldown has passed.
+        let later = now + COOLDOWN;
+        assert_eq!(breaker.check(later), Ok(()));
+        assert_eq!(breaker.check(later), Err(COOLDOWN));
+
+        // A failed trial opens the circuit again, a success closes it.
+        breaker.failure(later);
+        assert!(breaker.check(later).is_err());
+        breaker.success();
+        assert_eq!(breaker.check(later), Ok(()));
+        assert_eq!(breaker.retry_after(later), Duration::from_secs(1));
+    }
+
+    fn unreachable() -> Upstream {
+        let settings = Settings {
+            retries: 0,
+            threshold: 1,
+            ttl: Duration::ZERO,
+            ..Default::default()
+        };
+        Upstream::new("test", settings).unwrap()
+    }
+
+    #[tokio::test]
+    async fn fails_fast() {
+        const URL: &str = "http://127.0.0.1:9/crl";
+
+        let upstream = unreachable();
+        let err = upstream.get(URL, crl).await.unwrap_err();
+        assert_eq!(err.service, "test");
+
+        // The circuit is now open, so no connection is attempted.
+        let err = upstream.get(URL, crl).await.unwrap_err();
+        assert!(err.retry_after > Duration::from_secs(1));
+    }
+
+    #[test]
+    fn pck_cas() {
+        assert_eq!("processor".parse::<PckCa>().unwrap(), PckCa::Processor);
+        assert_eq!("platform".parse::<PckCa>().unwrap(), PckCa::Platform);
+        assert!("client".parse::<PckCa>().is_err());
+
+        let collateral = Collateral::new("https://pcs.example.com/", Settings::default())
+            .unwrap()
+            .with_cas(vec![PckCa::Platform]);
+        assert_eq!(
+            collateral.sgx_urls(),
+            [
+                SGX_ROOT_CRL,
+                "https://pcs.example.com/sgx/certification/v4/pckcrl?ca=platform&encoding=der",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_stale() {
+        const URL: &str = "http://127.0.0.1:9/crl";
+
+        let upstream = unreachable();
+        let entry = (Instant::now(), b"stale".to_vec());
+        upstream.cache.lock().unwrap().insert(URL.into(), entry);
+
+        assert_eq!(upstream.get(URL, crl).await.unwrap(), b"stale");
+        assert_eq!(upstream.get(URL, crl).await.unwrap(), b"stale");
+    }
+
+    #[cfg(feature = "chaos")]
+    #[tokio::test]
+    async fn injected_timeout() {
+        const URL: &str = "https://pcs.example.com/crl";
+
+        let settings = Settings {
+            timeout: Duration::from_millis(10),
+            retries: 0,
+            ttl: Duration::ZERO,
+            ..Default::default()
+        };
+        let faults = Faults::default().with(Fault::UpstreamTimeout, 1.0);
+        let upstream = Upstream::new("test", settings).unwrap().with_faults(faults);
+        assert_eq!(upstream.get(URL, crl).await.unwrap_err().service, "test");
+
+        // The last good response is served while the service times out.
+        let entry = (Instant::now(), b"stale".to_vec());
+        upstream.cache.lock().unwrap().insert(URL.into(), entry);
+        assert_eq!(upstream.get(URL, crl).await.unwrap(), b"stale");
+    }
+
+    #[cfg(feature = "sgx")]
+    #[tokio::test]
+    async fn pcs() {
+        use super::super::mock::{Mock, ROOT_CRL};
+
+        let pcs = Mock::pcs();
+        let settings = Settings {
+            retries: 0,
+            ttl: Duration::ZERO,
+            ..Default::default()
+        };
+        let collateral = Collateral::new(pcs.url(), settings)
+            .unwrap()
+            .with_root_crl(&format!("{}{ROOT_CRL}", pcs.url()));
+        collateral.prefetch().await.unwrap();
+        assert_eq!(collateral.sgx_crls().await.unwrap().len(), 3);
+        for url in collateral.sgx_urls() {
+            let path = url.strip_prefix(pcs.url()).unwrap();
+            assert_eq!(pcs.hits(path), 2, "{path}");
+        }
+
+        // Once the service fails, the lists it last served are used.
+        pcs.fail(StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(collateral.sgx_crls().await.unwrap().len(), 3);
+        assert_eq!(pcs.hits(ROOT_CRL), 3);
+        assert_eq!(collateral.available(), Ok(()));
+    }
+
+    #[cfg(feature = "sgx")]
+    #[tokio::test]
+    async fn sgx_issuance() {
+        use super::super::clock::Manual;
+        use super::super::mock::{icelake_now, Mock, ICELAKE, ROOT_CRL};
+        use super::super::{app, State, PKCS10};
+        use axum::http::header::CONTENT_TYPE;
+        use hyper::Body;
+        use tower::ServiceExt; // for `app.oneshot()`
+
+        let pcs = Mock::pcs();
+        let collateral = Collateral::new(pcs.url(), Settings::default())
+            .unwrap()
+            .with_root_crl(&format!("{}{ROOT_CRL}", pcs.url()));
+        let clock = Arc::new(Manual::new(icelake_now()));
+        let state = State::generate_with(None, "localhost", &Default::default(), clock)
+            .unwrap()
+            .with_collateral(collateral);
+        let attest = || {
+            let request = Request::post("/")
+                .header(CONTENT_TYPE, PKCS10)
+                .body(Body::from(ICELAKE))
+                .unwrap();
+            app(state.clone()).oneshot(request)
+        };
+
+        // The lists are fetched for the first request and cached for later ones.
+        assert_eq!(attest().await.unwrap().status(), StatusCode::OK);
+        assert_eq!(attest().await.unwrap().status(), StatusCode::OK);
+        assert_eq!(pcs.hits(ROOT_CRL), 1);
+    }
+
+    #[cfg(feature = "sgx")]
+    #[tokio::test]
+    async fn pcs_down() {
+        use super::super::mock::{Mock, ROOT_CRL};
+
+        let pcs = Mock::pcs();
+        pcs.fail(StatusCode::SERVICE_UNAVAILABLE);
+        let settings = Settings {
+            retries: 1,
+            backoff: Duration::from_millis(1),
+            threshold: 1,
+            ..Default::default()
+        };
+        let collateral = Collateral::new(pcs.url(), settings)
+            .unwrap()
+            .with_root_crl(&format!("{}{ROOT_CRL}", pcs.url()));
+        assert!(collateral.prefetch().await.is_err());
+        assert_eq!(pcs.hits(ROOT_CRL), 2);
+
+        // With the circuit open, no more requests are made.
+        let err = collateral.sgx_crls().await.unwrap_err();
+        assert_eq!(err.service, "intel pcs");
+        assert_eq!(pcs.hits(ROOT_CRL), 2);
+        assert!(collateral.available().is_err());
+    }
+
+    #[cfg(feature = "snp")]
+    #[tokio::test]
+    async fn kds() {
+        use super::super::mock::Mock;
+
+        let kds = Mock::kds();
+        let upstream = Upstream::new("amd kds", Settings::default()).unwrap();
+        for product in ["Milan", "Genoa"] {
+            let url = format!("{}/vcek/v1/{product}/crl", kds.url());
+            let der = upstream.get(&url, crl).await.unwrap();
+            CertificateList::from_der(&der).unwrap();
+        }
+        assert_eq!(kds.hits("/vcek/v1/Milan/crl"), 1);
+    }
+
+    #[tokio::test]
+    async fn available() {
+        const URL: &str = "http://127.0.0.1:9/crl";
+
+        // Untried services are assumed to be reachable.
+        let upstream = unreachable();
+        assert_eq!(upstream.available(URL), Ok(()));
+
+        upstream.get(URL, crl).await.unwrap_err();
+        assert_eq!(upstream.available(URL).unwrap_err().service, "test");
+
+        // A stale response will do while the service is down.
+        let entry = (Instant::now(), b"stale".to_vec());
+        upstream.cache.lock().unwrap().insert(URL.into(), entry);
+        assert_eq!(upstream.available(URL), Ok(()));
+    }
+}
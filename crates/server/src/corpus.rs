@@ -0,0 +1,384 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Conformance testing against a corpus of evidence samples.
+//!
+//! A corpus is a directory tree of certification requests (`*.csr`, in DER),
+//! each beside a `*.toml` file of the same name saying what should come of
+//! appraising it:
+//!
+//! ```toml
+//! outcome = "reject"
+//! # Optional: part of the reason the sample is rejected for.
+//! reason = "invalid signature"
+//! ```
+//!
+//! Samples are appraised as steward would appraise them under its policy,
+//! but nothing is issued. Neither the age of evidence nor quotas are
+//! checked, as samples are kept for far longer than evidence stays fresh.
+
+use super::{debug_mode, platforms, State};
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use attestation::crypto::CertReqExt;
+use attestation::parse;
+use const_oid::db::rfc5912::ID_EXTENSION_REQ;
+use der::Decode;
+use serde::Deserialize;
+use x509::request::ExtensionReq;
+use x509::Certificate;
+
+/// Whether a sample is certified.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Accept,
+    Reject,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Accept => f.write_str("accept"),
+            Self::Reject => f.write_str("reject"),
+        }
+    }
+}
+
+/// What should come of appraising a sample.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Expected {
+    pub outcome: Outcome,
+
+    /// Part of the reason the sample is rejected for.
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// What came of appraising a sample.
+#[derive(Clone, Debug)]
+pub struct Sample {
+    /// The path of the sample in the corpus, without its extension.
+    pub name: String,
+
+    /// The platforms whose evidence the sample carries.
+    pub platform: String,
+
+    pub expected: Expected,
+    pub outcome: Outcome,
+
+    /// Why the sample was rejected.
+    pub reason: Option<String>,
+}
+
+impl Sample {
+    /// Whether the sample came out as expected.
+    pub fn passed(&self) -> bool {
+        let reason = match (&self.expected.reason, &self.reason) {
+            (Some(expected), Some(actual)) => actual.contains(expected.as_str()),
+            (Some(..), None) => false,
+            (None, _) => true,
+        };
+        self.outcome == self.expected.outcome && (self.outcome == Outcome::Accept || reason)
+    }
+}
+
+/// What came of appraising a whole corpus.
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub samples: Vec<Sample>,
+}
+
+impl Report {
+    /// Whether every sample came out as expected.
+    pub fn passed(&self) -> bool {
+        self.samples.iter().all(Sample::passed)
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &Sample> {
+        self.samples.iter().filter(|sample| !sample.passed())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Tally passes and failures by platform and expected outcome.
+        let mut matrix = std::collections::BTreeMap::<_, (usize, usize)>::new();
+        for sample in &self.samples {
+            let key = (
+                sample.platform.as_str(),
+                sample.expected.outcome.to_string(),
+            );
+            let (passed, failed) = matrix.entry(key).or_default();
+            match sample.passed() {
+                true => *passed += 1,
+                false => *failed += 1,
+            }
+        }
+
+        writeln!(
+            f,
+            "{:<12} {:<8} {:>6} {:>6}",
+            "platform", "expected", "pass", "fail"
+        )?;
+        for ((platform, expected), (passed, failed)) in &matrix {
+            writeln!(f, "{platform:<12} {expected:<8} {passed:>6} {failed:>6}")?;
+        }
+
+        for sample in self.failures() {
+            write!(
+                f,
+                "FAIL {}: expected {}",
+                sample.name, sample.expected.outcome
+            )?;
+            if let Some(reason) = &sample.expected.reason {
+                write!(f, " ({reason:?})")?;
+            }
+            write!(f, ", got {}", sample.outcome)?;
+            match &sample.reason {
+                Some(reason) => writeln!(f, " ({reason:?})")?,
+                None => writeln!(f)?,
+            }
+        }
+
+        let failed = self.failures().count();
+        writeln!(
+            f,
+            "{} of {} samples passed",
+            self.samples.len() - failed,
+            self.samples.len()
+        )
+    }
+}
+
+/// Appraises the certification request `cr`, returning the platforms whose
+/// evidence it carries and the reason it is rejected for, if it is.
+pub async fn appraise(state: &State, cr: &[u8]) -> (String, Option<String>) {
+    let mut seen = Vec::new();
+    let reason = check(state, cr, &mut seen).await.err();
+    let platform = match seen.is_empty() {
+        true => "none".into(),
+        false => seen.join("+"),
+    };
+    (platform, reason.map(|e| format!("{e:#}")))
+}
+
+async fn check(state: &State, cr: &[u8], seen: &mut Vec<&'static str>) -> Result<()> {
+    let issuer = Certificate::from_der(&state.crt)?;
+    let cr = parse::cert_req(cr).context("malformed request")?;
+    let info = cr.verify().context("invalid request signature")?;
+
+    let dbg = debug_mode(&issuer);
+    let policy = state.policy();
+    let config = &policy.config;
+    let mut verified = Vec::new();
+    let mut failure = None;
+    for attribute in info.attributes.iter() {
+        if attribute.oid != ID_EXTENSION_REQ {
+            continue;
+        }
+        for any in attribute.values.iter() {
+            let request: ExtensionReq<'_> =
+                any.decode_into().context("malformed extension request")?;
+            for ext in Vec::from(request) {
+                let verifier = match state.verifiers.get(&ext.extn_id) {
+                    Some(verifier) => verifier,
+                    None => continue,
+                };
+                seen.push(verifier.platform());
+
+                let appraiser = verifier
+                    .prepare(state, config, &info, &ext, dbg)
+                    .await
+                    .map_err(|status| anyhow!("{} unappraisable: {status}", verifier.platform()))?;
+                match appraiser() {
+                    Ok(appraisal) if appraisal.attests => verified.push(verifier.platform()),
+                    Ok(..) => (),
+                    Err(e) => {
+                        let e = e.context(format!("{} evidence rejected", verifier.platform()));
+                        match config.platforms.require {
+                            platforms::Require::All => return Err(e),
+                            platforms::Require::Any => failure = failure.or(Some(e)),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Where no combination of platforms verified, say what failed to.
+    config
+        .platforms
+        .check(&verified)
+        .map_err(|e| failure.unwrap_or(e))
+}
+
+/// Returns the samples under `dir`, in order.
+fn samples(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("failed to read {}", dir.display()))?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            found.extend(samples(&path)?);
+        } else if path.extension().map_or(false, |ext| ext == "csr") {
+            found.push(path);
+        }
+    }
+    Ok(found)
+}
+
+/// Appraises every sample in the corpus at `dir`.
+pub async fn run(state: &State, dir: &Path) -> Result<Report> {
+    let mut report = Report::default();
+    for path in samples(dir)? {
+        let expectations = path.with_extension("toml");
+        let expected = std::fs::read_to_string(&expectations)
+            .with_context(|| format!("failed to read {}", expectations.display()))?;
+        let expected: Expected = toml::from_str(&expected)
+            .with_context(|| format!("invalid expectations in {}", expectations.display()))?;
+        let cr = std::fs::read(&path)?;
+
+        let (platform, reason) = appraise(state, &cr).await;
+        let name = path.strip_prefix(dir).unwrap_or(&path).with_extension("");
+        report.samples.push(Sample {
+            name: name.display().to_string(),
+            platform,
+            expected,
+            outcome: match reason {
+                Some(..) => Outcome::Reject,
+                None => Outcome::Accept,
+            },
+            reason,
+        });
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(platform: &str, expected: &str, outcome: Outcome, reason: Option<&str>) -> Sample {
+        Sample {
+            name: format!("{platform}/sample"),
+            platform: platform.into(),
+            expected: toml::from_str(expected).unwrap(),
+            outcome,
+            reason: reason.map(String::from),
+        }
+    }
+
+    #[test]
+    fn passed() {
+        let accept = r#"outcome = "accept""#;
+        let reject = "outcome = \"reject\"\nreason = \"signature\"";
+        assert!(sample("kvm", accept, Outcome::Accept, None).passed());
+        assert!(!sample("kvm", accept, Outcome::Reject, Some("x")).passed());
+        assert!(sample("sgx", reject, Outcome::Reject, Some("bad signature")).passed());
+        assert!(!sample("sgx", reject, Outcome::Reject, Some("expired")).passed());
+        assert!(!sample("sgx", reject, Outcome::Accept, None).passed());
+        assert!(toml::from_str::<Expected>("outcome = \"maybe\"").is_err());
+        assert!(toml::from_str::<Expected>("outcome = \"accept\"\nwhy = 1").is_err());
+
+        let report = Report {
+            samples: vec![
+                sample("kvm", accept, Outcome::Accept, None),
+                sample("sgx", reject, Outcome::Accept, None),
+            ],
+        };
+        assert!(!report.passed());
+        let text = report.to_string();
+        assert!(
+            text.contains("kvm          accept        1      0\n"),
+            "{text}"
+        );
+        assert!(
+            text.contains("sgx          reject        0      1\n"),
+            "{text}"
+        );
+        assert!(text.contains("FAIL sgx/sample: expected reject (\"signature\"), got accept\n"));
+        assert!(text.ends_with("1 of 2 samples passed\n"));
+    }
+
+    #[cfg(feature = "kvm")]
+    fn kvm() -> Vec<u8> {
+        use super::super::Kvm;
+        use attestation::crypto::{CertReqInfoExt, PrivateKeyInfoExt};
+        use const_oid::db::rfc5912::SECP_256_R_1;
+        use der::asn1::AnyRef;
+        use der::Encode;
+        use sec1::pkcs8::PrivateKeyInfo;
+        use x509::attr::Attribute;
+        use x509::ext::Extension;
+        use x509::name::RdnSequence;
+        use x509::request::{CertReqInfo, Version};
+
+        let key = PrivateKeyInfo::generate(SECP_256_R_1).unwrap();
+        let pki = PrivateKeyInfo::from_der(key.as_ref()).unwrap();
+        let ext = Extension {
+            extn_id: Kvm::OID,
+            critical: false,
+            extn_value: &[],
+        };
+        let req = ExtensionReq::from(vec![ext]).to_vec().unwrap();
+        let attribute = Attribute {
+            oid: ID_EXTENSION_REQ,
+            values: vec![AnyRef::from_der(&req).unwrap()].try_into().unwrap(),
+        };
+        CertReqInfo {
+            version: Version::V1,
+            subject: RdnSequence::default(),
+            public_key: pki.public_key().unwrap(),
+            attributes: vec![attribute].try_into().unwrap(),
+        }
+        .sign(&pki)
+        .unwrap()
+    }
+
+    #[cfg(feature = "kvm")]
+    #[tokio::test]
+    async fn run() {
+        let dir = std::env::temp_dir().join(format!("steward-corpus-{}", uuid::Uuid::new_v4()));
+        let write = |name: &str, cr: &[u8], expected: &str| {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path.with_extension("csr"), cr).unwrap();
+            std::fs::write(path.with_extension("toml"), expected).unwrap();
+        };
+        let kvm = kvm();
+        let mut tampered = kvm.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        write("kvm/fresh", &kvm, r#"outcome = "accept""#);
+        write(
+            "kvm/tampered",
+            &tampered,
+            "outcome = \"reject\"\nreason = \"signature\"",
+        );
+        write(
+            "garbage",
+            b"garbage",
+            "outcome = \"reject\"\nreason = \"malformed\"",
+        );
+
+        let state = State::generate(None, "localhost").unwrap();
+        let report = super::run(&state, &dir).await.unwrap();
+        let names: Vec<_> = report.samples.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["garbage", "kvm/fresh", "kvm/tampered"]);
+        assert!(report.passed(), "{report}");
+        assert_eq!(report.samples[0].platform, "none");
+        assert_eq!(report.samples[1].platform, "kvm");
+
+        // Samples without expectations are an error in the corpus.
+        std::fs::remove_file(dir.join("garbage.toml")).unwrap();
+        assert!(super::run(&state, &dir).await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,142 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Correlation of requests between clients, proxies and steward.
+//!
+//! Each request is identified by the `X-Request-ID` it arrives with, or else
+//! the trace ID of its W3C `traceparent`, or else a fresh UUID. The ID is
+//! attached to the request's span and audit events, and echoed back in the
+//! response along with any `traceparent`, so that a failed enrollment can be
+//! followed from the client into steward's logs.
+
+use axum::http::{HeaderMap, HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// The longest inbound request ID which is honored.
+const MAX_LEN: usize = 128;
+
+/// The ID of a request, in its extensions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+tokio::task_local! {
+    static CURRENT: String;
+}
+
+/// The ID of the request being handled, if any.
+pub fn current() -> Option<String> {
+    CURRENT.try_with(String::clone).ok()
+}
+
+/// Returns the ID given to a request by its client or proxies, if valid.
+pub fn inbound(headers: &HeaderMap) -> Option<String> {
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+
+    // Anything else could forge or break up log lines.
+    let id = header(REQUEST_ID_HEADER)
+        .filter(|id| !id.is_empty() && id.len() <= MAX_LEN)
+        .filter(|id| id.bytes().all(|b| b.is_ascii_graphic()));
+
+    id.or_else(|| header(TRACEPARENT_HEADER).and_then(trace_id))
+        .map(String::from)
+}
+
+/// Returns the trace ID of a version 00 `traceparent`.
+fn trace_id(traceparent: &str) -> Option<&str> {
+    let hex =
+        |s: &str, len| s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+    let zero = |s: &str| s.bytes().all(|b| b == b'0');
+
+    match traceparent.split('-').collect::<Vec<_>>()[..] {
+        ["00", trace, parent, flags]
+            if hex(trace, 32) && hex(parent, 16) && hex(flags, 2) && !zero(trace) =>
+        {
+            Some(trace)
+        }
+        _ => None,
+    }
+}
+
+/// Identifies each request, handles it under its ID and echoes the ID back.
+pub async fn propagate<B>(mut req: Request<B>, next: Next<B>) -> Response {
+    let id = inbound(req.headers()).unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let traceparent = req.headers().get(TRACEPARENT_HEADER).cloned();
+    req.extensions_mut().insert(RequestId(id.clone()));
+
+    let mut rsp = CURRENT.scope(id.clone(), next.run(req)).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        rsp.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    if let Some(traceparent) = traceparent {
+        rsp.headers_mut().insert(TRACEPARENT_HEADER, traceparent);
+    }
+    rsp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn request_id() {
+        let both = headers(&[
+            (REQUEST_ID_HEADER, "abc-123"),
+            (TRACEPARENT_HEADER, TRACEPARENT),
+        ]);
+        assert_eq!(inbound(&both).as_deref(), Some("abc-123"));
+
+        let trace = headers(&[(TRACEPARENT_HEADER, TRACEPARENT)]);
+        assert_eq!(
+            inbound(&trace).as_deref(),
+            Some("4bf92f3577b34da6a3ce929d0e0e4736")
+        );
+
+        assert_eq!(inbound(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn invalid() {
+        let long = "a".repeat(MAX_LEN + 1);
+        for (name, value) in [
+            (REQUEST_ID_HEADER, ""),
+            (REQUEST_ID_HEADER, "two words"),
+            (REQUEST_ID_HEADER, long.as_str()),
+            (
+                TRACEPARENT_HEADER,
+                "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            ),
+            (
+                TRACEPARENT_HEADER,
+                "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            ),
+            (
+                TRACEPARENT_HEADER,
+                "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            ),
+            (TRACEPARENT_HEADER, "00-4bf92f3577b34da6a3ce929d0e0e4736-01"),
+        ] {
+            assert_eq!(inbound(&headers(&[(name, value)])), None, "{value}");
+        }
+    }
+
+    #[tokio::test]
+    async fn scoped() {
+        assert_eq!(current(), None);
+        let id = CURRENT.scope("42".into(), async { current() }).await;
+        assert_eq!(id.as_deref(), Some("42"));
+    }
+}
@@ -0,0 +1,184 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Cross-origin resource sharing for browser-based clients.
+//!
+//! Web dashboards and WASM clients are served from other origins, so the
+//! browser only lets them call steward if it opts in with CORS headers.
+
+use super::{
+    CONTENT_TRANSFER_ENCODING, NOT_AFTER_HEADER, PLATFORM_HEADER, RENEW_AFTER_HEADER, SERIAL_HEADER,
+};
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use axum::http::{HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+/// Response headers which scripts may read.
+const EXPOSED: [&str; 5] = [
+    CONTENT_TRANSFER_ENCODING,
+    NOT_AFTER_HEADER,
+    PLATFORM_HEADER,
+    RENEW_AFTER_HEADER,
+    SERIAL_HEADER,
+];
+
+/// How long browsers may cache a preflight response.
+const MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Cors {
+    /// Origins which may call steward, or `*` for any. CORS is off when empty.
+    pub origins: Vec<String>,
+
+    /// Methods which may be used, by default `GET` and `POST`.
+    pub methods: Vec<String>,
+
+    /// Request headers which may be sent, by default `Content-Type`.
+    pub headers: Vec<String>,
+}
+
+impl Cors {
+    /// Builds the middleware, if any origins are allowed.
+    pub fn layer(&self) -> Result<Option<CorsLayer>> {
+        if self.origins.is_empty() {
+            return Ok(None);
+        }
+
+        let origins = match self.origins.iter().any(|o| o == "*") {
+            true => AllowOrigin::from(Any),
+            false => AllowOrigin::list(
+                self.origins
+                    .iter()
+                    .map(|o| {
+                        HeaderValue::from_str(o).with_context(|| format!("invalid origin `{o}`"))
+                    })
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+        };
+
+        let methods = match self.methods.as_slice() {
+            [] => vec![Method::GET, Method::POST],
+            methods => methods
+                .iter()
+                .map(|m| {
+                    Method::from_bytes(m.as_bytes())
+                        .with_context(|| format!("invalid method `{m}`"))
+                })
+                .collect::<Result<_>>()?,
+        };
+
+        let headers = match self.headers.as_slice() {
+            [] => vec![axum::http::header::CONTENT_TYPE],
+            headers => headers
+                .iter()
+                .map(|h| {
+                    HeaderName::from_bytes(h.as_bytes())
+                        .with_context(|| format!("invalid header `{h}`"))
+                })
+                .collect::<Result<_>>()?,
+        };
+
+        Ok(Some(
+            CorsLayer::new()
+                .allow_origin(origins)
+                .allow_methods(methods)
+                .allow_headers(headers)
+                .expose_headers(EXPOSED.map(HeaderName::from_static))
+                .max_age(MAX_AGE),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::body::Body;
+    use axum::http::header::{
+        ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_REQUEST_METHOD,
+        ORIGIN,
+    };
+    use axum::http::{Request, StatusCode};
+    use axum::routing::post;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    fn app(cors: Cors) -> Router {
+        let layer = cors.layer().unwrap().unwrap();
+        Router::new()
+            .route("/", post(|| async { "ok" }))
+            .layer(layer)
+    }
+
+    fn preflight(origin: &str) -> Request<Body> {
+        Request::builder()
+            .method("OPTIONS")
+            .uri("/")
+            .header(ORIGIN, origin)
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn disabled() {
+        assert!(Cors::default().layer().unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid() {
+        let cors = Cors {
+            origins: vec!["*".into()],
+            headers: vec!["bad header".into()],
+            ..Default::default()
+        };
+        assert!(cors.layer().is_err());
+    }
+
+    #[tokio::test]
+    async fn listed() {
+        let cors = Cors {
+            origins: vec!["https://dashboard.example.com".into()],
+            ..Default::default()
+        };
+
+        let response = app(cors.clone())
+            .oneshot(preflight("https://dashboard.example.com"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://dashboard.example.com"
+        );
+        let methods = headers[ACCESS_CONTROL_ALLOW_METHODS].to_str().unwrap();
+        assert!(methods.contains("POST"));
+
+        let response = app(cors)
+            .oneshot(preflight("https://evil.example.com"))
+            .await
+            .unwrap();
+        assert!(response
+            .headers()
+            .get(ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn any() {
+        let cors = Cors {
+            origins: vec!["*".into()],
+            ..Default::default()
+        };
+
+        let response = app(cors)
+            .oneshot(preflight("https://anywhere.example.com"))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    }
+}
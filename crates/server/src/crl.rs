@@ -0,0 +1,764 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Publication of revocations as certificate revocation lists.
+//!
+//! The CRL at `/crl` lists every revocation. Since it grows with issuance,
+//! CRLs may also be published under `/crl/` split into shards by serial
+//! number range, each as a base CRL reissued daily and a delta CRL listing
+//! revocations since the base (RFC 5280, section 5.2.4). Issued certificates
+//! then name the base and delta CRLs of their shard in their CRL
+//! distribution points and freshest CRL extensions.
+//!
+//! CRLs are signed by the CA's key or, so that the CA key need only ever
+//! sign certificates (`keyCertSign`) and the key which signs CRLs may live on
+//! a less protected host, by a delegated CRL signer. That is a key whose
+//! certificate the CA issued with the CA's own name as subject and the
+//! `cRLSign` key usage, so that relying parties accept its CRLs as the CA's
+//! (RFC 5280, section 6.3.3). The CRL's authority key identifier tells them
+//! which of the two keys signed it.
+//!
+//! The CRL at `/crl` is signed afresh only once revocations change or it is
+//! an hour old, so that fleets polling it can tell, by its ETag or date,
+//! when it has not changed. Each replica signs its own.
+
+use super::key::Key;
+use super::store::Revocation;
+use super::State;
+
+use std::io::BufRead;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, ensure, Context, Result};
+use attestation::crypto::{PrivateKeyInfoExt, TbsCertListExt, TbsCertificateExt};
+use axum::extract::{Extension, Path};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use const_oid::db::rfc5280::{
+    ID_CE_AUTHORITY_KEY_IDENTIFIER, ID_CE_CRL_NUMBER, ID_CE_CRL_REASONS, ID_CE_DELTA_CRL_INDICATOR,
+    ID_CE_FRESHEST_CRL, ID_CE_ISSUING_DISTRIBUTION_POINT, ID_CE_KEY_USAGE,
+};
+use der::asn1::{Ia5StringRef, OctetStringRef, UIntRef};
+use der::{Decode, Encode};
+use hyper::StatusCode;
+use sec1::pkcs8::PrivateKeyInfo;
+use sha2::{Digest, Sha256};
+use tracing::debug;
+use x509::crl::{RevokedCert, TbsCertList};
+use x509::ext::pkix::crl::dp::DistributionPoint;
+use x509::ext::pkix::crl::{CrlDistributionPoints, FreshestCrl, IssuingDistributionPoint};
+use x509::ext::pkix::name::{DistributionPointName, GeneralName};
+use x509::ext::pkix::{AuthorityKeyIdentifier, KeyUsage, KeyUsages};
+use x509::ext::Extension as X509Extension;
+use x509::time::Time;
+use x509::Certificate;
+
+pub const PKIX_CRL: &str = "application/pkix-crl";
+
+/// How long relying parties may use a CRL before fetching it again.
+pub const CRL_LIFETIME: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// How long the CRL at `/crl` is served unchanged, if revocations are.
+const REISSUE: Duration = Duration::from_secs(60 * 60);
+
+/// How often the base CRL of each shard is reissued.
+pub const BASE_PERIOD: Duration = CRL_LIFETIME;
+
+/// How long relying parties may use a delta CRL before fetching it again.
+pub const DELTA_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// A key, other than the CA's, which signs CRLs on its behalf.
+#[derive(Clone, Debug)]
+pub struct Delegate {
+    key: Key,
+    crt: Vec<u8>,
+}
+
+impl Delegate {
+    /// Reads a PEM key and certificate, checking that the certificate was
+    /// issued by `ca` to sign its CRLs and is for the key.
+    pub fn read(ca: &[u8], key: impl BufRead, mut crt: impl BufRead) -> Result<Self> {
+        let key = Key::read(key)?;
+        let crt = match rustls_pemfile::read_one(&mut crt)? {
+            Some(rustls_pemfile::Item::X509Certificate(buf)) => buf,
+            _ => return Err(anyhow!("invalid crl signer certificate")),
+        };
+
+        Self::new(ca, key, crt)
+    }
+
+    pub fn new(ca: &[u8], key: Key, crt: Vec<u8>) -> Result<Self> {
+        let ca = Certificate::from_der(ca)?;
+        let delegate = Certificate::from_der(&crt)?;
+        let tbs = ca
+            .tbs_certificate
+            .verify_crt(&delegate)
+            .context("the crl signer was not issued by the ca")?;
+        ensure!(
+            tbs.subject == ca.tbs_certificate.subject,
+            "the crl signer must be named as the ca"
+        );
+
+        let usages = tbs.extensions::<KeyUsage>(ID_CE_KEY_USAGE)?;
+        ensure!(
+            usages
+                .iter()
+                .any(|(_, usage)| usage.0.contains(KeyUsages::CRLSign)),
+            "the crl signer may not sign crls"
+        );
+
+        let pki = PrivateKeyInfo::from_der(&key)?;
+        ensure!(
+            pki.public_key()? == tbs.subject_public_key_info,
+            "the crl signer key does not match its certificate"
+        );
+
+        Ok(Self { key, crt })
+    }
+
+    /// The certificate of the delegated signer.
+    pub fn crt(&self) -> &[u8] {
+        &self.crt
+    }
+}
+
+/// Where CRLs are published for relying parties, and how they are split.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Distribution {
+    url: String,
+    shards: u32,
+}
+
+impl Distribution {
+    /// Publishes `shards` shards under `url`, the public URL of `/crl`.
+    pub fn new(url: &str, shards: u32) -> Result<Self> {
+        ensure!(shards > 0, "there must be at least one crl shard");
+        ensure!(
+            url.starts_with("http://") || url.starts_with("https://"),
+            "the crl url must be http or https"
+        );
+
+        Ok(Self {
+            url: url.trim_end_matches('/').into(),
+            shards,
+        })
+    }
+
+    pub fn shards(&self) -> u32 {
+        self.shards
+    }
+
+    /// The shard listing revocations of the certificate with `serial`.
+    ///
+    /// Serial numbers are 16 random bytes, less any leading zeros, so equal
+    /// ranges of them hold about equally many certificates.
+    pub fn shard(&self, serial: &[u8]) -> u32 {
+        let len = serial.len().min(16);
+        let mut padded = [0u8; 16];
+        padded[16 - len..].copy_from_slice(&serial[serial.len() - len..]);
+
+        let top = u64::from_be_bytes(padded[..8].try_into().unwrap());
+        ((u128::from(top) * u128::from(self.shards)) >> 64) as u32
+    }
+
+    /// The URL of the base CRL of `shard`.
+    pub fn base_url(&self, shard: u32) -> String {
+        format!("{}/{shard}", self.url)
+    }
+
+    /// The URL of the delta CRL of `shard`.
+    pub fn delta_url(&self, shard: u32) -> String {
+        format!("{}/{shard}/delta", self.url)
+    }
+
+    /// Encodes the CRL distribution points and freshest CRL extensions of
+    /// the certificate with `serial`.
+    pub fn extensions(&self, serial: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        let shard = self.shard(serial);
+        let (base, delta) = (self.base_url(shard), self.delta_url(shard));
+
+        let cdp = CrlDistributionPoints(vec![point(&base)?]).to_vec()?;
+        let freshest = FreshestCrl(vec![point(&delta)?]).to_vec()?;
+        Ok((cdp, freshest))
+    }
+
+    /// The issuing distribution point of the CRLs of `shard`, which is the
+    /// same for its base and delta CRLs.
+    fn idp(&self, shard: u32) -> Result<Vec<u8>> {
+        let url = self.base_url(shard);
+        let uri = GeneralName::UniformResourceIdentifier(Ia5StringRef::new(&url)?);
+
+        Ok(IssuingDistributionPoint {
+            distribution_point: Some(DistributionPointName::FullName(vec![uri])),
+            only_contains_user_certs: false,
+            only_contains_ca_certs: false,
+            only_some_reasons: None,
+            indirect_crl: false,
+            only_contains_attribute_certs: false,
+        }
+        .to_vec()?)
+    }
+
+    /// Signs with `key` the base CRL of `shard` in force at `now`.
+    ///
+    /// It lists the revocations before the start of the current period, so
+    /// that all replicas issue the same one. CRL numbers are shared with the
+    /// deltas: twice the start of the period for the base, and twice the
+    /// time of issuance plus one for each delta, which keeps them increasing.
+    pub fn base(
+        &self,
+        ca: &[u8],
+        key: &[u8],
+        revocations: &[Revocation],
+        shard: u32,
+        now: SystemTime,
+    ) -> Result<Vec<u8>> {
+        let start = period(now)?;
+        let idp = self.idp(shard)?;
+        let freshest = FreshestCrl(vec![point(&self.delta_url(shard))?]).to_vec()?;
+
+        let revoked = revocations
+            .iter()
+            .filter(|r| self.shard(&r.serial) == shard && r.revoked_at < start);
+        issue(
+            ca,
+            key,
+            revoked,
+            Scope {
+                number: 2 * secs(start)?,
+                this_update: start,
+                next_update: start + BASE_PERIOD,
+                extensions: vec![
+                    X509Extension {
+                        extn_id: ID_CE_ISSUING_DISTRIBUTION_POINT,
+                        critical: true,
+                        extn_value: &idp,
+                    },
+                    X509Extension {
+                        extn_id: ID_CE_FRESHEST_CRL,
+                        critical: false,
+                        extn_value: &freshest,
+                    },
+                ],
+            },
+        )
+    }
+
+    /// Signs with `key` the delta CRL of `shard` at `now`, listing the
+    /// revocations since its base CRL.
+    pub fn delta(
+        &self,
+        ca: &[u8],
+        key: &[u8],
+        revocations: &[Revocation],
+        shard: u32,
+        now: SystemTime,
+    ) -> Result<Vec<u8>> {
+        let start = period(now)?;
+        let idp = self.idp(shard)?;
+        let base = (2 * secs(start)?).to_be_bytes();
+        let base = UIntRef::new(&base)?.to_vec()?;
+
+        let revoked = revocations
+            .iter()
+            .filter(|r| self.shard(&r.serial) == shard && r.revoked_at >= start);
+        issue(
+            ca,
+            key,
+            revoked,
+            Scope {
+                number: 2 * secs(now)? + 1,
+                this_update: now,
+                next_update: now + DELTA_LIFETIME,
+                extensions: vec![
+                    X509Extension {
+                        extn_id: ID_CE_ISSUING_DISTRIBUTION_POINT,
+                        critical: true,
+                        extn_value: &idp,
+                    },
+                    X509Extension {
+                        extn_id: ID_CE_DELTA_CRL_INDICATOR,
+                        critical: true,
+                        extn_value: &base,
+                    },
+                ],
+            },
+        )
+    }
+}
+
+/// A distribution point at `url`.
+fn point(url: &str) -> Result<DistributionPoint<'_>> {
+    let uri = GeneralName::UniformResourceIdentifier(Ia5StringRef::new(url)?);
+    Ok(DistributionPoint {
+        distribution_point: Some(DistributionPointName::FullName(vec![uri])),
+        reasons: None,
+        crl_issuer: None,
+    })
+}
+
+fn secs(time: SystemTime) -> Result<u64> {
+    Ok(time.duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// The start of the base CRL period containing `now`.
+fn period(now: SystemTime) -> Result<SystemTime> {
+    let period = BASE_PERIOD.as_secs();
+    Ok(UNIX_EPOCH + Duration::from_secs(secs(now)? / period * period))
+}
+
+/// The key identifier of `pki` (RFC 7093, method 1).
+fn key_id(pki: &PrivateKeyInfo<'_>) -> Result<[u8; 20]> {
+    let spki = pki.public_key()?;
+    let digest = Sha256::digest(spki.subject_public_key);
+    let mut id = [0; 20];
+    id.copy_from_slice(&digest[..20]);
+    Ok(id)
+}
+
+/// A CRL to issue, apart from its revocations.
+struct Scope<'a> {
+    number: u64,
+    this_update: SystemTime,
+    next_update: SystemTime,
+
+    /// Extensions besides the authority key identifier and CRL number.
+    extensions: Vec<X509Extension<'a>>,
+}
+
+/// Signs with `key` a CRL of `revocations` of certificates issued by `ca`.
+fn issue<'r>(
+    ca: &[u8],
+    key: &[u8],
+    revocations: impl IntoIterator<Item = &'r Revocation>,
+    scope: Scope<'_>,
+) -> Result<Vec<u8>> {
+    let ca = Certificate::from_der(ca)?;
+    let pki = PrivateKeyInfo::from_der(key)?;
+
+    let revocations: Vec<_> = revocations.into_iter().collect();
+    let reasons: Vec<_> = revocations
+        .iter()
+        .map(|r| r.reason.map(|reason| [0x0a, 0x01, reason])) // ENUMERATED
+        .collect();
+    let mut revoked = Vec::with_capacity(revocations.len());
+    for (revocation, reason) in revocations.iter().zip(&reasons) {
+        let extensions = reason.as_ref().map(|reason| {
+            vec![X509Extension {
+                extn_id: ID_CE_CRL_REASONS,
+                critical: false,
+                extn_value: reason,
+            }]
+        });
+        revoked.push(RevokedCert {
+            serial_number: UIntRef::new(&revocation.serial)?,
+            revocation_date: Time::try_from(revocation.revoked_at)?,
+            crl_entry_extensions: extensions,
+        });
+    }
+
+    let number = scope.number.to_be_bytes();
+    let number = UIntRef::new(&number)?.to_vec()?;
+    let id = key_id(&pki)?;
+    let aki = AuthorityKeyIdentifier {
+        key_identifier: Some(OctetStringRef::new(&id)?),
+        authority_cert_issuer: None,
+        authority_cert_serial_number: None,
+    }
+    .to_vec()?;
+
+    let mut extensions = vec![
+        X509Extension {
+            extn_id: ID_CE_AUTHORITY_KEY_IDENTIFIER,
+            critical: false,
+            extn_value: &aki,
+        },
+        X509Extension {
+            extn_id: ID_CE_CRL_NUMBER,
+            critical: false,
+            extn_value: &number,
+        },
+    ];
+    extensions.extend(scope.extensions);
+
+    TbsCertList {
+        version: x509::Version::V2,
+        signature: pki.signs_with()?,
+        issuer: ca.tbs_certificate.subject,
+        this_update: Time::try_from(scope.this_update)?,
+        next_update: Some(Time::try_from(scope.next_update)?),
+        revoked_certificates: match revoked.is_empty() {
+            true => None,
+            false => Some(revoked),
+        },
+        crl_extensions: Some(extensions),
+    }
+    .sign(&pki)
+}
+
+/// Signs with `key` a full CRL of `revocations` of certificates issued by
+/// `ca`.
+///
+/// CRL numbers must increase with each CRL issued, including by other
+/// replicas, so the time of issuance is used.
+pub fn sign(ca: &[u8], key: &[u8], revocations: &[Revocation], now: SystemTime) -> Result<Vec<u8>> {
+    issue(
+        ca,
+        key,
+        revocations,
+        Scope {
+            number: secs(now)?,
+            this_update: now,
+            next_update: now + CRL_LIFETIME,
+            extensions: vec![],
+        },
+    )
+}
+
+fn internal(e: anyhow::Error) -> StatusCode {
+    debug!("crl failure: {e}");
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+/// The key signing CRLs: the delegated CRL signer's if any, else the CA's.
+fn signer(state: &State) -> &Key {
+    match &state.crl_signer {
+        Some(delegate) => &delegate.key,
+        None => &state.key,
+    }
+}
+
+/// A signed full CRL, and what it was signed for.
+#[derive(Clone, Debug)]
+struct Signed {
+    revocations: Vec<Revocation>,
+    at: SystemTime,
+    der: Arc<Vec<u8>>,
+}
+
+/// The full CRL last signed, reused until revocations change or it is due
+/// to be reissued.
+#[derive(Debug, Default)]
+pub struct Published(Mutex<Option<Signed>>);
+
+impl Published {
+    /// The full CRL of `revocations`, signed with `key` for `ca`, and when.
+    fn get(
+        &self,
+        ca: &[u8],
+        key: &[u8],
+        revocations: Vec<Revocation>,
+        now: SystemTime,
+    ) -> Result<(Arc<Vec<u8>>, SystemTime)> {
+        let mut signed = self.0.lock().unwrap();
+        if let Some(signed) = &*signed {
+            let age = now.duration_since(signed.at).ok();
+            if signed.revocations == revocations && age.map_or(false, |age| age < REISSUE) {
+                return Ok((signed.der.clone(), signed.at));
+            }
+        }
+
+        let der = Arc::new(sign(ca, key, &revocations, now)?);
+        *signed = Some(Signed {
+            revocations,
+            at: now,
+            der: der.clone(),
+        });
+        Ok((der, now))
+    }
+}
+
+/// The current full CRL, as served at `/crl`, and when it was signed.
+pub(crate) async fn current(state: &State) -> Result<(Arc<Vec<u8>>, SystemTime)> {
+    let now = state.clock.now();
+    let revocations = state.store.revocations(now).await?;
+    state
+        .crl_published
+        .get(&state.crt, signer(state), revocations, now)
+}
+
+/// Returns the current full CRL, unless the client's copy is current.
+pub async fn crl(
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Response, StatusCode> {
+    let (crl, at) = current(&state).await.map_err(internal)?;
+    super::conditional(&headers, PKIX_CRL, crl.to_vec(), at)
+}
+
+/// Returns the current base or delta CRL of `shard`.
+async fn sharded(state: &State, shard: u32, delta: bool) -> Result<impl IntoResponse, StatusCode> {
+    let distribution = state
+        .crl_distribution
+        .as_ref()
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if shard >= distribution.shards() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let (key, now) = (signer(state), state.clock.now());
+    let revocations = state.store.revocations(now).await.map_err(internal)?;
+    let crl = match delta {
+        false => distribution.base(&state.crt, key, &revocations, shard, now),
+        true => distribution.delta(&state.crt, key, &revocations, shard, now),
+    }
+    .map_err(internal)?;
+    Ok(([(CONTENT_TYPE, PKIX_CRL)], crl))
+}
+
+/// Returns the current base CRL of a shard.
+pub async fn base(
+    Path(shard): Path<u32>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    sharded(&state, shard, false).await
+}
+
+/// Returns the current delta CRL of a shard.
+pub async fn delta(
+    Path(shard): Path<u32>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<impl IntoResponse, StatusCode> {
+    sharded(&state, shard, true).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use attestation::crypto::SubjectPublicKeyInfoExt;
+    use const_oid::db::rfc5912::SECP_256_R_1;
+    use der::asn1::GeneralizedTime;
+    use x509::crl::CertificateList;
+    use x509::time::Validity;
+    use x509::TbsCertificate;
+
+    /// Issues a certificate for a fresh key named as the CA, with `usage`.
+    fn delegate(state: &State, usage: KeyUsages) -> (Key, Vec<u8>) {
+        let ca = Certificate::from_der(&state.crt).unwrap();
+        let ca_pki = PrivateKeyInfo::from_der(&state.key).unwrap();
+
+        let key = Key::try_from(PrivateKeyInfo::generate(SECP_256_R_1).unwrap()).unwrap();
+        let pki = PrivateKeyInfo::from_der(&key).unwrap();
+
+        let now = SystemTime::now();
+        let ku = KeyUsage(usage.into()).to_vec().unwrap();
+        let crt = TbsCertificate {
+            version: x509::Version::V3,
+            serial_number: UIntRef::new(&[7]).unwrap(),
+            signature: ca_pki.signs_with().unwrap(),
+            issuer: ca.tbs_certificate.subject.clone(),
+            validity: Validity {
+                not_before: Time::GeneralTime(GeneralizedTime::from_system_time(now).unwrap()),
+                not_after: Time::try_from(now + CRL_LIFETIME).unwrap(),
+            },
+            subject: ca.tbs_certificate.subject.clone(),
+            subject_public_key_info: pki.public_key().unwrap(),
+            issuer_unique_id: None,
+            subject_unique_id: None,
+            extensions: Some(vec![X509Extension {
+                extn_id: ID_CE_KEY_USAGE,
+                critical: true,
+                extn_value: &ku,
+            }]),
+        }
+        .sign(&ca_pki)
+        .unwrap();
+
+        (key, crt)
+    }
+
+    fn revocations() -> Vec<Revocation> {
+        vec![Revocation {
+            serial: vec![1, 2, 3],
+            revoked_at: SystemTime::now(),
+            reason: Some(1),
+        }]
+    }
+
+    #[test]
+    fn published() {
+        let state = State::generate(None, "localhost").unwrap();
+        let published = Published::default();
+        let now = SystemTime::now();
+        let get = |revocations, now| {
+            published
+                .get(&state.crt, &state.key, revocations, now)
+                .unwrap()
+        };
+
+        // The CRL is reused while revocations are unchanged...
+        let (first, at) = get(vec![], now);
+        assert_eq!(at, now);
+        let later = now + Duration::from_secs(60);
+        assert_eq!(get(vec![], later), (first.clone(), now));
+
+        // ...until they change, or it is due to be reissued.
+        let (second, at) = get(revocations(), later);
+        assert_ne!(second, first);
+        assert_eq!(at, later);
+        let (third, at) = get(revocations(), later + REISSUE);
+        assert_ne!(third, second);
+        assert_eq!(at, later + REISSUE);
+    }
+
+    #[test]
+    fn delegated() {
+        let state = State::generate(None, "localhost").unwrap();
+        let (key, crt) = delegate(&state, KeyUsages::CRLSign);
+        let delegate = Delegate::new(&state.crt, key.clone(), crt).unwrap();
+
+        let der = sign(&state.crt, &key, &revocations(), SystemTime::now()).unwrap();
+        let crl = CertificateList::from_der(&der).unwrap();
+        let ca = Certificate::from_der(&state.crt).unwrap();
+        assert_eq!(crl.tbs_cert_list.issuer, ca.tbs_certificate.subject);
+        let revoked = crl.tbs_cert_list.revoked_certificates.as_ref().unwrap();
+        assert_eq!(revoked[0].serial_number.as_bytes(), [1, 2, 3]);
+
+        // Only the delegate's key verifies the CRL.
+        let signer = Certificate::from_der(delegate.crt()).unwrap();
+        let body = crl.tbs_cert_list.to_vec().unwrap();
+        let signature = crl.signature.as_bytes().unwrap();
+        let spki = signer.tbs_certificate.subject_public_key_info;
+        spki.verify(&body, crl.signature_algorithm, signature)
+            .unwrap();
+        let spki = ca.tbs_certificate.subject_public_key_info;
+        assert!(spki
+            .verify(&body, crl.signature_algorithm, signature)
+            .is_err());
+    }
+
+    #[test]
+    fn rejected() {
+        let state = State::generate(None, "localhost").unwrap();
+
+        // Not allowed to sign CRLs.
+        let (key, crt) = delegate(&state, KeyUsages::DigitalSignature);
+        assert!(Delegate::new(&state.crt, key, crt).is_err());
+
+        // Not the key of the certificate.
+        let (_, crt) = delegate(&state, KeyUsages::CRLSign);
+        let (key, _) = delegate(&state, KeyUsages::CRLSign);
+        assert!(Delegate::new(&state.crt, key, crt).is_err());
+
+        // Not issued by this CA.
+        let other = State::generate(None, "localhost").unwrap();
+        let (key, crt) = delegate(&other, KeyUsages::CRLSign);
+        assert!(Delegate::new(&state.crt, key, crt).is_err());
+    }
+
+    #[test]
+    fn unrevoked() {
+        let state = State::generate(None, "localhost").unwrap();
+        let now = SystemTime::now();
+        let der = sign(&state.crt, &state.key, &[], now).unwrap();
+        let crl = CertificateList::from_der(&der).unwrap();
+        assert!(crl.tbs_cert_list.revoked_certificates.is_none());
+
+        // Numbered by the time of issuance.
+        let extensions = crl.tbs_cert_list.crl_extensions.unwrap();
+        let number = extensions
+            .iter()
+            .find(|ext| ext.extn_id == ID_CE_CRL_NUMBER)
+            .unwrap();
+        let number = UIntRef::from_der(number.extn_value).unwrap();
+        let secs = now.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert_eq!(
+            number.as_bytes(),
+            UIntRef::new(&secs.to_be_bytes()).unwrap().as_bytes()
+        );
+    }
+
+    #[test]
+    fn sharded() {
+        let distribution = Distribution::new("https://ca.example.com/crl/", 4).unwrap();
+        assert_eq!(distribution.shard(&[0x3f; 16]), 0);
+        assert_eq!(distribution.shard(&[0x40; 16]), 1);
+        assert_eq!(distribution.shard(&[0xff; 16]), 3);
+
+        // Serial numbers lose their leading zeros.
+        assert_eq!(distribution.shard(&[0xff; 15]), 0);
+
+        assert_eq!(distribution.base_url(2), "https://ca.example.com/crl/2");
+        let (cdp, freshest) = distribution.extensions(&[0xff; 16]).unwrap();
+        let url = |points: &[DistributionPoint<'_>]| match &points[0].distribution_point {
+            Some(DistributionPointName::FullName(names)) => match &names[0] {
+                GeneralName::UniformResourceIdentifier(uri) => uri.to_string(),
+                name => panic!("unexpected name: {name:?}"),
+            },
+            name => panic!("unexpected point: {name:?}"),
+        };
+        let cdp = CrlDistributionPoints::from_der(&cdp).unwrap();
+        assert_eq!(url(&cdp.0), "https://ca.example.com/crl/3");
+        let freshest = FreshestCrl::from_der(&freshest).unwrap();
+        assert_eq!(url(&freshest.0), "https://ca.example.com/crl/3/delta");
+
+        assert!(Distribution::new("https://ca.example.com/crl", 0).is_err());
+        assert!(Distribution::new("ldap://ca.example.com/crl", 1).is_err());
+    }
+
+    #[test]
+    fn base_and_delta() {
+        let state = State::generate(None, "localhost").unwrap();
+        let distribution = Distribution::new("https://ca.example.com/crl", 1).unwrap();
+
+        let start = UNIX_EPOCH + BASE_PERIOD * 20_000;
+        let now = start + Duration::from_secs(60 * 60);
+        let revocations = vec![
+            Revocation {
+                serial: vec![1],
+                revoked_at: start - Duration::from_secs(1),
+                reason: None,
+            },
+            Revocation {
+                serial: vec![2],
+                revoked_at: start + Duration::from_secs(1),
+                reason: None,
+            },
+        ];
+
+        let extension = |crl: &CertificateList<'_>, oid| {
+            let extensions = crl.tbs_cert_list.crl_extensions.as_ref().unwrap();
+            let ext = extensions.iter().find(|ext| ext.extn_id == oid).unwrap();
+            (ext.critical, ext.extn_value.to_vec())
+        };
+        let serials = |crl: &CertificateList<'_>| -> Vec<Vec<u8>> {
+            let revoked = crl.tbs_cert_list.revoked_certificates.iter().flatten();
+            revoked
+                .map(|r| r.serial_number.as_bytes().to_vec())
+                .collect()
+        };
+
+        // The base lists revocations before its period, whenever issued.
+        let der = distribution
+            .base(&state.crt, &state.key, &revocations, 0, now)
+            .unwrap();
+        let base = CertificateList::from_der(&der).unwrap();
+        assert_eq!(serials(&base), [vec![1]]);
+        assert_eq!(base.tbs_cert_list.this_update.to_system_time(), start);
+        let (critical, number) = extension(&base, ID_CE_CRL_NUMBER);
+        assert!(!critical);
+        assert!(extension(&base, ID_CE_ISSUING_DISTRIBUTION_POINT).0);
+        extension(&base, ID_CE_FRESHEST_CRL);
+
+        // The delta lists the rest, and names its base.
+        let der = distribution
+            .delta(&state.crt, &state.key, &revocations, 0, now)
+            .unwrap();
+        let delta = CertificateList::from_der(&der).unwrap();
+        assert_eq!(serials(&delta), [vec![2]]);
+        let (critical, indicator) = extension(&delta, ID_CE_DELTA_CRL_INDICATOR);
+        assert!(critical);
+        assert_eq!(indicator, number);
+        assert_eq!(
+            extension(&delta, ID_CE_ISSUING_DISTRIBUTION_POINT),
+            extension(&base, ID_CE_ISSUING_DISTRIBUTION_POINT)
+        );
+
+        let number = |der: &[u8]| UIntRef::from_der(der).unwrap().as_bytes().to_vec();
+        let (_, delta_number) = extension(&delta, ID_CE_CRL_NUMBER);
+        assert!(number(&delta_number) > number(&indicator));
+    }
+}
@@ -0,0 +1,502 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Trust anchors of platforms, uploaded at runtime.
+//!
+//! Verifiers are built with the roots of the platform vendors, such as the
+//! Intel SGX Root CA or AMD's ARKs. Operators may replace those of a platform,
+//! to rotate to a new root or to trust a test fleet, by uploading anchors to
+//! `POST /admin/endorsements/{platform}`. They are kept in the endorsement
+//! store and swapped into the verifiers without a restart:
+//!
+//! ```json
+//! {"anchors": ["<base64 DER>"]}
+//! ```
+//!
+//! SGX anchors are self-signed root certificates; SNP anchors are `PkiPath`s
+//! of a self-signed ARK followed by the ASKs it signed. Uploading no anchors
+//! reverts to the built-in roots. `GET` on the same path lists the uploaded
+//! anchors, to auditors too.
+//!
+//! Other replicas pick up uploads within [`REFRESH`]. Cached appraisals are
+//! keyed by the anchors and CRLs in use, so none made under the previous
+//! anchors is reused once a replica has picked up the new ones.
+//! Only the platforms in [`PLATFORMS`] take anchors, since no others verify
+//! evidence against built-in roots.
+//!
+//! Relying parties and auditors may confirm which roots are trusted, whether
+//! built in, uploaded or configured by the policy, at `GET /v1/trust-anchors`.
+
+use super::store::AuditRecord;
+use super::{admin, State};
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+#[cfg(any(feature = "sgx", feature = "snp"))]
+use anyhow::anyhow;
+#[cfg(feature = "snp")]
+use anyhow::ensure;
+use anyhow::Result;
+#[cfg(any(feature = "sgx", feature = "snp"))]
+use attestation::crypto::TbsCertificateExt;
+use axum::body::Bytes;
+use axum::extract::{Extension, Path, TypedHeader};
+use axum::headers::authorization::{Authorization, Bearer};
+use axum::Json;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+#[cfg(any(feature = "sgx", feature = "snp", feature = "se", feature = "cove"))]
+use der::Decode;
+use der::Encode;
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tracing::{debug, info};
+use x509::Certificate;
+#[cfg(feature = "snp")]
+use x509::PkiPath;
+
+/// The platforms which take uploaded trust anchors.
+pub const PLATFORMS: &[&str] = &[
+    #[cfg(feature = "sgx")]
+    "sgx",
+    #[cfg(feature = "snp")]
+    "snp",
+];
+
+/// How long uploaded anchors are used before they are read again.
+pub const REFRESH: Duration = Duration::from_secs(60);
+
+/// The uploaded anchors of each platform, as last read from the store.
+#[derive(Debug, Default)]
+pub struct Anchors(Mutex<BTreeMap<String, (Instant, Arc<Vec<Vec<u8>>>)>>);
+
+impl Anchors {
+    #[cfg(any(feature = "sgx", feature = "snp"))]
+    fn cached(&self, platform: &str) -> Option<Arc<Vec<Vec<u8>>>> {
+        let cache = self.0.lock().unwrap();
+        let (at, anchors) = cache.get(platform)?;
+        (at.elapsed() < REFRESH).then(|| anchors.clone())
+    }
+
+    fn set(&self, platform: &str, anchors: Arc<Vec<Vec<u8>>>) {
+        let mut cache = self.0.lock().unwrap();
+        cache.insert(platform.into(), (Instant::now(), anchors));
+    }
+}
+
+/// The anchors uploaded for `platform`, empty if its built-in roots apply.
+#[cfg(any(feature = "sgx", feature = "snp"))]
+pub(crate) async fn anchors(state: &State, platform: &str) -> Result<Arc<Vec<Vec<u8>>>> {
+    if let Some(anchors) = state.anchors.cached(platform) {
+        return Ok(anchors);
+    }
+
+    let anchors = Arc::new(state.store.anchors(platform).await?);
+    state.anchors.set(platform, anchors.clone());
+    Ok(anchors)
+}
+
+/// Checks that `der` is a trust anchor of `platform`.
+#[cfg_attr(not(any(feature = "sgx", feature = "snp")), allow(unused_variables))]
+fn validate(platform: &str, der: &[u8]) -> Result<()> {
+    match platform {
+        #[cfg(feature = "sgx")]
+        "sgx" => {
+            let root = Certificate::from_der(der)?;
+            root.tbs_certificate
+                .verify_crt(&root)
+                .map_err(|e| anyhow!("sgx anchor is not a self-signed root: {e}"))?;
+        }
+
+        #[cfg(feature = "snp")]
+        "snp" => {
+            let path = PkiPath::from_der(der)?;
+            let (ark, asks) = path
+                .split_first()
+                .ok_or_else(|| anyhow!("snp anchor is empty"))?;
+            ensure!(!asks.is_empty(), "snp anchor lacks an ask");
+            let ark = ark
+                .tbs_certificate
+                .verify_crt(ark)
+                .map_err(|e| anyhow!("snp anchor is not rooted in a self-signed ark: {e}"))?;
+            for ask in asks {
+                ark.verify_crt(ask)
+                    .map_err(|e| anyhow!("snp anchor has an ask not signed by its ark: {e}"))?;
+            }
+        }
+
+        _ => unreachable!("{platform} takes no anchors"),
+    }
+    Ok(())
+}
+
+/// Where a trust anchor comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Source {
+    /// Compiled into steward.
+    BuiltIn,
+
+    /// Uploaded at `POST /admin/endorsements/{platform}`.
+    Uploaded,
+
+    /// Listed in the policy, for platforms without built-in roots.
+    Policy,
+}
+
+/// A root which evidence is verified against.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TrustAnchor {
+    pub platform: &'static str,
+
+    /// The subject of the root certificate.
+    pub subject: String,
+
+    /// The hex SHA-256 digest of the DER root certificate.
+    pub fingerprint: String,
+
+    pub source: Source,
+
+    /// Seconds since the Unix epoch.
+    pub expires_at: u64,
+}
+
+impl TrustAnchor {
+    fn new(platform: &'static str, source: Source, root: &Certificate<'_>) -> Result<Self> {
+        let tbs = &root.tbs_certificate;
+        let expires_at = tbs
+            .validity
+            .not_after
+            .to_system_time()
+            .duration_since(UNIX_EPOCH)?
+            .as_secs();
+        Ok(Self {
+            platform,
+            subject: tbs.subject.to_string(),
+            fingerprint: hex::encode(Sha256::digest(root.to_vec()?)),
+            source,
+            expires_at,
+        })
+    }
+}
+
+/// The trust anchors which verifiers currently use.
+#[cfg_attr(
+    not(any(feature = "sgx", feature = "snp", feature = "se", feature = "cove")),
+    allow(unused_variables, unused_mut)
+)]
+pub(crate) async fn active(state: &State) -> Result<Vec<TrustAnchor>> {
+    let mut active = Vec::new();
+
+    #[cfg(feature = "sgx")]
+    {
+        let uploaded = anchors(state, "sgx").await?;
+        if uploaded.is_empty() {
+            let root = Certificate::from_der(attestation::sgx::Sgx::ROOT)?;
+            active.push(TrustAnchor::new("sgx", Source::BuiltIn, &root)?);
+        }
+        for der in uploaded.iter() {
+            let root = Certificate::from_der(der)?;
+            active.push(TrustAnchor::new("sgx", Source::Uploaded, &root)?);
+        }
+    }
+
+    // The ARK of each path is the root; the ASKs it carries are pinned too.
+    #[cfg(feature = "snp")]
+    {
+        let uploaded = anchors(state, "snp").await?;
+        let (source, paths): (_, Vec<&[u8]>) = match uploaded.is_empty() {
+            true => (Source::BuiltIn, attestation::snp::Snp::ROOTS.to_vec()),
+            false => (
+                Source::Uploaded,
+                uploaded.iter().map(Vec::as_slice).collect(),
+            ),
+        };
+        for der in paths {
+            let path = PkiPath::from_der(der)?;
+            let ark = path.first().ok_or_else(|| anyhow!("snp anchor is empty"))?;
+            active.push(TrustAnchor::new("snp", source, ark)?);
+        }
+    }
+
+    #[cfg(feature = "se")]
+    if let Some(se) = &state.policy().config.se {
+        for der in &se.ca {
+            let root = Certificate::from_der(der)?;
+            active.push(TrustAnchor::new("se", Source::Policy, &root)?);
+        }
+    }
+
+    #[cfg(feature = "cove")]
+    if let Some(cove) = &state.policy().config.cove {
+        for der in &cove.roots {
+            let root = Certificate::from_der(der)?;
+            active.push(TrustAnchor::new("cove", Source::Policy, &root)?);
+        }
+    }
+
+    Ok(active)
+}
+
+/// The response to `GET /v1/trust-anchors`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TrustAnchors {
+    pub anchors: Vec<TrustAnchor>,
+}
+
+/// Lists the trust anchors which verifiers currently use.
+pub async fn trust_anchors(
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Json<TrustAnchors>, StatusCode> {
+    let anchors = active(&state).await.map_err(|e| {
+        debug!("failed to list trust anchors: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(TrustAnchors { anchors }))
+}
+
+/// The body of `POST /admin/endorsements/{platform}`, and the response to
+/// `GET`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Upload {
+    /// Base64 encoded DER anchors.
+    pub anchors: Vec<String>,
+}
+
+/// Lists the anchors uploaded for a platform.
+pub async fn endorsements(
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<Arc<State>>,
+    Path(platform): Path<String>,
+) -> Result<Json<Upload>, StatusCode> {
+    admin::authorize(&state, auth, true).await?;
+    if !PLATFORMS.contains(&platform.as_str()) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let anchors = state.store.anchors(&platform).await.map_err(|e| {
+        debug!("failed to read {platform} anchors: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let anchors = anchors.iter().map(|der| BASE64.encode(der)).collect();
+    Ok(Json(Upload { anchors }))
+}
+
+/// Replaces the anchors of a platform.
+pub async fn upload(
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<Arc<State>>,
+    Path(platform): Path<String>,
+    body: Bytes,
+) -> Result<StatusCode, StatusCode> {
+    admin::authorize(&state, auth, false).await?;
+    if !PLATFORMS.contains(&platform.as_str()) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let upload: Upload = serde_json::from_slice(&body).map_err(|e| {
+        debug!("invalid anchor upload: {e}");
+        StatusCode::BAD_REQUEST
+    })?;
+    let mut anchors = Vec::with_capacity(upload.anchors.len());
+    for anchor in &upload.anchors {
+        let der = BASE64.decode(anchor).map_err(|e| {
+            debug!("invalid {platform} anchor: {e}");
+            StatusCode::BAD_REQUEST
+        })?;
+        validate(&platform, &der).map_err(|e| {
+            debug!("invalid {platform} anchor: {e}");
+            StatusCode::BAD_REQUEST
+        })?;
+        anchors.push(der);
+    }
+
+    let failed = |e: anyhow::Error| {
+        debug!("failed to replace {platform} anchors: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    state
+        .store
+        .set_anchors(&platform, &anchors)
+        .await
+        .map_err(failed)?;
+    let digests: Vec<String> = anchors
+        .iter()
+        .map(|der| hex::encode(Sha256::digest(der)))
+        .collect();
+    state.anchors.set(&platform, Arc::new(anchors));
+
+    let detail = match digests.is_empty() {
+        true => format!("reverted {platform} to its built-in trust anchors"),
+        false => format!(
+            "replaced {platform} trust anchors with {}",
+            digests.join(", ")
+        ),
+    };
+    let record = AuditRecord {
+        at: state.clock.now(),
+        event: "endorsement".into(),
+        detail,
+    };
+    state.store.audit(&record).await.map_err(failed)?;
+    state.notify(
+        &record.event,
+        json!({ "platform": platform, "anchors": digests }),
+    );
+    info!("{}", record.detail);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(all(test, feature = "snp"))]
+mod tests {
+    use super::super::admin::Auditor;
+    use super::super::{operations, public};
+    use super::*;
+
+    use std::time::UNIX_EPOCH;
+
+    use attestation::snp::Snp;
+    use http::header::AUTHORIZATION;
+    use http::{Method, Request};
+    use hyper::Body;
+    use tower::ServiceExt; // for `app.oneshot()`
+    use x509::request::{CertReq, ExtensionReq};
+
+    const MILAN: &[u8] = include_bytes!("../../attestation/src/snp/milan.pkipath");
+    const GENOA: &[u8] = include_bytes!("../../attestation/src/snp/genoa.pkipath");
+    const MILAN_CSR: &[u8] = include_bytes!("../../attestation/src/snp/milan.signed.crl.csr");
+
+    async fn call(state: &State, method: Method, uri: &str, token: &str, body: Body) -> StatusCode {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(AUTHORIZATION, format!("Bearer {token}"))
+            .body(body)
+            .unwrap();
+        let response = operations(state.clone()).oneshot(request).await.unwrap();
+        response.status()
+    }
+
+    fn body(anchors: &[&[u8]]) -> Body {
+        let anchors = anchors.iter().map(|der| BASE64.encode(der)).collect();
+        Body::from(serde_json::to_vec(&Upload { anchors }).unwrap())
+    }
+
+    /// Verifies the canned Milan evidence as the SNP verifier would.
+    async fn verify(state: &State) -> Result<()> {
+        let csr = CertReq::from_der(MILAN_CSR).unwrap();
+        let ereq: ExtensionReq<'_> = csr
+            .info
+            .attributes
+            .iter()
+            .next()
+            .unwrap()
+            .values
+            .iter()
+            .next()
+            .unwrap()
+            .decode_into()
+            .unwrap();
+        let ext = &Vec::from(ereq)[0];
+        let config = super::super::snp_config(state, None).await.unwrap();
+        Snp::default().verify(&csr.info, ext, config.as_deref(), false)
+    }
+
+    #[tokio::test]
+    async fn rotate() {
+        let state = State::generate(None, "localhost")
+            .unwrap()
+            .with_auditor(Auditor::new("auditor").unwrap());
+        let ttl = Duration::from_secs(60);
+        let expires_at = (state.clock.now() + ttl)
+            .duration_since(UNIX_EPOCH)
+            .unwrap();
+        let expires_at = expires_at.as_secs().to_be_bytes();
+        state
+            .shared
+            .put(&admin::key("operator"), &expires_at, ttl)
+            .await
+            .unwrap();
+        verify(&state).await.unwrap();
+
+        // Only operators may upload anchors, and only valid ones.
+        let uri = "/admin/endorsements/snp";
+        let status = call(&state, Method::POST, uri, "auditor", body(&[GENOA])).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let status = call(
+            &state,
+            Method::POST,
+            uri,
+            "operator",
+            body(&[state.crt.as_slice()]),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let unknown = "/admin/endorsements/nitro";
+        let status = call(&state, Method::POST, unknown, "operator", body(&[GENOA])).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Evidence from a platform whose root is no longer trusted is refused
+        // at once, and accepted again once it is.
+        let status = call(&state, Method::POST, uri, "operator", body(&[GENOA])).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(verify(&state).await.is_err());
+        assert_eq!(*anchors(&state, "snp").await.unwrap(), vec![GENOA.to_vec()]);
+
+        let status = call(&state, Method::POST, uri, "operator", body(&[GENOA, MILAN])).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        verify(&state).await.unwrap();
+
+        // Auditors may list the anchors, which survive the cache.
+        let status = call(&state, Method::GET, uri, "auditor", Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(state.store.anchors("snp").await.unwrap().len(), 2);
+
+        // Uploading none reverts to the built-in roots.
+        let status = call(&state, Method::POST, uri, "operator", body(&[])).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(super::super::snp_config(&state, None)
+            .await
+            .unwrap()
+            .is_none());
+        verify(&state).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn report() {
+        let state = State::generate(None, "localhost").unwrap();
+        let request = Request::get("/v1/trust-anchors")
+            .body(Body::empty())
+            .unwrap();
+        let response = public(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let snp: Vec<_> = report["anchors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|anchor| anchor["platform"] == "snp")
+            .collect();
+        assert_eq!(snp.len(), attestation::snp::Snp::ROOTS.len());
+        assert!(snp.iter().all(|anchor| anchor["source"] == "built-in"));
+
+        // Uploaded anchors replace those built in.
+        let store = state.store.clone();
+        store.set_anchors("snp", &[GENOA.to_vec()]).await.unwrap();
+        let state = state.with_store(store);
+        let active = active(&state).await.unwrap();
+        let snp: Vec<_> = active.iter().filter(|a| a.platform == "snp").collect();
+        let ark = PkiPath::from_der(GENOA).unwrap()[0].to_vec().unwrap();
+        assert_eq!(snp.len(), 1);
+        assert_eq!(snp[0].source, Source::Uploaded);
+        assert_eq!(snp[0].fingerprint, hex::encode(Sha256::digest(ark)));
+        assert!(snp[0].subject.contains("ARK-Genoa"), "{}", snp[0].subject);
+    }
+}
@@ -0,0 +1,287 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Policy deciding which requested extensions are copied into certificates.
+//!
+//! Evidence extensions are appraised and, unless allowlisted, consumed. Any
+//! other requested extension must be allowlisted or the request is rejected.
+
+#[cfg(feature = "kvm")]
+use super::kvm::Kvm;
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, ensure, Result};
+use const_oid::db::rfc5280::{
+    ID_CE_BASIC_CONSTRAINTS, ID_CE_EXT_KEY_USAGE, ID_CE_NAME_CONSTRAINTS, ID_CE_SUBJECT_ALT_NAME,
+};
+use const_oid::ObjectIdentifier;
+use serde::{Deserialize, Deserializer};
+use x509::ext::Extension;
+use x509::request::ExtensionReq;
+
+/// Extensions which steward sets itself or which would grant CA powers.
+const RESERVED: &[ObjectIdentifier] = &[
+    ID_CE_BASIC_CONSTRAINTS,
+    ID_CE_EXT_KEY_USAGE,
+    ID_CE_NAME_CONSTRAINTS,
+    ID_CE_SUBJECT_ALT_NAME,
+];
+
+/// The criticality a copied extension must have.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Criticality {
+    #[default]
+    Any,
+    Required,
+    Forbidden,
+}
+
+/// An extension which may be copied from the request into the certificate.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    #[serde(deserialize_with = "from_oid")]
+    pub oid: ObjectIdentifier,
+
+    #[serde(default)]
+    pub critical: Criticality,
+}
+
+/// The extension copy allowlist.
+///
+/// Without an `[extensions]` table only the (non-critical) KVM extension is
+/// copied, as before this was configurable.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    pub copy: Vec<Rule>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            copy: vec![
+                #[cfg(feature = "kvm")]
+                Rule {
+                    oid: Kvm::OID,
+                    critical: Criticality::Forbidden,
+                },
+            ],
+        }
+    }
+}
+
+impl Policy {
+    /// Rejects allowlists which would let requests override steward.
+    pub fn validate(&self) -> Result<()> {
+        for (i, rule) in self.copy.iter().enumerate() {
+            ensure!(
+                !RESERVED.contains(&rule.oid),
+                "extension `{}` is set by steward and cannot be copied",
+                rule.oid
+            );
+            ensure!(
+                !self.copy[..i].iter().any(|r| r.oid == rule.oid),
+                "extension `{}` is listed more than once",
+                rule.oid
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Checks that `ext` may be copied, explaining why not otherwise.
+    pub fn check(&self, ext: &Extension<'_>) -> Result<()> {
+        let oid = ext.extn_id;
+        let rule = self
+            .copy
+            .iter()
+            .find(|r| r.oid == oid)
+            .ok_or_else(|| anyhow!("extension `{oid}` is not allowed by policy"))?;
+
+        match (rule.critical, ext.critical) {
+            (Criticality::Required, false) => bail!("extension `{oid}` must be critical"),
+            (Criticality::Forbidden, true) => bail!("extension `{oid}` must not be critical"),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How repeated extension requests are treated.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Duplicates {
+    /// Merge all requests in order, collapsing identical repeated extensions.
+    #[default]
+    Merge,
+
+    /// Reject more than one extension request, or any repeated extension.
+    Reject,
+}
+
+/// Combines the `extensionRequest` attribute values of a request.
+///
+/// A repeated extension with a different criticality or value is always
+/// rejected, since there is no telling which one the requester meant.
+pub fn merge<'a>(
+    requests: Vec<ExtensionReq<'a>>,
+    duplicates: Duplicates,
+) -> Result<Vec<Extension<'a>>> {
+    ensure!(
+        duplicates == Duplicates::Merge || requests.len() <= 1,
+        "{} extension requests where at most one is allowed",
+        requests.len()
+    );
+
+    let mut merged: Vec<Extension<'a>> = Vec::new();
+    for ext in requests.into_iter().flat_map(Vec::from) {
+        let oid = ext.extn_id;
+        match merged.iter().find(|e| e.extn_id == oid) {
+            None => merged.push(ext),
+            Some(..) if duplicates == Duplicates::Reject => {
+                bail!("extension `{oid}` is requested more than once")
+            }
+            Some(e) if *e == ext => (),
+            Some(..) => bail!("extension `{oid}` is requested with conflicting values"),
+        }
+    }
+
+    Ok(merged)
+}
+
+fn from_oid<'de, D>(deserializer: D) -> Result<ObjectIdentifier, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: String = Deserialize::deserialize(deserializer)?;
+    ObjectIdentifier::from_str(&s).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.3.4");
+
+    fn ext(critical: bool) -> Extension<'static> {
+        Extension {
+            extn_id: OID,
+            critical,
+            extn_value: &[],
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let policy: Policy = toml::from_str(
+            r#"
+            [[copy]]
+            oid = "1.2.3.4"
+            critical = "required"
+
+            [[copy]]
+            oid = "1.2.3.5"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(policy.copy[0].oid, OID);
+        assert_eq!(policy.copy[0].critical, Criticality::Required);
+        assert_eq!(policy.copy[1].critical, Criticality::Any);
+        policy.validate().unwrap();
+
+        assert!(toml::from_str::<Policy>("[[copy]]\noid = \"x\"").is_err());
+    }
+
+    #[test]
+    fn criticality() {
+        let rule = |critical| Policy {
+            copy: vec![Rule { oid: OID, critical }],
+        };
+
+        assert!(rule(Criticality::Any).check(&ext(true)).is_ok());
+        assert!(rule(Criticality::Any).check(&ext(false)).is_ok());
+        assert!(rule(Criticality::Required).check(&ext(true)).is_ok());
+        assert!(rule(Criticality::Required).check(&ext(false)).is_err());
+        assert!(rule(Criticality::Forbidden).check(&ext(true)).is_err());
+        assert!(rule(Criticality::Forbidden).check(&ext(false)).is_ok());
+        assert!(Policy::default().check(&ext(false)).is_err());
+    }
+
+    #[test]
+    fn openssl() {
+        use der::Decode;
+        use x509::request::CertReq;
+
+        // OpenSSL emits a single request, alongside a challengePassword.
+        let csr = include_bytes!("../../../testdata/openssl.csr");
+        let csr = CertReq::from_der(csr).unwrap();
+        let requests: Vec<ExtensionReq<'_>> = csr
+            .info
+            .attributes
+            .iter()
+            .filter(|a| a.oid == const_oid::db::rfc5912::ID_EXTENSION_REQ)
+            .flat_map(|a| a.values.iter())
+            .map(|v| v.decode_into().unwrap())
+            .collect();
+
+        for duplicates in [Duplicates::Merge, Duplicates::Reject] {
+            let merged = merge(requests.clone(), duplicates).unwrap();
+            assert_eq!(merged.len(), 1);
+            assert_eq!(merged[0].extn_id, OID);
+        }
+    }
+
+    #[test]
+    fn windows() {
+        // Windows' certreq may split extensions across several requests and
+        // repeat them verbatim.
+        let other = Extension {
+            extn_id: ObjectIdentifier::new_unwrap("1.2.3.5"),
+            ..ext(false)
+        };
+        let requests = vec![
+            ExtensionReq::from(vec![ext(false)]),
+            ExtensionReq::from(vec![other.clone(), ext(false)]),
+        ];
+
+        let merged = merge(requests.clone(), Duplicates::Merge).unwrap();
+        assert_eq!(merged, vec![ext(false), other.clone()]);
+        assert!(merge(requests, Duplicates::Reject).is_err());
+
+        // Conflicts are never merged.
+        let requests = vec![
+            ExtensionReq::from(vec![ext(false)]),
+            ExtensionReq::from(vec![ext(true)]),
+        ];
+        assert!(merge(requests, Duplicates::Merge).is_err());
+
+        // Repeats within a single request.
+        let requests = vec![ExtensionReq::from(vec![ext(false), ext(false)])];
+        assert!(merge(requests.clone(), Duplicates::Merge).is_ok());
+        assert!(merge(requests, Duplicates::Reject).is_err());
+    }
+
+    #[test]
+    fn reserved() {
+        let policy = Policy {
+            copy: vec![Rule {
+                oid: ID_CE_BASIC_CONSTRAINTS,
+                critical: Criticality::Any,
+            }],
+        };
+        assert!(policy.validate().is_err());
+
+        let rule = Rule {
+            oid: OID,
+            critical: Criticality::Any,
+        };
+        let policy = Policy {
+            copy: vec![rule.clone(), rule],
+        };
+        assert!(policy.validate().is_err());
+    }
+}
@@ -0,0 +1,730 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! A Fulcio-compatible signing certificate endpoint at `/api/v2/signingCert`.
+//!
+//! Sigstore clients such as cosign trade an OIDC identity token for a
+//! short-lived code signing certificate. Steward does the same for the
+//! issuers named in the policy, but only certifies keys whose request also
+//! carries evidence, so that signatures can be traced to a confidential
+//! builder:
+//!
+//! ```toml
+//! [fulcio.issuers."https://token.actions.githubusercontent.com"]
+//! audience = "sigstore"
+//! ```
+//!
+//! Clients must send the `certificateSigningRequest` form, since a bare
+//! public key cannot carry evidence. Certificates are issued as if also
+//! appraised on platform `fulcio`, with the hex of the issuer URL as
+//! measurement `issuer`, so that validity rules may pick their profile.
+//! Whatever the profile, they are for code signing alone and last at most
+//! [`LIFETIME`]. They name the token's verified `email`, or else its subject
+//! as a URI, and carry the issuer in Fulcio's extension.
+//!
+//! No signed certificate timestamps are embedded, since steward does not
+//! submit to certificate transparency logs; cosign must be told not to expect
+//! them with `--insecure-ignore-sct`.
+
+use super::asn1::{oid, sequence, tlv, BIT_STRING};
+use super::collateral::{Settings, Upstream};
+use super::profiles::Profile;
+use super::verifier::Appraisal;
+use super::{appraise, debug_mode, issue, normalized, State};
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use attestation::crypto::{CertReqExt, SubjectPublicKeyInfoExt};
+use attestation::parse;
+use axum::body::Bytes;
+use axum::extract::Extension;
+use axum::Json;
+use base64::engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD as BASE64URL};
+use base64::Engine;
+use const_oid::db::rfc5912::{
+    ECDSA_WITH_SHA_256, ECDSA_WITH_SHA_384, ID_EC_PUBLIC_KEY, RSA_ENCRYPTION, SECP_256_R_1,
+    SECP_384_R_1, SHA_256_WITH_RSA_ENCRYPTION,
+};
+use const_oid::ObjectIdentifier;
+use der::asn1::{Ia5StringRef, UIntRef, Utf8StringRef};
+use der::pem::{self, LineEnding};
+use der::{Decode, Encode};
+use hyper::StatusCode;
+use sec1::pkcs8::PrivateKeyInfo;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use spki::{AlgorithmIdentifier, SubjectPublicKeyInfo};
+use tracing::debug;
+use x509::ext::pkix::name::GeneralName;
+use x509::ext::pkix::SubjectAltName;
+use x509::Certificate;
+
+/// Fulcio's extension naming the issuer of the identity token.
+const OIDC_ISSUER: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.57264.1.8");
+
+/// The seconds by which the clocks of steward and issuers may disagree.
+const SKEW: u64 = 60;
+
+/// The platform as which the identity token is appraised.
+pub(crate) const PLATFORM: &str = "fulcio";
+
+/// The longest lifetime of signing certificates, in seconds.
+pub const LIFETIME: u64 = 10 * 60;
+
+/// Narrows the `profile` picked for a certificate to a signing certificate's,
+/// if the certificate is for Fulcio's endpoint.
+pub(crate) fn signing(profile: Profile, appraisals: &[Appraisal]) -> Profile {
+    if !appraisals.iter().any(|a| a.platform == PLATFORM) {
+        return profile;
+    }
+
+    Profile {
+        lifetime: profile.lifetime.min(LIFETIME),
+        extended_key_usage: Some(vec!["code_signing".into()]),
+        key_usage: Some(vec!["digital_signature".into()]),
+        must_staple: false,
+        dns_names: Vec::new(),
+        ..profile
+    }
+}
+
+/// The OIDC issuers whose identity tokens are accepted.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    pub issuers: BTreeMap<String, Issuer>,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Issuer {
+    /// The audience which tokens must be for.
+    #[serde(default = "Issuer::audience")]
+    pub audience: String,
+}
+
+impl Issuer {
+    fn audience() -> String {
+        "sigstore".into()
+    }
+}
+
+impl Policy {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(!self.issuers.is_empty(), "no issuers are trusted");
+        for (url, issuer) in &self.issuers {
+            ensure!(
+                url.starts_with("https://"),
+                "issuer `{url}` must be an https URL"
+            );
+            ensure!(
+                !issuer.audience.is_empty(),
+                "issuer `{url}` must have an audience"
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Fetches the signing keys of OIDC issuers.
+#[derive(Debug)]
+pub struct Discovery {
+    upstream: Upstream,
+}
+
+#[derive(Deserialize)]
+struct Configuration {
+    issuer: String,
+    jwks_uri: String,
+}
+
+impl Discovery {
+    pub fn new(settings: Settings) -> Result<Self> {
+        Ok(Self {
+            upstream: Upstream::new("oidc", settings)?,
+        })
+    }
+
+    /// Returns the key set of `issuer`, as published by its discovery
+    /// document.
+    async fn keys(&self, issuer: &str) -> Result<Jwks, StatusCode> {
+        let unavailable = |e| {
+            debug!("{e}");
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            issuer.trim_end_matches('/')
+        );
+        let configuration = |body: &[u8]| -> Result<Configuration> {
+            let configuration: Configuration = serde_json::from_slice(body)?;
+            ensure!(
+                configuration.issuer == issuer,
+                "discovery document of `{issuer}` names another issuer"
+            );
+            Ok(configuration)
+        };
+        let body = self
+            .upstream
+            .get(&url, |body| configuration(body).map(drop))
+            .await
+            .map_err(unavailable)?;
+        let jwks_uri = configuration(&body).map_err(internal)?.jwks_uri;
+
+        let body = self
+            .upstream
+            .get(&jwks_uri, |body| {
+                Ok(serde_json::from_slice(body).map(drop::<Jwks>)?)
+            })
+            .await
+            .map_err(unavailable)?;
+        serde_json::from_slice(&body).map_err(internal)
+    }
+}
+
+/// A JSON Web Key Set.
+#[derive(Debug, Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+/// A public JSON Web Key, RSA or elliptic curve.
+#[derive(Debug, Default, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    crv: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+impl Jwk {
+    /// Encodes the key as a `SubjectPublicKeyInfo`.
+    fn spki(&self) -> Result<Vec<u8>> {
+        let field = |value: &Option<String>, name| -> Result<Vec<u8>> {
+            let value = value
+                .as_deref()
+                .with_context(|| format!("jwk without `{name}`"))?;
+            BASE64URL
+                .decode(value)
+                .with_context(|| format!("invalid jwk `{name}`"))
+        };
+
+        let (algorithm, key) = match (self.kty.as_str(), self.crv.as_deref()) {
+            ("RSA", _) => {
+                let (n, e) = (field(&self.n, "n")?, field(&self.e, "e")?);
+                let n = UIntRef::new(&n)?.to_vec()?;
+                let e = UIntRef::new(&e)?.to_vec()?;
+                let null = [0x05, 0x00];
+                (
+                    sequence(&[&oid(RSA_ENCRYPTION)?, &null]),
+                    sequence(&[&n, &e]),
+                )
+            }
+            ("EC", Some(crv @ ("P-256" | "P-384"))) => {
+                let curve = match crv {
+                    "P-256" => SECP_256_R_1,
+                    _ => SECP_384_R_1,
+                };
+                let (x, y) = (field(&self.x, "x")?, field(&self.y, "y")?);
+                let point = [&[0x04][..], &x, &y].concat();
+                (sequence(&[&oid(ID_EC_PUBLIC_KEY)?, &oid(curve)?]), point)
+            }
+            (kty, crv) => bail!("unsupported jwk type `{kty}` on curve {crv:?}"),
+        };
+
+        let bits = tlv(BIT_STRING, &[&[0][..], &key].concat());
+        Ok(sequence(&[&algorithm, &bits]))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, audience: &str) -> bool {
+        match self {
+            Self::One(one) => one == audience,
+            Self::Many(many) => many.iter().any(|a| a == audience),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: u64,
+    nbf: Option<u64>,
+    email: Option<String>,
+    email_verified: Option<bool>,
+}
+
+impl Claims {
+    /// Names the holder of the token: its email, if the issuer says it is
+    /// verified, or else its subject, if that is a URI.
+    fn identity(&self) -> Result<GeneralName<'_>> {
+        if let Some(email) = self.email.as_deref() {
+            if self.email_verified == Some(true) {
+                return Ok(GeneralName::Rfc822Name(Ia5StringRef::new(email)?));
+            }
+        }
+
+        let scheme = self.sub.split_once(':').map(|(scheme, _)| scheme);
+        match scheme {
+            Some(s) if !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric()) => Ok(
+                GeneralName::UniformResourceIdentifier(Ia5StringRef::new(&self.sub)?),
+            ),
+            _ => bail!("token names neither a verified email nor a URI"),
+        }
+    }
+}
+
+/// A signed JWT, split into its parts.
+#[derive(Debug)]
+struct Token<'a> {
+    header: Header,
+    claims: Claims,
+    signed: &'a str,
+    signature: Vec<u8>,
+}
+
+impl<'a> Token<'a> {
+    fn decode(jwt: &'a str) -> Result<Self> {
+        let (signed, signature) = jwt.rsplit_once('.').context("malformed token")?;
+        let (header, claims) = signed.split_once('.').context("malformed token")?;
+        let part = |part| BASE64URL.decode(part).context("malformed token");
+
+        Ok(Self {
+            header: serde_json::from_slice(&part(header)?).context("invalid token header")?,
+            claims: serde_json::from_slice(&part(claims)?).context("invalid token claims")?,
+            signed,
+            signature: part(signature)?,
+        })
+    }
+
+    /// Checks that the token is from a trusted issuer, for its audience and
+    /// current at `now`, in seconds since the epoch.
+    fn check(&self, policy: &Policy, now: u64) -> Result<()> {
+        let claims = &self.claims;
+        let issuer = policy
+            .issuers
+            .get(&claims.iss)
+            .ok_or_else(|| anyhow!("token from untrusted issuer `{}`", claims.iss))?;
+        ensure!(
+            claims.aud.contains(&issuer.audience),
+            "token not for audience `{}`",
+            issuer.audience
+        );
+        ensure!(now < claims.exp.saturating_add(SKEW), "token expired");
+        ensure!(
+            claims.nbf.map_or(true, |nbf| nbf <= now + SKEW),
+            "token not yet valid"
+        );
+        Ok(())
+    }
+
+    /// Verifies the signature with the key of `jwks` the token names.
+    fn verify(&self, jwks: &Jwks) -> Result<()> {
+        let (oid, signature) = match self.header.alg.as_str() {
+            "RS256" => (SHA_256_WITH_RSA_ENCRYPTION, self.signature.clone()),
+            "ES256" => (ECDSA_WITH_SHA_256, ecdsa(&self.signature, 32)?),
+            "ES384" => (ECDSA_WITH_SHA_384, ecdsa(&self.signature, 48)?),
+            alg => bail!("unsupported token algorithm `{alg}`"),
+        };
+        let algorithm = AlgorithmIdentifier {
+            oid,
+            parameters: None,
+        };
+
+        let named = |jwk: &&Jwk| self.header.kid.is_none() || jwk.kid == self.header.kid;
+        for jwk in jwks.keys.iter().filter(named) {
+            let spki = match jwk.spki() {
+                Ok(spki) => spki,
+                Err(..) => continue,
+            };
+            let spki = SubjectPublicKeyInfo::from_der(&spki)?;
+            if spki
+                .verify(self.signed.as_bytes(), algorithm, &signature)
+                .is_ok()
+            {
+                return Ok(());
+            }
+        }
+
+        bail!("token not signed by a key of `{}`", self.claims.iss)
+    }
+}
+
+/// Converts a JWS ECDSA signature, `r` and `s` of `len` bytes each, to DER.
+fn ecdsa(signature: &[u8], len: usize) -> Result<Vec<u8>> {
+    ensure!(signature.len() == 2 * len, "malformed ecdsa signature");
+    let (r, s) = signature.split_at(len);
+    let r = UIntRef::new(r)?.to_vec()?;
+    let s = UIntRef::new(s)?.to_vec()?;
+    Ok(sequence(&[&r, &s]))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Credentials {
+    oidc_identity_token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SigningCertRequest {
+    credentials: Credentials,
+
+    /// The base64 of a PEM certification request.
+    certificate_signing_request: Option<String>,
+}
+
+fn internal(e: impl std::fmt::Display) -> StatusCode {
+    debug!("{e}");
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+/// Decodes the base64 of a PEM certification request.
+fn csr(base64: &str) -> Result<Vec<u8>> {
+    let pem = BASE64.decode(base64).context("invalid base64")?;
+    let (label, der) = pem::decode_vec(&pem)?;
+    ensure!(
+        label == "CERTIFICATE REQUEST",
+        "unexpected pem label `{label}`"
+    );
+    Ok(der)
+}
+
+/// Issues a code signing certificate for an OIDC identity.
+pub async fn signing_cert(
+    Extension(state): Extension<Arc<State>>,
+    body: Bytes,
+) -> Result<Json<Value>, StatusCode> {
+    let policy = state.policy();
+    let (config, discovery) = match (&policy.config.fulcio, &state.fulcio) {
+        (Some(config), Some(discovery)) => (config, discovery),
+        _ => return Err(StatusCode::NOT_FOUND),
+    };
+    let reject = |status: StatusCode, reason: &str| {
+        state.notify(
+            "rejection",
+            json!({ "protocol": "fulcio", "reason": reason }),
+        );
+        status
+    };
+
+    let request: SigningCertRequest = serde_json::from_slice(&body).map_err(|e| {
+        debug!("failed to decode fulcio request: {e}");
+        reject(StatusCode::BAD_REQUEST, "malformed request")
+    })?;
+    let der = request
+        .certificate_signing_request
+        .as_deref()
+        .ok_or_else(|| anyhow!("fulcio request without a certification request"))
+        .and_then(csr)
+        .map_err(|e| {
+            debug!("{e:#}");
+            reject(StatusCode::BAD_REQUEST, "malformed request")
+        })?;
+
+    // Only fetch the keys of issuers the policy trusts.
+    let now = state
+        .clock
+        .now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(internal)?;
+    let token = Token::decode(&request.credentials.oidc_identity_token)
+        .and_then(|token| token.check(config, now.as_secs()).map(|_| token))
+        .map_err(|e| {
+            debug!("{e:#}");
+            reject(StatusCode::UNAUTHORIZED, "invalid token")
+        })?;
+    let jwks = discovery.keys(&token.claims.iss).await?;
+    token.verify(&jwks).map_err(|e| {
+        debug!("{e:#}");
+        reject(StatusCode::UNAUTHORIZED, "invalid token")
+    })?;
+    let identity = token.claims.identity().map_err(|e| {
+        debug!("{e:#}");
+        reject(StatusCode::BAD_REQUEST, "no identity")
+    })?;
+
+    let malformed = |e: anyhow::Error| {
+        debug!("failed to decode fulcio certification request: {e}");
+        reject(StatusCode::BAD_REQUEST, "malformed request")
+    };
+    let der = normalized(policy.config.parsing, &der).map_err(malformed)?;
+    let cr = parse::cert_req(&der).map_err(malformed)?;
+    let info = cr.verify().map_err(|e| {
+        debug!("failed to verify fulcio certification request: {e}");
+        reject(StatusCode::BAD_REQUEST, "malformed request")
+    })?;
+
+    let issuer = Certificate::from_der(&state.crt).map_err(internal)?;
+    let pki = PrivateKeyInfo::from_der(&state.key).map_err(internal)?;
+    let (mut extensions, mut appraisals) =
+        appraise(&info, debug_mode(&issuer), &state, &policy).await?;
+    appraisals.push(
+        Appraisal::new(PLATFORM, false).with_measurement("issuer", token.claims.iss.as_bytes()),
+    );
+
+    let iss = Utf8StringRef::new(&token.claims.iss)
+        .and_then(|iss| iss.to_vec())
+        .map_err(internal)?;
+    extensions.push(x509::ext::Extension {
+        extn_id: OIDC_ISSUER,
+        critical: false,
+        extn_value: &iss,
+    });
+
+    let request = state.archive.as_ref().map(|_| der.clone());
+    let (crt, _) = issue(
+        &issuer,
+        &pki,
+        SubjectAltName(vec![identity]),
+        info,
+        extensions,
+        &appraisals,
+        None,
+        &policy,
+        &state,
+        request,
+    )
+    .await?;
+    debug!("issued fulcio certificate for {}", token.claims.sub);
+
+    let certificates = [&crt, &state.crt]
+        .into_iter()
+        .map(|der| pem::encode_string("CERTIFICATE", LineEnding::LF, der))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(internal)?;
+    Ok(Json(json!({
+        "signedCertificateEmbeddedSct": {
+            "chain": { "certificates": certificates },
+        },
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::asn1::elements;
+    use super::*;
+
+    use attestation::crypto::PrivateKeyInfoExt;
+    use der::asn1::AnyRef;
+    use rsa::pkcs1::DecodeRsaPublicKey;
+    use rsa::PublicKeyParts;
+
+    const ISSUER: &str = "https://issuer.example.com";
+    const NOW: u64 = 1_700_000_000;
+
+    fn policy() -> Policy {
+        let issuer = Issuer {
+            audience: Issuer::audience(),
+        };
+        Policy {
+            issuers: [(ISSUER.to_string(), issuer)].into(),
+        }
+    }
+
+    /// Returns the JWK of the P-256 key `pki`.
+    fn jwk(pki: &PrivateKeyInfo<'_>) -> Jwk {
+        let spki = pki.public_key().unwrap();
+        let point = spki.subject_public_key;
+        Jwk {
+            kty: "EC".into(),
+            kid: Some("1".into()),
+            crv: Some("P-256".into()),
+            x: Some(BASE64URL.encode(&point[1..33])),
+            y: Some(BASE64URL.encode(&point[33..])),
+            ..Default::default()
+        }
+    }
+
+    /// Signs `claims` with `pki` as an ES256 JWT.
+    fn jwt(pki: &PrivateKeyInfo<'_>, claims: &Value) -> String {
+        let header = json!({ "alg": "ES256", "kid": "1" });
+        let encode = |v: &Value| BASE64URL.encode(serde_json::to_vec(v).unwrap());
+        let signed = format!("{}.{}", encode(&header), encode(claims));
+
+        let algorithm = AlgorithmIdentifier {
+            oid: ECDSA_WITH_SHA_256,
+            parameters: None,
+        };
+        let der = pki.sign(signed.as_bytes(), algorithm).unwrap();
+        let der = AnyRef::from_der(&der).unwrap();
+        let mut raw = Vec::new();
+        for int in elements(der.value()).unwrap() {
+            let int = UIntRef::try_from(int).unwrap();
+            let bytes = int.as_bytes();
+            raw.extend(std::iter::repeat(0).take(32 - bytes.len()));
+            raw.extend_from_slice(bytes);
+        }
+        format!("{signed}.{}", BASE64URL.encode(raw))
+    }
+
+    fn claims() -> Value {
+        json!({
+            "iss": ISSUER,
+            "sub": "repo:profian/steward:ref:refs/heads/main",
+            "aud": ["sigstore"],
+            "exp": NOW + 600,
+            "nbf": NOW,
+            "email": "builder@example.com",
+            "email_verified": true,
+        })
+    }
+
+    #[test]
+    fn token() {
+        let key = PrivateKeyInfo::generate(SECP_256_R_1).unwrap();
+        let pki = PrivateKeyInfo::from_der(key.as_ref()).unwrap();
+        let jwks = Jwks {
+            keys: vec![jwk(&pki)],
+        };
+
+        let signed = jwt(&pki, &claims());
+        let token = Token::decode(&signed).unwrap();
+        token.check(&policy(), NOW).unwrap();
+        token.verify(&jwks).unwrap();
+        assert_eq!(
+            token.claims.identity().unwrap(),
+            GeneralName::Rfc822Name(Ia5StringRef::new("builder@example.com").unwrap())
+        );
+
+        // The signature covers the claims.
+        let (_, signature) = signed.rsplit_once('.').unwrap();
+        let mut forged = claims();
+        forged["sub"] = "someone else".into();
+        let forged = format!(
+            "{}.{signature}",
+            jwt(&pki, &forged).rsplit_once('.').unwrap().0
+        );
+        assert!(Token::decode(&forged).unwrap().verify(&jwks).is_err());
+
+        // Nor does another key's signature verify.
+        let other = PrivateKeyInfo::generate(SECP_256_R_1).unwrap();
+        let other = PrivateKeyInfo::from_der(other.as_ref()).unwrap();
+        let signed = jwt(&other, &claims());
+        assert!(Token::decode(&signed).unwrap().verify(&jwks).is_err());
+    }
+
+    #[test]
+    fn claims_checked() {
+        let key = PrivateKeyInfo::generate(SECP_256_R_1).unwrap();
+        let pki = PrivateKeyInfo::from_der(key.as_ref()).unwrap();
+        let check = |claims: Value, now| {
+            let jwt = jwt(&pki, &claims);
+            Token::decode(&jwt).unwrap().check(&policy(), now)
+        };
+
+        assert!(check(claims(), NOW + 600 + SKEW).is_err());
+        assert!(check(claims(), NOW - 2 * SKEW).is_err());
+        check(claims(), NOW - SKEW).unwrap();
+
+        let mut other = claims();
+        other["iss"] = "https://elsewhere.example.com".into();
+        assert!(check(other, NOW).is_err());
+
+        let mut other = claims();
+        other["aud"] = "sigstore".into();
+        check(other, NOW).unwrap();
+
+        let mut other = claims();
+        other["aud"] = "elsewhere".into();
+        assert!(check(other, NOW).is_err());
+    }
+
+    #[test]
+    fn identity() {
+        let claims = |value: Value| serde_json::from_value::<Claims>(value).unwrap();
+
+        // Emails not said to be verified fall back to the subject.
+        let subject = GeneralName::UniformResourceIdentifier(
+            Ia5StringRef::new("repo:profian/steward:ref:refs/heads/main").unwrap(),
+        );
+        let mut unverified = self::claims();
+        unverified["email_verified"] = false.into();
+        assert_eq!(claims(unverified).identity().unwrap(), subject);
+        let mut unsaid = self::claims();
+        unsaid.as_object_mut().unwrap().remove("email_verified");
+        assert_eq!(claims(unsaid).identity().unwrap(), subject);
+
+        let mut neither = self::claims();
+        neither["email"] = Value::Null;
+        neither["sub"] = "1234567890".into();
+        assert!(claims(neither).identity().is_err());
+    }
+
+    #[test]
+    fn rsa_jwk() {
+        let key = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let jwk = Jwk {
+            kty: "RSA".into(),
+            n: Some(BASE64URL.encode(key.n().to_bytes_be())),
+            e: Some(BASE64URL.encode(key.e().to_bytes_be())),
+            ..Default::default()
+        };
+
+        let spki = jwk.spki().unwrap();
+        let spki = SubjectPublicKeyInfo::from_der(&spki).unwrap();
+        assert_eq!(spki.algorithm.oids().unwrap(), (RSA_ENCRYPTION, None));
+        let public = rsa::RsaPublicKey::from_pkcs1_der(spki.subject_public_key).unwrap();
+        assert_eq!(public, key.to_public_key());
+    }
+
+    #[test]
+    fn signing() {
+        use const_oid::db::rfc5280::ID_KP_CODE_SIGNING;
+
+        let long = Profile {
+            lifetime: 86400,
+            extended_key_usage: Some(vec!["server_auth".into()]),
+            dns_names: vec!["api.example.com".into()],
+            ..Default::default()
+        };
+        let snp = [Appraisal::new("snp", true)];
+        assert_eq!(super::signing(long.clone(), &snp), long);
+
+        // Whatever the profile, signing certificates are short-lived and for
+        // code signing alone.
+        let fulcio = [Appraisal::new("snp", true), Appraisal::new(PLATFORM, false)];
+        for profile in [Profile::default(), long] {
+            let profile = super::signing(profile, &fulcio);
+            assert_eq!(profile.lifetime, LIFETIME);
+            assert_eq!(profile.extended_key_usage().unwrap(), [ID_KP_CODE_SIGNING]);
+            assert!(profile.dns_names.is_empty());
+        }
+    }
+
+    #[test]
+    fn validate() {
+        policy().validate().unwrap();
+        assert!(Policy::default().validate().is_err());
+
+        let mut http = policy();
+        let issuer = http.issuers.remove(ISSUER).unwrap();
+        http.issuers
+            .insert("http://issuer.example.com".into(), issuer);
+        assert!(http.validate().is_err());
+    }
+}
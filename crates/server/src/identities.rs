@@ -0,0 +1,381 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! The history of workload identities, at `GET /identities/{key}`.
+//!
+//! A workload is identified by its key: every certificate issued for the same
+//! public key renews the same identity. Each issuance is linked into the
+//! identity's history with the platform and measurements attested for it, so
+//! that auditors can follow a workload from its first attestation through
+//! every renewal, and see where its measurements changed. Identities are
+//! named by the hex SHA-256 digest of the DER `SubjectPublicKeyInfo`.
+//!
+//! A renewal which attests other measurements than the identity's first
+//! issuance may be a tampered workload, or an upgrade nobody announced. The
+//! policy's `measurement_changes` says whether to `allow` it, `alert` (the
+//! default) by raising a `measurement_change` audit event, or `deny` it.
+
+use super::store::{AuditRecord, Issued, Link};
+use super::verifier::Appraisal;
+use super::{admin, State};
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use axum::extract::{Extension, Path, TypedHeader};
+use axum::headers::authorization::{Authorization, Bearer};
+use axum::Json;
+use der::{Decode, Encode};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+use x509::Certificate;
+
+/// What to do when a renewal attests other measurements than the first
+/// issuance for the same key.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Changes {
+    /// Renew without comment.
+    Allow,
+
+    /// Renew, raising an audit event.
+    #[default]
+    Alert,
+
+    /// Refuse to renew.
+    Deny,
+}
+
+/// The digest which names the identity of the DER `spki`.
+pub fn digest(spki: &[u8]) -> [u8; 32] {
+    Sha256::digest(spki).into()
+}
+
+/// The digest which names the identity of the key certified by `crt`.
+pub fn key(crt: &[u8]) -> Result<[u8; 32]> {
+    let crt = Certificate::from_der(crt)?;
+    Ok(digest(
+        &crt.tbs_certificate.subject_public_key_info.to_vec()?,
+    ))
+}
+
+/// The measurements attested by `appraisals`, by platform and name.
+pub fn measurements(appraisals: &[Appraisal]) -> BTreeMap<String, String> {
+    appraisals
+        .iter()
+        .flat_map(|a| {
+            a.measurements
+                .iter()
+                .map(|(name, value)| (format!("{}.{name}", a.platform), value.clone()))
+        })
+        .collect()
+}
+
+/// Links a newly issued certificate into the history of its identity.
+pub(crate) async fn link(state: &State, issued: &Issued, appraisals: &[Appraisal]) -> Result<()> {
+    let link = Link {
+        serial: issued.serial.clone(),
+        not_before: issued.not_before,
+        not_after: issued.not_after,
+        platform: issued.platform.clone(),
+        measurements: measurements(appraisals),
+    };
+    let now = state.clock.now();
+    state.store.link(&key(&issued.der)?, &link, now).await
+}
+
+/// Compares the measurements of `appraisals` with those of the first
+/// issuance for the DER `spki`, acting on any change as `changes` says.
+pub(crate) async fn check(
+    state: &State,
+    changes: Changes,
+    spki: &[u8],
+    appraisals: &[Appraisal],
+) -> Result<(), StatusCode> {
+    if changes == Changes::Allow {
+        return Ok(());
+    }
+
+    let key = digest(spki);
+    let links = state.store.identity(&key).await.map_err(|e| {
+        debug!("failed to read identity: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let first = match links.first() {
+        Some(first) => first,
+        None => return Ok(()),
+    };
+    let changed = changed(&first.measurements, &measurements(appraisals));
+    if changed.is_empty() {
+        return Ok(());
+    }
+
+    let (key, first) = (hex::encode(key), hex::encode(&first.serial));
+    let detail = format!(
+        "identity {key} first issued as {first} renewed with changed {}",
+        changed.join(", ")
+    );
+    if changes == Changes::Deny {
+        debug!("{detail}");
+        let reason = format!("measurements changed since {first}: {}", changed.join(", "));
+        return Err(state.reject(StatusCode::UNAUTHORIZED, reason));
+    }
+
+    let record = AuditRecord {
+        at: state.clock.now(),
+        event: "measurement_change".into(),
+        detail,
+    };
+    state.store.audit(&record).await.map_err(|e| {
+        debug!("failed to audit measurement change: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    state.notify(
+        &record.event,
+        json!({ "key": key, "first": first, "changed": changed }),
+    );
+    warn!("{}", record.detail);
+    Ok(())
+}
+
+/// One issuance of an identity.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Issuance {
+    pub serial: String,
+
+    /// Seconds since the Unix epoch.
+    pub not_before: u64,
+    pub not_after: u64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+
+    pub measurements: BTreeMap<String, String>,
+
+    /// The measurements which differ from the issuance before, if any.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub changed: Vec<String>,
+}
+
+/// The history of a workload identity.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Identity {
+    pub key: String,
+
+    /// When the identity was first attested, in seconds since the Unix epoch.
+    pub first_seen: u64,
+
+    /// Every issuance, oldest first.
+    pub issuances: Vec<Issuance>,
+}
+
+/// Names the measurements which were added, removed or changed.
+pub fn changed(before: &BTreeMap<String, String>, after: &BTreeMap<String, String>) -> Vec<String> {
+    let mut names: Vec<_> = before.keys().chain(after.keys()).collect();
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .filter(|name| before.get(*name) != after.get(*name))
+        .cloned()
+        .collect()
+}
+
+impl Identity {
+    fn new(key: &[u8], links: Vec<Link>) -> Option<Self> {
+        let secs = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        };
+
+        let mut issuances = Vec::<Issuance>::with_capacity(links.len());
+        for link in links {
+            let changed = match issuances.last() {
+                Some(last) => changed(&last.measurements, &link.measurements),
+                None => Vec::new(),
+            };
+            issuances.push(Issuance {
+                serial: hex::encode(&link.serial),
+                not_before: secs(link.not_before),
+                not_after: secs(link.not_after),
+                platform: link.platform,
+                measurements: link.measurements,
+                changed,
+            });
+        }
+
+        Some(Self {
+            key: hex::encode(key),
+            first_seen: issuances.first()?.not_before,
+            issuances,
+        })
+    }
+}
+
+/// Returns the history of the identity with the hex `key` digest.
+pub async fn identity(
+    Path(key): Path<String>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Json<Identity>, StatusCode> {
+    admin::authorize(&state, auth, true).await?;
+
+    let key = hex::decode(key).or(Err(StatusCode::BAD_REQUEST))?;
+    let links = state.store.identity(&key).await.map_err(|e| {
+        debug!("failed to read identity: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Identity::new(&key, links)
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::admin::Auditor;
+    use super::super::{app, stats};
+    use super::*;
+
+    use std::time::Duration;
+
+    use http::header::AUTHORIZATION;
+    use http::Request;
+    use hyper::Body;
+    use tower::ServiceExt; // for `app.oneshot()`
+
+    #[test]
+    fn history() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let link = |serial: u8, mrenclave: &str| Link {
+            serial: vec![serial],
+            not_before: start + Duration::from_secs(serial.into()),
+            not_after: start + Duration::from_secs(3600),
+            platform: Some("sgx".into()),
+            measurements: [
+                ("sgx.mrenclave".into(), mrenclave.into()),
+                ("sgx.mrsigner".into(), "bb".into()),
+            ]
+            .into(),
+        };
+
+        let identity = Identity::new(&[7], vec![link(1, "aa"), link(2, "aa"), link(3, "cc")]);
+        let identity = identity.unwrap();
+        assert_eq!(identity.key, "07");
+        assert_eq!(identity.first_seen, 1_700_000_001);
+        let changes: Vec<_> = identity.issuances.iter().map(|i| &i.changed).collect();
+        assert_eq!(
+            changes,
+            [&vec![], &vec![], &vec!["sgx.mrenclave".to_string()]]
+        );
+
+        assert_eq!(Identity::new(&[7], vec![]), None);
+    }
+
+    #[tokio::test]
+    async fn changes() {
+        let state = State::generate(None, "localhost").unwrap();
+        let spki = Certificate::from_der(&state.crt)
+            .unwrap()
+            .tbs_certificate
+            .subject_public_key_info
+            .to_vec()
+            .unwrap();
+        let appraisals = |hash: &[u8]| [Appraisal::new("kvm", true).with_measurement("hash", hash)];
+
+        // Nothing has changed before the first issuance.
+        check(&state, Changes::Deny, &spki, &appraisals(b"v1"))
+            .await
+            .unwrap();
+
+        let now = SystemTime::now();
+        let issued = Issued {
+            serial: vec![1],
+            not_before: now,
+            not_after: now + Duration::from_secs(3600),
+            der: state.crt.clone(),
+            rekor_index: None,
+            policy_version: 1,
+            platform: None,
+            tenant: None,
+        };
+        link(&state, &issued, &appraisals(b"v1")).await.unwrap();
+
+        for changes in [Changes::Allow, Changes::Alert, Changes::Deny] {
+            check(&state, changes, &spki, &appraisals(b"v1"))
+                .await
+                .unwrap();
+        }
+        for changes in [Changes::Allow, Changes::Alert] {
+            check(&state, changes, &spki, &appraisals(b"v2"))
+                .await
+                .unwrap();
+        }
+        let denied = check(&state, Changes::Deny, &spki, &appraisals(b"v2")).await;
+        assert_eq!(denied, Err(StatusCode::UNAUTHORIZED));
+        let rejection = state.rejections.recent().remove(0);
+        assert_eq!(rejection.reason, "measurements changed since 01: kvm.hash");
+    }
+
+    #[tokio::test]
+    async fn lookup() {
+        let state = State::generate(None, "localhost")
+            .unwrap()
+            .with_auditor(Auditor::new("auditor").unwrap());
+
+        // Certify the CA's own key twice, as a stand-in for a workload's.
+        let now = SystemTime::now();
+        let appraisals = [Appraisal::new("kvm", true).with_measurement("hash", b"v1")];
+        for serial in [1, 2] {
+            let issued = Issued {
+                serial: vec![serial],
+                not_before: now + Duration::from_secs(serial.into()),
+                not_after: now + Duration::from_secs(3600),
+                der: state.crt.clone(),
+                rekor_index: None,
+                policy_version: 1,
+                platform: stats::platform(&appraisals),
+                tenant: None,
+            };
+            link(&state, &issued, &appraisals).await.unwrap();
+        }
+
+        let get = |uri: String, token: Option<&'static str>| {
+            let state = state.clone();
+            async move {
+                let mut request = Request::get(uri);
+                if let Some(token) = token {
+                    request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+                }
+                app(state)
+                    .oneshot(request.body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let key = hex::encode(key(&state.crt).unwrap());
+        let rsp = get(format!("/identities/{key}"), Some("auditor")).await;
+        assert_eq!(rsp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(rsp.into_body()).await.unwrap();
+        let identity: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(identity["key"], key);
+        let issuances = identity["issuances"].as_array().unwrap();
+        assert_eq!(issuances.len(), 2);
+        assert_eq!(issuances[1]["serial"], "02");
+        assert_eq!(issuances[1]["platform"], "kvm");
+        assert_eq!(issuances[1]["measurements"]["kvm.hash"], hex::encode(b"v1"));
+
+        let rsp = get(format!("/identities/{key}"), None).await;
+        assert_eq!(rsp.status(), StatusCode::UNAUTHORIZED);
+        let rsp = get(format!("/identities/{}", "00".repeat(32)), Some("auditor")).await;
+        assert_eq!(rsp.status(), StatusCode::NOT_FOUND);
+        let rsp = get("/identities/xyz".into(), Some("auditor")).await;
+        assert_eq!(rsp.status(), StatusCode::BAD_REQUEST);
+    }
+}
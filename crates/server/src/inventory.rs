@@ -0,0 +1,341 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Export of the issued certificates at `GET /admin/certs/export`, for
+//! offline analysis and compliance reporting.
+//!
+//! Certificates are listed in order of issuance, as CSV (`format=csv`) or
+//! JSON lines (`format=jsonl`, the default), with the `fields` named in a
+//! comma separated list. Times are seconds since the Unix epoch. `since`
+//! skips certificates issued before then.
+//!
+//! Each response holds at most `limit` certificates. When there may be more,
+//! it carries an [`CURSOR_HEADER`], which is passed back as `cursor` to get
+//! the next page. Only unexpired certificates are kept by the embedded store.
+
+use super::store::Issued;
+use super::{admin, State};
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::extract::{Extension, TypedHeader};
+use axum::headers::authorization::{Authorization, Bearer};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::Uri;
+use axum::response::{IntoResponse, Response};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use der::Decode;
+use hyper::StatusCode;
+use serde_json::{Map, Value};
+use tracing::debug;
+use x509::Certificate;
+
+/// Names the page after this one.
+pub const CURSOR_HEADER: &str = "x-steward-cursor";
+
+/// The fields which may be exported.
+const FIELDS: &[&str] = &[
+    "serial",
+    "not_before",
+    "not_after",
+    "subject",
+    "policy_version",
+    "rekor_index",
+    "der",
+];
+
+const DEFAULT_FIELDS: &[&str] = &[
+    "serial",
+    "not_before",
+    "not_after",
+    "subject",
+    "policy_version",
+];
+
+const DEFAULT_LIMIT: usize = 1000;
+const MAX_LIMIT: usize = 10000;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Format {
+    Csv,
+    Jsonl,
+}
+
+/// What was asked for in the query string.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Query {
+    format: Format,
+    fields: Vec<&'static str>,
+    after: (SystemTime, Vec<u8>),
+    limit: usize,
+}
+
+impl Query {
+    fn parse(query: Option<&str>) -> Option<Self> {
+        let mut parsed = Self {
+            format: Format::Jsonl,
+            fields: DEFAULT_FIELDS.to_vec(),
+            after: (UNIX_EPOCH, Vec::new()),
+            limit: DEFAULT_LIMIT,
+        };
+
+        let mut cursor = None;
+        let params = query.into_iter().flat_map(|q| q.split('&'));
+        for param in params.filter(|p| !p.is_empty()) {
+            match param.split_once('=')? {
+                ("format", "csv") => parsed.format = Format::Csv,
+                ("format", "jsonl") => parsed.format = Format::Jsonl,
+                ("fields", fields) => {
+                    parsed.fields = fields
+                        .split(',')
+                        .map(|f| FIELDS.iter().find(|field| **field == f).copied())
+                        .collect::<Option<_>>()?;
+                }
+                ("since", since) => {
+                    let since = Duration::from_secs(since.parse().ok()?);
+                    parsed.after = (UNIX_EPOCH + since, Vec::new());
+                }
+                ("cursor", value) => cursor = Some(decode(value)?),
+                ("limit", limit) => {
+                    parsed.limit = limit.parse().ok().filter(|n| (1..=MAX_LIMIT).contains(n))?;
+                }
+                _ => return None,
+            }
+        }
+
+        // A cursor carries on from where an earlier `since` started.
+        if let Some(cursor) = cursor {
+            parsed.after = cursor;
+        }
+        Some(parsed)
+    }
+}
+
+/// Encodes the position just after `issued`.
+fn encode(issued: &Issued) -> String {
+    let nanos = issued
+        .not_before
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("{nanos}-{}", hex::encode(&issued.serial))
+}
+
+fn decode(cursor: &str) -> Option<(SystemTime, Vec<u8>)> {
+    let (nanos, serial) = cursor.split_once('-')?;
+    let nanos = Duration::from_nanos(nanos.parse().ok()?);
+    Some((UNIX_EPOCH + nanos, hex::decode(serial).ok()?))
+}
+
+/// Returns the value of `field` for `issued`.
+fn value(issued: &Issued, field: &str) -> Value {
+    let secs = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    };
+    match field {
+        "serial" => hex::encode(&issued.serial).into(),
+        "not_before" => secs(issued.not_before).into(),
+        "not_after" => secs(issued.not_after).into(),
+        "subject" => Certificate::from_der(&issued.der)
+            .map(|crt| crt.tbs_certificate.subject.to_string())
+            .unwrap_or_default()
+            .into(),
+        "policy_version" => issued.policy_version.into(),
+        "rekor_index" => issued.rekor_index.into(),
+        "der" => BASE64.encode(&issued.der).into(),
+        _ => Value::Null,
+    }
+}
+
+/// Quotes a CSV cell if it needs to be.
+fn cell(value: &Value) -> String {
+    let text = match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    };
+
+    match text.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", text.replace('"', "\"\"")),
+        false => text,
+    }
+}
+
+/// Renders a page of certificates.
+fn render(query: &Query, page: &[Issued]) -> String {
+    let mut out = String::new();
+    match query.format {
+        Format::Csv => {
+            out += &query.fields.join(",");
+            out += "\n";
+            for issued in page {
+                let cells: Vec<_> = query
+                    .fields
+                    .iter()
+                    .map(|field| cell(&value(issued, field)))
+                    .collect();
+                out += &cells.join(",");
+                out += "\n";
+            }
+        }
+        Format::Jsonl => {
+            for issued in page {
+                let object: Map<_, _> = query
+                    .fields
+                    .iter()
+                    .map(|field| (field.to_string(), value(issued, field)))
+                    .collect();
+                out += &Value::Object(object).to_string();
+                out += "\n";
+            }
+        }
+    }
+    out
+}
+
+/// Exports a page of the issued certificates.
+pub async fn export(
+    uri: Uri,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Response, StatusCode> {
+    admin::authorize(&state, auth, true).await?;
+    let query = Query::parse(uri.query()).ok_or(StatusCode::BAD_REQUEST)?;
+
+    let (since, serial) = &query.after;
+    let page = state
+        .store
+        .inventory((*since, serial), query.limit)
+        .await
+        .map_err(|e| {
+            debug!("failed to read issued certificates: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let content_type = match query.format {
+        Format::Csv => "text/csv",
+        Format::Jsonl => "application/jsonl",
+    };
+    let mut rsp = ([(CONTENT_TYPE, content_type)], render(&query, &page)).into_response();
+    if let Some(last) = page.last().filter(|_| page.len() == query.limit) {
+        let cursor = encode(last)
+            .parse()
+            .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+        rsp.headers_mut().insert(CURSOR_HEADER, cursor);
+    }
+    Ok(rsp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::admin::Auditor;
+    use super::super::operations;
+    use super::*;
+
+    use http::header::AUTHORIZATION;
+    use http::Request;
+    use hyper::Body;
+    use tower::ServiceExt; // for `app.oneshot()`
+
+    fn issued(serial: u8) -> Issued {
+        Issued {
+            serial: vec![serial],
+            not_before: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            not_after: SystemTime::now() + Duration::from_secs(60),
+            der: vec![],
+            rekor_index: None,
+            policy_version: 1,
+            platform: None,
+            tenant: None,
+        }
+    }
+
+    #[test]
+    fn query() {
+        let default = Query::parse(None).unwrap();
+        assert_eq!(default.format, Format::Jsonl);
+        assert_eq!(default.fields, DEFAULT_FIELDS);
+        assert_eq!(default.limit, DEFAULT_LIMIT);
+
+        let query = Query::parse(Some("format=csv&fields=serial,der&since=60&limit=5")).unwrap();
+        assert_eq!(query.format, Format::Csv);
+        assert_eq!(query.fields, ["serial", "der"]);
+        assert_eq!(query.after.0, UNIX_EPOCH + Duration::from_secs(60));
+        assert_eq!(query.limit, 5);
+
+        let cursor = encode(&issued(7));
+        let query = Query::parse(Some(&format!("since=60&cursor={cursor}"))).unwrap();
+        assert_eq!(query.after, (issued(7).not_before, vec![7]));
+
+        for bad in [
+            "format=xml",
+            "fields=serial,password",
+            "since=yesterday",
+            "limit=0",
+            "limit=10001",
+            "cursor=42",
+            "verbose",
+        ] {
+            assert_eq!(Query::parse(Some(bad)), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn csv() {
+        let query = Query::parse(Some("format=csv&fields=serial,not_before,rekor_index")).unwrap();
+        let rendered = render(&query, &[issued(1)]);
+        assert_eq!(rendered, "serial,not_before,rekor_index\n01,1700000000,\n");
+
+        assert_eq!(cell(&"a,\"b\"".into()), "\"a,\"\"b\"\"\"");
+    }
+
+    #[tokio::test]
+    async fn export() {
+        let state = State::generate(None, "localhost")
+            .unwrap()
+            .with_auditor(Auditor::new("auditor").unwrap());
+        for serial in 1..=3 {
+            let now = state.clock.now();
+            state.store.issue(&issued(serial), now).await.unwrap();
+        }
+
+        let get = |uri: String, token: Option<&'static str>| {
+            let state = state.clone();
+            async move {
+                let mut request = Request::get(uri);
+                if let Some(token) = token {
+                    request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+                }
+                operations(state)
+                    .oneshot(request.body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let rsp = get(
+            "/admin/certs/export?limit=2&fields=serial".into(),
+            Some("auditor"),
+        )
+        .await;
+        assert_eq!(rsp.status(), StatusCode::OK);
+        let cursor = rsp.headers()[CURSOR_HEADER].to_str().unwrap().to_string();
+        let body = hyper::body::to_bytes(rsp.into_body()).await.unwrap();
+        assert_eq!(body, "{\"serial\":\"01\"}\n{\"serial\":\"02\"}\n");
+
+        let uri = format!("/admin/certs/export?limit=2&fields=serial&cursor={cursor}");
+        let rsp = get(uri, Some("auditor")).await;
+        assert!(rsp.headers().get(CURSOR_HEADER).is_none());
+        let body = hyper::body::to_bytes(rsp.into_body()).await.unwrap();
+        assert_eq!(body, "{\"serial\":\"03\"}\n");
+
+        let rsp = get("/admin/certs/export".into(), None).await;
+        assert_eq!(rsp.status(), StatusCode::UNAUTHORIZED);
+        let rsp = get("/admin/certs/export?format=xml".into(), Some("auditor")).await;
+        assert_eq!(rsp.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,303 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! A signer for Kubernetes `CertificateSigningRequest` objects.
+//!
+//! Steward polls the API server for approved requests naming its signer,
+//! appraises the evidence they carry and writes the issued certificate chain
+//! to `.status.certificate`. Requests which fail appraisal are marked with a
+//! `Failed` condition.
+//!
+//! Evidence is normally embedded in the request, exactly as for requests
+//! posted to steward directly. Tools which cannot add extensions instead put
+//! a base64 DER attested request in the [`EVIDENCE`] annotation; its public
+//! key must match that of the Kubernetes request.
+//!
+//! The service account needs `get` and `list` on `certificatesigningrequests`,
+//! `update` on `certificatesigningrequests/status` and `sign` on the `signers`
+//! resource named by the signer.
+
+use super::scheduler::Scheduler;
+use super::{attest_request, normalized, sans, Outcome, State};
+
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use attestation::parse;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use der::pem::{self, LineEnding};
+use der::Decode;
+use hyper::StatusCode;
+use sec1::pkcs8::PrivateKeyInfo;
+use serde_json::{json, Value};
+use tracing::{debug, info, warn};
+use x509::Certificate;
+use zeroize::Zeroizing;
+
+/// The annotation carrying an attested request.
+pub const EVIDENCE: &str = "steward.profian.com/evidence";
+
+const TOKEN: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+const CA: &str = "/var/run/secrets/kubernetes.io/serviceaccount/ca.crt";
+const CSRS: &str = "/apis/certificates.k8s.io/v1/certificatesigningrequests";
+
+/// A Kubernetes API client signing requests for one signer name.
+#[derive(Clone, Debug)]
+pub struct Signer {
+    url: String,
+    name: String,
+    client: reqwest::Client,
+}
+
+impl Signer {
+    /// Creates a signer using the pod's service account.
+    pub fn in_cluster(name: &str) -> Result<Self> {
+        ensure!(
+            name.contains('/'),
+            "invalid kubernetes signer name `{name}`"
+        );
+
+        let host = std::env::var("KUBERNETES_SERVICE_HOST").context("not running in kubernetes")?;
+        let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".into());
+        let host = match host.contains(':') {
+            true => format!("[{host}]"),
+            false => host,
+        };
+
+        let ca = std::fs::read(CA).context("failed to read kubernetes ca certificate")?;
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(&ca)?)
+            .timeout(Duration::from_secs(30))
+            .build()?;
+
+        Ok(Self {
+            url: format!("https://{host}:{port}"),
+            name: name.into(),
+            client,
+        })
+    }
+
+    // Service account tokens are rotated, so are read for every use.
+    fn token() -> Result<Zeroizing<String>> {
+        let token =
+            std::fs::read_to_string(TOKEN).context("failed to read service account token")?;
+        Ok(Zeroizing::new(token.trim().into()))
+    }
+
+    async fn pending(&self) -> Result<Vec<Value>> {
+        let rsp = self
+            .client
+            .get(format!("{}{CSRS}", self.url))
+            .query(&[("fieldSelector", format!("spec.signerName={}", self.name))])
+            .bearer_auth(Self::token()?.as_str())
+            .send()
+            .await
+            .context("failed to reach kubernetes")?;
+        ensure!(
+            rsp.status().is_success(),
+            "kubernetes returned {}",
+            rsp.status()
+        );
+
+        let mut list: Value = rsp.json().await?;
+        let items = match list["items"].take() {
+            Value::Array(items) => items,
+            _ => bail!("invalid kubernetes response"),
+        };
+
+        Ok(items.into_iter().filter(pending).collect())
+    }
+
+    async fn update(&self, csr: &Value) -> Result<()> {
+        let name = csr["metadata"]["name"]
+            .as_str()
+            .ok_or_else(|| anyhow!("unnamed certificate signing request"))?;
+
+        let rsp = self
+            .client
+            .put(format!("{}{CSRS}/{name}/status", self.url))
+            .bearer_auth(Self::token()?.as_str())
+            .json(csr)
+            .send()
+            .await
+            .context("failed to reach kubernetes")?;
+        ensure!(
+            rsp.status().is_success(),
+            "kubernetes returned {}",
+            rsp.status()
+        );
+        Ok(())
+    }
+
+    /// Adds a task signing pending requests.
+    pub fn schedule(self, state: &State, scheduler: Scheduler) -> Scheduler {
+        let state = state.clone();
+        scheduler.every(
+            "kubernetes",
+            Duration::from_secs(5),
+            Duration::from_secs(1),
+            move || {
+                let (signer, state) = (self.clone(), state.clone());
+                async move {
+                    for mut csr in signer.pending().await? {
+                        let name = csr["metadata"]["name"].as_str().unwrap_or("").to_string();
+                        match sign(&state, &csr).await {
+                            Ok(chain) => {
+                                csr["status"]["certificate"] = BASE64.encode(chain).into();
+                                info!("signed kubernetes certificate signing request {name}");
+                            }
+                            Err(e) => {
+                                warn!("failed to sign kubernetes request {name}: {e:#}");
+                                fail(&mut csr, &e.to_string());
+                            }
+                        }
+                        signer.update(&csr).await?;
+                    }
+                    Ok(())
+                }
+            },
+        )
+    }
+}
+
+/// Whether an object is approved, but not yet signed or failed.
+fn pending(csr: &Value) -> bool {
+    let status = &csr["status"];
+    if status["certificate"]
+        .as_str()
+        .map_or(false, |c| !c.is_empty())
+    {
+        return false;
+    }
+
+    let conditions = status["conditions"].as_array().map(Vec::as_slice);
+    let has = |kind: &str| {
+        conditions
+            .unwrap_or_default()
+            .iter()
+            .any(|c| c["type"] == kind && c["status"] != "False")
+    };
+
+    has("Approved") && !has("Denied") && !has("Failed")
+}
+
+/// Marks an object as failed.
+fn fail(csr: &mut Value, message: &str) {
+    let condition = json!({
+        "type": "Failed",
+        "status": "True",
+        "reason": "AttestationFailed",
+        "message": message,
+    });
+
+    match csr["status"]["conditions"].as_array_mut() {
+        Some(conditions) => conditions.push(condition),
+        None => csr["status"]["conditions"] = json!([condition]),
+    }
+}
+
+/// Selects the DER request to appraise from an object.
+fn request(csr: &Value) -> Result<Vec<u8>> {
+    let spec = csr["spec"]["request"]
+        .as_str()
+        .ok_or_else(|| anyhow!("missing request"))?;
+    let spec = BASE64.decode(spec).context("invalid request encoding")?;
+    let (label, spec) = pem::decode_vec(&spec).context("invalid request pem")?;
+    ensure!(
+        label == "CERTIFICATE REQUEST",
+        "unexpected pem label `{label}`"
+    );
+
+    let evidence = match csr["metadata"]["annotations"][EVIDENCE].as_str() {
+        Some(evidence) => BASE64
+            .decode(evidence)
+            .context("invalid evidence encoding")?,
+        None => return Ok(spec),
+    };
+
+    let outer = parse::cert_req(&spec)?;
+    let inner = parse::cert_req(&evidence).context("invalid evidence request")?;
+    ensure!(
+        outer.info.public_key == inner.info.public_key,
+        "evidence is for a different key"
+    );
+    Ok(evidence)
+}
+
+/// Appraises an object's request, returning the PEM certificate chain.
+async fn sign(state: &State, csr: &Value) -> Result<String> {
+    let der = normalized(state.policy().config.parsing, &request(csr)?)?;
+    let cr = parse::cert_req(&der)?;
+
+    let status = |code: StatusCode| anyhow!("appraisal failed: {code}");
+    let issuer = Certificate::from_der(&state.crt)?;
+    let isskey = PrivateKeyInfo::from_der(&state.key)?;
+    let sans = sans(state).map_err(status)?;
+    let crt = match attest_request(&issuer, &isskey, sans, cr, None, state)
+        .await
+        .map_err(status)?
+    {
+        Outcome::Issued(crt, ..) => crt,
+        Outcome::Parked(id) => bail!("request {id} awaits approval"),
+    };
+    debug!("issued certificate for kubernetes request");
+
+    let mut chain = String::new();
+    for der in [&crt, &state.crt] {
+        chain += &pem::encode_string("CERTIFICATE", LineEnding::LF, der)?;
+    }
+    Ok(chain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CSR: &[u8] = include_bytes!("../../../testdata/openssl.csr");
+
+    fn object(annotation: Option<&[u8]>) -> Value {
+        let pem = pem::encode_string("CERTIFICATE REQUEST", LineEnding::LF, CSR).unwrap();
+        let mut csr = json!({
+            "metadata": { "name": "workload" },
+            "spec": {
+                "request": BASE64.encode(pem),
+                "signerName": "steward.profian.com/attested",
+            },
+            "status": {
+                "conditions": [{ "type": "Approved", "status": "True" }],
+            },
+        });
+        if let Some(annotation) = annotation {
+            csr["metadata"]["annotations"][EVIDENCE] = BASE64.encode(annotation).into();
+        }
+        csr
+    }
+
+    #[test]
+    fn pending() {
+        let mut csr = object(None);
+        assert!(super::pending(&csr));
+
+        fail(&mut csr, "no");
+        assert!(!super::pending(&csr));
+
+        let mut csr = object(None);
+        csr["status"]["certificate"] = "Y2VydA==".into();
+        assert!(!super::pending(&csr));
+
+        let mut csr = object(None);
+        csr["status"]["conditions"] = json!([]);
+        assert!(!super::pending(&csr));
+    }
+
+    #[test]
+    fn request() {
+        assert_eq!(super::request(&object(None)).unwrap(), CSR);
+        assert_eq!(super::request(&object(Some(CSR))).unwrap(), CSR);
+
+        // The evidence must be for the same key.
+        let other = include_bytes!("../../../crates/attestation/src/sgx/icelake.signed.crl.csr");
+        assert!(super::request(&object(Some(other))).is_err());
+    }
+}
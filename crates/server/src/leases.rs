@@ -0,0 +1,248 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Certificates held as leases, which lapse unless they are renewed.
+//!
+//! With a `[leases]` section in the policy, every certificate issued is a
+//! lease on its key, which the workload renews by asking for another
+//! certificate for the same key within `renew_by` percent of the lifetime.
+//! Leases which are not renewed in time are recorded as lapsed and, if
+//! `revoke` is set, revoked as `cessationOfOperation`, so that relying
+//! parties find them on the CRL long before they expire:
+//!
+//! ```toml
+//! [leases]
+//! renew_by = 50
+//! revoke = true
+//! ```
+//!
+//! Workloads are told to renew two thirds of the way to the deadline. Leases
+//! are kept in the store, so each lapse is handled by one replica sharing it.
+
+use super::identities;
+use super::scheduler::Scheduler;
+use super::store::{AuditRecord, Issued, Lease, Revocation};
+use super::State;
+
+use std::time::{Duration, SystemTime};
+
+use anyhow::{ensure, Result};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::info;
+use x509::time::Validity;
+
+/// The `CRLReason` of revoked leases.
+const CESSATION_OF_OPERATION: u8 = 5;
+
+/// How often leases are checked for lapses.
+const INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    /// The percentage of its lifetime within which a certificate must be
+    /// renewed.
+    pub renew_by: u8,
+
+    /// Whether to revoke the certificates whose leases lapse.
+    #[serde(default)]
+    pub revoke: bool,
+}
+
+impl Policy {
+    /// Rejects deadlines which are not within the lifetime.
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            (1..100).contains(&self.renew_by),
+            "renew_by must be a percentage between 1 and 99"
+        );
+        Ok(())
+    }
+
+    /// When a certificate valid from `start` until `end` must be renewed by.
+    fn deadline(&self, start: SystemTime, end: SystemTime) -> SystemTime {
+        let lifetime = end.duration_since(start).unwrap_or_default();
+        start + lifetime * u32::from(self.renew_by) / 100
+    }
+
+    /// When a workload should renew a certificate valid for `validity`.
+    pub fn renew_after(&self, validity: &Validity) -> SystemTime {
+        let start = validity.not_before.to_system_time();
+        let deadline = self.deadline(start, validity.not_after.to_system_time());
+        start + deadline.duration_since(start).unwrap_or_default() * 2 / 3
+    }
+
+    /// The lease which `issued` grants.
+    fn lease(&self, issued: &Issued) -> Result<Lease> {
+        Ok(Lease {
+            key: identities::key(&issued.der)?.to_vec(),
+            serial: issued.serial.clone(),
+            renew_by: self.deadline(issued.not_before, issued.not_after),
+        })
+    }
+}
+
+/// Grants the lease on a newly issued certificate, renewing any held on its
+/// key, if the policy treats certificates as leases.
+pub(crate) async fn grant(state: &State, issued: &Issued) -> Result<()> {
+    let policy = state.policy();
+    if let Some(leases) = &policy.config.leases {
+        state.store.grant(&leases.lease(issued)?).await?;
+    }
+    Ok(())
+}
+
+/// Records the leases which were not renewed in time as lapsed, revoking
+/// their certificates if the policy says to.
+pub(crate) async fn lapse(state: &State) -> Result<()> {
+    let now = state.clock.now();
+    let revoke = state
+        .policy()
+        .config
+        .leases
+        .as_ref()
+        .map_or(false, |leases| leases.revoke);
+
+    for lease in state.store.lapse(now).await? {
+        let serial = hex::encode(&lease.serial);
+        let revoked = match revoke {
+            false => false,
+            true => {
+                let revocation = Revocation {
+                    serial: lease.serial.clone(),
+                    revoked_at: now,
+                    reason: Some(CESSATION_OF_OPERATION),
+                };
+                state.store.revoke(&revocation).await?
+            }
+        };
+
+        let record = AuditRecord {
+            at: now,
+            event: "lapse".into(),
+            detail: match revoked {
+                false => format!("lease on {serial} lapsed"),
+                true => format!("lease on {serial} lapsed, revoked"),
+            },
+        };
+        state.store.audit(&record).await?;
+        state.notify(
+            &record.event,
+            json!({ "serial": serial, "revoked": revoked }),
+        );
+        info!("{}", record.detail);
+    }
+
+    Ok(())
+}
+
+/// Adds a task which checks for lapsed leases.
+pub(crate) fn schedule(state: &State, scheduler: Scheduler) -> Scheduler {
+    let state = state.clone();
+    scheduler.every("lease-lapse", INTERVAL, INTERVAL / 6, move || {
+        let state = state.clone();
+        async move { lapse(&state).await }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::clock::Manual;
+    use super::*;
+
+    use std::sync::Arc;
+
+    use x509::time::Time;
+
+    #[test]
+    fn policy() {
+        let policy: Policy = toml::from_str("renew_by = 50\nrevoke = true").unwrap();
+        policy.validate().unwrap();
+
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let validity = Validity {
+            not_before: Time::try_from(start).unwrap(),
+            not_after: Time::try_from(start + Duration::from_secs(3600)).unwrap(),
+        };
+        let end = validity.not_after.to_system_time();
+        assert_eq!(
+            policy.deadline(start, end),
+            start + Duration::from_secs(1800)
+        );
+        assert_eq!(
+            policy.renew_after(&validity),
+            start + Duration::from_secs(1200)
+        );
+
+        for renew_by in [0, 100] {
+            let policy = Policy {
+                renew_by,
+                revoke: false,
+            };
+            assert!(policy.validate().is_err(), "{renew_by}");
+        }
+    }
+
+    #[tokio::test]
+    async fn lapsed() {
+        let now = SystemTime::now();
+        let clock = Arc::new(Manual::new(now));
+        let mut state = State::generate(None, "localhost")
+            .unwrap()
+            .with_clock(clock.clone());
+        state.config_mut().leases = Some(Policy {
+            renew_by: 50,
+            revoke: true,
+        });
+
+        let issued = |serial: u8| Issued {
+            serial: vec![serial],
+            not_before: now,
+            not_after: now + Duration::from_secs(3600),
+            der: state.crt.clone(),
+            rekor_index: None,
+            policy_version: 0,
+            platform: None,
+            tenant: None,
+        };
+        for serial in [1, 2] {
+            state.store.issue(&issued(serial), now).await.unwrap();
+        }
+        grant(&state, &issued(1)).await.unwrap();
+        lapse(&state).await.unwrap();
+        assert!(state
+            .store
+            .revocations(clock.now())
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Renewing for the same key moves the deadline to the new certificate.
+        clock.advance(Duration::from_secs(1200));
+        let renewal = Issued {
+            not_before: now + Duration::from_secs(1200),
+            ..issued(2)
+        };
+        grant(&state, &renewal).await.unwrap();
+        clock.advance(Duration::from_secs(900));
+        lapse(&state).await.unwrap();
+        assert!(state
+            .store
+            .revocations(clock.now())
+            .await
+            .unwrap()
+            .is_empty());
+
+        clock.advance(Duration::from_secs(600));
+        lapse(&state).await.unwrap();
+        let revocations = state.store.revocations(clock.now()).await.unwrap();
+        assert_eq!(revocations.len(), 1);
+        assert_eq!(revocations[0].serial, [2]);
+        assert_eq!(revocations[0].reason, Some(CESSATION_OF_OPERATION));
+
+        // A lapse is only handled once.
+        lapse(&state).await.unwrap();
+        assert_eq!(state.store.revocations(clock.now()).await.unwrap().len(), 1);
+    }
+}
@@ -3,35 +3,145 @@
 
 #![warn(rust_2018_idioms, unused_lifetimes, unused_qualifications, clippy::all)]
 
+#[cfg(not(any(
+    feature = "cove",
+    feature = "kvm",
+    feature = "se",
+    feature = "sgx",
+    feature = "snp"
+)))]
+compile_error!("at least one attestation platform feature must be enabled");
+
+pub mod admin;
+pub mod approvals;
+pub mod archive;
+mod asn1;
+pub mod attributes;
+pub mod audit;
+#[cfg(all(feature = "bench", not(target_os = "wasi")))]
+pub mod bench;
+pub mod bundles;
+pub mod cache;
+pub mod capabilities;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod claims;
+pub mod clock;
+pub mod cmp;
+#[cfg(all(feature = "collateral", not(target_os = "wasi")))]
+pub mod collateral;
+pub mod corpus;
+pub mod correlation;
+pub mod cors;
+pub mod crl;
+pub mod endorsements;
+pub mod extensions;
+#[cfg(all(feature = "fulcio", not(target_os = "wasi")))]
+pub mod fulcio;
+pub mod identities;
+pub mod inventory;
+pub mod key;
+#[cfg(all(feature = "kubernetes", not(target_os = "wasi")))]
+pub mod kubernetes;
+#[cfg(feature = "kvm")]
 mod kvm;
+pub mod leases;
+#[cfg(not(target_os = "wasi"))]
+pub mod listener;
+pub mod logging;
+pub mod metrics;
+#[cfg(all(test, not(target_os = "wasi")))]
+mod mock;
+pub mod platforms;
+pub mod policy;
+pub mod profiles;
+pub mod proxy;
+pub mod quota;
+pub mod readiness;
+pub mod rejections;
+#[cfg(all(feature = "rekor", not(target_os = "wasi")))]
+pub mod rekor;
+pub mod scep;
+pub mod scheduler;
+pub mod serials;
+pub mod shared;
+#[cfg(all(test, not(target_os = "wasi")))]
+mod snapshots;
+pub mod source;
+#[cfg(all(feature = "spire", not(target_os = "wasi")))]
+pub mod spire;
+pub mod stats;
+pub mod store;
+pub mod tofu;
+pub mod tokens;
+pub mod transparency;
+pub mod tsa;
+pub mod verifier;
+#[cfg(all(feature = "webhook", not(target_os = "wasi")))]
+pub mod webhook;
 
+use archive::{Archive, Evidence};
+#[cfg(feature = "cove")]
+use attestation::cove::Cove;
 use attestation::crypto::{CertReqExt, PrivateKeyInfoExt, TbsCertificateExt};
+use attestation::parse::{self, Limits, Strictness};
+#[cfg(feature = "se")]
+use attestation::se::Se;
+#[cfg(feature = "sgx")]
 use attestation::sgx::Sgx;
+#[cfg(feature = "snp")]
 use attestation::snp::Snp;
+use cache::AppraisalCache;
+use clock::Clock;
+use cors::Cors;
+use key::Key;
+#[cfg(feature = "kvm")]
 use kvm::Kvm;
+use policy::Policy;
+use proxy::{Peer, Trusted};
+use rejections::{Rejection, Rejections};
+use scheduler::Scheduler;
+use serials::Serials;
+use shared::Shared;
+use store::{Issued, Parked, Store};
+use transparency::Log;
+use verifier::{Appraisal, VerifierRegistry};
 
+use std::collections::BTreeMap;
 use std::io::BufRead;
-use std::path::Path;
-use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
-use anyhow::{anyhow, Context};
+use anyhow::{anyhow, bail, ensure, Context};
 use axum::body::Bytes;
-use axum::extract::{Extension, TypedHeader};
-use axum::headers::ContentType;
-use axum::response::IntoResponse;
+use axum::extract::{ConnectInfo, Extension, TypedHeader};
+use axum::headers::{ContentType, ETag, HeaderMapExt, IfModifiedSince, IfNoneMatch, LastModified};
+use axum::http::header::{ACCEPT, ALLOW, CONTENT_TYPE, LINK};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Uri};
+use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
 use axum::Router;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use const_oid::db::rfc5280::{
-    ID_CE_BASIC_CONSTRAINTS, ID_CE_EXT_KEY_USAGE, ID_CE_KEY_USAGE, ID_CE_SUBJECT_ALT_NAME,
-    ID_KP_CLIENT_AUTH, ID_KP_SERVER_AUTH,
+    ID_CE_BASIC_CONSTRAINTS, ID_CE_CRL_DISTRIBUTION_POINTS, ID_CE_FRESHEST_CRL, ID_CE_KEY_USAGE,
+    ID_CE_NAME_CONSTRAINTS, ID_CE_SUBJECT_ALT_NAME,
 };
 use const_oid::db::rfc5912::ID_EXTENSION_REQ;
+use const_oid::ObjectIdentifier;
 use der::asn1::{GeneralizedTime, Ia5StringRef, UIntRef};
-use der::{Decode, Encode, Sequence};
+use der::{DateTime, Decode, Encode, Sequence};
 use hyper::StatusCode;
 use sec1::pkcs8::PrivateKeyInfo;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tower_http::compression::CompressionLayer;
+use tower_http::cors::CorsLayer;
+use tower_http::decompression::RequestDecompressionLayer;
+use tower_http::limit::RequestBodyLimitLayer;
 use tower_http::trace::{
     DefaultOnBodyChunk, DefaultOnEos, DefaultOnFailure, DefaultOnRequest, DefaultOnResponse,
     TraceLayer,
@@ -39,29 +149,340 @@ use tower_http::trace::{
 use tower_http::LatencyUnit;
 use tracing::{debug, Level};
 use x509::attr::Attribute;
+use x509::ext::pkix::constraints::name::GeneralSubtree;
 use x509::ext::pkix::name::GeneralName;
-use x509::ext::pkix::{BasicConstraints, ExtendedKeyUsage, KeyUsage, KeyUsages, SubjectAltName};
+use x509::ext::pkix::{BasicConstraints, KeyUsage, KeyUsages, NameConstraints, SubjectAltName};
 use x509::name::RdnSequence;
-use x509::request::{CertReq, ExtensionReq};
+use x509::request::{CertReq, CertReqInfo, ExtensionReq};
 use x509::time::{Time, Validity};
 use x509::{Certificate, TbsCertificate};
 use zeroize::Zeroizing;
 
+/// The DNS name placed in every issued certificate.
+const DEFAULT_SAN: &str = "foo.bar.hub.profian.com";
+
+/// The lifetime of issued certificates, unless a validity profile says
+/// otherwise or `--max-leaf-ttl` caps it.
+const LEAF_TTL: Duration = Duration::from_secs(60 * 60 * 24 * 28);
+
+/// The default limit on request bodies, after decompression.
+pub const BODY_LIMIT: usize = 1 << 20;
+
 pub const PKCS10: &str = "application/pkcs10";
+
+pub const PKIX_CERT: &str = "application/pkix-cert";
+
+/// The header with which EST (RFC 7030) marks base64 bodies.
+pub const CONTENT_TRANSFER_ENCODING: &str = "content-transfer-encoding";
+
+/// The hex serial numbers of the issued certificates, comma separated.
+pub const SERIAL_HEADER: &str = "x-steward-serial";
+
+/// When the issued certificates expire, in RFC 3339 format.
+pub const NOT_AFTER_HEADER: &str = "x-steward-not-after";
+
+/// When clients should renew the issued certificates, in RFC 3339 format.
+pub const RENEW_AFTER_HEADER: &str = "x-steward-renew-after";
+
+/// The platforms attested for each issued certificate, comma separated in
+/// the order of the serial numbers; several for one certificate are joined
+/// with `+`.
+pub const PLATFORM_HEADER: &str = "x-steward-platform";
+
+/// Asks, with the value `appraisal`, for the appraisal of the evidence to be
+/// returned alongside the certificates, if the policy sets `verbose`. The
+/// `verbose=appraisal` query parameter does the same.
+pub const VERBOSE_HEADER: &str = "x-steward-verbose";
+
+/// Names the validity profile to issue with, if the policy permits the client
+/// to ask for it. Requests parked for approval forget it, and are issued under
+/// the profile their certificate template extension names, if any.
+pub const PROFILE_HEADER: &str = "x-steward-profile";
+
 pub const BUNDLE: &str = "application/vnd.steward.pkcs10-bundle.v1";
 
 #[derive(Clone, Deserialize, Debug, Default, Eq, PartialEq)]
 pub struct Config {
+    #[cfg(feature = "sgx")]
     pub sgx: Option<attestation::sgx::config::Config>,
+    #[cfg(not(feature = "sgx"))]
+    pub sgx: Option<Unsupported>,
+
+    #[cfg(feature = "snp")]
     pub snp: Option<attestation::snp::config::Config>,
+    #[cfg(not(feature = "snp"))]
+    pub snp: Option<Unsupported>,
+
+    #[cfg(feature = "se")]
+    pub se: Option<attestation::se::config::Config>,
+    #[cfg(not(feature = "se"))]
+    pub se: Option<Unsupported>,
+
+    #[cfg(feature = "cove")]
+    pub cove: Option<attestation::cove::config::Config>,
+    #[cfg(not(feature = "cove"))]
+    pub cove: Option<Unsupported>,
+
+    /// The maximum age, in seconds, of evidence which carries a timestamp.
+    pub max_evidence_age: Option<u64>,
+
+    /// How strictly certification requests are held to DER: `der`,
+    /// `strict` or `compat`, wherever they are received.
+    #[serde(default)]
+    pub parsing: Strictness,
+
+    #[serde(default)]
+    pub duplicates: extensions::Duplicates,
+
+    #[serde(default)]
+    pub attributes: attributes::Policy,
+
+    #[serde(default)]
+    pub platforms: platforms::Policy,
+
+    #[serde(default)]
+    pub extensions: extensions::Policy,
+
+    /// Caps on issuance per chip, measurement or signer.
+    #[serde(default)]
+    pub quotas: quota::Policy,
+
+    /// Whether certificates are leases which lapse unless renewed in time.
+    pub leases: Option<leases::Policy>,
+
+    /// What to do when a renewal attests other measurements than the first
+    /// issuance for its key: `allow`, `alert` or `deny`.
+    #[serde(default)]
+    pub measurement_changes: identities::Changes,
+
+    /// Whether hosts without a TEE may enroll with one-time registration
+    /// tokens instead of evidence.
+    pub tokens: Option<tokens::Policy>,
+
+    /// Whether requests attesting measurements which no operator approved
+    /// are parked for approval.
+    pub approvals: Option<approvals::Policy>,
+
+    /// Whether measurements are trusted on first use, and pinned to the
+    /// workload which first attested them.
+    pub tofu: Option<tofu::Policy>,
+
+    /// How long certificates are valid, by platform and measurement.
+    #[serde(default)]
+    pub validity: profiles::Policy,
+
+    /// The management enclaves whose operators may use the admin API.
+    #[serde(default)]
+    pub admin: admin::Policy,
+
+    /// Whether clients may ask for the appraisal of their evidence, for
+    /// debugging. See [`VERBOSE_HEADER`].
+    #[serde(default)]
+    pub verbose: bool,
+
+    /// Whether to leave the self-signed root out of the chains returned with
+    /// issued certificates, unless clients ask for it. See [`root`].
+    #[serde(default)]
+    pub exclude_root: bool,
+
+    /// The device classes which may enroll over SCEP without attestation.
+    #[serde(default)]
+    pub scep: scep::Policy,
+
+    /// The OIDC issuers whose tokens are exchanged for signing certificates.
+    #[cfg(all(feature = "fulcio", not(target_os = "wasi")))]
+    pub fulcio: Option<fulcio::Policy>,
+    #[cfg(not(all(feature = "fulcio", not(target_os = "wasi"))))]
+    pub fulcio: Option<Unsupported>,
+}
+
+impl Config {
+    /// Checks every section, reporting all of their problems at once.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let problems: Vec<_> = [
+            ("extensions", self.extensions.validate()),
+            ("platforms", self.platforms.validate()),
+            ("quotas", self.quotas.validate()),
+            (
+                "leases",
+                self.leases.as_ref().map_or(Ok(()), |l| l.validate()),
+            ),
+            (
+                "tokens",
+                self.tokens.as_ref().map_or(Ok(()), |t| t.validate()),
+            ),
+            (
+                "approvals",
+                self.approvals.as_ref().map_or(Ok(()), |a| a.validate()),
+            ),
+            ("tofu", self.tofu.as_ref().map_or(Ok(()), |t| t.validate())),
+            ("validity", self.validity.validate()),
+            ("admin", self.admin.validate()),
+            ("scep", self.scep.validate()),
+            #[cfg(all(feature = "fulcio", not(target_os = "wasi")))]
+            (
+                "fulcio",
+                self.fulcio.as_ref().map_or(Ok(()), |f| f.validate()),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(section, result)| Some(format!("[{section}]: {:#}", result.err()?)))
+        .collect();
+
+        ensure!(
+            problems.is_empty(),
+            "invalid config:\n  {}",
+            problems.join("\n  ")
+        );
+        Ok(())
+    }
+}
+
+/// The configuration of a platform or feature which this build lacks.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Unsupported(());
+
+impl<'de> Deserialize<'de> for Unsupported {
+    fn deserialize<D: serde::Deserializer<'de>>(_: D) -> Result<Self, D::Error> {
+        Err(serde::de::Error::custom("not supported by this build"))
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct State {
-    key: Zeroizing<Vec<u8>>,
+    key: Key,
     pub crt: Vec<u8>,
     san: Option<String>,
-    config: Config,
+    policy: Arc<RwLock<Arc<Policy>>>,
+    policy_path: Option<PathBuf>,
+    policy_watch: Option<Duration>,
+    cache: AppraisalCache,
+    shared: Arc<dyn Shared>,
+    store: Arc<dyn Store>,
+    anchors: Arc<endorsements::Anchors>,
+    archive: Option<Archive>,
+    auditor: Option<admin::Auditor>,
+    approvers: Option<Arc<approvals::Approvers>>,
+    log: Arc<Log>,
+    proxies: Arc<Trusted>,
+    cors: Option<CorsLayer>,
+    body_limit: usize,
+    max_leaf_ttl: Option<Duration>,
+    verifiers: Arc<VerifierRegistry>,
+    rejections: Arc<Rejections>,
+    clock: Arc<dyn Clock>,
+    serials: Arc<dyn Serials>,
+    #[cfg(all(feature = "collateral", not(target_os = "wasi")))]
+    collateral: Option<Arc<collateral::Collateral>>,
+    audit: Vec<Arc<dyn audit::Sink>>,
+    crl_signer: Option<crl::Delegate>,
+    crl_distribution: Option<crl::Distribution>,
+    crl_published: Arc<crl::Published>,
+    bundles: Arc<bundles::Bundles>,
+    cross: Vec<Vec<Vec<u8>>>,
+    scep: Option<scep::Agent>,
+    tsa: Option<tsa::Authority>,
+    #[cfg(all(feature = "fulcio", not(target_os = "wasi")))]
+    fulcio: Option<Arc<fulcio::Discovery>>,
+    #[cfg(feature = "chaos")]
+    faults: chaos::Faults,
+}
+
+/// Limits placed on a generated CA certificate.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Constraints {
+    /// The maximum number of intermediate CAs below the generated one.
+    pub path_len: u8,
+
+    /// Domains to which issued DNS names and URI hosts are restricted.
+    ///
+    /// When empty, no name constraints are added.
+    pub permitted: Vec<String>,
+}
+
+impl Constraints {
+    fn permits(&self, name: &str) -> bool {
+        self.permitted.is_empty()
+            || self.permitted.iter().any(|domain| {
+                let domain = domain.trim_start_matches('.');
+                name == domain
+                    || name
+                        .strip_suffix(domain)
+                        .map_or(false, |sub| sub.ends_with('.'))
+            })
+    }
+}
+
+/// The algorithm of a generated CA's key.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum KeyAlgorithm {
+    /// ECDSA on P-256.
+    #[default]
+    P256,
+
+    /// ECDSA on P-384.
+    P384,
+
+    /// ML-DSA-44.
+    #[cfg(feature = "pqc")]
+    MlDsa44,
+
+    /// ML-DSA-65.
+    #[cfg(feature = "pqc")]
+    MlDsa65,
+
+    /// ML-DSA-87.
+    #[cfg(feature = "pqc")]
+    MlDsa87,
+
+    /// ML-DSA-44 and ECDSA on P-256, as a composite which signs with both.
+    #[cfg(feature = "pqc")]
+    MlDsa44P256,
+}
+
+impl KeyAlgorithm {
+    fn oid(self) -> ObjectIdentifier {
+        #[cfg(feature = "pqc")]
+        use attestation::crypto::pqc;
+        use const_oid::db::rfc5912::{SECP_256_R_1, SECP_384_R_1};
+
+        match self {
+            Self::P256 => SECP_256_R_1,
+            Self::P384 => SECP_384_R_1,
+            #[cfg(feature = "pqc")]
+            Self::MlDsa44 => pqc::ML_DSA_44,
+            #[cfg(feature = "pqc")]
+            Self::MlDsa65 => pqc::ML_DSA_65,
+            #[cfg(feature = "pqc")]
+            Self::MlDsa87 => pqc::ML_DSA_87,
+            #[cfg(feature = "pqc")]
+            Self::MlDsa44P256 => pqc::ML_DSA_44_ECDSA_P256,
+        }
+    }
+}
+
+impl FromStr for KeyAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "p256" => Ok(Self::P256),
+            "p384" => Ok(Self::P384),
+            #[cfg(feature = "pqc")]
+            "ml-dsa-44" => Ok(Self::MlDsa44),
+            #[cfg(feature = "pqc")]
+            "ml-dsa-65" => Ok(Self::MlDsa65),
+            #[cfg(feature = "pqc")]
+            "ml-dsa-87" => Ok(Self::MlDsa87),
+            #[cfg(feature = "pqc")]
+            "ml-dsa-44-p256" => Ok(Self::MlDsa44P256),
+            #[cfg(not(feature = "pqc"))]
+            "ml-dsa-44" | "ml-dsa-65" | "ml-dsa-87" | "ml-dsa-44-p256" => {
+                bail!("built without pqc support")
+            }
+            _ => bail!("unknown key algorithm `{s}`"),
+        }
+    }
 }
 
 /// ASN.1
@@ -85,56 +506,122 @@ impl State {
         crt: impl AsRef<Path>,
         config: Option<String>,
     ) -> anyhow::Result<Self> {
-        // Load the key file.
-        let key = std::io::BufReader::new(std::fs::File::open(key)?);
+        // Load the key file, in one allocation so that it can be zeroized.
+        let key = Zeroizing::new(std::fs::read(key)?);
 
         // Load the crt file.
         let crt = std::io::BufReader::new(std::fs::File::open(crt)?);
 
-        Self::read(san, key, crt, config)
+        Self::read(san, key.as_slice(), crt, config)
     }
 
     pub fn read(
         san: Option<String>,
-        mut key: impl BufRead,
+        key: impl BufRead,
         mut crt: impl BufRead,
         config: Option<String>,
     ) -> anyhow::Result<Self> {
-        let key = match rustls_pemfile::read_one(&mut key)? {
-            Some(rustls_pemfile::Item::PKCS8Key(buf)) => Zeroizing::new(buf),
-            _ => return Err(anyhow!("invalid key file")),
-        };
+        let key = Key::read(key)?;
 
         let crt = match rustls_pemfile::read_one(&mut crt)? {
             Some(rustls_pemfile::Item::X509Certificate(buf)) => buf,
             _ => return Err(anyhow!("invalid key file")),
         };
 
-        // Validate the syntax of the files.
-        PrivateKeyInfo::from_der(key.as_ref())?;
+        // Validate the syntax of the certificate; the key already was.
         Certificate::from_der(crt.as_ref())?;
 
-        let config = if let Some(path) = config {
-            let config = std::fs::read_to_string(path).context("failed to read config file")?;
-            toml::from_str(&config).context("failed to parse config")?
-        } else {
-            Config::default()
+        let policy = match &config {
+            Some(path) => {
+                let raw = std::fs::read(path).context("failed to read config file")?;
+                Policy::parse(raw)?
+            }
+            None => Policy::default(),
         };
 
         Ok(State {
             crt,
             san,
             key,
-            config,
+            policy: Arc::new(RwLock::new(Arc::new(policy))),
+            policy_path: config.map(PathBuf::from),
+            policy_watch: None,
+            cache: Default::default(),
+            shared: Arc::new(shared::Memory::default()),
+            store: Arc::new(store::Memory::default()),
+            anchors: Default::default(),
+            archive: None,
+            auditor: None,
+            approvers: None,
+            log: Default::default(),
+            proxies: Default::default(),
+            cors: None,
+            body_limit: BODY_LIMIT,
+            max_leaf_ttl: None,
+            verifiers: Default::default(),
+            rejections: Default::default(),
+            clock: Arc::new(clock::System),
+            serials: Arc::new(serials::Random),
+            #[cfg(all(feature = "collateral", not(target_os = "wasi")))]
+            collateral: None,
+            audit: Vec::new(),
+            crl_signer: None,
+            crl_distribution: None,
+            crl_published: Default::default(),
+            bundles: Default::default(),
+            scep: None,
+            tsa: None,
+            #[cfg(all(feature = "fulcio", not(target_os = "wasi")))]
+            fulcio: None,
+            #[cfg(feature = "chaos")]
+            faults: Default::default(),
+            cross: Vec::new(),
         })
     }
 
     pub fn generate(san: Option<String>, hostname: &str) -> anyhow::Result<Self> {
-        use const_oid::db::rfc5912::SECP_256_R_1 as P256;
+        Self::generate_constrained(san, hostname, &Constraints::default())
+    }
+
+    /// Generates a self-signed CA whose blast radius is bounded by `constraints`.
+    pub fn generate_constrained(
+        san: Option<String>,
+        hostname: &str,
+        constraints: &Constraints,
+    ) -> anyhow::Result<Self> {
+        Self::generate_with(san, hostname, constraints, Arc::new(clock::System))
+    }
+
+    /// Generates a self-signed CA as of, and thereafter keeping time by, `clock`.
+    pub fn generate_with(
+        san: Option<String>,
+        hostname: &str,
+        constraints: &Constraints,
+        clock: Arc<dyn Clock>,
+    ) -> anyhow::Result<Self> {
+        Self::generate_as(san, hostname, constraints, KeyAlgorithm::default(), clock)
+    }
+
+    /// Generates a self-signed CA as `generate_with()` does, with a key of
+    /// `algorithm`.
+    pub fn generate_as(
+        san: Option<String>,
+        hostname: &str,
+        constraints: &Constraints,
+        algorithm: KeyAlgorithm,
+        clock: Arc<dyn Clock>,
+    ) -> anyhow::Result<Self> {
+        // Refuse constraints which the certificates we issue would violate.
+        for name in std::iter::once(DEFAULT_SAN).chain(san.as_deref()) {
+            ensure!(
+                constraints.permits(name),
+                "issued name `{name}` is outside the permitted domains"
+            );
+        }
 
         // Generate the private key.
-        let key = PrivateKeyInfo::generate(P256)?;
-        let pki = PrivateKeyInfo::from_der(key.as_ref())?;
+        let key = Key::try_from(PrivateKeyInfo::generate(algorithm.oid())?)?;
+        let pki = PrivateKeyInfo::from_der(&key)?;
 
         // Create a relative distinguished name.
         let rdns = RdnSequence::encode_from_string(&format!("CN={hostname}"))?;
@@ -144,12 +631,61 @@ impl State {
         let ku = KeyUsage(KeyUsages::KeyCertSign.into()).to_vec()?;
         let bc = BasicConstraints {
             ca: true,
-            path_len_constraint: Some(0),
+            path_len_constraint: Some(constraints.path_len),
+        }
+        .to_vec()?;
+
+        // Permit DNS names within each domain, and URIs on it or its subdomains.
+        let domains: Vec<_> = constraints
+            .permitted
+            .iter()
+            .map(|d| d.trim_start_matches('.').to_string())
+            .collect();
+        let subdomains: Vec<_> = domains.iter().map(|d| format!(".{d}")).collect();
+        let mut permitted = Vec::new();
+        for (domain, subdomain) in domains.iter().zip(&subdomains) {
+            let domain = Ia5StringRef::new(domain)?;
+            let subdomain = Ia5StringRef::new(subdomain)?;
+            for base in [
+                GeneralName::DnsName(domain),
+                GeneralName::UniformResourceIdentifier(domain),
+                GeneralName::UniformResourceIdentifier(subdomain),
+            ] {
+                permitted.push(GeneralSubtree {
+                    base,
+                    minimum: 0,
+                    maximum: None,
+                });
+            }
+        }
+        let nc = NameConstraints {
+            permitted_subtrees: Some(permitted),
+            excluded_subtrees: None,
         }
         .to_vec()?;
 
+        let mut extensions = vec![
+            x509::ext::Extension {
+                extn_id: ID_CE_KEY_USAGE,
+                critical: true,
+                extn_value: &ku,
+            },
+            x509::ext::Extension {
+                extn_id: ID_CE_BASIC_CONSTRAINTS,
+                critical: true,
+                extn_value: &bc,
+            },
+        ];
+        if !constraints.permitted.is_empty() {
+            extensions.push(x509::ext::Extension {
+                extn_id: ID_CE_NAME_CONSTRAINTS,
+                critical: true,
+                extn_value: &nc,
+            });
+        }
+
         // Create the certificate duration.
-        let now = SystemTime::now();
+        let now = clock.now();
         let dur = Duration::from_secs(60 * 60 * 24 * 365);
         let validity = Validity {
             not_before: Time::GeneralTime(GeneralizedTime::from_system_time(now)?),
@@ -167,18 +703,7 @@ impl State {
             subject_public_key_info: pki.public_key()?,
             issuer_unique_id: None,
             subject_unique_id: None,
-            extensions: Some(vec![
-                x509::ext::Extension {
-                    extn_id: ID_CE_KEY_USAGE,
-                    critical: true,
-                    extn_value: &ku,
-                },
-                x509::ext::Extension {
-                    extn_id: ID_CE_BASIC_CONSTRAINTS,
-                    critical: true,
-                    extn_value: &bc,
-                },
-            ]),
+            extensions: Some(extensions),
         };
 
         // Self-sign the certificate.
@@ -187,37 +712,544 @@ impl State {
             key,
             crt,
             san,
-            config: Default::default(),
+            policy: Default::default(),
+            policy_path: None,
+            policy_watch: None,
+            cache: Default::default(),
+            shared: Arc::new(shared::Memory::default()),
+            store: Arc::new(store::Memory::default()),
+            anchors: Default::default(),
+            archive: None,
+            auditor: None,
+            approvers: None,
+            log: Default::default(),
+            proxies: Default::default(),
+            cors: None,
+            body_limit: BODY_LIMIT,
+            max_leaf_ttl: None,
+            verifiers: Default::default(),
+            rejections: Default::default(),
+            clock,
+            serials: Arc::new(serials::Random),
+            #[cfg(all(feature = "collateral", not(target_os = "wasi")))]
+            collateral: None,
+            audit: Vec::new(),
+            crl_signer: None,
+            crl_distribution: None,
+            crl_published: Default::default(),
+            bundles: Default::default(),
+            scep: None,
+            tsa: None,
+            #[cfg(all(feature = "fulcio", not(target_os = "wasi")))]
+            fulcio: None,
+            #[cfg(feature = "chaos")]
+            faults: Default::default(),
+            cross: Vec::new(),
         })
     }
+
+    /// Replaces the clock, e.g. to test expiry.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Replaces the source of serial numbers, e.g. for reproducible issuance.
+    pub fn with_serials(mut self, serials: Arc<dyn Serials>) -> Self {
+        self.serials = serials;
+        self
+    }
+
+    /// Replaces the appraisal cache, e.g. to change its lifetimes.
+    pub fn with_cache(mut self, cache: AppraisalCache) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Replaces the in-memory backend with one shared between replicas.
+    pub fn with_shared(mut self, shared: Arc<dyn Shared>) -> Self {
+        self.shared = shared;
+        self
+    }
+
+    /// Replaces the embedded store, e.g. with a database.
+    pub fn with_store(mut self, store: Arc<dyn Store>) -> Self {
+        self.store = store;
+        self.anchors = Default::default();
+        self
+    }
+
+    /// Believes client addresses forwarded by these proxies.
+    pub fn with_proxies(mut self, proxies: Trusted) -> Self {
+        self.proxies = Arc::new(proxies);
+        self
+    }
+
+    /// Limits the size of (decompressed) request bodies.
+    pub fn with_body_limit(mut self, limit: usize) -> Self {
+        self.body_limit = limit;
+        self
+    }
+
+    /// Keeps the last `kept` rejections for `GET /admin/rejections`.
+    pub fn with_rejections(mut self, kept: usize) -> Self {
+        self.rejections = Arc::new(Rejections::new(kept));
+        self
+    }
+
+    /// Caps the lifetime of every issued certificate, however it is requested.
+    pub fn with_max_leaf_ttl(mut self, ttl: Duration) -> Self {
+        self.max_leaf_ttl = Some(ttl);
+        self
+    }
+
+    /// Replaces the verifiers of evidence, e.g. to add a platform.
+    pub fn with_verifiers(mut self, verifiers: VerifierRegistry) -> Self {
+        self.verifiers = Arc::new(verifiers);
+        self
+    }
+
+    /// The lifetime of a certificate for which `requested` was asked.
+    fn leaf_ttl(&self, requested: Duration) -> Duration {
+        self.max_leaf_ttl
+            .map_or(requested, |max| requested.min(max))
+    }
+
+    /// Completes the collateral sent by clients with that fetched upstream.
+    #[cfg(all(feature = "collateral", not(target_os = "wasi")))]
+    pub fn with_collateral(mut self, collateral: collateral::Collateral) -> Self {
+        self.collateral = Some(Arc::new(collateral));
+        self
+    }
+
+    /// Exchanges OIDC identity tokens from the issuers of the policy for
+    /// signing certificates, fetching their keys through `discovery`.
+    #[cfg(all(feature = "fulcio", not(target_os = "wasi")))]
+    pub fn with_fulcio(mut self, discovery: fulcio::Discovery) -> Self {
+        self.fulcio = Some(Arc::new(discovery));
+        self
+    }
+
+    /// Injects `faults`, for soak and chaos testing.
+    #[cfg(feature = "chaos")]
+    pub fn with_faults(mut self, faults: chaos::Faults) -> Self {
+        self.faults = faults;
+        self
+    }
+
+    /// Sends audit events to `sink`, as well as any others.
+    pub fn with_audit_sink(mut self, sink: Arc<dyn audit::Sink>) -> Self {
+        self.audit.push(sink);
+        self
+    }
+
+    /// Signs CRLs with a delegated key rather than the CA's.
+    pub fn with_crl_signer(mut self, delegate: crl::Delegate) -> Self {
+        self.crl_signer = Some(delegate);
+        self.crl_published = Default::default();
+        self
+    }
+
+    /// Publishes sharded base and delta CRLs, naming them in issued
+    /// certificates.
+    pub fn with_crl_distribution(mut self, distribution: crl::Distribution) -> Self {
+        self.crl_distribution = Some(distribution);
+        self
+    }
+
+    /// Serves SCEP at `/scep` through a registration agent.
+    pub fn with_scep_agent(mut self, agent: scep::Agent) -> Self {
+        self.scep = Some(agent);
+        self
+    }
+
+    /// Serves timestamps at `/tsa`, signed by `authority`.
+    pub fn with_tsa(mut self, authority: tsa::Authority) -> Self {
+        self.tsa = Some(authority);
+        self
+    }
+
+    /// Also offers clients the chain of a cross-certificate, for the CA's
+    /// name and key but issued by another CA, so that relying parties which
+    /// trust only the other CA's root can validate issued certificates.
+    ///
+    /// `pem` holds the cross-certificate followed by its issuers, as far
+    /// towards that root as relying parties need.
+    pub fn with_cross_chain(mut self, mut pem: impl BufRead) -> anyhow::Result<Self> {
+        let chain = rustls_pemfile::certs(&mut pem)?;
+        let cross = chain
+            .first()
+            .ok_or_else(|| anyhow!("no cross-certificate found"))?;
+
+        let ca = Certificate::from_der(&self.crt)?;
+        let cross = Certificate::from_der(cross)?;
+        ensure!(
+            cross.tbs_certificate.subject == ca.tbs_certificate.subject
+                && cross.tbs_certificate.subject_public_key_info
+                    == ca.tbs_certificate.subject_public_key_info,
+            "the cross-certificate is not for the CA's name and key"
+        );
+        ensure!(
+            cross.tbs_certificate.issuer != ca.tbs_certificate.subject,
+            "the cross-certificate is issued by the CA itself"
+        );
+
+        for pair in chain.windows(2) {
+            let crt = Certificate::from_der(&pair[0])?;
+            let issuer = Certificate::from_der(&pair[1])?;
+            issuer
+                .tbs_certificate
+                .verify_crt(&crt)
+                .context("each certificate of a cross chain must be issued by the next")?;
+        }
+
+        // Kept from the root, as in a PkiPath.
+        let mut chain = chain;
+        chain.reverse();
+        self.cross.push(chain);
+        Ok(self)
+    }
+
+    /// Remembers why an enrollment was refused, answering with `status`.
+    fn reject(&self, status: StatusCode, reason: impl ToString) -> StatusCode {
+        let rejection = Rejection::new(self.clock.now(), status, reason);
+        self.rejections.push(rejection);
+        status
+    }
+
+    /// Passes an audit event to the configured sinks, if any.
+    fn notify(&self, event: &str, detail: serde_json::Value) {
+        if self.audit.is_empty() {
+            return;
+        }
+
+        let mut event = audit::Event::new(self.clock.now(), event, detail);
+        event.request_id = correlation::current();
+        for sink in &self.audit {
+            sink.emit(&event);
+        }
+    }
+
+    /// Allows browsers on the configured origins to call steward.
+    pub fn with_cors(mut self, cors: &Cors) -> anyhow::Result<Self> {
+        self.cors = cors.layer()?;
+        Ok(self)
+    }
+
+    /// Archives the evidence behind every issued certificate.
+    pub fn with_archive(mut self, archive: Archive) -> Self {
+        self.archive = Some(archive);
+        self
+    }
+
+    /// Lets auditors read the admin API with the token of `auditor`.
+    pub fn with_auditor(mut self, auditor: admin::Auditor) -> Self {
+        self.auditor = Some(auditor);
+        self
+    }
+
+    /// Lets only operators with client certificates from `approvers` decide
+    /// on parked requests.
+    pub fn with_approvers(mut self, approvers: approvals::Approvers) -> Self {
+        self.approvers = Some(Arc::new(approvers));
+        self
+    }
+
+    /// Checks the policy file for changes every `interval`, putting valid
+    /// ones in force.
+    pub fn with_policy_watch(mut self, interval: Duration) -> Self {
+        self.policy_watch = Some(interval);
+        self
+    }
+
+    /// Returns the policy in force.
+    pub fn policy(&self) -> Arc<Policy> {
+        self.policy.read().unwrap().clone()
+    }
+
+    /// Replaces the policy in force, for this state and all its clones.
+    fn swap_policy(&self, policy: Policy) {
+        *self.policy.write().unwrap() = Arc::new(policy);
+    }
+
+    /// Puts the policy in force, registering a new version if it changed.
+    pub async fn activate_policy(self) -> anyhow::Result<Self> {
+        let mut policy = Policy::clone(&self.policy());
+        policy.version = policy::activate(&self, &policy.raw).await?;
+        self.swap_policy(policy);
+        Ok(self)
+    }
+
+    /// Gives tests a policy of their own to change.
+    #[cfg(test)]
+    fn config_mut(&mut self) -> &mut Config {
+        let policy = Policy::clone(&self.policy());
+        self.policy = Arc::new(RwLock::new(Arc::new(policy)));
+        let live = Arc::get_mut(&mut self.policy).unwrap().get_mut().unwrap();
+        &mut Arc::get_mut(live).unwrap().config
+    }
+
+    /// Returns the periodic maintenance tasks for this state.
+    pub fn tasks(&self) -> Scheduler {
+        const MINUTE: Duration = Duration::from_secs(60);
+
+        let shared = self.shared.clone();
+        let crt = self.crt.clone();
+        let clock = self.clock.clone();
+        let scheduler = Scheduler::default()
+            .every("evict", MINUTE, MINUTE / 6, move || {
+                let shared = shared.clone();
+                async move { shared.evict().await }
+            })
+            .every("ca-expiry", MINUTE * 60, MINUTE, move || {
+                let (crt, now) = (crt.clone(), clock.now());
+                async move { check_expiry(&crt, now) }
+            });
+
+        let scheduler = match &self.archive {
+            None => scheduler,
+            Some(archive) => {
+                let store = self.store.clone();
+                let clock = self.clock.clone();
+                let retention = archive.retention();
+                scheduler.every("evidence-retention", MINUTE * 60, MINUTE, move || {
+                    let (store, now) = (store.clone(), clock.now());
+                    async move {
+                        let purged = store.purge_archive(now - retention).await?;
+                        debug!("purged {purged} archived evidence records");
+                        Ok(())
+                    }
+                })
+            }
+        };
+
+        let scheduler = leases::schedule(self, scheduler);
+        let scheduler = bundles::schedule(self, scheduler);
+
+        #[cfg(all(feature = "collateral", not(target_os = "wasi")))]
+        let scheduler = match &self.collateral {
+            None => scheduler,
+            Some(collateral) => collateral.schedule(scheduler),
+        };
+
+        match (&self.policy_path, self.policy_watch) {
+            (Some(path), Some(interval)) => policy::watch(self, path.clone(), interval, scheduler),
+            _ => scheduler,
+        }
+    }
+}
+
+/// Publishes the time left on the signing certificate, warning when it is short.
+fn check_expiry(crt: &[u8], now: SystemTime) -> anyhow::Result<()> {
+    const WARN: Duration = Duration::from_secs(60 * 60 * 24 * 30);
+
+    let crt = Certificate::from_der(crt)?;
+    let end = crt.tbs_certificate.validity.not_after.to_system_time();
+    let left = match end.duration_since(now) {
+        Ok(left) => left.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    };
+
+    metrics::CA_EXPIRY.set(left);
+    if left <= 0 {
+        tracing::error!("signing certificate has expired");
+    } else if left < WARN.as_secs() as i64 {
+        tracing::warn!("signing certificate expires in {} days", left / 86400);
+    }
+
+    Ok(())
 }
 
 #[derive(Debug, Clone, Default)]
-struct SpanMaker;
+struct SpanMaker {
+    proxies: Arc<Trusted>,
+}
 
 impl<B> tower_http::trace::MakeSpan<B> for SpanMaker {
     fn make_span(&mut self, request: &axum::http::request::Request<B>) -> tracing::span::Span {
-        let reqid = uuid::Uuid::new_v4();
+        let reqid = match request.extensions().get::<correlation::RequestId>() {
+            Some(correlation::RequestId(id)) => id.clone(),
+            None => uuid::Uuid::new_v4().to_string(),
+        };
+        let client = match request.extensions().get::<ConnectInfo<Peer>>() {
+            Some(ConnectInfo(Peer(peer))) => self
+                .proxies
+                .client(peer.ip(), request.headers())
+                .to_string(),
+            None => "unknown".into(),
+        };
         tracing::span!(
             Level::INFO,
             "request",
             method = %request.method(),
             uri = %request.uri(),
             version = ?request.version(),
-            headers = ?request.headers(),
+            headers = ?logging::Headers::new(request.headers()),
             request_id = %reqid,
+            client = %client,
         )
     }
 }
 
-pub fn app(state: State) -> Router {
+/// The endpoints used by workloads and auditors.
+///
+/// `GET` routes also answer `HEAD`, and every route answers `OPTIONS`.
+fn public_routes() -> Router {
+    let router = Router::new()
+        .route("/", get(health).post(attest).options(read_write))
+        .route("/crt", get(crt).options(read_only))
+        .route("/crl", get(crl::crl).options(read_only))
+        .route("/crl/:shard", get(crl::base).options(read_only))
+        .route("/crl/:shard/delta", get(crl::delta).options(read_only))
+        .route("/bundles/:name", get(bundles::bundle).options(read_only))
+        .route("/cmp", post(cmp::cmp).options(write_only))
+        .route(
+            "/scep",
+            get(scep::scep).post(scep::scep).options(read_write),
+        )
+        .route("/tsa", post(tsa::tsa).options(write_only))
+        .route(
+            "/v1/capabilities",
+            get(capabilities::capabilities).options(read_only),
+        )
+        .route(
+            "/v1/trust-anchors",
+            get(endorsements::trust_anchors).options(read_only),
+        )
+        .route("/requests/:id", get(approvals::request).options(read_only))
+        .route(
+            "/certs/:serial/evidence",
+            get(archive::evidence).options(read_only),
+        )
+        .route(
+            "/identities/:key",
+            get(identities::identity).options(read_only),
+        )
+        .route("/log/sth", get(transparency::sth).options(read_only))
+        .route(
+            "/log/proof/:serial",
+            get(transparency::proof).options(read_only),
+        );
+
+    #[cfg(all(feature = "fulcio", not(target_os = "wasi")))]
+    let router = router.route(
+        "/api/v2/signingCert",
+        post(fulcio::signing_cert).options(write_only),
+    );
+
+    router
+}
+
+/// The endpoints used by operators.
+fn operational_routes() -> Router {
     Router::new()
-        .route("/", post(attest))
-        .route("/", get(health))
+        .route("/healthz", get(health).options(read_only))
+        .route("/readyz", get(readiness::readyz).options(read_only))
+        .route("/metrics", get(metrics::metrics).options(read_only))
+        .route(
+            "/admin/credential",
+            post(admin::credential).options(write_only),
+        )
+        .route("/admin/nonce", post(admin::nonce).options(write_only))
+        .route(
+            "/admin/certs/export",
+            get(inventory::export).options(read_only),
+        )
+        .route("/admin/policy", get(policy::policy).options(read_only))
+        .route(
+            "/admin/rejections",
+            get(rejections::rejections).options(read_only),
+        )
+        .route("/admin/stats", get(stats::stats).options(read_only))
+        .route("/admin/tokens", post(tokens::tokens).options(write_only))
+        .route(
+            "/admin/requests",
+            get(approvals::requests).options(read_only),
+        )
+        .route(
+            "/admin/requests/:id",
+            post(approvals::decide).options(write_only),
+        )
+        .route(
+            "/admin/endorsements/:platform",
+            get(endorsements::endorsements)
+                .post(endorsements::upload)
+                .options(read_write),
+        )
+        .route(
+            "/admin/policy/reload",
+            post(policy::reload_policy).options(write_only),
+        )
+}
+
+/// Answers `OPTIONS` on routes which may only be read.
+async fn read_only() -> impl IntoResponse {
+    (StatusCode::NO_CONTENT, [(ALLOW, "GET, HEAD, OPTIONS")])
+}
+
+/// Answers `OPTIONS` on routes which may be read and posted to.
+async fn read_write() -> impl IntoResponse {
+    (
+        StatusCode::NO_CONTENT,
+        [(ALLOW, "GET, HEAD, POST, OPTIONS")],
+    )
+}
+
+/// Answers `OPTIONS` on routes which may only be posted to.
+async fn write_only() -> impl IntoResponse {
+    (StatusCode::NO_CONTENT, [(ALLOW, "POST, OPTIONS")])
+}
+
+/// Serves every endpoint on one listener.
+pub fn app(state: State) -> Router {
+    serve(public_routes().merge(operational_routes()), state)
+}
+
+/// Serves only the public endpoints, for when the operational ones are
+/// served on an internal interface by [`operations`].
+pub fn public(state: State) -> Router {
+    serve(public_routes(), state)
+}
+
+/// Serves health, metrics and the admin API.
+pub fn operations(state: State) -> Router {
+    serve(operational_routes(), state)
+}
+
+fn serve(routes: Router, state: State) -> Router {
+    let spans = SpanMaker {
+        proxies: state.proxies.clone(),
+    };
+    let cors = state.cors.clone();
+    let limit = state.body_limit;
+    #[cfg(all(feature = "collateral", not(target_os = "wasi")))]
+    let collateral = state.collateral.clone();
+
+    let mut router = routes
         .layer(Extension(Arc::new(state)))
+        // The limit applies after decompression, so that a small compressed
+        // body cannot expand into an arbitrarily large one.
+        .layer(RequestBodyLimitLayer::new(limit))
+        .layer(RequestDecompressionLayer::new())
+        .layer(CompressionLayer::new());
+
+    #[cfg(all(feature = "collateral", not(target_os = "wasi")))]
+    if let Some(collateral) = collateral {
+        router = router.layer(axum::middleware::from_fn(
+            move |req: axum::http::Request<axum::body::Body>, next| {
+                collateral::retry_after(collateral.clone(), req, next)
+            },
+        ));
+    }
+
+    if let Some(cors) = cors {
+        router = router.layer(cors);
+    }
+
+    router
         .layer(
             TraceLayer::new_for_http()
-                .make_span_with(SpanMaker::default())
+                .make_span_with(spans)
                 .on_request(DefaultOnRequest::new().level(Level::INFO))
                 .on_response(
                     DefaultOnResponse::new()
@@ -236,199 +1268,1176 @@ pub fn app(state: State) -> Router {
                         .latency_unit(LatencyUnit::Micros),
                 ),
         )
+        // Outermost, so that the request's span carries its ID.
+        .layer(axum::middleware::from_fn(correlation::propagate))
 }
 
 async fn health() -> StatusCode {
     StatusCode::OK
 }
 
-fn attest_request(
+/// Returns the DER signing certificate, unless the client's copy is current.
+async fn crt(
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Response, StatusCode> {
+    let crt = Certificate::from_der(&state.crt).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    let issued = crt.tbs_certificate.validity.not_before.to_system_time();
+    conditional(&headers, PKIX_CERT, state.crt.to_vec(), issued)
+}
+
+/// Answers with `body`, validated by a strong ETag of its digest and by the
+/// time it was last `modified`, or with `304 Not Modified` if the client's
+/// conditional headers name a current copy.
+fn conditional(
+    headers: &HeaderMap,
+    content_type: &'static str,
+    body: Vec<u8>,
+    modified: SystemTime,
+) -> Result<Response, StatusCode> {
+    let etag = format!("\"{}\"", hex::encode(Sha256::digest(&body)));
+    let etag: ETag = etag.parse().or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    // If-None-Match takes precedence over If-Modified-Since (RFC 9110,
+    // section 13.2.2).
+    let current = match headers.typed_get::<IfNoneMatch>() {
+        Some(tags) => !tags.precondition_passes(&etag),
+        None => headers
+            .typed_get::<IfModifiedSince>()
+            .map_or(false, |since| !since.is_modified(modified)),
+    };
+
+    let (etag, modified) = (TypedHeader(etag), TypedHeader(LastModified::from(modified)));
+    Ok(match current {
+        true => (StatusCode::NOT_MODIFIED, etag, modified).into_response(),
+        false => (etag, modified, [(CONTENT_TYPE, content_type)], body).into_response(),
+    })
+}
+
+/// Rejects evidence whose collateral is older than `max_age` seconds.
+fn fresh(
+    max_age: Option<u64>,
+    verifier: &dyn verifier::ExtVerifier,
+    ext: &x509::ext::Extension<'_>,
+    now: SystemTime,
+) -> anyhow::Result<()> {
+    let max_age = match max_age {
+        Some(max_age) => Duration::from_secs(max_age),
+        None => return Ok(()),
+    };
+
+    if let Some(issued) = verifier.issued(ext)? {
+        let age = now.duration_since(issued).unwrap_or_default();
+        ensure!(
+            age <= max_age,
+            "evidence is {}s old, exceeding the maximum of {}s",
+            age.as_secs(),
+            max_age.as_secs()
+        );
+    }
+
+    Ok(())
+}
+
+/// What became of one certification request.
+enum Outcome {
+    /// A certificate was issued, with the appraisals of the evidence it was
+    /// issued on and its validity.
+    Issued(Vec<u8>, Vec<Appraisal>, Validity),
+
+    /// The request was parked for approval under this ID.
+    Parked(String),
+}
+
+/// Whether one certification request may be issued for.
+enum Clearance<'a> {
+    /// The request needs no approval.
+    Cleared(Cleared<'a>),
+
+    /// The request was parked for approval under this ID.
+    Parked(String),
+}
+
+/// A certification request whose evidence was appraised, and which needs no
+/// approval.
+struct Cleared<'a> {
+    info: CertReqInfo<'a>,
+
+    /// The requested extensions to copy into the certificate.
+    extensions: Vec<x509::ext::Extension<'a>>,
+
+    /// The appraisals of the valid evidence.
+    appraisals: Vec<Appraisal>,
+
+    /// The DER request, if its evidence is to be archived.
+    request: Option<Vec<u8>>,
+}
+
+/// Appraises one request under `policy`, parking it if it needs approval
+/// other than of the measurements an operator is `approving`.
+async fn clear<'a>(
     issuer: &Certificate<'_>,
-    pki: &PrivateKeyInfo<'_>,
-    sans: SubjectAltName<'_>,
-    cr: CertReq<'_>,
-    validity: &Validity,
+    cr: CertReq<'a>,
+    profile: Option<&str>,
+    approving: Option<&BTreeMap<String, String>>,
+    policy: &Policy,
     state: &State,
-) -> Result<Vec<u8>, StatusCode> {
+) -> Result<Clearance<'a>, StatusCode> {
+    // Keep the request around if its evidence is to be archived, or it may
+    // be parked.
+    let request = match state.archive.is_some() || policy.config.approvals.is_some() {
+        true => Some(cr.to_vec().or(Err(StatusCode::INTERNAL_SERVER_ERROR))?),
+        false => None,
+    };
+
     let info = cr.verify().map_err(|e| {
         debug!("failed to verify certificate info: {e}");
-        StatusCode::BAD_REQUEST
+        state.reject(StatusCode::BAD_REQUEST, "invalid request signature")
     })?;
 
-    let mut extensions = Vec::new();
-    let mut attested = false;
-    for Attribute { oid, values } in info.attributes.iter() {
-        if *oid != ID_EXTENSION_REQ {
-            debug!("invalid extension {oid}");
-            return Err(StatusCode::BAD_REQUEST);
-        }
-        for any in values.iter() {
-            let ereq: ExtensionReq<'_> = any.decode_into().map_err(|e| {
-                debug!("failed to decode extension request: {e}");
-                StatusCode::BAD_REQUEST
-            })?;
-            for ext in Vec::from(ereq) {
-                // If the issuer is self-signed, we are in debug mode.
-                let iss = &issuer.tbs_certificate;
-                let dbg = iss.issuer_unique_id == iss.subject_unique_id;
-                let dbg = dbg && iss.issuer == iss.subject;
-
-                // Validate the extension.
-                let (copy, att) = match ext.extn_id {
-                    Kvm::OID => (Kvm::default().verify(&info, &ext, dbg), Kvm::ATT),
-                    Sgx::OID => (
-                        Sgx::default().verify(&info, &ext, state.config.sgx.as_ref(), dbg),
-                        Sgx::ATT,
-                    ),
-                    Snp::OID => (
-                        Snp::default().verify(&info, &ext, state.config.snp.as_ref(), dbg),
-                        Snp::ATT,
-                    ),
-                    oid => {
-                        debug!("extension `{oid}` is unsupported");
-                        return Err(StatusCode::BAD_REQUEST);
-                    }
-                };
-                let copy = copy.map_err(|e| {
-                    debug!("extension validation failed: {e}");
-                    StatusCode::BAD_REQUEST
-                })?;
+    let dbg = debug_mode(issuer);
+    let (extensions, appraisals) = appraise(&info, dbg, state, policy).await?;
+    #[cfg(feature = "chaos")]
+    if state.faults.strikes(chaos::Fault::PolicyReload) {
+        chaos::reload(state).await;
+    }
 
-                // Save results.
-                attested |= att;
-                if copy {
-                    extensions.push(ext);
-                }
-            }
+    // Hold back requests attesting measurements which need approval.
+    if let (Some(approvals), Some(request)) = (&policy.config.approvals, &request) {
+        let parked = approvals::park(state, approvals, request, &appraisals, profile, approving);
+        let parked = parked.await;
+        let parked = parked.map_err(|e| {
+            debug!("failed to park request: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        if let Some(id) = parked {
+            return Ok(Clearance::Parked(id));
         }
     }
-    if !attested {
-        debug!("attestation failed");
-        return Err(StatusCode::UNAUTHORIZED);
+
+    Ok(Clearance::Cleared(Cleared {
+        info,
+        extensions,
+        appraisals,
+        request,
+    }))
+}
+
+/// Issues a certificate for one request, unless it is parked for approval.
+async fn attest_request(
+    issuer: &Certificate<'_>,
+    pki: &PrivateKeyInfo<'_>,
+    sans: SubjectAltName<'_>,
+    cr: CertReq<'_>,
+    profile: Option<&str>,
+    state: &State,
+) -> Result<Outcome, StatusCode> {
+    // Appraise and record under the same policy, whatever reloads meanwhile.
+    let policy = state.policy();
+    let cleared = match clear(issuer, cr, profile, None, &policy, state).await? {
+        Clearance::Cleared(cleared) => cleared,
+        Clearance::Parked(id) => return Ok(Outcome::Parked(id)),
+    };
+    let appraisals = cleared.appraisals;
+    let (crt, validity) = issue(
+        issuer,
+        pki,
+        sans,
+        cleared.info,
+        cleared.extensions,
+        &appraisals,
+        profile,
+        &policy,
+        state,
+        cleared.request,
+    )
+    .await?;
+    Ok(Outcome::Issued(crt, appraisals, validity))
+}
+
+/// Issues a certificate for the `parked` request, under the profile it asked
+/// for, as its measurements are approved, returning the DER chain a client
+/// would have been sent.
+async fn reissue(state: &State, parked: &Parked) -> Result<Vec<u8>, StatusCode> {
+    let issuer = Certificate::from_der(&state.crt).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    let isskey = PrivateKeyInfo::from_der(&state.key).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    let cr = parse::cert_req(&parked.request).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    // The policy may have changed to need other approvals meanwhile.
+    let policy = state.policy();
+    let profile = parked.profile.as_deref();
+    let approving = Some(&parked.measurements);
+    let cleared = match clear(&issuer, cr, profile, approving, &policy, state).await? {
+        Clearance::Cleared(cleared) => cleared,
+        Clearance::Parked(..) => return Err(StatusCode::CONFLICT),
+    };
+    let (crt, _) = issue(
+        &issuer,
+        &isskey,
+        sans(state)?,
+        cleared.info,
+        cleared.extensions,
+        &cleared.appraisals,
+        profile,
+        &policy,
+        state,
+        cleared.request,
+    )
+    .await?;
+    let crt = Certificate::from_der(&crt).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    let mut path = vec![];
+    if !policy.config.exclude_root {
+        path.push(issuer);
+    }
+    path.push(crt);
+    path.to_vec().or(Err(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+/// Issues and records a certificate for `info` on the strength of
+/// `appraisals`, under `policy` and the profile named `profile`, if the
+/// client asked for one, counting it against the quotas of the evidence and
+/// redeeming any one-time credentials presented.
+#[allow(clippy::too_many_arguments)]
+async fn issue<'a>(
+    issuer: &Certificate<'_>,
+    pki: &PrivateKeyInfo<'_>,
+    sans: SubjectAltName<'_>,
+    info: CertReqInfo<'a>,
+    extensions: Vec<x509::ext::Extension<'a>>,
+    appraisals: &[Appraisal],
+    profile: Option<&str>,
+    policy: &Policy,
+    state: &State,
+    request: Option<Vec<u8>>,
+) -> Result<(Vec<u8>, Validity), StatusCode> {
+    let mut extensions = extensions;
+
+    // Renewals should attest the workload which was first certified.
+    let spki = info
+        .public_key
+        .to_vec()
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    let changes = policy.config.measurement_changes;
+    identities::check(state, changes, &spki, appraisals).await?;
+    if let Some(tofu) = &policy.config.tofu {
+        tofu::check(state, tofu, &spki, appraisals).await?;
     }
 
-    // Add Subject Alternative Name
-    let sans: Vec<u8> = sans.to_vec().or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    // Issue under the profile asked for, if it may be, else that for the
+    // evidence.
+    let profile = requested(&info, profile, &policy.config.validity, state)?;
+    let profile = match profile {
+        Some(name) => policy
+            .config
+            .validity
+            .request(&name, appraisals)
+            .map_err(|e| {
+                debug!("{e}");
+                state.reject(StatusCode::FORBIDDEN, e)
+            })?,
+        None => policy.config.validity.select(appraisals),
+    };
+    #[cfg(all(feature = "fulcio", not(target_os = "wasi")))]
+    let profile = fulcio::signing(profile, appraisals);
+
+    // Issue for as long as the profile allows.
+    let backdate = profile.backdate();
+    let ttl = backdate + state.leaf_ttl(profile.lifetime());
+    let validity = validity(state.clock.now() - backdate, ttl)?;
+
+    // Name the certificate after the evidence, if the profile says to.
+    let subject = profile.subject(appraisals).map_err(|e| {
+        debug!("{e}");
+        StatusCode::BAD_REQUEST
+    })?;
+    let subject = match &subject {
+        Some(subject) => {
+            RdnSequence::from_der(subject).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?
+        }
+        None => info.subject,
+    };
+
+    // Generate the instance id.
+    let uuid = state.serials.next();
+    let serial_number = UIntRef::new(uuid.as_bytes()).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    // Add Subject Alternative Name, and any names of the profile.
+    let mut names = sans.0;
+    names.extend(
+        profile
+            .dns_names()
+            .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?,
+    );
+    let sans: Vec<u8> = SubjectAltName(names)
+        .to_vec()
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
     extensions.push(x509::ext::Extension {
         extn_id: ID_CE_SUBJECT_ALT_NAME,
         critical: false,
         extn_value: &sans,
     });
 
-    // Add extended key usage.
-    let eku = ExtendedKeyUsage(vec![ID_KP_SERVER_AUTH, ID_KP_CLIENT_AUTH])
-        .to_vec()
+    // Add the extended key usage and any other extensions of the profile.
+    let encoded = profile
+        .extensions()
         .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
-    extensions.push(x509::ext::Extension {
-        extn_id: ID_CE_EXT_KEY_USAGE,
-        critical: false,
-        extn_value: &eku,
-    });
+    for (oid, value) in &encoded {
+        extensions.push(x509::ext::Extension {
+            extn_id: *oid,
+            critical: false,
+            extn_value: value,
+        });
+    }
 
-    // Generate the instance id.
-    let uuid = uuid::Uuid::new_v4();
-    let serial_number = UIntRef::new(uuid.as_bytes()).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    // State the claims of the evidence, and how the subject was enrolled.
+    let claims = claims::extension(appraisals).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    if let Some(claims) = &claims {
+        extensions.push(x509::ext::Extension {
+            extn_id: claims::OID,
+            critical: false,
+            extn_value: claims,
+        });
+    }
+
+    // Name the CRLs which will list the certificate if it is revoked.
+    let crls = match &state.crl_distribution {
+        Some(distribution) => Some(
+            distribution
+                .extensions(serial_number.as_bytes())
+                .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?,
+        ),
+        None => None,
+    };
+    if let Some((cdp, freshest)) = &crls {
+        extensions.push(x509::ext::Extension {
+            extn_id: ID_CE_CRL_DISTRIBUTION_POINTS,
+            critical: false,
+            extn_value: cdp,
+        });
+        extensions.push(x509::ext::Extension {
+            extn_id: ID_CE_FRESHEST_CRL,
+            critical: false,
+            extn_value: freshest,
+        });
+    }
 
     let signature = pki
         .signs_with()
         .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
 
+    // Only authorized issuance is counted against quotas.
+    let allowances: Vec<_> = appraisals
+        .iter()
+        .flat_map(|appraisal| appraisal.allowances.iter().cloned())
+        .collect();
+    match state.store.consume(&allowances, state.clock.now()).await {
+        Ok(true) => (),
+        Ok(false) => {
+            debug!("issuance quota exhausted");
+            let status = StatusCode::TOO_MANY_REQUESTS;
+            return Err(state.reject(status, "issuance quota exhausted"));
+        }
+        Err(e) => {
+            debug!("failed to count issuance against quotas: {e}");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+
+    // Redeem one-time credentials once nothing else stands in the way.
+    for redemption in appraisals.iter().filter_map(|a| a.redemption.as_ref()) {
+        let shared = &state.shared;
+        match shared.put_new(&redemption.key, &[], redemption.ttl).await {
+            Ok(true) => (),
+            Ok(false) => {
+                debug!("credential already redeemed");
+                let status = StatusCode::UNAUTHORIZED;
+                return Err(state.reject(status, "credential already redeemed"));
+            }
+            Err(e) => {
+                debug!("failed to redeem credential: {e}");
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+    }
+
+    #[cfg(feature = "chaos")]
+    if state.faults.strikes(chaos::Fault::SignerError) {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
     // Create and sign the new certificate.
-    TbsCertificate {
+    let signing = Instant::now();
+    let crt = TbsCertificate {
         version: x509::Version::V3,
         serial_number,
         signature,
         issuer: issuer.tbs_certificate.subject.clone(),
-        validity: *validity,
-        subject: info.subject,
+        validity,
+        subject,
         subject_public_key_info: info.public_key,
         issuer_unique_id: issuer.tbs_certificate.subject_unique_id,
         subject_unique_id: None,
         extensions: Some(extensions),
     }
     .sign(pki)
-    .or(Err(StatusCode::INTERNAL_SERVER_ERROR))
+    .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    metrics::STAGE_DURATION.observe("sign", signing.elapsed());
+
+    // Record the certificate before handing it out.
+    let issued = Issued {
+        serial: serial_number.as_bytes().to_vec(),
+        not_before: validity.not_before.to_system_time(),
+        not_after: validity.not_after.to_system_time(),
+        der: crt.clone(),
+        rekor_index: None,
+        policy_version: policy.version,
+        platform: stats::platform(appraisals),
+        tenant: stats::tenant(appraisals),
+    };
+    record(state, &issued, appraisals, request).await?;
+    Ok((crt, validity))
+}
+
+/// The name of the profile the client asked for, with `header` or in `info`.
+fn requested(
+    info: &CertReqInfo<'_>,
+    header: Option<&str>,
+    validity: &profiles::Policy,
+    state: &State,
+) -> Result<Option<String>, StatusCode> {
+    let oid = profiles::requested(info).map_err(|e| {
+        debug!("{e}");
+        state.reject(StatusCode::BAD_REQUEST, format!("{e:#}"))
+    })?;
+    let named = match oid {
+        Some(oid) => Some(validity.named(&oid).ok_or_else(|| {
+            debug!("no profile has oid {oid}");
+            state.reject(
+                StatusCode::FORBIDDEN,
+                format!("profile {oid} is not permitted"),
+            )
+        })?),
+        None => None,
+    };
+    match (header, named) {
+        (Some(header), Some(named)) if header != named => {
+            debug!("asked for profiles {header} and {named}");
+            Err(state.reject(StatusCode::BAD_REQUEST, "conflicting profiles"))
+        }
+        (header, named) => Ok(header.or(named).map(Into::into)),
+    }
+}
+
+/// Records an issued certificate, logging it and archiving its evidence.
+async fn record(
+    state: &State,
+    issued: &Issued,
+    appraisals: &[Appraisal],
+    request: Option<Vec<u8>>,
+) -> Result<(), StatusCode> {
+    state
+        .store
+        .issue(issued, state.clock.now())
+        .await
+        .map_err(|e| {
+            debug!("failed to record issued certificate: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    identities::link(state, issued, appraisals)
+        .await
+        .map_err(|e| {
+            debug!("failed to link issued certificate to its identity: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    leases::grant(state, issued).await.map_err(|e| {
+        debug!("failed to grant lease: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let platforms: Vec<_> = appraisals.iter().map(|a| &a.platform).collect();
+    state.notify(
+        "issuance",
+        json!({
+            "serial": hex::encode(&issued.serial),
+            "platforms": platforms,
+            "policy_version": issued.policy_version,
+            "not_after": issued
+                .not_after
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }),
+    );
+
+    // Make the certificate publicly auditable.
+    let leaf = transparency::leaf_hash(&issued.der);
+    state
+        .store
+        .append_log(&issued.serial, &leaf)
+        .await
+        .map_err(|e| {
+            debug!("failed to log issued certificate: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    // Archive the evidence, if enabled.
+    if let (Some(archive), Some(request)) = (&state.archive, request) {
+        let now = state.clock.now();
+        let version = issued.policy_version;
+        let appraisals = appraisals.to_vec();
+        let evidence = Evidence::new(&issued.serial, appraisals, version, &request, now);
+        let sealed = archive.seal(&issued.serial, &evidence).map_err(|e| {
+            debug!("failed to seal evidence: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        state
+            .store
+            .archive(&issued.serial, now, &sealed)
+            .await
+            .map_err(|e| {
+                debug!("failed to archive evidence: {e}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+    }
+
+    Ok(())
+}
+
+/// Returns the SGX `config`, completed with any fetched collateral and
+/// uploaded trust anchors.
+#[cfg(feature = "sgx")]
+async fn sgx_config<'a>(
+    state: &State,
+    config: Option<&'a attestation::sgx::config::Config>,
+) -> Result<Option<std::borrow::Cow<'a, attestation::sgx::config::Config>>, StatusCode> {
+    let mut config = config.map(std::borrow::Cow::Borrowed);
+
+    #[cfg(all(feature = "collateral", not(target_os = "wasi")))]
+    if let Some(collateral) = &state.collateral {
+        let crls = collateral.sgx_crls().await.map_err(|e| {
+            debug!("{e}");
+            StatusCode::SERVICE_UNAVAILABLE
+        })?;
+
+        let config = config.get_or_insert_with(Default::default).to_mut();
+        config.crls.extend(crls);
+    }
+
+    let anchors = endorsements::anchors(state, "sgx").await.map_err(|e| {
+        debug!("failed to read sgx anchors: {e}");
+        StatusCode::SERVICE_UNAVAILABLE
+    })?;
+    if !anchors.is_empty() {
+        let config = config.get_or_insert_with(Default::default).to_mut();
+        config.roots = anchors.to_vec();
+    }
+
+    Ok(config)
+}
+
+/// Returns the SNP `config`, completed with any uploaded trust anchors.
+#[cfg(feature = "snp")]
+async fn snp_config<'a>(
+    state: &State,
+    config: Option<&'a attestation::snp::config::Config>,
+) -> Result<Option<std::borrow::Cow<'a, attestation::snp::config::Config>>, StatusCode> {
+    let anchors = endorsements::anchors(state, "snp").await.map_err(|e| {
+        debug!("failed to read snp anchors: {e}");
+        StatusCode::SERVICE_UNAVAILABLE
+    })?;
+    if anchors.is_empty() {
+        return Ok(config.map(std::borrow::Cow::Borrowed));
+    }
+
+    let mut config = config.cloned().unwrap_or_default();
+    config.roots = anchors.to_vec();
+    Ok(Some(std::borrow::Cow::Owned(config)))
+}
+
+/// Whether steward is in debug mode, indicated by a self-signed issuer.
+fn debug_mode(issuer: &Certificate<'_>) -> bool {
+    let iss = &issuer.tbs_certificate;
+    let dbg = iss.issuer_unique_id == iss.subject_unique_id;
+    dbg && iss.issuer == iss.subject
+}
+
+/// Appraises the evidence in a request.
+///
+/// Returns the requested extensions to copy into the certificate and the
+/// appraisals of the valid evidence.
+async fn appraise<'a>(
+    info: &CertReqInfo<'a>,
+    dbg: bool,
+    state: &State,
+    policy: &Policy,
+) -> Result<(Vec<x509::ext::Extension<'a>>, Vec<Appraisal>), StatusCode> {
+    let config = &policy.config;
+    let evaluating = Instant::now();
+    let mut requests = Vec::new();
+    let mut present = Vec::new();
+    for Attribute { oid, values } in info.attributes.iter() {
+        if *oid != ID_EXTENSION_REQ {
+            config.attributes.check(oid).map_err(|e| {
+                debug!("{e}");
+                state.reject(StatusCode::BAD_REQUEST, e)
+            })?;
+            present.push(*oid);
+            continue;
+        }
+        for any in values.iter() {
+            let ereq: ExtensionReq<'_> = any.decode_into().map_err(|e| {
+                debug!("failed to decode extension request: {e}");
+                state.reject(StatusCode::BAD_REQUEST, "malformed extension request")
+            })?;
+            requests.push(ereq);
+        }
+    }
+    config.attributes.check_required(&present).map_err(|e| {
+        debug!("{e}");
+        state.reject(StatusCode::BAD_REQUEST, e)
+    })?;
+    let requested = extensions::merge(requests, config.duplicates).map_err(|e| {
+        debug!("{e}");
+        state.reject(StatusCode::BAD_REQUEST, e)
+    })?;
+    let mut evaluation = evaluating.elapsed();
+
+    let mut extensions = Vec::new();
+    let mut appraisals = Vec::new();
+    let mut refused = None;
+    for ext in requested {
+        // Validate the extension, reusing a recent appraisal if possible.
+        let (cache, shared, raw) = (&state.cache, &*state.shared, &policy.raw);
+        let verifier = match state.verifiers.get(&ext.extn_id) {
+            Some(verifier) => verifier,
+            None if ext.extn_id == profiles::ID_CERTIFICATE_TEMPLATE => {
+                // The profile asked for is applied on issuance.
+                continue;
+            }
+            None => {
+                // Anything other than evidence must be explicitly allowed.
+                config.extensions.check(&ext).map_err(|e| {
+                    debug!("{e}");
+                    state.reject(StatusCode::BAD_REQUEST, e)
+                })?;
+                extensions.push(ext);
+                continue;
+            }
+        };
+        let verifying = Instant::now();
+        let platform = verifier.platform();
+        let unavailable = |status| {
+            let reason = format!("{platform} evidence could not be appraised");
+            let rejection = Rejection::new(state.clock.now(), status, reason);
+            state
+                .rejections
+                .push(rejection.with_platform(platform, None));
+            status
+        };
+        let trust = verifier.trust(state).await.map_err(unavailable)?;
+        let appraiser = verifier
+            .prepare(state, config, info, &ext, dbg)
+            .await
+            .map_err(unavailable)?;
+        let basis: Vec<&[u8]> = std::iter::once(&raw[..])
+            .chain(trust.iter().map(Vec::as_slice))
+            .collect();
+        let appraised = cache
+            .appraise(shared, &basis, info, &ext, dbg, appraiser)
+            .await;
+        metrics::VERIFY_DURATION.observe(verifier.platform(), verifying.elapsed());
+
+        // Freshness depends on the current time, so is never cached.
+        let max_age = config.max_evidence_age;
+        let appraised = appraised.and_then(|mut appraisal| {
+            fresh(max_age, verifier, &ext, state.clock.now())?;
+            if let Some(max_age) = max_age {
+                appraisal.decide(format!("evidence at most {max_age}s old"));
+            }
+            Ok(appraisal)
+        });
+        let mut appraisal = match appraised {
+            Ok(appraisal) => appraisal,
+            Err(e) => {
+                metrics::APPRAISAL_FAILURES.inc(verifier.platform());
+                debug!("extension validation failed: {e}");
+                let reason = format!("{platform} evidence rejected: {e:#}");
+                let rejection = Rejection::new(state.clock.now(), StatusCode::BAD_REQUEST, reason)
+                    .with_platform(platform, verifier.claimed(&ext));
+                match config.platforms.require {
+                    platforms::Require::All => {
+                        state.rejections.push(rejection);
+                        return Err(StatusCode::BAD_REQUEST);
+                    }
+                    platforms::Require::Any => {
+                        refused.get_or_insert(rejection);
+                        continue;
+                    }
+                }
+            }
+        };
+
+        // Count any issuance against the platform's quotas.
+        let quotas = config
+            .quotas
+            .allowances(&appraisal.platform, &ext)
+            .map_err(|e| {
+                debug!("{e}");
+                state.reject(StatusCode::BAD_REQUEST, e)
+            })?;
+        if !quotas.is_empty() {
+            appraisal.decide(format!("counted against {} quotas", quotas.len()));
+        }
+        appraisal.allowances = quotas;
+        appraisal.redemption = verifier.redemption(config, &ext);
+
+        // Save results.
+        appraisal.copy = config.extensions.check(&ext).is_ok();
+        if appraisal.copy {
+            extensions.push(ext);
+        }
+        metrics::APPRAISALS.inc(&appraisal.platform);
+        debug!(
+            "appraised {} evidence: decisions {:?}, measurements {:?}",
+            appraisal.platform,
+            appraisal.decisions,
+            logging::Redacted::new(&appraisal.measurements)
+        );
+        appraisals.push(appraisal);
+    }
+    let evaluating = Instant::now();
+    let verified: Vec<&str> = appraisals
+        .iter()
+        .filter(|appraisal| appraisal.attests)
+        .map(|appraisal| appraisal.platform.as_str())
+        .collect();
+    if let Err(e) = config.platforms.check(&verified) {
+        debug!("attestation failed: {e}");
+
+        // Where evidence was refused, that is why attestation failed.
+        let status = StatusCode::UNAUTHORIZED;
+        let rejection = match refused {
+            Some(refused) => Rejection {
+                status: status.as_u16(),
+                ..refused
+            },
+            None => Rejection::new(state.clock.now(), status, e),
+        };
+        state.rejections.push(rejection);
+        return Err(status);
+    }
+
+    evaluation += evaluating.elapsed();
+    metrics::STAGE_DURATION.observe("policy", evaluation);
+
+    Ok((extensions, appraisals))
+}
+
+/// The validity period of certificates issued at `now` for `ttl`.
+fn validity(now: SystemTime, ttl: Duration) -> Result<Validity, StatusCode> {
+    let end = now + ttl;
+    Ok(Validity {
+        not_before: Time::try_from(now).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?,
+        not_after: Time::try_from(end).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?,
+    })
+}
+
+/// When a certificate valid for `validity` should be renewed: two thirds of
+/// the way through, leaving time to retry should steward be unavailable.
+fn renew_after(validity: &Validity) -> SystemTime {
+    let start = validity.not_before.to_system_time();
+    let lifetime = validity
+        .not_after
+        .to_system_time()
+        .duration_since(start)
+        .unwrap_or_default();
+    start + lifetime * 2 / 3
+}
+
+/// The subject alternative names placed in every issued certificate.
+fn sans(state: &State) -> Result<SubjectAltName<'_>, StatusCode> {
+    // Create the basic subject alt name.
+    let name = Ia5StringRef::new(DEFAULT_SAN).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    let mut sans = vec![GeneralName::DnsName(name)];
+
+    // Optionally, add the configured subject alt name.
+    if let Some(name) = &state.san {
+        let name = Ia5StringRef::new(name).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+        sans.push(GeneralName::DnsName(name));
+    }
+
+    Ok(SubjectAltName(sans))
+}
+
+/// The essence of a content type, e.g. `application/pkcs10`.
+///
+/// Clients variously add parameters such as `name=csr.p10` or `charset`, and
+/// vary the case, none of which change how the body is read.
+fn media_type(ct: &ContentType) -> String {
+    let ct = ct.to_string();
+    let essence = ct.split(';').next().unwrap_or_default();
+    essence.trim().to_ascii_lowercase()
+}
+
+/// Decodes a request body sent as base64, with `Content-Transfer-Encoding:
+/// base64`, or as a PEM certification request.
+///
+/// Returns the DER and whether the response should be base64 in turn.
+fn decode_body(headers: &HeaderMap, body: Bytes) -> Result<(Bytes, bool), StatusCode> {
+    const LABELS: &[&str] = &["CERTIFICATE REQUEST", "NEW CERTIFICATE REQUEST"];
+
+    let encoding = match headers.get(CONTENT_TRANSFER_ENCODING) {
+        Some(value) => value.to_str().or(Err(StatusCode::BAD_REQUEST))?.trim(),
+        None => "binary",
+    };
+
+    if encoding.eq_ignore_ascii_case("base64") {
+        let text: Vec<u8> = body
+            .iter()
+            .copied()
+            .filter(|b| !b.is_ascii_whitespace())
+            .collect();
+        let der = BASE64.decode(text).map_err(|e| {
+            debug!("invalid base64 body: {e}");
+            StatusCode::BAD_REQUEST
+        })?;
+        return Ok((der.into(), true));
+    }
+
+    if !encoding.eq_ignore_ascii_case("binary") {
+        debug!("unsupported content transfer encoding: {encoding}");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    if body.starts_with(b"-----BEGIN ") {
+        let (label, der) = der::pem::decode_vec(&body).map_err(|e| {
+            debug!("invalid pem body: {e}");
+            StatusCode::BAD_REQUEST
+        })?;
+        if !LABELS.contains(&label) {
+            debug!("unexpected pem label: {label}");
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        return Ok((der.into(), false));
+    }
+
+    Ok((body, false))
+}
+
+/// Holds a certification request received other than at `POST /` to the
+/// `parsing` policy, returning its DER: normalized from BER in compat mode,
+/// or checked to be canonical in strict mode.
+fn normalized(parsing: Strictness, req: &[u8]) -> anyhow::Result<Vec<u8>> {
+    match parsing {
+        Strictness::Der => Ok(req.to_vec()),
+        Strictness::Strict => {
+            parse::canonical(&parse::cert_req(req)?, req)?;
+            Ok(req.to_vec())
+        }
+        Strictness::Compat => parse::normalize(req, &Limits::default()),
+    }
+}
+
+/// The answer to a request for the appraisal of its evidence.
+#[derive(Debug, Serialize)]
+struct Verbose {
+    /// The base64 DER certificates, as answered otherwise.
+    chain: String,
+
+    /// The appraisals of the evidence of each certification request, in order.
+    appraisals: Vec<Vec<Appraisal>>,
+}
+
+/// Whether the client asks for the appraisal of its evidence.
+fn verbose(headers: &HeaderMap, uri: &Uri) -> bool {
+    let header = headers
+        .get(VERBOSE_HEADER)
+        .map_or(false, |value| value == "appraisal");
+    let query = uri.query().map_or(false, |q| {
+        q.split('&').any(|param| param == "verbose=appraisal")
+    });
+    header || query
+}
+
+/// The chain which the client asks for: the CA's own (0) or the chain of
+/// the nth cross-certificate.
+fn chain(uri: &Uri, cross: usize) -> Result<usize, StatusCode> {
+    let param = uri
+        .query()
+        .into_iter()
+        .flat_map(|q| q.split('&'))
+        .find_map(|param| param.strip_prefix("chain="));
+    match param {
+        Some(n) => n
+            .parse()
+            .ok()
+            .filter(|n| *n <= cross)
+            .ok_or(StatusCode::BAD_REQUEST),
+        None => Ok(0),
+    }
+}
+
+/// Whether the client asks for the root to be in the chain, with a
+/// `root=include` or `root=exclude` parameter on a media range it accepts.
+fn root(headers: &HeaderMap) -> Option<bool> {
+    let accept = headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok());
+    let params = accept
+        .flat_map(|v| v.split(','))
+        .flat_map(|range| range.split(';').skip(1));
+    params.map(str::trim).find_map(|param| match param {
+        "root=include" => Some(true),
+        "root=exclude" => Some(false),
+        _ => None,
+    })
+}
+
+/// Whether the client asks for the issued certificate alone, as
+/// `application/pkix-cert`, in place of its chain.
+fn leaf(headers: &HeaderMap) -> bool {
+    let accept = headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok());
+    let media = accept
+        .flat_map(|v| v.split(','))
+        .filter_map(|range| range.split(';').next());
+    media
+        .map(str::trim)
+        .any(|media| media.eq_ignore_ascii_case(PKIX_CERT))
 }
 
 /// Receives:
 /// ASN.1 SEQUENCE OF CertRequest.
 /// Returns:
 /// ASN.1 SEQUENCE OF Output.
+///
+/// A single certification request is answered with the PkiPath of the issued
+/// certificate, or the certificate alone given `Accept: application/pkix-cert`.
 pub async fn attest(
     TypedHeader(ct): TypedHeader<ContentType>,
+    headers: HeaderMap,
+    uri: Uri,
     body: Bytes,
     Extension(state): Extension<Arc<State>>,
-) -> Result<Vec<u8>, impl IntoResponse> {
+) -> Result<Response, StatusCode> {
     // Decode the signing certificate and key.
     let issuer = Certificate::from_der(&state.crt).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
     let isskey = PrivateKeyInfo::from_der(&state.key).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
 
-    const TTL: Duration = Duration::from_secs(60 * 60 * 24 * 28);
-    let now = SystemTime::now();
-    let end = now + TTL;
-    let validity = Validity {
-        not_before: Time::try_from(now).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?,
-        not_after: Time::try_from(end).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?,
-    };
+    let (body, base64) = decode_body(&headers, body)?;
+    let verbose = verbose(&headers, &uri) && state.policy().config.verbose;
+    let selected = chain(&uri, state.cross.len())?;
+    let include_root = root(&headers).unwrap_or(!state.policy().config.exclude_root);
 
     // Check for correct mime type.
-    let reqs = match ct.to_string().as_ref() {
-        PKCS10 => vec![CertReq::from_der(body.as_ref()).or(Err(StatusCode::BAD_REQUEST))?],
-        BUNDLE => Vec::from_der(body.as_ref()).or(Err(StatusCode::BAD_REQUEST))?,
+    let media = media_type(&ct);
+    let leaf = leaf(&headers) && media == PKCS10;
+    let profile = headers
+        .get(PROFILE_HEADER)
+        .map(|value| value.to_str().or(Err(StatusCode::BAD_REQUEST)))
+        .transpose()?;
+    let parsing = Instant::now();
+    let malformed = |e: anyhow::Error| {
+        debug!("failed to decode certification requests: {e}");
+        state.notify(
+            "rejection",
+            json!({ "status": 400, "reason": "malformed request" }),
+        );
+        state.reject(StatusCode::BAD_REQUEST, format!("malformed request: {e}"))
+    };
+    let strictness = state.policy().config.parsing;
+    let der: Bytes = match strictness {
+        Strictness::Compat => parse::normalize(&body, &Limits::default())
+            .map_err(malformed)?
+            .into(),
+        _ => body.clone(),
+    };
+    let strict = strictness == Strictness::Strict;
+    let reqs = match media.as_str() {
+        PKCS10 => parse::cert_req(der.as_ref()).and_then(|cr| {
+            if strict {
+                parse::canonical(&cr, &der)?;
+            }
+            Ok(vec![cr])
+        }),
+        BUNDLE => parse::cert_reqs(der.as_ref()).and_then(|crs| {
+            if strict {
+                parse::canonical(&crs, &der)?;
+            }
+            Ok(crs)
+        }),
         _ => return Err(StatusCode::BAD_REQUEST),
+    }
+    .map_err(malformed)?;
+    metrics::STAGE_DURATION.observe("parse", parsing.elapsed());
+
+    // Say how the requests were parsed, where it was out of the ordinary.
+    let parsed = match strictness {
+        Strictness::Der => None,
+        Strictness::Strict => Some("request is canonical der"),
+        Strictness::Compat if der != body => Some("request normalized from ber"),
+        Strictness::Compat => Some("request parsed in compat mode"),
     };
 
-    // Decode and verify the certification requests.
-    reqs.into_iter()
-        .map(|cr| {
-            // Create the basic subject alt name.
-            let name = Ia5StringRef::new("foo.bar.hub.profian.com")
-                .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
-            let mut sans = vec![GeneralName::DnsName(name)];
-
-            // Optionally, add the configured subject alt name.
-            if let Some(name) = &state.san {
-                let name = Ia5StringRef::new(name).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
-                sans.push(GeneralName::DnsName(name));
-            }
-            attest_request(
-                &issuer,
-                &isskey,
-                SubjectAltName(sans),
-                cr,
-                &validity,
-                &state,
-            )
-        })
-        .collect::<Result<Vec<_>, _>>()
-        .and_then(|issued| {
-            let issued: Vec<Certificate<'_>> = issued
-                .iter()
-                .map(|c| Certificate::from_der(c).or(Err(StatusCode::INTERNAL_SERVER_ERROR)))
-                .collect::<Result<_, _>>()?;
-
-            match ct.to_string().as_ref() {
-                PKCS10 => vec![issuer, issued[0].clone()].to_vec(),
-                BUNDLE => Output {
-                    chain: vec![issuer],
-                    issued,
-                }
-                .to_vec(),
-                _ => return Err(StatusCode::BAD_REQUEST),
-            }
-            .or(Err(StatusCode::INTERNAL_SERVER_ERROR))
-        })
+    // Appraise and record under the same policy, whatever reloads meanwhile.
+    let policy = state.policy();
+    let rejected = |status: StatusCode| {
+        state.notify("rejection", json!({ "status": status.as_u16() }));
+        status
+    };
+
+    // Decode and verify the certification requests, issuing for none while
+    // any is parked for approval.
+    let mut cleared = Vec::with_capacity(reqs.len());
+    let mut parked = Vec::new();
+    for cr in reqs {
+        match clear(&issuer, cr, profile, None, &policy, &state)
+            .await
+            .map_err(rejected)?
+        {
+            Clearance::Cleared(request) => cleared.push(request),
+            Clearance::Parked(id) => parked.push(id),
+        }
+    }
+    if !parked.is_empty() {
+        let accepted = approvals::Accepted { requests: parked };
+        return Ok(accepted.respond());
+    }
+
+    let mut issued = Vec::with_capacity(cleared.len());
+    let mut platforms = Vec::with_capacity(cleared.len());
+    let mut validities = Vec::with_capacity(cleared.len());
+    let mut appraisals = Vec::with_capacity(cleared.len());
+    for request in cleared {
+        let Cleared {
+            info,
+            extensions,
+            appraisals: mut appraised,
+            request,
+        } = request;
+        let (crt, validity) = issue(
+            &issuer,
+            &isskey,
+            sans(&state)?,
+            info,
+            extensions,
+            &appraised,
+            profile,
+            &policy,
+            &state,
+            request,
+        )
+        .await
+        .map_err(rejected)?;
+        if let Some(parsed) = parsed {
+            appraised
+                .iter_mut()
+                .for_each(|appraisal| appraisal.decide(parsed));
+        }
+        let attested: Vec<_> = appraised.iter().map(|a| a.platform.as_str()).collect();
+        platforms.push(attested.join("+"));
+        issued.push(crt);
+        validities.push(validity);
+        appraisals.push(appraised);
+    }
+
+    let issued: Vec<Certificate<'_>> = issued
+        .iter()
+        .map(|c| Certificate::from_der(c).or(Err(StatusCode::INTERNAL_SERVER_ERROR)))
+        .collect::<Result<_, _>>()?;
+    let serials: Vec<_> = issued
+        .iter()
+        .map(|c| hex::encode(c.tbs_certificate.serial_number.as_bytes()))
+        .collect();
+
+    // Chain back to the root the client asked for.
+    let mut path = match selected {
+        0 => vec![issuer],
+        n => state.cross[n - 1]
+            .iter()
+            .map(|c| Certificate::from_der(c).or(Err(StatusCode::INTERNAL_SERVER_ERROR)))
+            .collect::<Result<_, _>>()?,
+    };
+    let self_signed = path.first().map_or(false, |c| {
+        c.tbs_certificate.issuer == c.tbs_certificate.subject
+    });
+    if self_signed && !include_root {
+        path.remove(0);
+    }
+
+    let der = match media.as_str() {
+        PKCS10 if leaf => issued[0].to_vec(),
+        PKCS10 => {
+            path.push(issued[0].clone());
+            path.to_vec()
+        }
+        BUNDLE => Output {
+            chain: path,
+            issued,
+        }
+        .to_vec(),
+        _ => return Err(StatusCode::BAD_REQUEST),
+    }
+    .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    // Describe the issuance for orchestrators which don't parse DER. Where
+    // certificates differ in validity, the first to expire is described.
+    let mut described = vec![
+        (SERIAL_HEADER, serials.join(", ")),
+        (PLATFORM_HEADER, platforms.join(", ")),
+    ];
+    let first = validities
+        .iter()
+        .min_by_key(|validity| validity.not_after.to_system_time());
+    if let Some(validity) = first {
+        // Leases must be renewed well before they lapse.
+        let renew_after = match &policy.config.leases {
+            Some(leases) => leases.renew_after(validity),
+            None => renew_after(validity),
+        };
+        let renew_after =
+            DateTime::from_system_time(renew_after).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+        described.push((
+            NOT_AFTER_HEADER,
+            validity.not_after.to_date_time().to_string(),
+        ));
+        described.push((RENEW_AFTER_HEADER, renew_after.to_string()));
+    }
+    let mut meta = HeaderMap::new();
+    for (name, value) in described {
+        let value = HeaderValue::from_str(&value).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+        meta.insert(HeaderName::from_static(name), value);
+    }
+
+    // Point to the chains not chosen, as ACME does (RFC 8555, section 7.4.2).
+    for n in (0..=state.cross.len()).filter(|n| *n != selected) {
+        let link = format!("<?chain={n}>;rel=\"alternate\"");
+        let value = HeaderValue::from_str(&link).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+        meta.append(LINK, value);
+    }
+
+    // Answer in kind.
+    if verbose {
+        let chain = BASE64.encode(der);
+        return Ok((meta, axum::Json(Verbose { chain, appraisals })).into_response());
+    }
+    if leaf {
+        meta.insert(CONTENT_TYPE, HeaderValue::from_static(PKIX_CERT));
+    }
+    if base64 {
+        let encoding = HeaderName::from_static(CONTENT_TRANSFER_ENCODING);
+        meta.insert(encoding, HeaderValue::from_static("base64"));
+        return Ok((meta, BASE64.encode(der)).into_response());
+    }
+
+    Ok((meta, der).into_response())
 }
 
 pub fn init_tracing() {
-    if std::env::var("RUST_LOG_JSON").is_ok() {
-        tracing_subscriber::fmt::fmt()
-            .json()
-            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-            .init();
-    } else {
-        tracing_subscriber::fmt::init();
-    }
+    logging::init(logging::Format::from_env(), false);
 }
 
 #[cfg(test)]
@@ -438,22 +2447,51 @@ mod tests {
 
     static TRACING: Once = Once::new();
 
+    #[cfg(feature = "kvm")]
     mod attest {
+        use super::super::attributes::Handling;
+        use super::super::audit::{Event, Sink};
+        use super::super::correlation::REQUEST_ID_HEADER;
+        use super::super::crl;
+        use super::super::extensions::{Criticality, Rule};
         use super::super::kvm::Kvm;
-        use super::super::{app, Output, State, BUNDLE, PKCS10};
+        use super::super::verifier::{Appraisal, Appraiser, ExtVerifier, VerifierRegistry};
+        use super::super::{
+            app, metrics, profiles, renew_after, Archive, Config, Constraints, Output, State,
+            BUNDLE, CONTENT_TRANSFER_ENCODING, NOT_AFTER_HEADER, PKCS10, PKIX_CERT,
+            PLATFORM_HEADER, PROFILE_HEADER, RENEW_AFTER_HEADER, SERIAL_HEADER, VERBOSE_HEADER,
+        };
+        #[cfg(feature = "pqc")]
+        use super::super::{clock, KeyAlgorithm};
         use super::{init_tracing, TRACING};
 
         use attestation::crypto::{CertReqInfoExt, PrivateKeyInfoExt, TbsCertificateExt};
+        use const_oid::db::rfc5280::{
+            ID_CE_EXT_KEY_USAGE, ID_CE_KEY_USAGE, ID_CE_SUBJECT_ALT_NAME, ID_KP_SERVER_AUTH,
+            ID_PE_AUTHORITY_INFO_ACCESS,
+        };
         use const_oid::db::rfc5912::{ID_EXTENSION_REQ, SECP_256_R_1};
         use const_oid::ObjectIdentifier;
-        use der::{AnyRef, Decode, Encode};
+        use der::{AnyRef, DateTime, Decode, Encode};
         use x509::attr::Attribute;
+        use x509::ext::pkix::name::GeneralName;
+        use x509::ext::pkix::{ExtendedKeyUsage, KeyUsage, KeyUsages, SubjectAltName};
         use x509::request::{CertReq, CertReqInfo, ExtensionReq};
         use x509::{ext::Extension, name::RdnSequence};
         use x509::{Certificate, PkiPath};
 
+        use std::io::{Read, Write};
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
         use axum::response::Response;
-        use http::header::CONTENT_TYPE;
+        use base64::engine::general_purpose::STANDARD as BASE64;
+        use base64::Engine;
+        use der::pem::LineEnding;
+        use flate2::read::GzDecoder;
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use http::header::{ACCEPT, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE, LINK};
         use http::{Request, StatusCode};
         use hyper::Body;
         use rstest::rstest;
@@ -491,108 +2529,916 @@ mod tests {
                 values: vec![any].try_into().unwrap(),
             };
 
-            // Create a certification request information structure.
-            let cri = CertReqInfo {
-                version: x509::request::Version::V1,
-                attributes: vec![att].try_into().unwrap(),
-                subject: RdnSequence::default(),
-                public_key: spki,
+            // Create a certification request information structure.
+            let cri = CertReqInfo {
+                version: x509::request::Version::V1,
+                attributes: vec![att].try_into().unwrap(),
+                subject: RdnSequence::default(),
+                public_key: spki,
+            };
+
+            // Sign the request.
+            let signed = cri.sign(&pki).unwrap();
+            if multi {
+                vec![CertReq::from_der(&signed).unwrap()].to_vec().unwrap()
+            } else {
+                signed
+            }
+        }
+
+        async fn attest_response(state: State, response: Response, multi: bool) {
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+            let path = if multi {
+                let response = Output::from_der(body.as_ref()).unwrap();
+                let mut path = response.chain;
+                path.push(response.issued[0].clone());
+                path
+            } else {
+                PkiPath::from_der(&body).unwrap()
+            };
+
+            let issr = Certificate::from_der(&state.crt).unwrap();
+            assert_eq!(2, path.len());
+            assert_eq!(issr, path[0]);
+            issr.tbs_certificate.verify_crt(&path[1]).unwrap();
+        }
+
+        #[test]
+        fn reencode_multi() {
+            let encoded = cr(SECP_256_R_1, vec![], true);
+            let crs = Vec::<CertReq<'_>>::from_der(&encoded).unwrap();
+            assert_eq!(crs.len(), 1);
+
+            let encoded: Vec<u8> = crs[0].to_vec().unwrap();
+            let decoded = CertReq::from_der(&encoded).unwrap();
+            let reencoded: Vec<u8> = decoded.to_vec().unwrap();
+            assert_eq!(encoded, reencoded);
+        }
+
+        #[test]
+        fn reencode_single() {
+            let encoded = cr(SECP_256_R_1, vec![], false);
+            let decoded = CertReq::from_der(&encoded).unwrap();
+            let reencoded = decoded.to_vec().unwrap();
+            assert_eq!(encoded, reencoded);
+        }
+
+        #[rstest]
+        #[case(PKCS10, false)]
+        #[case(BUNDLE, true)]
+        #[case("application/pkcs10; name=csr.p10", false)]
+        #[case("application/pkcs10;name=\"csr.p10\"", false)]
+        #[case("Application/PKCS10", false)]
+        #[case("application/pkcs10; charset=binary", false)]
+        #[case("application/vnd.steward.pkcs10-bundle.v1; charset=utf-8", true)]
+        #[tokio::test]
+        async fn kvm_certs(#[case] header: &str, #[case] multi: bool) {
+            TRACING.call_once(init_tracing);
+            let ext = Extension {
+                extn_id: Kvm::OID,
+                critical: false,
+                extn_value: &[],
+            };
+
+            let request = Request::builder()
+                .method("POST")
+                .uri("/")
+                .header(CONTENT_TYPE, header)
+                .body(Body::from(cr(SECP_256_R_1, vec![ext], multi)))
+                .unwrap();
+
+            let response = app(certificates_state()).oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            attest_response(certificates_state(), response, multi).await;
+        }
+
+        #[rstest]
+        #[case(PKCS10, false)]
+        #[case(BUNDLE, true)]
+        #[tokio::test]
+        async fn kvm_hostname(#[case] header: &str, #[case] multi: bool) {
+            TRACING.call_once(init_tracing);
+            let ext = Extension {
+                extn_id: Kvm::OID,
+                critical: false,
+                extn_value: &[],
+            };
+
+            let request = Request::builder()
+                .method("POST")
+                .uri("/")
+                .header(CONTENT_TYPE, header)
+                .body(Body::from(cr(SECP_256_R_1, vec![ext], multi)))
+                .unwrap();
+
+            let state = hostname_state();
+            let response = app(state.clone()).oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            attest_response(state, response, multi).await;
+        }
+
+        #[cfg(feature = "pqc")]
+        #[rstest]
+        #[case(KeyAlgorithm::MlDsa65)]
+        #[case(KeyAlgorithm::MlDsa44P256)]
+        #[tokio::test]
+        async fn kvm_pqc(#[case] algorithm: KeyAlgorithm) {
+            use attestation::crypto::pqc::ML_DSA_44_ECDSA_P256;
+
+            TRACING.call_once(init_tracing);
+            let ext = Extension {
+                extn_id: Kvm::OID,
+                critical: false,
+                extn_value: &[],
+            };
+
+            let request = Request::builder()
+                .method("POST")
+                .uri("/")
+                .header(CONTENT_TYPE, PKCS10)
+                .body(Body::from(cr(ML_DSA_44_ECDSA_P256, vec![ext], false)))
+                .unwrap();
+
+            let clock = Arc::new(clock::System);
+            let state =
+                State::generate_as(None, "localhost", &Default::default(), algorithm, clock)
+                    .unwrap();
+            let response = app(state.clone()).oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            attest_response(state, response, false).await;
+        }
+
+        /// Accepts evidence reading "ok" from a platform that doesn't exist.
+        #[derive(Debug)]
+        struct Fake;
+
+        #[async_trait::async_trait]
+        impl ExtVerifier for Fake {
+            fn platform(&self) -> &'static str {
+                "fake"
+            }
+
+            async fn prepare<'a>(
+                &'a self,
+                _state: &'a State,
+                _config: &'a Config,
+                _cri: &'a CertReqInfo<'_>,
+                ext: &'a Extension<'_>,
+                _dbg: bool,
+            ) -> Result<Appraiser<'a>, StatusCode> {
+                Ok(Box::new(move || {
+                    anyhow::ensure!(ext.extn_value == b"ok", "invalid fake evidence");
+                    Ok(Appraisal::new("fake", true).with_measurement("hash", b"ok"))
+                }))
+            }
+        }
+
+        #[tokio::test]
+        async fn registry() {
+            TRACING.call_once(init_tracing);
+            const FAKE: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.58270.1.99");
+
+            let attest = |state: State, ext: Extension<'static>| async move {
+                let request = Request::builder()
+                    .method("POST")
+                    .uri("/")
+                    .header(CONTENT_TYPE, PKCS10)
+                    .body(Body::from(cr(SECP_256_R_1, vec![ext], false)))
+                    .unwrap();
+                app(state).oneshot(request).await.unwrap().status()
+            };
+            let fake = |extn_value| Extension {
+                extn_id: FAKE,
+                critical: false,
+                extn_value,
+            };
+
+            let mut verifiers = VerifierRegistry::default();
+            verifiers.register(FAKE, Fake);
+            let state = hostname_state().with_verifiers(verifiers);
+            assert_eq!(attest(state.clone(), fake(b"ok")).await, StatusCode::OK);
+            assert_eq!(
+                attest(state.clone(), fake(b"no")).await,
+                StatusCode::BAD_REQUEST
+            );
+
+            // Unregistered platforms are just unknown extensions.
+            let kvm = Extension {
+                extn_id: Kvm::OID,
+                critical: false,
+                extn_value: &[],
+            };
+            assert_eq!(attest(state, kvm.clone()).await, StatusCode::OK);
+            let state = hostname_state().with_verifiers(VerifierRegistry::empty());
+            assert_eq!(attest(state, kvm).await, StatusCode::BAD_REQUEST);
+        }
+
+        #[tokio::test]
+        async fn appraisal() {
+            TRACING.call_once(init_tracing);
+            const FAKE: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.58270.1.98");
+
+            let mut verifiers = VerifierRegistry::empty();
+            verifiers.register(FAKE, Fake);
+            let state = hostname_state()
+                .with_verifiers(verifiers)
+                .with_archive(Archive::new(Duration::from_secs(60)));
+
+            let ext = Extension {
+                extn_id: FAKE,
+                critical: false,
+                extn_value: b"ok",
+            };
+            let request = Request::builder()
+                .method("POST")
+                .uri("/")
+                .header(CONTENT_TYPE, PKCS10)
+                .body(Body::from(cr(SECP_256_R_1, vec![ext], false)))
+                .unwrap();
+            let response = app(state.clone()).oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[PLATFORM_HEADER], "fake");
+            assert!(metrics::APPRAISALS.get("fake") > 0);
+            assert!(metrics::VERIFY_DURATION.count("fake") > 0);
+            for stage in ["parse", "policy", "sign"] {
+                assert!(metrics::STAGE_DURATION.count(stage) > 0, "{stage}");
+            }
+
+            // The archived evidence says what the appraisal found.
+            let serial = response.headers()[SERIAL_HEADER].to_str().unwrap();
+            let serial = hex::decode(serial).unwrap();
+            let sealed = state.store.archived(&serial).await.unwrap().unwrap();
+            let evidence = state
+                .archive
+                .as_ref()
+                .unwrap()
+                .open(&serial, &sealed)
+                .unwrap();
+            assert_eq!(evidence.platforms, ["fake"]);
+            let expected = Appraisal::new("fake", true).with_measurement("hash", b"ok");
+            assert_eq!(evidence.appraisals, [expected]);
+        }
+
+        fn gzip(data: &[u8]) -> Vec<u8> {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        }
+
+        fn kvm_cr() -> Vec<u8> {
+            let ext = Extension {
+                extn_id: Kvm::OID,
+                critical: false,
+                extn_value: &[],
+            };
+            cr(SECP_256_R_1, vec![ext], false)
+        }
+
+        #[tokio::test]
+        async fn deterministic() {
+            use super::super::clock::Manual;
+            use super::super::serials::Seeded;
+            use std::time::UNIX_EPOCH;
+
+            TRACING.call_once(init_tracing);
+            let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+            let state = || {
+                certificates_state()
+                    .with_clock(Arc::new(Manual::new(now)))
+                    .with_serials(Arc::new(Seeded::new(7)))
+            };
+            let issue = |state: State, cr: Vec<u8>| async move {
+                let request = Request::builder()
+                    .method("POST")
+                    .uri("/")
+                    .header(CONTENT_TYPE, PKCS10)
+                    .body(Body::from(cr))
+                    .unwrap();
+                let response = app(state).oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                hyper::body::to_bytes(response.into_body()).await.unwrap()
+            };
+
+            // The same request is issued the same certificate, byte for byte.
+            let cr = kvm_cr();
+            let (a, b) = (state(), state());
+            let first = issue(a.clone(), cr.clone()).await;
+            assert_eq!(first, issue(b.clone(), cr.clone()).await);
+
+            let path = PkiPath::from_der(&first).unwrap();
+            let tbs = &path[1].tbs_certificate;
+            assert_eq!(tbs.validity.not_before.to_system_time(), now);
+
+            // Later serials follow from the seed too, but differ.
+            let second = issue(a, cr.clone()).await;
+            assert_eq!(second, issue(b, cr).await);
+            assert_ne!(first, second);
+        }
+
+        #[tokio::test]
+        async fn compressed() {
+            TRACING.call_once(init_tracing);
+            let request = Request::builder()
+                .method("POST")
+                .uri("/")
+                .header(CONTENT_TYPE, PKCS10)
+                .header(CONTENT_ENCODING, "gzip")
+                .header(ACCEPT_ENCODING, "gzip")
+                .body(Body::from(gzip(&kvm_cr())))
+                .unwrap();
+
+            let state = hostname_state();
+            let response = app(state.clone()).oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let mut der = Vec::new();
+            GzDecoder::new(body.as_ref()).read_to_end(&mut der).unwrap();
+            let path = PkiPath::from_der(&der).unwrap();
+            assert_eq!(path.len(), 2);
+        }
+
+        #[tokio::test]
+        async fn metadata() {
+            TRACING.call_once(init_tracing);
+            let request = Request::builder()
+                .method("POST")
+                .uri("/")
+                .header(CONTENT_TYPE, PKCS10)
+                .body(Body::from(kvm_cr()))
+                .unwrap();
+
+            let response = app(hostname_state()).oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let headers = response.headers().clone();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let path = PkiPath::from_der(&body).unwrap();
+            let tbs = &path[1].tbs_certificate;
+
+            let serial = hex::encode(tbs.serial_number.as_bytes());
+            assert_eq!(headers[SERIAL_HEADER], serial.as_str());
+            assert_eq!(headers[PLATFORM_HEADER], "kvm");
+            let not_after = tbs.validity.not_after.to_date_time().to_string();
+            assert_eq!(headers[NOT_AFTER_HEADER], not_after.as_str());
+            let renew_after = DateTime::from_system_time(renew_after(&tbs.validity)).unwrap();
+            assert_eq!(
+                headers[RENEW_AFTER_HEADER],
+                renew_after.to_string().as_str()
+            );
+        }
+
+        #[derive(Debug, Default)]
+        struct Recorder(Mutex<Vec<Event>>);
+
+        impl Sink for Recorder {
+            fn emit(&self, event: &Event) {
+                self.0.lock().unwrap().push(event.clone());
+            }
+        }
+
+        #[tokio::test]
+        async fn audit_events() {
+            TRACING.call_once(init_tracing);
+            let recorder = Arc::new(Recorder::default());
+            let state = hostname_state().with_audit_sink(recorder.clone());
+
+            for body in [kvm_cr(), vec![0x01, 0x02, 0x03, 0x04]] {
+                let request = Request::builder()
+                    .method("POST")
+                    .uri("/")
+                    .header(CONTENT_TYPE, PKCS10)
+                    .header(REQUEST_ID_HEADER, "enroll-42")
+                    .body(Body::from(body))
+                    .unwrap();
+                let response = app(state.clone()).oneshot(request).await.unwrap();
+                assert_eq!(response.headers()[REQUEST_ID_HEADER], "enroll-42");
+            }
+
+            let events = recorder.0.lock().unwrap();
+            let kinds: Vec<_> = events.iter().map(|e| e.event.as_str()).collect();
+            assert_eq!(kinds, ["issuance", "rejection"]);
+            assert_eq!(events[0].detail["platforms"][0], "kvm");
+            assert_eq!(events[1].detail["status"], 400);
+            for event in events.iter() {
+                assert_eq!(event.request_id.as_deref(), Some("enroll-42"));
+            }
+        }
+
+        #[tokio::test]
+        async fn max_leaf_ttl() {
+            TRACING.call_once(init_tracing);
+            let request = Request::builder()
+                .method("POST")
+                .uri("/")
+                .header(CONTENT_TYPE, PKCS10)
+                .body(Body::from(kvm_cr()))
+                .unwrap();
+
+            let hour = Duration::from_secs(60 * 60);
+            let state = hostname_state().with_max_leaf_ttl(hour);
+            let response = app(state).oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let path = PkiPath::from_der(&body).unwrap();
+
+            let validity = &path[1].tbs_certificate.validity;
+            let start = validity.not_before.to_system_time();
+            assert_eq!(validity.not_after.to_system_time(), start + hour);
+            assert_eq!(renew_after(validity), start + hour * 2 / 3);
+        }
+
+        /// Issues a cross-certificate for the CA of `state` by that of `other`.
+        fn cross(state: &State, other: &State) -> String {
+            let ca = Certificate::from_der(&state.crt).unwrap();
+            let mut tbs = Certificate::from_der(&other.crt).unwrap().tbs_certificate;
+            tbs.subject = ca.tbs_certificate.subject.clone();
+            tbs.subject_public_key_info = ca.tbs_certificate.subject_public_key_info;
+
+            let pki = PrivateKeyInfo::from_der(&other.key).unwrap();
+            let crt = tbs.sign(&pki).unwrap();
+            der::pem::encode_string("CERTIFICATE", LineEnding::LF, &crt).unwrap()
+        }
+
+        #[tokio::test]
+        async fn cross_signed() {
+            TRACING.call_once(init_tracing);
+            let request = |uri: &str| {
+                Request::builder()
+                    .method("POST")
+                    .uri(uri)
+                    .header(CONTENT_TYPE, PKCS10)
+                    .body(Body::from(kvm_cr()))
+                    .unwrap()
+            };
+
+            // Another root, which may certify CAs.
+            let constraints = Constraints {
+                path_len: 1,
+                permitted: vec![],
+            };
+            let other =
+                State::generate_constrained(None, "other.example.com", &constraints).unwrap();
+            let state = hostname_state();
+            let pem = cross(&state, &other);
+            let state = state.with_cross_chain(pem.as_bytes()).unwrap();
+
+            // By default, the chain to the CA's own root, pointing to the other.
+            let response = app(state.clone()).oneshot(request("/")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[LINK], "<?chain=1>;rel=\"alternate\"");
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let path = PkiPath::from_der(&body).unwrap();
+            assert_eq!(path[0].to_vec().unwrap(), state.crt);
+
+            // Or that through the cross-certificate, to the other root.
+            let response = app(state.clone())
+                .oneshot(request("/?chain=1"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[LINK], "<?chain=0>;rel=\"alternate\"");
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let path = PkiPath::from_der(&body).unwrap();
+            assert_eq!(path.len(), 2);
+            let root = Certificate::from_der(&other.crt).unwrap();
+            root.tbs_certificate.verify_crt(&path[0]).unwrap();
+            path[0].tbs_certificate.verify_crt(&path[1]).unwrap();
+
+            let response = app(state.clone())
+                .oneshot(request("/?chain=2"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+            // Only certificates for the CA's name and key are accepted.
+            let pem = cross(&other, &state);
+            assert!(hostname_state().with_cross_chain(pem.as_bytes()).is_err());
+        }
+
+        #[tokio::test]
+        async fn root() {
+            TRACING.call_once(init_tracing);
+            let request = |accept: &str| {
+                Request::builder()
+                    .method("POST")
+                    .uri("/")
+                    .header(CONTENT_TYPE, PKCS10)
+                    .header(ACCEPT, accept)
+                    .body(Body::from(kvm_cr()))
+                    .unwrap()
+            };
+            let len = |state: &State, accept: &str| {
+                let request = request(accept);
+                let state = state.clone();
+                async move {
+                    let response = app(state).oneshot(request).await.unwrap();
+                    assert_eq!(response.status(), StatusCode::OK);
+                    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                    PkiPath::from_der(&body).unwrap().len()
+                }
+            };
+
+            // Clients may leave the root out, or have it back.
+            let mut state = hostname_state();
+            assert_eq!(len(&state, "*/*").await, 2);
+            assert_eq!(len(&state, "*/*; root=exclude").await, 1);
+
+            state.config_mut().exclude_root = true;
+            assert_eq!(len(&state, "*/*").await, 1);
+            assert_eq!(len(&state, "text/plain, */*;q=0.5;root=include").await, 2);
+        }
+
+        #[tokio::test]
+        async fn leaf() {
+            TRACING.call_once(init_tracing);
+            let request = Request::builder()
+                .method("POST")
+                .uri("/")
+                .header(CONTENT_TYPE, PKCS10)
+                .header(ACCEPT, "application/PKIX-cert, */*;q=0.5")
+                .body(Body::from(kvm_cr()))
+                .unwrap();
+
+            // Clients may have the issued certificate alone.
+            let response = app(hostname_state()).oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[CONTENT_TYPE], PKIX_CERT);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let cert = Certificate::from_der(&body).unwrap();
+            let issuer = Certificate::from_der(&hostname_state().crt).unwrap();
+            assert_eq!(cert.tbs_certificate.issuer, issuer.tbs_certificate.subject);
+        }
+
+        #[tokio::test]
+        async fn crl_distribution() {
+            TRACING.call_once(init_tracing);
+            let request = Request::builder()
+                .method("POST")
+                .uri("/")
+                .header(CONTENT_TYPE, PKCS10)
+                .body(Body::from(kvm_cr()))
+                .unwrap();
+
+            let distribution = crl::Distribution::new("https://ca.example.com/crl", 16).unwrap();
+            let state = hostname_state().with_crl_distribution(distribution.clone());
+            let response = app(state.clone()).oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let path = PkiPath::from_der(&body).unwrap();
+
+            // The certificate names the CRLs of its shard, which are served.
+            let tbs = &path[1].tbs_certificate;
+            let shard = distribution.shard(tbs.serial_number.as_bytes());
+            assert_eq!(tbs.get_crl_urls().unwrap(), [distribution.base_url(shard)]);
+            for uri in [format!("/crl/{shard}"), format!("/crl/{shard}/delta")] {
+                let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+                let response = app(state.clone()).oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(response.headers()[CONTENT_TYPE], crl::PKIX_CRL);
+            }
+
+            let request = Request::builder()
+                .uri("/crl/16")
+                .body(Body::empty())
+                .unwrap();
+            let response = app(state).oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+
+        #[tokio::test]
+        async fn validity_profile() {
+            TRACING.call_once(init_tracing);
+            let request = || {
+                Request::builder()
+                    .method("POST")
+                    .uri("/")
+                    .header(CONTENT_TYPE, PKCS10)
+                    .body(Body::from(kvm_cr()))
+                    .unwrap()
+            };
+
+            let mut state = hostname_state();
+            state.config_mut().validity = toml::from_str(
+                r#"
+                [profiles.ephemeral]
+                lifetime = 3600
+                backdate = 300
+                extended_key_usage = ["server_auth"]
+                must_staple = true
+                ocsp = "http://ocsp.example.com"
+
+                [[rules]]
+                platform = "kvm"
+                profile = "ephemeral"
+                "#,
+            )
+            .unwrap();
+            let response = app(state.clone()).oneshot(request()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let path = PkiPath::from_der(&body).unwrap();
+
+            let validity = &path[1].tbs_certificate.validity;
+            let start = validity.not_before.to_system_time();
+            let lifetime = Duration::from_secs(3600 + 300);
+            assert_eq!(validity.not_after.to_system_time(), start + lifetime);
+
+            // As are the profile's extensions.
+            let tbs = &path[1].tbs_certificate;
+            let eku = tbs
+                .extensions::<ExtendedKeyUsage>(ID_CE_EXT_KEY_USAGE)
+                .unwrap();
+            assert_eq!(eku[0].1 .0, [ID_KP_SERVER_AUTH]);
+            let oids: Vec<_> = tbs.extensions.iter().flatten().map(|e| e.extn_id).collect();
+            assert!(oids.contains(&ID_PE_AUTHORITY_INFO_ACCESS));
+            assert!(oids.contains(&ObjectIdentifier::new_unwrap("1.3.6.1.5.5.7.1.24")));
+
+            // The cap on lifetimes still applies.
+            let hour = Duration::from_secs(60 * 60);
+            let state = state.with_max_leaf_ttl(hour / 2);
+            let response = app(state).oneshot(request()).await.unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let validity = PkiPath::from_der(&body).unwrap()[1]
+                .tbs_certificate
+                .validity;
+            let start = validity.not_before.to_system_time();
+            let lifetime = Duration::from_secs(1800 + 300);
+            assert_eq!(validity.not_after.to_system_time(), start + lifetime);
+        }
+
+        #[tokio::test]
+        async fn requested_profile() {
+            TRACING.call_once(init_tracing);
+            let oid = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.58270.3.1");
+            let oid = oid.to_vec().unwrap();
+            let template = [&[0x30, oid.len() as u8][..], &oid].concat();
+            let request = |profile: Option<&str>, template: bool| {
+                let mut extensions = vec![Extension {
+                    extn_id: Kvm::OID,
+                    critical: false,
+                    extn_value: &[],
+                }];
+                if template {
+                    extensions.push(Extension {
+                        extn_id: profiles::ID_CERTIFICATE_TEMPLATE,
+                        critical: false,
+                        extn_value: &template,
+                    });
+                }
+                let mut request = Request::builder()
+                    .method("POST")
+                    .uri("/")
+                    .header(CONTENT_TYPE, PKCS10);
+                if let Some(profile) = profile {
+                    request = request.header(PROFILE_HEADER, profile);
+                }
+                request
+                    .body(Body::from(cr(SECP_256_R_1, extensions, false)))
+                    .unwrap()
+            };
+
+            let mut state = hostname_state();
+            state.config_mut().validity = toml::from_str(
+                r#"
+                [profiles.server-tls]
+                lifetime = 3600
+                oid = "1.3.6.1.4.1.58270.3.1"
+                key_usage = ["digital_signature", "key_encipherment"]
+                extended_key_usage = ["server_auth"]
+                dns_names = ["api.example.com"]
+                platforms = ["kvm"]
+
+                [profiles.builder]
+                lifetime = 3600
+                extended_key_usage = ["code_signing"]
+
+                [profiles.tenant]
+                lifetime = 3600
+                platforms = ["kvm"]
+                tenants = ["abcd"]
+                "#,
+            )
+            .unwrap();
+            state.policy().config.validity.validate().unwrap();
+
+            // Clients may ask for a profile by name or OID.
+            for (profile, template) in [(Some("server-tls"), false), (None, true)] {
+                let request = request(profile, template);
+                let response = app(state.clone()).oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let path = PkiPath::from_der(&body).unwrap();
+
+                let tbs = &path[1].tbs_certificate;
+                let validity = &tbs.validity;
+                let start = validity.not_before.to_system_time();
+                let lifetime = Duration::from_secs(3600);
+                assert_eq!(validity.not_after.to_system_time(), start + lifetime);
+                let ku = tbs.extensions::<KeyUsage>(ID_CE_KEY_USAGE).unwrap();
+                assert_eq!(
+                    ku[0].1 .0,
+                    KeyUsages::DigitalSignature | KeyUsages::KeyEncipherment
+                );
+                let sans = tbs
+                    .extensions::<SubjectAltName>(ID_CE_SUBJECT_ALT_NAME)
+                    .unwrap();
+                let named = |name: &GeneralName<'_>| matches!(name, GeneralName::DnsName(dns) if dns.as_str() == "api.example.com");
+                assert!(sans[0].1 .0.iter().any(named));
+            }
+
+            // But only for profiles which the policy lets them ask for.
+            for (profile, template, status) in [
+                (Some("builder"), false, StatusCode::FORBIDDEN),
+                (Some("tenant"), false, StatusCode::FORBIDDEN),
+                (Some("missing"), false, StatusCode::FORBIDDEN),
+                (Some("builder"), true, StatusCode::BAD_REQUEST),
+            ] {
+                let request = request(profile, template);
+                let response = app(state.clone()).oneshot(request).await.unwrap();
+                assert_eq!(response.status(), status, "{profile:?}");
+            }
+
+            // Without the profile, the template extension is refused.
+            state.config_mut().validity = Default::default();
+            let response = app(state).oneshot(request(None, true)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+
+        #[tokio::test]
+        async fn verbose() {
+            TRACING.call_once(init_tracing);
+            let request = |uri: &str| {
+                Request::builder()
+                    .method("POST")
+                    .uri(uri)
+                    .header(CONTENT_TYPE, PKCS10)
+                    .header(VERBOSE_HEADER, "appraisal")
+                    .body(Body::from(kvm_cr()))
+                    .unwrap()
             };
 
-            // Sign the request.
-            let signed = cri.sign(&pki).unwrap();
-            if multi {
-                vec![CertReq::from_der(&signed).unwrap()].to_vec().unwrap()
-            } else {
-                signed
+            // Unless the policy allows it, the request is answered as usual.
+            let mut state = hostname_state();
+            let response = app(state.clone()).oneshot(request("/")).await.unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(PkiPath::from_der(&body).unwrap().len(), 2);
+
+            state.config_mut().verbose = true;
+            for uri in ["/", "/?verbose=appraisal"] {
+                let response = app(state.clone()).oneshot(request(uri)).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+                let der = BASE64.decode(json["chain"].as_str().unwrap()).unwrap();
+                assert_eq!(PkiPath::from_der(&der).unwrap().len(), 2);
+                assert_eq!(json["appraisals"][0][0]["platform"], "kvm");
             }
         }
 
-        async fn attest_response(state: State, response: Response, multi: bool) {
-            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        #[tokio::test]
+        async fn parsing() {
+            use attestation::parse::Strictness;
 
-            let path = if multi {
-                let response = Output::from_der(body.as_ref()).unwrap();
-                let mut path = response.chain;
-                path.push(response.issued[0].clone());
-                path
-            } else {
-                PkiPath::from_der(&body).unwrap()
+            TRACING.call_once(init_tracing);
+            let request = |body: Vec<u8>| {
+                Request::builder()
+                    .method("POST")
+                    .uri("/")
+                    .header(CONTENT_TYPE, PKCS10)
+                    .header(VERBOSE_HEADER, "appraisal")
+                    .body(Body::from(body))
+                    .unwrap()
             };
 
-            let issr = Certificate::from_der(&state.crt).unwrap();
-            assert_eq!(2, path.len());
-            assert_eq!(issr, path[0]);
-            issr.tbs_certificate.verify_crt(&path[1]).unwrap();
-        }
+            // The same request, with its outer length in more octets than needed.
+            let der = kvm_cr();
+            let cr = CertReq::from_der(&der).unwrap();
+            let len = cr.info.to_vec().unwrap().len()
+                + cr.algorithm.to_vec().unwrap().len()
+                + cr.signature.to_vec().unwrap().len();
+            let mut ber = vec![0x30, 0x84];
+            ber.extend_from_slice(&(len as u32).to_be_bytes());
+            ber.extend_from_slice(&der[der.len() - len..]);
 
-        #[test]
-        fn reencode_multi() {
-            let encoded = cr(SECP_256_R_1, vec![], true);
-            let crs = Vec::<CertReq<'_>>::from_der(&encoded).unwrap();
-            assert_eq!(crs.len(), 1);
+            let mut state = hostname_state();
+            state.config_mut().verbose = true;
+            let response = app(state.clone()).oneshot(request(ber.clone())).await;
+            assert_eq!(response.unwrap().status(), StatusCode::BAD_REQUEST);
 
-            let encoded: Vec<u8> = crs[0].to_vec().unwrap();
-            let decoded = CertReq::from_der(&encoded).unwrap();
-            let reencoded: Vec<u8> = decoded.to_vec().unwrap();
-            assert_eq!(encoded, reencoded);
-        }
+            // Requests received elsewhere are held to the same policy.
+            assert_eq!(
+                super::super::normalized(Strictness::Strict, &der).unwrap(),
+                der
+            );
+            assert!(super::super::normalized(Strictness::Strict, &ber).is_err());
+            assert_eq!(
+                super::super::normalized(Strictness::Compat, &ber).unwrap(),
+                der
+            );
 
-        #[test]
-        fn reencode_single() {
-            let encoded = cr(SECP_256_R_1, vec![], false);
-            let decoded = CertReq::from_der(&encoded).unwrap();
-            let reencoded = decoded.to_vec().unwrap();
-            assert_eq!(encoded, reencoded);
+            for (parsing, body, decision) in [
+                (Strictness::Strict, der, "request is canonical der"),
+                (Strictness::Compat, ber, "request normalized from ber"),
+            ] {
+                state.config_mut().parsing = parsing;
+                let response = app(state.clone()).oneshot(request(body)).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                let decisions = json["appraisals"][0][0]["decisions"].as_array().unwrap();
+                assert!(decisions.contains(&decision.into()), "{parsing:?}");
+            }
         }
 
-        #[rstest]
-        #[case(PKCS10, false)]
-        #[case(BUNDLE, true)]
         #[tokio::test]
-        async fn kvm_certs(#[case] header: &str, #[case] multi: bool) {
+        async fn base64() {
             TRACING.call_once(init_tracing);
-            let ext = Extension {
-                extn_id: Kvm::OID,
-                critical: false,
-                extn_value: &[],
-            };
 
+            // Wrapped, as EST clients do.
+            let text = BASE64.encode(kvm_cr());
+            let lines: Vec<_> = text.as_bytes().chunks(64).collect();
             let request = Request::builder()
                 .method("POST")
                 .uri("/")
-                .header(CONTENT_TYPE, header)
-                .body(Body::from(cr(SECP_256_R_1, vec![ext], multi)))
+                .header(CONTENT_TYPE, PKCS10)
+                .header(CONTENT_TRANSFER_ENCODING, "base64")
+                .body(Body::from(lines.join(&b"\r\n"[..])))
                 .unwrap();
 
-            let response = app(certificates_state()).oneshot(request).await.unwrap();
+            let response = app(hostname_state()).oneshot(request).await.unwrap();
             assert_eq!(response.status(), StatusCode::OK);
-            attest_response(certificates_state(), response, multi).await;
+            assert_eq!(response.headers()[CONTENT_TRANSFER_ENCODING], "base64");
+
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let path = PkiPath::from_der(&BASE64.decode(body).unwrap()).unwrap();
+            assert_eq!(path.len(), 2);
+
+            // Neither garbage nor unknown encodings are accepted.
+            for (encoding, body) in [("base64", &b"!!"[..]), ("quoted-printable", &b""[..])] {
+                let request = Request::builder()
+                    .method("POST")
+                    .uri("/")
+                    .header(CONTENT_TYPE, PKCS10)
+                    .header(CONTENT_TRANSFER_ENCODING, encoding)
+                    .body(Body::from(body))
+                    .unwrap();
+                let response = app(hostname_state()).oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            }
         }
 
-        #[rstest]
-        #[case(PKCS10, false)]
-        #[case(BUNDLE, true)]
         #[tokio::test]
-        async fn kvm_hostname(#[case] header: &str, #[case] multi: bool) {
+        async fn pem() {
             TRACING.call_once(init_tracing);
-            let ext = Extension {
-                extn_id: Kvm::OID,
-                critical: false,
-                extn_value: &[],
+            let request = |label| {
+                let pem = der::pem::encode_string(label, LineEnding::LF, &kvm_cr()).unwrap();
+                Request::builder()
+                    .method("POST")
+                    .uri("/")
+                    .header(CONTENT_TYPE, PKCS10)
+                    .body(Body::from(pem))
+                    .unwrap()
             };
 
+            for label in ["CERTIFICATE REQUEST", "NEW CERTIFICATE REQUEST"] {
+                let response = app(hostname_state()).oneshot(request(label)).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                assert_eq!(PkiPath::from_der(&body).unwrap().len(), 2);
+            }
+
+            let response = app(hostname_state()).oneshot(request("CERTIFICATE")).await;
+            assert_eq!(response.unwrap().status(), StatusCode::BAD_REQUEST);
+        }
+
+        #[tokio::test]
+        async fn err_body_limit() {
+            TRACING.call_once(init_tracing);
+            let state = hostname_state().with_body_limit(64);
+
             let request = Request::builder()
                 .method("POST")
                 .uri("/")
-                .header(CONTENT_TYPE, header)
-                .body(Body::from(cr(SECP_256_R_1, vec![ext], multi)))
+                .header(CONTENT_TYPE, PKCS10)
+                .body(Body::from(kvm_cr()))
                 .unwrap();
-
-            let state = hostname_state();
             let response = app(state.clone()).oneshot(request).await.unwrap();
-            assert_eq!(response.status(), StatusCode::OK);
-            attest_response(state, response, multi).await;
+            assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+            // A small compressed body may not expand beyond the limit.
+            let body = gzip(&vec![0; 1 << 20]);
+            let request = Request::builder()
+                .method("POST")
+                .uri("/")
+                .header(CONTENT_TYPE, PKCS10)
+                .header(CONTENT_ENCODING, "gzip")
+                .body(Body::from(body))
+                .unwrap();
+            let response = app(state).oneshot(request).await.unwrap();
+            assert!(response.status().is_client_error());
         }
 
         // Though similar to the above test, this is the only test which
@@ -682,6 +3528,23 @@ mod tests {
             assert_eq!(response.status(), StatusCode::BAD_REQUEST);
         }
 
+        #[rstest]
+        #[case("application/pkcs10x")]
+        #[case("text/plain; type=application/pkcs10")]
+        #[tokio::test]
+        async fn err_wrong_content_type(#[case] header: &str) {
+            TRACING.call_once(init_tracing);
+            let request = Request::builder()
+                .method("POST")
+                .uri("/")
+                .header(CONTENT_TYPE, header)
+                .body(Body::from(cr(SECP_256_R_1, vec![], false)))
+                .unwrap();
+
+            let response = app(certificates_state()).oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+
         #[tokio::test]
         async fn err_empty_body() {
             TRACING.call_once(init_tracing);
@@ -725,17 +3588,127 @@ mod tests {
             let response = app(certificates_state()).oneshot(request).await.unwrap();
             assert_eq!(response.status(), StatusCode::BAD_REQUEST);
         }
+
+        #[tokio::test]
+        async fn extension_allowlist() {
+            TRACING.call_once(init_tracing);
+            let oid = ObjectIdentifier::new_unwrap("1.2.3.4");
+            let request = |critical| {
+                let kvm = Extension {
+                    extn_id: Kvm::OID,
+                    critical: false,
+                    extn_value: &[],
+                };
+                let other = Extension {
+                    extn_id: oid,
+                    critical,
+                    extn_value: &[0x05, 0x00],
+                };
+                Request::builder()
+                    .method("POST")
+                    .uri("/")
+                    .header(CONTENT_TYPE, PKCS10)
+                    .body(Body::from(cr(SECP_256_R_1, vec![kvm, other], false)))
+                    .unwrap()
+            };
+
+            // Not allowlisted.
+            let response = app(hostname_state()).oneshot(request(false)).await;
+            assert_eq!(response.unwrap().status(), StatusCode::BAD_REQUEST);
+
+            let mut state = hostname_state();
+            state.config_mut().extensions.copy.push(Rule {
+                oid,
+                critical: Criticality::Forbidden,
+            });
+
+            // Wrong criticality.
+            let response = app(state.clone()).oneshot(request(true)).await;
+            assert_eq!(response.unwrap().status(), StatusCode::BAD_REQUEST);
+
+            let response = app(state.clone()).oneshot(request(false)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let path = PkiPath::from_der(&body).unwrap();
+            let exts = path[1].tbs_certificate.extensions.as_ref().unwrap();
+            assert!(exts.iter().any(|e| e.extn_id == oid));
+            assert!(exts.iter().any(|e| e.extn_id == Kvm::OID));
+        }
+
+        #[tokio::test]
+        async fn openssl_csr() {
+            TRACING.call_once(init_tracing);
+            let request = || {
+                Request::builder()
+                    .method("POST")
+                    .uri("/")
+                    .header(CONTENT_TYPE, PKCS10)
+                    .body(Body::from(
+                        &include_bytes!("../../../testdata/openssl.csr")[..],
+                    ))
+                    .unwrap()
+            };
+
+            let mut state = hostname_state();
+            state.config_mut().extensions.copy.push(Rule {
+                oid: ObjectIdentifier::new_unwrap("1.2.3.4"),
+                critical: Criticality::Any,
+            });
+
+            // The challengePassword is ignored; only evidence is missing.
+            let response = app(state.clone()).oneshot(request()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+            state.config_mut().attributes.challenge_password = Handling::Reject;
+            let response = app(state).oneshot(request()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+
+        #[tokio::test]
+        async fn platform_combinations() {
+            TRACING.call_once(init_tracing);
+            let request = || {
+                let ext = Extension {
+                    extn_id: Kvm::OID,
+                    critical: false,
+                    extn_value: &[],
+                };
+                Request::builder()
+                    .method("POST")
+                    .uri("/")
+                    .header(CONTENT_TYPE, PKCS10)
+                    .body(Body::from(cr(SECP_256_R_1, vec![ext], false)))
+                    .unwrap()
+            };
+
+            let mut state = hostname_state();
+            state.config_mut().platforms.combinations = vec![["sgx".into(), "kvm".into()].into()];
+            let response = app(state.clone()).oneshot(request()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+            state
+                .config
+                .platforms
+                .combinations
+                .push(["kvm".into()].into());
+            let response = app(state).oneshot(request()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
     }
 
     // Unit tests for configuration
+    #[cfg(all(feature = "sgx", feature = "snp"))]
     mod config {
+        use super::super::verifier::VerifierRegistry;
         use super::{init_tracing, Config, TRACING};
         use attestation::sgx::quote::traits::ParseBytes;
         use attestation::sgx::quote::Quote;
+        use attestation::sgx::Sgx;
         use attestation::snp::{Evidence, PolicyFlags, Report, Snp};
         use attestation::{Digest, Measurements};
 
         use std::collections::HashSet;
+        use std::time::{Duration, SystemTime};
 
         use der::Decode;
         use sgx::parameters::MiscSelect;
@@ -767,10 +3740,11 @@ mod tests {
                             .map(|c| Certificate::from_der(c))
                             .collect::<Result<Vec<_>, _>>()?;
 
-                        // Validate the report.
-                        let pck = sgx.trusted(&chain, &quote.crls)?;
+                        // Validate the report while the canned CRLs were current.
+                        let now = quote.crls.latest_update().unwrap();
+                        let pck = sgx.trusted(&chain, &quote.crls, now)?;
                         let report = quote.verify(pck)?;
-                        sgx.verify(&csr.info, &ext, Some(conf), false)?;
+                        sgx.verify(&csr.info, &ext, Some(conf), false, now)?;
                     }
                 }
             }
@@ -805,6 +3779,25 @@ mod tests {
             assert!(config.snp.is_none());
         }
 
+        #[test]
+        fn test_config_problems() {
+            let config: Config = toml::from_str(
+                r#"
+            [platforms]
+            combinations = [[]]
+
+            [admin]
+            ttl = 0
+            "#,
+            )
+            .expect("Couldn't deserialize");
+
+            let err = config.validate().unwrap_err().to_string();
+            assert!(err.contains("[platforms]"), "{err}");
+            assert!(err.contains("[admin]"), "{err}");
+            assert!(!err.contains("[quotas]"), "{err}");
+        }
+
         #[test]
         fn test_config() {
             TRACING.call_once(init_tracing);
@@ -843,6 +3836,8 @@ mod tests {
                         .bits(),
                 ),
                 platform_info_flags: None,
+                require_id_block: false,
+                roots: Default::default(),
             };
 
             let sgx = attestation::sgx::config::Config {
@@ -856,14 +3851,26 @@ mod tests {
                     hash_blacklist: Default::default(),
                 },
                 features: Default::default(),
+                required_features: Default::default(),
+                forbidden_features: Default::default(),
+                xfrm: None,
                 enclave_security_version: None,
                 enclave_product_id: None,
                 misc_select: MiscSelect::default(),
+                config_id: Default::default(),
+                config_security_version: None,
+                extended_product_id: Default::default(),
+                family_id: Default::default(),
+                fmspc: Default::default(),
+                backend: Default::default(),
+                crls: Default::default(),
+                roots: Default::default(),
             };
 
             let steward = Config {
                 sgx: Some(sgx),
                 snp: Some(snp),
+                ..Default::default()
             };
 
             assert_eq!(config, steward);
@@ -907,6 +3914,56 @@ mod tests {
             assert!(assert_sgx_config(&csr, &config.sgx.unwrap()).is_err());
         }
 
+        #[test]
+        fn test_sgx_signed_csr_bad_config_attributes() {
+            let csr = CertReq::from_der(ICELAKE_CSR).unwrap();
+
+            // Every enclave enables the x87 and SSE state.
+            let config: Config = toml::from_str(
+                r#"
+            [sgx]
+            signer = ["c88de47bcb4c199a599c5ea0ab43bf4f4292071a16ea96c840409d95c4f98480"]
+            xfrm = { value = 0, mask = 0x3 }
+            "#,
+            )
+            .expect("Couldn't deserialize");
+            let e = assert_sgx_config(&csr, &config.sgx.unwrap()).unwrap_err();
+            assert!(e.to_string().starts_with("sgx xfrm"), "{e}");
+
+            // Debugging is either enabled or not, so one of these must fail.
+            let config: Config = toml::from_str(
+                r#"
+            [sgx]
+            signer = ["c88de47bcb4c199a599c5ea0ab43bf4f4292071a16ea96c840409d95c4f98480"]
+            required_features = ["Debug"]
+            forbidden_features = ["Debug"]
+            xfrm = { value = 0x3, mask = 0x3 }
+            "#,
+            )
+            .expect("Couldn't deserialize");
+            let e = assert_sgx_config(&csr, &config.sgx.unwrap()).unwrap_err();
+            assert!(e.to_string().contains("features"), "{e}");
+        }
+
+        #[test]
+        fn test_sgx_signed_csr_config_fmspc() {
+            let csr = CertReq::from_der(ICELAKE_CSR).unwrap();
+            let config = |fmspc: &str| -> Config {
+                toml::from_str(&format!(
+                    r#"
+            [sgx]
+            signer = ["c88de47bcb4c199a599c5ea0ab43bf4f4292071a16ea96c840409d95c4f98480"]
+            fmspc = ["{fmspc}"]
+            "#
+                ))
+                .expect("Couldn't deserialize")
+            };
+
+            assert_sgx_config(&csr, &config("00706e470000").sgx.unwrap()).unwrap();
+            let e = assert_sgx_config(&csr, &config("00906ea10000").sgx.unwrap()).unwrap_err();
+            assert!(e.to_string().contains("fmspc 00706e470000"), "{e}");
+        }
+
         #[test]
         fn test_snp_signed_canned_csr() {
             TRACING.call_once(init_tracing);
@@ -957,5 +4014,283 @@ mod tests {
 
             assert!(assert_snp_config(&csr, &config.snp.unwrap()).is_err());
         }
+
+        #[test]
+        fn test_max_evidence_age() {
+            const DAY: u64 = 60 * 60 * 24;
+
+            for csr in [ICELAKE_CSR, MILAN_CSR] {
+                let csr = CertReq::from_der(csr).unwrap();
+                let ereq: ExtensionReq<'_> = csr
+                    .info
+                    .attributes
+                    .iter()
+                    .next()
+                    .unwrap()
+                    .values
+                    .iter()
+                    .next()
+                    .unwrap()
+                    .decode_into()
+                    .unwrap();
+                let ext = &Vec::from(ereq)[0];
+                let registry = VerifierRegistry::default();
+                let verifier = registry.get(&ext.extn_id).unwrap();
+                let fresh = |max_age, now| super::super::fresh(max_age, verifier, ext, now);
+
+                let now = SystemTime::now();
+                assert!(fresh(None, now).is_ok());
+                assert!(fresh(Some(u32::MAX.into()), now).is_ok());
+
+                // The canned collateral is long past any sensible window.
+                assert!(fresh(Some(DAY), now).is_err());
+
+                // Evidence is fresh up to and including its maximum age.
+                let issued = match ext.extn_id {
+                    Sgx::OID => Sgx::issued(ext),
+                    _ => Snp::issued(ext),
+                };
+                let edge = issued.unwrap().unwrap() + Duration::from_secs(DAY);
+                assert!(fresh(Some(DAY), edge).is_ok());
+                let late = edge + Duration::from_secs(1);
+                assert!(fresh(Some(DAY), late).is_err());
+            }
+        }
+    }
+
+    mod generate {
+        use super::super::clock::{Clock, Manual};
+        use super::super::{validity, Constraints, State, LEAF_TTL};
+
+        use std::sync::Arc;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        use const_oid::db::rfc5280::{ID_CE_BASIC_CONSTRAINTS, ID_CE_NAME_CONSTRAINTS};
+        use der::Decode;
+        use x509::ext::pkix::name::GeneralName;
+        use x509::ext::pkix::{BasicConstraints, NameConstraints};
+        use x509::Certificate;
+
+        #[test]
+        fn clocked() {
+            let start = UNIX_EPOCH + Duration::from_secs(2_000_000_000);
+            let clock = Arc::new(Manual::new(start));
+            let state =
+                State::generate_with(None, "localhost", &Constraints::default(), clock.clone())
+                    .unwrap();
+            let crt = Certificate::from_der(&state.crt).unwrap();
+            let ca = crt.tbs_certificate.validity;
+            assert_eq!(ca.not_before.to_system_time(), start);
+
+            // Certificates are issued as of the state's time.
+            clock.advance(Duration::from_secs(60));
+            let now = state.clock.now();
+            assert_eq!(now, start + Duration::from_secs(60));
+            let leaf = validity(now, state.leaf_ttl(LEAF_TTL)).unwrap();
+            assert_eq!(leaf.not_before.to_system_time(), now);
+            assert_eq!(
+                leaf.not_after.to_system_time(),
+                now + Duration::from_secs(60 * 60 * 24 * 28)
+            );
+        }
+
+        #[test]
+        fn unconstrained() {
+            let state = State::generate(None, "localhost").unwrap();
+            let crt = Certificate::from_der(&state.crt).unwrap();
+            let exts = crt.tbs_certificate.extensions.unwrap();
+
+            let bc = exts.iter().find(|e| e.extn_id == ID_CE_BASIC_CONSTRAINTS);
+            let bc = BasicConstraints::from_der(bc.unwrap().extn_value).unwrap();
+            assert_eq!(bc.path_len_constraint, Some(0));
+            assert!(!exts.iter().any(|e| e.extn_id == ID_CE_NAME_CONSTRAINTS));
+        }
+
+        #[test]
+        fn constrained() {
+            let constraints = Constraints {
+                path_len: 1,
+                permitted: vec!["profian.com".into()],
+            };
+            let state = State::generate_constrained(
+                Some("steward.profian.com".into()),
+                "localhost",
+                &constraints,
+            )
+            .unwrap();
+            let crt = Certificate::from_der(&state.crt).unwrap();
+            let exts = crt.tbs_certificate.extensions.unwrap();
+
+            let bc = exts.iter().find(|e| e.extn_id == ID_CE_BASIC_CONSTRAINTS);
+            let bc = BasicConstraints::from_der(bc.unwrap().extn_value).unwrap();
+            assert_eq!(bc.path_len_constraint, Some(1));
+
+            let nc = exts.iter().find(|e| e.extn_id == ID_CE_NAME_CONSTRAINTS);
+            let nc = nc.unwrap();
+            assert!(nc.critical);
+            let nc = NameConstraints::from_der(nc.extn_value).unwrap();
+            let permitted = nc.permitted_subtrees.unwrap();
+            assert_eq!(permitted.len(), 3);
+            assert!(matches!(
+                permitted[0].base,
+                GeneralName::DnsName(d) if d.as_str() == "profian.com"
+            ));
+            assert!(matches!(
+                permitted[2].base,
+                GeneralName::UniformResourceIdentifier(u) if u.as_str() == ".profian.com"
+            ));
+            assert!(nc.excluded_subtrees.is_none());
+        }
+
+        #[test]
+        fn outside_permitted() {
+            let constraints = Constraints {
+                path_len: 0,
+                permitted: vec!["example.com".into()],
+            };
+            assert!(State::generate_constrained(None, "localhost", &constraints).is_err());
+
+            // Suffix matches must fall on a label boundary.
+            let constraints = Constraints {
+                path_len: 0,
+                permitted: vec!["ub.profian.com".into()],
+            };
+            assert!(State::generate_constrained(None, "localhost", &constraints).is_err());
+        }
+    }
+
+    mod listeners {
+        use super::super::{app, crl, operations, public, State, PKIX_CERT};
+
+        use http::header::{
+            HeaderName, ALLOW, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+        };
+        use http::{Method, Request, StatusCode};
+        use hyper::Body;
+        use tower::ServiceExt; // for `app.oneshot()`
+
+        async fn status(router: axum::Router, method: Method, uri: &str) -> StatusCode {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap();
+            router.oneshot(request).await.unwrap().status()
+        }
+
+        #[tokio::test]
+        async fn methods() {
+            let state = State::generate(None, "localhost").unwrap();
+
+            // HEAD is answered like GET, without the body.
+            for uri in ["/", "/crt"] {
+                let request = Request::builder()
+                    .method(Method::HEAD)
+                    .uri(uri)
+                    .body(Body::empty())
+                    .unwrap();
+                let response = app(state.clone()).oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                assert!(body.is_empty());
+            }
+
+            let request = Request::builder().uri("/crt").body(Body::empty()).unwrap();
+            let response = app(state.clone()).oneshot(request).await.unwrap();
+            assert_eq!(response.headers()[CONTENT_TYPE], PKIX_CERT);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(body, state.crt);
+
+            let request = Request::builder().uri("/crl").body(Body::empty()).unwrap();
+            let response = app(state.clone()).oneshot(request).await.unwrap();
+            assert_eq!(response.headers()[CONTENT_TYPE], crl::PKIX_CRL);
+
+            for (uri, allow) in [
+                ("/", "GET, HEAD, POST, OPTIONS"),
+                ("/crt", "GET, HEAD, OPTIONS"),
+                ("/crl", "GET, HEAD, OPTIONS"),
+                ("/metrics", "GET, HEAD, OPTIONS"),
+                ("/admin/credential", "POST, OPTIONS"),
+                ("/admin/nonce", "POST, OPTIONS"),
+            ] {
+                let request = Request::builder()
+                    .method(Method::OPTIONS)
+                    .uri(uri)
+                    .body(Body::empty())
+                    .unwrap();
+                let response = app(state.clone()).oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::NO_CONTENT);
+                assert_eq!(response.headers()[ALLOW], allow);
+            }
+        }
+
+        #[tokio::test]
+        async fn conditional() {
+            let state = State::generate(None, "localhost").unwrap();
+            let get = |uri: &str, condition: Option<(HeaderName, &str)>| {
+                let mut request = Request::builder().uri(uri);
+                if let Some((name, value)) = condition {
+                    request = request.header(name, value);
+                }
+                app(state.clone()).oneshot(request.body(Body::empty()).unwrap())
+            };
+
+            for uri in ["/crt", "/crl"] {
+                let response = get(uri, None).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let etag = response.headers()[ETAG].to_str().unwrap().to_owned();
+                let modified = response.headers()[LAST_MODIFIED]
+                    .to_str()
+                    .unwrap()
+                    .to_owned();
+                assert!(etag.starts_with('"'), "{etag}");
+
+                // Unchanged artifacts are not sent again.
+                for condition in [
+                    (IF_NONE_MATCH, etag.as_str()),
+                    (IF_MODIFIED_SINCE, &modified),
+                ] {
+                    let response = get(uri, Some(condition)).await.unwrap();
+                    assert_eq!(response.status(), StatusCode::NOT_MODIFIED, "{uri}");
+                    assert_eq!(response.headers()[ETAG], etag.as_str());
+                    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                    assert!(body.is_empty());
+                }
+
+                // Other copies are replaced, whatever their date.
+                let response = get(uri, Some((IF_NONE_MATCH, "\"stale\""))).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let old = "Thu, 01 Jan 1970 00:00:00 GMT";
+                let response = get(uri, Some((IF_MODIFIED_SINCE, old))).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+            }
+        }
+
+        #[tokio::test]
+        async fn split() {
+            let state = State::generate(None, "localhost").unwrap();
+
+            for (uri, public_status) in [("/", StatusCode::OK), ("/metrics", StatusCode::NOT_FOUND)]
+            {
+                assert_eq!(
+                    status(public(state.clone()), Method::GET, uri).await,
+                    public_status
+                );
+                assert_eq!(
+                    status(app(state.clone()), Method::GET, uri).await,
+                    StatusCode::OK
+                );
+            }
+
+            let ops = |uri| status(operations(state.clone()), Method::GET, uri);
+            assert_eq!(ops("/healthz").await, StatusCode::OK);
+            assert_eq!(ops("/readyz").await, StatusCode::OK);
+            assert_eq!(ops("/metrics").await, StatusCode::OK);
+            assert_eq!(ops("/v1/capabilities").await, StatusCode::NOT_FOUND);
+
+            // Workloads cannot attest on the operational listener.
+            let attest = status(operations(state), Method::POST, "/").await;
+            assert_eq!(attest, StatusCode::NOT_FOUND);
+        }
     }
 }
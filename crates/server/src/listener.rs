@@ -0,0 +1,613 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Listening sockets, one per address.
+//!
+//! Whether a socket bound to `::` also accepts IPv4 depends on the platform
+//! (and on Linux, on a sysctl). When several addresses are given, IPv6
+//! sockets are made IPv6-only, so that `0.0.0.0` and `::` can share a port
+//! and either family failing to bind is reported rather than masked.
+//!
+//! Connections are then handled according to a [`Tuning`]. Large fleets
+//! enrolling at once favor HTTP/2, which multiplexes requests over a few
+//! long-lived connections instead of paying a TCP handshake for each.
+//!
+//! Clients which send their requests too slowly, whether by accident or to
+//! hold connections open (slowloris), are dropped if the tuning sets
+//! timeouts: the header timeout bounds how long an HTTP/1 client may take,
+//! from connecting or from its last response, to send the headers of a
+//! request, and so also how long a connection may idle between requests; the
+//! read timeout bounds how long a client may stall while sending headers or
+//! a body of known length. Bodies sent in chunks are only bounded by the
+//! body limit, and HTTP/2 connections by their keep-alive pings. Dropped
+//! connections are counted by `steward_connection_timeouts_total`, by
+//! whether they were `idle` or stalled sending `headers` or a `body`.
+
+use super::metrics;
+use super::proxy::Peer;
+
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::pin::Pin;
+use std::task::{ready, Context as TaskContext, Poll};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use axum::extract::connect_info::Connected;
+use hyper::server::accept::Accept;
+use hyper::server::conn::AddrIncoming;
+use hyper::server::{Builder, Server};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+use tracing::debug;
+
+const BACKLOG: i32 = 1024;
+
+/// The most of a request's headers kept to find the length of its body.
+const MAX_HEAD: usize = 64 * 1024;
+
+/// How HTTP/2 connections with prior knowledge begin.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0";
+
+/// How connections are handled.
+///
+/// The default matches hyper's own: HTTP/1 only, with keep-alive, and no
+/// probing of idle connections.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tuning {
+    /// Also accept HTTP/2, with prior knowledge.
+    pub http2: bool,
+
+    /// The most requests in flight on one HTTP/2 connection.
+    pub max_concurrent_streams: Option<u32>,
+
+    /// Keep HTTP/1 connections open for further requests.
+    pub keep_alive: bool,
+
+    /// How long a connection may idle before it is probed, with TCP
+    /// keep-alives and, on HTTP/2, with pings.
+    pub keep_alive_interval: Option<Duration>,
+
+    /// How long to wait for a ping to be answered before closing an HTTP/2
+    /// connection.
+    pub keep_alive_timeout: Option<Duration>,
+
+    /// Send small responses at once rather than coalescing them.
+    pub nodelay: bool,
+
+    /// How long an HTTP/1 client may take to send the headers of a request,
+    /// from connecting or from its last response.
+    pub header_timeout: Option<Duration>,
+
+    /// How long a client may stall while sending a request.
+    pub read_timeout: Option<Duration>,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self {
+            http2: false,
+            max_concurrent_streams: None,
+            keep_alive: true,
+            keep_alive_interval: None,
+            keep_alive_timeout: None,
+            nodelay: false,
+            header_timeout: None,
+            read_timeout: None,
+        }
+    }
+}
+
+impl Tuning {
+    /// Applies the protocol settings to a server on any kind of connection.
+    pub fn protocol<I>(&self, builder: Builder<I>) -> Builder<I> {
+        let builder = builder
+            .http1_only(!self.http2)
+            .http1_keepalive(self.keep_alive)
+            .http2_max_concurrent_streams(self.max_concurrent_streams)
+            .http2_keep_alive_interval(self.keep_alive_interval);
+        match self.keep_alive_timeout {
+            Some(timeout) => builder.http2_keep_alive_timeout(timeout),
+            None => builder,
+        }
+    }
+
+    /// Serves plain TCP connections on `listener`, with every setting applied.
+    pub fn server(&self, listener: TcpListener) -> Result<Builder<Guard<AddrIncoming>>> {
+        listener.set_nonblocking(true)?;
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+        let mut incoming = AddrIncoming::from_listener(listener)?;
+        incoming.set_nodelay(self.nodelay);
+        incoming.set_keepalive(self.keep_alive_interval);
+        Ok(self.protocol(Server::builder(self.guard(incoming))))
+    }
+
+    /// Applies the timeouts to connections accepted some other way.
+    pub fn guard<A>(&self, incoming: A) -> Guard<A> {
+        Guard {
+            incoming,
+            header_timeout: self.header_timeout,
+            read_timeout: self.read_timeout,
+        }
+    }
+
+    /// Applies the TCP settings to a connection accepted some other way.
+    pub fn stream(&self, stream: &tokio::net::TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        if let Some(interval) = self.keep_alive_interval {
+            let keepalive = TcpKeepalive::new().with_time(interval);
+            SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+}
+
+/// Connections accepted by another [`Accept`], guarded by the timeouts of a
+/// [`Tuning`].
+#[derive(Debug)]
+pub struct Guard<A> {
+    incoming: A,
+    header_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+}
+
+impl<A: Accept + Unpin> Accept for Guard<A> {
+    type Conn = Guarded<A::Conn>;
+    type Error = A::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+        let accepted = ready!(Pin::new(&mut this.incoming).poll_accept(cx));
+        let guard = |stream| Guarded::new(stream, this.header_timeout, this.read_timeout);
+        Poll::Ready(accepted.map(|accepted| accepted.map(guard)))
+    }
+}
+
+/// How far a connection has got with a request.
+#[derive(Debug)]
+enum Phase {
+    /// Awaiting the headers of a request, of which `head` have arrived.
+    /// `newline` is whether the last line ended, so that another ending
+    /// ends the headers.
+    Head { head: Vec<u8>, newline: bool },
+
+    /// Awaiting this many more bytes of a request body.
+    Body(u64),
+
+    /// Awaiting the response to a request.
+    Handling,
+
+    /// Not timed, as HTTP/2 connections are not.
+    Exempt,
+
+    /// Dropped for being too slow.
+    TimedOut,
+}
+
+impl Phase {
+    fn idle() -> Self {
+        Self::Head {
+            head: Vec::new(),
+            newline: false,
+        }
+    }
+
+    /// What follows the headers `head`.
+    fn after(head: &[u8]) -> Self {
+        if head.starts_with(PREFACE) {
+            return Self::Exempt;
+        }
+
+        let head = String::from_utf8_lossy(head);
+        let length = head
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, length)| length.trim().parse().ok());
+        match length {
+            Some(length) if length > 0 => Self::Body(length),
+            _ => Self::Handling,
+        }
+    }
+}
+
+/// A connection which is dropped if its client sends too slowly.
+#[derive(Debug)]
+pub struct Guarded<S> {
+    stream: S,
+    header_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    phase: Phase,
+
+    /// When the headers of the next request must have arrived.
+    headers_by: Option<Instant>,
+
+    /// When the client last sent anything.
+    last_read: Instant,
+
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Guarded<S> {
+    fn new(stream: S, header_timeout: Option<Duration>, read_timeout: Option<Duration>) -> Self {
+        let now = Instant::now();
+        Self {
+            stream,
+            header_timeout,
+            read_timeout,
+            phase: Phase::idle(),
+            headers_by: header_timeout.map(|timeout| now + timeout),
+            last_read: now,
+            timer: None,
+        }
+    }
+
+    /// Follows the request through the bytes `read` from the client.
+    fn received(&mut self, mut read: &[u8]) {
+        if !read.is_empty() {
+            self.last_read = Instant::now();
+        }
+
+        while !read.is_empty() {
+            match &mut self.phase {
+                Phase::Head { head, newline } => {
+                    let end = read.iter().position(|b| match b {
+                        b'\n' if *newline => true,
+                        b'\n' => {
+                            *newline = true;
+                            false
+                        }
+                        b'\r' => false,
+                        _ => {
+                            *newline = false;
+                            false
+                        }
+                    });
+                    let (taken, rest) = read.split_at(end.map_or(read.len(), |end| end + 1));
+                    if head.len() < MAX_HEAD {
+                        head.extend_from_slice(taken);
+                    }
+                    if end.is_some() {
+                        self.phase = Phase::after(head);
+                    }
+                    read = rest;
+                }
+                Phase::Body(remaining) => {
+                    let taken = (*remaining).min(read.len() as u64);
+                    *remaining -= taken;
+                    if *remaining == 0 {
+                        self.phase = Phase::Handling;
+                    }
+                    read = &read[taken as usize..];
+                }
+                Phase::Handling | Phase::Exempt | Phase::TimedOut => break,
+            }
+        }
+    }
+
+    /// Notes that a response is being written, after which the client may
+    /// send its next request.
+    fn wrote(&mut self) {
+        let idle = match &self.phase {
+            Phase::Head { head, .. } => head.is_empty(),
+            Phase::Handling => true,
+            _ => false,
+        };
+        if idle {
+            self.phase = Phase::idle();
+            self.headers_by = self.header_timeout.map(|timeout| Instant::now() + timeout);
+        }
+    }
+
+    /// When the client must next have sent something, and why.
+    fn deadline(&self) -> Option<(Instant, &'static str)> {
+        let stalled = self.read_timeout.map(|timeout| self.last_read + timeout);
+        match &self.phase {
+            Phase::Head { head, .. } if head.is_empty() => Some((self.headers_by?, "idle")),
+            Phase::Head { .. } => {
+                let deadline = self.headers_by.into_iter().chain(stalled).min()?;
+                Some((deadline, "headers"))
+            }
+            Phase::Body(..) => Some((stalled?, "body")),
+            _ => None,
+        }
+    }
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        "client sent its request too slowly",
+    )
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Guarded<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Phase::TimedOut = this.phase {
+            return Poll::Ready(Err(timed_out()));
+        }
+
+        let filled = buf.filled().len();
+        match Pin::new(&mut this.stream).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                this.received(&buf.filled()[filled..]);
+                return Poll::Ready(Ok(()));
+            }
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => {}
+        }
+
+        let (deadline, phase) = match this.deadline() {
+            Some(deadline) => deadline,
+            None => return Poll::Pending,
+        };
+        let timer = this
+            .timer
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+        if timer.deadline() != deadline {
+            timer.as_mut().reset(deadline);
+        }
+        ready!(timer.as_mut().poll(cx));
+
+        debug!("dropping connection whose client stalled: {phase}");
+        metrics::CONNECTION_TIMEOUTS.inc(phase);
+        this.phase = Phase::TimedOut;
+        Poll::Ready(Err(timed_out()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Guarded<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.stream).poll_write(cx, buf))?;
+        this.wrote();
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.stream).poll_write_vectored(cx, bufs))?;
+        this.wrote();
+        Poll::Ready(Ok(written))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+impl<'a, S> Connected<&'a Guarded<S>> for Peer
+where
+    Peer: Connected<&'a S>,
+{
+    fn connect_info(target: &'a Guarded<S>) -> Self {
+        <Peer as Connected<&'a S>>::connect_info(&target.stream)
+    }
+}
+
+/// Binds a non-blocking listener to each of `addrs` on `port`.
+pub fn bind(addrs: &[IpAddr], port: u16) -> Result<Vec<TcpListener>> {
+    let only_v6 = addrs.len() > 1;
+    addrs
+        .iter()
+        .map(|ip| {
+            let addr = SocketAddr::new(*ip, port);
+            listen(addr, only_v6).with_context(|| match ip {
+                IpAddr::V4(..) => format!("failed to listen on {addr}"),
+                IpAddr::V6(..) => format!("failed to listen on {addr}; is IPv6 available?"),
+            })
+        })
+        .collect()
+}
+
+fn listen(addr: SocketAddr, only_v6: bool) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use axum::routing::get;
+    use axum::Router;
+    use hyper::{Body, Request, StatusCode};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn bind() {
+        let localhost = [IpAddr::V4(Ipv4Addr::LOCALHOST)];
+        let listeners = super::bind(&localhost, 0).unwrap();
+        assert_eq!(listeners.len(), 1);
+
+        // A taken port is reported with its address.
+        let port = listeners[0].local_addr().unwrap().port();
+        let err = super::bind(&localhost, port).unwrap_err();
+        assert!(err.to_string().contains(&format!("127.0.0.1:{port}")));
+    }
+
+    async fn echo(body: String) -> String {
+        body
+    }
+
+    /// Serves `tuning` on a local port with a handler that takes a moment, and
+    /// returns the address along with the most requests seen in flight.
+    fn serve(tuning: Tuning) -> (SocketAddr, Arc<AtomicUsize>) {
+        let (active, peak) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let seen = peak.clone();
+        let handler = move || {
+            let (active, peak) = (active.clone(), peak.clone());
+            async move {
+                let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                active.fetch_sub(1, Ordering::SeqCst);
+                "ok"
+            }
+        };
+
+        let localhost = [IpAddr::V4(Ipv4Addr::LOCALHOST)];
+        let listener = super::bind(&localhost, 0).unwrap().remove(0);
+        let addr = listener.local_addr().unwrap();
+        let server = tuning.server(listener).unwrap().serve(
+            Router::new()
+                .route("/", get(handler).post(echo))
+                .into_make_service(),
+        );
+        tokio::spawn(server);
+        (addr, seen)
+    }
+
+    /// Sends `n` concurrent requests over a single HTTP/2 connection.
+    async fn burst(addr: SocketAddr, n: usize) -> hyper::Result<()> {
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (sender, conn) = hyper::client::conn::Builder::new()
+            .http2_only(true)
+            .handshake::<_, Body>(stream)
+            .await?;
+        tokio::spawn(conn);
+
+        let requests = (0..n).map(|_| {
+            let mut sender = sender.clone();
+            tokio::spawn(async move {
+                let request = Request::get("/").body(Body::empty()).unwrap();
+                sender.send_request(request).await
+            })
+        });
+        for request in requests.collect::<Vec<_>>() {
+            assert_eq!(request.await.unwrap()?.status(), StatusCode::OK);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tuning() {
+        const N: usize = 64;
+
+        // HTTP/1 servers refuse an HTTP/2 connection preface.
+        let (addr, _) = serve(Tuning::default());
+        assert!(burst(addr, 1).await.is_err());
+
+        // With HTTP/2 the whole burst shares one connection, and the stream
+        // limit bounds how much of it is handled at once: the handler sees
+        // exactly as many requests in flight as the limit allows.
+        let tuning = Tuning {
+            http2: true,
+            max_concurrent_streams: Some(8),
+            keep_alive_interval: Some(Duration::from_secs(30)),
+            keep_alive_timeout: Some(Duration::from_secs(10)),
+            nodelay: true,
+            ..Default::default()
+        };
+        let (addr, peak) = serve(tuning);
+        burst(addr, N).await.unwrap();
+        assert_eq!(peak.load(Ordering::SeqCst), 8);
+
+        let (addr, peak) = serve(Tuning {
+            max_concurrent_streams: None,
+            ..tuning
+        });
+        burst(addr, N).await.unwrap();
+        assert!(peak.load(Ordering::SeqCst) > 8);
+    }
+
+    #[tokio::test]
+    async fn timeouts() {
+        let timeout = Duration::from_millis(200);
+        let (addr, _) = serve(Tuning {
+            header_timeout: Some(timeout * 2),
+            read_timeout: Some(timeout),
+            ..Default::default()
+        });
+
+        // Sends `request`, then reads until the server closes the connection.
+        let send = |request: &'static [u8]| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(request).await.unwrap();
+            let mut response = Vec::new();
+            let read = stream.read_to_end(&mut response);
+            let _ = tokio::time::timeout(timeout * 10, read).await.unwrap();
+            String::from_utf8_lossy(&response).into_owned()
+        };
+        let dropped = |phase| metrics::CONNECTION_TIMEOUTS.get(phase);
+
+        // Clients sending their requests promptly are answered, and are
+        // dropped once idle for too long.
+        let idle = dropped("idle");
+        let response = send(b"GET / HTTP/1.1\r\nHost: steward\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(dropped("idle") > idle);
+
+        // But not those stalling midway through their headers or body.
+        let headers = dropped("headers");
+        let response = send(b"GET / HTTP/1.1\r\nHost: steward\r\n").await;
+        assert!(response.is_empty(), "{response}");
+        assert!(dropped("headers") > headers);
+
+        let body = dropped("body");
+        let request = b"POST / HTTP/1.1\r\nHost: steward\r\nContent-Length: 8\r\n\r\nabc";
+        send(request).await;
+        assert!(dropped("body") > body);
+    }
+
+    #[test]
+    fn phases() {
+        let head = b"POST / HTTP/1.1\nhost: steward\r\nCONTENT-LENGTH: 3\r\n\r\n";
+        let mut guarded = Guarded::new((), None, None);
+        for chunk in head.chunks(5) {
+            assert!(matches!(guarded.phase, Phase::Head { .. }));
+            guarded.received(chunk);
+        }
+        assert!(matches!(guarded.phase, Phase::Body(3)));
+        guarded.received(b"abc");
+        assert!(matches!(guarded.phase, Phase::Handling));
+
+        // Responding readies the connection for the next request.
+        guarded.wrote();
+        guarded.received(b"GET / HTTP/1.1\r\n\r\nGET");
+        assert!(matches!(guarded.phase, Phase::Handling));
+
+        // HTTP/2 connections are left alone.
+        let mut guarded = Guarded::new((), None, None);
+        guarded.received(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n");
+        assert!(matches!(guarded.phase, Phase::Exempt));
+    }
+}
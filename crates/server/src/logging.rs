@@ -0,0 +1,195 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Log output and the redaction of sensitive values from it.
+//!
+//! Logs often travel further than the CA itself, so values which identify a
+//! workload or grant access (credentials in headers, evidence and the
+//! measurements taken from it, public keys) are logged as `[redacted]`
+//! unless redaction is turned off for debugging. Such values are wrapped in
+//! [`Redacted`] (or [`Headers`]) where they are logged, so that whichever
+//! format is chosen, they are never written out.
+
+use std::fmt::{self, Debug, Display, Formatter};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{bail, Result};
+use axum::http::header::{AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION};
+use axum::http::HeaderMap;
+use tracing_subscriber::EnvFilter;
+
+static UNREDACTED: AtomicBool = AtomicBool::new(false);
+
+const REDACTED: &str = "[redacted]";
+
+/// How log lines are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    /// One line per event, with the fields of all enclosing spans.
+    #[default]
+    Full,
+
+    /// One shorter line per event.
+    Compact,
+
+    /// Several indented, coloured lines per event, for people.
+    Pretty,
+
+    /// One JSON object per event, for machines.
+    Json,
+}
+
+impl Format {
+    /// The format chosen by the environment: JSON if `RUST_LOG_JSON` is set.
+    pub fn from_env() -> Self {
+        match std::env::var("RUST_LOG_JSON") {
+            Ok(..) => Self::Json,
+            Err(..) => Self::Full,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "full" => Ok(Self::Full),
+            "compact" => Ok(Self::Compact),
+            "pretty" => Ok(Self::Pretty),
+            "json" => Ok(Self::Json),
+            _ => bail!("unknown log format `{s}`"),
+        }
+    }
+}
+
+/// Starts logging in `format`, filtered by `RUST_LOG`, redacting sensitive
+/// values unless `unredacted`.
+pub fn init(format: Format, unredacted: bool) {
+    UNREDACTED.store(unredacted, Ordering::Relaxed);
+
+    let logs = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_ansi(false);
+    match format {
+        Format::Full => logs.init(),
+        Format::Compact => logs.compact().init(),
+        Format::Pretty => logs.pretty().with_ansi(true).init(),
+        Format::Json => logs.json().init(),
+    }
+}
+
+/// Whether sensitive values are currently logged.
+fn shown() -> bool {
+    UNREDACTED.load(Ordering::Relaxed)
+}
+
+/// A sensitive value, logged only when redaction is off.
+pub struct Redacted<T> {
+    value: T,
+    shown: bool,
+}
+
+impl<T> Redacted<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            value,
+            shown: shown(),
+        }
+    }
+}
+
+impl<T: Debug> Debug for Redacted<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.shown {
+            true => self.value.fmt(f),
+            false => f.write_str(REDACTED),
+        }
+    }
+}
+
+impl<T: Display> Display for Redacted<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.shown {
+            true => self.value.fmt(f),
+            false => f.write_str(REDACTED),
+        }
+    }
+}
+
+/// Request headers, with credentials redacted.
+pub struct Headers<'a> {
+    headers: &'a HeaderMap,
+    shown: bool,
+}
+
+impl<'a> Headers<'a> {
+    pub fn new(headers: &'a HeaderMap) -> Self {
+        Self {
+            headers,
+            shown: shown(),
+        }
+    }
+}
+
+impl Debug for Headers<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let sensitive = [AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION];
+        let mut map = f.debug_map();
+        for (name, value) in self.headers {
+            match self.shown || !sensitive.contains(name) {
+                true => map.entry(name, value),
+                false => map.entry(name, &format_args!("{REDACTED}")),
+            };
+        }
+        map.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn format() {
+        assert_eq!("json".parse::<Format>().unwrap(), Format::Json);
+        assert_eq!("compact".parse::<Format>().unwrap(), Format::Compact);
+        assert!("yaml".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn redacted() {
+        let key = Redacted {
+            value: "04abcd",
+            shown: false,
+        };
+        assert_eq!(format!("{key} {key:?}"), "[redacted] [redacted]");
+
+        let key = Redacted { shown: true, ..key };
+        assert_eq!(format!("{key} {key:?}"), "04abcd \"04abcd\"");
+    }
+
+    #[test]
+    fn headers() {
+        let mut map = HeaderMap::new();
+        map.insert(AUTHORIZATION, HeaderValue::from_static("Bearer secret"));
+        map.insert("x-request-id", HeaderValue::from_static("42"));
+
+        let headers = Headers {
+            headers: &map,
+            shown: false,
+        };
+        let logged = format!("{headers:?}");
+        assert!(!logged.contains("secret"), "{logged}");
+        assert!(logged.contains("\"x-request-id\": \"42\""), "{logged}");
+
+        let headers = Headers {
+            headers: &map,
+            shown: true,
+        };
+        assert!(format!("{headers:?}").contains("secret"));
+    }
+}
@@ -0,0 +1,331 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Process-wide metrics rendered in the Prometheus text exposition format.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+
+/// A metric which can render itself in the Prometheus text format.
+pub trait Metric: Sync {
+    fn render(&self, out: &mut String);
+}
+
+/// A value which can go up and down.
+#[derive(Debug)]
+pub struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicI64,
+}
+
+impl Gauge {
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            value: AtomicI64::new(0),
+        }
+    }
+
+    pub fn set(&self, value: i64) {
+        self.value.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+impl Metric for Gauge {
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} gauge", self.name);
+        let _ = writeln!(out, "{} {}", self.name, self.get());
+    }
+}
+
+/// A family of counters distinguished by the value of one label.
+#[derive(Debug)]
+pub struct CounterVec {
+    name: &'static str,
+    help: &'static str,
+    label: &'static str,
+    values: Mutex<BTreeMap<String, u64>>,
+}
+
+impl CounterVec {
+    pub const fn new(name: &'static str, help: &'static str, label: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            label,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn inc(&self, label: &str) {
+        let mut values = self.values.lock().unwrap();
+        match values.get_mut(label) {
+            Some(value) => *value += 1,
+            None => {
+                values.insert(label.into(), 1);
+            }
+        }
+    }
+
+    pub fn get(&self, label: &str) -> u64 {
+        self.values.lock().unwrap().get(label).copied().unwrap_or(0)
+    }
+}
+
+impl Metric for CounterVec {
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} counter", self.name);
+        for (label, value) in self.values.lock().unwrap().iter() {
+            let label = label.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = writeln!(
+                out,
+                "{}{{{}=\"{}\"}} {}",
+                self.name, self.label, label, value
+            );
+        }
+    }
+}
+
+/// The upper bounds, in seconds, of the buckets of every histogram.
+const BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// The observations of one histogram.
+#[derive(Debug, Default)]
+struct Observations {
+    /// The count in each bucket alone, with those over the last at the end.
+    counts: [u64; BUCKETS.len() + 1],
+    sum: f64,
+}
+
+/// A family of histograms of durations distinguished by the value of one
+/// label.
+#[derive(Debug)]
+pub struct HistogramVec {
+    name: &'static str,
+    help: &'static str,
+    label: &'static str,
+    values: Mutex<BTreeMap<String, Observations>>,
+}
+
+impl HistogramVec {
+    pub const fn new(name: &'static str, help: &'static str, label: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            label,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn observe(&self, label: &str, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let bucket = BUCKETS.iter().take_while(|le| secs > **le).count();
+
+        let mut values = self.values.lock().unwrap();
+        if !values.contains_key(label) {
+            values.insert(label.into(), Observations::default());
+        }
+        let observations = values.get_mut(label).unwrap();
+        observations.counts[bucket] += 1;
+        observations.sum += secs;
+    }
+
+    pub fn count(&self, label: &str) -> u64 {
+        let values = self.values.lock().unwrap();
+        values.get(label).map_or(0, |o| o.counts.iter().sum())
+    }
+}
+
+impl Metric for HistogramVec {
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} histogram", self.name);
+        for (label, observations) in self.values.lock().unwrap().iter() {
+            let label = label.replace('\\', "\\\\").replace('"', "\\\"");
+            let name = self.name;
+            let labels = format!("{}=\"{}\"", self.label, label);
+
+            let mut count = 0;
+            let bounds = BUCKETS.iter().map(|le| le.to_string());
+            for (le, n) in bounds.chain(["+Inf".into()]).zip(observations.counts) {
+                count += n;
+                let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{le}\"}} {count}");
+            }
+            let _ = writeln!(out, "{name}_sum{{{labels}}} {}", observations.sum);
+            let _ = writeln!(out, "{name}_count{{{labels}}} {count}");
+        }
+    }
+}
+
+pub static FIPS_MODE: Gauge = Gauge::new(
+    "steward_fips_mode",
+    "Whether cryptography is routed through the FIPS validated module.",
+);
+
+pub static TASK_RUNS: CounterVec = CounterVec::new(
+    "steward_task_runs_total",
+    "Number of times a background task has run.",
+    "task",
+);
+
+pub static TASK_FAILURES: CounterVec = CounterVec::new(
+    "steward_task_failures_total",
+    "Number of times a background task has failed.",
+    "task",
+);
+
+pub static CA_EXPIRY: Gauge = Gauge::new(
+    "steward_ca_expiry_seconds",
+    "Seconds until the signing certificate expires.",
+);
+
+pub static APPRAISALS: CounterVec = CounterVec::new(
+    "steward_appraisals_total",
+    "Number of pieces of evidence which passed appraisal.",
+    "platform",
+);
+
+pub static APPRAISAL_FAILURES: CounterVec = CounterVec::new(
+    "steward_appraisal_failures_total",
+    "Number of pieces of evidence which failed appraisal.",
+    "platform",
+);
+
+pub static STAGE_DURATION: HistogramVec = HistogramVec::new(
+    "steward_stage_duration_seconds",
+    "Time spent parsing requests, evaluating policy and signing certificates.",
+    "stage",
+);
+
+pub static VERIFY_DURATION: HistogramVec = HistogramVec::new(
+    "steward_verify_duration_seconds",
+    "Time spent verifying a piece of evidence, including any cached appraisal.",
+    "platform",
+);
+
+pub static FETCH_DURATION: HistogramVec = HistogramVec::new(
+    "steward_fetch_duration_seconds",
+    "Time spent fetching from an upstream service, including retries.",
+    "service",
+);
+
+pub static CONNECTION_TIMEOUTS: CounterVec = CounterVec::new(
+    "steward_connection_timeouts_total",
+    "Number of connections dropped for sending requests too slowly.",
+    "phase",
+);
+
+static REGISTRY: &[&dyn Metric] = &[
+    &FIPS_MODE,
+    &TASK_RUNS,
+    &TASK_FAILURES,
+    &CA_EXPIRY,
+    &APPRAISALS,
+    &APPRAISAL_FAILURES,
+    &STAGE_DURATION,
+    &VERIFY_DURATION,
+    &FETCH_DURATION,
+    &CONNECTION_TIMEOUTS,
+];
+
+/// Renders all registered metrics.
+pub fn render() -> String {
+    let mut out = String::new();
+    for metric in REGISTRY {
+        metric.render(&mut out);
+    }
+    out
+}
+
+pub async fn metrics() -> impl IntoResponse {
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], render())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gauge() {
+        let gauge = Gauge::new("steward_test", "A test gauge.");
+        gauge.set(3);
+
+        let mut out = String::new();
+        gauge.render(&mut out);
+        assert_eq!(
+            out,
+            "# HELP steward_test A test gauge.\n# TYPE steward_test gauge\nsteward_test 3\n"
+        );
+    }
+
+    #[test]
+    fn counter_vec() {
+        let counter = CounterVec::new("steward_test_total", "A test counter.", "task");
+        counter.inc("b");
+        counter.inc("a\"");
+        counter.inc("b");
+        assert_eq!(counter.get("b"), 2);
+        assert_eq!(counter.get("c"), 0);
+
+        let mut out = String::new();
+        counter.render(&mut out);
+        assert_eq!(
+            out,
+            concat!(
+                "# HELP steward_test_total A test counter.\n",
+                "# TYPE steward_test_total counter\n",
+                "steward_test_total{task=\"a\\\"\"} 1\n",
+                "steward_test_total{task=\"b\"} 2\n",
+            )
+        );
+    }
+
+    #[test]
+    fn histogram_vec() {
+        let histogram = HistogramVec::new("steward_test_seconds", "A test histogram.", "stage");
+        histogram.observe("sign", Duration::from_millis(2));
+        histogram.observe("sign", Duration::from_millis(20));
+        histogram.observe("sign", Duration::from_secs(60));
+        assert_eq!(histogram.count("sign"), 3);
+        assert_eq!(histogram.count("parse"), 0);
+
+        let mut out = String::new();
+        histogram.render(&mut out);
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines[1], "# TYPE steward_test_seconds histogram");
+        assert_eq!(
+            lines[2],
+            "steward_test_seconds_bucket{stage=\"sign\",le=\"0.001\"} 0"
+        );
+        assert_eq!(
+            lines[3],
+            "steward_test_seconds_bucket{stage=\"sign\",le=\"0.0025\"} 1"
+        );
+        assert_eq!(
+            lines[6],
+            "steward_test_seconds_bucket{stage=\"sign\",le=\"0.025\"} 2"
+        );
+        assert_eq!(
+            lines[14],
+            "steward_test_seconds_bucket{stage=\"sign\",le=\"+Inf\"} 3"
+        );
+        assert!(lines[15].starts_with("steward_test_seconds_sum{stage=\"sign\"} 60.02"));
+        assert_eq!(lines[16], "steward_test_seconds_count{stage=\"sign\"} 3");
+    }
+}
@@ -0,0 +1,259 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Local HTTP mocks of the vendor collateral services, so that tests of the
+//! paths which fetch collateral need no internet access.
+//!
+//! The Intel PCS and AMD KDS mocks serve, at the paths the real services do,
+//! the revocation lists carried by the canned certification requests of the
+//! attestation crate, so that the evidence in those requests checks out
+//! against them.
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "sgx")]
+use std::time::{Duration, SystemTime};
+
+#[cfg(any(feature = "sgx", feature = "snp"))]
+use anyhow::{Context, Result};
+use axum::http::{StatusCode, Uri};
+use axum::routing::get;
+use axum::Router;
+#[cfg(any(feature = "sgx", feature = "snp"))]
+use der::{Decode, Encode};
+use tokio::task::JoinHandle;
+#[cfg(any(feature = "sgx", feature = "snp"))]
+use x509::request::{CertReq, ExtensionReq};
+
+#[cfg(feature = "sgx")]
+pub const ICELAKE: &[u8] = include_bytes!("../../attestation/src/sgx/icelake.signed.crl.csr");
+#[cfg(feature = "snp")]
+pub const MILAN: &[u8] = include_bytes!("../../attestation/src/snp/milan.signed.crl.csr");
+
+/// The path of the SGX root CA revocation list on the PCS mock.
+#[cfg(feature = "sgx")]
+pub const ROOT_CRL: &str = "/IntelSGXRootCA.der";
+
+type Responses = BTreeMap<String, (StatusCode, Vec<u8>)>;
+
+/// A mock service, answering on a local port until it is dropped.
+#[derive(Debug)]
+pub struct Mock {
+    url: String,
+    responses: Arc<Mutex<Responses>>,
+    hits: Arc<Mutex<BTreeMap<String, usize>>>,
+    server: JoinHandle<()>,
+}
+
+impl Drop for Mock {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+impl Mock {
+    /// Serves `bodies` at their paths, and `404 Not Found` elsewhere.
+    pub fn serve(bodies: impl IntoIterator<Item = (String, Vec<u8>)>) -> Self {
+        let responses: Responses = bodies
+            .into_iter()
+            .map(|(path, body)| (path, (StatusCode::OK, body)))
+            .collect();
+        let responses = Arc::new(Mutex::new(responses));
+        let hits = Arc::new(Mutex::new(BTreeMap::new()));
+
+        let (served, counted) = (responses.clone(), hits.clone());
+        let handler = move |uri: Uri| {
+            let path = uri
+                .path_and_query()
+                .map_or("/", |pq| pq.as_str())
+                .to_string();
+            *counted.lock().unwrap().entry(path.clone()).or_default() += 1;
+            let response = served.lock().unwrap().get(&path).cloned();
+            async move { response.unwrap_or((StatusCode::NOT_FOUND, Vec::new())) }
+        };
+
+        let localhost = [IpAddr::V4(Ipv4Addr::LOCALHOST)];
+        let listener = super::listener::bind(&localhost, 0).unwrap().remove(0);
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = axum::Server::from_tcp(listener).unwrap().serve(
+            Router::new()
+                .route("/*path", get(handler))
+                .into_make_service(),
+        );
+        let server = tokio::spawn(async move {
+            server.await.unwrap();
+        });
+
+        Self {
+            url,
+            responses,
+            hits,
+            server,
+        }
+    }
+
+    /// Mocks the Intel PCS, and the host serving the SGX root CA's
+    /// revocation list at [`ROOT_CRL`].
+    #[cfg(feature = "sgx")]
+    pub fn pcs() -> Self {
+        Self::serve(sgx_crls().unwrap())
+    }
+
+    /// Mocks the AMD KDS.
+    #[cfg(feature = "snp")]
+    pub fn kds() -> Self {
+        Self::serve(snp_crls().unwrap())
+    }
+
+    /// The base URL of the service.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Answers requests for `path` with `status` and `body` from now on.
+    pub fn respond(&self, path: &str, status: StatusCode, body: &[u8]) {
+        let response = (status, body.to_vec());
+        self.responses.lock().unwrap().insert(path.into(), response);
+    }
+
+    /// Fails every request from now on.
+    pub fn fail(&self, status: StatusCode) {
+        for response in self.responses.lock().unwrap().values_mut() {
+            *response = (status, Vec::new());
+        }
+    }
+
+    /// How many times `path` was requested.
+    pub fn hits(&self, path: &str) -> usize {
+        self.hits
+            .lock()
+            .unwrap()
+            .get(path)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// Returns the path and query of `url`, dropping its scheme and host.
+#[cfg(any(feature = "sgx", feature = "snp"))]
+fn path_of(url: &str) -> Result<String> {
+    let uri: Uri = url.parse().with_context(|| format!("invalid url {url}"))?;
+    Ok(uri.path_and_query().context("url has no path")?.to_string())
+}
+
+/// Returns the evidence extensions of the canned request `csr`.
+#[cfg(any(feature = "sgx", feature = "snp"))]
+fn evidence(csr: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let cr = CertReq::from_der(csr)?;
+    let mut evidence = Vec::new();
+    for attribute in cr.info.attributes.iter() {
+        for any in attribute.values.iter() {
+            let request: ExtensionReq<'_> = any.decode_into()?;
+            let extensions = Vec::from(request);
+            evidence.extend(extensions.iter().map(|ext| ext.extn_value.to_vec()));
+        }
+    }
+    Ok(evidence)
+}
+
+/// A time at which the revocation lists sent with the canned SGX quote are
+/// current, for tests which appraise it.
+#[cfg(feature = "sgx")]
+pub fn icelake_now() -> SystemTime {
+    let quote = &evidence(ICELAKE).unwrap()[0];
+    let quote = attestation::parse::sgx_quote(quote).unwrap();
+    quote.crls.latest_update().unwrap() + Duration::from_secs(60 * 60)
+}
+
+/// Returns the revocation lists sent with the canned SGX quote, by path.
+#[cfg(feature = "sgx")]
+fn sgx_crls() -> Result<Vec<(String, Vec<u8>)>> {
+    let mut crls = Vec::new();
+    for quote in evidence(ICELAKE)? {
+        let quote = attestation::parse::sgx_quote(&quote)?;
+        for entry in &quote.crls.crls {
+            // The root CA's list is served by a separate host at Intel.
+            let path = match entry.url.ends_with(ROOT_CRL) {
+                true => ROOT_CRL.into(),
+                false => path_of(&entry.url)?,
+            };
+            crls.push((path, entry.crl.to_vec()?));
+        }
+    }
+    Ok(crls)
+}
+
+/// Returns the revocation lists sent with the canned SNP evidence, by path.
+#[cfg(feature = "snp")]
+fn snp_crls() -> Result<Vec<(String, Vec<u8>)>> {
+    let mut crls = Vec::new();
+    for evidence in evidence(MILAN)? {
+        let parts = attestation::parse::snp_evidence(&evidence)?;
+        for entry in parts.crl.iter().flat_map(|list| &list.crls) {
+            crls.push((path_of(&entry.url)?, entry.crl.to_vec()?));
+        }
+    }
+    Ok(crls)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn serve() {
+        let mock = Mock::serve([("/a?b=c".to_string(), b"d".to_vec())]);
+        let get = |path: &str| fetch(format!("{}{path}", mock.url()));
+
+        assert_eq!(get("/a?b=c").await, (StatusCode::OK, b"d".to_vec()));
+        assert_eq!(get("/a").await.0, StatusCode::NOT_FOUND);
+        assert_eq!(mock.hits("/a?b=c"), 1);
+
+        mock.fail(StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(get("/a?b=c").await.0, StatusCode::SERVICE_UNAVAILABLE);
+        mock.respond("/e", StatusCode::OK, b"f");
+        assert_eq!(get("/e").await, (StatusCode::OK, b"f".to_vec()));
+        assert_eq!(mock.hits("/a?b=c"), 2);
+    }
+
+    /// Fetches `url` with a bare hyper client.
+    async fn fetch(url: String) -> (StatusCode, Vec<u8>) {
+        let rsp = hyper::Client::new()
+            .get(url.parse().unwrap())
+            .await
+            .unwrap();
+        let status = rsp.status();
+        let body = hyper::body::to_bytes(rsp.into_body()).await.unwrap();
+        (status, body.to_vec())
+    }
+
+    #[cfg(feature = "sgx")]
+    #[test]
+    fn pcs() {
+        let crls = sgx_crls().unwrap();
+        let paths: Vec<_> = crls.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                ROOT_CRL,
+                "/sgx/certification/v4/pckcrl?ca=processor&encoding=der",
+                "/sgx/certification/v4/pckcrl?ca=platform&encoding=der",
+            ]
+        );
+        for (_, crl) in crls {
+            x509::crl::CertificateList::from_der(&crl).unwrap();
+        }
+    }
+
+    #[cfg(feature = "snp")]
+    #[test]
+    fn kds() {
+        let crls = snp_crls().unwrap();
+        let paths: Vec<_> = crls.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, ["/vcek/v1/Genoa/crl", "/vcek/v1/Milan/crl"]);
+        for (_, crl) in crls {
+            x509::crl::CertificateList::from_der(&crl).unwrap();
+        }
+    }
+}
@@ -0,0 +1,128 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Policy combining evidence from several platforms in one request.
+//!
+//! A workload may present evidence for more than one platform, for example
+//! both an SNP report and an SGX quote. The policy states whether all of the
+//! presented evidence must verify or any of it, and optionally which
+//! combinations of verified platforms are sufficient.
+
+use std::collections::BTreeSet;
+
+use anyhow::{bail, ensure, Result};
+use serde::{Deserialize, Serialize};
+
+/// The platforms for which evidence can be appraised.
+pub const KNOWN: &[&str] = &[
+    #[cfg(feature = "cove")]
+    "cove",
+    #[cfg(feature = "kvm")]
+    "kvm",
+    #[cfg(feature = "se")]
+    "se",
+    #[cfg(feature = "sgx")]
+    "sgx",
+    #[cfg(feature = "snp")]
+    "snp",
+    super::tokens::PLATFORM,
+];
+
+/// How much of the presented evidence must verify.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Require {
+    /// Every piece of presented evidence must verify.
+    #[default]
+    All,
+
+    /// At least one piece of presented evidence must verify.
+    Any,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    pub require: Require,
+
+    /// Sets of platforms, one of which must be among those verified.
+    ///
+    /// When empty, any attesting platform suffices.
+    pub combinations: Vec<BTreeSet<String>>,
+}
+
+impl Policy {
+    /// Rejects combinations which could never be satisfied.
+    pub fn validate(&self) -> Result<()> {
+        for combination in &self.combinations {
+            ensure!(!combination.is_empty(), "empty platform combination");
+            for platform in combination {
+                ensure!(
+                    KNOWN.contains(&platform.as_str()),
+                    "unknown platform `{platform}` in combination"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks whether the `verified` attesting platforms satisfy the policy.
+    pub fn check(&self, verified: &[&str]) -> Result<()> {
+        ensure!(!verified.is_empty(), "no evidence verified");
+
+        if self.combinations.is_empty() {
+            return Ok(());
+        }
+
+        for combination in &self.combinations {
+            if combination.iter().all(|p| verified.contains(&p.as_str())) {
+                return Ok(());
+            }
+        }
+
+        bail!("verified platforms {verified:?} match no required combination")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default() {
+        let policy: Policy = toml::from_str("").unwrap();
+        assert_eq!(policy.require, Require::All);
+        assert!(policy.check(&["sgx"]).is_ok());
+        assert!(policy.check(&["sgx", "snp"]).is_ok());
+        assert!(policy.check(&[]).is_err());
+    }
+
+    #[test]
+    #[cfg(all(feature = "kvm", feature = "sgx", feature = "snp"))]
+    fn combinations() {
+        let policy: Policy = toml::from_str(
+            r#"
+            require = "any"
+            combinations = [["sgx", "snp"], ["kvm"]]
+            "#,
+        )
+        .unwrap();
+        policy.validate().unwrap();
+
+        assert_eq!(policy.require, Require::Any);
+        assert!(policy.check(&["snp", "sgx"]).is_ok());
+        assert!(policy.check(&["kvm"]).is_ok());
+        assert!(policy.check(&["snp"]).is_err());
+        assert!(policy.check(&[]).is_err());
+    }
+
+    #[test]
+    fn validate() {
+        let policy: Policy = toml::from_str("combinations = [[\"tdx\"]]").unwrap();
+        assert!(policy.validate().is_err());
+
+        let policy: Policy = toml::from_str("combinations = [[]]").unwrap();
+        assert!(policy.validate().is_err());
+    }
+}
@@ -0,0 +1,345 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! The policy in force, its versions and its reloading.
+//!
+//! Every change of policy is given the next version number, kept in the store
+//! so that it survives restarts and is agreed between replicas, and audited.
+//! The version is recorded with each issued certificate and its archived
+//! evidence, so that auditors can tell which rules a certificate was issued
+//! under, and `GET /admin/policy` tells them which rules are in force now.
+//!
+//! The policy file may be changed while steward runs: it is reread on
+//! `POST /admin/policy/reload`, or periodically when watched. A new policy
+//! is validated in full before it replaces the old one, which stays in force
+//! if it is not; requests in flight finish under the policy they began with.
+
+use super::scheduler::Scheduler;
+use super::store::AuditRecord;
+use super::{admin, Config, State};
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use axum::extract::{Extension, TypedHeader};
+use axum::headers::authorization::{Authorization, Bearer};
+use axum::Json;
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, warn};
+
+/// A policy and its version.
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    pub config: Config,
+
+    /// The policy file as it was read, which also keys cached appraisals.
+    pub raw: Vec<u8>,
+
+    /// Increases with every change of policy; 0 until the first is activated.
+    pub version: u64,
+}
+
+impl Policy {
+    /// Parses and validates a policy file.
+    pub fn parse(raw: Vec<u8>) -> Result<Self> {
+        let text = std::str::from_utf8(&raw).context("config is not utf-8")?;
+        let config: Config = toml::from_str(text).context("failed to parse config")?;
+        config.validate()?;
+        Ok(Self {
+            config,
+            raw,
+            version: 0,
+        })
+    }
+
+    fn active(&self) -> Active {
+        Active {
+            version: self.version,
+            sha256: hex::encode(digest(&self.raw)),
+        }
+    }
+}
+
+/// The version and digest of the policy in force.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Active {
+    /// Increases with every change of policy; 0 until the first is activated.
+    pub version: u64,
+
+    /// The hex SHA-256 digest of the policy file, or of nothing without one.
+    pub sha256: String,
+}
+
+/// Hashes a policy file.
+pub fn digest(policy: &[u8]) -> [u8; 32] {
+    Sha256::digest(policy).into()
+}
+
+/// Registers the policy file `raw`, auditing the change if it is a new one,
+/// and returns its version.
+pub(crate) async fn activate(state: &State, raw: &[u8]) -> Result<u64> {
+    let hash = digest(raw);
+    let now = state.clock.now();
+    let (version, new) = state.store.activate_policy(&hash, now).await?;
+    if new {
+        let record = AuditRecord {
+            at: now,
+            event: "policy".into(),
+            detail: format!("activated version {version}, sha256 {}", hex::encode(hash)),
+        };
+        state.store.audit(&record).await?;
+        state.notify(&record.event, json!(record.detail));
+        info!("activated policy version {version}");
+    }
+
+    Ok(version)
+}
+
+/// Puts the policy file `raw` in force, unless it already is or is invalid.
+pub(crate) async fn put_in_force(state: &State, raw: Vec<u8>) -> Result<Active> {
+    let current = state.policy();
+    if raw == current.raw {
+        return Ok(current.active());
+    }
+
+    let mut policy = Policy::parse(raw)?;
+    policy.version = activate(state, &policy.raw).await?;
+    let active = policy.active();
+    state.swap_policy(policy);
+    Ok(active)
+}
+
+/// Rereads the policy file, putting it in force if it changed and is valid.
+pub async fn reload(state: &State) -> Result<Active> {
+    let path = state
+        .policy_path
+        .as_ref()
+        .ok_or_else(|| anyhow!("steward was started without a config file"))?;
+    let raw = std::fs::read(path).context("failed to read config file")?;
+    put_in_force(state, raw).await
+}
+
+/// Adds a task which checks the policy file for changes every `interval`.
+pub(crate) fn watch(
+    state: &State,
+    path: PathBuf,
+    interval: Duration,
+    scheduler: Scheduler,
+) -> Scheduler {
+    // A rejected file is only reported once, not on every check.
+    let rejected = Arc::new(Mutex::new(None));
+    let state = state.clone();
+    scheduler.every("policy-watch", interval, Duration::ZERO, move || {
+        let (state, path, rejected) = (state.clone(), path.clone(), rejected.clone());
+        async move {
+            let raw = std::fs::read(&path).context("failed to read config file")?;
+            let hash = digest(&raw);
+            if *rejected.lock().unwrap() == Some(hash) {
+                return Ok(());
+            }
+
+            match put_in_force(&state, raw).await {
+                Ok(..) => Ok(()),
+                Err(e) => {
+                    *rejected.lock().unwrap() = Some(hash);
+                    Err(e.context("keeping the current policy"))
+                }
+            }
+        }
+    })
+}
+
+/// Returns the version and digest of the policy in force.
+pub async fn policy(
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Json<Active>, StatusCode> {
+    admin::authorize(&state, auth, true).await?;
+    Ok(Json(state.policy().active()))
+}
+
+/// Rereads the policy file, answering with the policy in force afterwards,
+/// or `422 Unprocessable Entity` and the reason the new one was rejected.
+pub async fn reload_policy(
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Json<Active>, (StatusCode, String)> {
+    admin::authorize(&state, auth, false)
+        .await
+        .map_err(|status| (status, String::new()))?;
+    if state.policy_path.is_none() {
+        return Err((StatusCode::NOT_FOUND, String::new()));
+    }
+
+    match reload(&state).await {
+        Ok(active) => {
+            debug!("policy version {} in force", active.version);
+            Ok(Json(active))
+        }
+        Err(e) => {
+            warn!("rejected policy reload: {e:#}");
+            Err((StatusCode::UNPROCESSABLE_ENTITY, format!("{e:#}\n")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::admin::Auditor;
+    use super::super::operations;
+    use super::*;
+
+    use http::header::AUTHORIZATION;
+    use http::{Method, Request};
+    use hyper::Body;
+    use tower::ServiceExt; // for `app.oneshot()`
+
+    async fn call(
+        state: State,
+        method: Method,
+        uri: &str,
+        token: Option<&str>,
+    ) -> Result<Active, StatusCode> {
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+
+        let response = operations(state)
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        match response.status() {
+            StatusCode::OK => {
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                Ok(serde_json::from_slice(&body).unwrap())
+            }
+            status => Err(status),
+        }
+    }
+
+    async fn get(state: State, token: Option<&str>) -> Result<Active, StatusCode> {
+        call(state, Method::GET, "/admin/policy", token).await
+    }
+
+    fn audited() -> State {
+        State::generate(None, "localhost")
+            .unwrap()
+            .with_auditor(Auditor::new("auditor").unwrap())
+    }
+
+    /// A policy file of its own for each test.
+    struct File(PathBuf);
+
+    impl File {
+        fn new(contents: &str) -> Self {
+            let name = format!("steward-policy-{}.toml", uuid::Uuid::new_v4());
+            let file = Self(std::env::temp_dir().join(name));
+            file.write(contents);
+            file
+        }
+
+        fn write(&self, contents: &str) {
+            std::fs::write(&self.0, contents).unwrap();
+        }
+    }
+
+    impl Drop for File {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn versions() {
+        let state = audited();
+
+        let empty = hex::encode(digest(b""));
+        let active = get(state.clone(), Some("auditor")).await.unwrap();
+        assert_eq!(active.version, 0);
+        assert_eq!(active.sha256, empty);
+
+        // Activating the same policy again, e.g. on restart, changes nothing.
+        let state = state.activate_policy().await.unwrap();
+        let state = state.activate_policy().await.unwrap();
+        assert_eq!(
+            get(state.clone(), Some("auditor")).await.unwrap().version,
+            1
+        );
+
+        let changed = Policy::parse(b"max_evidence_age = 60".to_vec()).unwrap();
+        state.swap_policy(changed);
+        let state = state.activate_policy().await.unwrap();
+        let active = get(state.clone(), Some("auditor")).await.unwrap();
+        assert_eq!(active.version, 2);
+        assert_ne!(active.sha256, empty);
+
+        assert_eq!(
+            get(state.clone(), None).await,
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            get(state, Some("forged")).await,
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[tokio::test]
+    async fn reloads() {
+        let file = File::new("max_evidence_age = 60");
+        let mut state = audited();
+        state.policy_path = Some(file.0.clone());
+
+        // Clones, such as those serving other listeners, see the new policy.
+        let active = reload(&state.clone()).await.unwrap();
+        assert_eq!(active.version, 1);
+        assert_eq!(state.policy().config.max_evidence_age, Some(60));
+
+        // An invalid policy is reported, and the current one kept.
+        file.write("max_evidence_age = \"soon\"");
+        assert!(reload(&state).await.is_err());
+        file.write("[admin]\nttl = 0");
+        let err = reload(&state).await.unwrap_err();
+        assert!(format!("{err:#}").contains("[admin]"));
+        assert_eq!(state.policy().version, 1);
+        assert_eq!(state.policy().config.max_evidence_age, Some(60));
+
+        file.write("max_evidence_age = 120");
+        assert_eq!(reload(&state).await.unwrap().version, 2);
+        assert_eq!(reload(&state).await.unwrap().version, 2);
+        assert_eq!(state.policy().config.max_evidence_age, Some(120));
+
+        // Only operators may reload.
+        let uri = "/admin/policy/reload";
+        let status = call(state.clone(), Method::POST, uri, Some("auditor")).await;
+        assert_eq!(status, Err(StatusCode::UNAUTHORIZED));
+
+        // Nor is there anything to reload without a file.
+        assert!(reload(&audited()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn watches() {
+        let file = File::new("");
+        let mut state = audited();
+        state.policy_path = Some(file.0.clone());
+        let state = state.with_policy_watch(Duration::from_millis(10));
+        let tasks = state.tasks().start();
+
+        file.write("max_evidence_age = 60");
+        for _ in 0..100 {
+            if state.policy().version > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(state.policy().config.max_evidence_age, Some(60));
+
+        tasks.shutdown().await;
+    }
+}
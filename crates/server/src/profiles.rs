@@ -0,0 +1,718 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Issuance profiles: how long issued certificates last, and what for.
+//!
+//! Different workloads want different lifetimes, and fleets whose clocks are
+//! skewed reject certificates which are not yet valid by their own clock, so
+//! a profile may also backdate the start of validity. It may also narrow the
+//! extended key usages of certificates, and require TLS servers using them
+//! to staple a response from an OCSP responder (RFC 7633). Rules pick a
+//! profile by the platform and measurements of the evidence, for example:
+//!
+//! ```toml
+//! [validity]
+//! default = "standard"
+//!
+//! [validity.profiles.ephemeral]
+//! lifetime = 3600
+//! backdate = 300
+//!
+//! [validity.profiles.standard]
+//! lifetime = 86400
+//! extended_key_usage = ["server_auth"]
+//! must_staple = true
+//! ocsp = "http://ocsp.example.com"
+//!
+//! [[validity.rules]]
+//! platform = "snp"
+//! measurement = "0f1e..."
+//! profile = "ephemeral"
+//! ```
+//!
+//! The first matching rule wins, then the default profile. Without either,
+//! certificates are valid for 28 days from issuance.
+//!
+//! A profile may also name issued certificates after a measurement, in place
+//! of the subject requested, as code-signing certificates for builders
+//! running in TEEs do, so that signed artifacts are attributable to the
+//! attested build pipeline rather than to whoever asked:
+//!
+//! ```toml
+//! [validity.profiles.builder]
+//! lifetime = 3600
+//! extended_key_usage = ["code_signing"]
+//! subject_measurement = "mrenclave"
+//! ```
+//!
+//! Such certificates have the hex measurement as their common name and the
+//! platform reporting it as their organizational unit.
+//!
+//! Clients may ask for a profile themselves, by name with the
+//! `X-Steward-Profile` header, or by OID with the certificate template
+//! extension (`1.3.6.1.4.1.311.21.7`) in their request, as Windows clients
+//! do. Only profiles listing the platforms which may ask for them can be
+//! asked for, by attested evidence of one of those platforms and, if the
+//! profile lists tenants, signed by one of them. Any profile may also set
+//! the key usage of certificates and add DNS names to them:
+//!
+//! ```toml
+//! [validity.profiles.server-tls]
+//! lifetime = 86400
+//! oid = "1.3.6.1.4.1.58270.3.1"
+//! key_usage = ["digital_signature", "key_encipherment"]
+//! extended_key_usage = ["server_auth"]
+//! dns_names = ["api.example.com"]
+//! platforms = ["snp"]
+//! tenants = ["0f1e..."]
+//! ```
+//!
+//! A profile asked for replaces the one the rules would pick; one which
+//! doesn't exist or isn't permitted is refused.
+
+use super::stats;
+use super::verifier::Appraisal;
+use super::LEAF_TTL;
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use anyhow::{anyhow, ensure, Context, Result};
+use const_oid::db::rfc5280::{
+    ID_AD_OCSP, ID_CE_EXT_KEY_USAGE, ID_CE_KEY_USAGE, ID_KP_CLIENT_AUTH, ID_KP_CODE_SIGNING,
+    ID_KP_EMAIL_PROTECTION, ID_KP_OCSP_SIGNING, ID_KP_SERVER_AUTH, ID_KP_TIME_STAMPING,
+    ID_PE_AUTHORITY_INFO_ACCESS,
+};
+use const_oid::db::rfc5912::ID_EXTENSION_REQ;
+use const_oid::ObjectIdentifier;
+use der::asn1::Ia5StringRef;
+use der::{Decode, Encode, Sequence};
+use serde::Deserialize;
+use x509::attr::Attribute;
+use x509::ext::pkix::name::GeneralName;
+use x509::ext::pkix::{
+    AccessDescription, AuthorityInfoAccessSyntax, ExtendedKeyUsage, KeyUsage, KeyUsages,
+};
+use x509::name::RdnSequence;
+use x509::request::{CertReqInfo, ExtensionReq};
+
+/// The TLS feature extension (RFC 7633).
+const ID_PE_TLS_FEATURE: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.5.5.7.1.24");
+
+/// The TLS features `SEQUENCE { INTEGER 5 }`: the `status_request` extension,
+/// i.e. OCSP Must-Staple.
+const STATUS_REQUEST: &[u8] = &[0x30, 0x03, 0x02, 0x01, 0x05];
+
+/// The certificate template extension, with which a request names the
+/// profile it asks for by OID.
+pub const ID_CERTIFICATE_TEMPLATE: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.3.6.1.4.1.311.21.7");
+
+/// ASN.1
+/// CertificateTemplate ::= SEQUENCE {
+///     templateID OBJECT IDENTIFIER,
+///     templateMajorVersion INTEGER OPTIONAL,
+///     templateMinorVersion INTEGER OPTIONAL,
+/// }
+#[derive(Clone, Debug, Eq, PartialEq, Sequence)]
+struct Template {
+    id: ObjectIdentifier,
+
+    #[asn1(optional = "true")]
+    major: Option<u32>,
+
+    #[asn1(optional = "true")]
+    minor: Option<u32>,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// How long certificates are valid after issuance, in seconds.
+    pub lifetime: u64,
+
+    /// How long before issuance certificates become valid, in seconds.
+    #[serde(default)]
+    pub backdate: u64,
+
+    /// The extended key usages of certificates, by name (such as
+    /// `server_auth`) or dotted OID. By default, TLS server and client
+    /// authentication.
+    pub extended_key_usage: Option<Vec<String>>,
+
+    /// Whether TLS servers must staple an OCSP response to certificates.
+    #[serde(default)]
+    pub must_staple: bool,
+
+    /// The URL of the OCSP responder for certificates.
+    pub ocsp: Option<String>,
+
+    /// The measurement which names the subject of certificates.
+    pub subject_measurement: Option<String>,
+
+    /// The dotted OID with which requests may ask for the profile.
+    pub oid: Option<String>,
+
+    /// The key usages of certificates, by name (such as
+    /// `digital_signature`). By default, certificates have none.
+    pub key_usage: Option<Vec<String>>,
+
+    /// DNS names added to the subject alternative names of certificates.
+    #[serde(default)]
+    pub dns_names: Vec<String>,
+
+    /// The platforms whose evidence may ask for the profile. Unless set,
+    /// clients may not ask for it.
+    pub platforms: Option<Vec<String>>,
+
+    /// The hex keys which sign the workloads which may ask for the profile.
+    /// By default, any.
+    pub tenants: Option<Vec<String>>,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            lifetime: LEAF_TTL.as_secs(),
+            backdate: 0,
+            extended_key_usage: None,
+            must_staple: false,
+            ocsp: None,
+            subject_measurement: None,
+            oid: None,
+            key_usage: None,
+            dns_names: Vec::new(),
+            platforms: None,
+            tenants: None,
+        }
+    }
+}
+
+/// Looks up an extended key usage by name or dotted OID.
+fn usage(name: &str) -> Result<ObjectIdentifier> {
+    Ok(match name {
+        "server_auth" => ID_KP_SERVER_AUTH,
+        "client_auth" => ID_KP_CLIENT_AUTH,
+        "code_signing" => ID_KP_CODE_SIGNING,
+        "email_protection" => ID_KP_EMAIL_PROTECTION,
+        "time_stamping" => ID_KP_TIME_STAMPING,
+        "ocsp_signing" => ID_KP_OCSP_SIGNING,
+        oid => oid
+            .parse()
+            .map_err(|_| anyhow!("unknown extended key usage `{oid}`"))?,
+    })
+}
+
+/// Looks up a key usage of end entity certificates by name.
+fn key_usage(name: &str) -> Result<KeyUsages> {
+    Ok(match name {
+        "digital_signature" => KeyUsages::DigitalSignature,
+        "non_repudiation" => KeyUsages::NonRepudiation,
+        "key_encipherment" => KeyUsages::KeyEncipherment,
+        "data_encipherment" => KeyUsages::DataEncipherment,
+        "key_agreement" => KeyUsages::KeyAgreement,
+        name => return Err(anyhow!("unknown key usage `{name}`")),
+    })
+}
+
+impl Profile {
+    pub fn lifetime(&self) -> Duration {
+        Duration::from_secs(self.lifetime)
+    }
+
+    pub fn backdate(&self) -> Duration {
+        Duration::from_secs(self.backdate)
+    }
+
+    /// The extended key usages of certificates.
+    pub fn extended_key_usage(&self) -> Result<Vec<ObjectIdentifier>> {
+        match &self.extended_key_usage {
+            Some(usages) => usages.iter().map(|name| usage(name)).collect(),
+            None => Ok(vec![ID_KP_SERVER_AUTH, ID_KP_CLIENT_AUTH]),
+        }
+    }
+
+    /// The key usage of certificates, if the profile sets one.
+    pub fn key_usage(&self) -> Result<Option<KeyUsage>> {
+        let names = match &self.key_usage {
+            Some(names) => names,
+            None => return Ok(None),
+        };
+        let mut usage = KeyUsage(Default::default());
+        for name in names {
+            usage = KeyUsage(usage.0 | key_usage(name)?);
+        }
+        Ok(Some(usage))
+    }
+
+    /// The DNS names which the profile adds to certificates.
+    pub fn dns_names(&self) -> Result<Vec<GeneralName<'_>>> {
+        self.dns_names
+            .iter()
+            .map(|name| Ok(GeneralName::DnsName(Ia5StringRef::new(name)?)))
+            .collect()
+    }
+
+    /// Encodes the extensions which the profile adds to certificates.
+    pub fn extensions(&self) -> Result<Vec<(ObjectIdentifier, Vec<u8>)>> {
+        let eku = ExtendedKeyUsage(self.extended_key_usage()?).to_vec()?;
+        let mut extensions = vec![(ID_CE_EXT_KEY_USAGE, eku)];
+
+        if let Some(usage) = self.key_usage()? {
+            extensions.push((ID_CE_KEY_USAGE, usage.to_vec()?));
+        }
+
+        if let Some(url) = &self.ocsp {
+            let aia = AuthorityInfoAccessSyntax(vec![AccessDescription {
+                access_method: ID_AD_OCSP,
+                access_location: GeneralName::UniformResourceIdentifier(Ia5StringRef::new(url)?),
+            }]);
+            extensions.push((ID_PE_AUTHORITY_INFO_ACCESS, aia.to_vec()?));
+        }
+
+        if self.must_staple {
+            extensions.push((ID_PE_TLS_FEATURE, STATUS_REQUEST.to_vec()));
+        }
+
+        Ok(extensions)
+    }
+
+    /// Encodes the subject of certificates issued on the strength of
+    /// `appraisals`, if the profile names it after a measurement.
+    pub fn subject(&self, appraisals: &[Appraisal]) -> Result<Option<Vec<u8>>> {
+        let name = match &self.subject_measurement {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+
+        let (platform, value) = appraisals
+            .iter()
+            .filter(|appraisal| appraisal.attests)
+            .find_map(|a| Some((&a.platform, a.measurements.get(name)?)))
+            .ok_or_else(|| anyhow!("no attested evidence carries measurement `{name}`"))?;
+        let subject = RdnSequence::encode_from_string(&format!("CN={value},OU={platform}"))?;
+        Ok(Some(subject))
+    }
+
+    /// Whether the workload which attested `appraisals` may ask for the
+    /// profile.
+    fn permits(&self, appraisals: &[Appraisal]) -> bool {
+        let platforms = match &self.platforms {
+            Some(platforms) => platforms,
+            None => return false,
+        };
+        let attested = appraisals
+            .iter()
+            .filter(|appraisal| appraisal.attests)
+            .any(|appraisal| platforms.contains(&appraisal.platform));
+        let tenant = || {
+            let tenant = stats::tenant(appraisals);
+            let signed = |t: &String| tenant.iter().any(|tenant| tenant.eq_ignore_ascii_case(t));
+            self.tenants.iter().flatten().any(signed)
+        };
+        attested && (self.tenants.is_none() || tenant())
+    }
+}
+
+/// The OID of the profile which `info` asks for, if any.
+pub fn requested(info: &CertReqInfo<'_>) -> Result<Option<ObjectIdentifier>> {
+    let attributes = info
+        .attributes
+        .iter()
+        .filter(|Attribute { oid, .. }| *oid == ID_EXTENSION_REQ);
+    for Attribute { values, .. } in attributes {
+        for any in values.iter() {
+            let ereq: ExtensionReq<'_> = any.decode_into()?;
+            let template = ereq
+                .0
+                .iter()
+                .find(|ext| ext.extn_id == ID_CERTIFICATE_TEMPLATE);
+            if let Some(ext) = template {
+                let template =
+                    Template::from_der(ext.extn_value).context("malformed certificate template")?;
+                return Ok(Some(template.id));
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// The platform whose evidence the rule applies to.
+    pub platform: String,
+
+    /// A hex measurement, such as a launch digest or signer, which the
+    /// evidence must carry for the rule to apply.
+    pub measurement: Option<String>,
+
+    /// The name of the profile to issue with.
+    pub profile: String,
+}
+
+impl Rule {
+    fn matches(&self, appraisal: &Appraisal) -> bool {
+        if appraisal.platform != self.platform {
+            return false;
+        }
+
+        match &self.measurement {
+            Some(wanted) => appraisal
+                .measurements
+                .values()
+                .any(|value| value.eq_ignore_ascii_case(wanted)),
+            None => true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    /// The profile used when no rule matches.
+    pub default: Option<String>,
+
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+impl Policy {
+    /// Rejects profiles which issue nothing useful and references to
+    /// profiles which don't exist.
+    pub fn validate(&self) -> Result<()> {
+        for (name, profile) in &self.profiles {
+            ensure!(
+                profile.lifetime > 0,
+                "profile `{name}` lifetime must be positive"
+            );
+            ensure!(
+                profile.backdate < profile.lifetime,
+                "profile `{name}` backdate must be shorter than its lifetime"
+            );
+            ensure!(
+                !profile.extended_key_usage()?.is_empty(),
+                "profile `{name}` must allow some extended key usage"
+            );
+
+            // Certificates requiring a response nobody can get are useless.
+            ensure!(
+                !profile.must_staple || profile.ocsp.is_some(),
+                "profile `{name}` must name an ocsp responder to require stapling"
+            );
+            if let Some(url) = &profile.ocsp {
+                ensure!(
+                    url.starts_with("http://") || url.starts_with("https://"),
+                    "profile `{name}` ocsp responder must be an http url"
+                );
+            }
+            if let Some(measurement) = &profile.subject_measurement {
+                ensure!(
+                    !measurement.is_empty() && measurement.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_'),
+                    "profile `{name}` subject measurement `{measurement}` is not a measurement name"
+                );
+            }
+
+            if let Some(oid) = &profile.oid {
+                ensure!(
+                    oid.parse::<ObjectIdentifier>().is_ok(),
+                    "profile `{name}` oid `{oid}` is not a dotted OID"
+                );
+                let shared = self
+                    .profiles
+                    .values()
+                    .filter(|p| p.oid.as_ref() == Some(oid));
+                ensure!(
+                    shared.count() == 1,
+                    "profile `{name}` oid `{oid}` names several profiles"
+                );
+            }
+            if let Some(usage) = profile.key_usage()? {
+                ensure!(
+                    !usage.0.is_empty(),
+                    "profile `{name}` must allow some key usage"
+                );
+            }
+            for dns in &profile.dns_names {
+                ensure!(
+                    !dns.is_empty() && dns.bytes().all(|b| b.is_ascii_graphic()),
+                    "profile `{name}` dns name `{dns}` is not a DNS name"
+                );
+            }
+            if let Some(platforms) = &profile.platforms {
+                ensure!(
+                    !platforms.is_empty(),
+                    "profile `{name}` must name some platform which may ask for it"
+                );
+            }
+            if let Some(tenants) = &profile.tenants {
+                ensure!(
+                    profile.platforms.is_some(),
+                    "profile `{name}` must name the platforms of its tenants"
+                );
+                for tenant in tenants {
+                    ensure!(
+                        hex::decode(tenant).is_ok(),
+                        "profile `{name}` tenant `{tenant}` is not hex"
+                    );
+                }
+            }
+        }
+
+        let names = self
+            .default
+            .iter()
+            .chain(self.rules.iter().map(|r| &r.profile));
+        for name in names {
+            ensure!(self.profiles.contains_key(name), "unknown profile `{name}`");
+        }
+
+        for rule in &self.rules {
+            if let Some(measurement) = &rule.measurement {
+                ensure!(
+                    hex::decode(measurement).is_ok(),
+                    "measurement `{measurement}` is not hex"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The profile to issue a certificate with on the strength of `appraisals`.
+    pub fn select(&self, appraisals: &[Appraisal]) -> Profile {
+        let rule = self
+            .rules
+            .iter()
+            .find(|rule| appraisals.iter().any(|a| rule.matches(a)));
+        rule.map(|rule| &rule.profile)
+            .or(self.default.as_ref())
+            .and_then(|name| self.profiles.get(name))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// The name of the profile which requests ask for with `oid`.
+    pub fn named(&self, oid: &ObjectIdentifier) -> Option<&str> {
+        let oid = oid.to_string();
+        self.profiles
+            .iter()
+            .find(|(_, profile)| profile.oid.as_ref() == Some(&oid))
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// The profile named `name`, which the workload which attested
+    /// `appraisals` asked for, if it may.
+    pub fn request(&self, name: &str, appraisals: &[Appraisal]) -> Result<Profile> {
+        let profile = self
+            .profiles
+            .get(name)
+            .filter(|profile| profile.permits(appraisals));
+        profile
+            .cloned()
+            .ok_or_else(|| anyhow!("profile `{name}` is not permitted"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Config {
+        validity: Policy,
+    }
+
+    fn policy(toml: &str) -> Policy {
+        toml::from_str::<Config>(toml).unwrap().validity
+    }
+
+    const POLICY: &str = r#"
+        [validity]
+        default = "standard"
+
+        [validity.profiles.ephemeral]
+        lifetime = 3600
+        backdate = 300
+
+        [validity.profiles.standard]
+        lifetime = 86400
+
+        [validity.profiles.service]
+        lifetime = 604800
+        extended_key_usage = ["server_auth", "1.2.3.4"]
+        must_staple = true
+        ocsp = "http://ocsp.example.com"
+
+        [validity.profiles.builder]
+        lifetime = 3600
+        extended_key_usage = ["code_signing"]
+        subject_measurement = "mrenclave"
+
+        [validity.profiles.server-tls]
+        lifetime = 86400
+        oid = "1.3.6.1.4.1.58270.3.1"
+        key_usage = ["digital_signature", "key_agreement"]
+        dns_names = ["api.example.com"]
+        platforms = ["snp", "sgx"]
+        tenants = ["AABB"]
+
+        [[validity.rules]]
+        platform = "snp"
+        measurement = "AABB"
+        profile = "ephemeral"
+
+        [[validity.rules]]
+        platform = "sgx"
+        profile = "service"
+        "#;
+
+    #[test]
+    fn select() {
+        let policy = policy(POLICY);
+        policy.validate().unwrap();
+
+        let snp = |measurement: &[u8]| {
+            Appraisal::new("snp", true).with_measurement("measurement", measurement)
+        };
+        let ephemeral = policy.select(&[snp(&[0xaa, 0xbb])]);
+        assert_eq!(ephemeral.lifetime(), Duration::from_secs(3600));
+        assert_eq!(ephemeral.backdate(), Duration::from_secs(300));
+        assert_eq!(policy.select(&[snp(&[0xcc])]).lifetime, 86400);
+
+        // Any of several pieces of evidence may match.
+        let sgx = Appraisal::new("sgx", true);
+        assert_eq!(policy.select(&[snp(&[0xcc]), sgx]).lifetime, 604800);
+
+        // Without a profile, certificates last as long as they always have.
+        assert_eq!(Policy::default().select(&[snp(&[])]), Profile::default());
+        assert_eq!(Profile::default().lifetime(), LEAF_TTL);
+    }
+
+    #[test]
+    fn extensions() {
+        let policy = policy(POLICY);
+        let oids = |profile: &Profile| -> Vec<_> {
+            let extensions = profile.extensions().unwrap();
+            extensions.into_iter().map(|(oid, _)| oid).collect()
+        };
+
+        // By default, only the usual extended key usages.
+        let standard = &policy.profiles["standard"];
+        assert_eq!(oids(standard), [ID_CE_EXT_KEY_USAGE]);
+        assert_eq!(
+            standard.extended_key_usage().unwrap(),
+            [ID_KP_SERVER_AUTH, ID_KP_CLIENT_AUTH]
+        );
+
+        let service = &policy.profiles["service"];
+        assert_eq!(
+            service.extended_key_usage().unwrap(),
+            [ID_KP_SERVER_AUTH, ObjectIdentifier::new_unwrap("1.2.3.4")]
+        );
+        assert_eq!(
+            oids(service),
+            [
+                ID_CE_EXT_KEY_USAGE,
+                ID_PE_AUTHORITY_INFO_ACCESS,
+                ID_PE_TLS_FEATURE
+            ]
+        );
+        let (_, features) = &service.extensions().unwrap()[2];
+        assert_eq!(features, STATUS_REQUEST);
+    }
+
+    #[test]
+    fn subject() {
+        use der::Decode;
+
+        let policy = policy(POLICY);
+        let builder = &policy.profiles["builder"];
+        assert_eq!(builder.extended_key_usage().unwrap(), [ID_KP_CODE_SIGNING]);
+
+        let sgx = Appraisal::new("sgx", true).with_measurement("mrenclave", &[0xab; 4]);
+        let subject = builder.subject(&[sgx.clone()]).unwrap().unwrap();
+        let subject = RdnSequence::from_der(&subject).unwrap();
+        let expected = RdnSequence::encode_from_string("CN=abababab,OU=sgx").unwrap();
+        assert_eq!(subject, RdnSequence::from_der(&expected).unwrap());
+
+        // Only attested measurements name certificates.
+        let unattested = Appraisal {
+            attests: false,
+            ..sgx
+        };
+        assert!(builder.subject(&[unattested]).is_err());
+        assert!(builder.subject(&[Appraisal::new("snp", true)]).is_err());
+
+        // Other profiles keep the requested subject.
+        assert_eq!(policy.profiles["standard"].subject(&[]).unwrap(), None);
+    }
+
+    #[test]
+    fn request() {
+        let policy = policy(POLICY);
+        policy.validate().unwrap();
+
+        let oid = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.58270.3.1");
+        assert_eq!(policy.named(&oid), Some("server-tls"));
+        assert_eq!(policy.named(&ID_KP_SERVER_AUTH), None);
+
+        // Workloads of the tenants on the platforms may ask for the profile.
+        let sgx = |signer: &[u8]| Appraisal::new("sgx", true).with_measurement("mrsigner", signer);
+        let profile = policy.request("server-tls", &[sgx(&[0xaa, 0xbb])]).unwrap();
+        assert_eq!(profile.lifetime, 86400);
+        let usage = profile.key_usage().unwrap().unwrap();
+        assert_eq!(
+            usage.0,
+            KeyUsages::DigitalSignature | KeyUsages::KeyAgreement
+        );
+        assert_eq!(profile.dns_names().unwrap().len(), 1);
+        let extensions = profile.extensions().unwrap();
+        assert_eq!(extensions[1].0, ID_CE_KEY_USAGE);
+
+        // But not others, nor for profiles which don't say who may.
+        let unattested = Appraisal {
+            attests: false,
+            ..sgx(&[0xaa, 0xbb])
+        };
+        for appraisals in [
+            vec![sgx(&[0xcc])],
+            vec![unattested],
+            vec![Appraisal::new("se", true)],
+        ] {
+            assert!(policy.request("server-tls", &appraisals).is_err());
+        }
+        assert!(policy.request("standard", &[sgx(&[0xaa, 0xbb])]).is_err());
+        assert!(policy.request("missing", &[sgx(&[0xaa, 0xbb])]).is_err());
+    }
+
+    #[test]
+    fn validate() {
+        for toml in [
+            "[validity.profiles.p]\nlifetime = 0",
+            "[validity.profiles.p]\nlifetime = 60\nbackdate = 60",
+            "[validity]\ndefault = \"missing\"",
+            "[[validity.rules]]\nplatform = \"snp\"\nprofile = \"missing\"",
+            "[validity.profiles.p]\nlifetime = 60\n[[validity.rules]]\nplatform = \"snp\"\nmeasurement = \"xyz\"\nprofile = \"p\"",
+            "[validity.profiles.p]\nlifetime = 60\nextended_key_usage = []",
+            "[validity.profiles.p]\nlifetime = 60\nextended_key_usage = [\"telepathy\"]",
+            "[validity.profiles.p]\nlifetime = 60\nmust_staple = true",
+            "[validity.profiles.p]\nlifetime = 60\nocsp = \"ldap://ocsp\"",
+            "[validity.profiles.p]\nlifetime = 60\nsubject_measurement = \"\"",
+            "[validity.profiles.p]\nlifetime = 60\nsubject_measurement = \"a,OU=b\"",
+            "[validity.profiles.p]\nlifetime = 60\noid = \"server-tls\"",
+            "[validity.profiles.p]\nlifetime = 60\noid = \"1.2.3\"\n[validity.profiles.q]\nlifetime = 60\noid = \"1.2.3\"",
+            "[validity.profiles.p]\nlifetime = 60\nkey_usage = []",
+            "[validity.profiles.p]\nlifetime = 60\nkey_usage = [\"key_cert_sign\"]",
+            "[validity.profiles.p]\nlifetime = 60\ndns_names = [\"a b\"]",
+            "[validity.profiles.p]\nlifetime = 60\nplatforms = []",
+            "[validity.profiles.p]\nlifetime = 60\ntenants = [\"aa\"]",
+            "[validity.profiles.p]\nlifetime = 60\nplatforms = [\"snp\"]\ntenants = [\"xyz\"]",
+        ] {
+            assert!(policy(toml).validate().is_err(), "{toml}");
+        }
+    }
+}
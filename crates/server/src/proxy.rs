@@ -0,0 +1,491 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Recovery of client addresses behind reverse proxies.
+//!
+//! Behind a load balancer the connected peer is the proxy. Proxies either
+//! name the client in an `X-Forwarded-For` header, which is only believed
+//! when the peer is a trusted proxy, or announce it at the start of the
+//! connection with the HAProxy PROXY protocol.
+//!
+//! Proxies which terminate TLS may also forward the client's certificate in
+//! an `X-Client-Cert` header, as URL-encoded PEM like nginx's
+//! `$ssl_client_escaped_cert`. It too is only believed from a trusted proxy,
+//! which must replace any such header the client sends.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use anyhow::{anyhow, ensure};
+use axum::extract::connect_info::Connected;
+use axum::http::HeaderMap;
+use hyper::server::conn::AddrStream;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_CLIENT_CERT: &str = "x-client-cert";
+
+/// Converts IPv4-mapped IPv6 addresses, as seen on dual-stack sockets, to IPv4.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    }
+}
+
+/// An address range in CIDR notation; a bare address is a single host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr = canonical(addr.parse().map_err(|_| anyhow!("invalid address `{s}`"))?);
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .map_err(|_| anyhow!("invalid prefix `{s}`"))?,
+            None => max,
+        };
+        ensure!(prefix <= max, "invalid prefix `{s}`");
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        fn mask(bits: u32, prefix: u8) -> u128 {
+            match prefix {
+                0 => 0,
+                p => u128::MAX << (bits - u32::from(p)),
+            }
+        }
+
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = mask(32, self.prefix) as u32;
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = mask(128, self.prefix);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The proxies whose forwarding headers are believed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Trusted(Vec<Cidr>);
+
+impl Trusted {
+    pub fn new(ranges: Vec<Cidr>) -> Self {
+        Self(ranges)
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|range| range.contains(ip))
+    }
+
+    /// The address of the client behind a request from `peer`.
+    ///
+    /// `X-Forwarded-For` is walked from the nearest hop outwards, skipping
+    /// trusted proxies, so that a client cannot spoof its address by sending
+    /// the header itself.
+    pub fn client(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client = canonical(peer);
+        if !self.trusts(client) {
+            return client;
+        }
+
+        let hops = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>();
+
+        for hop in hops.into_iter().rev() {
+            match hop.trim().parse() {
+                Ok(ip) => client = canonical(ip),
+                Err(..) => break,
+            }
+
+            if !self.trusts(client) {
+                break;
+            }
+        }
+
+        client
+    }
+
+    /// The DER certificate with which the client behind a request from
+    /// `peer` authenticated to a trusted proxy, if any.
+    pub fn client_crt(&self, peer: IpAddr, headers: &HeaderMap) -> Option<Vec<u8>> {
+        if !self.trusts(canonical(peer)) {
+            return None;
+        }
+
+        let pem = unescape(headers.get(X_CLIENT_CERT)?.to_str().ok()?)?;
+        match rustls_pemfile::read_one(&mut pem.as_slice()).ok()? {
+            Some(rustls_pemfile::Item::X509Certificate(der)) => Some(der),
+            _ => None,
+        }
+    }
+}
+
+/// Decodes a percent-encoded header value.
+fn unescape(value: &str) -> Option<Vec<u8>> {
+    let mut bytes = value.bytes();
+    let mut out = Vec::with_capacity(value.len());
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => out.extend(hex::decode([bytes.next()?, bytes.next()?]).ok()?),
+            byte => out.push(byte),
+        }
+    }
+    Some(out)
+}
+
+/// The connected peer: the socket's address, or that from a PROXY header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Peer(pub SocketAddr);
+
+impl Connected<&AddrStream> for Peer {
+    fn connect_info(target: &AddrStream) -> Self {
+        Self(target.remote_addr())
+    }
+}
+
+#[cfg(not(target_os = "wasi"))]
+pub use self::protocol::{incoming, Incoming, Proxied};
+
+#[cfg(not(target_os = "wasi"))]
+mod protocol {
+    use super::Peer;
+    use crate::listener::Tuning;
+
+    use std::io;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use axum::extract::connect_info::Connected;
+    use hyper::server::accept::Accept;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+    use tracing::{debug, warn};
+
+    const V2: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+    /// How long a connection may take to send its PROXY header.
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn invalid(msg: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+    }
+
+    /// Reads a version 1 or 2 PROXY protocol header.
+    ///
+    /// Returns the client's address, or `None` for connections the proxy
+    /// makes on its own behalf (e.g. health checks).
+    pub(super) async fn read_header<R: AsyncRead + Unpin>(
+        r: &mut R,
+    ) -> io::Result<Option<SocketAddr>> {
+        // Every valid header, of either version, is at least this long.
+        let mut buf = [0u8; 12];
+        r.read_exact(&mut buf).await?;
+
+        if &buf == V2 {
+            let mut head = [0u8; 4];
+            r.read_exact(&mut head).await?;
+            let len = u16::from_be_bytes([head[2], head[3]]) as usize;
+            let mut body = vec![0u8; len];
+            r.read_exact(&mut body).await?;
+
+            return match (head[0], head[1]) {
+                (0x20, _) => Ok(None),
+                (0x21, 0x11) if len >= 12 => {
+                    let ip: [u8; 4] = body[..4].try_into().unwrap();
+                    let port = u16::from_be_bytes([body[8], body[9]]);
+                    Ok(Some((Ipv4Addr::from(ip), port).into()))
+                }
+                (0x21, 0x21) if len >= 36 => {
+                    let ip: [u8; 16] = body[..16].try_into().unwrap();
+                    let port = u16::from_be_bytes([body[32], body[33]]);
+                    Ok(Some((Ipv6Addr::from(ip), port).into()))
+                }
+                (0x21, _) => Ok(None),
+                _ => Err(invalid("unsupported proxy protocol v2 header")),
+            };
+        }
+
+        if !buf.starts_with(b"PROXY ") {
+            return Err(invalid("missing proxy protocol header"));
+        }
+
+        // A version 1 header is a single line of at most 107 bytes.
+        let mut line = buf.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= 107 {
+                return Err(invalid("proxy protocol v1 header too long"));
+            }
+            line.push(r.read_u8().await?);
+        }
+
+        let line = std::str::from_utf8(&line[..line.len() - 2])
+            .map_err(|_| invalid("invalid proxy protocol v1 header"))?;
+        let fields: Vec<_> = line.split(' ').collect();
+        match fields.as_slice() {
+            ["PROXY", "UNKNOWN", ..] => Ok(None),
+            ["PROXY", "TCP4" | "TCP6", src, _, sport, _] => {
+                let ip = src.parse().map_err(|_| invalid("invalid source address"))?;
+                let port = sport.parse().map_err(|_| invalid("invalid source port"))?;
+                Ok(Some(SocketAddr::new(ip, port)))
+            }
+            _ => Err(invalid("invalid proxy protocol v1 header")),
+        }
+    }
+
+    /// A connection whose PROXY header has been consumed.
+    #[derive(Debug)]
+    pub struct Proxied {
+        stream: TcpStream,
+        peer: SocketAddr,
+    }
+
+    impl AsyncRead for Proxied {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Proxied {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+        }
+    }
+
+    impl Connected<&Proxied> for Peer {
+        fn connect_info(target: &Proxied) -> Self {
+            Self(target.peer)
+        }
+    }
+
+    /// Connections accepted by [`incoming`].
+    #[derive(Debug)]
+    pub struct Incoming(mpsc::Receiver<Proxied>);
+
+    impl Accept for Incoming {
+        type Conn = Proxied;
+        type Error = io::Error;
+
+        fn poll_accept(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+            self.get_mut().0.poll_recv(cx).map(|conn| conn.map(Ok))
+        }
+    }
+
+    /// Accepts connections which must begin with a PROXY protocol header.
+    ///
+    /// Headers are read concurrently, so a slow client cannot hold up others.
+    /// The TCP settings of `tuning` are applied to each connection.
+    pub fn incoming(listener: TcpListener, tuning: Tuning) -> Incoming {
+        let (tx, rx) = mpsc::channel(64);
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("failed to accept connection: {e}");
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                if let Err(e) = tuning.stream(&stream) {
+                    debug!("failed to tune connection from {addr}: {e}");
+                }
+
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(TIMEOUT, read_header(&mut stream)).await {
+                        Ok(Ok(peer)) => {
+                            let peer = peer.unwrap_or(addr);
+                            let _ = tx.send(Proxied { stream, peer }).await;
+                        }
+                        Ok(Err(e)) => debug!("rejected connection from {addr}: {e}"),
+                        Err(..) => debug!("timed out reading proxy header from {addr}"),
+                    }
+                });
+            }
+        });
+
+        Incoming(rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::http::HeaderValue;
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine;
+
+    fn trusted(ranges: &[&str]) -> Trusted {
+        Trusted::new(ranges.iter().map(|r| r.parse().unwrap()).collect())
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr() {
+        let net: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains(ip("10.1.2.3")));
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(ip("11.0.0.1")));
+        assert!(!net.contains(ip("fd00::1")));
+
+        let net: Cidr = "fd00::/8".parse().unwrap();
+        assert!(net.contains(ip("fd12::1")));
+        assert!(!net.contains(ip("fe80::1")));
+
+        let host: Cidr = "192.0.2.1".parse().unwrap();
+        assert!(host.contains(ip("192.0.2.1")));
+        assert!(!host.contains(ip("192.0.2.2")));
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(ip("203.0.113.9")));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("proxy".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn forwarded() {
+        let proxies = trusted(&["10.0.0.0/8"]);
+        let mut headers = HeaderMap::new();
+        headers.append(
+            X_FORWARDED_FOR,
+            HeaderValue::from_static("198.51.100.7, 203.0.113.5"),
+        );
+        headers.append(X_FORWARDED_FOR, HeaderValue::from_static("10.0.0.2"));
+
+        // The nearest untrusted hop is the client; earlier hops may be forged.
+        assert_eq!(proxies.client(ip("10.0.0.1"), &headers), ip("203.0.113.5"));
+
+        // Untrusted peers cannot forward.
+        assert_eq!(proxies.client(ip("192.0.2.1"), &headers), ip("192.0.2.1"));
+
+        // Nothing is trusted by default.
+        let none = Trusted::default();
+        assert_eq!(none.client(ip("10.0.0.1"), &headers), ip("10.0.0.1"));
+
+        // Garbage stops the walk.
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            X_FORWARDED_FOR,
+            HeaderValue::from_static("unknown, 10.0.0.3"),
+        );
+        assert_eq!(proxies.client(ip("10.0.0.1"), &headers), ip("10.0.0.3"));
+    }
+
+    #[test]
+    fn client_crt() {
+        let der = [0x30, 0x03, 0x02, 0x01, 0x01];
+        let pem = format!(
+            "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+            BASE64.encode(der)
+        );
+        let escaped = pem
+            .replace('\n', "%0A")
+            .replace('+', "%2B")
+            .replace(' ', "%20");
+
+        let proxies = trusted(&["10.0.0.0/8"]);
+        let mut headers = HeaderMap::new();
+        headers.insert(X_CLIENT_CERT, HeaderValue::from_str(&escaped).unwrap());
+        assert_eq!(
+            proxies.client_crt(ip("10.0.0.1"), &headers),
+            Some(der.to_vec())
+        );
+
+        // Only trusted proxies vouch for the certificate.
+        assert_eq!(proxies.client_crt(ip("192.0.2.1"), &headers), None);
+        assert_eq!(
+            Trusted::default().client_crt(ip("10.0.0.1"), &headers),
+            None
+        );
+
+        headers.insert(X_CLIENT_CERT, HeaderValue::from_static("%0"));
+        assert_eq!(proxies.client_crt(ip("10.0.0.1"), &headers), None);
+        assert_eq!(proxies.client_crt(ip("10.0.0.1"), &HeaderMap::new()), None);
+    }
+
+    #[cfg(not(target_os = "wasi"))]
+    #[tokio::test]
+    async fn proxy_protocol() {
+        use super::protocol::read_header;
+
+        let mut v1 = &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /"[..];
+        let peer = read_header(&mut v1).await.unwrap();
+        assert_eq!(peer, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(v1, b"GET /");
+
+        let mut unknown = &b"PROXY UNKNOWN\r\n"[..];
+        assert_eq!(read_header(&mut unknown).await.unwrap(), None);
+
+        let mut v2 = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        v2.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        v2.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        v2.extend_from_slice(b"GET /");
+        let mut v2 = &v2[..];
+        let peer = read_header(&mut v2).await.unwrap();
+        assert_eq!(peer, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(v2, b"GET /");
+
+        let mut missing = &b"GET / HTTP/1.1\r\n"[..];
+        assert!(read_header(&mut missing).await.is_err());
+    }
+}
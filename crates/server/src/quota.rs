@@ -0,0 +1,239 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Per-platform issuance quotas.
+//!
+//! A workload which is compromised but still attests can ask for as many
+//! certificates as it likes. Quotas cap how many are issued for the same
+//! chip, measurement or signing key (which stands in for the tenant) within
+//! a sliding window, for example:
+//!
+//! ```toml
+//! [[quotas]]
+//! platform = "snp"
+//! per = "chip_id"
+//! limit = 100
+//! window = 3600
+//! ```
+//!
+//! Issuances are counted in the store, so that every replica sharing it
+//! enforces the same quotas.
+
+use super::store::Allowance;
+
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Result};
+#[cfg(feature = "sgx")]
+use attestation::sgx::Sgx;
+#[cfg(feature = "snp")]
+use attestation::snp::Snp;
+use serde::Deserialize;
+use x509::ext::Extension;
+
+/// What issuances are counted per.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Per {
+    /// The chip which produced the evidence.
+    ChipId,
+
+    /// The launch measurement of the workload.
+    Measurement,
+
+    /// The key which signed the workload.
+    Signer,
+}
+
+impl Per {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::ChipId => "chip_id",
+            Self::Measurement => "measurement",
+            Self::Signer => "signer",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Quota {
+    /// The platform whose evidence is counted.
+    pub platform: String,
+
+    pub per: Per,
+
+    /// The most certificates issued within the window.
+    pub limit: u64,
+
+    /// The length of the window, in seconds.
+    pub window: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(transparent)]
+pub struct Policy(pub Vec<Quota>);
+
+impl Policy {
+    /// Rejects quotas which could never be kept.
+    pub fn validate(&self) -> Result<()> {
+        for quota in &self.0 {
+            ensure!(quota.limit > 0, "quota limit must be positive");
+            ensure!(quota.window > 0, "quota window must be positive");
+            match (quota.platform.as_str(), quota.per) {
+                #[cfg(feature = "sgx")]
+                ("sgx", Per::ChipId) => bail!("sgx quotes carry no chip id"),
+                #[cfg(feature = "sgx")]
+                ("sgx", _) => (),
+                #[cfg(feature = "snp")]
+                ("snp", _) => (),
+                (platform, _) => bail!("quotas cannot be kept for platform `{platform}`"),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The allowances which issuing on the verified evidence in `ext`, from
+    /// `platform`, counts against.
+    pub fn allowances(&self, platform: &str, ext: &Extension<'_>) -> Result<Vec<Allowance>> {
+        let mut allowances = Vec::new();
+        for quota in self.0.iter().filter(|q| q.platform == platform) {
+            let id = identity(ext, quota.per)?;
+            allowances.push(Allowance {
+                subject: format!(
+                    "{platform}/{}/{}/{}",
+                    quota.per.as_str(),
+                    quota.window,
+                    hex::encode(id)
+                ),
+                window: Duration::from_secs(quota.window),
+                limit: quota.limit,
+            });
+        }
+
+        Ok(allowances)
+    }
+}
+
+#[cfg_attr(not(any(feature = "sgx", feature = "snp")), allow(unused_variables))]
+fn identity(ext: &Extension<'_>, per: Per) -> Result<Vec<u8>> {
+    match ext.extn_id {
+        #[cfg(feature = "sgx")]
+        Sgx::OID => {
+            let report = Sgx::report(ext)?;
+            match per {
+                Per::ChipId => bail!("sgx quotes carry no chip id"),
+                Per::Measurement => Ok(report.mrenclave.to_vec()),
+                Per::Signer => Ok(report.mrsigner.to_vec()),
+            }
+        }
+
+        #[cfg(feature = "snp")]
+        Snp::OID => {
+            let body = Snp::report(ext)?.body;
+            Ok(match per {
+                Per::ChipId => body.chip_id.to_vec(),
+                Per::Measurement => body.measurement.to_vec(),
+                Per::Signer => body.author_key_digest.to_vec(),
+            })
+        }
+
+        oid => bail!("quotas cannot be kept for extension {oid}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate() {
+        let policy: Policy = toml::from_str::<Config>(
+            r#"
+            [[quotas]]
+            platform = "snp"
+            per = "chip_id"
+            limit = 100
+            window = 3600
+            "#,
+        )
+        .unwrap()
+        .quotas;
+        assert_eq!(policy.0[0].per, Per::ChipId);
+        #[cfg(feature = "snp")]
+        policy.validate().unwrap();
+
+        for (platform, per, limit, window) in [
+            ("tdx", "measurement", 1, 1),
+            ("kvm", "measurement", 1, 1),
+            ("sgx", "chip_id", 1, 1),
+            ("snp", "measurement", 0, 1),
+            ("snp", "measurement", 1, 0),
+        ] {
+            let policy: Policy = toml::from_str::<Config>(&format!(
+                "[[quotas]]\nplatform = \"{platform}\"\nper = \"{per}\"\nlimit = {limit}\nwindow = {window}"
+            ))
+            .unwrap()
+            .quotas;
+            assert!(policy.validate().is_err(), "{platform} {per}");
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct Config {
+        quotas: Policy,
+    }
+
+    #[cfg(all(feature = "sgx", feature = "snp"))]
+    #[test]
+    fn allowances() {
+        use der::Decode;
+        use x509::request::{CertReq, ExtensionReq};
+
+        let policy = Policy(vec![
+            Quota {
+                platform: "snp".into(),
+                per: Per::ChipId,
+                limit: 1,
+                window: 60,
+            },
+            Quota {
+                platform: "sgx".into(),
+                per: Per::Measurement,
+                limit: 1,
+                window: 60,
+            },
+        ]);
+
+        for (csr, platform) in [
+            (
+                &include_bytes!("../../attestation/src/sgx/icelake.signed.crl.csr")[..],
+                "sgx",
+            ),
+            (
+                &include_bytes!("../../attestation/src/snp/milan.signed.crl.csr")[..],
+                "snp",
+            ),
+        ] {
+            let cr = CertReq::from_der(csr).unwrap();
+            let attr = cr.info.attributes.iter().next().unwrap();
+            let ereq: ExtensionReq<'_> = attr.values.iter().next().unwrap().decode_into().unwrap();
+            for ext in Vec::from(ereq) {
+                let allowances = policy.allowances(platform, &ext).unwrap();
+                assert_eq!(allowances.len(), 1);
+                assert_eq!(allowances[0].limit, 1);
+                assert_eq!(allowances[0].window, Duration::from_secs(60));
+
+                let prefix = match platform {
+                    "sgx" => "sgx/measurement/60/",
+                    _ => "snp/chip_id/60/",
+                };
+                assert!(allowances[0].subject.starts_with(prefix));
+
+                // Other platforms' quotas do not apply.
+                assert!(policy.allowances("kvm", &ext).unwrap().is_empty());
+            }
+        }
+    }
+}
@@ -0,0 +1,136 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Whether an instance can issue certificates.
+//!
+//! `/healthz` only says that the process is up. `/readyz` says whether it is
+//! worth sending workloads to, so that orchestrators route around instances
+//! which would only fail them: those whose signing certificate is not valid,
+//! or which have neither cached nor fetchable collateral. The policy is
+//! validated before the server starts, so an instance whose policy failed to
+//! load never reports ready, and a reloaded one before it replaces the
+//! current one, which stays in force otherwise.
+
+use super::State;
+
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use axum::extract::Extension;
+use der::Decode;
+use hyper::StatusCode;
+use x509::Certificate;
+
+/// Lists what keeps the instance from issuing certificates.
+pub(crate) fn problems(state: &State) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if let Err(problem) = validity(&state.crt, state.clock.now()) {
+        problems.push(problem.into());
+    }
+
+    #[cfg(all(feature = "collateral", not(target_os = "wasi")))]
+    if let Some(collateral) = &state.collateral {
+        if let Err(e) = collateral.available() {
+            problems.push(format!("no collateral: {e}"));
+        }
+    }
+
+    problems
+}
+
+fn validity(crt: &[u8], now: SystemTime) -> Result<(), &'static str> {
+    let crt = Certificate::from_der(crt).or(Err("invalid signing certificate"))?;
+    let validity = crt.tbs_certificate.validity;
+    if now < validity.not_before.to_system_time() {
+        return Err("signing certificate is not yet valid");
+    }
+    if now >= validity.not_after.to_system_time() {
+        return Err("signing certificate has expired");
+    }
+    Ok(())
+}
+
+/// Answers `200 OK` when ready, or else `503 Service Unavailable` with the
+/// reasons, one per line.
+pub async fn readyz(Extension(state): Extension<Arc<State>>) -> (StatusCode, String) {
+    let problems = problems(&state);
+    if problems.is_empty() {
+        return (StatusCode::OK, "ready\n".into());
+    }
+
+    let mut body = problems.join("\n");
+    body.push('\n');
+    (StatusCode::SERVICE_UNAVAILABLE, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{clock, operations, Constraints};
+    use super::*;
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use http::Request;
+    use hyper::Body;
+    use tower::ServiceExt; // for `app.oneshot()`
+
+    const DAY: Duration = Duration::from_secs(60 * 60 * 24);
+
+    async fn readyz(state: State) -> (StatusCode, String) {
+        let request = Request::get("/readyz").body(Body::empty()).unwrap();
+        let response = operations(state).oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn expiry() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = Arc::new(clock::Manual::new(start));
+        let state = State::generate_with(None, "localhost", &Constraints::default(), clock.clone())
+            .unwrap();
+
+        assert_eq!(
+            readyz(state.clone()).await,
+            (StatusCode::OK, "ready\n".into())
+        );
+
+        clock.advance(DAY * 365);
+        let (status, body) = readyz(state.clone()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body, "signing certificate has expired\n");
+
+        clock.set(start - DAY);
+        let (status, body) = readyz(state).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body, "signing certificate is not yet valid\n");
+    }
+
+    #[cfg(all(feature = "collateral", not(target_os = "wasi")))]
+    #[tokio::test]
+    async fn collateral() {
+        use super::super::collateral::{Collateral, Settings};
+
+        let settings = Settings {
+            retries: 0,
+            threshold: 1,
+            ..Default::default()
+        };
+        let collateral = Collateral::new("http://127.0.0.1:9", settings).unwrap();
+        let state = State::generate(None, "localhost")
+            .unwrap()
+            .with_collateral(collateral);
+
+        // Nothing has been fetched yet, but nothing has failed either.
+        assert_eq!(readyz(state.clone()).await.0, StatusCode::OK);
+
+        // Once the upstream is known to be down, there is nothing to serve.
+        let collateral = state.collateral.clone().unwrap();
+        assert!(collateral.prefetch().await.is_err());
+        let (status, body) = readyz(state).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body, "no collateral: intel pcs is unavailable\n");
+    }
+}
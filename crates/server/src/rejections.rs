@@ -0,0 +1,208 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! The most recent rejections of enrollments, at `GET /admin/rejections`.
+//!
+//! When a fleet rollout fails to enroll, operators need to know why without
+//! trawling the logs of every replica. Each replica keeps the reasons for its
+//! last few rejections, with the platform and claimed measurement of the
+//! evidence where it got that far, and the request ID to find the rest of
+//! the story in the logs by. They are listed newest first.
+
+use super::{admin, correlation, State};
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::extract::{Extension, TypedHeader};
+use axum::headers::authorization::{Authorization, Bearer};
+use axum::Json;
+use hyper::StatusCode;
+use serde::Serialize;
+
+/// How many rejections are kept, unless configured otherwise.
+pub const DEFAULT_KEPT: usize = 100;
+
+/// Why an enrollment was refused.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Rejection {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+
+    /// The HTTP status the client was answered with.
+    pub status: u16,
+
+    pub reason: String,
+
+    /// The platform whose evidence was refused.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+
+    /// The hex measurement the evidence claims, which may not be genuine.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub measurement: Option<String>,
+}
+
+impl Rejection {
+    /// A rejection of the request being handled.
+    pub fn new(now: SystemTime, status: StatusCode, reason: impl ToString) -> Self {
+        Self {
+            timestamp: now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            request_id: correlation::current(),
+            status: status.as_u16(),
+            reason: reason.to_string(),
+            platform: None,
+            measurement: None,
+        }
+    }
+
+    /// Names the platform whose evidence was refused, and what it measured.
+    pub fn with_platform(mut self, platform: &str, measurement: Option<String>) -> Self {
+        self.platform = Some(platform.into());
+        self.measurement = measurement;
+        self
+    }
+}
+
+/// A ring buffer of the latest rejections.
+#[derive(Debug)]
+pub struct Rejections {
+    kept: usize,
+    ring: Mutex<VecDeque<Rejection>>,
+}
+
+impl Default for Rejections {
+    fn default() -> Self {
+        Self::new(DEFAULT_KEPT)
+    }
+}
+
+impl Rejections {
+    /// Keeps the last `kept` rejections.
+    pub fn new(kept: usize) -> Self {
+        Self {
+            kept,
+            ring: Mutex::new(VecDeque::with_capacity(kept)),
+        }
+    }
+
+    /// Keeps `rejection`, forgetting the oldest if there are too many.
+    pub fn push(&self, rejection: Rejection) {
+        if self.kept == 0 {
+            return;
+        }
+
+        let mut ring = self.ring.lock().unwrap();
+        if ring.len() == self.kept {
+            ring.pop_front();
+        }
+        ring.push_back(rejection);
+    }
+
+    /// The rejections kept, newest first.
+    pub fn recent(&self) -> Vec<Rejection> {
+        self.ring.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+/// Lists the latest rejections.
+pub async fn rejections(
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Json<Vec<Rejection>>, StatusCode> {
+    admin::authorize(&state, auth, true).await?;
+    Ok(Json(state.rejections.recent()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::admin::Auditor;
+    use super::super::{app, PKCS10};
+    use super::*;
+
+    use std::time::Duration;
+
+    use http::header::{AUTHORIZATION, CONTENT_TYPE};
+    use http::Request;
+    use hyper::Body;
+    use tower::ServiceExt; // for `app.oneshot()`
+
+    #[test]
+    fn ring() {
+        let rejections = Rejections::new(2);
+        for n in 1..=3 {
+            let now = UNIX_EPOCH + Duration::from_secs(n);
+            rejections.push(Rejection::new(now, StatusCode::BAD_REQUEST, n));
+        }
+        let reasons: Vec<_> = rejections.recent().into_iter().map(|r| r.reason).collect();
+        assert_eq!(reasons, ["3", "2"]);
+
+        let none = Rejections::new(0);
+        none.push(Rejection::new(UNIX_EPOCH, StatusCode::BAD_REQUEST, "x"));
+        assert!(none.recent().is_empty());
+    }
+
+    #[tokio::test]
+    async fn listed() {
+        let state = State::generate(None, "localhost")
+            .unwrap()
+            .with_auditor(Auditor::new("auditor").unwrap());
+
+        let request = Request::post("/")
+            .header(CONTENT_TYPE, PKCS10)
+            .header(correlation::REQUEST_ID_HEADER, "rollout-1")
+            .body(Body::from(&b"garbage"[..]))
+            .unwrap();
+        let rsp = app(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(rsp.status(), StatusCode::BAD_REQUEST);
+
+        let list = |token: Option<&'static str>| {
+            let mut request = Request::get("/admin/rejections");
+            if let Some(token) = token {
+                request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+            }
+            app(state.clone()).oneshot(request.body(Body::empty()).unwrap())
+        };
+        assert_eq!(list(None).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+
+        let rsp = list(Some("auditor")).await.unwrap();
+        assert_eq!(rsp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(rsp.into_body()).await.unwrap();
+        let listed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let listed = listed.as_array().unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0]["status"], 400);
+        assert_eq!(listed[0]["request_id"], "rollout-1");
+        assert!(listed[0]["reason"]
+            .as_str()
+            .unwrap()
+            .starts_with("malformed request"));
+    }
+
+    #[cfg(feature = "sgx")]
+    #[tokio::test]
+    async fn measured() {
+        const ICELAKE: &[u8] = include_bytes!("../../attestation/src/sgx/icelake.signed.crl.csr");
+        const SIGNER: &str =
+            r#"signer = ["2eba0f494f428e799c22d6f12778aebea4dc8d991f9e63fd3cddd57ac6eb5dd9"]"#;
+
+        let mut state = State::generate(None, "localhost").unwrap();
+        state.config_mut().sgx = Some(toml::from_str(SIGNER).unwrap());
+        let request = Request::post("/")
+            .header(CONTENT_TYPE, PKCS10)
+            .body(Body::from(ICELAKE))
+            .unwrap();
+        let rsp = app(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(rsp.status(), StatusCode::BAD_REQUEST);
+
+        // The enclave which was refused is named, to compare with the policy.
+        let rejection = state.rejections.recent().remove(0);
+        assert_eq!(rejection.platform.as_deref(), Some("sgx"));
+        assert_eq!(rejection.measurement.map(|m| m.len()), Some(64));
+        assert!(rejection.reason.starts_with("sgx evidence rejected"));
+    }
+}
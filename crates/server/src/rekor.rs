@@ -0,0 +1,124 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Publication of issued certificates to a Sigstore Rekor log.
+//!
+//! Each certificate is submitted as a `hashedrekord` entry: its digest,
+//! signed by the CA key and accompanied by the CA certificate. Submission
+//! happens in the background so that Rekor outages never block issuance;
+//! unpublished certificates are simply retried on the next run.
+
+use super::scheduler::Scheduler;
+use super::State;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use attestation::crypto::PrivateKeyInfoExt;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use const_oid::db::rfc5912::{ECDSA_WITH_SHA_256, ECDSA_WITH_SHA_384, ECDSA_WITH_SHA_512};
+use der::pem::{self, LineEnding};
+use der::Decode;
+use sec1::pkcs8::PrivateKeyInfo;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use tracing::info;
+
+const BATCH: usize = 100;
+
+#[derive(Deserialize)]
+struct LogEntry {
+    #[serde(rename = "logIndex")]
+    log_index: u64,
+}
+
+/// A Rekor log client.
+#[derive(Clone, Debug)]
+pub struct Rekor {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl Rekor {
+    /// Creates a client for the Rekor instance at `url`.
+    pub fn new(url: &str) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()?;
+
+        Ok(Self {
+            url: url.trim_end_matches('/').into(),
+            client,
+        })
+    }
+
+    /// Submits a certificate, returning its log index.
+    pub async fn submit(&self, ca: &[u8], key: &[u8], crt: &[u8]) -> Result<u64> {
+        let pki = PrivateKeyInfo::from_der(key)?;
+        let algo = pki.signs_with()?;
+        let (name, digest) = match algo.oid {
+            ECDSA_WITH_SHA_256 => ("sha256", hex::encode(Sha256::digest(crt))),
+            ECDSA_WITH_SHA_384 => ("sha384", hex::encode(Sha384::digest(crt))),
+            ECDSA_WITH_SHA_512 => ("sha512", hex::encode(Sha512::digest(crt))),
+            _ => bail!("rekor requires an ecdsa signing key"),
+        };
+        let signature = pki.sign(crt, algo)?;
+        let ca = pem::encode_string("CERTIFICATE", LineEnding::LF, ca)?;
+
+        let entry = json!({
+            "apiVersion": "0.0.1",
+            "kind": "hashedrekord",
+            "spec": {
+                "signature": {
+                    "content": BASE64.encode(signature),
+                    "publicKey": { "content": BASE64.encode(ca) },
+                },
+                "data": {
+                    "hash": { "algorithm": name, "value": digest },
+                },
+            },
+        });
+
+        let rsp = self
+            .client
+            .post(format!("{}/api/v1/log/entries", self.url))
+            .json(&entry)
+            .send()
+            .await
+            .context("failed to reach rekor")?;
+        ensure!(rsp.status().is_success(), "rekor returned {}", rsp.status());
+
+        let entries: HashMap<String, LogEntry> = rsp.json().await?;
+        match entries.into_values().next() {
+            Some(entry) => Ok(entry.log_index),
+            None => bail!("empty rekor response"),
+        }
+    }
+
+    /// Adds a task publishing the state's issued certificates.
+    pub fn schedule(self, state: &State, scheduler: Scheduler) -> Scheduler {
+        let state = state.clone();
+        scheduler.every(
+            "rekor",
+            Duration::from_secs(30),
+            Duration::from_secs(5),
+            move || {
+                let (rekor, state) = (self.clone(), state.clone());
+                async move {
+                    for issued in state.store.unpublished(BATCH).await? {
+                        let index = rekor.submit(&state.crt, &state.key, &issued.der).await?;
+                        state.store.published(&issued.serial, index).await?;
+                        info!(
+                            "published {} to rekor at {index}",
+                            hex::encode(&issued.serial)
+                        );
+                    }
+                    Ok(())
+                }
+            },
+        )
+    }
+}
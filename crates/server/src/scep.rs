@@ -0,0 +1,916 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Enrollment of legacy devices over SCEP (RFC 8894) at `/scep`.
+//!
+//! Network gear and MDM stacks cannot produce evidence, so they may only
+//! enroll as one of the device classes for which the policy waives
+//! attestation. Each class has its own challenge password, which devices
+//! send in the `challengePassword` attribute of their requests:
+//!
+//! ```toml
+//! [scep.classes.switches]
+//! challenge = "<hex SHA-256 digest of the password>"
+//! ```
+//!
+//! Their certificates are issued as if appraised on platform `scep`, with
+//! the hex of the class name as measurement `class`, so that validity rules
+//! may give each class its own profile. They claim `enrollment` by `scep`
+//! and their `scep.class`, so that relying parties can tell them from
+//! certificates issued on evidence.
+//!
+//! SCEP messages are CMS, signed by and encrypted for a registration agent
+//! with an RSA key, which the CA must have certified. Only `GetCACaps`,
+//! `GetCACert` and initial enrollment (`PKCSReq`) POSTed to `PKIOperation`
+//! are supported, with SHA-256 and AES-CBC.
+
+use super::asn1::{
+    attribute, constructed, content_info, content_of, elements, issuer_and_serial, oid, primitive,
+    sequence, set, tag, tlv, ID_CONTENT_TYPE, ID_DATA, ID_MESSAGE_DIGEST, ID_SIGNED_DATA, INTEGER,
+    OCTET_STRING, PRINTABLE_STRING, SEQUENCE, SET,
+};
+use super::attributes::CHALLENGE_PASSWORD;
+use super::key::Key;
+use super::verifier::Appraisal;
+use super::{issue, normalized, sans, State};
+
+use std::collections::BTreeMap;
+use std::io::BufRead;
+use std::sync::Arc;
+
+use aes_gcm::aes::cipher::{Block, BlockDecrypt, BlockEncrypt, KeyInit};
+use aes_gcm::aes::{Aes128, Aes256};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use attestation::crypto::{
+    CertReqExt, PrivateKeyInfoExt, SubjectPublicKeyInfoExt, TbsCertificateExt,
+};
+use attestation::parse;
+use axum::body::Bytes;
+use axum::extract::Extension;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{Method, Uri};
+use axum::response::{IntoResponse, Response};
+use const_oid::db::rfc5912::{ID_SHA_256, RSA_ENCRYPTION, SHA_256_WITH_RSA_ENCRYPTION};
+use const_oid::ObjectIdentifier;
+use der::asn1::{AnyRef, OctetStringRef};
+use der::{Decode, Encode};
+use hyper::StatusCode;
+use rand::RngCore;
+use rsa::pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey};
+use rsa::PublicKey;
+use sec1::pkcs8::PrivateKeyInfo;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use spki::AlgorithmIdentifier;
+use tracing::debug;
+use x509::Certificate;
+use zeroize::Zeroizing;
+
+/// The CA and agent certificates, as returned by `GetCACert`.
+pub const CA_RA_CERT: &str = "application/x-x509-ca-ra-cert";
+
+pub const PKI_MESSAGE: &str = "application/x-pki-message";
+
+/// What steward supports beyond the basics (RFC 8894, section 3.5.2).
+const CAPABILITIES: &str = "POSTPKIOperation\nSHA-256\nAES\nSCEPStandard\n";
+
+const ID_ENVELOPED_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.3");
+const ID_AES_128_CBC: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.1.2");
+const ID_AES_256_CBC: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.1.42");
+
+// The attributes of SCEP messages (RFC 8894, section 3.2.1).
+const MESSAGE_TYPE: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.113733.1.9.2");
+const PKI_STATUS: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.113733.1.9.3");
+const FAIL_INFO: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.113733.1.9.4");
+const SENDER_NONCE: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.113733.1.9.5");
+const RECIPIENT_NONCE: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.113733.1.9.6");
+const TRANSACTION_ID: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.113733.1.9.7");
+
+const CERT_REP: &[u8] = b"3";
+const PKCS_REQ: &[u8] = b"19";
+
+const SUCCESS: &[u8] = b"0";
+const FAILURE: &[u8] = b"2";
+
+/// The platform as which the class of a device is appraised.
+pub const PLATFORM: &str = "scep";
+
+/// The claims about a device enrolled as the class appraised.
+pub fn claims(appraisal: &Appraisal) -> Result<Vec<(String, String)>> {
+    let class = match appraisal.measurements.get("class") {
+        Some(hex) => String::from_utf8(hex::decode(hex)?)?,
+        None => return Ok(vec![]),
+    };
+
+    Ok(vec![
+        ("enrollment".into(), "scep".into()),
+        ("scep.class".into(), class),
+    ])
+}
+
+/// The device classes which may enroll without attestation.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    pub classes: BTreeMap<String, Class>,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Class {
+    /// The hex SHA-256 digest of the class's challenge password.
+    pub challenge: String,
+}
+
+impl Policy {
+    pub fn validate(&self) -> Result<()> {
+        let mut seen = Vec::new();
+        for (name, class) in &self.classes {
+            let digest = hex::decode(&class.challenge).unwrap_or_default();
+            ensure!(
+                digest.len() == 32,
+                "class `{name}` must have a hex SHA-256 digest as challenge"
+            );
+            ensure!(
+                !seen.contains(&digest),
+                "class `{name}` shares its challenge with another class"
+            );
+            seen.push(digest);
+        }
+
+        Ok(())
+    }
+
+    /// The class whose challenge password is `password`, if any.
+    pub fn class(&self, password: &[u8]) -> Option<&str> {
+        let digest = Sha256::digest(password);
+        self.classes
+            .iter()
+            .find(|(_, class)| hex::decode(&class.challenge).map_or(false, |c| c[..] == digest[..]))
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+/// The registration agent which SCEP messages are signed by and encrypted
+/// for.
+#[derive(Clone, Debug)]
+pub struct Agent {
+    key: Key,
+    crt: Vec<u8>,
+}
+
+impl Agent {
+    /// Reads a PEM key and certificate, checking that the certificate was
+    /// issued by `ca` and is for the key.
+    pub fn read(ca: &[u8], key: impl BufRead, mut crt: impl BufRead) -> Result<Self> {
+        let key = Key::read(key)?;
+        let crt = match rustls_pemfile::read_one(&mut crt)? {
+            Some(rustls_pemfile::Item::X509Certificate(buf)) => buf,
+            _ => return Err(anyhow!("invalid scep agent certificate")),
+        };
+
+        Self::new(ca, key, crt)
+    }
+
+    pub fn new(ca: &[u8], key: Key, crt: Vec<u8>) -> Result<Self> {
+        let ca = Certificate::from_der(ca)?;
+        let agent = Certificate::from_der(&crt)?;
+        let tbs = ca
+            .tbs_certificate
+            .verify_crt(&agent)
+            .context("the scep agent was not issued by the ca")?;
+
+        // Clients encrypt for the agent with RSA only.
+        let pki = PrivateKeyInfo::from_der(&key)?;
+        ensure!(
+            pki.algorithm.oid == RSA_ENCRYPTION,
+            "the scep agent key must be an rsa key"
+        );
+        ensure!(
+            pki.public_key()? == tbs.subject_public_key_info,
+            "the scep agent key does not match its certificate"
+        );
+
+        Ok(Self { key, crt })
+    }
+
+    /// The certificate of the agent.
+    pub fn crt(&self) -> &[u8] {
+        &self.crt
+    }
+}
+
+/// Why a request was refused, as told to the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Failure {
+    BadMessageCheck = 1,
+    BadRequest = 2,
+}
+
+impl Failure {
+    fn name(self) -> &'static str {
+        match self {
+            Self::BadMessageCheck => "badMessageCheck",
+            Self::BadRequest => "badRequest",
+        }
+    }
+}
+
+/// Encodes the certs-only `SignedData` with which certificates are returned.
+fn certs_only(crts: &[&[u8]]) -> Result<Vec<u8>> {
+    let signed = sequence(&[
+        &tlv(INTEGER, &[1]),
+        &set(vec![]),
+        &sequence(&[&oid(ID_DATA)?]),
+        &tlv(constructed(0), &crts.concat()),
+        &set(vec![]),
+    ]);
+    content_info(ID_SIGNED_DATA, &signed)
+}
+
+/// Signs `content`, if any, and `attributes` as `SignedData`.
+///
+/// The content type and message digest attributes are added.
+fn sign(
+    key: &[u8],
+    crt: &[u8],
+    content: Option<&[u8]>,
+    mut attributes: Vec<Vec<u8>>,
+) -> Result<Vec<u8>> {
+    let pki = PrivateKeyInfo::from_der(key)?;
+    let signer = Certificate::from_der(crt)?;
+
+    let digest = Sha256::digest(content.unwrap_or_default());
+    attributes.push(attribute(ID_CONTENT_TYPE, &oid(ID_DATA)?)?);
+    attributes.push(attribute(ID_MESSAGE_DIGEST, &tlv(OCTET_STRING, &digest))?);
+
+    // The signature is over the attributes, encoded as a SET OF.
+    let signed = set(attributes);
+    let algorithm = AlgorithmIdentifier {
+        oid: SHA_256_WITH_RSA_ENCRYPTION,
+        parameters: Some(AnyRef::NULL),
+    };
+    let signature = pki.sign(&signed, algorithm)?;
+    let signed = AnyRef::from_der(&signed)?.value();
+
+    let sha256 = sequence(&[&oid(ID_SHA_256)?]);
+    let signer_info = sequence(&[
+        &tlv(INTEGER, &[1]),
+        &issuer_and_serial(&signer)?,
+        &sha256,
+        &tlv(constructed(0), signed),
+        &algorithm.to_vec()?,
+        &tlv(OCTET_STRING, &signature),
+    ]);
+
+    let encapsulated = match content {
+        Some(content) => sequence(&[
+            &oid(ID_DATA)?,
+            &tlv(constructed(0), &tlv(OCTET_STRING, content)),
+        ]),
+        None => sequence(&[&oid(ID_DATA)?]),
+    };
+
+    let signed = sequence(&[
+        &tlv(INTEGER, &[1]),
+        &set(vec![sha256]),
+        &encapsulated,
+        &tlv(constructed(0), crt),
+        &set(vec![signer_info]),
+    ]);
+    content_info(ID_SIGNED_DATA, &signed)
+}
+
+/// A SCEP message whose signature has been verified.
+struct Message<'a> {
+    signer: Certificate<'a>,
+    attributes: BTreeMap<ObjectIdentifier, AnyRef<'a>>,
+    content: Option<&'a [u8]>,
+}
+
+impl<'a> Message<'a> {
+    /// Decodes `SignedData` with a single signer, verifying the signature.
+    fn decode(der: &'a [u8]) -> Result<Self> {
+        let signed = content_of(der, ID_SIGNED_DATA)?;
+        let (encapsulated, crts, signers) = match &elements(signed.value())?[..] {
+            [_, _, encapsulated, crts, signers] if tag(crts) == constructed(0) => {
+                (*encapsulated, *crts, *signers)
+            }
+            _ => bail!("signed data without certificates"),
+        };
+
+        let content = match &elements(encapsulated.value())?[..] {
+            [_] => None,
+            [_, content] if tag(content) == constructed(0) => {
+                let content = OctetStringRef::from_der(content.value())?;
+                Some(content.as_bytes())
+            }
+            _ => bail!("invalid encapsulated content"),
+        };
+
+        let signer = match &elements(signers.value())?[..] {
+            [signer] => *signer,
+            _ => bail!("expected exactly one signer"),
+        };
+        let (sid, digest, signed, algorithm, signature) = match &elements(signer.value())?[..] {
+            [_, sid, digest, signed, algorithm, signature, ..] if tag(signed) == constructed(0) => {
+                (*sid, *digest, *signed, *algorithm, *signature)
+            }
+            _ => bail!("signer without signed attributes"),
+        };
+
+        let digest = digest.decode_into::<AlgorithmIdentifier<'_>>()?;
+        ensure!(
+            digest.oid == ID_SHA_256,
+            "unsupported digest {}",
+            digest.oid
+        );
+
+        // Find the signer's certificate among those sent.
+        let sid = sid.to_vec()?;
+        let mut signer = None;
+        for crt in elements(crts.value())? {
+            let crt = crt.decode_into::<Certificate<'a>>()?;
+            if issuer_and_serial(&crt)? == sid {
+                signer = Some(crt);
+            }
+        }
+        let signer = signer.ok_or_else(|| anyhow!("missing signer certificate"))?;
+
+        // Many clients name the key's algorithm instead of the signature's.
+        let mut algorithm = algorithm.decode_into::<AlgorithmIdentifier<'_>>()?;
+        if algorithm.oid == RSA_ENCRYPTION {
+            algorithm.oid = SHA_256_WITH_RSA_ENCRYPTION;
+        }
+        let signature = signature.decode_into::<OctetStringRef<'_>>()?;
+        signer.tbs_certificate.subject_public_key_info.verify(
+            &tlv(SET, signed.value()),
+            algorithm,
+            signature.as_bytes(),
+        )?;
+
+        let mut attributes = BTreeMap::new();
+        for attr in elements(signed.value())? {
+            match &elements(attr.value())?[..] {
+                [id, values] => match &elements(values.value())?[..] {
+                    [value] => attributes.insert(id.decode_into()?, *value),
+                    _ => bail!("attribute without a single value"),
+                },
+                _ => bail!("invalid attribute"),
+            };
+        }
+
+        let message = Self {
+            signer,
+            attributes,
+            content,
+        };
+        let digest = message.attribute(ID_MESSAGE_DIGEST)?;
+        let digest = digest.decode_into::<OctetStringRef<'_>>()?;
+        ensure!(
+            digest.as_bytes() == &Sha256::digest(content.unwrap_or_default())[..],
+            "message digest mismatch"
+        );
+
+        Ok(message)
+    }
+
+    /// Returns the value of the signed attribute `id`.
+    fn attribute(&self, id: ObjectIdentifier) -> Result<AnyRef<'a>> {
+        self.attributes
+            .get(&id)
+            .copied()
+            .ok_or_else(|| anyhow!("missing attribute {id}"))
+    }
+}
+
+/// Decrypts AES-CBC `data` and removes its PKCS #7 padding.
+fn decrypt<C: BlockDecrypt + KeyInit>(
+    key: &[u8],
+    iv: &[u8],
+    data: &[u8],
+) -> Result<Zeroizing<Vec<u8>>> {
+    let cipher = C::new_from_slice(key).map_err(|_| anyhow!("invalid content key"))?;
+    ensure!(
+        iv.len() == 16 && !data.is_empty() && data.len() % 16 == 0,
+        "invalid encrypted content"
+    );
+
+    let mut plain = Zeroizing::new(Vec::with_capacity(data.len()));
+    let mut previous = iv;
+    for chunk in data.chunks(16) {
+        let mut block = Block::<C>::clone_from_slice(chunk);
+        cipher.decrypt_block(&mut block);
+        plain.extend(block.iter().zip(previous).map(|(b, p)| b ^ p));
+        previous = chunk;
+    }
+
+    let pad = usize::from(plain[plain.len() - 1]);
+    let len = plain.len().saturating_sub(pad);
+    ensure!(
+        (1..=16).contains(&pad) && plain[len..].iter().all(|b| usize::from(*b) == pad),
+        "invalid padding"
+    );
+    plain.truncate(len);
+    Ok(plain)
+}
+
+/// Pads `data` as PKCS #7 does and encrypts it with AES-CBC.
+fn encrypt<C: BlockEncrypt + KeyInit>(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let cipher = C::new_from_slice(key).map_err(|_| anyhow!("invalid content key"))?;
+    let pad = 16 - data.len() % 16;
+    let mut padded = Zeroizing::new(data.to_vec());
+    padded.resize(data.len() + pad, pad as u8);
+
+    let mut encrypted = Vec::with_capacity(padded.len());
+    let mut previous = iv.to_vec();
+    for chunk in padded.chunks(16) {
+        let mut block = Block::<C>::clone_from_slice(chunk);
+        for (b, p) in block.iter_mut().zip(&previous) {
+            *b ^= p;
+        }
+        cipher.encrypt_block(&mut block);
+        encrypted.extend_from_slice(&block);
+        previous = block.to_vec();
+    }
+    Ok(encrypted)
+}
+
+/// Encrypts `content` for the holder of `recipient` as `EnvelopedData`.
+fn seal(recipient: &Certificate<'_>, content: &[u8]) -> Result<Vec<u8>> {
+    let spki = &recipient.tbs_certificate.subject_public_key_info;
+    ensure!(
+        spki.algorithm.oid == RSA_ENCRYPTION,
+        "can only encrypt for rsa keys"
+    );
+    let public = rsa::RsaPublicKey::from_pkcs1_der(spki.subject_public_key)?;
+
+    let mut rng = rand::thread_rng();
+    let mut key = Zeroizing::new([0u8; 16]);
+    let mut iv = [0u8; 16];
+    rng.fill_bytes(&mut key[..]);
+    rng.fill_bytes(&mut iv);
+    let padding = rsa::PaddingScheme::new_pkcs1v15_encrypt();
+    let encrypted_key = public.encrypt(&mut rng, padding, &key[..])?;
+
+    let recipient_info = sequence(&[
+        &tlv(INTEGER, &[0]),
+        &issuer_and_serial(recipient)?,
+        &sequence(&[&oid(RSA_ENCRYPTION)?, &AnyRef::NULL.to_vec()?]),
+        &tlv(OCTET_STRING, &encrypted_key),
+    ]);
+    let encrypted = sequence(&[
+        &oid(ID_DATA)?,
+        &sequence(&[&oid(ID_AES_128_CBC)?, &tlv(OCTET_STRING, &iv)]),
+        &tlv(primitive(0), &encrypt::<Aes128>(&key[..], &iv, content)?),
+    ]);
+
+    let enveloped = sequence(&[&tlv(INTEGER, &[0]), &set(vec![recipient_info]), &encrypted]);
+    content_info(ID_ENVELOPED_DATA, &enveloped)
+}
+
+/// Decrypts `EnvelopedData` for the holder of `key` and `crt`.
+fn open(key: &[u8], crt: &[u8], der: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+    let enveloped = content_of(der, ID_ENVELOPED_DATA)?;
+
+    // Skip the version and any originator information.
+    let fields: Vec<_> = elements(enveloped.value())?
+        .into_iter()
+        .skip(1)
+        .filter(|field| tag(field) != constructed(0))
+        .collect();
+    let (recipients, encrypted) = match &fields[..] {
+        [recipients, encrypted, ..] => (*recipients, *encrypted),
+        _ => bail!("invalid enveloped data"),
+    };
+
+    // Unwrap the content key sent to us with RSA.
+    let us = issuer_and_serial(&Certificate::from_der(crt)?)?;
+    let mut content_key = None;
+    for recipient in elements(recipients.value())? {
+        if tag(&recipient) != SEQUENCE {
+            continue;
+        }
+        if let [_, rid, algorithm, encrypted_key] = &elements(recipient.value())?[..] {
+            if rid.to_vec()? != us {
+                continue;
+            }
+
+            let algorithm = algorithm.decode_into::<AlgorithmIdentifier<'_>>()?;
+            ensure!(
+                algorithm.oid == RSA_ENCRYPTION,
+                "unsupported key transport {}",
+                algorithm.oid
+            );
+            let pki = PrivateKeyInfo::from_der(key)?;
+            let private = rsa::RsaPrivateKey::from_pkcs1_der(pki.private_key)?;
+            let encrypted_key = encrypted_key.decode_into::<OctetStringRef<'_>>()?;
+            let padding = rsa::PaddingScheme::new_pkcs1v15_encrypt();
+            content_key = Some(Zeroizing::new(
+                private.decrypt(padding, encrypted_key.as_bytes())?,
+            ));
+        }
+    }
+    let content_key = content_key.ok_or_else(|| anyhow!("not encrypted for the agent"))?;
+
+    let (algorithm, data) = match &elements(encrypted.value())?[..] {
+        [_, algorithm, data] if tag(data) == primitive(0) => (
+            algorithm.decode_into::<AlgorithmIdentifier<'_>>()?,
+            data.value(),
+        ),
+        _ => bail!("invalid encrypted content"),
+    };
+    let iv = algorithm
+        .parameters
+        .ok_or_else(|| anyhow!("missing iv"))?
+        .decode_into::<OctetStringRef<'_>>()?;
+    match algorithm.oid {
+        ID_AES_128_CBC => decrypt::<Aes128>(&content_key, iv.as_bytes(), data),
+        ID_AES_256_CBC => decrypt::<Aes256>(&content_key, iv.as_bytes(), data),
+        oid => bail!("unsupported content encryption {oid}"),
+    }
+}
+
+fn internal(e: anyhow::Error) -> StatusCode {
+    debug!("scep failure: {e}");
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+/// Handles a `PKIOperation`, answering with a signed `CertRep`.
+async fn operate(state: &State, agent: &Agent, body: &[u8]) -> Result<Vec<u8>, StatusCode> {
+    let message = Message::decode(body).map_err(|e| {
+        debug!("invalid scep message: {e}");
+        StatusCode::BAD_REQUEST
+    })?;
+
+    // The reply echoes the transaction ID and the client's nonce.
+    let echoed = [TRANSACTION_ID, SENDER_NONCE].map(|id| message.attribute(id));
+    let (transaction, nonce) = match echoed {
+        [Ok(transaction), Ok(nonce)] => (transaction, nonce),
+        _ => {
+            debug!("scep message without transaction id or nonce");
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    };
+    let transaction = transaction
+        .to_vec()
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+    let nonce = nonce.to_vec().or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    // Enroll under the same policy throughout, whatever reloads meanwhile.
+    let policy = state.policy();
+    let csr = match message.attribute(MESSAGE_TYPE) {
+        Ok(kind) if kind.value() == PKCS_REQ => {
+            let envelope = message.content.unwrap_or_default();
+            open(&agent.key, &agent.crt, envelope).map_err(|e| {
+                debug!("failed to decrypt scep request: {e}");
+                Failure::BadMessageCheck
+            })
+        }
+        _ => {
+            debug!("unsupported scep message type");
+            Err(Failure::BadRequest)
+        }
+    };
+    let csr = csr.and_then(|csr| {
+        normalized(policy.config.parsing, &csr).map_err(|e| {
+            debug!("failed to decode scep certification request: {e}");
+            Failure::BadRequest
+        })
+    });
+    let checked = csr.as_ref().map_err(|f| *f).and_then(|csr| {
+        let cr = parse::cert_req(csr).map_err(|e| {
+            debug!("failed to decode scep certification request: {e}");
+            Failure::BadRequest
+        })?;
+        let info = cr.verify().map_err(|e| {
+            debug!("failed to verify scep certification request: {e}");
+            Failure::BadMessageCheck
+        })?;
+
+        // Only the holder of the key may ask for it to be certified.
+        if info.public_key != message.signer.tbs_certificate.subject_public_key_info {
+            debug!("scep request signed with another key");
+            return Err(Failure::BadMessageCheck);
+        }
+
+        let password = info
+            .attributes
+            .iter()
+            .find(|attr| attr.oid == CHALLENGE_PASSWORD)
+            .and_then(|attr| attr.values.iter().next())
+            .map(|value| value.value());
+        let class = password.and_then(|p| policy.config.scep.class(p));
+        match class {
+            Some(class) => Ok((info, class)),
+            None => {
+                debug!("scep request without a known challenge password");
+                Err(Failure::BadRequest)
+            }
+        }
+    });
+
+    let outcome = match checked {
+        Ok((info, class)) => {
+            let issuer =
+                Certificate::from_der(&state.crt).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+            let pki =
+                PrivateKeyInfo::from_der(&state.key).or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+            let request = match (&state.archive, &csr) {
+                (Some(..), Ok(csr)) => Some(csr.to_vec()),
+                _ => None,
+            };
+
+            let appraisals =
+                [Appraisal::new(PLATFORM, false).with_measurement("class", class.as_bytes())];
+            let (crt, _) = issue(
+                &issuer,
+                &pki,
+                sans(state)?,
+                info,
+                vec![],
+                &appraisals,
+                None,
+                &policy,
+                state,
+                request,
+            )
+            .await?;
+
+            let crts = certs_only(&[&crt]).map_err(internal)?;
+            Ok(seal(&message.signer, &crts).map_err(internal)?)
+        }
+        Err(failure) => {
+            state.notify(
+                "rejection",
+                json!({ "protocol": "scep", "reason": failure.name() }),
+            );
+            Err(failure)
+        }
+    };
+
+    let mut sender_nonce = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut sender_nonce);
+    let printable = |value: &[u8]| tlv(PRINTABLE_STRING, value);
+    let mut attributes = vec![
+        attribute(MESSAGE_TYPE, &printable(CERT_REP)),
+        attribute(TRANSACTION_ID, &transaction),
+        attribute(RECIPIENT_NONCE, &nonce),
+        attribute(SENDER_NONCE, &tlv(OCTET_STRING, &sender_nonce)),
+    ];
+    let content = match &outcome {
+        Ok(envelope) => {
+            attributes.push(attribute(PKI_STATUS, &printable(SUCCESS)));
+            Some(envelope.as_slice())
+        }
+        Err(failure) => {
+            let info = (*failure as u8).to_string();
+            attributes.push(attribute(PKI_STATUS, &printable(FAILURE)));
+            attributes.push(attribute(FAIL_INFO, &printable(info.as_bytes())));
+            None
+        }
+    };
+    let attributes = attributes
+        .into_iter()
+        .collect::<Result<_>>()
+        .map_err(internal)?;
+    sign(&agent.key, &agent.crt, content, attributes).map_err(internal)
+}
+
+/// Answers the SCEP operation named by the `operation` query parameter.
+pub async fn scep(
+    method: Method,
+    uri: Uri,
+    Extension(state): Extension<Arc<State>>,
+    body: Bytes,
+) -> Result<Response, StatusCode> {
+    let agent = state.scep.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    let operation = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("operation="));
+
+    match (method, operation) {
+        (Method::GET, Some("GetCACaps")) => {
+            Ok(([(CONTENT_TYPE, "text/plain")], CAPABILITIES).into_response())
+        }
+        (Method::GET, Some("GetCACert")) => {
+            let crts = certs_only(&[&state.crt, &agent.crt]).map_err(internal)?;
+            Ok(([(CONTENT_TYPE, CA_RA_CERT)], crts).into_response())
+        }
+        (Method::POST, Some("PKIOperation")) => {
+            let rep = operate(&state, agent, &body).await?;
+            Ok(([(CONTENT_TYPE, PKI_MESSAGE)], rep).into_response())
+        }
+        _ => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::claims::{self, Claim};
+    use super::*;
+
+    use std::time::{Duration, SystemTime};
+
+    use attestation::crypto::CertReqInfoExt;
+    use const_oid::db::rfc5912::SECP_256_R_1;
+    use der::asn1::{GeneralizedTime, UIntRef};
+    use x509::attr::Attribute;
+    use x509::name::RdnSequence;
+    use x509::request::{CertReqInfo, Version};
+    use x509::time::{Time, Validity};
+    use x509::TbsCertificate;
+
+    const PASSWORD: &[u8] = b"swordfish";
+    const NONCE: &[u8] = &[0x5a; 16];
+
+    /// Issues a certificate for the key of `pki` as `issuer`.
+    fn certify(
+        pki: &PrivateKeyInfo<'_>,
+        issuer: &PrivateKeyInfo<'_>,
+        issuer_name: RdnSequence<'_>,
+        serial: u8,
+    ) -> Vec<u8> {
+        let now = SystemTime::now();
+        TbsCertificate {
+            version: x509::Version::V3,
+            serial_number: UIntRef::new(&[serial]).unwrap(),
+            signature: issuer.signs_with().unwrap(),
+            issuer: issuer_name,
+            validity: Validity {
+                not_before: Time::GeneralTime(GeneralizedTime::from_system_time(now).unwrap()),
+                not_after: Time::try_from(now + Duration::from_secs(3600)).unwrap(),
+            },
+            subject: RdnSequence::default(),
+            subject_public_key_info: pki.public_key().unwrap(),
+            issuer_unique_id: None,
+            subject_unique_id: None,
+            extensions: None,
+        }
+        .sign(issuer)
+        .unwrap()
+    }
+
+    /// Issues a certificate to a fresh key of `oid` from the CA of `state`.
+    fn agent(state: &State, oid: ObjectIdentifier) -> (Key, Vec<u8>) {
+        let ca = Certificate::from_der(&state.crt).unwrap();
+        let ca_pki = PrivateKeyInfo::from_der(&state.key).unwrap();
+
+        let key = Key::try_from(PrivateKeyInfo::generate(oid).unwrap()).unwrap();
+        let pki = PrivateKeyInfo::from_der(&key).unwrap();
+        let crt = certify(&pki, &ca_pki, ca.tbs_certificate.subject.clone(), 1);
+        (key, crt)
+    }
+
+    /// A request carrying `password` in each of `copies` attributes.
+    fn csr(pki: &PrivateKeyInfo<'_>, password: &[u8], copies: usize) -> Vec<u8> {
+        let password = tlv(PRINTABLE_STRING, password);
+        let attr = Attribute {
+            oid: CHALLENGE_PASSWORD,
+            values: vec![AnyRef::from_der(&password).unwrap()]
+                .try_into()
+                .unwrap(),
+        };
+
+        CertReqInfo {
+            version: Version::V1,
+            subject: RdnSequence::default(),
+            public_key: pki.public_key().unwrap(),
+            attributes: vec![attr; copies].try_into().unwrap(),
+        }
+        .sign(pki)
+        .unwrap()
+    }
+
+    /// Wraps `csr` in a `PKCSReq` from the holder of `key` and `crt`.
+    fn pkcs_req(key: &[u8], crt: &[u8], agent: &Agent, csr: &[u8]) -> Vec<u8> {
+        let recipient = Certificate::from_der(agent.crt()).unwrap();
+        let envelope = seal(&recipient, csr).unwrap();
+        let attributes = vec![
+            attribute(MESSAGE_TYPE, &tlv(PRINTABLE_STRING, PKCS_REQ)).unwrap(),
+            attribute(TRANSACTION_ID, &tlv(PRINTABLE_STRING, b"42")).unwrap(),
+            attribute(SENDER_NONCE, &tlv(OCTET_STRING, NONCE)).unwrap(),
+        ];
+        sign(key, crt, Some(&envelope), attributes).unwrap()
+    }
+
+    #[test]
+    fn policy() {
+        let digest = hex::encode(Sha256::digest(PASSWORD));
+        let policy: Policy =
+            toml::from_str(&format!("[classes.switches]\nchallenge = \"{digest}\"")).unwrap();
+        policy.validate().unwrap();
+        assert_eq!(policy.class(PASSWORD), Some("switches"));
+        assert_eq!(policy.class(b"hunter2"), None);
+
+        let shared: Policy = toml::from_str(&format!(
+            "[classes.a]\nchallenge = \"{digest}\"\n[classes.b]\nchallenge = \"{digest}\""
+        ))
+        .unwrap();
+        assert!(shared.validate().is_err());
+
+        // Challenges are configured as digests, never as passwords.
+        let plain: Policy = toml::from_str("[classes.a]\nchallenge = \"swordfish\"").unwrap();
+        assert!(plain.validate().is_err());
+    }
+
+    #[test]
+    fn cbc() {
+        let (key, iv) = ([7; 32], [9; 16]);
+        for len in [0, 15, 16, 33] {
+            let data = vec![1; len];
+            let encrypted = encrypt::<Aes256>(&key, &iv, &data).unwrap();
+            assert_eq!(encrypted.len(), (len / 16 + 1) * 16);
+            assert_eq!(*decrypt::<Aes256>(&key, &iv, &encrypted).unwrap(), data);
+        }
+
+        // Changing the IV changes the padding of a single block.
+        let encrypted = encrypt::<Aes128>(&key[..16], &iv, b"steward").unwrap();
+        let mut changed = iv;
+        changed[15] ^= 1;
+        assert!(decrypt::<Aes128>(&key[..16], &changed, &encrypted).is_err());
+    }
+
+    #[test]
+    fn agent_key() {
+        let state = State::generate(None, "localhost").unwrap();
+
+        // The CA's own key type cannot be encrypted to.
+        let (key, crt) = agent(&state, SECP_256_R_1);
+        assert!(Agent::new(&state.crt, key, crt).is_err());
+    }
+
+    #[tokio::test]
+    async fn enroll() {
+        let mut state = State::generate(None, "localhost").unwrap();
+        let digest = hex::encode(Sha256::digest(PASSWORD));
+        state
+            .config_mut()
+            .scep
+            .classes
+            .insert("switches".into(), Class { challenge: digest });
+
+        // One RSA key plays both agent and device, as generating it is slow.
+        let (key, crt) = agent(&state, RSA_ENCRYPTION);
+        let agent = Agent::new(&state.crt, key.clone(), crt).unwrap();
+        let pki = PrivateKeyInfo::from_der(&key).unwrap();
+        let device = certify(&pki, &pki, RdnSequence::default(), 2);
+
+        let msg = pkcs_req(&key, &device, &agent, &csr(&pki, PASSWORD, 1));
+        let rep = operate(&state, &agent, &msg).await.unwrap();
+        let rep = Message::decode(&rep).unwrap();
+        assert_eq!(rep.signer.to_vec().unwrap(), agent.crt());
+        assert_eq!(rep.attribute(MESSAGE_TYPE).unwrap().value(), CERT_REP);
+        assert_eq!(rep.attribute(PKI_STATUS).unwrap().value(), SUCCESS);
+        assert_eq!(rep.attribute(RECIPIENT_NONCE).unwrap().value(), NONCE);
+        assert_eq!(rep.attribute(TRANSACTION_ID).unwrap().value(), b"42");
+
+        // The certificate is encrypted for the device, and issued by the CA.
+        let crts = open(&key, &device, rep.content.unwrap()).unwrap();
+        let signed = content_of(&crts, ID_SIGNED_DATA).unwrap();
+        let crts = elements(signed.value()).unwrap()[3];
+        let crt = elements(crts.value()).unwrap()[0]
+            .decode_into::<Certificate<'_>>()
+            .unwrap();
+        let ca = Certificate::from_der(&state.crt).unwrap();
+        ca.tbs_certificate.verify_crt(&crt).unwrap();
+        assert_eq!(
+            crt.tbs_certificate.subject_public_key_info,
+            pki.public_key().unwrap()
+        );
+
+        // It says that it was enrolled over SCEP, and as which class.
+        let mut extensions = crt.tbs_certificate.extensions.iter().flatten();
+        let claims = extensions.find(|e| e.extn_id == claims::OID).unwrap();
+        let claims = Vec::<Claim<'_>>::from_der(claims.extn_value).unwrap();
+        let claims: Vec<_> = claims
+            .iter()
+            .map(|c| (c.name.as_str(), c.value.as_str()))
+            .collect();
+        assert_eq!(claims, [("enrollment", "scep"), ("scep.class", "switches")]);
+
+        // Without the challenge password of a class, the request is refused.
+        let msg = pkcs_req(&key, &device, &agent, &csr(&pki, b"hunter2", 1));
+        let rep = operate(&state, &agent, &msg).await.unwrap();
+        let rep = Message::decode(&rep).unwrap();
+        assert_eq!(rep.attribute(PKI_STATUS).unwrap().value(), FAILURE);
+        assert_eq!(rep.attribute(FAIL_INFO).unwrap().value(), b"2");
+        assert!(rep.content.is_none());
+
+        // Requests are held to the same limits as those posted directly.
+        let msg = pkcs_req(&key, &device, &agent, &csr(&pki, PASSWORD, 17));
+        let rep = operate(&state, &agent, &msg).await.unwrap();
+        let rep = Message::decode(&rep).unwrap();
+        assert_eq!(rep.attribute(PKI_STATUS).unwrap().value(), FAILURE);
+        assert_eq!(rep.attribute(FAIL_INFO).unwrap().value(), b"2");
+
+        // Nor is anything but a signed message answered.
+        assert_eq!(
+            operate(&state, &agent, b"junk").await,
+            Err(StatusCode::BAD_REQUEST)
+        );
+    }
+}
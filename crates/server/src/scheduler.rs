@@ -0,0 +1,158 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Periodic background work.
+
+use super::metrics::{TASK_FAILURES, TASK_RUNS};
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+type Job = Arc<dyn Fn() -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>> + Send + Sync>;
+
+struct Task {
+    name: &'static str,
+    interval: Duration,
+    jitter: Duration,
+    job: Job,
+}
+
+/// A set of tasks, each run at its own interval.
+///
+/// Every run is delayed by a random amount up to the task's jitter, so that
+/// replicas started together do not all do the same work at the same time.
+#[derive(Default)]
+pub struct Scheduler {
+    tasks: Vec<Task>,
+}
+
+impl std::fmt::Debug for Scheduler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<_> = self.tasks.iter().map(|t| t.name).collect();
+        f.debug_struct("Scheduler").field("tasks", &names).finish()
+    }
+}
+
+impl Scheduler {
+    /// Adds a task which runs every `interval`, plus up to `jitter`.
+    pub fn every<F, R>(
+        mut self,
+        name: &'static str,
+        interval: Duration,
+        jitter: Duration,
+        f: F,
+    ) -> Self
+    where
+        F: Fn() -> R + Send + Sync + 'static,
+        R: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        self.tasks.push(Task {
+            name,
+            interval,
+            jitter,
+            job: Arc::new(move || Box::pin(f())),
+        });
+        self
+    }
+
+    /// Spawns all tasks; the first run of each happens after its jitter.
+    pub fn start(self) -> Running {
+        let (stop, watch) = watch::channel(());
+
+        let handles = self
+            .tasks
+            .into_iter()
+            .map(|task| {
+                let mut watch = watch.clone();
+                tokio::spawn(async move {
+                    let mut delay = Duration::ZERO;
+                    loop {
+                        if !task.jitter.is_zero() {
+                            delay += rand::thread_rng().gen_range(Duration::ZERO..=task.jitter);
+                        }
+
+                        tokio::select! {
+                            _ = tokio::time::sleep(delay) => (),
+                            _ = watch.changed() => break,
+                        }
+
+                        debug!("running task {}", task.name);
+                        TASK_RUNS.inc(task.name);
+                        if let Err(e) = (task.job)().await {
+                            TASK_FAILURES.inc(task.name);
+                            warn!("task {} failed: {e:#}", task.name);
+                        }
+
+                        delay = task.interval;
+                    }
+                })
+            })
+            .collect();
+
+        Running { stop, handles }
+    }
+}
+
+/// Handle to a started scheduler.
+#[derive(Debug)]
+pub struct Running {
+    stop: watch::Sender<()>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Running {
+    /// Stops all tasks, waiting for any in-progress runs to finish.
+    pub async fn shutdown(self) {
+        let _ = self.stop.send(());
+        for handle in self.handles {
+            let _ = handle.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test(start_paused = true)]
+    async fn runs_and_stops() {
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+        let running = Scheduler::default()
+            .every(
+                "test-ok",
+                Duration::from_secs(10),
+                Duration::ZERO,
+                || async {
+                    RUNS.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                },
+            )
+            .every(
+                "test-fail",
+                Duration::from_secs(10),
+                Duration::ZERO,
+                || async { anyhow::bail!("failure") },
+            )
+            .start();
+
+        tokio::time::sleep(Duration::from_secs(25)).await;
+        running.shutdown().await;
+        assert_eq!(RUNS.load(Ordering::SeqCst), 3);
+        assert_eq!(TASK_RUNS.get("test-ok"), 3);
+        assert_eq!(TASK_FAILURES.get("test-ok"), 0);
+        assert_eq!(TASK_FAILURES.get("test-fail"), 3);
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(RUNS.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,67 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! The source of serial numbers.
+//!
+//! Serial numbers are random (version 4) UUIDs. For golden file tests, and
+//! for differential testing against other CAs, they can instead be drawn from
+//! a seeded generator, which together with a [`Manual`](super::clock::Manual)
+//! clock makes issuance reproducible. Seeded serials are guessable, so they
+//! are never for production.
+
+use std::fmt::Debug;
+use std::sync::Mutex;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use uuid::{Builder, Uuid};
+
+/// Hands out serial numbers.
+pub trait Serials: Debug + Send + Sync {
+    fn next(&self) -> Uuid;
+}
+
+/// Serial numbers from the operating system's random number generator.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Random;
+
+impl Serials for Random {
+    fn next(&self) -> Uuid {
+        Uuid::new_v4()
+    }
+}
+
+/// Serial numbers which are the same for the same seed, every time.
+#[derive(Debug)]
+pub struct Seeded(Mutex<StdRng>);
+
+impl Seeded {
+    pub fn new(seed: u64) -> Self {
+        Self(Mutex::new(StdRng::seed_from_u64(seed)))
+    }
+}
+
+impl Serials for Seeded {
+    fn next(&self) -> Uuid {
+        let bytes = self.0.lock().unwrap().gen();
+        Builder::from_random_bytes(bytes).into_uuid()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded() {
+        let (a, b) = (Seeded::new(7), Seeded::new(7));
+        let serials: Vec<_> = (0..3).map(|_| a.next()).collect();
+        assert_eq!(serials, (0..3).map(|_| b.next()).collect::<Vec<_>>());
+        assert_ne!(serials[0], serials[1]);
+        assert_ne!(Seeded::new(8).next(), serials[0]);
+
+        // Seeded serials look like any other.
+        assert_eq!(serials[0].get_version_num(), 4);
+        assert_eq!(Random.next().get_version_num(), 4);
+    }
+}
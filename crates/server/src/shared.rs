@@ -0,0 +1,288 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! State shared between steward replicas.
+//!
+//! A single replica keeps everything in memory. A load-balanced fleet points
+//! every replica at the same backend (e.g. Redis) so that caches, replay
+//! windows and counters behave as if there were one logical CA.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+/// A key/value store with expiry.
+#[async_trait]
+pub trait Shared: Debug + Send + Sync {
+    /// Returns the value of an unexpired key.
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Stores a value which expires after `ttl`.
+    async fn put(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()>;
+
+    /// Stores a value only if the key is absent, returning whether it was stored.
+    async fn put_new(&self, key: &str, value: &[u8], ttl: Duration) -> Result<bool>;
+
+    /// Atomically increments a counter, returning the new value.
+    async fn incr(&self, key: &str) -> Result<u64>;
+
+    /// Frees expired entries, for backends which do not do so themselves.
+    async fn evict(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    expires: Option<Instant>,
+    value: Vec<u8>,
+}
+
+impl Entry {
+    fn live(&self, now: Instant) -> bool {
+        self.expires.map_or(true, |e| e > now)
+    }
+}
+
+/// An in-process backend for single replica deployments.
+#[derive(Debug)]
+pub struct Memory {
+    capacity: usize,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new(Self::CAPACITY)
+    }
+}
+
+impl Memory {
+    const CAPACITY: usize = 65536;
+
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Default::default(),
+        }
+    }
+
+    fn store(&self, key: &str, value: &[u8], ttl: Duration, replace: bool) -> bool {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        if !replace && entries.get(key).map_or(false, |e| e.live(now)) {
+            return false;
+        }
+
+        // Make room: drop expired entries first, then the soonest to expire.
+        if entries.len() >= self.capacity && !entries.contains_key(key) {
+            entries.retain(|_, e| e.live(now));
+        }
+        if entries.len() >= self.capacity && !entries.contains_key(key) {
+            let oldest = entries
+                .iter()
+                .filter_map(|(k, e)| e.expires.map(|x| (x, k)))
+                .min()
+                .map(|(_, k)| k.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+
+        let entry = Entry {
+            expires: Some(now + ttl),
+            value: value.to_vec(),
+        };
+        entries.insert(key.into(), entry);
+        true
+    }
+}
+
+#[async_trait]
+impl Shared for Memory {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let entries = self.entries.lock().unwrap();
+        Ok(entries
+            .get(key)
+            .filter(|e| e.live(Instant::now()))
+            .map(|e| e.value.clone()))
+    }
+
+    async fn put(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()> {
+        self.store(key, value, ttl, true);
+        Ok(())
+    }
+
+    async fn put_new(&self, key: &str, value: &[u8], ttl: Duration) -> Result<bool> {
+        Ok(self.store(key, value, ttl, false))
+    }
+
+    async fn incr(&self, key: &str) -> Result<u64> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(key.into()).or_insert(Entry {
+            expires: None,
+            value: Vec::new(),
+        });
+
+        let mut count = [0u8; 8];
+        if entry.live(now) && entry.value.len() == count.len() {
+            count.copy_from_slice(&entry.value);
+        }
+        let count = u64::from_le_bytes(count) + 1;
+
+        entry.expires = None;
+        entry.value = count.to_le_bytes().to_vec();
+        Ok(count)
+    }
+
+    async fn evict(&self) -> Result<()> {
+        let now = Instant::now();
+        self.entries.lock().unwrap().retain(|_, e| e.live(now));
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "redis", not(target_os = "wasi")))]
+pub use self::redis::Redis;
+
+#[cfg(all(feature = "redis", not(target_os = "wasi")))]
+mod redis {
+    use super::*;
+
+    use ::redis::aio::ConnectionManager;
+    use ::redis::{cmd, Client};
+    use anyhow::Context;
+
+    /// A Redis backend shared by all replicas.
+    #[derive(Clone)]
+    pub struct Redis {
+        conn: ConnectionManager,
+        prefix: String,
+    }
+
+    impl Debug for Redis {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("Redis")
+                .field("prefix", &self.prefix)
+                .finish_non_exhaustive()
+        }
+    }
+
+    impl Redis {
+        /// Connects to the server at `url`, namespacing all keys with `prefix`.
+        pub async fn connect(url: &str, prefix: &str) -> Result<Self> {
+            let client = Client::open(url).context("invalid redis url")?;
+            let conn = ConnectionManager::new(client)
+                .await
+                .context("failed to connect to redis")?;
+
+            Ok(Self {
+                conn,
+                prefix: prefix.into(),
+            })
+        }
+
+        fn key(&self, key: &str) -> String {
+            format!("{}{}", self.prefix, key)
+        }
+    }
+
+    #[async_trait]
+    impl Shared for Redis {
+        async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+            Ok(cmd("GET")
+                .arg(self.key(key))
+                .query_async(&mut self.conn.clone())
+                .await?)
+        }
+
+        async fn put(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()> {
+            Ok(cmd("SET")
+                .arg(self.key(key))
+                .arg(value)
+                .arg("PX")
+                .arg(ttl.as_millis().max(1) as u64)
+                .query_async(&mut self.conn.clone())
+                .await?)
+        }
+
+        async fn put_new(&self, key: &str, value: &[u8], ttl: Duration) -> Result<bool> {
+            let set: Option<String> = cmd("SET")
+                .arg(self.key(key))
+                .arg(value)
+                .arg("PX")
+                .arg(ttl.as_millis().max(1) as u64)
+                .arg("NX")
+                .query_async(&mut self.conn.clone())
+                .await?;
+            Ok(set.is_some())
+        }
+
+        async fn incr(&self, key: &str) -> Result<u64> {
+            Ok(cmd("INCR")
+                .arg(self.key(key))
+                .query_async(&mut self.conn.clone())
+                .await?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    #[tokio::test]
+    async fn put_get() {
+        let mem = Memory::default();
+        assert_eq!(mem.get("foo").await.unwrap(), None);
+
+        mem.put("foo", b"bar", TTL).await.unwrap();
+        assert_eq!(mem.get("foo").await.unwrap().as_deref(), Some(&b"bar"[..]));
+
+        mem.put("foo", b"baz", Duration::ZERO).await.unwrap();
+        assert_eq!(mem.get("foo").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn put_new() {
+        let mem = Memory::default();
+        assert!(mem.put_new("nonce", b"", TTL).await.unwrap());
+        assert!(!mem.put_new("nonce", b"", TTL).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn incr() {
+        let mem = Memory::default();
+        assert_eq!(mem.incr("serial").await.unwrap(), 1);
+        assert_eq!(mem.incr("serial").await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn capacity() {
+        let mem = Memory::new(2);
+        mem.put("a", b"", TTL).await.unwrap();
+        mem.put("b", b"", TTL * 2).await.unwrap();
+        mem.put("c", b"", TTL).await.unwrap();
+
+        assert_eq!(mem.get("a").await.unwrap(), None);
+        assert!(mem.get("b").await.unwrap().is_some());
+        assert!(mem.get("c").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn evict() {
+        let mem = Memory::default();
+        mem.put("a", b"", Duration::ZERO).await.unwrap();
+        mem.put("b", b"", TTL).await.unwrap();
+        mem.evict().await.unwrap();
+        assert_eq!(mem.entries.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,313 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Snapshot tests of the structure of issued certificates.
+//!
+//! Each test issues a certificate for a platform under a profile and checks
+//! a textual dump of it against the file of the same name in `snapshots/`.
+//! The dump leaves out whatever differs between issuances (serial numbers,
+//! times, keys and signatures), so that it changes only when what steward
+//! puts into certificates does.
+//!
+//! After an intended change, rerun the tests with `UPDATE_SNAPSHOTS=1` to
+//! rewrite the snapshots, and review the difference.
+
+use super::claims::{self, Claim};
+#[cfg(feature = "sgx")]
+use super::clock::Manual;
+use super::clock::{Clock, System};
+use super::{app, State, PKCS10};
+
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use const_oid::db::rfc5280::{
+    ID_AD_OCSP, ID_CE_EXT_KEY_USAGE, ID_CE_SUBJECT_ALT_NAME, ID_KP_CLIENT_AUTH, ID_KP_CODE_SIGNING,
+    ID_KP_SERVER_AUTH, ID_PE_AUTHORITY_INFO_ACCESS,
+};
+use const_oid::db::rfc5912::{
+    ECDSA_WITH_SHA_256, ECDSA_WITH_SHA_384, ID_EC_PUBLIC_KEY, SECP_256_R_1, SECP_384_R_1,
+};
+use const_oid::ObjectIdentifier;
+use der::Decode;
+use http::header::CONTENT_TYPE;
+use http::{Request, StatusCode};
+use hyper::Body;
+use spki::AlgorithmIdentifier;
+use tower::ServiceExt; // for `app.oneshot()`
+use x509::ext::pkix::name::GeneralName;
+use x509::ext::pkix::{AuthorityInfoAccessSyntax, ExtendedKeyUsage, SubjectAltName};
+use x509::{Certificate, PkiPath};
+
+const ID_PE_TLS_FEATURE: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.5.5.7.1.24");
+
+/// Names for the identifiers which appear in issued certificates.
+const NAMES: &[(ObjectIdentifier, &str)] = &[
+    (ECDSA_WITH_SHA_256, "ecdsa-with-SHA256"),
+    (ECDSA_WITH_SHA_384, "ecdsa-with-SHA384"),
+    (ID_EC_PUBLIC_KEY, "id-ecPublicKey"),
+    (SECP_256_R_1, "secp256r1"),
+    (SECP_384_R_1, "secp384r1"),
+    (ID_CE_SUBJECT_ALT_NAME, "subjectAltName"),
+    (ID_CE_EXT_KEY_USAGE, "extKeyUsage"),
+    (ID_PE_AUTHORITY_INFO_ACCESS, "authorityInfoAccess"),
+    (ID_PE_TLS_FEATURE, "tlsFeature"),
+    (claims::OID, "claims"),
+    (ID_KP_SERVER_AUTH, "serverAuth"),
+    (ID_KP_CLIENT_AUTH, "clientAuth"),
+    (ID_KP_CODE_SIGNING, "codeSigning"),
+    (ID_AD_OCSP, "OCSP"),
+];
+
+fn name(oid: &ObjectIdentifier) -> String {
+    match NAMES.iter().find(|(known, _)| known == oid) {
+        Some((_, name)) => name.to_string(),
+        None => oid.to_string(),
+    }
+}
+
+fn algorithm(algorithm: &AlgorithmIdentifier<'_>) -> String {
+    let parameter = algorithm
+        .parameters
+        .and_then(|any| any.decode_into::<ObjectIdentifier>().ok());
+    match parameter {
+        Some(parameter) => format!("{} {}", name(&algorithm.oid), name(&parameter)),
+        None => name(&algorithm.oid),
+    }
+}
+
+fn general_name(name: &GeneralName<'_>) -> String {
+    match name {
+        GeneralName::DnsName(dns) => format!("DNS:{}", dns.as_str()),
+        GeneralName::Rfc822Name(email) => format!("email:{}", email.as_str()),
+        GeneralName::UniformResourceIdentifier(uri) => format!("URI:{}", uri.as_str()),
+        other => format!("{other:?}"),
+    }
+}
+
+/// Renders the value of an extension, decoding those steward adds.
+fn extension(oid: ObjectIdentifier, value: &[u8]) -> String {
+    let decoded = match oid {
+        ID_CE_SUBJECT_ALT_NAME => SubjectAltName::from_der(value)
+            .map(|sans| sans.0.iter().map(general_name).collect::<Vec<_>>()),
+        ID_CE_EXT_KEY_USAGE => {
+            ExtendedKeyUsage::from_der(value).map(|eku| eku.0.iter().map(name).collect())
+        }
+        ID_PE_AUTHORITY_INFO_ACCESS => AuthorityInfoAccessSyntax::from_der(value).map(|aia| {
+            aia.0
+                .iter()
+                .map(|ad| {
+                    format!(
+                        "{} {}",
+                        name(&ad.access_method),
+                        general_name(&ad.access_location)
+                    )
+                })
+                .collect()
+        }),
+        claims::OID => Vec::<Claim<'_>>::from_der(value).map(|claims| {
+            claims
+                .iter()
+                .map(|c| format!("{}={}", c.name.as_str(), c.value.as_str()))
+                .collect()
+        }),
+        _ => return hex::encode(value),
+    };
+
+    match decoded {
+        Ok(items) => items.join(", "),
+        Err(e) => format!("undecodable ({e})"),
+    }
+}
+
+/// Dumps the parts of `crt` which are the same every time it is issued.
+fn dump(crt: &Certificate<'_>) -> String {
+    let tbs = &crt.tbs_certificate;
+    let lifetime = tbs
+        .validity
+        .not_after
+        .to_system_time()
+        .duration_since(tbs.validity.not_before.to_system_time())
+        .unwrap_or_default();
+    let subject = match tbs.subject.to_string() {
+        subject if subject.is_empty() => "(empty)".to_string(),
+        subject => subject,
+    };
+
+    let mut out = String::new();
+    writeln!(out, "version: {:?}", tbs.version).unwrap();
+    writeln!(out, "signature: {}", algorithm(&tbs.signature)).unwrap();
+    writeln!(out, "issuer: {}", tbs.issuer).unwrap();
+    writeln!(out, "validity: {}s", lifetime.as_secs()).unwrap();
+    writeln!(out, "subject: {subject}").unwrap();
+    let spki = algorithm(&tbs.subject_public_key_info.algorithm);
+    writeln!(out, "subject public key: {spki}").unwrap();
+    writeln!(out, "extensions:").unwrap();
+    for ext in tbs.extensions.iter().flatten() {
+        let critical = if ext.critical { " (critical)" } else { "" };
+        let value = extension(ext.extn_id, ext.extn_value);
+        writeln!(out, "  {}{critical}: {value}", name(&ext.extn_id)).unwrap();
+    }
+    out
+}
+
+/// Checks `actual` against the snapshot `name`, or rewrites the snapshot if
+/// `UPDATE_SNAPSHOTS` is set.
+fn assert_snapshot(name: &str, actual: &str) {
+    let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "src", "snapshots"]
+        .iter()
+        .collect::<PathBuf>()
+        .join(format!("{name}.snap"));
+    if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+        std::fs::write(&path, actual).unwrap();
+        return;
+    }
+
+    let expected = std::fs::read_to_string(&path).unwrap_or_default();
+    assert!(
+        expected == actual,
+        "snapshot {name} differs; rerun with UPDATE_SNAPSHOTS=1 to accept\n\
+         --- expected\n{expected}--- actual\n{actual}"
+    );
+}
+
+/// Issues a certificate for `cr` and returns the dump of it.
+async fn issue(state: State, cr: Vec<u8>) -> String {
+    let request = Request::post("/")
+        .header(CONTENT_TYPE, PKCS10)
+        .body(Body::from(cr))
+        .unwrap();
+    let response = app(state).oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let path = PkiPath::from_der(&body).unwrap();
+    dump(path.last().unwrap())
+}
+
+/// Returns a state issuing under the profiles of `validity`, a policy section.
+fn state(validity: &str) -> State {
+    state_with(validity, Arc::new(System))
+}
+
+/// Returns a state as [`state`] does, keeping time by `clock`.
+fn state_with(validity: &str, clock: Arc<dyn Clock>) -> State {
+    let constraints = Default::default();
+    let mut state = State::generate_with(None, "localhost", &constraints, clock).unwrap();
+    state.config_mut().validity = toml::from_str(validity).unwrap();
+    state
+}
+
+/// Returns a state as [`state`] does, at a time when the canned SGX quote's
+/// revocation lists were current.
+#[cfg(feature = "sgx")]
+fn sgx_state(validity: &str) -> State {
+    state_with(validity, Arc::new(Manual::new(super::mock::icelake_now())))
+}
+
+const STANDARD: &str = r#"
+default = "standard"
+
+[profiles.standard]
+lifetime = 86400
+backdate = 300
+extended_key_usage = ["server_auth"]
+must_staple = true
+ocsp = "http://ocsp.example.com"
+"#;
+
+#[cfg(feature = "sgx")]
+const BUILDER: &str = r#"
+[profiles.builder]
+lifetime = 3600
+extended_key_usage = ["code_signing"]
+subject_measurement = "mrenclave"
+
+[[rules]]
+platform = "sgx"
+profile = "builder"
+"#;
+
+#[cfg(feature = "kvm")]
+fn kvm() -> Vec<u8> {
+    use super::Kvm;
+    use attestation::crypto::{CertReqInfoExt, PrivateKeyInfoExt};
+    use const_oid::db::rfc5912::ID_EXTENSION_REQ;
+    use der::asn1::AnyRef;
+    use der::Encode;
+    use sec1::pkcs8::PrivateKeyInfo;
+    use x509::attr::Attribute;
+    use x509::ext::Extension;
+    use x509::name::RdnSequence;
+    use x509::request::{CertReqInfo, ExtensionReq, Version};
+
+    let key = PrivateKeyInfo::generate(SECP_256_R_1).unwrap();
+    let pki = PrivateKeyInfo::from_der(key.as_ref()).unwrap();
+    let ext = Extension {
+        extn_id: Kvm::OID,
+        critical: false,
+        extn_value: &[],
+    };
+    let req = ExtensionReq::from(vec![ext]).to_vec().unwrap();
+    let attribute = Attribute {
+        oid: ID_EXTENSION_REQ,
+        values: vec![AnyRef::from_der(&req).unwrap()].try_into().unwrap(),
+    };
+    CertReqInfo {
+        version: Version::V1,
+        subject: RdnSequence::default(),
+        public_key: pki.public_key().unwrap(),
+        attributes: vec![attribute].try_into().unwrap(),
+    }
+    .sign(&pki)
+    .unwrap()
+}
+
+#[cfg(feature = "sgx")]
+const ICELAKE: &[u8] = include_bytes!("../../attestation/src/sgx/icelake.signed.crl.csr");
+
+#[cfg(feature = "snp")]
+const MILAN: &[u8] = include_bytes!("../../attestation/src/snp/milan.signed.crl.csr");
+
+#[cfg(feature = "kvm")]
+#[tokio::test]
+async fn kvm_default() {
+    let dump = issue(state(""), kvm()).await;
+    assert_snapshot("kvm_default", &dump);
+}
+
+#[cfg(feature = "kvm")]
+#[tokio::test]
+async fn kvm_standard() {
+    let dump = issue(state(STANDARD), kvm()).await;
+    assert_snapshot("kvm_standard", &dump);
+}
+
+#[cfg(feature = "sgx")]
+#[tokio::test]
+async fn sgx_default() {
+    let dump = issue(sgx_state(""), ICELAKE.to_vec()).await;
+    assert_snapshot("sgx_default", &dump);
+}
+
+#[cfg(feature = "sgx")]
+#[tokio::test]
+async fn sgx_builder() {
+    let dump = issue(sgx_state(BUILDER), ICELAKE.to_vec()).await;
+    assert_snapshot("sgx_builder", &dump);
+}
+
+#[cfg(feature = "snp")]
+#[tokio::test]
+async fn snp_default() {
+    let dump = issue(state(""), MILAN.to_vec()).await;
+    assert_snapshot("snp_default", &dump);
+}
+
+#[cfg(feature = "snp")]
+#[tokio::test]
+async fn snp_standard() {
+    let dump = issue(state(STANDARD), MILAN.to_vec()).await;
+    assert_snapshot("snp_standard", &dump);
+}
@@ -0,0 +1,382 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! The SPIRE `UpstreamAuthority` plugin interface.
+//!
+//! A SPIRE server chains its X.509 CA to an upstream authority by sending it
+//! a certification request for the CA key. The request SPIRE generates can
+//! carry no evidence, so the caller also sends an attested request for the
+//! same key, in the [`EVIDENCE`] binary metadata entry. It is appraised as if
+//! it had been posted to steward, and only then is the CA certificate minted.
+//!
+//! The messages and service are those of
+//! `spire.plugin.server.upstreamauthority.v1`. JWT key publishing is not
+//! supported, which SPIRE tolerates.
+
+use super::store::Issued;
+use super::{appraise, debug_mode, normalized, record, stats, State};
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use attestation::crypto::{CertReqExt, PrivateKeyInfoExt, TbsCertificateExt};
+use attestation::parse;
+use const_oid::db::rfc5280::{ID_CE_BASIC_CONSTRAINTS, ID_CE_KEY_USAGE, ID_CE_SUBJECT_ALT_NAME};
+use const_oid::db::rfc5912::ID_EXTENSION_REQ;
+use der::asn1::{Ia5StringRef, UIntRef};
+use der::{Decode, Encode};
+use futures_util::stream::{self, Stream, StreamExt};
+use hyper::StatusCode;
+use sec1::pkcs8::PrivateKeyInfo;
+use tonic::codegen::{empty_body, http, BoxFuture};
+use tonic::{Request, Response, Status};
+use tracing::{debug, info};
+use x509::ext::pkix::name::GeneralName;
+use x509::ext::pkix::{BasicConstraints, KeyUsage, KeyUsages, SubjectAltName};
+use x509::request::{CertReq, ExtensionReq};
+use x509::time::{Time, Validity};
+use x509::{Certificate, TbsCertificate};
+
+/// The metadata entry carrying the DER attested request.
+pub const EVIDENCE: &str = "steward-evidence-bin";
+
+const SERVICE: &str = "spire.plugin.server.upstreamauthority.v1.UpstreamAuthority";
+const MINT: &str =
+    "/spire.plugin.server.upstreamauthority.v1.UpstreamAuthority/MintX509CAAndSubscribe";
+
+/// The lifetime of minted CAs when SPIRE expresses no preference.
+const DEFAULT_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// The longest lifetime of a minted CA.
+const MAX_TTL: Duration = Duration::from_secs(60 * 60 * 24 * 28);
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct MintX509CaRequest {
+    /// The DER certification request for the SPIRE server's CA key.
+    #[prost(bytes = "vec", tag = "1")]
+    pub csr: Vec<u8>,
+
+    /// The preferred lifetime of the CA, in seconds.
+    #[prost(int32, tag = "2")]
+    pub preferred_ttl: i32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct X509Certificate {
+    #[prost(bytes = "vec", tag = "1")]
+    pub asn1: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct MintX509CaResponse {
+    /// The minted CA followed by any intermediates.
+    #[prost(message, repeated, tag = "1")]
+    pub x509_ca_chain: Vec<X509Certificate>,
+
+    /// The roots the chain leads to.
+    #[prost(message, repeated, tag = "2")]
+    pub upstream_x509_roots: Vec<X509Certificate>,
+}
+
+type MintStream = Pin<Box<dyn Stream<Item = Result<MintX509CaResponse, Status>> + Send>>;
+
+fn status(code: StatusCode) -> Status {
+    match code {
+        StatusCode::BAD_REQUEST => Status::invalid_argument("invalid request or evidence"),
+        StatusCode::UNAUTHORIZED => Status::permission_denied("attestation failed"),
+        _ => Status::internal("internal error"),
+    }
+}
+
+/// Extracts the SPIFFE ID of the trust domain from a request.
+fn spiffe_id<'a>(cr: &CertReq<'a>) -> Result<Ia5StringRef<'a>, Status> {
+    let mut ids = Vec::new();
+    for attr in cr.info.attributes.iter() {
+        if attr.oid != ID_EXTENSION_REQ {
+            continue;
+        }
+        for any in attr.values.iter() {
+            let ereq: ExtensionReq<'a> = any
+                .decode_into()
+                .map_err(|_| Status::invalid_argument("invalid extension request"))?;
+            for ext in Vec::from(ereq) {
+                if ext.extn_id != ID_CE_SUBJECT_ALT_NAME {
+                    continue;
+                }
+                let san = SubjectAltName::from_der(ext.extn_value)
+                    .map_err(|_| Status::invalid_argument("invalid subject alt name"))?;
+                for name in san.0 {
+                    match name {
+                        GeneralName::UniformResourceIdentifier(uri)
+                            if uri.as_str().starts_with("spiffe://") =>
+                        {
+                            ids.push(uri)
+                        }
+                        _ => (),
+                    }
+                }
+            }
+        }
+    }
+
+    match ids.as_slice() {
+        [id] => Ok(*id),
+        _ => Err(Status::invalid_argument("expected exactly one spiffe id")),
+    }
+}
+
+/// Appraises the evidence for the CA key and mints its certificate.
+async fn mint(state: &State, csr: &[u8], evidence: &[u8], ttl: i32) -> Result<Vec<u8>, Status> {
+    let policy = state.policy();
+    let csr = normalized(policy.config.parsing, csr)
+        .map_err(|_| Status::invalid_argument("invalid csr"))?;
+    let evidence = normalized(policy.config.parsing, evidence)
+        .map_err(|_| Status::invalid_argument("invalid evidence request"))?;
+    let outer = parse::cert_req(&csr).map_err(|_| Status::invalid_argument("invalid csr"))?;
+    let inner = parse::cert_req(&evidence)
+        .map_err(|_| Status::invalid_argument("invalid evidence request"))?;
+    if outer.info.public_key != inner.info.public_key {
+        return Err(Status::invalid_argument("evidence is for a different key"));
+    }
+    let id = spiffe_id(&outer)?;
+    let outer = outer
+        .verify()
+        .map_err(|_| Status::invalid_argument("invalid csr signature"))?;
+    let inner = inner
+        .verify()
+        .map_err(|_| Status::invalid_argument("invalid evidence signature"))?;
+
+    let issuer = Certificate::from_der(&state.crt).map_err(|_| Status::internal("invalid ca"))?;
+    let pki = PrivateKeyInfo::from_der(&state.key).map_err(|_| Status::internal("invalid key"))?;
+    let (_, appraisals) = appraise(&inner, debug_mode(&issuer), state, &policy)
+        .await
+        .map_err(status)?;
+
+    let ttl = match ttl {
+        0 => DEFAULT_TTL,
+        ttl => Duration::from_secs(ttl.unsigned_abs().into()).min(MAX_TTL),
+    };
+    let ttl = state.leaf_ttl(ttl);
+    let now = state.clock.now();
+    let validity = Validity {
+        not_before: Time::try_from(now).map_err(|_| Status::internal("invalid time"))?,
+        not_after: Time::try_from(now + ttl).map_err(|_| Status::internal("invalid time"))?,
+    };
+
+    let internal = |_| Status::internal("failed to encode extension");
+    let bc = BasicConstraints {
+        ca: true,
+        path_len_constraint: None,
+    }
+    .to_vec()
+    .map_err(internal)?;
+    let ku = KeyUsage(KeyUsages::KeyCertSign | KeyUsages::CRLSign)
+        .to_vec()
+        .map_err(internal)?;
+    let san = SubjectAltName(vec![GeneralName::UniformResourceIdentifier(id)])
+        .to_vec()
+        .map_err(internal)?;
+
+    let uuid = state.serials.next();
+    let serial_number =
+        UIntRef::new(uuid.as_bytes()).map_err(|_| Status::internal("invalid serial"))?;
+
+    let crt = TbsCertificate {
+        version: x509::Version::V3,
+        serial_number,
+        signature: pki
+            .signs_with()
+            .map_err(|_| Status::internal("invalid key"))?,
+        issuer: issuer.tbs_certificate.subject.clone(),
+        validity,
+        subject: outer.subject,
+        subject_public_key_info: outer.public_key,
+        issuer_unique_id: issuer.tbs_certificate.subject_unique_id,
+        subject_unique_id: None,
+        extensions: Some(vec![
+            x509::ext::Extension {
+                extn_id: ID_CE_BASIC_CONSTRAINTS,
+                critical: true,
+                extn_value: &bc,
+            },
+            x509::ext::Extension {
+                extn_id: ID_CE_KEY_USAGE,
+                critical: true,
+                extn_value: &ku,
+            },
+            x509::ext::Extension {
+                extn_id: ID_CE_SUBJECT_ALT_NAME,
+                critical: false,
+                extn_value: &san,
+            },
+        ]),
+    }
+    .sign(&pki)
+    .map_err(|_| Status::internal("failed to sign"))?;
+
+    let issued = Issued {
+        serial: serial_number.as_bytes().to_vec(),
+        not_before: validity.not_before.to_system_time(),
+        not_after: validity.not_after.to_system_time(),
+        der: crt.clone(),
+        rekor_index: None,
+        policy_version: policy.version,
+        platform: stats::platform(&appraisals),
+        tenant: stats::tenant(&appraisals),
+    };
+    let request = state.archive.as_ref().map(|_| evidence.to_vec());
+    record(state, &issued, &appraisals, request)
+        .await
+        .map_err(status)?;
+
+    info!("minted spire ca for {}", id.as_str());
+    Ok(crt)
+}
+
+/// The `UpstreamAuthority` gRPC service.
+#[derive(Clone, Debug)]
+pub struct UpstreamAuthority {
+    state: Arc<State>,
+}
+
+impl UpstreamAuthority {
+    pub fn new(state: State) -> Self {
+        Self {
+            state: Arc::new(state),
+        }
+    }
+
+    async fn mint_and_subscribe(
+        state: Arc<State>,
+        request: Request<MintX509CaRequest>,
+    ) -> Result<Response<MintStream>, Status> {
+        let evidence = request
+            .metadata()
+            .get_bin(EVIDENCE)
+            .ok_or_else(|| Status::unauthenticated("missing evidence"))?
+            .to_bytes()
+            .map_err(|_| Status::invalid_argument("invalid evidence encoding"))?;
+        let request = request.into_inner();
+
+        let crt = mint(&state, &request.csr, &evidence, request.preferred_ttl)
+            .await
+            .map_err(|e| {
+                debug!("failed to mint spire ca: {e}");
+                e
+            })?;
+
+        // Steward's certificate is the anchor SPIRE verifies the chain with.
+        let rsp = MintX509CaResponse {
+            x509_ca_chain: vec![X509Certificate { asn1: crt }],
+            upstream_x509_roots: vec![X509Certificate {
+                asn1: state.crt.clone(),
+            }],
+        };
+
+        // SPIRE keeps the stream open to hear of new roots; ours never change.
+        let stream = stream::once(async move { Ok(rsp) }).chain(stream::pending());
+        Ok(Response::new(Box::pin(stream) as MintStream))
+    }
+}
+
+impl tonic::server::NamedService for UpstreamAuthority {
+    const NAME: &'static str = SERVICE;
+}
+
+impl<B> tower::Service<http::Request<B>> for UpstreamAuthority
+where
+    B: tonic::codegen::Body + Send + 'static,
+    B::Error: Into<tonic::codegen::StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        struct Mint(Arc<State>);
+
+        impl tonic::server::ServerStreamingService<MintX509CaRequest> for Mint {
+            type Response = MintX509CaResponse;
+            type ResponseStream = MintStream;
+            type Future = BoxFuture<Response<MintStream>, Status>;
+
+            fn call(&mut self, request: Request<MintX509CaRequest>) -> Self::Future {
+                Box::pin(UpstreamAuthority::mint_and_subscribe(
+                    self.0.clone(),
+                    request,
+                ))
+            }
+        }
+
+        let state = self.state.clone();
+        match req.uri().path() {
+            MINT => Box::pin(async move {
+                let codec = tonic::codec::ProstCodec::default();
+                let mut grpc = tonic::server::Grpc::new(codec);
+                Ok(grpc.server_streaming(Mint(state), req).await)
+            }),
+
+            // Including PublishJWTKeyAndSubscribe.
+            _ => Box::pin(async move {
+                Ok(http::Response::builder()
+                    .status(200)
+                    .header("grpc-status", "12")
+                    .header("content-type", "application/grpc")
+                    .body(empty_body())
+                    .unwrap())
+            }),
+        }
+    }
+}
+
+/// Serves the `UpstreamAuthority` service on `addr`.
+pub async fn serve(state: State, addr: SocketAddr) -> anyhow::Result<()> {
+    tonic::transport::Server::builder()
+        .add_service(UpstreamAuthority::new(state))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ICELAKE_CSR: &[u8] =
+        include_bytes!("../../../crates/attestation/src/sgx/icelake.signed.crl.csr");
+
+    #[test]
+    fn messages() {
+        use prost::Message;
+
+        let req = MintX509CaRequest {
+            csr: vec![1, 2, 3],
+            preferred_ttl: 3600,
+        };
+        let bytes = req.encode_to_vec();
+        assert_eq!(bytes, [0x0a, 3, 1, 2, 3, 0x10, 0x90, 0x1c]);
+        assert_eq!(MintX509CaRequest::decode(&bytes[..]).unwrap(), req);
+    }
+
+    #[test]
+    fn no_spiffe_id() {
+        let cr = CertReq::from_der(ICELAKE_CSR).unwrap();
+        assert!(spiffe_id(&cr).is_err());
+    }
+
+    #[tokio::test]
+    async fn mismatched_key() {
+        let state = State::generate(None, "localhost").unwrap();
+        let openssl = include_bytes!("../../../testdata/openssl.csr");
+        let err = mint(&state, openssl, ICELAKE_CSR, 0).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    }
+}
@@ -0,0 +1,311 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Issuance statistics for dashboards, at `GET /admin/stats`.
+//!
+//! Certificates are counted by platform and tenant: how many were issued
+//! within the last `window` seconds (a day by default), how many are still
+//! active, and how many of those expire within the next `horizon` seconds
+//! (also a day by default). The tenant of a workload is the key which signed
+//! it, as for quotas. Certificates issued on evidence from several platforms
+//! are counted under their platforms joined by `+`.
+//!
+//! Issuances are counted in the store, so every replica sharing it agrees.
+//! Rejections are only those this replica still keeps for
+//! `GET /admin/rejections`, counted by platform alone.
+
+use super::store::Tally;
+use super::verifier::Appraisal;
+use super::{admin, State};
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use axum::extract::{Extension, TypedHeader};
+use axum::headers::authorization::{Authorization, Bearer};
+use axum::http::Uri;
+use axum::Json;
+use hyper::StatusCode;
+use serde::Serialize;
+use tracing::debug;
+
+const DEFAULT_WINDOW: u64 = 24 * 60 * 60;
+const DEFAULT_HORIZON: u64 = 24 * 60 * 60;
+
+/// The longest window or horizon, a year.
+const MAX_SECS: u64 = 366 * 24 * 60 * 60;
+
+/// The measurements which name the key that signed a workload.
+const SIGNERS: &[&str] = &["mrsigner", "author_key_digest"];
+
+/// The platform label recorded for a certificate issued on `appraisals`.
+pub fn platform(appraisals: &[Appraisal]) -> Option<String> {
+    let platforms: Vec<_> = appraisals.iter().map(|a| a.platform.as_str()).collect();
+    match platforms.is_empty() {
+        true => None,
+        false => Some(platforms.join("+")),
+    }
+}
+
+/// The tenant recorded for a certificate issued on `appraisals`, if any of
+/// them names the key which signed the workload.
+pub fn tenant(appraisals: &[Appraisal]) -> Option<String> {
+    appraisals
+        .iter()
+        .flat_map(|a| SIGNERS.iter().filter_map(|name| a.measurements.get(*name)))
+        .next()
+        .cloned()
+}
+
+/// What was asked for in the query string.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Query {
+    window: u64,
+    horizon: u64,
+}
+
+impl Query {
+    fn parse(query: Option<&str>) -> Option<Self> {
+        let mut parsed = Self {
+            window: DEFAULT_WINDOW,
+            horizon: DEFAULT_HORIZON,
+        };
+
+        let secs = |value: &str| value.parse().ok().filter(|n| (1..=MAX_SECS).contains(n));
+        let params = query.into_iter().flat_map(|q| q.split('&'));
+        for param in params.filter(|p| !p.is_empty()) {
+            match param.split_once('=')? {
+                ("window", window) => parsed.window = secs(window)?,
+                ("horizon", horizon) => parsed.horizon = secs(horizon)?,
+                _ => return None,
+            }
+        }
+        Some(parsed)
+    }
+}
+
+/// The counts for one platform and tenant, or for all of them.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Counts {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+
+    pub issued: u64,
+    pub rejected: u64,
+    pub active: u64,
+    pub expiring: u64,
+}
+
+impl Counts {
+    fn add(&mut self, other: &Self) {
+        self.issued += other.issued;
+        self.rejected += other.rejected;
+        self.active += other.active;
+        self.expiring += other.expiring;
+    }
+}
+
+/// The statistics over a window.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Stats {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+
+    pub window: u64,
+    pub horizon: u64,
+    pub total: Counts,
+    pub groups: Vec<Counts>,
+}
+
+/// Merges the tallies of the store with the rejections, by platform.
+fn group(tallies: Vec<Tally>, rejected: BTreeMap<Option<String>, u64>) -> Vec<Counts> {
+    let mut groups = BTreeMap::new();
+    for tally in tallies {
+        let key = (tally.platform.clone(), tally.tenant.clone());
+        groups.insert(
+            key,
+            Counts {
+                platform: tally.platform,
+                tenant: tally.tenant,
+                issued: tally.issued,
+                active: tally.active,
+                expiring: tally.expiring,
+                ..Default::default()
+            },
+        );
+    }
+
+    for (platform, count) in rejected {
+        let counts = groups
+            .entry((platform.clone(), None))
+            .or_insert_with(|| Counts {
+                platform,
+                ..Default::default()
+            });
+        counts.rejected += count;
+    }
+    groups.into_values().collect()
+}
+
+/// Returns the issuance statistics.
+pub async fn stats(
+    uri: Uri,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Json<Stats>, StatusCode> {
+    admin::authorize(&state, auth, true).await?;
+    let query = Query::parse(uri.query()).ok_or(StatusCode::BAD_REQUEST)?;
+
+    let now = state.clock.now();
+    let since = now - Duration::from_secs(query.window);
+    let until = now + Duration::from_secs(query.horizon);
+    let tallies = state.store.tally(since, now, until).await.map_err(|e| {
+        debug!("failed to tally issued certificates: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let timestamp = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let mut rejected = BTreeMap::new();
+    for rejection in state.rejections.recent() {
+        if rejection.timestamp + query.window >= timestamp {
+            *rejected.entry(rejection.platform).or_default() += 1;
+        }
+    }
+
+    let groups = group(tallies, rejected);
+    let mut total = Counts::default();
+    for counts in &groups {
+        total.add(counts);
+    }
+    Ok(Json(Stats {
+        timestamp,
+        window: query.window,
+        horizon: query.horizon,
+        total,
+        groups,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::admin::Auditor;
+    use super::super::clock::Manual;
+    use super::super::operations;
+    use super::super::rejections::Rejection;
+    use super::super::store::Issued;
+    use super::*;
+
+    use http::header::AUTHORIZATION;
+    use http::Request;
+    use hyper::Body;
+    use tower::ServiceExt; // for `app.oneshot()`
+
+    #[test]
+    fn query() {
+        let default = Query::parse(None).unwrap();
+        assert_eq!(default.window, DEFAULT_WINDOW);
+        assert_eq!(default.horizon, DEFAULT_HORIZON);
+
+        let query = Query::parse(Some("window=3600&horizon=604800")).unwrap();
+        assert_eq!(query.window, 3600);
+        assert_eq!(query.horizon, 604800);
+
+        for bad in ["window=0", "window=1d", "horizon=31708801", "tenant=ab"] {
+            assert_eq!(Query::parse(Some(bad)), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn labels() {
+        let sgx = Appraisal::new("sgx", true)
+            .with_measurement("mrenclave", &[1; 32])
+            .with_measurement("mrsigner", &[2; 32]);
+        let kvm = Appraisal::new("kvm", true);
+        assert_eq!(platform(&[kvm.clone(), sgx.clone()]).unwrap(), "kvm+sgx");
+        assert_eq!(tenant(&[kvm.clone(), sgx]).unwrap(), hex::encode([2; 32]));
+        assert_eq!(tenant(&[kvm]), None);
+        assert_eq!(platform(&[]), None);
+    }
+
+    #[tokio::test]
+    async fn stats() {
+        const NOW: u64 = 1_700_000_000;
+
+        let state = State::generate(None, "localhost")
+            .unwrap()
+            .with_auditor(Auditor::new("auditor").unwrap())
+            .with_clock(Arc::new(Manual::new(UNIX_EPOCH + Duration::from_secs(NOW))));
+
+        let now = UNIX_EPOCH + Duration::from_secs(NOW);
+        let expires = now + Duration::from_secs(MAX_SECS);
+        for (serial, age, tenant) in [(1, 60, "aa"), (2, 60, "aa"), (3, 7200, "bb")] {
+            let issued = Issued {
+                serial: vec![serial],
+                not_before: UNIX_EPOCH + Duration::from_secs(NOW - age),
+                not_after: expires,
+                der: vec![],
+                rekor_index: None,
+                policy_version: 1,
+                platform: Some("sgx".into()),
+                tenant: Some(tenant.into()),
+            };
+            state.store.issue(&issued, now).await.unwrap();
+        }
+        let rejection = Rejection::new(now, StatusCode::BAD_REQUEST, "refused");
+        state.rejections.push(rejection.with_platform("snp", None));
+
+        let get = |uri: &'static str, token: Option<&'static str>| {
+            let state = state.clone();
+            async move {
+                let mut request = Request::get(uri);
+                if let Some(token) = token {
+                    request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+                }
+                operations(state)
+                    .oneshot(request.body(Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let rsp = get("/admin/stats?window=3600", Some("auditor")).await;
+        assert_eq!(rsp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(rsp.into_body()).await.unwrap();
+        let stats: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(stats["timestamp"], NOW);
+        assert_eq!(stats["total"]["issued"], 2);
+        assert_eq!(stats["total"]["rejected"], 1);
+        assert_eq!(stats["total"]["active"], 3);
+        assert_eq!(stats["total"]["expiring"], 0);
+
+        let groups = stats["groups"].as_array().unwrap();
+        let summary: Vec<_> = groups
+            .iter()
+            .map(|g| {
+                let tenant = g.get("tenant").and_then(|t| t.as_str());
+                (
+                    g["platform"].as_str().unwrap(),
+                    tenant,
+                    g["issued"].as_u64(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("sgx", Some("aa"), Some(2)),
+                ("sgx", Some("bb"), Some(0)),
+                ("snp", None, Some(0)),
+            ]
+        );
+
+        let rsp = get("/admin/stats", None).await;
+        assert_eq!(rsp.status(), StatusCode::UNAUTHORIZED);
+        let rsp = get("/admin/stats?window=forever", Some("auditor")).await;
+        assert_eq!(rsp.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,1663 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Durable records of what the CA has done.
+//!
+//! The embedded store keeps records in memory, which is adequate for
+//! development and short-lived deployments. Production CAs should use the
+//! PostgreSQL store (behind the `postgres` feature) so that issued
+//! certificates, revocations and audit records survive restarts.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+/// A certificate issued by this CA.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Issued {
+    pub serial: Vec<u8>,
+    pub not_before: SystemTime,
+    pub not_after: SystemTime,
+    pub der: Vec<u8>,
+
+    /// The index of the certificate in the Rekor log, once published.
+    pub rekor_index: Option<u64>,
+
+    /// The version of the policy under which it was issued, or 0 if the
+    /// policy was never versioned.
+    pub policy_version: u64,
+
+    /// The platforms whose evidence it was issued on, joined by `+`.
+    pub platform: Option<String>,
+
+    /// The hex key which signed the workload, which stands in for its tenant.
+    pub tenant: Option<String>,
+}
+
+/// The revocation of an issued certificate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Revocation {
+    pub serial: Vec<u8>,
+    pub revoked_at: SystemTime,
+
+    /// The RFC 5280 `CRLReason`, if any.
+    pub reason: Option<u8>,
+}
+
+/// A security relevant event.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub at: SystemTime,
+    pub event: String,
+    pub detail: String,
+}
+
+/// A reference value (e.g. a measurement) for a platform.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ReferenceValue {
+    pub platform: String,
+    pub kind: String,
+    pub value: Vec<u8>,
+}
+
+/// The certificates issued for one platform and tenant.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Tally {
+    pub platform: Option<String>,
+    pub tenant: Option<String>,
+
+    /// How many were issued since the start of the window.
+    pub issued: u64,
+
+    /// How many have not yet expired.
+    pub active: u64,
+
+    /// How many of those expire before the end of the horizon.
+    pub expiring: u64,
+}
+
+/// One issuance in the history of a workload identity, that is of a key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Link {
+    pub serial: Vec<u8>,
+    pub not_before: SystemTime,
+    pub not_after: SystemTime,
+    pub platform: Option<String>,
+
+    /// The hex measurements of the workload, by platform and name, such as
+    /// `sgx.mrenclave`.
+    pub measurements: BTreeMap<String, String>,
+}
+
+/// A certificate held as a lease on its key, which lapses unless another
+/// is issued for the key by `renew_by`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Lease {
+    /// The SHA-256 digest of the certified public key.
+    pub key: Vec<u8>,
+
+    pub serial: Vec<u8>,
+    pub renew_by: SystemTime,
+}
+
+/// A cap on the issuances counted against a subject within a sliding window.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Allowance {
+    pub subject: String,
+    pub window: Duration,
+    pub limit: u64,
+}
+
+/// An operator's decision on a parked certification request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decision {
+    Pending,
+
+    /// Claimed by an operator deciding on it, until the decision is made.
+    Deciding,
+
+    /// Approved, with the DER certificate chain issued for the request.
+    Approved(Vec<u8>),
+
+    Denied,
+}
+
+/// A certification request parked for an operator's decision.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Parked {
+    /// The hex SHA-256 digest of the DER request.
+    pub id: String,
+
+    pub received_at: SystemTime,
+    pub expires_at: SystemTime,
+
+    /// The DER certification request.
+    pub request: Vec<u8>,
+
+    /// The hex measurements which need approval, by platform and name.
+    pub measurements: BTreeMap<String, String>,
+
+    /// The issuance profile the client asked for by name, if any.
+    pub profile: Option<String>,
+
+    pub decision: Decision,
+}
+
+#[async_trait]
+pub trait Store: Debug + Send + Sync {
+    /// Records a newly issued certificate, issued at `now`.
+    async fn issue(&self, issued: &Issued, now: SystemTime) -> Result<()>;
+
+    /// Looks up an issued certificate by serial number.
+    async fn issued(&self, serial: &[u8]) -> Result<Option<Issued>>;
+
+    /// Revokes an issued certificate, returning false if it is unknown.
+    async fn revoke(&self, revocation: &Revocation) -> Result<bool>;
+
+    /// Returns all revocations of certificates which have not expired by `now`.
+    async fn revocations(&self, now: SystemTime) -> Result<Vec<Revocation>>;
+
+    /// Appends an audit record.
+    async fn audit(&self, record: &AuditRecord) -> Result<()>;
+
+    /// Adds a reference value.
+    async fn add_reference_value(&self, value: &ReferenceValue) -> Result<()>;
+
+    /// Returns all reference values for the platform.
+    async fn reference_values(&self, platform: &str) -> Result<Vec<ReferenceValue>>;
+
+    /// Archives the (opaque, possibly encrypted) evidence for a certificate.
+    async fn archive(&self, serial: &[u8], at: SystemTime, data: &[u8]) -> Result<()>;
+
+    /// Returns the evidence archived for a certificate.
+    async fn archived(&self, serial: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Deletes evidence archived before `before`, returning how much was deleted.
+    async fn purge_archive(&self, before: SystemTime) -> Result<u64>;
+
+    /// Appends a certificate's leaf hash to the issuance log, returning its index.
+    async fn append_log(&self, serial: &[u8], leaf: &[u8; 32]) -> Result<u64>;
+
+    /// Returns the issuance log's leaf hashes, starting at index `from`.
+    async fn log_leaves(&self, from: u64) -> Result<Vec<[u8; 32]>>;
+
+    /// Returns the index of a certificate in the issuance log.
+    async fn log_index(&self, serial: &[u8]) -> Result<Option<u64>>;
+
+    /// Returns up to `limit` certificates not yet published to Rekor.
+    async fn unpublished(&self, limit: usize) -> Result<Vec<Issued>>;
+
+    /// Records the Rekor log index of a published certificate.
+    async fn published(&self, serial: &[u8], index: u64) -> Result<()>;
+
+    /// Returns up to `limit` issued certificates in order of `not_before` and
+    /// serial number, starting after the certificate so keyed by `after`.
+    async fn inventory(&self, after: (SystemTime, &[u8]), limit: usize) -> Result<Vec<Issued>>;
+
+    /// Tallies the certificates issued since `since`, unexpired at `now` and
+    /// expiring before `until`, by platform and tenant.
+    async fn tally(
+        &self,
+        since: SystemTime,
+        now: SystemTime,
+        until: SystemTime,
+    ) -> Result<Vec<Tally>>;
+
+    /// Counts an issuance at `at` against every allowance, unless any of them
+    /// is exhausted, returning whether it was counted.
+    async fn consume(&self, allowances: &[Allowance], at: SystemTime) -> Result<bool>;
+
+    /// Adds an issuance at `now` to the history of the identity with the
+    /// `key` digest.
+    async fn link(&self, key: &[u8], link: &Link, now: SystemTime) -> Result<()>;
+
+    /// Returns the history of the identity with the `key` digest, in order of
+    /// issuance.
+    async fn identity(&self, key: &[u8]) -> Result<Vec<Link>>;
+
+    /// Grants a lease, renewing any held on the same key.
+    async fn grant(&self, lease: &Lease) -> Result<()>;
+
+    /// Removes and returns the leases which were not renewed by `at`.
+    async fn lapse(&self, at: SystemTime) -> Result<Vec<Lease>>;
+
+    /// Returns the version of the policy with the SHA-256 `hash`, which is
+    /// registered at `at` as the next version unless it is already the
+    /// latest, and whether it was newly registered.
+    async fn activate_policy(&self, hash: &[u8; 32], at: SystemTime) -> Result<(u64, bool)>;
+
+    /// Replaces the DER trust anchors uploaded for `platform`.
+    async fn set_anchors(&self, platform: &str, anchors: &[Vec<u8>]) -> Result<()>;
+
+    /// Returns the DER trust anchors uploaded for `platform`, in order.
+    async fn anchors(&self, platform: &str) -> Result<Vec<Vec<u8>>>;
+
+    /// Parks a request for an operator's decision, unless one with the same
+    /// ID is parked and unexpired, returning whether it was newly parked.
+    async fn park(&self, parked: &Parked) -> Result<bool>;
+
+    /// Returns the parked request `id`, unless it expired by `now`.
+    async fn parked(&self, id: &str, now: SystemTime) -> Result<Option<Parked>>;
+
+    /// Returns the requests awaiting a decision at `now`, oldest first.
+    async fn pending(&self, now: SystemTime) -> Result<Vec<Parked>>;
+
+    /// Claims the parked request `id` for a decision, returning false unless
+    /// it awaited one.
+    async fn claim(&self, id: &str) -> Result<bool>;
+
+    /// Returns the parked request `id`, claimed for a decision, to awaiting
+    /// one.
+    async fn release(&self, id: &str) -> Result<()>;
+
+    /// Decides on the parked request `id`, claimed for a decision, together
+    /// approving the measurements with the SHA-256 `fingerprint` if it is
+    /// approved, returning false unless it was claimed.
+    async fn decide(&self, id: &str, decision: &Decision, fingerprint: &[u8; 32]) -> Result<bool>;
+
+    /// Whether the measurements with the SHA-256 `fingerprint` are approved.
+    async fn approved(&self, fingerprint: &[u8; 32]) -> Result<bool>;
+
+    /// Pins the hex `measurements`, by platform and name, to the workload
+    /// `identity` at `at` unless it is already pinned, returning those it is
+    /// pinned to.
+    async fn pin(
+        &self,
+        identity: &str,
+        measurements: &BTreeMap<String, String>,
+        at: SystemTime,
+    ) -> Result<BTreeMap<String, String>>;
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    issued: BTreeMap<Vec<u8>, Issued>,
+    revoked: BTreeMap<Vec<u8>, Revocation>,
+    audit: VecDeque<AuditRecord>,
+    references: Vec<ReferenceValue>,
+    archive: BTreeMap<Vec<u8>, (SystemTime, Vec<u8>)>,
+    log: Vec<[u8; 32]>,
+    log_index: BTreeMap<Vec<u8>, u64>,
+    quotas: BTreeMap<String, Vec<SystemTime>>,
+    identities: BTreeMap<Vec<u8>, Vec<Link>>,
+    leases: BTreeMap<Vec<u8>, Lease>,
+    policies: Vec<[u8; 32]>,
+    anchors: BTreeMap<String, Vec<Vec<u8>>>,
+    parked: BTreeMap<String, Parked>,
+    approved: BTreeSet<[u8; 32]>,
+    pins: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+/// The embedded, in-memory store.
+///
+/// Expired certificates, and identities whose certificates have all expired,
+/// are pruned and only the most recent audit records are retained, so memory
+/// use stays bounded (apart from the issuance log, which
+/// is append-only by design).
+#[derive(Debug, Default)]
+pub struct Memory(Mutex<Inner>);
+
+impl Memory {
+    const AUDIT: usize = 10000;
+}
+
+#[async_trait]
+impl Store for Memory {
+    async fn issue(&self, issued: &Issued, now: SystemTime) -> Result<()> {
+        let mut inner = self.0.lock().unwrap();
+        let Inner {
+            issued: all,
+            revoked,
+            ..
+        } = &mut *inner;
+
+        all.retain(|_, i| i.not_after > now);
+        revoked.retain(|serial, _| all.contains_key(serial));
+        all.insert(issued.serial.clone(), issued.clone());
+        Ok(())
+    }
+
+    async fn issued(&self, serial: &[u8]) -> Result<Option<Issued>> {
+        Ok(self.0.lock().unwrap().issued.get(serial).cloned())
+    }
+
+    async fn revoke(&self, revocation: &Revocation) -> Result<bool> {
+        let mut inner = self.0.lock().unwrap();
+        if !inner.issued.contains_key(&revocation.serial) {
+            return Ok(false);
+        }
+
+        inner
+            .revoked
+            .entry(revocation.serial.clone())
+            .or_insert_with(|| revocation.clone());
+        Ok(true)
+    }
+
+    async fn revocations(&self, now: SystemTime) -> Result<Vec<Revocation>> {
+        let inner = self.0.lock().unwrap();
+        Ok(inner
+            .revoked
+            .values()
+            .filter(|r| {
+                inner
+                    .issued
+                    .get(&r.serial)
+                    .map_or(false, |i| i.not_after > now)
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn audit(&self, record: &AuditRecord) -> Result<()> {
+        let mut inner = self.0.lock().unwrap();
+        if inner.audit.len() >= Self::AUDIT {
+            inner.audit.pop_front();
+        }
+        inner.audit.push_back(record.clone());
+        Ok(())
+    }
+
+    async fn add_reference_value(&self, value: &ReferenceValue) -> Result<()> {
+        let mut inner = self.0.lock().unwrap();
+        if !inner.references.contains(value) {
+            inner.references.push(value.clone());
+        }
+        Ok(())
+    }
+
+    async fn reference_values(&self, platform: &str) -> Result<Vec<ReferenceValue>> {
+        let inner = self.0.lock().unwrap();
+        Ok(inner
+            .references
+            .iter()
+            .filter(|r| r.platform == platform)
+            .cloned()
+            .collect())
+    }
+
+    async fn archive(&self, serial: &[u8], at: SystemTime, data: &[u8]) -> Result<()> {
+        let mut inner = self.0.lock().unwrap();
+        inner.archive.insert(serial.to_vec(), (at, data.to_vec()));
+        Ok(())
+    }
+
+    async fn archived(&self, serial: &[u8]) -> Result<Option<Vec<u8>>> {
+        let inner = self.0.lock().unwrap();
+        Ok(inner.archive.get(serial).map(|(_, data)| data.clone()))
+    }
+
+    async fn purge_archive(&self, before: SystemTime) -> Result<u64> {
+        let mut inner = self.0.lock().unwrap();
+        let count = inner.archive.len();
+        inner.archive.retain(|_, (at, _)| *at >= before);
+        Ok((count - inner.archive.len()) as u64)
+    }
+
+    async fn append_log(&self, serial: &[u8], leaf: &[u8; 32]) -> Result<u64> {
+        let mut inner = self.0.lock().unwrap();
+        let index = inner.log.len() as u64;
+        inner.log.push(*leaf);
+        inner.log_index.insert(serial.to_vec(), index);
+        Ok(index)
+    }
+
+    async fn log_leaves(&self, from: u64) -> Result<Vec<[u8; 32]>> {
+        let inner = self.0.lock().unwrap();
+        let from = (from as usize).min(inner.log.len());
+        Ok(inner.log[from..].to_vec())
+    }
+
+    async fn log_index(&self, serial: &[u8]) -> Result<Option<u64>> {
+        Ok(self.0.lock().unwrap().log_index.get(serial).copied())
+    }
+
+    async fn unpublished(&self, limit: usize) -> Result<Vec<Issued>> {
+        let inner = self.0.lock().unwrap();
+        Ok(inner
+            .issued
+            .values()
+            .filter(|i| i.rekor_index.is_none())
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    async fn published(&self, serial: &[u8], index: u64) -> Result<()> {
+        if let Some(issued) = self.0.lock().unwrap().issued.get_mut(serial) {
+            issued.rekor_index = Some(index);
+        }
+        Ok(())
+    }
+
+    async fn inventory(&self, after: (SystemTime, &[u8]), limit: usize) -> Result<Vec<Issued>> {
+        let inner = self.0.lock().unwrap();
+        let mut page: Vec<_> = inner
+            .issued
+            .values()
+            .filter(|i| (i.not_before, i.serial.as_slice()) > after)
+            .cloned()
+            .collect();
+        page.sort_by(|a, b| (a.not_before, &a.serial).cmp(&(b.not_before, &b.serial)));
+        page.truncate(limit);
+        Ok(page)
+    }
+
+    async fn tally(
+        &self,
+        since: SystemTime,
+        now: SystemTime,
+        until: SystemTime,
+    ) -> Result<Vec<Tally>> {
+        let inner = self.0.lock().unwrap();
+        let mut tallies = BTreeMap::<_, Tally>::new();
+        for issued in inner.issued.values() {
+            let key = (issued.platform.clone(), issued.tenant.clone());
+            let counts = (
+                issued.not_before >= since,
+                issued.not_after > now,
+                issued.not_after > now && issued.not_after <= until,
+            );
+            if counts == (false, false, false) {
+                continue;
+            }
+
+            let tally = tallies
+                .entry(key)
+                .or_insert_with_key(|(platform, tenant)| Tally {
+                    platform: platform.clone(),
+                    tenant: tenant.clone(),
+                    ..Default::default()
+                });
+            tally.issued += counts.0 as u64;
+            tally.active += counts.1 as u64;
+            tally.expiring += counts.2 as u64;
+        }
+        Ok(tallies.into_values().collect())
+    }
+
+    async fn consume(&self, allowances: &[Allowance], at: SystemTime) -> Result<bool> {
+        let mut inner = self.0.lock().unwrap();
+
+        // Each subject holds the times its issuances leave the window.
+        inner.quotas.retain(|_, expiries| {
+            expiries.retain(|expires| *expires > at);
+            !expiries.is_empty()
+        });
+
+        for allowance in allowances {
+            let used = inner.quotas.get(&allowance.subject).map_or(0, Vec::len);
+            if used as u64 >= allowance.limit {
+                return Ok(false);
+            }
+        }
+
+        for allowance in allowances {
+            inner
+                .quotas
+                .entry(allowance.subject.clone())
+                .or_default()
+                .push(at + allowance.window);
+        }
+        Ok(true)
+    }
+
+    async fn link(&self, key: &[u8], link: &Link, now: SystemTime) -> Result<()> {
+        let mut inner = self.0.lock().unwrap();
+        inner
+            .identities
+            .retain(|_, links| links.iter().any(|l| l.not_after > now));
+        let links = inner.identities.entry(key.to_vec()).or_default();
+        links.push(link.clone());
+        links.sort_by(|a, b| (a.not_before, &a.serial).cmp(&(b.not_before, &b.serial)));
+        Ok(())
+    }
+
+    async fn identity(&self, key: &[u8]) -> Result<Vec<Link>> {
+        let inner = self.0.lock().unwrap();
+        Ok(inner.identities.get(key).cloned().unwrap_or_default())
+    }
+
+    async fn grant(&self, lease: &Lease) -> Result<()> {
+        let mut inner = self.0.lock().unwrap();
+        inner.leases.insert(lease.key.clone(), lease.clone());
+        Ok(())
+    }
+
+    async fn lapse(&self, at: SystemTime) -> Result<Vec<Lease>> {
+        let mut inner = self.0.lock().unwrap();
+        let mut lapsed = Vec::new();
+        inner.leases.retain(|_, lease| match lease.renew_by > at {
+            true => true,
+            false => {
+                lapsed.push(lease.clone());
+                false
+            }
+        });
+        Ok(lapsed)
+    }
+
+    async fn activate_policy(&self, hash: &[u8; 32], _at: SystemTime) -> Result<(u64, bool)> {
+        let mut inner = self.0.lock().unwrap();
+        let new = inner.policies.last() != Some(hash);
+        if new {
+            inner.policies.push(*hash);
+        }
+        Ok((inner.policies.len() as u64, new))
+    }
+
+    async fn set_anchors(&self, platform: &str, anchors: &[Vec<u8>]) -> Result<()> {
+        let mut inner = self.0.lock().unwrap();
+        match anchors.is_empty() {
+            true => inner.anchors.remove(platform),
+            false => inner.anchors.insert(platform.into(), anchors.to_vec()),
+        };
+        Ok(())
+    }
+
+    async fn anchors(&self, platform: &str) -> Result<Vec<Vec<u8>>> {
+        let inner = self.0.lock().unwrap();
+        Ok(inner.anchors.get(platform).cloned().unwrap_or_default())
+    }
+
+    async fn park(&self, parked: &Parked) -> Result<bool> {
+        let mut inner = self.0.lock().unwrap();
+        let now = parked.received_at;
+        inner.parked.retain(|_, p| p.expires_at > now);
+        if inner.parked.contains_key(&parked.id) {
+            return Ok(false);
+        }
+        inner.parked.insert(parked.id.clone(), parked.clone());
+        Ok(true)
+    }
+
+    async fn parked(&self, id: &str, now: SystemTime) -> Result<Option<Parked>> {
+        let inner = self.0.lock().unwrap();
+        let parked = inner.parked.get(id).filter(|p| p.expires_at > now);
+        Ok(parked.cloned())
+    }
+
+    async fn pending(&self, now: SystemTime) -> Result<Vec<Parked>> {
+        let inner = self.0.lock().unwrap();
+        let mut pending: Vec<_> = inner
+            .parked
+            .values()
+            .filter(|p| p.expires_at > now && p.decision == Decision::Pending)
+            .cloned()
+            .collect();
+        pending.sort_by(|a, b| (a.received_at, &a.id).cmp(&(b.received_at, &b.id)));
+        Ok(pending)
+    }
+
+    async fn claim(&self, id: &str) -> Result<bool> {
+        let mut inner = self.0.lock().unwrap();
+        match inner.parked.get_mut(id) {
+            Some(parked) if parked.decision == Decision::Pending => {
+                parked.decision = Decision::Deciding;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn release(&self, id: &str) -> Result<()> {
+        let mut inner = self.0.lock().unwrap();
+        if let Some(parked) = inner.parked.get_mut(id) {
+            if parked.decision == Decision::Deciding {
+                parked.decision = Decision::Pending;
+            }
+        }
+        Ok(())
+    }
+
+    async fn decide(&self, id: &str, decision: &Decision, fingerprint: &[u8; 32]) -> Result<bool> {
+        let mut inner = self.0.lock().unwrap();
+        match inner.parked.get_mut(id) {
+            Some(parked) if parked.decision == Decision::Deciding => {
+                parked.decision = decision.clone();
+            }
+            _ => return Ok(false),
+        }
+        if let Decision::Approved(..) = decision {
+            inner.approved.insert(*fingerprint);
+        }
+        Ok(true)
+    }
+
+    async fn approved(&self, fingerprint: &[u8; 32]) -> Result<bool> {
+        let inner = self.0.lock().unwrap();
+        Ok(inner.approved.contains(fingerprint))
+    }
+
+    async fn pin(
+        &self,
+        identity: &str,
+        measurements: &BTreeMap<String, String>,
+        _at: SystemTime,
+    ) -> Result<BTreeMap<String, String>> {
+        let mut inner = self.0.lock().unwrap();
+        let pinned = inner
+            .pins
+            .entry(identity.into())
+            .or_insert_with(|| measurements.clone());
+        Ok(pinned.clone())
+    }
+}
+
+#[cfg(all(feature = "postgres", not(target_os = "wasi")))]
+pub use self::postgres::Postgres;
+
+#[cfg(all(feature = "postgres", not(target_os = "wasi")))]
+mod postgres {
+    use super::*;
+
+    use std::time::UNIX_EPOCH;
+
+    use anyhow::Context;
+    use sqlx::postgres::{PgPool, PgPoolOptions};
+
+    fn secs(time: SystemTime) -> i64 {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() as i64,
+            Err(e) => -(e.duration().as_secs() as i64),
+        }
+    }
+
+    fn time(secs: i64) -> SystemTime {
+        match secs {
+            s if s >= 0 => UNIX_EPOCH + Duration::from_secs(s as u64),
+            s => UNIX_EPOCH - Duration::from_secs(s.unsigned_abs()),
+        }
+    }
+
+    type Row = (
+        Vec<u8>,
+        i64,
+        i64,
+        Vec<u8>,
+        Option<i64>,
+        i64,
+        Option<String>,
+        Option<String>,
+    );
+
+    fn issued((serial, nb, na, der, rekor, policy, platform, tenant): Row) -> Issued {
+        Issued {
+            serial,
+            not_before: time(nb),
+            not_after: time(na),
+            der,
+            rekor_index: rekor.map(|i| i as u64),
+            policy_version: policy as u64,
+            platform,
+            tenant,
+        }
+    }
+
+    /// A PostgreSQL store with a pool of connections.
+    #[derive(Clone, Debug)]
+    pub struct Postgres(PgPool);
+
+    impl Postgres {
+        /// Connects to the database at `url` and applies any pending migrations.
+        pub async fn connect(url: &str, connections: u32) -> Result<Self> {
+            let pool = PgPoolOptions::new()
+                .max_connections(connections)
+                .connect(url)
+                .await
+                .context("failed to connect to database")?;
+
+            sqlx::migrate!("./migrations")
+                .run(&pool)
+                .await
+                .context("failed to migrate database")?;
+
+            Ok(Self(pool))
+        }
+    }
+
+    #[async_trait]
+    impl Store for Postgres {
+        async fn issue(&self, issued: &Issued, _now: SystemTime) -> Result<()> {
+            sqlx::query(
+                "INSERT INTO issued \
+                 (serial, not_before, not_after, der, policy_version, platform, tenant) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            )
+            .bind(&issued.serial)
+            .bind(secs(issued.not_before))
+            .bind(secs(issued.not_after))
+            .bind(&issued.der)
+            .bind(issued.policy_version as i64)
+            .bind(&issued.platform)
+            .bind(&issued.tenant)
+            .execute(&self.0)
+            .await?;
+            Ok(())
+        }
+
+        async fn issued(&self, serial: &[u8]) -> Result<Option<Issued>> {
+            let row: Option<Row> = sqlx::query_as(
+                "SELECT serial, not_before, not_after, der, rekor_index, policy_version, \
+                 platform, tenant \
+                 FROM issued WHERE serial = $1",
+            )
+            .bind(serial)
+            .fetch_optional(&self.0)
+            .await?;
+
+            Ok(row.map(issued))
+        }
+
+        async fn revoke(&self, revocation: &Revocation) -> Result<bool> {
+            let known: Option<(i32,)> = sqlx::query_as("SELECT 1 FROM issued WHERE serial = $1")
+                .bind(&revocation.serial)
+                .fetch_optional(&self.0)
+                .await?;
+            if known.is_none() {
+                return Ok(false);
+            }
+
+            sqlx::query(
+                "INSERT INTO revocations (serial, revoked_at, reason) VALUES ($1, $2, $3) \
+                 ON CONFLICT (serial) DO NOTHING",
+            )
+            .bind(&revocation.serial)
+            .bind(secs(revocation.revoked_at))
+            .bind(revocation.reason.map(i16::from))
+            .execute(&self.0)
+            .await?;
+            Ok(true)
+        }
+
+        async fn revocations(&self, now: SystemTime) -> Result<Vec<Revocation>> {
+            let rows: Vec<(Vec<u8>, i64, Option<i16>)> = sqlx::query_as(
+                "SELECT r.serial, r.revoked_at, r.reason FROM revocations r \
+                 JOIN issued i ON i.serial = r.serial WHERE i.not_after > $1",
+            )
+            .bind(secs(now))
+            .fetch_all(&self.0)
+            .await?;
+
+            Ok(rows
+                .into_iter()
+                .map(|(serial, at, reason)| Revocation {
+                    serial,
+                    revoked_at: time(at),
+                    reason: reason.map(|r| r as u8),
+                })
+                .collect())
+        }
+
+        async fn audit(&self, record: &AuditRecord) -> Result<()> {
+            sqlx::query("INSERT INTO audit (at, event, detail) VALUES ($1, $2, $3)")
+                .bind(secs(record.at))
+                .bind(&record.event)
+                .bind(&record.detail)
+                .execute(&self.0)
+                .await?;
+            Ok(())
+        }
+
+        async fn add_reference_value(&self, value: &ReferenceValue) -> Result<()> {
+            sqlx::query(
+                "INSERT INTO reference_values (platform, kind, value) VALUES ($1, $2, $3) \
+                 ON CONFLICT DO NOTHING",
+            )
+            .bind(&value.platform)
+            .bind(&value.kind)
+            .bind(&value.value)
+            .execute(&self.0)
+            .await?;
+            Ok(())
+        }
+
+        async fn reference_values(&self, platform: &str) -> Result<Vec<ReferenceValue>> {
+            let rows: Vec<(String, String, Vec<u8>)> = sqlx::query_as(
+                "SELECT platform, kind, value FROM reference_values WHERE platform = $1",
+            )
+            .bind(platform)
+            .fetch_all(&self.0)
+            .await?;
+
+            Ok(rows
+                .into_iter()
+                .map(|(platform, kind, value)| ReferenceValue {
+                    platform,
+                    kind,
+                    value,
+                })
+                .collect())
+        }
+
+        async fn archive(&self, serial: &[u8], at: SystemTime, data: &[u8]) -> Result<()> {
+            sqlx::query("INSERT INTO evidence (serial, archived_at, data) VALUES ($1, $2, $3)")
+                .bind(serial)
+                .bind(secs(at))
+                .bind(data)
+                .execute(&self.0)
+                .await?;
+            Ok(())
+        }
+
+        async fn archived(&self, serial: &[u8]) -> Result<Option<Vec<u8>>> {
+            let row: Option<(Vec<u8>,)> =
+                sqlx::query_as("SELECT data FROM evidence WHERE serial = $1")
+                    .bind(serial)
+                    .fetch_optional(&self.0)
+                    .await?;
+            Ok(row.map(|(data,)| data))
+        }
+
+        async fn purge_archive(&self, before: SystemTime) -> Result<u64> {
+            let done = sqlx::query("DELETE FROM evidence WHERE archived_at < $1")
+                .bind(secs(before))
+                .execute(&self.0)
+                .await?;
+            Ok(done.rows_affected())
+        }
+
+        async fn append_log(&self, serial: &[u8], leaf: &[u8; 32]) -> Result<u64> {
+            // Indices must be dense and assigned in commit order, so appends
+            // are serialized rather than relying on a sequence.
+            let mut tx = self.0.begin().await?;
+            sqlx::query("LOCK TABLE log IN EXCLUSIVE MODE")
+                .execute(&mut *tx)
+                .await?;
+            let (index,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM log")
+                .fetch_one(&mut *tx)
+                .await?;
+            sqlx::query("INSERT INTO log (idx, serial, leaf) VALUES ($1, $2, $3)")
+                .bind(index)
+                .bind(serial)
+                .bind(&leaf[..])
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            Ok(index as u64)
+        }
+
+        async fn log_leaves(&self, from: u64) -> Result<Vec<[u8; 32]>> {
+            let rows: Vec<(Vec<u8>,)> =
+                sqlx::query_as("SELECT leaf FROM log WHERE idx >= $1 ORDER BY idx")
+                    .bind(from as i64)
+                    .fetch_all(&self.0)
+                    .await?;
+
+            rows.into_iter()
+                .map(|(leaf,)| {
+                    leaf.try_into()
+                        .map_err(|_| anyhow::anyhow!("invalid log leaf"))
+                })
+                .collect()
+        }
+
+        async fn log_index(&self, serial: &[u8]) -> Result<Option<u64>> {
+            let row: Option<(i64,)> = sqlx::query_as("SELECT idx FROM log WHERE serial = $1")
+                .bind(serial)
+                .fetch_optional(&self.0)
+                .await?;
+            Ok(row.map(|(idx,)| idx as u64))
+        }
+
+        async fn unpublished(&self, limit: usize) -> Result<Vec<Issued>> {
+            let rows: Vec<Row> = sqlx::query_as(
+                "SELECT serial, not_before, not_after, der, rekor_index, policy_version, \
+                 platform, tenant \
+                 FROM issued WHERE rekor_index IS NULL LIMIT $1",
+            )
+            .bind(limit as i64)
+            .fetch_all(&self.0)
+            .await?;
+            Ok(rows.into_iter().map(issued).collect())
+        }
+
+        async fn published(&self, serial: &[u8], index: u64) -> Result<()> {
+            sqlx::query("UPDATE issued SET rekor_index = $2 WHERE serial = $1")
+                .bind(serial)
+                .bind(index as i64)
+                .execute(&self.0)
+                .await?;
+            Ok(())
+        }
+
+        async fn inventory(
+            &self,
+            (not_before, serial): (SystemTime, &[u8]),
+            limit: usize,
+        ) -> Result<Vec<Issued>> {
+            let rows: Vec<Row> = sqlx::query_as(
+                "SELECT serial, not_before, not_after, der, rekor_index, policy_version, \
+                 platform, tenant \
+                 FROM issued WHERE (not_before, serial) > ($1, $2) \
+                 ORDER BY not_before, serial LIMIT $3",
+            )
+            .bind(secs(not_before))
+            .bind(serial)
+            .bind(limit as i64)
+            .fetch_all(&self.0)
+            .await?;
+            Ok(rows.into_iter().map(issued).collect())
+        }
+
+        async fn tally(
+            &self,
+            since: SystemTime,
+            now: SystemTime,
+            until: SystemTime,
+        ) -> Result<Vec<Tally>> {
+            type Counts = (Option<String>, Option<String>, i64, i64, i64);
+            let rows: Vec<Counts> = sqlx::query_as(
+                "SELECT platform, tenant, \
+                 COUNT(*) FILTER (WHERE not_before >= $1), \
+                 COUNT(*) FILTER (WHERE not_after > $2), \
+                 COUNT(*) FILTER (WHERE not_after > $2 AND not_after <= $3) \
+                 FROM issued WHERE not_before >= $1 OR not_after > $2 \
+                 GROUP BY platform, tenant ORDER BY platform, tenant",
+            )
+            .bind(secs(since))
+            .bind(secs(now))
+            .bind(secs(until))
+            .fetch_all(&self.0)
+            .await?;
+            Ok(rows
+                .into_iter()
+                .map(|(platform, tenant, issued, active, expiring)| Tally {
+                    platform,
+                    tenant,
+                    issued: issued as u64,
+                    active: active as u64,
+                    expiring: expiring as u64,
+                })
+                .collect())
+        }
+
+        async fn consume(&self, allowances: &[Allowance], at: SystemTime) -> Result<bool> {
+            if allowances.is_empty() {
+                return Ok(true);
+            }
+
+            // Counting and recording must not interleave between replicas.
+            let mut tx = self.0.begin().await?;
+            sqlx::query("LOCK TABLE quota IN EXCLUSIVE MODE")
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query("DELETE FROM quota WHERE expires <= $1")
+                .bind(secs(at))
+                .execute(&mut *tx)
+                .await?;
+
+            for allowance in allowances {
+                let (used,): (i64,) =
+                    sqlx::query_as("SELECT COUNT(*) FROM quota WHERE subject = $1")
+                        .bind(&allowance.subject)
+                        .fetch_one(&mut *tx)
+                        .await?;
+                if used as u64 >= allowance.limit {
+                    return Ok(false);
+                }
+            }
+
+            for allowance in allowances {
+                sqlx::query("INSERT INTO quota (subject, expires) VALUES ($1, $2)")
+                    .bind(&allowance.subject)
+                    .bind(secs(at + allowance.window))
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+            Ok(true)
+        }
+
+        async fn link(&self, key: &[u8], link: &Link, _now: SystemTime) -> Result<()> {
+            sqlx::query(
+                "INSERT INTO identities \
+                 (key, serial, not_before, not_after, platform, measurements) \
+                 VALUES ($1, $2, $3, $4, $5, $6)",
+            )
+            .bind(key)
+            .bind(&link.serial)
+            .bind(secs(link.not_before))
+            .bind(secs(link.not_after))
+            .bind(&link.platform)
+            .bind(serde_json::to_string(&link.measurements)?)
+            .execute(&self.0)
+            .await?;
+            Ok(())
+        }
+
+        async fn identity(&self, key: &[u8]) -> Result<Vec<Link>> {
+            type Row = (Vec<u8>, i64, i64, Option<String>, String);
+            let rows: Vec<Row> = sqlx::query_as(
+                "SELECT serial, not_before, not_after, platform, measurements \
+                 FROM identities WHERE key = $1 ORDER BY not_before, serial",
+            )
+            .bind(key)
+            .fetch_all(&self.0)
+            .await?;
+            rows.into_iter()
+                .map(|(serial, nb, na, platform, measurements)| {
+                    Ok(Link {
+                        serial,
+                        not_before: time(nb),
+                        not_after: time(na),
+                        platform,
+                        measurements: serde_json::from_str(&measurements)?,
+                    })
+                })
+                .collect()
+        }
+
+        async fn grant(&self, lease: &Lease) -> Result<()> {
+            sqlx::query(
+                "INSERT INTO leases (key, serial, renew_by) VALUES ($1, $2, $3) \
+                 ON CONFLICT (key) DO UPDATE \
+                 SET serial = EXCLUDED.serial, renew_by = EXCLUDED.renew_by",
+            )
+            .bind(&lease.key)
+            .bind(&lease.serial)
+            .bind(secs(lease.renew_by))
+            .execute(&self.0)
+            .await?;
+            Ok(())
+        }
+
+        async fn lapse(&self, at: SystemTime) -> Result<Vec<Lease>> {
+            // Deleting claims each lapse for exactly one replica.
+            let rows: Vec<(Vec<u8>, Vec<u8>, i64)> = sqlx::query_as(
+                "DELETE FROM leases WHERE renew_by <= $1 RETURNING key, serial, renew_by",
+            )
+            .bind(secs(at))
+            .fetch_all(&self.0)
+            .await?;
+            Ok(rows
+                .into_iter()
+                .map(|(key, serial, renew_by)| Lease {
+                    key,
+                    serial,
+                    renew_by: time(renew_by),
+                })
+                .collect())
+        }
+
+        async fn activate_policy(&self, hash: &[u8; 32], at: SystemTime) -> Result<(u64, bool)> {
+            // Replicas starting together must agree on a single new version.
+            let mut tx = self.0.begin().await?;
+            sqlx::query("LOCK TABLE policies IN EXCLUSIVE MODE")
+                .execute(&mut *tx)
+                .await?;
+            let latest: Option<(i64, Vec<u8>)> = sqlx::query_as(
+                "SELECT version, sha256 FROM policies ORDER BY version DESC LIMIT 1",
+            )
+            .fetch_optional(&mut *tx)
+            .await?;
+            if let Some((version, sha256)) = latest {
+                if sha256 == hash {
+                    return Ok((version as u64, false));
+                }
+            }
+
+            let (version,): (i64,) = sqlx::query_as(
+                "INSERT INTO policies (sha256, activated_at) VALUES ($1, $2) RETURNING version",
+            )
+            .bind(&hash[..])
+            .bind(secs(at))
+            .fetch_one(&mut *tx)
+            .await?;
+            tx.commit().await?;
+            Ok((version as u64, true))
+        }
+
+        async fn set_anchors(&self, platform: &str, anchors: &[Vec<u8>]) -> Result<()> {
+            let mut tx = self.0.begin().await?;
+            sqlx::query("DELETE FROM anchors WHERE platform = $1")
+                .bind(platform)
+                .execute(&mut *tx)
+                .await?;
+            for (position, der) in anchors.iter().enumerate() {
+                sqlx::query("INSERT INTO anchors (platform, position, der) VALUES ($1, $2, $3)")
+                    .bind(platform)
+                    .bind(position as i32)
+                    .bind(der)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+            Ok(())
+        }
+
+        async fn anchors(&self, platform: &str) -> Result<Vec<Vec<u8>>> {
+            let rows: Vec<(Vec<u8>,)> =
+                sqlx::query_as("SELECT der FROM anchors WHERE platform = $1 ORDER BY position")
+                    .bind(platform)
+                    .fetch_all(&self.0)
+                    .await?;
+            Ok(rows.into_iter().map(|(der,)| der).collect())
+        }
+
+        async fn park(&self, parked: &Parked) -> Result<bool> {
+            // An expired request is parked afresh.
+            let (status, response) = columns(&parked.decision);
+            let result = sqlx::query(
+                "INSERT INTO approvals \
+                 (id, received_at, expires_at, request, measurements, profile, status, response) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8) \
+                 ON CONFLICT (id) DO UPDATE \
+                 SET received_at = EXCLUDED.received_at, expires_at = EXCLUDED.expires_at, \
+                 request = EXCLUDED.request, measurements = EXCLUDED.measurements, \
+                 profile = EXCLUDED.profile, status = EXCLUDED.status, \
+                 response = EXCLUDED.response \
+                 WHERE approvals.expires_at <= EXCLUDED.received_at",
+            )
+            .bind(&parked.id)
+            .bind(secs(parked.received_at))
+            .bind(secs(parked.expires_at))
+            .bind(&parked.request)
+            .bind(serde_json::to_string(&parked.measurements)?)
+            .bind(&parked.profile)
+            .bind(status)
+            .bind(response)
+            .execute(&self.0)
+            .await?;
+            Ok(result.rows_affected() == 1)
+        }
+
+        async fn parked(&self, id: &str, now: SystemTime) -> Result<Option<Parked>> {
+            let row: Option<ParkedRow> = sqlx::query_as(
+                "SELECT id, received_at, expires_at, request, measurements, profile, status, \
+                 response FROM approvals WHERE id = $1 AND expires_at > $2",
+            )
+            .bind(id)
+            .bind(secs(now))
+            .fetch_optional(&self.0)
+            .await?;
+            row.map(parked).transpose()
+        }
+
+        async fn pending(&self, now: SystemTime) -> Result<Vec<Parked>> {
+            let rows: Vec<ParkedRow> = sqlx::query_as(
+                "SELECT id, received_at, expires_at, request, measurements, profile, status, \
+                 response FROM approvals WHERE status = 'pending' AND expires_at > $1 \
+                 ORDER BY received_at, id",
+            )
+            .bind(secs(now))
+            .fetch_all(&self.0)
+            .await?;
+            rows.into_iter().map(parked).collect()
+        }
+
+        async fn claim(&self, id: &str) -> Result<bool> {
+            let result = sqlx::query(
+                "UPDATE approvals SET status = 'deciding' WHERE id = $1 AND status = 'pending'",
+            )
+            .bind(id)
+            .execute(&self.0)
+            .await?;
+            Ok(result.rows_affected() == 1)
+        }
+
+        async fn release(&self, id: &str) -> Result<()> {
+            sqlx::query(
+                "UPDATE approvals SET status = 'pending' WHERE id = $1 AND status = 'deciding'",
+            )
+            .bind(id)
+            .execute(&self.0)
+            .await?;
+            Ok(())
+        }
+
+        async fn decide(
+            &self,
+            id: &str,
+            decision: &Decision,
+            fingerprint: &[u8; 32],
+        ) -> Result<bool> {
+            let (status, response) = columns(decision);
+            let mut tx = self.0.begin().await?;
+            let result = sqlx::query(
+                "UPDATE approvals SET status = $2, response = $3 \
+                 WHERE id = $1 AND status = 'deciding'",
+            )
+            .bind(id)
+            .bind(status)
+            .bind(response)
+            .execute(&mut *tx)
+            .await?;
+            if result.rows_affected() != 1 {
+                return Ok(false);
+            }
+            if let Decision::Approved(..) = decision {
+                sqlx::query(
+                    "INSERT INTO approved_measurements (fingerprint) VALUES ($1) \
+                     ON CONFLICT (fingerprint) DO NOTHING",
+                )
+                .bind(&fingerprint[..])
+                .execute(&mut *tx)
+                .await?;
+            }
+            tx.commit().await?;
+            Ok(true)
+        }
+
+        async fn approved(&self, fingerprint: &[u8; 32]) -> Result<bool> {
+            let row: Option<(Vec<u8>,)> = sqlx::query_as(
+                "SELECT fingerprint FROM approved_measurements WHERE fingerprint = $1",
+            )
+            .bind(&fingerprint[..])
+            .fetch_optional(&self.0)
+            .await?;
+            Ok(row.is_some())
+        }
+
+        async fn pin(
+            &self,
+            identity: &str,
+            measurements: &BTreeMap<String, String>,
+            at: SystemTime,
+        ) -> Result<BTreeMap<String, String>> {
+            // Whichever replica pins first, all agree on its measurements.
+            sqlx::query(
+                "INSERT INTO pins (identity, measurements, pinned_at) VALUES ($1, $2, $3) \
+                 ON CONFLICT (identity) DO NOTHING",
+            )
+            .bind(identity)
+            .bind(serde_json::to_string(measurements)?)
+            .bind(secs(at))
+            .execute(&self.0)
+            .await?;
+            let (pinned,): (String,) =
+                sqlx::query_as("SELECT measurements FROM pins WHERE identity = $1")
+                    .bind(identity)
+                    .fetch_one(&self.0)
+                    .await?;
+            Ok(serde_json::from_str(&pinned)?)
+        }
+    }
+
+    type ParkedRow = (
+        String,
+        i64,
+        i64,
+        Vec<u8>,
+        String,
+        Option<String>,
+        String,
+        Option<Vec<u8>>,
+    );
+
+    /// The status and response columns of a decision.
+    fn columns(decision: &Decision) -> (&'static str, Option<&[u8]>) {
+        match decision {
+            Decision::Pending => ("pending", None),
+            Decision::Deciding => ("deciding", None),
+            Decision::Approved(chain) => ("approved", Some(chain)),
+            Decision::Denied => ("denied", None),
+        }
+    }
+
+    fn parked(row: ParkedRow) -> Result<Parked> {
+        let (id, received_at, expires_at, request, measurements, profile, status, response) = row;
+        let decision = match (status.as_str(), response) {
+            ("pending", _) => Decision::Pending,
+            ("deciding", _) => Decision::Deciding,
+            ("approved", Some(chain)) => Decision::Approved(chain),
+            ("denied", _) => Decision::Denied,
+            _ => anyhow::bail!("invalid decision {status} on request {id}"),
+        };
+        Ok(Parked {
+            id,
+            received_at: time(received_at),
+            expires_at: time(expires_at),
+            request,
+            measurements: serde_json::from_str(&measurements)?,
+            profile,
+            decision,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn issued(serial: u8, ttl: Duration) -> Issued {
+        let now = SystemTime::now();
+        Issued {
+            serial: vec![serial],
+            not_before: now,
+            not_after: now + ttl,
+            der: vec![],
+            rekor_index: None,
+            policy_version: 1,
+            platform: None,
+            tenant: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn issue_and_revoke() {
+        let store = Memory::default();
+        let now = SystemTime::now();
+        let crt = issued(1, Duration::from_secs(60));
+        store.issue(&crt, now).await.unwrap();
+        assert_eq!(store.issued(&[1]).await.unwrap(), Some(crt));
+        assert_eq!(store.issued(&[2]).await.unwrap(), None);
+
+        let revocation = Revocation {
+            serial: vec![1],
+            revoked_at: SystemTime::now(),
+            reason: Some(1),
+        };
+        assert!(store.revoke(&revocation).await.unwrap());
+        assert_eq!(store.revocations(now).await.unwrap(), vec![revocation]);
+
+        // Revocations are listed until the certificate expires.
+        let later = now + Duration::from_secs(120);
+        assert!(store.revocations(later).await.unwrap().is_empty());
+
+        let unknown = Revocation {
+            serial: vec![2],
+            revoked_at: SystemTime::now(),
+            reason: None,
+        };
+        assert!(!store.revoke(&unknown).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn prunes_expired() {
+        let store = Memory::default();
+        let now = SystemTime::now();
+        store
+            .issue(&issued(1, Duration::from_secs(60)), now)
+            .await
+            .unwrap();
+        let later = now + Duration::from_secs(120);
+        store
+            .issue(&issued(2, Duration::from_secs(3600)), later)
+            .await
+            .unwrap();
+        assert_eq!(store.issued(&[1]).await.unwrap(), None);
+        assert!(store.issued(&[2]).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn reference_values() {
+        let store = Memory::default();
+        let value = ReferenceValue {
+            platform: "snp".into(),
+            kind: "measurement".into(),
+            value: vec![0; 48],
+        };
+        store.add_reference_value(&value).await.unwrap();
+        store.add_reference_value(&value).await.unwrap();
+        assert_eq!(store.reference_values("snp").await.unwrap(), vec![value]);
+        assert!(store.reference_values("sgx").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn archive() {
+        let store = Memory::default();
+        let now = SystemTime::now();
+        let old = now - Duration::from_secs(60);
+        store.archive(&[1], old, b"old").await.unwrap();
+        store.archive(&[2], now, b"new").await.unwrap();
+        assert_eq!(store.archived(&[1]).await.unwrap(), Some(b"old".to_vec()));
+
+        assert_eq!(store.purge_archive(now).await.unwrap(), 1);
+        assert_eq!(store.archived(&[1]).await.unwrap(), None);
+        assert_eq!(store.archived(&[2]).await.unwrap(), Some(b"new".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn log() {
+        let store = Memory::default();
+        assert_eq!(store.append_log(&[1], &[1; 32]).await.unwrap(), 0);
+        assert_eq!(store.append_log(&[2], &[2; 32]).await.unwrap(), 1);
+        assert_eq!(store.log_index(&[2]).await.unwrap(), Some(1));
+        assert_eq!(store.log_index(&[3]).await.unwrap(), None);
+        assert_eq!(store.log_leaves(1).await.unwrap(), vec![[2; 32]]);
+        assert!(store.log_leaves(5).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rekor() {
+        let store = Memory::default();
+        let now = SystemTime::now();
+        store
+            .issue(&issued(1, Duration::from_secs(60)), now)
+            .await
+            .unwrap();
+        assert_eq!(store.unpublished(10).await.unwrap().len(), 1);
+
+        store.published(&[1], 42).await.unwrap();
+        assert!(store.unpublished(10).await.unwrap().is_empty());
+        let issued = store.issued(&[1]).await.unwrap().unwrap();
+        assert_eq!(issued.rekor_index, Some(42));
+    }
+
+    #[tokio::test]
+    async fn inventory() {
+        let store = Memory::default();
+        let now = SystemTime::now();
+        for serial in [3, 1, 2] {
+            // Certificates issued in the same second are ordered by serial.
+            let crt = Issued {
+                not_before: now,
+                ..issued(serial, Duration::from_secs(60))
+            };
+            store.issue(&crt, now).await.unwrap();
+        }
+        let serials = |page: Vec<Issued>| page.into_iter().map(|i| i.serial[0]).collect::<Vec<_>>();
+
+        let start = (SystemTime::UNIX_EPOCH, &[][..]);
+        assert_eq!(serials(store.inventory(start, 2).await.unwrap()), [1, 2]);
+
+        let after = (now, &[2][..]);
+        assert_eq!(serials(store.inventory(after, 2).await.unwrap()), [3]);
+
+        let later = (now + Duration::from_secs(1), &[][..]);
+        assert!(store.inventory(later, 2).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn tally() {
+        let store = Memory::default();
+        let now = SystemTime::now();
+        for (serial, platform, age, ttl) in [
+            (1, "sgx", 0, 60),
+            (2, "sgx", 7200, 10800),
+            (3, "snp", 0, 7200),
+        ] {
+            let crt = Issued {
+                not_before: now - Duration::from_secs(age),
+                not_after: now - Duration::from_secs(age) + Duration::from_secs(ttl),
+                platform: Some(platform.into()),
+                ..issued(serial, Duration::ZERO)
+            };
+            store.issue(&crt, now).await.unwrap();
+        }
+
+        let since = now - Duration::from_secs(3600);
+        let until = now + Duration::from_secs(3600);
+        let tallies = store.tally(since, now, until).await.unwrap();
+        let counts: Vec<_> = tallies
+            .iter()
+            .map(|t| (t.platform.as_deref(), t.issued, t.active, t.expiring))
+            .collect();
+        assert_eq!(counts, [(Some("sgx"), 1, 2, 2), (Some("snp"), 1, 1, 0)]);
+    }
+
+    #[tokio::test]
+    async fn identities() {
+        let store = Memory::default();
+        let now = SystemTime::now();
+        let link = |serial: u8, age: u64, ttl: u64| Link {
+            serial: vec![serial],
+            not_before: now - Duration::from_secs(age),
+            not_after: now - Duration::from_secs(age) + Duration::from_secs(ttl),
+            platform: Some("sgx".into()),
+            measurements: Default::default(),
+        };
+        store.link(&[1], &link(2, 0, 60), now).await.unwrap();
+        store.link(&[1], &link(1, 60, 120), now).await.unwrap();
+        store.link(&[2], &link(3, 120, 60), now).await.unwrap();
+
+        let history = store.identity(&[1]).await.unwrap();
+        let serials: Vec<_> = history.iter().map(|l| l.serial[0]).collect();
+        assert_eq!(serials, [1, 2]);
+
+        // Identities are forgotten once all their certificates have expired.
+        store.link(&[3], &link(4, 0, 60), now).await.unwrap();
+        assert!(store.identity(&[2]).await.unwrap().is_empty());
+        assert_eq!(store.identity(&[1]).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn leases() {
+        let store = Memory::default();
+        let now = SystemTime::now();
+        let lease = |key: u8, serial: u8, secs: u64| Lease {
+            key: vec![key],
+            serial: vec![serial],
+            renew_by: now + Duration::from_secs(secs),
+        };
+        store.grant(&lease(1, 1, 10)).await.unwrap();
+        store.grant(&lease(2, 2, 10)).await.unwrap();
+        store.grant(&lease(1, 3, 30)).await.unwrap();
+
+        let at = now + Duration::from_secs(20);
+        assert_eq!(store.lapse(at).await.unwrap(), [lease(2, 2, 10)]);
+        assert!(store.lapse(at).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn quota() {
+        let store = Memory::default();
+        let now = SystemTime::now();
+        let chip = Allowance {
+            subject: "chip".into(),
+            window: Duration::from_secs(60),
+            limit: 2,
+        };
+        let measurement = Allowance {
+            subject: "measurement".into(),
+            window: Duration::from_secs(60),
+            limit: 1,
+        };
+
+        assert!(store.consume(&[chip.clone()], now).await.unwrap());
+        assert!(store.consume(&[chip.clone()], now).await.unwrap());
+        assert!(!store.consume(&[chip.clone()], now).await.unwrap());
+
+        // Nothing is counted when any allowance is exhausted.
+        assert!(store.consume(&[measurement.clone()], now).await.unwrap());
+        let both = [chip.clone(), measurement];
+        assert!(!store.consume(&both, now).await.unwrap());
+
+        // Issuances leave the window.
+        let later = now + Duration::from_secs(60);
+        assert!(store.consume(&both, later).await.unwrap());
+        assert!(store.consume(&[], later).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn policies() {
+        let store = Memory::default();
+        let now = SystemTime::now();
+        let (a, b) = ([1; 32], [2; 32]);
+        assert_eq!(store.activate_policy(&a, now).await.unwrap(), (1, true));
+        assert_eq!(store.activate_policy(&a, now).await.unwrap(), (1, false));
+        assert_eq!(store.activate_policy(&b, now).await.unwrap(), (2, true));
+
+        // Going back to an earlier policy is a change too.
+        assert_eq!(store.activate_policy(&a, now).await.unwrap(), (3, true));
+    }
+
+    #[tokio::test]
+    async fn anchors() {
+        let store = Memory::default();
+        assert!(store.anchors("sgx").await.unwrap().is_empty());
+
+        store.set_anchors("sgx", &[vec![1], vec![2]]).await.unwrap();
+        assert_eq!(store.anchors("sgx").await.unwrap(), [vec![1], vec![2]]);
+        assert!(store.anchors("snp").await.unwrap().is_empty());
+
+        // Rotation replaces every anchor, and clearing them reverts.
+        store.set_anchors("sgx", &[vec![3]]).await.unwrap();
+        assert_eq!(store.anchors("sgx").await.unwrap(), [vec![3]]);
+        store.set_anchors("sgx", &[]).await.unwrap();
+        assert!(store.anchors("sgx").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn approvals() {
+        let store = Memory::default();
+        let now = SystemTime::now();
+        let parked = |id: &str, at: SystemTime| Parked {
+            id: id.into(),
+            received_at: at,
+            expires_at: at + Duration::from_secs(60),
+            request: vec![1],
+            measurements: [("snp.measurement".into(), "00".into())].into(),
+            profile: None,
+            decision: Decision::Pending,
+        };
+
+        assert!(store.park(&parked("b", now)).await.unwrap());
+        assert!(store.park(&parked("a", now)).await.unwrap());
+        assert!(!store.park(&parked("a", now)).await.unwrap());
+        let pending = store.pending(now).await.unwrap();
+        let ids: Vec<_> = pending.iter().map(|p| p.id.as_str()).collect();
+        assert_eq!(ids, ["a", "b"]);
+
+        // Each request is claimed, then decided, once.
+        let fingerprint = [7; 32];
+        let approved = Decision::Approved(vec![2]);
+        assert!(!store.decide("a", &approved, &fingerprint).await.unwrap());
+        assert!(store.claim("a").await.unwrap());
+        assert!(!store.claim("a").await.unwrap());
+        assert!(!store.claim("c").await.unwrap());
+        assert_eq!(store.pending(now).await.unwrap().len(), 1);
+        assert!(store.decide("a", &approved, &fingerprint).await.unwrap());
+        assert!(!store
+            .decide("a", &Decision::Denied, &fingerprint)
+            .await
+            .unwrap());
+        let a = store.parked("a", now).await.unwrap().unwrap();
+        assert_eq!(a.decision, approved);
+        assert!(store.approved(&fingerprint).await.unwrap());
+
+        // A claim given up leaves the request to be decided on again, and
+        // denying it approves nothing.
+        let other = [8; 32];
+        assert!(store.claim("b").await.unwrap());
+        store.release("b").await.unwrap();
+        assert_eq!(store.pending(now).await.unwrap().len(), 1);
+        assert!(store.claim("b").await.unwrap());
+        assert!(store.decide("b", &Decision::Denied, &other).await.unwrap());
+        assert!(!store.approved(&other).await.unwrap());
+
+        // Until it expires, when it may be parked afresh.
+        let later = now + Duration::from_secs(60);
+        assert_eq!(store.parked("a", later).await.unwrap(), None);
+        assert!(store.pending(later).await.unwrap().is_empty());
+        assert!(store.park(&parked("a", later)).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn pins() {
+        let store = Memory::default();
+        let now = SystemTime::now();
+        let a: BTreeMap<_, _> = [("snp.measurement".into(), "aa".into())].into();
+        let b: BTreeMap<_, _> = [("snp.measurement".into(), "bb".into())].into();
+
+        // The first measurements pinned to an identity stay pinned.
+        assert_eq!(store.pin("one", &a, now).await.unwrap(), a);
+        assert_eq!(store.pin("one", &b, now).await.unwrap(), a);
+        assert_eq!(store.pin("two", &b, now).await.unwrap(), b);
+    }
+}
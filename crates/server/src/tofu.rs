@@ -0,0 +1,226 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Trusting measurements on first use.
+//!
+//! Fleets whose measurements are not known in advance may, given a `[tofu]`
+//! section in the policy, trust whatever a workload first attests. The first
+//! time evidence verifies with a measurement which is not yet allowed, it is
+//! added to the allowed reference values of its platform, raising a `tofu`
+//! audit event. The measurements are pinned to the workload identity too, so
+//! that the workload is refused if it later attests different ones:
+//!
+//! ```toml
+//! [tofu]
+//! measurements = ["snp.measurement"]
+//! identity = ["snp.author_key_digest"]
+//! ```
+//!
+//! `measurements` names those which are trusted on first use, by default
+//! those in [`approvals::MEASUREMENTS`]. A workload is identified by its
+//! certified key, as for [`identities`], unless `identity` names measurements
+//! which identify it instead, such as the key which signed it; workloads
+//! attesting none of them fall back to their key. Naming a signer holds every
+//! workload it signs to the first measurements seen, until their pin is
+//! removed from the store.
+
+use super::store::{AuditRecord, ReferenceValue};
+use super::verifier::Appraisal;
+use super::{approvals, identities, State};
+
+use std::collections::BTreeMap;
+
+use anyhow::{ensure, Context, Result};
+use hyper::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{debug, warn};
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    /// The measurements trusted on first use, such as `snp.measurement`.
+    pub measurements: Option<Vec<String>>,
+
+    /// The measurements which identify a workload in place of its key.
+    pub identity: Option<Vec<String>>,
+}
+
+impl Policy {
+    /// Refuses policies which would trust nothing, or identify nothing.
+    pub fn validate(&self) -> Result<()> {
+        for (names, what) in [
+            (&self.measurements, "measurement"),
+            (&self.identity, "identity"),
+        ] {
+            if let Some(names) = names {
+                ensure!(!names.is_empty(), "tofu must name at least one {what}");
+                for name in names {
+                    ensure!(
+                        name.split_once('.').is_some(),
+                        "tofu {what} {name} must be named by platform, such as snp.measurement"
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The measurements of `appraisals` which are trusted on first use.
+    fn measurements(&self, appraisals: &[Appraisal]) -> BTreeMap<String, String> {
+        let trusted = |name: &String| match &self.measurements {
+            Some(names) => names.contains(name),
+            None => approvals::MEASUREMENTS.contains(&name.as_str()),
+        };
+        identities::measurements(appraisals)
+            .into_iter()
+            .filter(|(name, _)| trusted(name))
+            .collect()
+    }
+
+    /// Names the workload which attested `appraisals` for the DER `spki`.
+    fn identity(&self, spki: &[u8], appraisals: &[Appraisal]) -> String {
+        let measurements = identities::measurements(appraisals);
+        let named: Vec<_> = self
+            .identity
+            .iter()
+            .flatten()
+            .filter_map(|name| Some(format!("{name}={}", measurements.get(name)?)))
+            .collect();
+        match named.is_empty() {
+            true => format!("key={}", hex::encode(identities::digest(spki))),
+            false => named.join(","),
+        }
+    }
+}
+
+/// Trusts the measurements of `appraisals` on first use, and refuses them if
+/// they differ from those first seen for the same workload.
+pub(crate) async fn check(
+    state: &State,
+    policy: &Policy,
+    spki: &[u8],
+    appraisals: &[Appraisal],
+) -> Result<(), StatusCode> {
+    let measurements = policy.measurements(appraisals);
+    if measurements.is_empty() {
+        return Ok(());
+    }
+
+    let identity = policy.identity(spki, appraisals);
+    let failed = |e: anyhow::Error| {
+        debug!("failed to trust measurements of {identity} on first use: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    let now = state.clock.now();
+    let pinned = state.store.pin(&identity, &measurements, now).await;
+    let changed = identities::changed(&pinned.map_err(failed)?, &measurements);
+    if !changed.is_empty() {
+        let changed = changed.join(", ");
+        debug!("{identity} attested other {changed} than first seen");
+        let reason = format!("measurements differ from those first seen: {changed}");
+        return Err(state.reject(StatusCode::UNAUTHORIZED, reason));
+    }
+
+    for (name, value) in &measurements {
+        trust(state, &identity, name, value).await.map_err(failed)?;
+    }
+    Ok(())
+}
+
+/// Allows the hex measurement `value` named `name`, unless it already is.
+async fn trust(state: &State, identity: &str, name: &str, value: &str) -> Result<()> {
+    let (platform, kind) = name.split_once('.').context("unqualified measurement")?;
+    let reference = ReferenceValue {
+        platform: platform.into(),
+        kind: kind.into(),
+        value: hex::decode(value)?,
+    };
+    if state
+        .store
+        .reference_values(platform)
+        .await?
+        .contains(&reference)
+    {
+        return Ok(());
+    }
+    state.store.add_reference_value(&reference).await?;
+
+    let record = AuditRecord {
+        at: state.clock.now(),
+        event: "tofu".into(),
+        detail: format!("trusted {name} {value} on first use by {identity}"),
+    };
+    state.store.audit(&record).await?;
+    state.notify(
+        &record.event,
+        json!({ "identity": identity, "measurement": name, "value": value }),
+    );
+    warn!("{}", record.detail);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snp(measurement: u8, signer: u8) -> Vec<Appraisal> {
+        vec![Appraisal::new("snp", true)
+            .with_measurement("measurement", &[measurement; 48])
+            .with_measurement("author_key_digest", &[signer; 48])]
+    }
+
+    #[test]
+    fn validate() {
+        let policy: Policy = toml::from_str("").unwrap();
+        policy.validate().unwrap();
+
+        for toml in [
+            "measurements = []",
+            "identity = []",
+            "identity = [\"mrsigner\"]",
+        ] {
+            let policy: Policy = toml::from_str(toml).unwrap();
+            assert!(policy.validate().is_err(), "{toml}");
+        }
+    }
+
+    #[tokio::test]
+    async fn keys() {
+        let state = State::generate(None, "localhost").unwrap();
+        let policy = Policy::default();
+
+        // Whatever a key first attests is trusted, and allowed.
+        check(&state, &policy, b"one", &snp(1, 0)).await.unwrap();
+        check(&state, &policy, b"one", &snp(1, 0)).await.unwrap();
+        let allowed = state.store.reference_values("snp").await.unwrap();
+        assert_eq!(allowed.len(), 1);
+        assert_eq!(allowed[0].kind, "measurement");
+        assert_eq!(allowed[0].value, [1; 48]);
+
+        // But nothing else, though other keys may attest it.
+        let status = check(&state, &policy, b"one", &snp(2, 0)).await;
+        assert_eq!(status, Err(StatusCode::UNAUTHORIZED));
+        check(&state, &policy, b"two", &snp(2, 0)).await.unwrap();
+        let allowed = state.store.reference_values("snp").await.unwrap();
+        assert_eq!(allowed.len(), 2);
+
+        // Evidence without the measurements is left alone.
+        let kvm = [Appraisal::new("kvm", false)];
+        check(&state, &policy, b"one", &kvm).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn signers() {
+        let state = State::generate(None, "localhost").unwrap();
+        let policy: Policy = toml::from_str("identity = [\"snp.author_key_digest\"]").unwrap();
+        policy.validate().unwrap();
+
+        // Every workload of a signer is held to what it first signed.
+        check(&state, &policy, b"one", &snp(1, 7)).await.unwrap();
+        check(&state, &policy, b"two", &snp(1, 7)).await.unwrap();
+        let status = check(&state, &policy, b"three", &snp(2, 7)).await;
+        assert_eq!(status, Err(StatusCode::UNAUTHORIZED));
+        check(&state, &policy, b"three", &snp(2, 8)).await.unwrap();
+    }
+}
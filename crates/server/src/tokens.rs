@@ -0,0 +1,395 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Enrollment of hosts without a TEE by one-time registration tokens.
+//!
+//! Mixed fleets have hosts which cannot attest. Given a `[tokens]` section in
+//! the policy, operators mint a registration token for such a host at
+//! `POST /admin/tokens`, naming it, and deliver the token out of band. The
+//! host presents it, as the UTF-8 value of the [`OID`] extension, in place of
+//! evidence. Each token is redeemed once, before it expires, when a
+//! certificate is issued on it. Until then it is held for the key of the
+//! first request presenting it, which may be sent again should issuance be
+//! refused for other reasons:
+//!
+//! ```toml
+//! [tokens]
+//! ttl = 86400
+//! ```
+//!
+//! Tokens count as the `token` platform in the platform policy, so that it
+//! may demand hardware evidence besides. Certificates issued on a token are
+//! marked as such in their [`claims`](super::claims) extension.
+
+use super::store::AuditRecord;
+use super::verifier::{Appraisal, Appraiser, ExtVerifier, Redemption};
+use super::{admin, Config, State};
+
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{anyhow, ensure, Result};
+use async_trait::async_trait;
+use axum::body::Bytes;
+use axum::extract::{Extension, TypedHeader};
+use axum::headers::authorization::{Authorization, Bearer};
+use axum::Json;
+use const_oid::ObjectIdentifier;
+use der::Encode;
+use hyper::StatusCode;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tracing::{debug, info};
+use x509::ext::Extension as X509Extension;
+use x509::request::CertReqInfo;
+
+/// The certification request extension carrying a registration token.
+pub const OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.58270.1.4");
+
+/// The platform name of registration tokens.
+pub const PLATFORM: &str = "token";
+
+/// The default lifetime of registration tokens.
+const TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The longest lifetime which may be configured for registration tokens.
+const MAX_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// How long a token is held for the request which first presented it.
+const HOLD: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    /// The lifetime, in seconds, of registration tokens.
+    pub ttl: Option<u64>,
+}
+
+impl Policy {
+    /// The lifetime of registration tokens.
+    pub fn ttl(&self) -> Duration {
+        self.ttl.map_or(TTL, Duration::from_secs)
+    }
+
+    /// Refuses tokens which would linger.
+    pub fn validate(&self) -> Result<()> {
+        let ttl = self.ttl();
+        ensure!(
+            !ttl.is_zero() && ttl <= MAX_TTL,
+            "registration token lifetime must be between 1 and {} seconds",
+            MAX_TTL.as_secs()
+        );
+        Ok(())
+    }
+}
+
+/// A registration token, as returned to the operator who minted it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Registration {
+    pub token: String,
+
+    /// The name of the host the token is for.
+    pub name: String,
+
+    /// Seconds since the Unix epoch.
+    pub expires_at: u64,
+}
+
+/// What is kept of a registration until it is redeemed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Pending {
+    name: String,
+    expires_at: u64,
+}
+
+/// The shared key under which the registration of a token is kept.
+fn key(token: &[u8]) -> String {
+    format!("registration/token/{}", hex::encode(Sha256::digest(token)))
+}
+
+/// The shared key marking a token as redeemed.
+fn redeemed(token: &[u8]) -> String {
+    format!(
+        "registration/redeemed/{}",
+        hex::encode(Sha256::digest(token))
+    )
+}
+
+/// The shared key holding a token for the request presenting it.
+fn held(token: &[u8]) -> String {
+    format!("registration/held/{}", hex::encode(Sha256::digest(token)))
+}
+
+/// Mints a registration token for the host `name`.
+pub(crate) async fn mint(state: &State, name: &str) -> Result<Registration> {
+    let policy = state.policy();
+    let tokens = policy
+        .config
+        .tokens
+        .as_ref()
+        .ok_or_else(|| anyhow!("registration tokens are not accepted"))?;
+    ensure!(
+        !name.is_empty() && name.len() <= 255,
+        "host name must be 1 to 255 bytes"
+    );
+
+    // Only the digest of the token is kept, alongside its registration.
+    let token = hex::encode(rand::thread_rng().gen::<[u8; 32]>());
+    let ttl = tokens.ttl();
+    let expires_at = (state.clock.now() + ttl)
+        .duration_since(UNIX_EPOCH)?
+        .as_secs();
+    let pending = Pending {
+        name: name.into(),
+        expires_at,
+    };
+    let value = serde_json::to_vec(&pending)?;
+    state
+        .shared
+        .put(&key(token.as_bytes()), &value, ttl)
+        .await?;
+
+    let record = AuditRecord {
+        at: state.clock.now(),
+        event: "registration".into(),
+        detail: format!("minted registration token for {name}"),
+    };
+    state.store.audit(&record).await?;
+    state.notify(
+        &record.event,
+        json!({ "name": name, "expires_at": expires_at }),
+    );
+    info!("{}", record.detail);
+
+    Ok(Registration {
+        token,
+        name: name.into(),
+        expires_at,
+    })
+}
+
+/// Holds the registration token `token` for the request with the DER public
+/// key `spki`, returning the name of its host.
+async fn hold(state: &State, config: &Config, token: &[u8], spki: &[u8]) -> Result<String> {
+    config
+        .tokens
+        .as_ref()
+        .ok_or_else(|| anyhow!("registration tokens are not accepted"))?;
+    let value = state
+        .shared
+        .get(&key(token))
+        .await?
+        .ok_or_else(|| anyhow!("unknown registration token"))?;
+    let pending: Pending = serde_json::from_slice(&value)?;
+    let now = state.clock.now().duration_since(UNIX_EPOCH)?.as_secs();
+    ensure!(now < pending.expires_at, "expired registration token");
+    let spent = state.shared.get(&redeemed(token)).await?;
+    ensure!(spent.is_none(), "registration token already redeemed");
+
+    // Replicas sharing the backend agree on the one request holding it.
+    let holder = Sha256::digest(spki);
+    let shared = &state.shared;
+    let fresh = shared.put_new(&held(token), &holder, HOLD).await?;
+    let ours = fresh || shared.get(&held(token)).await?.as_deref() == Some(&holder[..]);
+    ensure!(ours, "registration token held by another request");
+    Ok(pending.name)
+}
+
+/// The body of `POST /admin/tokens`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Mint {
+    name: String,
+}
+
+/// Mints a registration token for the host named in the request.
+pub async fn tokens(
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(state): Extension<Arc<State>>,
+    body: Bytes,
+) -> Result<Json<Registration>, StatusCode> {
+    admin::authorize(&state, auth, false).await?;
+    if state.policy().config.tokens.is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let request: Mint = serde_json::from_slice(&body).or(Err(StatusCode::BAD_REQUEST))?;
+    mint(&state, &request.name).await.map(Json).map_err(|e| {
+        debug!("failed to mint registration token: {e:#}");
+        StatusCode::BAD_REQUEST
+    })
+}
+
+/// The claims about a host enrolled on the registration token appraised.
+pub fn claims(appraisal: &Appraisal) -> Result<Vec<(String, String)>> {
+    let name = match appraisal.measurements.get("registration") {
+        Some(hex) => String::from_utf8(hex::decode(hex)?)?,
+        None => return Ok(vec![]),
+    };
+
+    Ok(vec![
+        ("enrollment".into(), "registration-token".into()),
+        ("registration".into(), name),
+    ])
+}
+
+/// Accepts registration tokens in place of evidence.
+#[derive(Debug)]
+pub struct TokenVerifier;
+
+#[async_trait]
+impl ExtVerifier for TokenVerifier {
+    fn platform(&self) -> &'static str {
+        PLATFORM
+    }
+
+    async fn prepare<'a>(
+        &'a self,
+        state: &'a State,
+        config: &'a Config,
+        cri: &'a CertReqInfo<'_>,
+        ext: &'a X509Extension<'_>,
+        _dbg: bool,
+    ) -> Result<Appraiser<'a>, StatusCode> {
+        // Holding here, not in the appraisal, keeps it out of the cache.
+        let spki = cri.public_key.to_vec().or(Err(StatusCode::BAD_REQUEST))?;
+        let name = hold(state, config, ext.extn_value, &spki)
+            .await
+            .map_err(|e| {
+                debug!("registration token refused: {e:#}");
+                StatusCode::UNAUTHORIZED
+            })?;
+        Ok(Box::new(move || {
+            let mut appraisal =
+                Appraisal::new(PLATFORM, true).with_measurement("registration", name.as_bytes());
+            appraisal.decide("registration token held");
+            Ok(appraisal)
+        }))
+    }
+
+    fn redemption(&self, config: &Config, ext: &X509Extension<'_>) -> Option<Redemption> {
+        config.tokens.as_ref().map(|tokens| Redemption {
+            key: redeemed(ext.extn_value),
+            ttl: tokens.ttl(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::admin::Auditor;
+    use super::super::claims::{self, Claim};
+    use super::super::{app, operations, PKCS10, PROFILE_HEADER};
+    use super::*;
+
+    use attestation::crypto::{CertReqInfoExt, PrivateKeyInfoExt};
+    use const_oid::db::rfc5912::{ID_EXTENSION_REQ, SECP_256_R_1};
+    use der::asn1::AnyRef;
+    use der::Decode;
+    use http::header::{AUTHORIZATION, CONTENT_TYPE};
+    use http::Request;
+    use hyper::Body;
+    use sec1::pkcs8::PrivateKeyInfo;
+    use tower::ServiceExt; // for `app.oneshot()`
+    use x509::attr::Attribute;
+    use x509::name::RdnSequence;
+    use x509::request::{ExtensionReq, Version};
+    use x509::{Certificate, PkiPath};
+
+    /// A certification request presenting `token`.
+    fn cr(token: &str) -> Vec<u8> {
+        let key = PrivateKeyInfo::generate(SECP_256_R_1).unwrap();
+        let pki = PrivateKeyInfo::from_der(key.as_ref()).unwrap();
+        let ext = X509Extension {
+            extn_id: OID,
+            critical: false,
+            extn_value: token.as_bytes(),
+        };
+        let req = ExtensionReq::from(vec![ext]).to_vec().unwrap();
+        let attribute = Attribute {
+            oid: ID_EXTENSION_REQ,
+            values: vec![AnyRef::from_der(&req).unwrap()].try_into().unwrap(),
+        };
+        CertReqInfo {
+            version: Version::V1,
+            subject: RdnSequence::default(),
+            public_key: pki.public_key().unwrap(),
+            attributes: vec![attribute].try_into().unwrap(),
+        }
+        .sign(&pki)
+        .unwrap()
+    }
+
+    #[test]
+    fn validate() {
+        let policy: Policy = toml::from_str("").unwrap();
+        assert_eq!(policy.ttl(), TTL);
+        policy.validate().unwrap();
+
+        for ttl in [0, MAX_TTL.as_secs() + 1] {
+            let policy = Policy { ttl: Some(ttl) };
+            assert!(policy.validate().is_err(), "{ttl}");
+        }
+    }
+
+    #[tokio::test]
+    async fn enroll() {
+        let mut state = State::generate(None, "localhost")
+            .unwrap()
+            .with_auditor(Auditor::new("auditor").unwrap());
+
+        // Tokens are only minted, or accepted, when the policy says so.
+        assert!(mint(&state, "host-1").await.is_err());
+        state.config_mut().tokens = Some(Policy::default());
+        let registration = mint(&state, "host-1").await.unwrap();
+        assert_eq!(registration.name, "host-1");
+
+        let attest = |request: &[u8], profile: Option<&str>| {
+            let mut builder = Request::post("/").header(CONTENT_TYPE, PKCS10);
+            if let Some(profile) = profile {
+                builder = builder.header(PROFILE_HEADER, profile);
+            }
+            let request = builder.body(Body::from(request.to_vec())).unwrap();
+            app(state.clone()).oneshot(request)
+        };
+
+        // A token is not redeemed by a request refused for other reasons,
+        // which holds it against any other.
+        let request = cr(&registration.token);
+        let rsp = attest(&request, Some("missing")).await.unwrap();
+        assert_eq!(rsp.status(), StatusCode::FORBIDDEN);
+        let rsp = attest(&cr(&registration.token), None).await.unwrap();
+        assert_eq!(rsp.status(), StatusCode::UNAUTHORIZED);
+
+        let rsp = attest(&request, None).await.unwrap();
+        assert_eq!(rsp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(rsp.into_body()).await.unwrap();
+        let path = PkiPath::from_der(&body).unwrap();
+        let crt: &Certificate<'_> = path.last().unwrap();
+        let extensions = crt.tbs_certificate.extensions.as_ref().unwrap();
+        let claims = extensions
+            .iter()
+            .find(|e| e.extn_id == claims::OID)
+            .unwrap();
+        let claims = Vec::<Claim<'_>>::from_der(claims.extn_value).unwrap();
+        assert_eq!(claims[0].value.as_str(), "registration-token");
+        assert_eq!(claims[1].value.as_str(), "host-1");
+
+        // Tokens are single use, and never copied into certificates.
+        assert!(extensions.iter().all(|e| e.extn_id != OID));
+        for request in [request, cr(&registration.token), cr("guess")] {
+            let rsp = attest(&request, None).await.unwrap();
+            assert_eq!(rsp.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        // Auditors may not mint tokens.
+        let request = Request::post("/admin/tokens")
+            .header(AUTHORIZATION, "Bearer auditor")
+            .body(Body::from(r#"{"name": "host-2"}"#))
+            .unwrap();
+        let rsp = operations(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(rsp.status(), StatusCode::UNAUTHORIZED);
+    }
+}
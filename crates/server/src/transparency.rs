@@ -0,0 +1,278 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! An append-only Merkle tree log of issued certificates.
+//!
+//! Hashing follows RFC 6962: leaves are `SHA-256(0x00 || certificate)` and
+//! interior nodes `SHA-256(0x01 || left || right)`. Tree heads are signed
+//! with the CA key over the RFC 6962 `TreeHeadSignature` structure, so that
+//! monitors can hold the CA to everything it has signed.
+
+use super::State;
+
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Result;
+use attestation::crypto::PrivateKeyInfoExt;
+use axum::extract::{Extension, Path};
+use axum::Json;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use der::Decode;
+use hyper::StatusCode;
+use sec1::pkcs8::PrivateKeyInfo;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use tracing::debug;
+
+pub type Hash = [u8; 32];
+
+/// Hashes a log entry.
+pub fn leaf_hash(entry: &[u8]) -> Hash {
+    Sha256::new()
+        .chain_update([0])
+        .chain_update(entry)
+        .finalize()
+        .into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    Sha256::new()
+        .chain_update([1])
+        .chain_update(left)
+        .chain_update(right)
+        .finalize()
+        .into()
+}
+
+/// The largest power of two smaller than `n` (which must be at least 2).
+fn split(n: usize) -> usize {
+    1 << (usize::BITS - (n - 1).leading_zeros() - 1)
+}
+
+/// Computes the root of the tree over `leaves`.
+pub fn root(leaves: &[Hash]) -> Hash {
+    match leaves.len() {
+        0 => Sha256::digest(b"").into(),
+        1 => leaves[0],
+        n => {
+            let (left, right) = leaves.split_at(split(n));
+            node_hash(&root(left), &root(right))
+        }
+    }
+}
+
+/// Computes the inclusion proof (audit path) of leaf `index`.
+pub fn path(index: usize, leaves: &[Hash]) -> Vec<Hash> {
+    if leaves.len() <= 1 {
+        return Vec::new();
+    }
+
+    let (left, right) = leaves.split_at(split(leaves.len()));
+    let (mut proof, sibling) = if index < left.len() {
+        (path(index, left), root(right))
+    } else {
+        (path(index - left.len(), right), root(left))
+    };
+    proof.push(sibling);
+    proof
+}
+
+/// Checks an inclusion proof as described in RFC 9162, section 2.1.3.2.
+pub fn verify(index: u64, size: u64, leaf: &Hash, proof: &[Hash], root: &Hash) -> bool {
+    if index >= size {
+        return false;
+    }
+
+    let (mut fnode, mut snode) = (index, size - 1);
+    let mut hash = *leaf;
+    for p in proof {
+        if snode == 0 {
+            return false;
+        }
+
+        if fnode & 1 == 1 || fnode == snode {
+            hash = node_hash(p, &hash);
+            while fnode & 1 == 0 && fnode != 0 {
+                fnode >>= 1;
+                snode >>= 1;
+            }
+        } else {
+            hash = node_hash(&hash, p);
+        }
+
+        fnode >>= 1;
+        snode >>= 1;
+    }
+
+    snode == 0 && hash == *root
+}
+
+/// A local copy of the log's leaves, synchronized from the store.
+#[derive(Debug, Default)]
+pub struct Log(Mutex<Vec<Hash>>);
+
+impl Log {
+    async fn leaves(&self, state: &State) -> Result<Vec<Hash>> {
+        let mut leaves = self.0.lock().await;
+        let new = state.store.log_leaves(leaves.len() as u64).await?;
+        leaves.extend(new);
+        Ok(leaves.clone())
+    }
+}
+
+/// A signed tree head.
+#[derive(Clone, Debug, Serialize)]
+pub struct TreeHead {
+    pub tree_size: u64,
+
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+
+    /// Base64 root hash.
+    pub sha256_root_hash: String,
+
+    /// The OID of the signature algorithm.
+    pub signature_algorithm: String,
+
+    /// Base64 signature over the RFC 6962 `TreeHeadSignature`.
+    pub tree_head_signature: String,
+}
+
+/// An inclusion proof for a certificate.
+#[derive(Clone, Debug, Serialize)]
+pub struct Inclusion {
+    pub leaf_index: u64,
+
+    /// Base64 hashes from the leaf towards the root.
+    pub audit_path: Vec<String>,
+
+    /// The tree head the proof leads to.
+    pub sth: TreeHead,
+}
+
+fn sign(state: &State, leaves: &[Hash]) -> Result<TreeHead> {
+    const V1: u8 = 0;
+    const TREE_HASH: u8 = 1;
+
+    let tree_size = leaves.len() as u64;
+    let root = root(leaves);
+    let timestamp = state
+        .clock
+        .now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+
+    let mut body = vec![V1, TREE_HASH];
+    body.extend_from_slice(&timestamp.to_be_bytes());
+    body.extend_from_slice(&tree_size.to_be_bytes());
+    body.extend_from_slice(&root);
+
+    let pki = PrivateKeyInfo::from_der(&state.key)?;
+    let algo = pki.signs_with()?;
+    let signature = pki.sign(&body, algo)?;
+
+    Ok(TreeHead {
+        tree_size,
+        timestamp,
+        sha256_root_hash: BASE64.encode(root),
+        signature_algorithm: algo.oid.to_string(),
+        tree_head_signature: BASE64.encode(signature),
+    })
+}
+
+fn internal(e: anyhow::Error) -> StatusCode {
+    debug!("transparency log failure: {e}");
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+/// Returns the current signed tree head.
+pub async fn sth(Extension(state): Extension<Arc<State>>) -> Result<Json<TreeHead>, StatusCode> {
+    let leaves = state.log.leaves(&state).await.map_err(internal)?;
+    sign(&state, &leaves).map(Json).map_err(internal)
+}
+
+/// Returns the inclusion proof for the certificate with the hex `serial`.
+pub async fn proof(
+    Path(serial): Path<String>,
+    Extension(state): Extension<Arc<State>>,
+) -> Result<Json<Inclusion>, StatusCode> {
+    let serial = hex::decode(serial).or(Err(StatusCode::BAD_REQUEST))?;
+    let index = state
+        .store
+        .log_index(&serial)
+        .await
+        .map_err(internal)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let leaves = state.log.leaves(&state).await.map_err(internal)?;
+    if index >= leaves.len() as u64 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let audit_path = path(index as usize, &leaves)
+        .iter()
+        .map(|h| BASE64.encode(h))
+        .collect();
+    let sth = sign(&state, &leaves).map_err(internal)?;
+
+    Ok(Json(Inclusion {
+        leaf_index: index,
+        audit_path,
+        sth,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(n: u8) -> Vec<Hash> {
+        (0..n).map(|i| leaf_hash(&[i])).collect()
+    }
+
+    #[test]
+    fn split() {
+        assert_eq!(super::split(2), 1);
+        assert_eq!(super::split(3), 2);
+        assert_eq!(super::split(4), 2);
+        assert_eq!(super::split(5), 4);
+        assert_eq!(super::split(8), 4);
+        assert_eq!(super::split(9), 8);
+    }
+
+    #[test]
+    fn empty() {
+        // RFC 6962 test vector.
+        assert_eq!(
+            hex::encode(root(&[])),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn roots() {
+        let l = leaves(3);
+        assert_eq!(root(&l[..1]), l[0]);
+        assert_eq!(root(&l[..2]), node_hash(&l[0], &l[1]));
+        assert_eq!(root(&l), node_hash(&node_hash(&l[0], &l[1]), &l[2]));
+    }
+
+    #[test]
+    fn inclusion() {
+        for n in 1..=17 {
+            let l = leaves(n);
+            let r = root(&l);
+            for i in 0..l.len() {
+                let p = path(i, &l);
+                assert!(verify(i as u64, n as u64, &l[i], &p, &r), "{i}/{n}");
+
+                let other = leaf_hash(b"other");
+                assert!(!verify(i as u64, n as u64, &other, &p, &r));
+            }
+        }
+    }
+}
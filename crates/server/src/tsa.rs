@@ -0,0 +1,471 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! A timestamping authority (RFC 3161) at `/tsa`.
+//!
+//! Workloads may have evidence and artifacts they produce stamped with a
+//! time which verifies up to steward's CA. Timestamps are signed by a key
+//! whose certificate the CA has issued for time stamping alone, and are
+//! issued under the TSA policy given with it.
+//!
+//! Requests must hash with SHA-256, SHA-384 or SHA-512 and carry no
+//! extensions. The nonce, if any, is echoed and the TSA certificate is
+//! included when asked for. Times are to the second, and unordered.
+
+use super::asn1::{
+    attribute, constructed, content_info, elements, issuer_and_serial, named_bit, oid, sequence,
+    set, tag, tlv, BOOLEAN, ID_CONTENT_TYPE, ID_MESSAGE_DIGEST, ID_SIGNED_DATA, INTEGER,
+    OBJECT_IDENTIFIER, OCTET_STRING, SEQUENCE,
+};
+use super::key::Key;
+use super::State;
+
+use std::io::BufRead;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use attestation::crypto::{PrivateKeyInfoExt, TbsCertificateExt};
+use axum::body::Bytes;
+use axum::extract::Extension;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use const_oid::db::rfc5280::{ID_CE_EXT_KEY_USAGE, ID_KP_TIME_STAMPING};
+use const_oid::db::rfc5912::{ID_SHA_256, ID_SHA_384, ID_SHA_512};
+use const_oid::ObjectIdentifier;
+use der::asn1::{AnyRef, GeneralizedTime, OctetStringRef, UIntRef};
+use der::{Decode, Encode};
+use hyper::StatusCode;
+use sec1::pkcs8::PrivateKeyInfo;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use spki::AlgorithmIdentifier;
+use tracing::debug;
+use x509::ext::pkix::ExtendedKeyUsage;
+use x509::Certificate;
+
+pub const TIMESTAMP_QUERY: &str = "application/timestamp-query";
+pub const TIMESTAMP_REPLY: &str = "application/timestamp-reply";
+
+const ID_CT_TST_INFO: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.16.1.4");
+const ID_AA_SIGNING_CERTIFICATE_V2: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.16.2.47");
+
+// The `PKIStatus` values which are sent.
+const GRANTED: u8 = 0;
+const REJECTION: u8 = 2;
+
+/// Why a request was refused, as told to the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Failure {
+    BadAlg = 0,
+    BadDataFormat = 5,
+    UnacceptedPolicy = 15,
+    UnacceptedExtension = 16,
+}
+
+/// The authority which signs timestamps.
+#[derive(Clone, Debug)]
+pub struct Authority {
+    key: Key,
+    crt: Vec<u8>,
+    policy: ObjectIdentifier,
+}
+
+impl Authority {
+    /// Reads a PEM key and certificate, checking that the certificate was
+    /// issued by `ca` for time stamping and is for the key, and the dotted
+    /// OID of the TSA policy.
+    pub fn read(ca: &[u8], key: impl BufRead, mut crt: impl BufRead, policy: &str) -> Result<Self> {
+        let policy = policy
+            .parse()
+            .map_err(|e| anyhow!("invalid tsa policy: {e}"))?;
+        let key = Key::read(key)?;
+        let crt = match rustls_pemfile::read_one(&mut crt)? {
+            Some(rustls_pemfile::Item::X509Certificate(buf)) => buf,
+            _ => return Err(anyhow!("invalid tsa certificate")),
+        };
+
+        Self::new(ca, key, crt, policy)
+    }
+
+    pub fn new(ca: &[u8], key: Key, crt: Vec<u8>, policy: ObjectIdentifier) -> Result<Self> {
+        let ca = Certificate::from_der(ca)?;
+        let tsa = Certificate::from_der(&crt)?;
+        let tbs = ca
+            .tbs_certificate
+            .verify_crt(&tsa)
+            .context("the tsa certificate was not issued by the ca")?;
+
+        // RFC 3161, section 2.3.
+        let eku = tbs
+            .extensions
+            .iter()
+            .flatten()
+            .find(|ext| ext.extn_id == ID_CE_EXT_KEY_USAGE)
+            .ok_or_else(|| anyhow!("the tsa certificate has no extended key usage"))?;
+        let usages = ExtendedKeyUsage::from_der(eku.extn_value)?;
+        ensure!(
+            eku.critical && usages.0 == [ID_KP_TIME_STAMPING],
+            "the tsa certificate must be for time stamping alone, critically"
+        );
+
+        let pki = PrivateKeyInfo::from_der(&key)?;
+        ensure!(
+            pki.public_key()? == tbs.subject_public_key_info,
+            "the tsa key does not match its certificate"
+        );
+        algorithms(&pki)?;
+
+        Ok(Self { key, crt, policy })
+    }
+
+    /// The certificate of the authority.
+    pub fn crt(&self) -> &[u8] {
+        &self.crt
+    }
+}
+
+/// The length of digests of `algorithm`, if it is accepted.
+fn digest_len(algorithm: ObjectIdentifier) -> Option<usize> {
+    match algorithm {
+        ID_SHA_256 => Some(32),
+        ID_SHA_384 => Some(48),
+        ID_SHA_512 => Some(64),
+        _ => None,
+    }
+}
+
+fn digest(algorithm: ObjectIdentifier, data: &[u8]) -> Result<Vec<u8>> {
+    Ok(match algorithm {
+        ID_SHA_256 => Sha256::digest(data).to_vec(),
+        ID_SHA_384 => Sha384::digest(data).to_vec(),
+        ID_SHA_512 => Sha512::digest(data).to_vec(),
+        _ => bail!("unsupported digest {algorithm}"),
+    })
+}
+
+/// The digest and signature algorithms with which `pki` signs.
+fn algorithms<'a>(
+    pki: &'a PrivateKeyInfo<'_>,
+) -> Result<(ObjectIdentifier, AlgorithmIdentifier<'a>)> {
+    [ID_SHA_256, ID_SHA_384, ID_SHA_512]
+        .into_iter()
+        .find_map(|digest| Some((digest, pki.signs_with_digest(digest).ok()?)))
+        .ok_or_else(|| anyhow!("the tsa key cannot sign"))
+}
+
+/// A decoded `TimeStampReq`.
+#[derive(Debug)]
+struct Request {
+    /// The DER `MessageImprint`, copied into the timestamp.
+    imprint: Vec<u8>,
+
+    /// The algorithm and length of the imprinted digest.
+    algorithm: ObjectIdentifier,
+    len: usize,
+
+    /// The DER nonce, if any.
+    nonce: Option<Vec<u8>>,
+
+    certificate: bool,
+    policy: Option<ObjectIdentifier>,
+    extensions: bool,
+}
+
+impl Request {
+    fn decode(der: &[u8]) -> Result<Self> {
+        let req = AnyRef::from_der(der)?;
+        ensure!(tag(&req) == SEQUENCE, "invalid timestamp request");
+        let (imprint, rest) = match &elements(req.value())?[..] {
+            [version, imprint, rest @ ..] if version.decode_into::<u8>()? == 1 => {
+                (*imprint, rest.to_vec())
+            }
+            _ => bail!("invalid timestamp request"),
+        };
+        let (algorithm, hashed) = match &elements(imprint.value())?[..] {
+            [algorithm, hashed] => (*algorithm, *hashed),
+            _ => bail!("invalid message imprint"),
+        };
+
+        let mut request = Self {
+            imprint: imprint.to_vec()?,
+            algorithm: algorithm.decode_into::<AlgorithmIdentifier<'_>>()?.oid,
+            len: hashed.decode_into::<OctetStringRef<'_>>()?.as_bytes().len(),
+            nonce: None,
+            certificate: false,
+            policy: None,
+            extensions: false,
+        };
+        for field in rest {
+            match tag(&field) {
+                OBJECT_IDENTIFIER => request.policy = Some(field.decode_into()?),
+                INTEGER => request.nonce = Some(field.to_vec()?),
+                BOOLEAN => request.certificate = field.decode_into()?,
+                t if t == constructed(0) => request.extensions = true,
+                t => bail!("unexpected field {t:#x} in timestamp request"),
+            }
+        }
+        Ok(request)
+    }
+
+    /// Checks that the request may be granted by `tsa`.
+    fn check(&self, tsa: &Authority) -> Result<(), Failure> {
+        if digest_len(self.algorithm) != Some(self.len) {
+            debug!("timestamp requested for an unsupported imprint");
+            return Err(Failure::BadAlg);
+        }
+        if self.policy.map_or(false, |policy| policy != tsa.policy) {
+            debug!("timestamp requested under another policy");
+            return Err(Failure::UnacceptedPolicy);
+        }
+        if self.extensions {
+            debug!("timestamp requested with extensions");
+            return Err(Failure::UnacceptedExtension);
+        }
+        Ok(())
+    }
+}
+
+/// Encodes a `PKIStatusInfo`.
+fn status(status: u8, failure: Option<Failure>) -> Vec<u8> {
+    let status = tlv(INTEGER, &[status]);
+    match failure {
+        Some(failure) => sequence(&[&status, &named_bit(failure as usize)]),
+        None => sequence(&[&status]),
+    }
+}
+
+/// Signs a timestamp of `request` at the current time.
+fn stamp(state: &State, tsa: &Authority, request: &Request) -> Result<Vec<u8>> {
+    let pki = PrivateKeyInfo::from_der(&tsa.key)?;
+    let signer = Certificate::from_der(&tsa.crt)?;
+    let (hash, algorithm) = algorithms(&pki)?;
+
+    let serial = state.serials.next();
+    let serial = UIntRef::new(serial.as_bytes())?.to_vec()?;
+    let time = GeneralizedTime::from_system_time(state.clock.now())?.to_vec()?;
+    let mut fields = vec![
+        tlv(INTEGER, &[1]),
+        oid(tsa.policy)?,
+        request.imprint.clone(),
+        serial,
+        time,
+    ];
+    fields.extend(request.nonce.clone());
+    let info = tlv(SEQUENCE, &fields.concat());
+
+    // Name the signing certificate by its SHA-256 hash (RFC 5816).
+    let crt_hash = tlv(OCTET_STRING, &Sha256::digest(&tsa.crt));
+    let signing_certificate = sequence(&[&sequence(&[&sequence(&[&crt_hash])])]);
+    let attributes = vec![
+        attribute(ID_CONTENT_TYPE, &oid(ID_CT_TST_INFO)?)?,
+        attribute(ID_MESSAGE_DIGEST, &tlv(OCTET_STRING, &digest(hash, &info)?))?,
+        attribute(ID_AA_SIGNING_CERTIFICATE_V2, &signing_certificate)?,
+    ];
+
+    // The signature is over the attributes, encoded as a SET OF.
+    let signed = set(attributes);
+    let signature = pki.sign(&signed, algorithm)?;
+    let signed = AnyRef::from_der(&signed)?.value();
+
+    let hash = sequence(&[&oid(hash)?]);
+    let signer_info = sequence(&[
+        &tlv(INTEGER, &[1]),
+        &issuer_and_serial(&signer)?,
+        &hash,
+        &tlv(constructed(0), signed),
+        &algorithm.to_vec()?,
+        &tlv(OCTET_STRING, &signature),
+    ]);
+
+    let encapsulated = sequence(&[
+        &oid(ID_CT_TST_INFO)?,
+        &tlv(constructed(0), &tlv(OCTET_STRING, &info)),
+    ]);
+    let mut signed = vec![tlv(INTEGER, &[3]), set(vec![hash]), encapsulated];
+    if request.certificate {
+        signed.push(tlv(constructed(0), &tsa.crt));
+    }
+    signed.push(set(vec![signer_info]));
+    content_info(ID_SIGNED_DATA, &tlv(SEQUENCE, &signed.concat()))
+}
+
+/// Answers a `TimeStampReq` with a `TimeStampResp`.
+fn respond(state: &State, tsa: &Authority, der: &[u8]) -> Result<Vec<u8>> {
+    let request = Request::decode(der).map_err(|e| {
+        debug!("invalid timestamp request: {e}");
+        Failure::BadDataFormat
+    });
+    let request = request.and_then(|request| request.check(tsa).map(|_| request));
+
+    match request {
+        Ok(request) => {
+            let token = stamp(state, tsa, &request)?;
+            Ok(sequence(&[&status(GRANTED, None), &token]))
+        }
+        Err(failure) => Ok(sequence(&[&status(REJECTION, Some(failure))])),
+    }
+}
+
+/// Answers a timestamp request POSTed to `/tsa`.
+pub async fn tsa(
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<State>>,
+    body: Bytes,
+) -> Result<Response, StatusCode> {
+    let tsa = state.tsa.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    match headers.get(CONTENT_TYPE).and_then(|ct| ct.to_str().ok()) {
+        Some(ct) if ct.eq_ignore_ascii_case(TIMESTAMP_QUERY) => (),
+        _ => return Err(StatusCode::BAD_REQUEST),
+    }
+
+    let rep = respond(&state, tsa, &body).map_err(|e| {
+        debug!("failed to sign timestamp: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(([(CONTENT_TYPE, TIMESTAMP_REPLY)], rep).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, SystemTime};
+
+    use attestation::crypto::SubjectPublicKeyInfoExt;
+    use const_oid::db::rfc5280::ID_KP_CODE_SIGNING;
+    use const_oid::db::rfc5912::SECP_256_R_1;
+    use x509::name::RdnSequence;
+    use x509::time::{Time, Validity};
+    use x509::TbsCertificate;
+
+    use super::super::asn1::{content_of, SET};
+
+    const POLICY: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.32473.1");
+    const NONCE: &[u8] = &[0x02, 0x02, 0x01, 0x01];
+
+    /// Issues a certificate with `usages` from the CA of `state` to a fresh
+    /// key.
+    fn certify(state: &State, usages: &[ObjectIdentifier], critical: bool) -> (Key, Vec<u8>) {
+        let ca = Certificate::from_der(&state.crt).unwrap();
+        let ca_pki = PrivateKeyInfo::from_der(&state.key).unwrap();
+        let key = Key::try_from(PrivateKeyInfo::generate(SECP_256_R_1).unwrap()).unwrap();
+        let pki = PrivateKeyInfo::from_der(&key).unwrap();
+
+        let eku = ExtendedKeyUsage(usages.to_vec()).to_vec().unwrap();
+        let now = SystemTime::now();
+        let crt = TbsCertificate {
+            version: x509::Version::V3,
+            serial_number: UIntRef::new(&[1]).unwrap(),
+            signature: ca_pki.signs_with().unwrap(),
+            issuer: ca.tbs_certificate.subject.clone(),
+            validity: Validity {
+                not_before: Time::try_from(now).unwrap(),
+                not_after: Time::try_from(now + Duration::from_secs(3600)).unwrap(),
+            },
+            subject: RdnSequence::default(),
+            subject_public_key_info: pki.public_key().unwrap(),
+            issuer_unique_id: None,
+            subject_unique_id: None,
+            extensions: Some(vec![x509::ext::Extension {
+                extn_id: ID_CE_EXT_KEY_USAGE,
+                critical,
+                extn_value: &eku,
+            }]),
+        }
+        .sign(&ca_pki)
+        .unwrap();
+        (key, crt)
+    }
+
+    fn query(digest: &[u8], policy: Option<ObjectIdentifier>) -> Vec<u8> {
+        let imprint = sequence(&[
+            &sequence(&[&oid(ID_SHA_256).unwrap()]),
+            &tlv(OCTET_STRING, digest),
+        ]);
+        let mut fields = vec![tlv(INTEGER, &[1]), imprint];
+        fields.extend(policy.map(|policy| oid(policy).unwrap()));
+        fields.push(NONCE.to_vec());
+        fields.push(tlv(BOOLEAN, &[0xff]));
+        tlv(SEQUENCE, &fields.concat())
+    }
+
+    #[test]
+    fn authority() {
+        let state = State::generate(None, "localhost").unwrap();
+
+        let (key, crt) = certify(&state, &[ID_KP_TIME_STAMPING], true);
+        Authority::new(&state.crt, key, crt, POLICY).unwrap();
+
+        // The usage must be exclusive and critical.
+        let (key, crt) = certify(&state, &[ID_KP_TIME_STAMPING], false);
+        assert!(Authority::new(&state.crt, key, crt, POLICY).is_err());
+        let (key, crt) = certify(&state, &[ID_KP_TIME_STAMPING, ID_KP_CODE_SIGNING], true);
+        assert!(Authority::new(&state.crt, key, crt, POLICY).is_err());
+    }
+
+    #[test]
+    fn stamp() {
+        let state = State::generate(None, "localhost").unwrap();
+        let (key, crt) = certify(&state, &[ID_KP_TIME_STAMPING], true);
+        let tsa = Authority::new(&state.crt, key, crt, POLICY).unwrap();
+
+        let digest = Sha256::digest(b"evidence");
+        let rep = respond(&state, &tsa, &query(&digest, Some(POLICY))).unwrap();
+        let (granted, token) = match &elements(AnyRef::from_der(&rep).unwrap().value()).unwrap()[..]
+        {
+            [granted, token] => (granted.to_vec().unwrap(), token.to_vec().unwrap()),
+            _ => panic!("expected a timestamp"),
+        };
+        assert_eq!(granted, status(GRANTED, None));
+
+        let signed = content_of(&token, ID_SIGNED_DATA).unwrap();
+        let (encapsulated, crts, signers) = match &elements(signed.value()).unwrap()[..] {
+            [_, _, encapsulated, crts, signers] => (*encapsulated, *crts, *signers),
+            _ => panic!("expected the tsa certificate"),
+        };
+        assert_eq!(crts.value(), tsa.crt());
+
+        // The timestamp is of the imprint, under the policy, with the nonce.
+        let info = elements(encapsulated.value()).unwrap()[1];
+        let info = OctetStringRef::from_der(info.value()).unwrap();
+        let info = AnyRef::from_der(info.as_bytes()).unwrap();
+        let fields = elements(info.value()).unwrap();
+        assert_eq!(fields[1].decode_into::<ObjectIdentifier>().unwrap(), POLICY);
+        let imprint = elements(fields[2].value()).unwrap();
+        assert_eq!(imprint[1].value(), &digest[..]);
+        assert_eq!(fields[5].to_vec().unwrap(), NONCE);
+
+        // And is signed by the authority.
+        let signer = elements(signers.value()).unwrap()[0];
+        let signer = elements(signer.value()).unwrap();
+        let signature = signer[5].decode_into::<OctetStringRef<'_>>().unwrap();
+        let crt = Certificate::from_der(tsa.crt()).unwrap();
+        crt.tbs_certificate
+            .subject_public_key_info
+            .verify(
+                &tlv(SET, signer[3].value()),
+                signer[4].decode_into().unwrap(),
+                signature.as_bytes(),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn refused() {
+        let state = State::generate(None, "localhost").unwrap();
+        let (key, crt) = certify(&state, &[ID_KP_TIME_STAMPING], true);
+        let tsa = Authority::new(&state.crt, key, crt, POLICY).unwrap();
+
+        let digest = Sha256::digest(b"evidence");
+        let other = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.32473.2");
+        for (query, failure) in [
+            (query(&digest, Some(other)), Failure::UnacceptedPolicy),
+            (query(&digest[..20], None), Failure::BadAlg),
+            (b"junk".to_vec(), Failure::BadDataFormat),
+        ] {
+            let rep = respond(&state, &tsa, &query).unwrap();
+            assert_eq!(rep, sequence(&[&status(REJECTION, Some(failure))]));
+        }
+    }
+}
@@ -0,0 +1,395 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! The verifiers of evidence, by the OID of the extension carrying it.
+//!
+//! Each platform registers a verifier at startup, so that supporting a new
+//! one (or loading one from a plugin) does not mean editing the appraisal of
+//! requests, and tests can substitute fakes for real hardware.
+//!
+//! Verifiers describe what they found in an [`Appraisal`], from which
+//! issuance, metrics and the archived evidence are all derived, so that they
+//! cannot disagree about what was appraised.
+
+use super::store::Allowance;
+use super::{Config, State};
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use const_oid::ObjectIdentifier;
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use x509::ext::Extension;
+use x509::request::CertReqInfo;
+
+/// The outcome of appraising valid evidence.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Appraisal {
+    /// The platform which produced the evidence.
+    pub platform: String,
+
+    /// Whether the evidence counts as attestation.
+    pub attests: bool,
+
+    /// The hex measurements of the workload, by name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub measurements: BTreeMap<String, String>,
+
+    /// The hex TCB version of the platform, for those which report one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcb: Option<String>,
+
+    /// The policy checks which the evidence passed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub decisions: Vec<String>,
+
+    /// Whether the extension is copied into the certificate.
+    #[serde(default)]
+    pub copy: bool,
+
+    /// The quotas which a certificate issued on the evidence counts against,
+    /// as of the policy in force, so never cached.
+    #[serde(skip)]
+    pub allowances: Vec<Allowance>,
+
+    /// The one-time credential presented as the evidence, redeemed once a
+    /// certificate is issued on it, so never cached.
+    #[serde(skip)]
+    pub redemption: Option<Redemption>,
+}
+
+impl Appraisal {
+    pub fn new(platform: &str, attests: bool) -> Self {
+        Self {
+            platform: platform.into(),
+            attests,
+            ..Default::default()
+        }
+    }
+
+    /// Records the measurement `name` of the workload.
+    pub fn with_measurement(mut self, name: &str, value: &[u8]) -> Self {
+        self.measurements.insert(name.into(), hex::encode(value));
+        self
+    }
+
+    /// Records that the evidence passed a policy check.
+    pub fn decide(&mut self, decision: impl Into<String>) {
+        self.decisions.push(decision.into());
+    }
+}
+
+/// A one-time credential presented as evidence.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Redemption {
+    /// The shared key marking the credential as redeemed.
+    pub key: String,
+
+    /// How long the mark is kept, which is as long as the credential lasts.
+    pub ttl: Duration,
+}
+
+/// Appraises prepared evidence.
+pub type Appraiser<'a> = Box<dyn FnOnce() -> Result<Appraisal> + Send + 'a>;
+
+/// A verifier of the evidence of one platform.
+#[async_trait]
+pub trait ExtVerifier: Debug + Send + Sync {
+    /// The name of the platform, as used in policies and metrics.
+    fn platform(&self) -> &'static str;
+
+    /// When the collateral behind the evidence was issued, if it says.
+    fn issued(&self, _ext: &Extension<'_>) -> Result<Option<SystemTime>> {
+        Ok(None)
+    }
+
+    /// The hex measurement of the workload which the evidence claims, before
+    /// it is verified, to say what was refused.
+    fn claimed(&self, _ext: &Extension<'_>) -> Option<String> {
+        None
+    }
+
+    /// The one-time credential which `ext` presents, if it is one, redeemed
+    /// only once a certificate is issued on it.
+    fn redemption(&self, _config: &Config, _ext: &Extension<'_>) -> Option<Redemption> {
+        None
+    }
+
+    /// The DER trust anchors and revocation lists which appraisals depend
+    /// on besides the policy, such as uploaded roots. Cached appraisals are
+    /// only reused while these stay the same.
+    async fn trust(&self, _state: &State) -> Result<Vec<Vec<u8>>, StatusCode> {
+        Ok(Vec::new())
+    }
+
+    /// Gathers what appraising `ext` needs besides the request, such as
+    /// collateral fetched upstream, and returns the appraisal to run.
+    ///
+    /// Failing here means the evidence cannot be appraised for now, not that
+    /// it is invalid, so the appraisal is neither run nor cached.
+    async fn prepare<'a>(
+        &'a self,
+        state: &'a State,
+        config: &'a Config,
+        cri: &'a CertReqInfo<'_>,
+        ext: &'a Extension<'_>,
+        dbg: bool,
+    ) -> Result<Appraiser<'a>, StatusCode>;
+}
+
+/// The verifiers known to this steward.
+#[derive(Clone, Debug)]
+pub struct VerifierRegistry(BTreeMap<ObjectIdentifier, Arc<dyn ExtVerifier>>);
+
+impl Default for VerifierRegistry {
+    /// Registers the platforms this build supports.
+    fn default() -> Self {
+        let mut registry = Self::empty();
+
+        #[cfg(feature = "kvm")]
+        registry.register(super::Kvm::OID, KvmVerifier);
+
+        #[cfg(feature = "sgx")]
+        registry.register(super::Sgx::OID, SgxVerifier);
+
+        #[cfg(feature = "snp")]
+        registry.register(super::Snp::OID, SnpVerifier);
+
+        #[cfg(feature = "se")]
+        registry.register(super::Se::OID, SeVerifier);
+
+        #[cfg(feature = "cove")]
+        registry.register(super::Cove::OID, CoveVerifier);
+
+        registry.register(super::tokens::OID, super::tokens::TokenVerifier);
+        registry
+    }
+}
+
+impl VerifierRegistry {
+    /// A registry which accepts no evidence at all.
+    pub fn empty() -> Self {
+        Self(BTreeMap::new())
+    }
+
+    /// Verifies evidence in extensions of type `oid` with `verifier`,
+    /// replacing any verifier already registered for it.
+    pub fn register(&mut self, oid: ObjectIdentifier, verifier: impl ExtVerifier + 'static) {
+        self.0.insert(oid, Arc::new(verifier));
+    }
+
+    /// Returns the verifier of extensions of type `oid`, if they carry evidence.
+    pub fn get(&self, oid: &ObjectIdentifier) -> Option<&dyn ExtVerifier> {
+        self.0.get(oid).map(|verifier| &**verifier)
+    }
+}
+
+#[cfg(feature = "kvm")]
+#[derive(Debug)]
+struct KvmVerifier;
+
+#[cfg(feature = "kvm")]
+#[async_trait]
+impl ExtVerifier for KvmVerifier {
+    fn platform(&self) -> &'static str {
+        "kvm"
+    }
+
+    async fn prepare<'a>(
+        &'a self,
+        _state: &'a State,
+        _config: &'a Config,
+        cri: &'a CertReqInfo<'_>,
+        ext: &'a Extension<'_>,
+        dbg: bool,
+    ) -> Result<Appraiser<'a>, StatusCode> {
+        Ok(Box::new(move || {
+            super::Kvm::default().verify(cri, ext, dbg)?;
+            Ok(Appraisal::new("kvm", super::Kvm::ATT))
+        }))
+    }
+}
+
+#[cfg(feature = "sgx")]
+#[derive(Debug)]
+struct SgxVerifier;
+
+#[cfg(feature = "sgx")]
+#[async_trait]
+impl ExtVerifier for SgxVerifier {
+    fn platform(&self) -> &'static str {
+        "sgx"
+    }
+
+    fn issued(&self, ext: &Extension<'_>) -> Result<Option<SystemTime>> {
+        super::Sgx::issued(ext)
+    }
+
+    fn claimed(&self, ext: &Extension<'_>) -> Option<String> {
+        let report = super::Sgx::report(ext).ok()?;
+        Some(hex::encode(report.mrenclave))
+    }
+
+    async fn trust(&self, state: &State) -> Result<Vec<Vec<u8>>, StatusCode> {
+        let sgx = super::sgx_config(state, None).await?;
+        Ok(sgx.map_or_else(Vec::new, |sgx| [&sgx.roots[..], &sgx.crls].concat()))
+    }
+
+    async fn prepare<'a>(
+        &'a self,
+        state: &'a State,
+        config: &'a Config,
+        cri: &'a CertReqInfo<'_>,
+        ext: &'a Extension<'_>,
+        dbg: bool,
+    ) -> Result<Appraiser<'a>, StatusCode> {
+        let sgx = super::sgx_config(state, config.sgx.as_ref()).await?;
+        let now = state.clock.now();
+        Ok(Box::new(move || {
+            super::Sgx::default().verify(cri, ext, sgx.as_deref(), dbg, now)?;
+            let report = super::Sgx::report(ext)?;
+            let mut appraisal = Appraisal::new("sgx", super::Sgx::ATT)
+                .with_measurement("mrenclave", &report.mrenclave)
+                .with_measurement("mrsigner", &report.mrsigner);
+            if config.sgx.is_some() {
+                appraisal.decide("sgx policy");
+            }
+            Ok(appraisal)
+        }))
+    }
+}
+
+#[cfg(feature = "snp")]
+#[derive(Debug)]
+struct SnpVerifier;
+
+#[cfg(feature = "snp")]
+#[async_trait]
+impl ExtVerifier for SnpVerifier {
+    fn platform(&self) -> &'static str {
+        "snp"
+    }
+
+    fn issued(&self, ext: &Extension<'_>) -> Result<Option<SystemTime>> {
+        super::Snp::issued(ext)
+    }
+
+    fn claimed(&self, ext: &Extension<'_>) -> Option<String> {
+        let body = super::Snp::report(ext).ok()?.body;
+        Some(hex::encode(body.measurement))
+    }
+
+    async fn trust(&self, state: &State) -> Result<Vec<Vec<u8>>, StatusCode> {
+        let snp = super::snp_config(state, None).await?;
+        Ok(snp.map_or_else(Vec::new, |snp| snp.roots.clone()))
+    }
+
+    async fn prepare<'a>(
+        &'a self,
+        state: &'a State,
+        config: &'a Config,
+        cri: &'a CertReqInfo<'_>,
+        ext: &'a Extension<'_>,
+        dbg: bool,
+    ) -> Result<Appraiser<'a>, StatusCode> {
+        let snp = super::snp_config(state, config.snp.as_ref()).await?;
+        Ok(Box::new(move || {
+            super::Snp::default().verify(cri, ext, snp.as_deref(), dbg)?;
+            let body = super::Snp::report(ext)?.body;
+            let mut appraisal = Appraisal::new("snp", super::Snp::ATT)
+                .with_measurement("measurement", &body.measurement)
+                .with_measurement("author_key_digest", &body.author_key_digest)
+                .with_measurement("host_data", &body.host_data)
+                .with_measurement("report_data", &body.report_data)
+                .with_measurement("family_id", &body.family_id)
+                .with_measurement("image_id", &body.image_id);
+            appraisal.tcb = Some(format!("{:016x}", { body.reported_tcb }));
+            if config.snp.is_some() {
+                appraisal.decide("snp policy");
+            }
+            Ok(appraisal)
+        }))
+    }
+}
+
+#[cfg(feature = "se")]
+#[derive(Debug)]
+struct SeVerifier;
+
+#[cfg(feature = "se")]
+#[async_trait]
+impl ExtVerifier for SeVerifier {
+    fn platform(&self) -> &'static str {
+        "se"
+    }
+
+    fn claimed(&self, ext: &Extension<'_>) -> Option<String> {
+        let evidence = super::Se::evidence(ext).ok()?;
+        Some(hex::encode(evidence.tag.as_bytes()))
+    }
+
+    async fn prepare<'a>(
+        &'a self,
+        state: &'a State,
+        config: &'a Config,
+        cri: &'a CertReqInfo<'_>,
+        ext: &'a Extension<'_>,
+        dbg: bool,
+    ) -> Result<Appraiser<'a>, StatusCode> {
+        let now = state.clock.now();
+        Ok(Box::new(move || {
+            super::Se::default().verify(cri, ext, config.se.as_ref(), dbg, now)?;
+            let evidence = super::Se::evidence(ext)?;
+            let mut appraisal = Appraisal::new("se", super::Se::ATT)
+                .with_measurement("tag", evidence.tag.as_bytes())
+                .with_measurement("config_uid", evidence.config_uid.as_bytes());
+            appraisal.decide("se policy");
+            Ok(appraisal)
+        }))
+    }
+}
+
+#[cfg(feature = "cove")]
+#[derive(Debug)]
+struct CoveVerifier;
+
+#[cfg(feature = "cove")]
+#[async_trait]
+impl ExtVerifier for CoveVerifier {
+    fn platform(&self) -> &'static str {
+        "cove"
+    }
+
+    fn claimed(&self, ext: &Extension<'_>) -> Option<String> {
+        let leaf = super::Cove::leaf(ext).ok()?;
+        let tcb = attestation::cove::TcbInfo::of(&leaf.tbs_certificate).ok()?;
+        Some(hex::encode(tcb.digest().ok()?))
+    }
+
+    async fn prepare<'a>(
+        &'a self,
+        _state: &'a State,
+        config: &'a Config,
+        cri: &'a CertReqInfo<'_>,
+        ext: &'a Extension<'_>,
+        dbg: bool,
+    ) -> Result<Appraiser<'a>, StatusCode> {
+        Ok(Box::new(move || {
+            super::Cove::default().verify(cri, ext, config.cove.as_ref(), dbg)?;
+            let leaf = super::Cove::leaf(ext)?;
+            let tcb = attestation::cove::TcbInfo::of(&leaf.tbs_certificate)?;
+            let mut appraisal =
+                Appraisal::new("cove", super::Cove::ATT).with_measurement("tvm", &tcb.digest()?);
+            if let Some(svn) = tcb.svn()? {
+                appraisal.tcb = Some(format!("{svn:016x}"));
+            }
+            appraisal.decide("cove policy");
+            Ok(appraisal)
+        }))
+    }
+}
@@ -0,0 +1,322 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Delivery of audit events to a webhook, e.g. for SIEM ingestion.
+//!
+//! Every issuance, rejection and policy change is POSTed as JSON to the
+//! configured endpoint, signed with HMAC-SHA256 over the body so that the
+//! receiver can tell it came from this steward:
+//!
+//! ```text
+//! X-Steward-Signature: sha256=<hex>
+//! ```
+//!
+//! Events are first written to a queue directory and delivered in order in
+//! the background, so that an outage of the receiver neither blocks issuance
+//! nor loses events. The queue is bounded: once full, the oldest events are
+//! dropped to make room.
+
+use super::audit::{Event, Sink};
+use super::scheduler::Scheduler;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{ensure, Context, Result};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+use zeroize::Zeroizing;
+
+/// The header carrying the signature of the body.
+pub const SIGNATURE_HEADER: &str = "x-steward-signature";
+
+/// The header carrying the unique ID of the event, for deduplication.
+pub const EVENT_HEADER: &str = "x-steward-event";
+
+const BATCH: usize = 100;
+
+/// Computes the HMAC-SHA256 of `msg` under `key` (RFC 2104).
+pub fn hmac(key: &[u8], msg: &[u8]) -> [u8; 32] {
+    const BLOCK: usize = 64;
+
+    let mut block = Zeroizing::new([0u8; BLOCK]);
+    if key.len() > BLOCK {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let pad = |byte: u8| -> Zeroizing<[u8; BLOCK]> {
+        let mut pad = Zeroizing::new([0u8; BLOCK]);
+        for (p, k) in pad.iter_mut().zip(block.iter()) {
+            *p = k ^ byte;
+        }
+        pad
+    };
+
+    let inner = Sha256::new()
+        .chain_update(&pad(0x36)[..])
+        .chain_update(msg)
+        .finalize();
+    Sha256::new()
+        .chain_update(&pad(0x5c)[..])
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+/// A bounded queue of events, one file each, in a directory.
+#[derive(Debug)]
+pub struct Queue {
+    dir: PathBuf,
+    bound: usize,
+    lock: Mutex<()>,
+}
+
+impl Queue {
+    /// Opens the queue in `dir`, creating it if need be, holding at most
+    /// `bound` events.
+    pub fn open(dir: impl AsRef<Path>, bound: usize) -> Result<Self> {
+        ensure!(bound > 0, "the audit queue must hold at least one event");
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create audit queue {}", dir.display()))?;
+
+        Ok(Self {
+            dir,
+            bound,
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// The queued events' files, oldest first.
+    fn files(&self) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(false, |ext| ext == "json") {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Adds an event, dropping the oldest ones if the queue is full.
+    pub fn push(&self, event: &Event) -> Result<()> {
+        let _lock = self.lock.lock().unwrap();
+
+        let files = self.files()?;
+        let excess = (files.len() + 1).saturating_sub(self.bound);
+        for file in &files[..excess] {
+            fs::remove_file(file)?;
+            warn!("audit queue full, dropped {}", file.display());
+        }
+
+        // Names sort by time, then arbitrarily among simultaneous events.
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let name = format!("{nanos:024}-{}", event.id);
+        let tmp = self.dir.join(format!("{name}.tmp"));
+        fs::write(&tmp, serde_json::to_vec(event)?)?;
+        fs::rename(tmp, self.dir.join(format!("{name}.json")))?;
+        Ok(())
+    }
+
+    /// Returns up to `limit` of the oldest events, with their files.
+    pub fn peek(&self, limit: usize) -> Result<Vec<(PathBuf, Vec<u8>)>> {
+        let _lock = self.lock.lock().unwrap();
+
+        let mut events = Vec::new();
+        for file in self.files()?.into_iter().take(limit) {
+            let body = fs::read(&file)?;
+            events.push((file, body));
+        }
+        Ok(events)
+    }
+
+    /// Removes a delivered event.
+    pub fn remove(&self, file: &Path) -> Result<()> {
+        let _lock = self.lock.lock().unwrap();
+        match fs::remove_file(file) {
+            // Dropped to make room while it was being delivered.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            res => Ok(res?),
+        }
+    }
+
+    /// The number of events awaiting delivery.
+    pub fn len(&self) -> Result<usize> {
+        let _lock = self.lock.lock().unwrap();
+        Ok(self.files()?.len())
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+}
+
+/// A signing webhook client with a queue of undelivered events.
+#[derive(Debug)]
+pub struct Webhook {
+    url: String,
+    secret: Zeroizing<Vec<u8>>,
+    client: reqwest::Client,
+    queue: Queue,
+}
+
+impl Webhook {
+    /// Creates a client delivering to `url`, signing with `secret`.
+    pub fn new(url: &str, secret: &[u8], queue: Queue) -> Result<Self> {
+        ensure!(!secret.is_empty(), "empty webhook secret");
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()?;
+
+        Ok(Self {
+            url: url.into(),
+            secret: Zeroizing::new(secret.to_vec()),
+            client,
+            queue,
+        })
+    }
+
+    /// Returns the value of the signature header for `body`.
+    pub fn sign(&self, body: &[u8]) -> String {
+        format!("sha256={}", hex::encode(hmac(&self.secret, body)))
+    }
+
+    /// Delivers queued events in order, stopping at the first failure so
+    /// that it is retried on the next run.
+    pub async fn flush(&self) -> Result<usize> {
+        let mut delivered = 0;
+        for (file, body) in self.queue.peek(BATCH)? {
+            let event: Event = match serde_json::from_slice(&body) {
+                Ok(event) => event,
+                Err(e) => {
+                    warn!("dropping corrupt audit event {}: {e}", file.display());
+                    self.queue.remove(&file)?;
+                    continue;
+                }
+            };
+
+            let rsp = self
+                .client
+                .post(&self.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(SIGNATURE_HEADER, self.sign(&body))
+                .header(EVENT_HEADER, &event.id)
+                .body(body)
+                .send()
+                .await
+                .context("failed to reach the audit webhook")?;
+            ensure!(
+                rsp.status().is_success(),
+                "the audit webhook returned {}",
+                rsp.status()
+            );
+
+            self.queue.remove(&file)?;
+            delivered += 1;
+        }
+        Ok(delivered)
+    }
+
+    /// Adds a task delivering queued events.
+    pub fn schedule(self: Arc<Self>, scheduler: Scheduler) -> Scheduler {
+        scheduler.every(
+            "audit-webhook",
+            Duration::from_secs(5),
+            Duration::from_secs(1),
+            move || {
+                let webhook = self.clone();
+                async move {
+                    let delivered = webhook.flush().await?;
+                    if delivered > 0 {
+                        info!("delivered {delivered} audit events");
+                    }
+                    Ok(())
+                }
+            },
+        )
+    }
+}
+
+impl Sink for Webhook {
+    /// Queues an event for delivery.
+    fn emit(&self, event: &Event) {
+        if let Err(e) = self.queue.push(event) {
+            warn!("failed to queue audit event {}: {e:#}", event.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    fn queue(bound: usize) -> Queue {
+        let dir = std::env::temp_dir().join(format!("steward-audit-{}", uuid::Uuid::new_v4()));
+        Queue::open(dir, bound).unwrap()
+    }
+
+    #[test]
+    fn hmac_sha256() {
+        // RFC 4231, test cases 2 and 6.
+        assert_eq!(
+            hex::encode(hmac(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex::encode(hmac(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn bounded() {
+        let queue = queue(2);
+        let now = SystemTime::now();
+        let events: Vec<_> = (0..3)
+            .map(|i| Event::new(now, "issuance", json!({ "n": i })))
+            .collect();
+        for event in &events {
+            queue.push(event).unwrap();
+        }
+
+        // The oldest event made room for the newest.
+        let queued: Vec<Event> = queue
+            .peek(10)
+            .unwrap()
+            .iter()
+            .map(|(_, body)| serde_json::from_slice(body).unwrap())
+            .collect();
+        assert_eq!(queued, events[1..]);
+
+        let (file, _) = &queue.peek(1).unwrap()[0];
+        queue.remove(file).unwrap();
+        queue.remove(file).unwrap();
+        assert_eq!(queue.len().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn retained() {
+        let webhook = Webhook::new("http://127.0.0.1:9/audit", b"secret", queue(10)).unwrap();
+        let event = Event::new(SystemTime::now(), "rejection", json!({ "status": 403 }));
+        webhook.emit(&event);
+
+        // Undelivered events stay queued for the next attempt.
+        assert!(webhook.flush().await.is_err());
+        assert_eq!(webhook.queue.len().unwrap(), 1);
+        assert_eq!(webhook.sign(b"").len(), "sha256=".len() + 64);
+    }
+}
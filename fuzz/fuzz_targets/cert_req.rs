@@ -0,0 +1,15 @@
+// SPDX-FileCopyrightText: 2022 Profian Inc. <opensource@profian.com>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+#![no_main]
+
+use attestation::parse;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse::cert_req(data);
+    let _ = parse::cert_reqs(data);
+    if let Ok(der) = parse::normalize(data, &parse::Limits::default()) {
+        let _ = parse::cert_req(&der);
+    }
+});
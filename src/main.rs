@@ -52,14 +52,33 @@
 
 #![warn(rust_2018_idioms, unused_lifetimes, unused_qualifications, clippy::all)]
 
-use steward_server::{app, init_tracing, State};
+use steward_server::admin::Auditor;
+use steward_server::approvals::Approvers;
+use steward_server::archive::Archive;
+use steward_server::cache::AppraisalCache;
+#[cfg(feature = "deterministic")]
+use steward_server::clock::Manual;
+use steward_server::clock::System;
+use steward_server::cors::Cors;
+use steward_server::crl::{Delegate, Distribution};
+use steward_server::proxy::{Cidr, Peer, Trusted};
+use steward_server::scep::Agent;
+#[cfg(feature = "deterministic")]
+use steward_server::serials::Seeded;
+use steward_server::source::Source;
+use steward_server::tsa::Authority;
+use steward_server::{app, logging, metrics, public, Constraints, KeyAlgorithm, State};
 
 use std::net::IpAddr;
 use std::path::PathBuf;
+use std::time::Duration;
+#[cfg(feature = "deterministic")]
+use std::time::UNIX_EPOCH;
 
 use anyhow::{anyhow, Context};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use confargs::{prefix_char_filter, Toml};
+use zeroize::Zeroizing;
 
 /// Attestation server for use with Enarx.
 ///
@@ -71,57 +90,1140 @@ use confargs::{prefix_char_filter, Toml};
 #[derive(Clone, Debug, Parser)]
 #[command(author, version, about)]
 struct Args {
+    /// The CA private key: a path, inline PEM, `fd:N` to read an inherited
+    /// file descriptor, `secret:NAME` for a Docker secret or `credential:NAME`
+    /// for a systemd credential. Key files must not be world-readable.
     #[arg(short, long, env = "STEWARD_KEY")]
-    key: Option<PathBuf>,
+    key: Option<Source>,
 
+    /// The CA certificate, from any of the sources the key may come from.
     #[arg(short, long, env = "STEWARD_CRT")]
-    crt: Option<PathBuf>,
+    crt: Option<Source>,
+
+    /// A cross-certificate for the CA, issued by another CA, followed by its
+    /// issuers, from any of the sources the CA certificate may come from.
+    /// Clients may ask for issued certificates chained through it.
+    ///
+    /// May be repeated, once for each cross-signer.
+    #[arg(long = "cross-crt", env = "STEWARD_CROSS_CRT")]
+    cross_crts: Vec<Source>,
+
+    /// A key to sign CRLs with instead of the CA's, from any of the sources
+    /// the CA key may come from.
+    #[arg(long, env = "STEWARD_CRL_KEY")]
+    crl_key: Option<Source>,
+
+    /// The certificate of `--crl-key`: issued by the CA, with the CA's name as
+    /// subject and the `cRLSign` key usage.
+    #[arg(long, env = "STEWARD_CRL_CRT")]
+    crl_crt: Option<Source>,
+
+    /// The public URL of `/crl`. When given, base and delta CRLs are served
+    /// under it and named in issued certificates.
+    #[arg(long, env = "STEWARD_CRL_URL")]
+    crl_url: Option<String>,
+
+    /// The number of shards, by serial number range, across which CRLs under
+    /// `--crl-url` are split.
+    #[arg(long, env = "STEWARD_CRL_SHARDS", default_value = "1")]
+    crl_shards: u32,
+
+    /// The RSA key of the registration agent through which devices enroll
+    /// over SCEP, from any of the sources the CA key may come from.
+    #[arg(long, env = "STEWARD_SCEP_KEY")]
+    scep_key: Option<Source>,
+
+    /// The certificate of `--scep-key`, issued by the CA.
+    #[arg(long, env = "STEWARD_SCEP_CRT")]
+    scep_crt: Option<Source>,
+
+    /// The key with which timestamps are signed at `/tsa`, from any of the
+    /// sources the CA key may come from.
+    #[arg(long, env = "STEWARD_TSA_KEY")]
+    tsa_key: Option<Source>,
+
+    /// The certificate of `--tsa-key`, issued by the CA with the critical
+    /// extended key usage of time stamping alone.
+    #[arg(long, env = "STEWARD_TSA_CRT")]
+    tsa_crt: Option<Source>,
+
+    /// The OID of the policy under which timestamps are issued.
+    #[arg(long, env = "STEWARD_TSA_POLICY")]
+    tsa_policy: Option<String>,
 
     #[arg(short, long, env = "ROCKET_PORT", default_value = "3000")]
     port: u16,
 
-    #[arg(short, long, env = "ROCKET_ADDRESS", default_value = "::")]
-    addr: IpAddr,
+    /// Address to listen on.
+    ///
+    /// May be repeated, e.g. `0.0.0.0,::`, to bind a socket for each rather
+    /// than rely on the platform's dual-stack behavior. Other listeners use
+    /// the first.
+    #[arg(
+        short,
+        long,
+        env = "ROCKET_ADDRESS",
+        value_delimiter = ',',
+        default_value = "::"
+    )]
+    addr: Vec<IpAddr>,
+
+    /// Port on which to serve health, metrics and the admin API separately.
+    ///
+    /// When set, the main port only serves workloads and auditors, so that it
+    /// can be exposed while operational endpoints stay internal.
+    #[arg(long, env = "STEWARD_ADMIN_PORT")]
+    admin_port: Option<u16>,
+
+    /// Address on which to serve the admin port; defaults to the first `--addr`.
+    #[arg(long, env = "STEWARD_ADMIN_ADDRESS")]
+    admin_addr: Option<IpAddr>,
 
     #[arg(long, env = "RENDER_EXTERNAL_HOSTNAME")]
     host: Option<String>,
 
+    /// Address range of a reverse proxy whose `X-Forwarded-For` is believed.
+    ///
+    /// May be repeated. Client addresses are only used for logging.
+    #[arg(
+        long = "trusted-proxy",
+        env = "STEWARD_TRUSTED_PROXIES",
+        value_delimiter = ','
+    )]
+    trusted_proxies: Vec<Cidr>,
+
+    /// CA certificates, in PEM, of which operators must present a client
+    /// certificate to decide on parked requests, as well as an admin token.
+    ///
+    /// Steward does not terminate TLS, so a `--trusted-proxy` must forward
+    /// the certificate in `X-Client-Cert`.
+    #[arg(long, env = "STEWARD_APPROVER_CA")]
+    approver_ca: Option<Source>,
+
+    /// Require every connection to begin with a HAProxy PROXY protocol header.
+    #[arg(long, env = "STEWARD_PROXY_PROTOCOL")]
+    proxy_protocol: bool,
+
+    /// Also accept HTTP/2 (with prior knowledge) on the main port.
+    #[arg(long, env = "STEWARD_HTTP2")]
+    http2: bool,
+
+    /// Maximum number of concurrent requests on one HTTP/2 connection.
+    #[arg(long, env = "STEWARD_HTTP2_MAX_STREAMS")]
+    http2_max_streams: Option<u32>,
+
+    /// Close HTTP/1 connections after each response.
+    #[arg(long, env = "STEWARD_NO_KEEP_ALIVE")]
+    no_keep_alive: bool,
+
+    /// Seconds a connection may idle before it is probed.
+    ///
+    /// Sets the TCP keep-alive time and, with `--http2`, the ping interval.
+    #[arg(long, env = "STEWARD_KEEP_ALIVE_INTERVAL")]
+    keep_alive_interval: Option<u64>,
+
+    /// Seconds to wait for an HTTP/2 ping to be answered.
+    #[arg(long, env = "STEWARD_KEEP_ALIVE_TIMEOUT")]
+    keep_alive_timeout: Option<u64>,
+
+    /// Disable Nagle's algorithm on accepted connections.
+    #[arg(long, env = "STEWARD_TCP_NODELAY")]
+    tcp_nodelay: bool,
+
+    /// Seconds an HTTP/1 client may take to send the headers of a request.
+    ///
+    /// Counts from connecting or from the previous response, so also bounds
+    /// how long a connection may idle between requests.
+    #[arg(long, env = "STEWARD_HEADER_TIMEOUT")]
+    header_timeout: Option<u64>,
+
+    /// Seconds a client may stall while sending the headers or body of a
+    /// request.
+    #[arg(long, env = "STEWARD_READ_TIMEOUT")]
+    read_timeout: Option<u64>,
+
+    /// Origin allowed to call steward from a browser, or `*` for any.
+    ///
+    /// May be repeated. Cross-origin requests are refused when unset.
+    #[arg(
+        long = "cors-origin",
+        env = "STEWARD_CORS_ORIGINS",
+        value_delimiter = ','
+    )]
+    cors_origins: Vec<String>,
+
+    /// Method allowed in cross-origin requests; defaults to `GET` and `POST`.
+    #[arg(
+        long = "cors-method",
+        env = "STEWARD_CORS_METHODS",
+        value_delimiter = ','
+    )]
+    cors_methods: Vec<String>,
+
+    /// Header allowed in cross-origin requests; defaults to `Content-Type`.
+    #[arg(
+        long = "cors-header",
+        env = "STEWARD_CORS_HEADERS",
+        value_delimiter = ','
+    )]
+    cors_headers: Vec<String>,
+
     #[arg(long, env = "STEWARD_SAN")]
     san: Option<String>,
 
     #[arg(long)]
     config: Option<String>,
+
+    /// Seconds between checks of the `--config` file for changes.
+    ///
+    /// A changed policy is put in force once validated; an invalid one is
+    /// reported and the current one kept. The file may also be reloaded on
+    /// demand through the admin API.
+    #[arg(long, env = "STEWARD_WATCH_CONFIG")]
+    watch_config: Option<u64>,
+
+    /// Maximum number of intermediate CAs below a generated CA.
+    #[arg(long, env = "STEWARD_CA_PATH_LEN", default_value = "0")]
+    ca_path_len: u8,
+
+    /// Restrict the names a generated CA may certify to this domain.
+    ///
+    /// May be repeated. Without it, the generated CA has no name constraints.
+    #[arg(
+        long = "permitted-domain",
+        env = "STEWARD_PERMITTED_DOMAINS",
+        value_delimiter = ','
+    )]
+    permitted_domains: Vec<String>,
+
+    /// Algorithm of a generated CA's key: `p256` (the default), `p384` or,
+    /// in builds with the `pqc` feature, `ml-dsa-44`, `ml-dsa-65`,
+    /// `ml-dsa-87` or `ml-dsa-44-p256`, a composite signing with both.
+    ///
+    /// Such builds also load `--key` files holding ML-DSA or composite keys.
+    #[arg(long, env = "STEWARD_KEY_ALGORITHM")]
+    key_algorithm: Option<String>,
+
+    /// Route all cryptography through the FIPS validated module.
+    ///
+    /// Requires a build with the `fips` feature.
+    #[arg(long, env = "STEWARD_FIPS")]
+    fips: bool,
+
+    /// Largest accepted request body in bytes, after decompression.
+    #[arg(long, env = "STEWARD_BODY_LIMIT", default_value_t = steward_server::BODY_LIMIT)]
+    body_limit: usize,
+
+    /// Maximum lifetime, in seconds, of any issued certificate.
+    ///
+    /// Applies to every issuance route, whatever lifetime it would otherwise
+    /// use or a client asks for. Clients are told to renew two thirds of the
+    /// way through a certificate's lifetime.
+    #[arg(long, env = "STEWARD_MAX_LEAF_TTL")]
+    max_leaf_ttl: Option<u64>,
+
+    /// Seconds to cache successful appraisals of identical evidence (0 disables).
+    #[arg(long, env = "STEWARD_CACHE_TTL", default_value = "30")]
+    cache_ttl: u64,
+
+    /// Seconds to cache failed appraisals of identical evidence (0 disables).
+    #[arg(long, env = "STEWARD_NEGATIVE_CACHE_TTL", default_value = "5")]
+    negative_cache_ttl: u64,
+
+    /// How many of the latest rejected enrollments to keep for
+    /// `GET /admin/rejections` (0 keeps none).
+    #[arg(long, env = "STEWARD_REJECTIONS_KEPT", default_value = "100")]
+    rejections_kept: usize,
+
+    /// URL of a Redis server holding state shared between replicas.
+    ///
+    /// Requires a build with the `redis` feature.
+    #[arg(long, env = "STEWARD_REDIS")]
+    redis: Option<String>,
+
+    /// URL of a PostgreSQL database recording issued certificates.
+    ///
+    /// Requires a build with the `postgres` feature. Without it, records are
+    /// only kept in memory.
+    #[arg(long, env = "STEWARD_DATABASE")]
+    database: Option<String>,
+
+    /// Maximum number of pooled database connections.
+    #[arg(long, env = "STEWARD_DATABASE_CONNECTIONS", default_value = "10")]
+    database_connections: u32,
+
+    /// Archive the attestation evidence behind every issued certificate.
+    ///
+    /// Auditors fetch it from `/certs/{serial}/evidence` using the
+    /// `--auditor-token` bearer token.
+    #[arg(long, env = "STEWARD_ARCHIVE_EVIDENCE")]
+    archive_evidence: bool,
+
+    /// Bearer token granting auditors read access to the admin API and
+    /// archived evidence.
+    #[arg(long, env = "STEWARD_AUDITOR_TOKEN")]
+    auditor_token: Option<String>,
+
+    /// Hex encoded AES-256 key file used to encrypt archived evidence at rest.
+    #[arg(long, env = "STEWARD_EVIDENCE_KEY")]
+    evidence_key: Option<PathBuf>,
+
+    /// Days to keep archived evidence.
+    #[arg(long, env = "STEWARD_EVIDENCE_RETENTION", default_value = "90")]
+    evidence_retention: u64,
+
+    /// URL of Intel's Provisioning Certification Service, from which to fetch
+    /// current SGX revocation lists (e.g. https://api.trustedservices.intel.com).
+    ///
+    /// Requires a build with the `collateral` feature.
+    #[arg(long, env = "STEWARD_PCS_URL")]
+    pcs_url: Option<String>,
+
+    /// Product lines, by PCK CA (`processor` or `platform`), whose SGX
+    /// revocation lists are fetched.
+    #[arg(
+        long = "sgx-pck-ca",
+        env = "STEWARD_SGX_PCK_CAS",
+        value_delimiter = ',',
+        default_value = "processor,platform"
+    )]
+    sgx_pck_cas: Vec<String>,
+
+    /// Seconds to wait for a collateral service to respond.
+    #[arg(long, env = "STEWARD_COLLATERAL_TIMEOUT", default_value = "5")]
+    collateral_timeout: u64,
+
+    /// Times to retry a failed request to a collateral service.
+    #[arg(long, env = "STEWARD_COLLATERAL_RETRIES", default_value = "2")]
+    collateral_retries: u32,
+
+    /// URL of a Sigstore Rekor instance to publish issued certificates to.
+    ///
+    /// Requires a build with the `rekor` feature.
+    #[arg(long, env = "STEWARD_REKOR")]
+    rekor: Option<String>,
+
+    /// URL to which audit events (issuances, rejections and policy changes)
+    /// are POSTed.
+    ///
+    /// Requires a build with the `webhook` feature, and `--audit-webhook-secret`
+    /// and `--audit-queue`.
+    #[arg(long, env = "STEWARD_AUDIT_WEBHOOK")]
+    audit_webhook: Option<String>,
+
+    /// Secret with which audit events are signed (HMAC-SHA256).
+    #[arg(long, env = "STEWARD_AUDIT_WEBHOOK_SECRET")]
+    audit_webhook_secret: Option<String>,
+
+    /// Directory in which audit events wait to be delivered.
+    #[arg(long, env = "STEWARD_AUDIT_QUEUE")]
+    audit_queue: Option<PathBuf>,
+
+    /// Maximum number of undelivered audit events kept, beyond which the
+    /// oldest are dropped.
+    #[arg(long, env = "STEWARD_AUDIT_QUEUE_SIZE", default_value = "10000")]
+    audit_queue_size: usize,
+
+    /// Syslog daemon to which audit events are sent as RFC 5424 messages:
+    /// `unix:PATH` (e.g. `unix:/dev/log`) or `udp:HOST:PORT`.
+    #[arg(long, env = "STEWARD_AUDIT_SYSLOG")]
+    audit_syslog: Option<String>,
+
+    /// Send audit events to the journal, with their details as fields.
+    #[arg(long, env = "STEWARD_AUDIT_JOURNALD")]
+    audit_journald: bool,
+
+    /// How to write logs: `full`, `compact`, `pretty` or `json`.
+    ///
+    /// Defaults to `json` if `RUST_LOG_JSON` is set, and `full` otherwise.
+    #[arg(long, env = "STEWARD_LOG_FORMAT")]
+    log_format: Option<String>,
+
+    /// Log credentials, evidence and measurements rather than redacting
+    /// them, for debugging.
+    #[arg(long, env = "STEWARD_LOG_UNREDACTED")]
+    log_unredacted: bool,
+
+    /// Sign Kubernetes certificate signing requests naming this signer.
+    ///
+    /// Requires a build with the `kubernetes` feature, running in a pod whose
+    /// service account may sign for the signer.
+    #[arg(long, env = "STEWARD_KUBERNETES_SIGNER")]
+    kubernetes_signer: Option<String>,
+
+    /// Port on which to serve the SPIRE `UpstreamAuthority` gRPC interface.
+    ///
+    /// Requires a build with the `spire` feature.
+    #[arg(long, env = "STEWARD_SPIRE_PORT")]
+    spire_port: Option<u16>,
+
+    /// Draw serial numbers from a generator seeded with this, so that
+    /// issuance is reproducible.
+    ///
+    /// For golden file and differential testing only: seeded serial numbers
+    /// are guessable. Requires a build with the `deterministic` feature.
+    #[cfg(feature = "deterministic")]
+    #[arg(long, env = "STEWARD_DETERMINISTIC_SEED")]
+    deterministic_seed: Option<u64>,
+
+    /// Stop the clock at this many seconds since the Unix epoch, so that
+    /// issued certificates are valid for the same times on every run.
+    ///
+    /// For golden file and differential testing only. Requires a build with
+    /// the `deterministic` feature.
+    #[cfg(feature = "deterministic")]
+    #[arg(long, env = "STEWARD_FROZEN_TIME")]
+    frozen_time: Option<u64>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+enum Command {
+    /// Drive load against a running steward and report its latency.
+    ///
+    /// Requires a build with the `bench` feature.
+    Bench(Bench),
+
+    /// Appraise a corpus of evidence samples under the configured policy and
+    /// report which came out as expected, rather than serving.
+    Verify(Verify),
+}
+
+#[derive(Clone, Debug, clap::Args)]
+struct Bench {
+    /// The URL to post certification requests to.
+    #[arg(long)]
+    target: String,
+
+    /// The platform whose evidence to send: `kvm`, `sgx` or `snp`.
+    #[arg(long, default_value = "kvm")]
+    platform: String,
+
+    /// Requests to send per second.
+    #[arg(long, default_value = "10")]
+    rps: u32,
+
+    /// Seconds to keep sending for.
+    #[arg(long, default_value = "30")]
+    duration: u64,
+
+    /// Seconds to wait for each response.
+    #[arg(long, default_value = "30")]
+    timeout: u64,
+}
+
+impl Bench {
+    #[cfg(all(feature = "bench", not(target_os = "wasi")))]
+    async fn run(self) -> anyhow::Result<()> {
+        use steward_server::bench::{run, Settings};
+
+        let settings = Settings {
+            target: self.target,
+            platform: self.platform,
+            rps: self.rps,
+            duration: Duration::from_secs(self.duration),
+            timeout: Duration::from_secs(self.timeout),
+        };
+        tracing::info!(
+            "sending {} requests per second to {} for {}s",
+            settings.rps,
+            settings.target,
+            self.duration
+        );
+        print!("{}", run(&settings).await?);
+        Ok(())
+    }
+
+    #[cfg(not(all(feature = "bench", not(target_os = "wasi"))))]
+    async fn run(self) -> anyhow::Result<()> {
+        Err(anyhow!("built without bench support"))
+    }
+}
+
+#[derive(Clone, Debug, clap::Args)]
+struct Verify {
+    /// The directory of samples: certification requests (`*.csr`), each
+    /// beside a `*.toml` file of expectations.
+    #[arg(long)]
+    corpus: PathBuf,
+}
+
+impl Verify {
+    async fn run(self, state: &State) -> anyhow::Result<()> {
+        let report = steward_server::corpus::run(state, &self.corpus).await?;
+        print!("{report}");
+        match report.passed() {
+            true => Ok(()),
+            false => Err(anyhow!(
+                "{} samples came out unexpectedly",
+                report.failures().count()
+            )),
+        }
+    }
+}
+
+/// Where the CA comes from.
+enum Ca {
+    /// Loaded from an existing key and certificate.
+    Load { key: Source, crt: Source },
+
+    /// Generated afresh for the host.
+    Generate { host: String },
+}
+
+impl Args {
+    /// Checks the combinations of options which clap cannot, reporting every
+    /// problem at once, and takes the CA's source.
+    fn validate(&mut self) -> anyhow::Result<Ca> {
+        let mut problems = Vec::new();
+        let mut problem =
+            |option: &str, message: &str| problems.push(format!("--{option}: {message}"));
+
+        let load = self.key.is_some() || self.crt.is_some();
+        match (&self.key, &self.crt) {
+            (Some(..), None) => problem("key", "requires --crt"),
+            (None, Some(..)) => problem("crt", "requires --key"),
+            (None, None) if self.host.is_none() => {
+                problem("host", "required unless --key and --crt are given")
+            }
+            _ => (),
+        }
+        match (&self.crl_key, &self.crl_crt) {
+            (Some(..), None) => problem("crl-key", "requires --crl-crt"),
+            (None, Some(..)) => problem("crl-crt", "requires --crl-key"),
+            _ => (),
+        }
+        match (&self.scep_key, &self.scep_crt) {
+            (Some(..), None) => problem("scep-key", "requires --scep-crt"),
+            (None, Some(..)) => problem("scep-crt", "requires --scep-key"),
+            _ => (),
+        }
+        match (&self.tsa_key, &self.tsa_crt, &self.tsa_policy) {
+            (Some(..), None, _) => problem("tsa-key", "requires --tsa-crt"),
+            (None, Some(..), _) => problem("tsa-crt", "requires --tsa-key"),
+            (Some(..), _, None) => problem("tsa-key", "requires --tsa-policy"),
+            (None, _, Some(..)) => problem("tsa-policy", "requires --tsa-key"),
+            _ => (),
+        }
+        if self.approver_ca.is_some() && self.trusted_proxies.is_empty() {
+            problem("approver-ca", "requires --trusted-proxy");
+        }
+        if self.crl_shards == 0 {
+            problem("crl-shards", "must be positive");
+        }
+        if self.crl_shards != 1 && self.crl_url.is_none() {
+            problem("crl-shards", "requires --crl-url");
+        }
+        if !load && self.config.is_some() {
+            problem("config", "only applies with --key and --crt");
+        }
+        if self.watch_config.is_some() && self.config.is_none() {
+            problem("watch-config", "requires --config");
+        }
+        if self.watch_config == Some(0) {
+            problem("watch-config", "must be positive");
+        }
+        if load && self.ca_path_len != 0 {
+            problem("ca-path-len", "only applies to a generated CA");
+        }
+        if load && !self.permitted_domains.is_empty() {
+            problem("permitted-domain", "only applies to a generated CA");
+        }
+        if load && self.key_algorithm.is_some() {
+            problem("key-algorithm", "only applies to a generated CA");
+        }
+        if let Some(Err(e)) = self
+            .key_algorithm
+            .as_ref()
+            .map(|a| a.parse::<KeyAlgorithm>())
+        {
+            problem("key-algorithm", &e.to_string());
+        }
+
+        if self.evidence_key.is_some() && !self.archive_evidence {
+            problem("evidence-key", "requires --archive-evidence");
+        }
+        if self.evidence_retention == 0 {
+            problem("evidence-retention", "must be at least one day");
+        }
+        if self.addr.is_empty() {
+            problem("addr", "at least one address is required");
+        }
+        for (i, addr) in self.addr.iter().enumerate() {
+            if self.addr[..i].contains(addr) {
+                problem("addr", &format!("{addr} is repeated"));
+            }
+        }
+        if self.admin_addr.is_some() && self.admin_port.is_none() {
+            problem("admin-addr", "requires --admin-port");
+        }
+        if self.admin_port == Some(self.port) {
+            problem("admin-port", "must differ from --port");
+        }
+        if let Some(port) = self.spire_port {
+            if port == self.port || Some(port) == self.admin_port {
+                problem("spire-port", "must differ from --port and --admin-port");
+            }
+        }
+        if self.http2_max_streams.is_some() && !self.http2 {
+            problem("http2-max-streams", "requires --http2");
+        }
+        if self.http2_max_streams == Some(0) {
+            problem("http2-max-streams", "must be positive");
+        }
+        if self.keep_alive_interval == Some(0) {
+            problem("keep-alive-interval", "must be positive");
+        }
+        if self.keep_alive_timeout.is_some() && !(self.http2 && self.keep_alive_interval.is_some())
+        {
+            problem(
+                "keep-alive-timeout",
+                "requires --http2 and --keep-alive-interval",
+            );
+        }
+        if self.keep_alive_timeout == Some(0) {
+            problem("keep-alive-timeout", "must be positive");
+        }
+        if self.header_timeout == Some(0) {
+            problem("header-timeout", "must be positive");
+        }
+        if self.read_timeout == Some(0) {
+            problem("read-timeout", "must be positive");
+        }
+        if self.body_limit == 0 {
+            problem("body-limit", "must be positive");
+        }
+        if self.max_leaf_ttl == Some(0) {
+            problem("max-leaf-ttl", "must be positive");
+        }
+        if self.database_connections == 0 {
+            problem("database-connections", "must be positive");
+        }
+        if self.audit_webhook.is_some() {
+            if self
+                .audit_webhook_secret
+                .as_deref()
+                .map_or(true, str::is_empty)
+            {
+                problem("audit-webhook", "requires --audit-webhook-secret");
+            }
+            if self.audit_queue.is_none() {
+                problem("audit-webhook", "requires --audit-queue");
+            }
+        } else if self.audit_webhook_secret.is_some() || self.audit_queue.is_some() {
+            problem("audit-queue", "requires --audit-webhook");
+        }
+        if self.audit_queue_size == 0 {
+            problem("audit-queue-size", "must be positive");
+        }
+        if let Some(Err(e)) = self
+            .log_format
+            .as_ref()
+            .map(|f| f.parse::<logging::Format>())
+        {
+            problem("log-format", &e.to_string());
+        }
+
+        // Options which need a feature this build lacks.
+        let native = cfg!(not(target_os = "wasi"));
+        let features = [
+            (
+                "fips",
+                "fips",
+                self.fips,
+                attestation::crypto::fips::AVAILABLE,
+            ),
+            (
+                "redis",
+                "redis",
+                self.redis.is_some(),
+                cfg!(feature = "redis") && native,
+            ),
+            (
+                "database",
+                "postgres",
+                self.database.is_some(),
+                cfg!(feature = "postgres") && native,
+            ),
+            (
+                "pcs-url",
+                "collateral",
+                self.pcs_url.is_some(),
+                cfg!(feature = "collateral") && native,
+            ),
+            (
+                "rekor",
+                "rekor",
+                self.rekor.is_some(),
+                cfg!(feature = "rekor") && native,
+            ),
+            (
+                "audit-webhook",
+                "webhook",
+                self.audit_webhook.is_some(),
+                cfg!(feature = "webhook") && native,
+            ),
+            (
+                "kubernetes-signer",
+                "kubernetes",
+                self.kubernetes_signer.is_some(),
+                cfg!(feature = "kubernetes") && native,
+            ),
+            (
+                "spire-port",
+                "spire",
+                self.spire_port.is_some(),
+                cfg!(feature = "spire") && native,
+            ),
+        ];
+        for (option, feature, requested, built) in features {
+            if requested && !built {
+                problem(option, &format!("built without {feature} support"));
+            }
+        }
+        let tuned = [
+            ("http2", self.http2),
+            ("no-keep-alive", self.no_keep_alive),
+            ("keep-alive-interval", self.keep_alive_interval.is_some()),
+            ("tcp-nodelay", self.tcp_nodelay),
+            ("header-timeout", self.header_timeout.is_some()),
+            ("read-timeout", self.read_timeout.is_some()),
+            ("audit-syslog", self.audit_syslog.is_some()),
+            ("audit-journald", self.audit_journald),
+        ];
+        for (option, requested) in tuned {
+            if requested && !native {
+                problem(option, "not supported on wasi");
+            }
+        }
+
+        if !problems.is_empty() {
+            return Err(anyhow!(
+                "invalid configuration:\n  {}\n\nRun with `--help` for more information.",
+                problems.join("\n  ")
+            ));
+        }
+
+        Ok(match (self.key.take(), self.crt.take(), self.host.take()) {
+            (Some(key), Some(crt), _) => Ca::Load { key, crt },
+            (_, _, Some(host)) => Ca::Generate { host },
+            _ => unreachable!("validated above"),
+        })
+    }
 }
 
 #[cfg_attr(not(target_os = "wasi"), tokio::main)]
 #[cfg_attr(target_os = "wasi", tokio::main(flavor = "current_thread"))]
 async fn main() -> anyhow::Result<()> {
-    init_tracing();
-
-    let args = confargs::args::<Toml>(prefix_char_filter::<'@'>)
+    let mut args = confargs::args::<Toml>(prefix_char_filter::<'@'>)
         .context("Failed to parse config")
         .map(Args::parse_from)?;
-    let state = match (args.key, args.crt, args.host) {
-        (None, None, Some(host)) => State::generate(args.san, &host)?,
-        (Some(key), Some(crt), _) => State::load(args.san, key, crt, args.config)?,
-        _ => {
-            eprintln!("Either:\n* Specify the public key `--crt` and private key `--key`, or\n* Specify the host `--host`.\n\nRun with `--help` for more information.");
-            return Err(anyhow!("invalid configuration"));
+
+    // An unknown format is reported by validation, once logs are flowing.
+    let format = match &args.log_format {
+        Some(format) => format.parse().unwrap_or_default(),
+        None => logging::Format::from_env(),
+    };
+    logging::init(format, args.log_unredacted);
+
+    // Benchmarks need no CA; a corpus is appraised once steward is set up.
+    let verify = match args.command.take() {
+        Some(Command::Bench(bench)) => return bench.run().await,
+        Some(Command::Verify(verify)) => Some(verify),
+        None => None,
+    };
+
+    if args.fips {
+        attestation::crypto::fips::enable().context("failed to enable FIPS mode")?;
+        metrics::FIPS_MODE.set(1);
+        tracing::info!("FIPS mode enabled: using the AWS-LC FIPS validated module");
+    } else if attestation::crypto::fips::AVAILABLE {
+        tracing::info!("FIPS mode available but not enabled");
+    }
+
+    let state = match args.validate()? {
+        Ca::Generate { host } => {
+            let constraints = Constraints {
+                path_len: args.ca_path_len,
+                permitted: args.permitted_domains,
+            };
+            let algorithm = match &args.key_algorithm {
+                Some(algorithm) => algorithm.parse()?,
+                None => KeyAlgorithm::default(),
+            };
+            let clock = std::sync::Arc::new(System);
+            State::generate_as(args.san, &host, &constraints, algorithm, clock)?
+        }
+        Ca::Load { key, crt } => {
+            let key = key.read_private().context("failed to read key")?;
+            let crt = crt.read().context("failed to read certificate")?;
+            State::read(args.san, key.as_slice(), crt.as_slice(), args.config)?
+        }
+    };
+    let state = match (&args.crl_key, &args.crl_crt) {
+        (Some(key), Some(crt)) => {
+            let key = key
+                .read_private()
+                .context("failed to read crl signer key")?;
+            let crt = crt
+                .read()
+                .context("failed to read crl signer certificate")?;
+            let delegate = Delegate::read(&state.crt, key.as_slice(), crt.as_slice())
+                .context("invalid crl signer")?;
+            state.with_crl_signer(delegate)
+        }
+        _ => state,
+    };
+    let state = match (&args.scep_key, &args.scep_crt) {
+        (Some(key), Some(crt)) => {
+            let key = key
+                .read_private()
+                .context("failed to read scep agent key")?;
+            let crt = crt
+                .read()
+                .context("failed to read scep agent certificate")?;
+            let agent = Agent::read(&state.crt, key.as_slice(), crt.as_slice())
+                .context("invalid scep agent")?;
+            state.with_scep_agent(agent)
+        }
+        _ => state,
+    };
+    let state = match (&args.tsa_key, &args.tsa_crt, &args.tsa_policy) {
+        (Some(key), Some(crt), Some(policy)) => {
+            let key = key.read_private().context("failed to read tsa key")?;
+            let crt = crt.read().context("failed to read tsa certificate")?;
+            let tsa = Authority::read(&state.crt, key.as_slice(), crt.as_slice(), policy)
+                .context("invalid timestamping authority")?;
+            state.with_tsa(tsa)
+        }
+        _ => state,
+    };
+    let state = match &args.approver_ca {
+        Some(cas) => {
+            let cas = cas.read().context("failed to read approver ca")?;
+            state.with_approvers(Approvers::read(cas.as_slice()).context("invalid approver ca")?)
+        }
+        None => state,
+    };
+    let mut state = state;
+    for crt in std::mem::take(&mut args.cross_crts) {
+        let crt = crt.read().context("failed to read cross-certificate")?;
+        state = state
+            .with_cross_chain(crt.as_slice())
+            .context("invalid cross-certificate")?;
+    }
+    let state = match &args.crl_url {
+        Some(url) => state.with_crl_distribution(Distribution::new(url, args.crl_shards)?),
+        None => state,
+    };
+    let state = state
+        .with_cache(AppraisalCache::new(
+            Duration::from_secs(args.cache_ttl),
+            Duration::from_secs(args.negative_cache_ttl),
+        ))
+        .with_proxies(Trusted::new(args.trusted_proxies))
+        .with_body_limit(args.body_limit)
+        .with_rejections(args.rejections_kept)
+        .with_cors(&Cors {
+            origins: args.cors_origins,
+            methods: args.cors_methods,
+            headers: args.cors_headers,
+        })
+        .context("invalid cors configuration")?;
+    let state = match args.max_leaf_ttl {
+        Some(ttl) => state.with_max_leaf_ttl(Duration::from_secs(ttl)),
+        None => state,
+    };
+    let state = match args.watch_config {
+        Some(secs) => state.with_policy_watch(Duration::from_secs(secs)),
+        None => state,
+    };
+
+    // Reproducible issuance is only ever offered by builds for golden file
+    // and differential testing.
+    #[cfg(feature = "deterministic")]
+    let state = match args.deterministic_seed {
+        Some(seed) => {
+            tracing::warn!("issuing guessable serial numbers seeded with {seed}");
+            state.with_serials(std::sync::Arc::new(Seeded::new(seed)))
+        }
+        None => state,
+    };
+    #[cfg(feature = "deterministic")]
+    let state = match args.frozen_time {
+        Some(secs) => {
+            let now = UNIX_EPOCH + Duration::from_secs(secs);
+            tracing::warn!("clock frozen at {secs} seconds since the epoch");
+            state.with_clock(std::sync::Arc::new(Manual::new(now)))
+        }
+        None => state,
+    };
+
+    let state = match args.redis {
+        #[cfg(all(feature = "redis", not(target_os = "wasi")))]
+        Some(url) => {
+            use steward_server::shared::Redis;
+            let redis = Redis::connect(&url, "steward/").await?;
+            tracing::info!("sharing state through redis");
+            state.with_shared(std::sync::Arc::new(redis))
+        }
+        #[cfg(not(all(feature = "redis", not(target_os = "wasi"))))]
+        Some(..) => return Err(anyhow!("built without redis support")),
+        None => state,
+    };
+
+    let state = match args.database {
+        #[cfg(all(feature = "postgres", not(target_os = "wasi")))]
+        Some(url) => {
+            use steward_server::store::Postgres;
+            let db = Postgres::connect(&url, args.database_connections).await?;
+            tracing::info!("recording issuance in postgres");
+            state.with_store(std::sync::Arc::new(db))
+        }
+        #[cfg(not(all(feature = "postgres", not(target_os = "wasi"))))]
+        Some(..) => return Err(anyhow!("built without postgres support")),
+        None => state,
+    };
+
+    // Failures are only ever injected by builds for soak and chaos testing.
+    #[cfg(feature = "chaos")]
+    let faults = steward_server::chaos::Faults::from_env()?;
+    #[cfg(feature = "chaos")]
+    if !faults.is_empty() {
+        tracing::warn!("injecting faults: {faults:?}");
+    }
+
+    let state = match args.pcs_url {
+        #[cfg(all(feature = "collateral", not(target_os = "wasi")))]
+        Some(url) => {
+            use steward_server::collateral::{Collateral, PckCa, Settings};
+            let settings = Settings {
+                timeout: Duration::from_secs(args.collateral_timeout),
+                retries: args.collateral_retries,
+                ..Default::default()
+            };
+            let cas = args
+                .sgx_pck_cas
+                .iter()
+                .map(|ca| ca.parse::<PckCa>())
+                .collect::<anyhow::Result<_>>()?;
+            let collateral = Collateral::new(&url, settings)?.with_cas(cas);
+            #[cfg(feature = "chaos")]
+            let collateral = collateral.with_faults(faults.clone());
+
+            // Warm the cache, but serve anyway if the vendor is down.
+            tracing::info!("fetching sgx collateral from {url}");
+            if let Err(e) = collateral.prefetch().await {
+                tracing::warn!("{e:#}");
+            }
+            state.with_collateral(collateral)
+        }
+        #[cfg(not(all(feature = "collateral", not(target_os = "wasi"))))]
+        Some(..) => return Err(anyhow!("built without collateral support")),
+        None => state,
+    };
+    #[cfg(feature = "chaos")]
+    let state = state.with_faults(faults);
+
+    // Tokens are only accepted from issuers the policy names.
+    #[cfg(all(feature = "fulcio", not(target_os = "wasi")))]
+    let state = {
+        use steward_server::collateral::Settings;
+        use steward_server::fulcio::Discovery;
+        let settings = Settings {
+            timeout: Duration::from_secs(args.collateral_timeout),
+            retries: args.collateral_retries,
+            ..Default::default()
+        };
+        state.with_fulcio(Discovery::new(settings)?)
+    };
+
+    let state = match args.auditor_token {
+        Some(token) => state.with_auditor(Auditor::new(&token)?),
+        None => state,
+    };
+    let state = if args.archive_evidence {
+        let retention = Duration::from_secs(args.evidence_retention * 60 * 60 * 24);
+        let mut archive = Archive::new(retention);
+        if let Some(path) = args.evidence_key {
+            let key = std::fs::read_to_string(path).context("failed to read evidence key")?;
+            let key = Zeroizing::new(key);
+            let key = Zeroizing::new(hex::decode(key.trim()).context("invalid evidence key")?);
+            archive = archive.with_key(&key)?;
+        }
+        state.with_archive(archive)
+    } else {
+        state
+    };
+    #[cfg(all(feature = "webhook", not(target_os = "wasi")))]
+    let webhook = match (
+        args.audit_webhook,
+        args.audit_webhook_secret,
+        args.audit_queue,
+    ) {
+        (Some(url), Some(secret), Some(dir)) => {
+            use steward_server::webhook::{Queue, Webhook};
+            let queue = Queue::open(dir, args.audit_queue_size)?;
+            let webhook = Webhook::new(&url, secret.as_bytes(), queue)?;
+            tracing::info!("sending audit events to {url}");
+            Some(std::sync::Arc::new(webhook))
+        }
+        _ => None,
+    };
+    #[cfg(all(feature = "webhook", not(target_os = "wasi")))]
+    let state = match &webhook {
+        Some(webhook) => state.with_audit_sink(webhook.clone()),
+        None => state,
+    };
+    #[cfg(not(all(feature = "webhook", not(target_os = "wasi"))))]
+    if args.audit_webhook.is_some() {
+        return Err(anyhow!("built without webhook support"));
+    }
+    #[cfg(unix)]
+    let state = match &args.audit_syslog {
+        Some(target) => {
+            let syslog = steward_server::audit::Syslog::connect(target)?;
+            tracing::info!("sending audit events to syslog at {target}");
+            state.with_audit_sink(std::sync::Arc::new(syslog))
+        }
+        None => state,
+    };
+    #[cfg(unix)]
+    let state = if args.audit_journald {
+        let journald = steward_server::audit::Journald::connect()?;
+        tracing::info!("sending audit events to the journal");
+        state.with_audit_sink(std::sync::Arc::new(journald))
+    } else {
+        state
+    };
+
+    if let Some(verify) = verify {
+        return verify.run(&state).await;
+    }
+
+    let state = state
+        .activate_policy()
+        .await
+        .context("failed to activate policy")?;
+
+    let tasks = state.tasks();
+    let tasks = match args.rekor {
+        #[cfg(all(feature = "rekor", not(target_os = "wasi")))]
+        Some(url) => {
+            let rekor = steward_server::rekor::Rekor::new(&url)?;
+            tracing::info!("publishing issued certificates to rekor at {url}");
+            rekor.schedule(&state, tasks)
+        }
+        #[cfg(not(all(feature = "rekor", not(target_os = "wasi"))))]
+        Some(..) => return Err(anyhow!("built without rekor support")),
+        None => tasks,
+    };
+    #[cfg(all(feature = "webhook", not(target_os = "wasi")))]
+    let tasks = match webhook {
+        Some(webhook) => webhook.schedule(tasks),
+        None => tasks,
+    };
+    let tasks = match args.kubernetes_signer {
+        #[cfg(all(feature = "kubernetes", not(target_os = "wasi")))]
+        Some(name) => {
+            let signer = steward_server::kubernetes::Signer::in_cluster(&name)?;
+            tracing::info!("signing kubernetes certificate signing requests for {name}");
+            signer.schedule(&state, tasks)
+        }
+        #[cfg(not(all(feature = "kubernetes", not(target_os = "wasi"))))]
+        Some(..) => return Err(anyhow!("built without kubernetes support")),
+        None => tasks,
+    };
+    let tasks = tasks.start();
+
+    let spire: Option<tokio::task::JoinHandle<anyhow::Result<()>>> = match args.spire_port {
+        #[cfg(all(feature = "spire", not(target_os = "wasi")))]
+        Some(port) => {
+            let addr = std::net::SocketAddr::from((args.addr[0], port));
+            tracing::info!("serving the spire upstream authority on {addr}");
+            Some(tokio::spawn(steward_server::spire::serve(
+                state.clone(),
+                addr,
+            )))
+        }
+        #[cfg(not(all(feature = "spire", not(target_os = "wasi"))))]
+        Some(..) => return Err(anyhow!("built without spire support")),
+        None => None,
+    };
+
+    let admin: Option<tokio::task::JoinHandle<anyhow::Result<()>>> = match args.admin_port {
+        #[cfg(not(target_os = "wasi"))]
+        Some(port) => {
+            let addr = std::net::SocketAddr::from((args.admin_addr.unwrap_or(args.addr[0]), port));
+            let ops = steward_server::operations(state.clone())
+                .into_make_service_with_connect_info::<Peer>();
+            let server = axum::Server::try_bind(&addr)?.serve(ops);
+            tracing::info!("serving health, metrics and the admin api on {addr}");
+            Some(tokio::spawn(async move { Ok(server.await?) }))
         }
+        #[cfg(target_os = "wasi")]
+        Some(..) => return Err(anyhow!("a separate admin port is not supported on wasi")),
+        None => None,
+    };
+    let app = match admin {
+        Some(..) => public,
+        None => app,
     };
 
     #[cfg(not(target_os = "wasi"))]
     {
-        use std::net::SocketAddr;
-        let addr = SocketAddr::from((args.addr, args.port));
-        tracing::debug!("listening on {}", addr);
-        axum::Server::bind(&addr)
-            .serve(app(state).into_make_service())
-            .await?;
+        let tuning = steward_server::listener::Tuning {
+            http2: args.http2,
+            max_concurrent_streams: args.http2_max_streams,
+            keep_alive: !args.no_keep_alive,
+            keep_alive_interval: args.keep_alive_interval.map(Duration::from_secs),
+            keep_alive_timeout: args.keep_alive_timeout.map(Duration::from_secs),
+            nodelay: args.tcp_nodelay,
+            header_timeout: args.header_timeout.map(Duration::from_secs),
+            read_timeout: args.read_timeout.map(Duration::from_secs),
+        };
+
+        let mut servers = Vec::new();
+        for listener in steward_server::listener::bind(&args.addr, args.port)? {
+            let addr = listener.local_addr()?;
+            let app = app(state.clone()).into_make_service_with_connect_info::<Peer>();
+            let shutdown = async move {
+                let _ = tokio::signal::ctrl_c().await;
+                tracing::info!("shutting down {addr}");
+            };
+
+            tracing::info!("listening on {addr}");
+            let server: tokio::task::JoinHandle<anyhow::Result<()>> = if args.proxy_protocol {
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                let incoming = steward_server::proxy::incoming(listener, tuning);
+                let server = tuning
+                    .protocol(axum::Server::builder(tuning.guard(incoming)))
+                    .serve(app)
+                    .with_graceful_shutdown(shutdown);
+                tokio::spawn(async move { Ok(server.await?) })
+            } else {
+                let server = tuning
+                    .server(listener)?
+                    .serve(app)
+                    .with_graceful_shutdown(shutdown);
+                tokio::spawn(async move { Ok(server.await?) })
+            };
+            servers.push(server);
+        }
+
+        for server in servers {
+            server.await??;
+        }
     }
     #[cfg(target_os = "wasi")]
     {
         use std::os::wasi::io::FromRawFd;
+        if args.proxy_protocol {
+            return Err(anyhow!("proxy protocol is not supported on wasi"));
+        }
+
         tracing::debug!("listening");
         let std_listener = unsafe { std::net::TcpListener::from_raw_fd(3) };
         std_listener
@@ -129,9 +1231,16 @@ async fn main() -> anyhow::Result<()> {
             .context("failed to set NONBLOCK")?;
         axum::Server::from_tcp(std_listener)
             .context("failed to construct server")?
-            .serve(app(state).into_make_service())
+            .serve(app(state).into_make_service_with_connect_info::<Peer>())
             .await?;
     }
 
+    if let Some(spire) = spire {
+        spire.abort();
+    }
+    if let Some(admin) = admin {
+        admin.abort();
+    }
+    tasks.shutdown().await;
     Ok(())
 }
@@ -8,3 +8,10 @@ printf "\nGenerating CA certificate\n"
 openssl req -new -x509 -days 9999 -config ca.conf -key ca.key -out ca.crt
 printf "\nCA "
 openssl x509 -noout -text -in ca.crt
+
+printf "\nGenerating OpenSSL certification request\n"
+openssl ecparam -genkey -name prime256v1 | openssl pkcs8 -topk8 -nocrypt -out openssl.key
+openssl req -new -config csr.conf -key openssl.key -outform DER -out openssl.csr
+rm openssl.key
+printf "\nOpenSSL "
+openssl req -inform DER -noout -text -in openssl.csr